@@ -0,0 +1,346 @@
+// Builds the user programs in user/ so that the kernel can embed them (see
+// src/programs.rs). They are a separate crate for x86_64-unknown-none, since
+// they run in ring 3 and know nothing about the kernel.
+//
+// `core` is built from source for them too, since the build-std setting in
+// .cargo/config.toml also applies to builds started from user/.
+//
+// Then it packs the user programs (as bin/<name>) and the contents of initrd/
+// into a tar archive, which the kernel embeds as its initrd (see src/initrd.rs).
+//
+// Finally, it formats a FAT32 disk image with the contents of
+// tests/fixtures/disk, for the filesystem tests in tests/fs.rs.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// User programs are linked at the start of the user part of the address space
+// (see src/memory/address_space.rs).
+const USER_START: &str = "0x100000000000";
+
+const TARGET: &str = "x86_64-unknown-none";
+
+fn main() {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let user_dir = manifest_dir.join("user");
+    let target_dir = PathBuf::from(env::var("OUT_DIR").unwrap()).join("user");
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".into());
+
+    // The loader doesn't do relocations, so the programs are linked to a fixed
+    // address. That address is way above 2 GiB, hence the large code model.
+    let rustflags = [
+        "-Crelocation-model=static",
+        "-Ccode-model=large",
+        &format!("-Clink-arg=--image-base={}", USER_START),
+    ]
+    .join("\x1f");
+
+    let status = Command::new(cargo)
+        .current_dir(&user_dir)
+        .args(["build", "--release", "--bins", "--target", TARGET])
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .env("CARGO_ENCODED_RUSTFLAGS", rustflags)
+        // Don't let the kernel's build settings leak into this build.
+        .env_remove("RUSTFLAGS")
+        .env_remove("RUSTC_WORKSPACE_WRAPPER")
+        .env_remove("CARGO_BUILD_TARGET")
+        .status()
+        .expect("failed to run cargo for the user programs");
+    assert!(status.success(), "building the user programs failed");
+
+    let programs_dir = target_dir.join(TARGET).join("release");
+    println!(
+        "cargo:rustc-env=USER_PROGRAMS_DIR={}",
+        programs_dir.display()
+    );
+    println!("cargo:rerun-if-changed=user/src");
+    println!("cargo:rerun-if-changed=user/Cargo.toml");
+
+    let initrd = PathBuf::from(env::var("OUT_DIR").unwrap()).join("initrd.tar");
+    build_initrd(
+        &manifest_dir.join("initrd"),
+        &user_dir,
+        &programs_dir,
+        &initrd,
+    )
+    .expect("failed to build the initrd");
+    println!("cargo:rustc-env=INITRD={}", initrd.display());
+    println!("cargo:rerun-if-changed=initrd");
+
+    let disk_image = PathBuf::from(env::var("OUT_DIR").unwrap()).join("disk.img");
+    build_disk_image(
+        &manifest_dir.join("tests").join("fixtures").join("disk"),
+        &disk_image,
+    )
+    .expect("failed to build the disk image");
+    println!("cargo:rustc-env=DISK_IMAGE={}", disk_image.display());
+    println!("cargo:rerun-if-changed=tests/fixtures/disk");
+}
+
+fn build_initrd(
+    initrd_dir: &Path,
+    user_dir: &Path,
+    programs_dir: &Path,
+    output: &Path,
+) -> io::Result<()> {
+    let mut archive = Vec::new();
+
+    // Every file in user/src/bin is a program.
+    let mut programs = Vec::new();
+    for entry in fs::read_dir(user_dir.join("src").join("bin"))? {
+        let path = entry?.path();
+        if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+            programs.push(name.to_string());
+        }
+    }
+    programs.sort();
+
+    append_tar_entry(&mut archive, "bin/", None)?;
+    for name in programs {
+        let data = fs::read(programs_dir.join(&name))?;
+        append_tar_entry(&mut archive, &format!("bin/{}", name), Some(&data))?;
+    }
+    if initrd_dir.is_dir() {
+        append_tar_dir(&mut archive, initrd_dir, "")?;
+    }
+
+    // The end of the archive is marked by two zeroed blocks.
+    archive.resize(archive.len() + 2 * 512, 0);
+    fs::write(output, archive)
+}
+
+/// Adds the contents of `dir` to the archive, under `prefix`.
+fn append_tar_dir(archive: &mut Vec<u8>, dir: &Path, prefix: &str) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        if entry.file_type()?.is_dir() {
+            append_tar_entry(archive, &format!("{}/", name), None)?;
+            append_tar_dir(archive, &entry.path(), &format!("{}/", name))?;
+        } else {
+            append_tar_entry(archive, &name, Some(&fs::read(entry.path())?))?;
+        }
+    }
+    Ok(())
+}
+
+/// Adds a ustar header and the data (`None` for a directory), padded to
+/// whole 512-byte blocks. Ownership and timestamps are left at 0, so that the
+/// archive only changes when its contents do.
+fn append_tar_entry(archive: &mut Vec<u8>, name: &str, data: Option<&[u8]>) -> io::Result<()> {
+    if name.len() > 100 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("initrd path too long: {}", name),
+        ));
+    }
+    let size = data.map_or(0, |data| data.len());
+
+    let mut header = [0u8; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    let (mode, kind) = match data {
+        Some(_) => ("0000644", b'0'),
+        None => ("0000755", b'5'),
+    };
+    header[100..107].copy_from_slice(mode.as_bytes());
+    header[108..115].copy_from_slice(b"0000000");
+    header[116..123].copy_from_slice(b"0000000");
+    header[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+    header[136..147].copy_from_slice(b"00000000000");
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is calculated with the checksum field itself set to spaces.
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+    header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+
+    archive.extend_from_slice(&header);
+    if let Some(data) = data {
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(512), 0);
+    }
+    Ok(())
+}
+
+// The layout of the disk image: 512-byte sectors, one sector per cluster, 32
+// reserved sectors and two FATs, like mkfs.fat would make it.
+const SECTOR: usize = 512;
+const RESERVED_SECTORS: usize = 32;
+const NUM_FATS: usize = 2;
+/// Free clusters at the end, so that the image isn't completely full.
+const SPARE_CLUSTERS: usize = 16;
+const END_OF_CHAIN: u32 = 0x0FFF_FFFF;
+
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const LOWERCASE_BASE: u8 = 0x08;
+const LOWERCASE_EXTENSION: u8 = 0x10;
+
+/// The FAT and the data area of a FAT32 volume that is being put together.
+struct FatImage {
+    fat: Vec<u32>,
+    // The clusters, starting with cluster 2
+    data: Vec<u8>,
+}
+
+impl FatImage {
+    /// Allocates enough consecutive clusters for `len` bytes (at least one),
+    /// chains them, and returns the first.
+    fn allocate(&mut self, len: usize) -> u32 {
+        let count = len.div_ceil(SECTOR).max(1);
+        let first = self.fat.len() as u32;
+
+        for i in 1..count as u32 {
+            self.fat.push(first + i);
+        }
+        self.fat.push(END_OF_CHAIN);
+        self.data.resize(self.data.len() + count * SECTOR, 0);
+        first
+    }
+
+    fn fill(&mut self, cluster: u32, bytes: &[u8]) {
+        let offset = (cluster as usize - 2) * SECTOR;
+        self.data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Adds the files in `dir`, and returns the first cluster of the
+    /// directory. `parent` is the cluster of the parent directory, with 0
+    /// standing for the root, and `None` for the root itself.
+    fn add_dir(&mut self, dir: &Path, parent: Option<u32>) -> io::Result<u32> {
+        let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        let dots = if parent.is_some() { 2 } else { 0 };
+        let cluster = self.allocate((entries.len() + dots) * 32);
+
+        let mut raw = Vec::new();
+        if let Some(parent) = parent {
+            raw.extend_from_slice(&fat_dir_entry(*b".          ", 0, ATTR_DIRECTORY, cluster, 0));
+            raw.extend_from_slice(&fat_dir_entry(*b"..         ", 0, ATTR_DIRECTORY, parent, 0));
+        }
+
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let (short_name, case) = fat_short_name(&name)?;
+
+            if entry.file_type()?.is_dir() {
+                let child_parent = if parent.is_some() { cluster } else { 0 };
+                let child = self.add_dir(&entry.path(), Some(child_parent))?;
+                raw.extend_from_slice(&fat_dir_entry(short_name, case, ATTR_DIRECTORY, child, 0));
+            } else {
+                let data = fs::read(entry.path())?;
+                // Empty files have no clusters.
+                let first = if data.is_empty() {
+                    0
+                } else {
+                    let first = self.allocate(data.len());
+                    self.fill(first, &data);
+                    first
+                };
+                let size = data.len() as u32;
+                raw.extend_from_slice(&fat_dir_entry(short_name, case, ATTR_ARCHIVE, first, size));
+            }
+        }
+
+        self.fill(cluster, &raw);
+        Ok(cluster)
+    }
+}
+
+/// Writes a FAT32 image with the contents of `dir`. We don't write long names,
+/// so all names have to fit into 8.3, and be either upper- or lowercase.
+fn build_disk_image(dir: &Path, output: &Path) -> io::Result<()> {
+    // Clusters 0 and 1 are reserved.
+    let mut image = FatImage {
+        fat: vec![0x0FFF_FFF8, END_OF_CHAIN],
+        data: Vec::new(),
+    };
+    let root = image.add_dir(dir, None)?;
+    image.fat.resize(image.fat.len() + SPARE_CLUSTERS, 0);
+    image.data.resize(image.data.len() + SPARE_CLUSTERS * SECTOR, 0);
+
+    let fat_sectors = (image.fat.len() * 4).div_ceil(SECTOR);
+    let total_sectors = RESERVED_SECTORS + NUM_FATS * fat_sectors + image.data.len() / SECTOR;
+
+    let mut disk = vec![0; RESERVED_SECTORS * SECTOR];
+    let boot = &mut disk[..SECTOR];
+    boot[..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    boot[3..11].copy_from_slice(b"MSWIN4.1");
+    boot[11..13].copy_from_slice(&(SECTOR as u16).to_le_bytes());
+    boot[13] = 1;
+    boot[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+    boot[16] = NUM_FATS as u8;
+    boot[21] = 0xF8;
+    boot[32..36].copy_from_slice(&(total_sectors as u32).to_le_bytes());
+    boot[36..40].copy_from_slice(&(fat_sectors as u32).to_le_bytes());
+    boot[44..48].copy_from_slice(&root.to_le_bytes());
+    boot[64] = 0x80;
+    boot[66] = 0x29;
+    boot[71..82].copy_from_slice(b"FIXTURES   ");
+    boot[82..90].copy_from_slice(b"FAT32   ");
+    boot[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+    for _ in 0..NUM_FATS {
+        let start = disk.len();
+        disk.extend(image.fat.iter().flat_map(|entry| entry.to_le_bytes()));
+        disk.resize(start + fat_sectors * SECTOR, 0);
+    }
+    disk.extend_from_slice(&image.data);
+
+    fs::write(output, disk)
+}
+
+fn fat_dir_entry(name: [u8; 11], case: u8, attributes: u8, cluster: u32, size: u32) -> [u8; 32] {
+    let mut entry = [0; 32];
+    entry[..11].copy_from_slice(&name);
+    entry[11] = attributes;
+    entry[12] = case;
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+    entry
+}
+
+/// Turns "notes.txt" into "NOTES   TXT", and the flags that mark which parts
+/// are lowercase.
+fn fat_short_name(name: &str) -> io::Result<([u8; 11], u8)> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("disk image file names have to fit into 8.3: {}", name),
+        )
+    };
+    let (base, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    let valid_part = |part: &str, max: usize| {
+        part.len() <= max
+            && part
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-')
+    };
+    if base.is_empty() || !valid_part(base, 8) || !valid_part(extension, 3) {
+        return Err(invalid());
+    }
+
+    let mut case = 0;
+    for (part, flag) in [(base, LOWERCASE_BASE), (extension, LOWERCASE_EXTENSION)] {
+        if part.bytes().any(|byte| byte.is_ascii_lowercase()) {
+            if part.bytes().any(|byte| byte.is_ascii_uppercase()) {
+                return Err(invalid());
+            }
+            case |= flag;
+        }
+    }
+
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+    short_name[8..8 + extension.len()].copy_from_slice(extension.to_ascii_uppercase().as_bytes());
+    Ok((short_name, case))
+}
@@ -0,0 +1,5 @@
+Reads a line and prints it backwards
+
+>,----------[++++++++++>,----------]
+<[.<]
+++++++++++.
@@ -0,0 +1,295 @@
+// ACPI tables, as far as the kernel uses them. The firmware leaves the root
+// pointer (RSDP) in the first KiB of the extended BIOS data area, or in the
+// BIOS area at 0xE0000-0xFFFFF, on a 16 byte boundary. It points to the RSDT
+// (or to the XSDT, its 64-bit version from ACPI 2.0 on), which lists the
+// physical addresses of all the other tables. Every table starts with the same
+// header: a signature like "FACP", the length, and a checksum byte that makes
+// all the table's bytes add up to zero.
+//
+// The tables are read through `memory::phys_to_virt`, like the local APIC's
+// registers, so `init` has to wait for `memory::init`.
+//
+// There is no AML interpreter. What the kernel needs from the DSDT's AML, the
+// sleep type for S5, is fished out of its bytes, which works for the simple
+// way firmware (QEMU's included) defines it.
+
+use crate::memory;
+use alloc::vec::Vec;
+use core::{slice, str};
+use spin::Once;
+use x86_64::PhysAddr;
+
+const RSDP_SIGNATURE: &[u8] = b"RSD PTR ";
+const RSDP_V1_LEN: usize = 20;
+const RSDP_V2_LEN: usize = 36;
+
+/// Where the BIOS data area keeps the real mode segment of the EBDA.
+const EBDA_SEGMENT: u64 = 0x40E;
+const BIOS_AREA: (u64, u64) = (0xE0000, 0x100000);
+
+pub const HEADER_LEN: usize = 36;
+
+// AML opcodes, for finding \_S5
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const BYTE_PREFIX: u8 = 0x0A;
+
+/// Generic address structures in the system I/O space are I/O ports.
+const ADDRESS_SPACE_IO: u8 = 1;
+const FADT_RESET_REG_SUPPORTED: u32 = 1 << 10;
+
+/// The RSDT or XSDT, and how long its entries are.
+#[derive(Debug, Clone, Copy)]
+struct Root {
+    address: PhysAddr,
+    entry_len: usize,
+}
+
+static ROOT: Once<Option<Root>> = Once::new();
+
+/// Looks for the root pointer. Called once memory is set up.
+pub fn init() {
+    ROOT.call_once(find_root);
+}
+
+/// Like `init`, but with the root pointer where the bootloader says it is.
+/// UEFI firmware needn't leave it in the BIOS area, and tells the bootloader
+/// where it is instead.
+pub fn init_at(rsdp: PhysAddr) {
+    ROOT.call_once(|| root_at(rsdp.as_u64()));
+}
+
+/// Whether the firmware has ACPI tables.
+pub fn present() -> bool {
+    matches!(ROOT.r#try(), Some(Some(_)))
+}
+
+/// An ACPI table, header included.
+#[derive(Debug, Clone, Copy)]
+pub struct Table {
+    address: PhysAddr,
+    data: &'static [u8],
+}
+
+impl Table {
+    /// Reads the table at `address`, if its length and checksum make sense.
+    ///
+    /// Unsafe because `address` must be where a table is.
+    unsafe fn at(address: PhysAddr) -> Option<Table> {
+        let len = u32_at(bytes(address, HEADER_LEN), 4) as usize;
+        if len < HEADER_LEN {
+            return None;
+        }
+        let data = bytes(address, len);
+        if !checksum_ok(data) {
+            return None;
+        }
+        Some(Table { address, data })
+    }
+
+    pub fn address(&self) -> PhysAddr {
+        self.address
+    }
+
+    pub fn signature(&self) -> &'static str {
+        str::from_utf8(&self.data[0..4]).unwrap_or("????")
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.data[8]
+    }
+
+    pub fn oem_id(&self) -> &'static str {
+        str::from_utf8(&self.data[10..16]).unwrap_or("").trim_end()
+    }
+
+    /// The whole table, header included.
+    pub fn data(&self) -> &'static [u8] {
+        self.data
+    }
+}
+
+/// Returns the `len` bytes of physical memory at `address`.
+unsafe fn bytes(address: PhysAddr, len: usize) -> &'static [u8] {
+    slice::from_raw_parts(memory::phys_to_virt(address).as_ptr(), len)
+}
+
+fn checksum_ok(data: &[u8]) -> bool {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+pub fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+pub fn u32_at(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+pub fn u64_at(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn find_root() -> Option<Root> {
+    let ebda = u64::from(u16_at(unsafe { bytes(PhysAddr::new(EBDA_SEGMENT), 2) }, 0)) << 4;
+    let mut areas = [(ebda, ebda + 1024), BIOS_AREA];
+    if ebda == 0 {
+        areas[0] = (0, 0);
+    }
+
+    areas
+        .iter()
+        .flat_map(|&(start, end)| (start..end).step_by(16))
+        .find_map(root_at)
+}
+
+/// The root table of the RSDP at `address`, if there is one.
+fn root_at(address: u64) -> Option<Root> {
+    let rsdp = unsafe { bytes(PhysAddr::new(address), RSDP_V1_LEN) };
+    if &rsdp[0..8] == RSDP_SIGNATURE && checksum_ok(rsdp) {
+        Some(root(rsdp, address))
+    } else {
+        None
+    }
+}
+
+/// The root table of the RSDP at `address`: the XSDT if there is one.
+fn root(rsdp: &[u8], address: u64) -> Root {
+    let revision = rsdp[15];
+    if revision >= 2 {
+        let rsdp = unsafe { bytes(PhysAddr::new(address), RSDP_V2_LEN) };
+        let xsdt = u64_at(rsdp, 24);
+        if checksum_ok(rsdp) && xsdt != 0 {
+            return Root {
+                address: PhysAddr::new(xsdt),
+                entry_len: 8,
+            };
+        }
+    }
+    Root {
+        address: PhysAddr::new(u64::from(u32_at(rsdp, 16))),
+        entry_len: 4,
+    }
+}
+
+/// All the tables that the root table lists.
+pub fn tables() -> Vec<Table> {
+    let root = match ROOT.r#try() {
+        Some(Some(root)) => *root,
+        _ => return Vec::new(),
+    };
+    let root_table = match unsafe { Table::at(root.address) } {
+        Some(table) => table,
+        None => return Vec::new(),
+    };
+
+    root_table.data[HEADER_LEN..]
+        .chunks_exact(root.entry_len)
+        .filter_map(|entry| {
+            let address = match root.entry_len {
+                8 => u64_at(entry, 0),
+                _ => u64::from(u32_at(entry, 0)),
+            };
+            unsafe { Table::at(PhysAddr::new(address)) }
+        })
+        .collect()
+}
+
+/// Finds the table with `signature`, like "APIC" for the MADT.
+pub fn find(signature: &str) -> Option<Table> {
+    tables()
+        .into_iter()
+        .find(|table| table.signature() == signature)
+}
+
+/// What the kernel needs from the FADT (the "FACP" table).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    pub dsdt: PhysAddr,
+    /// The port that `acpi_enable` is written to, to switch the firmware
+    /// from legacy mode to ACPI. Zero if there is no legacy mode.
+    pub smi_command: u16,
+    pub acpi_enable: u8,
+    /// The I/O ports of the PM1 control registers, zero if there's no PM1b.
+    pub pm1a_control: u16,
+    pub pm1b_control: u16,
+    /// The I/O port that resets the machine, and the value for it.
+    pub reset: Option<(u16, u8)>,
+}
+
+pub fn fadt() -> Option<Fadt> {
+    let data = find("FACP")?.data();
+    if data.len() < 116 {
+        return None;
+    }
+
+    // From ACPI 2.0 on, the reset register, and the 64-bit DSDT address
+    let flags = u32_at(data, 112);
+    let reset = if data.len() >= 129
+        && flags & FADT_RESET_REG_SUPPORTED != 0
+        && data[116] == ADDRESS_SPACE_IO
+    {
+        Some((u64_at(data, 120) as u16, data[128]))
+    } else {
+        None
+    };
+    let dsdt = match data.len() {
+        len if len >= 148 && u64_at(data, 140) != 0 => u64_at(data, 140),
+        _ => u64::from(u32_at(data, 40)),
+    };
+
+    Some(Fadt {
+        dsdt: PhysAddr::new(dsdt),
+        smi_command: u32_at(data, 48) as u16,
+        acpi_enable: data[52],
+        pm1a_control: u32_at(data, 64) as u16,
+        pm1b_control: u32_at(data, 68) as u16,
+        reset,
+    })
+}
+
+/// The SLP_TYPa and SLP_TYPb values for S5, "soft off", from the DSDT's
+/// `Name (_S5, Package () { a, b, ... })`.
+pub fn s5_sleep_types() -> Option<(u16, u16)> {
+    let dsdt = unsafe { Table::at(fadt()?.dsdt)? }.data();
+    parse_s5(&dsdt[HEADER_LEN..])
+}
+
+/// Finds the \_S5 object in AML, see `s5_sleep_types`.
+pub fn parse_s5(aml: &[u8]) -> Option<(u16, u16)> {
+    let at = aml.windows(4).position(|name| name == b"_S5_")?;
+    // The name may have a root prefix
+    let name_op = match aml[..at] {
+        [.., op, b'\\'] | [.., op] => op,
+        [] => return None,
+    };
+    if name_op != NAME_OP {
+        return None;
+    }
+
+    let mut bytes = aml[at + 4..].iter().copied();
+    if bytes.next()? != PACKAGE_OP {
+        return None;
+    }
+    // The package length takes up to three more bytes, as the top two bits
+    // of its first byte say, and the number of elements follows
+    let lead = bytes.next()?;
+    for _ in 0..lead >> 6 {
+        bytes.next()?;
+    }
+    bytes.next()?;
+
+    let mut integer = || match bytes.next()? {
+        ZERO_OP => Some(0),
+        ONE_OP => Some(1),
+        BYTE_PREFIX => bytes.next().map(u16::from),
+        _ => None,
+    };
+    Some((integer()?, integer()?))
+}
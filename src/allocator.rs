@@ -1,7 +1,12 @@
+use crate::config;
+use crate::error::KernelError;
+use crate::memory::{layout, Size};
+use crate::shell;
+use alloc::{boxed::Box, vec::Vec};
+use core::fmt::{self, Write};
+use x86_64::instructions::interrupts;
 use x86_64::{
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
-    },
+    structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB},
     VirtAddr,
 };
 
@@ -10,6 +15,9 @@ use x86_64::{
 // use linked_list::LinkedListAllocator;
 use fixed_size_block::FixedSizeBlockAllocator;
 
+#[cfg(test)]
+use crate::HostTest;
+
 // The responsibility of an allocator is to manage the available heap memory.
 // It needs to return unused memory on alloc calls and keep track of memory
 // freed by dealloc so that it can be reused again. Most importantly, it
@@ -23,26 +31,12 @@ use fixed_size_block::FixedSizeBlockAllocator;
 // it could even optimize the memory layout with respect to the CPU caches to
 // improve cache locality and avoid false sharing.
 
+pub mod accounting;
 pub mod bump;
 pub mod fixed_size_block;
 pub mod linked_list;
 
-/// A wrapper around spin::Mutex to permit trait implementations.
-pub struct Locked<A> {
-    inner: spin::Mutex<A>,
-}
-
-impl<A> Locked<A> {
-    pub const fn new(inner: A) -> Self {
-        Locked {
-            inner: spin::Mutex::new(inner),
-        }
-    }
-
-    pub fn lock(&self) -> spin::MutexGuard<A> {
-        self.inner.lock()
-    }
-}
+pub use crate::sync::SpinLock;
 
 /// Align the given address `addr` upwards to alignment `align`.
 ///
@@ -69,22 +63,106 @@ fn align_up(addr: usize, align: usize) -> usize {
 //     }
 // }
 
-#[global_allocator]
+#[cfg_attr(not(all(test, feature = "std-tests")), global_allocator)]
 // static ALLOCATOR: LockedHeap = LockedHeap::empty();
-// static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
-// static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+// static ALLOCATOR: SpinLock<BumpAllocator> = SpinLock::new(BumpAllocator::new());
+// static ALLOCATOR: SpinLock<LinkedListAllocator> = SpinLock::new(LinkedListAllocator::new());
+static ALLOCATOR: SpinLock<FixedSizeBlockAllocator> = SpinLock::new(FixedSizeBlockAllocator::new());
+
+/// Which of the allocators above is in use, for `heap`.
+pub const BACKEND: &str = "fixed-size blocks, with a linked list fallback";
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+/// Returns whether the global allocator is currently locked, e.g. by code that
+/// got interrupted in the middle of an allocation.
+pub fn is_locked() -> bool {
+    ALLOCATOR.is_locked()
+}
+
+/// A snapshot of the heap usage.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// The size of the heap in bytes.
+    pub size: usize,
+    /// Bytes handed out by the fallback allocator, including the blocks in `cached`.
+    pub used: usize,
+    /// Bytes in freed blocks that are kept around for reuse.
+    pub cached: usize,
+}
+
+/// Returns the current heap usage, or `None` if the allocator is locked.
+pub fn stats() -> Option<HeapStats> {
+    ALLOCATOR.try_lock().map(|allocator| allocator.stats())
+}
+
+/// The freed blocks of one size, kept for reuse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreeList {
+    pub block_size: usize,
+    pub blocks: usize,
+}
+
+/// Returns the free lists by block size, or `None` if the allocator is
+/// locked.
+pub fn free_lists() -> Option<Vec<FreeList>> {
+    // Copied out first, allocating the Vec needs the lock
+    let lists = ALLOCATOR
+        .try_lock()
+        .map(|allocator| allocator.free_lists())?;
+    Some(lists.to_vec())
+}
+
+/// Something wrong with a free list, found by `check`. Usually the result of
+/// a double free or of writing to freed memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FreeListError {
+    /// A block that's outside of the heap or not aligned to its size.
+    BadBlock { block_size: usize, addr: usize },
+    /// The list for `block_size` loops back on itself.
+    Loop { block_size: usize },
+}
+
+impl fmt::Display for FreeListError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FreeListError::BadBlock { block_size, addr } => {
+                write!(f, "bad block {:#x} in the {}-byte list", addr, block_size)
+            }
+            FreeListError::Loop { block_size } => {
+                write!(f, "the {}-byte list loops", block_size)
+            }
+        }
+    }
+}
+
+/// Walks the allocator's free lists and checks that they are intact, for
+/// tests and debugging. Slow with many freed blocks.
+pub fn check() -> Result<(), FreeListError> {
+    ALLOCATOR.lock().check()
+}
+
+/// Returns who currently holds the global allocator lock, if anyone.
+#[cfg(feature = "lockdep")]
+pub fn lock_owner() -> Option<crate::sync::lockdep::OwnerInfo> {
+    ALLOCATOR.owner()
+}
+
+/// The size of the heap: `config::HEAP_SIZE`, unless the command line says otherwise
+/// with `heap=`, rounded up to whole pages.
+pub fn heap_size() -> usize {
+    match crate::cmdline::size("heap") {
+        Some(size) if size > 0 => align_up(size, 4096),
+        _ => config::HEAP_SIZE,
+    }
+}
 
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
+) -> Result<(), KernelError> {
+    let heap_size = heap_size();
     let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_start = VirtAddr::new(layout::heap_start() as u64);
+        let heap_end = heap_start + heap_size - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
         Page::range_inclusive(heap_start_page, heap_end_page)
@@ -93,12 +171,195 @@ pub fn init_heap(
     for page in page_range {
         let frame = frame_allocator
             .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
+            .ok_or(KernelError::NoMemory)?;
         let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
         unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
     }
 
-    unsafe { ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE) };
+    unsafe { ALLOCATOR.lock().init(layout::heap_start(), heap_size) };
+
+    Ok(())
+}
+
+/// How many free blocks `heap freelist` prints per list.
+const MAX_DUMPED_BLOCKS: usize = 32;
+
+/// The allocation sizes `heap test` tries: some for each block size, and some
+/// for the fallback allocator.
+const TEST_SIZES: [usize; 8] = [1, 8, 24, 100, 512, 2048, 4096, 16384];
+
+pub fn register_commands() {
+    shell::register(
+        "heap",
+        "print the heap usage, `heap freelist [size]` for the free lists, `heap test` to check the allocator",
+        |args, out| match args.get(1).copied() {
+            None => heap(out),
+            Some("freelist") => match args.get(2).map(|size| size.parse()) {
+                None => free_list_dump(out, None),
+                Some(Ok(size)) => free_list_dump(out, Some(size)),
+                Some(Err(_)) => writeln!(out, "usage: heap freelist [size]"),
+            },
+            Some("test") => self_test(out),
+            Some(_) => writeln!(out, "usage: heap [freelist [size] | test]"),
+        },
+    );
+}
+
+fn heap(out: &mut dyn Write) -> fmt::Result {
+    let (stats, lists) = match (stats(), free_lists()) {
+        (Some(stats), Some(lists)) => (stats, lists),
+        _ => return writeln!(out, "heap: the allocator is locked"),
+    };
+    let free = stats.size - stats.used;
+
+    writeln!(out, "backend: {}", BACKEND)?;
+    writeln!(
+        out,
+        "heap:    {:#x}, {}",
+        layout::heap_start(),
+        Size(stats.size as u64)
+    )?;
+    writeln!(
+        out,
+        "used:    {} ({}%), {} of it in free lists",
+        Size(stats.used as u64),
+        stats.used * 100 / stats.size.max(1),
+        Size(stats.cached as u64)
+    )?;
+    writeln!(out, "free:    {}", Size(free as u64))?;
+
+    writeln!(out, "free lists:\n   size  blocks  bytes")?;
+    for list in &lists {
+        writeln!(
+            out,
+            "  {:>5}  {:>6}  {}",
+            list.block_size,
+            list.blocks,
+            list.block_size * list.blocks
+        )?;
+    }
+
+    // Freed blocks only serve allocations of their own size, so memory in
+    // the free lists is lost to everything else.
+    writeln!(
+        out,
+        "fragmentation: {}% of the unused memory is in free lists",
+        stats.cached * 100 / (stats.cached + free).max(1)
+    )?;
 
+    writeln!(
+        out,
+        "by subsystem ({} more for the tags):",
+        Size(accounting::table_size(heap_size()) as u64)
+    )?;
+    for (subsystem, usage) in accounting::usage() {
+        writeln!(
+            out,
+            "  {:<8}  {:>9} in {} allocations",
+            subsystem,
+            Size(usage.bytes as u64),
+            usage.allocations
+        )?;
+    }
     Ok(())
 }
+
+fn free_list_dump(out: &mut dyn Write, block_size: Option<usize>) -> fmt::Result {
+    let sizes: Vec<usize> = match free_lists() {
+        Some(lists) => lists.iter().map(|list| list.block_size).collect(),
+        None => return writeln!(out, "heap: the allocator is locked"),
+    };
+    if let Some(size) = block_size.filter(|size| !sizes.contains(size)) {
+        return writeln!(out, "heap: no free list for {}-byte blocks", size);
+    }
+
+    for size in sizes {
+        if block_size.is_some_and(|block_size| block_size != size) {
+            continue;
+        }
+        // Written out after the lock is released, `out` may allocate
+        let mut addrs = [0; MAX_DUMPED_BLOCKS];
+        let blocks = match ALLOCATOR.try_lock() {
+            Some(allocator) => allocator.free_blocks(size, &mut addrs),
+            None => return writeln!(out, "heap: the allocator is locked"),
+        };
+
+        writeln!(out, "{}-byte blocks: {}", size, blocks)?;
+        for addr in &addrs[..blocks.min(MAX_DUMPED_BLOCKS)] {
+            writeln!(out, "  {:#x}", addr)?;
+        }
+        if blocks > MAX_DUMPED_BLOCKS {
+            writeln!(out, "  ... {} more", blocks - MAX_DUMPED_BLOCKS)?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks the free lists, that allocations of all sizes hold their contents
+/// without overlapping, and that freed blocks get reused.
+fn self_test(out: &mut dyn Write) -> fmt::Result {
+    let mut passed = report(out, "free lists", check().err())?;
+
+    let mut blocks = Vec::new();
+    for (i, &size) in TEST_SIZES.iter().enumerate() {
+        let mut block = Vec::new();
+        if block.try_reserve_exact(size).is_err() {
+            break;
+        }
+        block.resize(size, i as u8);
+        blocks.push(block);
+    }
+    let intact = blocks
+        .iter()
+        .enumerate()
+        .all(|(i, block)| block.iter().all(|&byte| byte == i as u8));
+    let error = match (blocks.len() == TEST_SIZES.len(), intact) {
+        (false, _) => Some("out of memory"),
+        (true, false) => Some("blocks overlap"),
+        (true, true) => None,
+    };
+    passed &= report(out, "allocations", error)?;
+    drop(blocks);
+
+    // Nothing else may take the block in between
+    let reused = interrupts::without_interrupts(|| {
+        let first = Box::into_raw(Box::new([0u8; 64]));
+        drop(unsafe { Box::from_raw(first) });
+        let second = Box::new([0u8; 64]);
+        core::ptr::eq(first, &*second)
+    });
+    let error = match reused {
+        true => None,
+        false => Some("a freed block wasn't reused"),
+    };
+    passed &= report(out, "reuse", error)?;
+
+    passed &= report(out, "free lists afterwards", check().err())?;
+    writeln!(
+        out,
+        "heap test {}",
+        if passed { "passed" } else { "FAILED" }
+    )?;
+    Ok(())
+}
+
+/// Writes whether a step of `self_test` passed, and returns it.
+fn report(
+    out: &mut dyn Write,
+    step: &str,
+    error: Option<impl fmt::Display>,
+) -> Result<bool, fmt::Error> {
+    match error {
+        None => writeln!(out, "{}: ok", step).map(|()| true),
+        Some(error) => writeln!(out, "{}: FAILED, {}", step, error).map(|()| false),
+    }
+}
+
+#[test_case]
+static ALIGN_UP: HostTest = HostTest::new("allocator::align_up", || {
+    assert_eq!(align_up(0, 8), 0);
+    assert_eq!(align_up(1, 8), 8);
+    assert_eq!(align_up(8, 8), 8);
+    assert_eq!(align_up(4097, 4096), 8192);
+    assert_eq!(align_up(0x_4444_4444_0001, 1), 0x_4444_4444_0001);
+});
@@ -0,0 +1,272 @@
+// Who the heap's memory is used by. Every allocation is charged to a
+// subsystem, which `heap` reports the usage of:
+//
+//     let _charge = accounting::charge_to(Subsystem::Vfs);
+//     let node = Box::new(Node::new());      // charged to the VFS
+//
+// `charge_to` sets the current CPU's subsystem until the guard it returns is
+// dropped, and allocations made in between are charged to it. Containers that
+// should always be charged to one subsystem, wherever they grow, can use the
+// `Charged` allocator instead:
+//
+//     let buf = Box::new_in([0; 2048], Charged(Subsystem::Net));
+//
+// The executor charges each task's polls to the task's subsystem (see
+// `Task::charged_to`), and its own bookkeeping to itself. An interrupt handler
+// is charged to whatever it interrupted, unless it says otherwise.
+//
+// `dealloc` has to know who to give the bytes back to, and isn't told, so the
+// allocator notes the subsystem of each allocation in a table of tags, 4 bits
+// for every 8 bytes of the heap, where an allocation can start. The table
+// takes the first sixteenth of the heap.
+
+use crate::per_cpu;
+#[cfg(test)]
+use crate::HostTest;
+use alloc::alloc::{AllocError, Allocator, Global, Layout};
+use core::fmt;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+/// Allocations start on multiples of this, so each needs one tag.
+const GRANULE: usize = 8;
+
+/// What an allocation is charged to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Subsystem {
+    /// Anything that isn't charged to one of the others.
+    Other = 0,
+    Executor = 1,
+    Vfs = 2,
+    /// Network buffers and the network tasks.
+    Net = 3,
+    /// Drivers, while they're set up.
+    Drivers = 4,
+}
+
+impl Subsystem {
+    pub const ALL: [Subsystem; 5] = [
+        Subsystem::Other,
+        Subsystem::Executor,
+        Subsystem::Vfs,
+        Subsystem::Net,
+        Subsystem::Drivers,
+    ];
+
+    fn from_u8(subsystem: u8) -> Subsystem {
+        Subsystem::ALL
+            .get(usize::from(subsystem))
+            .copied()
+            .unwrap_or(Subsystem::Other)
+    }
+}
+
+impl fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Subsystem::Other => "other",
+            Subsystem::Executor => "executor",
+            Subsystem::Vfs => "vfs",
+            Subsystem::Net => "net",
+            Subsystem::Drivers => "drivers",
+        })
+    }
+}
+
+per_cpu! {
+    static CURRENT: AtomicU8 = AtomicU8::new(Subsystem::Other as u8);
+}
+
+/// What the current CPU's allocations are charged to.
+pub fn current() -> Subsystem {
+    Subsystem::from_u8(CURRENT.get().load(Ordering::Relaxed))
+}
+
+/// Charges the current CPU's allocations to `subsystem` until it's dropped.
+#[must_use = "the charge ends when the guard is dropped"]
+pub struct ChargeGuard {
+    previous: u8,
+}
+
+/// Charges the current CPU's allocations to `subsystem` until the returned
+/// guard is dropped, after which they go to whatever they went to before.
+pub fn charge_to(subsystem: Subsystem) -> ChargeGuard {
+    ChargeGuard {
+        previous: CURRENT.get().swap(subsystem as u8, Ordering::Relaxed),
+    }
+}
+
+impl Drop for ChargeGuard {
+    fn drop(&mut self) {
+        CURRENT.get().store(self.previous, Ordering::Relaxed);
+    }
+}
+
+/// The global allocator, charging everything to one subsystem.
+#[derive(Debug, Clone, Copy)]
+pub struct Charged(pub Subsystem);
+
+unsafe impl Allocator for Charged {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let _charge = charge_to(self.0);
+        Global.allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        // The tag knows who to give it back to
+        Global.deallocate(ptr, layout)
+    }
+}
+
+/// How much of the heap a subsystem uses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// The bytes asked for, without what the allocator rounds up.
+    pub bytes: usize,
+    pub allocations: usize,
+}
+
+struct Counters {
+    bytes: AtomicUsize,
+    allocations: AtomicUsize,
+}
+
+impl Counters {
+    const fn new() -> Counters {
+        Counters {
+            bytes: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+        }
+    }
+
+    fn charge(&self, size: usize) {
+        self.bytes.fetch_add(size, Ordering::Relaxed);
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn uncharge(&self, size: usize) {
+        self.bytes.fetch_sub(size, Ordering::Relaxed);
+        self.allocations.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn usage(&self) -> Usage {
+        Usage {
+            bytes: self.bytes.load(Ordering::Relaxed),
+            allocations: self.allocations.load(Ordering::Relaxed),
+        }
+    }
+}
+
+static USAGE: [Counters; Subsystem::ALL.len()] = {
+    const ZERO: Counters = Counters::new();
+    [ZERO; Subsystem::ALL.len()]
+};
+
+pub(super) fn charge(subsystem: Subsystem, size: usize) {
+    USAGE[subsystem as usize].charge(size);
+}
+
+pub(super) fn uncharge(subsystem: Subsystem, size: usize) {
+    USAGE[subsystem as usize].uncharge(size);
+}
+
+/// The heap usage of each subsystem, in the order of `Subsystem::ALL`.
+pub fn usage() -> [(Subsystem, Usage); Subsystem::ALL.len()] {
+    Subsystem::ALL.map(|subsystem| (subsystem, USAGE[subsystem as usize].usage()))
+}
+
+/// The size of the table of tags for a heap of `heap_size` bytes, rounded up
+/// to keep what comes after it aligned.
+pub const fn table_size(heap_size: usize) -> usize {
+    let size = heap_size.div_ceil(GRANULE * 2);
+    (size + 15) & !15
+}
+
+/// The subsystem of each allocation in the heap, by where it starts.
+pub struct Tags {
+    heap_start: usize,
+    table: &'static mut [u8],
+}
+
+impl Tags {
+    pub const fn empty() -> Tags {
+        Tags {
+            heap_start: 0,
+            table: &mut [],
+        }
+    }
+
+    /// Tags the heap at `heap_start` with a table in `table`, everything
+    /// `Other` to start with.
+    pub fn init(&mut self, heap_start: usize, table: &'static mut [u8]) {
+        table.fill(0);
+        self.heap_start = heap_start;
+        self.table = table;
+    }
+
+    /// Where the tag of the allocation at `addr` is: a byte and which half.
+    fn slot(&self, addr: usize) -> Option<(usize, u32)> {
+        let granule = addr.checked_sub(self.heap_start)? / GRANULE;
+        (granule / 2 < self.table.len()).then_some((granule / 2, granule as u32 % 2 * 4))
+    }
+
+    pub fn set(&mut self, addr: usize, subsystem: Subsystem) {
+        if let Some((index, shift)) = self.slot(addr) {
+            let byte = &mut self.table[index];
+            *byte = *byte & !(0xf << shift) | (subsystem as u8) << shift;
+        }
+    }
+
+    pub fn get(&self, addr: usize) -> Subsystem {
+        match self.slot(addr) {
+            Some((index, shift)) => Subsystem::from_u8(self.table[index] >> shift & 0xf),
+            None => Subsystem::Other,
+        }
+    }
+}
+
+#[test_case]
+static TAGS: HostTest = HostTest::new("allocator::accounting::tags", || {
+    use alloc::{boxed::Box, vec};
+
+    let heap_start = 0x1000;
+    let table = Box::leak(vec![0xffu8; table_size(256)].into_boxed_slice());
+    assert_eq!(table.len(), 16);
+    let mut tags = Tags::empty();
+    tags.init(heap_start, table);
+
+    assert_eq!(tags.get(heap_start), Subsystem::Other);
+    tags.set(heap_start, Subsystem::Vfs);
+    tags.set(heap_start + 8, Subsystem::Drivers);
+    tags.set(heap_start + 248, Subsystem::Net);
+    assert_eq!(tags.get(heap_start), Subsystem::Vfs);
+    assert_eq!(tags.get(heap_start + 8), Subsystem::Drivers);
+    assert_eq!(tags.get(heap_start + 16), Subsystem::Other);
+    assert_eq!(tags.get(heap_start + 248), Subsystem::Net);
+    tags.set(heap_start + 8, Subsystem::Executor);
+    assert_eq!(tags.get(heap_start), Subsystem::Vfs);
+    assert_eq!(tags.get(heap_start + 8), Subsystem::Executor);
+
+    // Outside of the heap, nothing is tagged
+    tags.set(heap_start - 8, Subsystem::Net);
+    assert_eq!(tags.get(heap_start - 8), Subsystem::Other);
+    assert_eq!(tags.get(heap_start + 16 * 2 * GRANULE), Subsystem::Other);
+});
+
+#[test_case]
+static COUNTERS: HostTest = HostTest::new("allocator::accounting::counters", || {
+    let counters = Counters::new();
+    counters.charge(100);
+    counters.charge(28);
+    counters.uncharge(100);
+    assert_eq!(
+        counters.usage(),
+        Usage {
+            bytes: 28,
+            allocations: 1
+        }
+    );
+    assert_eq!(Subsystem::from_u8(Subsystem::Net as u8), Subsystem::Net);
+    assert_eq!(Subsystem::from_u8(15), Subsystem::Other);
+});
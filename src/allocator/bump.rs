@@ -11,7 +11,7 @@
 // allocation performance, for example when creating a virtual DOM library.
 //
 
-use super::{align_up, Locked};
+use super::{align_up, SpinLock};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
@@ -46,7 +46,7 @@ impl BumpAllocator {
     }
 }
 
-unsafe impl GlobalAlloc for Locked<BumpAllocator> {
+unsafe impl GlobalAlloc for SpinLock<BumpAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut bump = self.lock();
         let alloc_start = align_up(bump.next, layout.align());
@@ -5,7 +5,9 @@
 // to find a suitable block (compared to the linked list allocator), resulting in much
 // better allocation performance.
 
-use super::Locked;
+use super::accounting::{self, Tags};
+use super::{FreeList, FreeListError, HeapStats, SpinLock};
+use crate::{debugflags, info};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::{mem, ptr, ptr::NonNull};
 
@@ -22,6 +24,7 @@ const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    tags: Tags,
 }
 
 impl FixedSizeBlockAllocator {
@@ -32,6 +35,7 @@ impl FixedSizeBlockAllocator {
         FixedSizeBlockAllocator {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            tags: Tags::empty(),
         }
     }
 
@@ -43,7 +47,100 @@ impl FixedSizeBlockAllocator {
     /// heap bounds are valid and that the heap is unused. This method must be
     /// called only once.
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
-        self.fallback_allocator.init(heap_start, heap_size);
+        // The table of tags comes first, see accounting.rs
+        let table_size = accounting::table_size(heap_size);
+        let table = core::slice::from_raw_parts_mut(heap_start as *mut u8, table_size);
+        self.tags.init(heap_start, table);
+        self.fallback_allocator
+            .init(heap_start + table_size, heap_size - table_size);
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        let mut cached = 0;
+
+        for (head, &block_size) in self.list_heads.iter().zip(BLOCK_SIZES) {
+            let mut node = head.as_deref();
+            while let Some(current) = node {
+                cached += block_size;
+                node = current.next.as_deref();
+            }
+        }
+
+        HeapStats {
+            size: self.fallback_allocator.size(),
+            used: self.fallback_allocator.used(),
+            cached,
+        }
+    }
+
+    /// Returns how many blocks of each size are in the free lists. Nothing is
+    /// allocated, since the caller holds the allocator's lock.
+    pub fn free_lists(&self) -> [FreeList; BLOCK_SIZES.len()] {
+        let mut lists = [FreeList {
+            block_size: 0,
+            blocks: 0,
+        }; BLOCK_SIZES.len()];
+
+        for ((list, head), &block_size) in lists.iter_mut().zip(&self.list_heads).zip(BLOCK_SIZES) {
+            list.block_size = block_size;
+            let mut node = head.as_deref();
+            while let Some(current) = node {
+                list.blocks += 1;
+                node = current.next.as_deref();
+            }
+        }
+        lists
+    }
+
+    /// Writes the addresses of the first blocks in the free list for
+    /// `block_size` to `addrs`, as many as fit, and returns how many blocks
+    /// the list has. Returns 0 if there is no such block size.
+    pub fn free_blocks(&self, block_size: usize, addrs: &mut [usize]) -> usize {
+        let head = match BLOCK_SIZES.iter().position(|&size| size == block_size) {
+            Some(index) => &self.list_heads[index],
+            None => return 0,
+        };
+
+        // Stop at a list that loops, see `check`
+        let max_blocks = self.fallback_allocator.size() / block_size;
+        let mut node = head.as_deref();
+        let mut blocks = 0;
+        while let Some(current) = node.filter(|_| blocks < max_blocks) {
+            if let Some(addr) = addrs.get_mut(blocks) {
+                *addr = current as *const ListNode as usize;
+            }
+            blocks += 1;
+            node = current.next.as_deref();
+        }
+        blocks
+    }
+
+    /// Checks that every block in the free lists is inside the heap and
+    /// aligned to its size, and that no list loops.
+    pub fn check(&self) -> Result<(), FreeListError> {
+        let heap = self.fallback_allocator.bottom()..self.fallback_allocator.top();
+
+        for (head, &block_size) in self.list_heads.iter().zip(BLOCK_SIZES) {
+            // A list with more blocks than fit into the heap has to loop.
+            let max_blocks = heap.len() / block_size;
+            let mut node = head.as_deref();
+            let mut blocks = 0;
+
+            while let Some(current) = node {
+                let addr = current as *const ListNode as usize;
+                if !heap.contains(&addr) || addr % block_size != 0 {
+                    return Err(FreeListError::BadBlock { block_size, addr });
+                }
+
+                blocks += 1;
+                if blocks > max_blocks {
+                    return Err(FreeListError::Loop { block_size });
+                }
+                node = current.next.as_deref();
+            }
+        }
+
+        Ok(())
     }
 
     /// Allocates using the fallback allocator.
@@ -64,11 +161,11 @@ fn list_index(layout: &Layout) -> Option<usize> {
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
-unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+unsafe impl GlobalAlloc for SpinLock<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
 
-        match list_index(&layout) {
+        let ptr = match list_index(&layout) {
             Some(index) => match allocator.list_heads[index].take() {
                 Some(node) => {
                     allocator.list_heads[index] = node.next.take();
@@ -83,11 +180,28 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                 }
             },
             None => allocator.fallback_alloc(layout),
+        };
+
+        if !ptr.is_null() {
+            let subsystem = accounting::current();
+            allocator.tags.set(ptr as usize, subsystem);
+            accounting::charge(subsystem, layout.size());
+        }
+
+        if debugflags::ALLOC_TRACE.get() {
+            info!(target: "alloc", "alloc {:?} -> {:p}", layout, ptr);
         }
+
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if debugflags::ALLOC_TRACE.get() {
+            info!(target: "alloc", "dealloc {:p} {:?}", ptr, layout);
+        }
+
         let mut allocator = self.lock();
+        accounting::uncharge(allocator.tags.get(ptr as usize), layout.size());
 
         match list_index(&layout) {
             Some(index) => {
@@ -6,7 +6,9 @@
 // approach is to construct a single linked list in the freed memory, with each node being a freed memory
 // region.
 
-use super::{align_up, Locked};
+use super::{align_up, SpinLock};
+#[cfg(test)]
+use crate::HostTest;
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::{mem, ptr};
 
@@ -138,20 +140,23 @@ impl LinkedListAllocator {
 
         (size, layout.align())
     }
-}
 
-unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    /// Allocates a block for `layout` from the free list, or returns null if
+    /// there's no region large enough.
+    ///
+    /// # Safety
+    ///
+    /// The allocator must have been initialized with `init`.
+    pub unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
         // Perform layout adjustments
         let (size, align) = LinkedListAllocator::size_align(layout);
-        let mut allocator = self.lock();
 
-        if let Some((region, alloc_start)) = allocator.find_region(size, align) {
+        if let Some((region, alloc_start)) = self.find_region(size, align) {
             let alloc_end = alloc_start.checked_add(size).expect("overflow");
             let excess_size = region.end_addr() - alloc_end;
 
             if excess_size > 0 {
-                allocator.add_free_region(alloc_end, excess_size);
+                self.add_free_region(alloc_end, excess_size);
             }
 
             alloc_start as *mut u8
@@ -160,10 +165,84 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    /// Puts the block at `ptr` back on the free list.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `alloc` with the same `layout`.
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
         // Perform layout adjustments
         let (size, _) = LinkedListAllocator::size_align(layout);
 
-        self.lock().add_free_region(ptr as usize, size);
+        self.add_free_region(ptr as usize, size);
+    }
+}
+
+// The allocator itself doesn't lock, so that its logic can be tested without
+// a `SpinLock` (see the host tests below).
+unsafe impl GlobalAlloc for SpinLock<LinkedListAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock().dealloc(ptr, layout)
     }
 }
+
+/// A byte array that stands in for the heap.
+#[cfg(test)]
+#[repr(align(16))]
+struct TestHeap([u8; 1024]);
+
+#[cfg(test)]
+fn with_test_heap(f: impl FnOnce(&mut LinkedListAllocator, usize)) {
+    let mut heap = TestHeap([0; 1024]);
+    let start = heap.0.as_mut_ptr() as usize;
+    let mut allocator = LinkedListAllocator::new();
+    unsafe { allocator.init(start, heap.0.len()) };
+    f(&mut allocator, start);
+}
+
+#[test_case]
+static ALLOCATES_INSIDE_THE_HEAP: HostTest =
+    HostTest::new("allocator::linked_list::allocates_inside_the_heap", || {
+        with_test_heap(|allocator, start| {
+            let layout = Layout::from_size_align(100, 8).unwrap();
+            let a = unsafe { allocator.alloc(layout) } as usize;
+            let b = unsafe { allocator.alloc(layout) } as usize;
+
+            for block in [a, b] {
+                assert!(block >= start && block + 100 <= start + 1024);
+                assert_eq!(block % 8, 0);
+            }
+            assert!(a + 100 <= b || b + 100 <= a);
+        });
+    });
+
+#[test_case]
+static ALIGNS_ALLOCATIONS: HostTest =
+    HostTest::new("allocator::linked_list::aligns_allocations", || {
+        with_test_heap(|allocator, _| {
+            // Moves the free region off any large alignment first.
+            unsafe { allocator.alloc(Layout::from_size_align(24, 8).unwrap()) };
+
+            let block = unsafe { allocator.alloc(Layout::from_size_align(64, 256).unwrap()) };
+            assert!(!block.is_null());
+            assert_eq!(block as usize % 256, 0);
+        });
+    });
+
+#[test_case]
+static RUNS_OUT_AND_REUSES: HostTest =
+    HostTest::new("allocator::linked_list::runs_out_and_reuses", || {
+        with_test_heap(|allocator, _| {
+            let layout = Layout::from_size_align(1024, 16).unwrap();
+            let block = unsafe { allocator.alloc(layout) };
+            assert!(!block.is_null());
+            assert!(unsafe { allocator.alloc(layout) }.is_null());
+
+            unsafe { allocator.dealloc(block, layout) };
+            assert_eq!(unsafe { allocator.alloc(layout) }, block);
+        });
+    });
@@ -0,0 +1,111 @@
+// The local APIC, as far as message signalled interrupts (MSIs) need it.
+//
+// Interrupts from legacy devices still come from the 8259 PICs, which the local
+// APIC passes on to the CPU in "virtual wire" mode: its LINT0 pin is where the
+// PICs' output is connected. MSIs don't go through the PICs at all. A PCI device
+// raises one by writing a message to the local APIC's address range, and the
+// local APIC raises the vector given in the message. They are acknowledged by
+// writing to the local APIC's end of interrupt register instead of the PICs'.
+//
+// The local APIC also has a timer. In TSC-deadline mode, it raises
+// `TIMER_VECTOR` once the TSC reaches the value in IA32_TSC_DEADLINE, which
+// `time` can use for the timer interrupt instead of the PIT (see time.rs).
+//
+// The registers are memory mapped. The bootloader maps all of physical memory
+// up to the end of the memory map, which includes the local APIC's page right
+// below 4 GiB, so they are accessed through `memory::phys_to_virt`.
+
+use crate::memory;
+use crate::mmio::RegisterBlock;
+use spin::Once;
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+const BASE_GLOBAL_ENABLE: u64 = 1 << 11;
+const BASE_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+const REGISTERS_SIZE: usize = 0x400;
+
+// Register offsets
+const ID: usize = 0x20;
+const END_OF_INTERRUPT: usize = 0xB0;
+const SPURIOUS_INTERRUPT: usize = 0xF0;
+const LVT_TIMER: usize = 0x320;
+const LVT_LINT0: usize = 0x350;
+const LVT_LINT1: usize = 0x360;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const DELIVERY_NMI: u32 = 0b100 << 8;
+const DELIVERY_EXTERNAL: u32 = 0b111 << 8;
+const TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+
+/// CPUID leaf 1, ECX
+const CPUID_TSC_DEADLINE: u32 = 1 << 24;
+
+/// The vector of spurious interrupts, which need no end of interrupt.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+/// The vector of the local APIC timer.
+pub const TIMER_VECTOR: u8 = 0xFE;
+
+/// Where MSIs are written to. Bits 12-19 of the address select the
+/// destination APIC.
+pub const MSI_ADDRESS: u64 = 0xFEE0_0000;
+
+static REGISTERS: Once<RegisterBlock> = Once::new();
+
+/// Enables the local APIC in virtual wire mode, so that it delivers MSIs
+/// without getting in the way of the PICs. Called on first use.
+fn init() -> RegisterBlock {
+    let mut base_msr = Msr::new(IA32_APIC_BASE);
+    let base = unsafe { base_msr.read() };
+    if base & BASE_GLOBAL_ENABLE == 0 {
+        unsafe { base_msr.write(base | BASE_GLOBAL_ENABLE) };
+    }
+
+    let address = memory::phys_to_virt(PhysAddr::new(base & BASE_ADDRESS_MASK));
+    let registers = unsafe { RegisterBlock::new("lapic", address, REGISTERS_SIZE) };
+    registers.write(LVT_LINT0, DELIVERY_EXTERNAL);
+    registers.write(LVT_LINT1, DELIVERY_NMI);
+    registers.write(
+        SPURIOUS_INTERRUPT,
+        SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR),
+    );
+    registers
+}
+
+fn registers() -> &'static RegisterBlock {
+    REGISTERS.call_once(init)
+}
+
+/// The ID of the current CPU's local APIC, the destination for its MSIs.
+pub fn id() -> u8 {
+    (registers().read::<u32>(ID) >> 24) as u8
+}
+
+/// The address that MSIs for the current CPU are written to.
+pub fn msi_address() -> u64 {
+    MSI_ADDRESS | u64::from(id()) << 12
+}
+
+/// Acknowledges an interrupt that came through the local APIC, i.e. an MSI.
+pub fn end_of_interrupt() {
+    registers().write(END_OF_INTERRUPT, 0u32);
+}
+
+/// Whether the local APIC timer has TSC-deadline mode.
+pub fn tsc_deadline_supported() -> bool {
+    unsafe { core::arch::x86_64::__cpuid(1) }.ecx & CPUID_TSC_DEADLINE != 0
+}
+
+/// Puts the timer in TSC-deadline mode, raising `TIMER_VECTOR`. It fires
+/// once for each `set_tsc_deadline`.
+pub fn start_tsc_deadline_timer() {
+    registers().write(LVT_TIMER, TIMER_TSC_DEADLINE | u32::from(TIMER_VECTOR));
+}
+
+/// Makes the timer fire once the TSC reaches `tsc`, or right away if it
+/// already has.
+pub fn set_tsc_deadline(tsc: u64) {
+    unsafe { Msr::new(IA32_TSC_DEADLINE).write(tsc) };
+}
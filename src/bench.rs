@@ -0,0 +1,115 @@
+// Cycle-count benchmarks, which run with the tests. A benchmark is a static
+// `Bench` marked with `#[test_case]`, since that's the one attribute the custom
+// test framework gives us:
+//
+//     #[test_case]
+//     static BOX_SMALL: Bench = Bench::new("allocator::box_small", || {
+//         black_box(Box::new(1u64));
+//     });
+//
+// It runs its routine a number of times, measuring each run with the TSC with
+// interrupts disabled, and prints one line with the fastest and the median run
+// after the test's `[ok]`:
+//
+//     bench allocator::box_small iterations=1000 min=<cycles> median=<cycles>
+//
+// That line doesn't change shape, so logs of two runs can be compared with
+// grep. The median is what to compare; the minimum shows how fast it can be
+// when nothing gets in the way. Cycles aren't comparable between machines.
+
+use crate::{time, Testable};
+use core::arch::asm;
+use core::fmt;
+use core::sync::atomic::{compiler_fence, Ordering};
+use x86_64::instructions::interrupts;
+
+/// How often a routine runs, unless the benchmark says otherwise.
+pub const DEFAULT_ITERATIONS: usize = 1000;
+
+/// The most iterations a benchmark can have, since each one's cycle count is
+/// kept on the stack until the median is known.
+pub const MAX_ITERATIONS: usize = 1024;
+
+pub struct Bench {
+    name: &'static str,
+    routine: fn(),
+    iterations: usize,
+}
+
+impl Bench {
+    pub const fn new(name: &'static str, routine: fn()) -> Bench {
+        Bench {
+            name,
+            routine,
+            iterations: DEFAULT_ITERATIONS,
+        }
+    }
+
+    /// Runs the routine `iterations` times instead, at most `MAX_ITERATIONS`.
+    pub const fn iterations(mut self, iterations: usize) -> Bench {
+        self.iterations = iterations;
+        self
+    }
+
+    /// Runs the routine and measures each run.
+    pub fn measure(&self) -> BenchResult {
+        let iterations = self.iterations.clamp(1, MAX_ITERATIONS);
+        let mut cycles = [0u64; MAX_ITERATIONS];
+
+        for sample in cycles[..iterations].iter_mut() {
+            *sample = interrupts::without_interrupts(|| {
+                let start = tsc_serialized();
+                (self.routine)();
+                tsc_serialized() - start
+            });
+        }
+
+        let cycles = &mut cycles[..iterations];
+        cycles.sort_unstable();
+        BenchResult {
+            name: self.name,
+            iterations,
+            min: cycles[0],
+            median: cycles[iterations / 2],
+        }
+    }
+}
+
+impl Testable for Bench {
+    fn run(&self) {
+        let mut result = None;
+        crate::run_test(self.name, || result = Some(self.measure()));
+        if let Some(result) = result {
+            crate::serial_println!("{}", result);
+        }
+    }
+}
+
+/// Reads the TSC once everything before it is done, and before anything after
+/// it starts, so that the routine is all that's between two reads.
+fn tsc_serialized() -> u64 {
+    compiler_fence(Ordering::SeqCst);
+    unsafe { asm!("lfence", options(nostack, preserves_flags)) };
+    let tsc = time::tsc();
+    unsafe { asm!("lfence", options(nostack, preserves_flags)) };
+    compiler_fence(Ordering::SeqCst);
+    tsc
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BenchResult {
+    pub name: &'static str,
+    pub iterations: usize,
+    pub min: u64,
+    pub median: u64,
+}
+
+impl fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "bench {} iterations={} min={} median={}",
+            self.name, self.iterations, self.min, self.median
+        )
+    }
+}
@@ -0,0 +1,275 @@
+// A Brainfuck interpreter, run by the shell's `bf <path>`. The language has
+// eight commands on a tape of byte cells:
+//
+//     > <   move the pointer right or left
+//     + -   add or subtract one from the cell, wrapping around
+//     . ,   write the cell to the console, or read a byte of input into it
+//     [ ]   jump past the matching `]` if the cell is 0, or back to the
+//           matching `[` if it isn't
+//
+// Everything else is a comment. The program is compiled first, which merges
+// runs of `+ - < >` and matches up the brackets, so that a missing one is
+// found before anything runs.
+//
+// The machine runs a slice of steps at a time and then hands back to its
+// caller, which keeps a long-running program from holding up the other tasks
+// and lets the shell wait for input when the program wants some. Input comes
+// from the console a line at a time, like for the shell itself.
+
+#[cfg(test)]
+use crate::HostTest;
+use crate::{fs, shell, task, tty};
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::fmt::{self, Write};
+
+/// How many cells the tape has, like in the original implementation.
+pub const TAPE_LEN: usize = 30_000;
+
+/// How many steps `bf` runs before it lets the other tasks have a turn.
+const STEPS_PER_SLICE: u64 = 100_000;
+
+/// How many steps a program gets unless `bf` is told otherwise, so that one
+/// that loops forever doesn't keep the shell for good.
+const DEFAULT_MAX_STEPS: u64 = 1_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Add(u8),
+    Move(isize),
+    Output,
+    Input,
+    /// Jumps to the given op if the cell is 0.
+    JumpIfZero(usize),
+    /// Jumps to the given op if the cell isn't 0.
+    JumpUnlessZero(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BfError {
+    /// A `[` or `]` without a match, at the given byte of the source.
+    UnmatchedBracket(usize),
+    /// The pointer moved off either end of the tape.
+    PointerOutOfRange,
+}
+
+impl fmt::Display for BfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BfError::UnmatchedBracket(offset) => write!(f, "unmatched bracket at {}", offset),
+            BfError::PointerOutOfRange => f.write_str("pointer moved off the tape"),
+        }
+    }
+}
+
+/// A compiled program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    ops: Vec<Op>,
+}
+
+impl Program {
+    pub fn compile(source: &[u8]) -> Result<Program, BfError> {
+        let mut ops = Vec::new();
+        // The ops of the `[`s that aren't closed yet, and where they are in the
+        // source
+        let mut open = Vec::new();
+
+        for (offset, &byte) in source.iter().enumerate() {
+            let op = match byte {
+                b'+' => Op::Add(1),
+                b'-' => Op::Add(u8::MAX),
+                b'>' => Op::Move(1),
+                b'<' => Op::Move(-1),
+                b'.' => Op::Output,
+                b',' => Op::Input,
+                b'[' => {
+                    open.push((ops.len(), offset));
+                    Op::JumpIfZero(0)
+                }
+                b']' => {
+                    let (start, _) = open.pop().ok_or(BfError::UnmatchedBracket(offset))?;
+                    ops[start] = Op::JumpIfZero(ops.len() + 1);
+                    Op::JumpUnlessZero(start + 1)
+                }
+                _ => continue,
+            };
+
+            match (ops.last_mut(), op) {
+                (Some(Op::Add(sum)), Op::Add(n)) => *sum = sum.wrapping_add(n),
+                (Some(Op::Move(sum)), Op::Move(n)) => *sum += n,
+                _ => ops.push(op),
+            }
+        }
+
+        match open.pop() {
+            Some((_, offset)) => Err(BfError::UnmatchedBracket(offset)),
+            None => Ok(Program { ops }),
+        }
+    }
+}
+
+/// Why `Machine::run` stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// The program ended.
+    Done,
+    /// The program wants a byte of input, see `Machine::input`.
+    Input,
+    /// The steps ran out; `run` carries on where it stopped.
+    Steps,
+}
+
+/// A program with its tape, part way through running.
+pub struct Machine {
+    program: Program,
+    tape: Box<[u8]>,
+    pointer: usize,
+    pc: usize,
+    steps: u64,
+}
+
+impl Machine {
+    pub fn new(program: Program) -> Machine {
+        Machine {
+            program,
+            tape: vec![0; TAPE_LEN].into_boxed_slice(),
+            pointer: 0,
+            pc: 0,
+            steps: 0,
+        }
+    }
+
+    /// How many ops have run so far.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Runs at most `steps` ops, adding the output to `output`.
+    pub fn run(&mut self, steps: u64, output: &mut Vec<u8>) -> Result<Stop, BfError> {
+        for _ in 0..steps {
+            let op = match self.program.ops.get(self.pc) {
+                Some(&op) => op,
+                None => return Ok(Stop::Done),
+            };
+            let cell = self.tape[self.pointer];
+            self.pc += 1;
+
+            match op {
+                Op::Add(n) => self.tape[self.pointer] = cell.wrapping_add(n),
+                Op::Move(n) => {
+                    self.pointer = self
+                        .pointer
+                        .checked_add_signed(n)
+                        .filter(|&pointer| pointer < TAPE_LEN)
+                        .ok_or(BfError::PointerOutOfRange)?;
+                }
+                Op::Output => output.push(cell),
+                Op::Input => {
+                    // Comes back here once the input is there
+                    self.pc -= 1;
+                    return Ok(Stop::Input);
+                }
+                Op::JumpIfZero(target) if cell == 0 => self.pc = target,
+                Op::JumpUnlessZero(target) if cell != 0 => self.pc = target,
+                Op::JumpIfZero(_) | Op::JumpUnlessZero(_) => {}
+            }
+            self.steps += 1;
+        }
+        Ok(Stop::Steps)
+    }
+
+    /// Gives the `,` that `run` stopped at its byte.
+    pub fn input(&mut self, byte: u8) {
+        if let Some(Op::Input) = self.program.ops.get(self.pc) {
+            self.tape[self.pointer] = byte;
+            self.pc += 1;
+            self.steps += 1;
+        }
+    }
+}
+
+pub fn register_commands() {
+    shell::register_async(
+        "bf",
+        "run a Brainfuck program, `bf <path> [max steps]`",
+        |args, out| Box::pin(bf(args, out)),
+    );
+}
+
+async fn bf(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    let (path, max_steps) = match args {
+        [_, path] => (*path, DEFAULT_MAX_STEPS),
+        [_, path, steps] => match steps.parse() {
+            Ok(steps) => (*path, steps),
+            Err(_) => return writeln!(out, "usage: bf <path> [max steps]"),
+        },
+        _ => return writeln!(out, "usage: bf <path> [max steps]"),
+    };
+    let program = match fs::read_file(path) {
+        Ok(source) => Program::compile(&source),
+        Err(error) => return writeln!(out, "bf: {}: {}", path, error),
+    };
+    let mut machine = match program {
+        Ok(program) => Machine::new(program),
+        Err(error) => return writeln!(out, "bf: {}: {}", path, error),
+    };
+
+    let mut output = Vec::new();
+    loop {
+        let steps = STEPS_PER_SLICE.min(max_steps.saturating_sub(machine.steps()));
+        let stop = machine.run(steps, &mut output);
+        // Programs print bytes, which are taken to be Latin-1
+        for &byte in &output {
+            out.write_char(char::from(byte))?;
+        }
+        output.clear();
+
+        match stop {
+            Ok(Stop::Done) => return Ok(()),
+            Ok(Stop::Input) => machine.input(tty::read_byte().await),
+            Ok(Stop::Steps) if machine.steps() >= max_steps => {
+                return writeln!(out, "\nbf: stopped after {} steps", max_steps)
+            }
+            Ok(Stop::Steps) => task::yield_now().await,
+            Err(error) => return writeln!(out, "\nbf: {}", error),
+        }
+    }
+}
+
+#[cfg(test)]
+fn run_to_end(source: &[u8], input: &[u8]) -> Result<Vec<u8>, BfError> {
+    let mut machine = Machine::new(Program::compile(source)?);
+    let mut input = input.iter();
+    let mut output = Vec::new();
+    loop {
+        match machine.run(u64::MAX, &mut output)? {
+            Stop::Done => return Ok(output),
+            Stop::Input => machine.input(*input.next().unwrap_or(&0)),
+            Stop::Steps => unreachable!(),
+        }
+    }
+}
+
+#[test_case]
+static RUNS_HELLO_WORLD: HostTest = HostTest::new("bf::runs_hello_world", || {
+    let hello = b"++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+    assert_eq!(run_to_end(hello, b"").unwrap(), b"Hello World!\n");
+
+    // Echoes its input up to a 0
+    assert_eq!(run_to_end(b",[.,]", b"abc").unwrap(), b"abc");
+});
+
+#[test_case]
+static COMPILES_PROGRAMS: HostTest = HostTest::new("bf::compiles_programs", || {
+    let program = Program::compile(b"+++ comment -- >><").unwrap();
+    assert_eq!(program.ops, [Op::Add(1), Op::Move(1)]);
+    let program = Program::compile(b"[-]").unwrap();
+    assert_eq!(
+        program.ops,
+        [Op::JumpIfZero(3), Op::Add(u8::MAX), Op::JumpUnlessZero(1)]
+    );
+
+    assert_eq!(Program::compile(b"+[[]"), Err(BfError::UnmatchedBracket(1)));
+    assert_eq!(Program::compile(b"+]"), Err(BfError::UnmatchedBracket(1)));
+    assert_eq!(run_to_end(b"<", b""), Err(BfError::PointerOutOfRange));
+});
@@ -0,0 +1,220 @@
+// Block devices: disks and anything that looks like one. Drivers (and the
+// RAM disk) implement `BlockDevice` and `register` their devices, so that
+// filesystems can work on top of any of them without knowing which driver is
+// underneath.
+//
+// Devices are shared through an `Arc`, so the methods take `&self` and each
+// device does its own locking.
+//
+// Registered devices also go into the device tree, whose power hooks write out
+// what they hold back before the machine sleeps or turns off.
+//
+// Each operation comes in two flavours. The asynchronous one is for tasks: a
+// driver whose device finishes requests with an interrupt returns a future
+// that waits for a `Completion`, and the executor runs other tasks meanwhile.
+// The synchronous one is for everything else, and blocks until the request is
+// done. Drivers for devices that finish right away (like the RAM disk) only
+// implement the synchronous methods, and get asynchronous ones that are ready
+// immediately.
+
+use crate::device::{self, Device, PowerError};
+use crate::sync::RwSpinLock;
+use crate::{shell, warn};
+use alloc::{boxed::Box, collections::BTreeMap, format, string::String, sync::Arc, vec::Vec};
+use core::{fmt, future::Future, pin::Pin};
+use futures_util::future;
+
+pub mod cache;
+pub mod completion;
+pub mod partitions;
+pub mod ramdisk;
+
+pub use cache::BlockCache;
+pub use completion::Completion;
+pub use ramdisk::RamDisk;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request goes past the end of the device.
+    OutOfRange,
+    /// The buffer isn't a whole number of blocks.
+    BadBufferSize,
+    ReadOnly,
+    /// The device reported an error.
+    Io,
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            BlockError::OutOfRange => "request past the end of the device",
+            BlockError::BadBufferSize => "buffer isn't a whole number of blocks",
+            BlockError::ReadOnly => "device is read-only",
+            BlockError::Io => "I/O error",
+        })
+    }
+}
+
+/// A block request that is in progress.
+pub type BlockFuture<'a> = Pin<Box<dyn Future<Output = Result<(), BlockError>> + Send + 'a>>;
+
+pub trait BlockDevice: Send + Sync {
+    /// The size of a block in bytes, usually 512.
+    fn block_size(&self) -> usize;
+
+    fn num_blocks(&self) -> u64;
+
+    /// Reads the blocks starting at block `start` into `buf`, whose length
+    /// has to be a multiple of the block size.
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buf`, whose length has to be a multiple of the block size, to
+    /// the blocks starting at block `start`.
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Like `read_blocks`, but lets other tasks run while the device works.
+    fn read_blocks_async<'a>(&'a self, start: u64, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(future::ready(self.read_blocks(start, buf)))
+    }
+
+    /// Like `write_blocks`, but lets other tasks run while the device works.
+    fn write_blocks_async<'a>(&'a self, start: u64, buf: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(future::ready(self.write_blocks(start, buf)))
+    }
+
+    /// Writes out any writes that the device (or a cache) holds back.
+    fn sync(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    /// The size of the device in bytes.
+    fn size(&self) -> u64 {
+        self.num_blocks() * self.block_size() as u64
+    }
+}
+
+/// Checks that a request for `len` bytes at block `start` is a whole number of
+/// blocks and fits on the device, for the implementations of `BlockDevice`.
+/// Returns the number of blocks.
+pub fn check_request(device: &dyn BlockDevice, start: u64, len: usize) -> Result<u64, BlockError> {
+    let count = len / device.block_size();
+    if count * device.block_size() != len {
+        return Err(BlockError::BadBufferSize);
+    }
+
+    let count = count as u64;
+    match start.checked_add(count) {
+        Some(end) if end <= device.num_blocks() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+// Looked up on every open, changed when a disk or partition shows up.
+static DEVICES: RwSpinLock<BTreeMap<String, Arc<dyn BlockDevice>>> =
+    RwSpinLock::new_irq_safe(BTreeMap::new());
+
+/// Adds a device and returns the name it was given: `kind` followed by the
+/// first number that isn't taken yet, e.g. "ram0".
+pub fn register(kind: &str, device: Arc<dyn BlockDevice>) -> String {
+    let mut devices = DEVICES.write();
+    let name = (0..)
+        .map(|i| format!("{}{}", kind, i))
+        .find(|name| !devices.contains_key(name))
+        .unwrap();
+
+    devices.insert(name.clone(), device.clone());
+    drop(devices);
+
+    device::add(
+        None,
+        Arc::new(Disk {
+            name: name.clone(),
+            device,
+        }),
+    );
+    name
+}
+
+/// A registered device, in the device tree.
+struct Disk {
+    name: String,
+    device: Arc<dyn BlockDevice>,
+}
+
+impl Device for Disk {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn describe(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        write!(
+            out,
+            "{} blocks of {} bytes",
+            self.device.num_blocks(),
+            self.device.block_size()
+        )
+    }
+
+    fn suspend(&self) -> Result<(), PowerError> {
+        self.device.sync().map_err(|_| PowerError::Io)
+    }
+
+    fn shutdown(&self) -> Result<(), PowerError> {
+        self.suspend()
+    }
+}
+
+/// Adds a device under the given name, replacing any device of that name.
+fn insert(name: String, device: Arc<dyn BlockDevice>) {
+    DEVICES.write().insert(name, device);
+}
+
+/// Returns the device with the given name.
+pub fn get(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.read().get(name).cloned()
+}
+
+/// Returns all devices, sorted by name.
+pub fn devices() -> Vec<(String, Arc<dyn BlockDevice>)> {
+    DEVICES
+        .read()
+        .iter()
+        .map(|(name, device)| (name.clone(), device.clone()))
+        .collect()
+}
+
+/// Writes out the held-back writes of all devices. Tries all of them, and
+/// returns the first error.
+pub fn sync() -> Result<(), BlockError> {
+    let mut result = Ok(());
+    for (name, device) in devices() {
+        if let Err(error) = device.sync() {
+            warn!("{}: can't sync: {}", name, error);
+            result = result.and(Err(error));
+        }
+    }
+    result
+}
+
+pub fn register_commands() {
+    shell::register("lsblk", "list the block devices", |_args, out| {
+        for (name, device) in devices() {
+            writeln!(
+                out,
+                "{:<8} {:>10} blocks of {} bytes ({} KiB)",
+                name,
+                device.num_blocks(),
+                device.block_size(),
+                device.size() / 1024
+            )?;
+        }
+        Ok(())
+    });
+
+    shell::register("sync", "write cached data to the disks", |_args, out| {
+        if let Err(error) = sync() {
+            writeln!(out, "sync: {}", error)?;
+        }
+        Ok(())
+    });
+}
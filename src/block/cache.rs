@@ -0,0 +1,266 @@
+// A cache in front of a block device, so that filesystems can read the same
+// blocks (FATs, directories, inodes...) over and over without going to the
+// disk each time.
+//
+// Recently used blocks are kept in memory, up to a fixed number of them. Reads
+// are served from the cache where possible, and the blocks that are missing
+// are read in runs. Writes only go to the cache and mark the blocks dirty
+// ("write-back"). Dirty blocks are written to the device when they are evicted
+// to make room, or when `sync` is called, which writes them out in runs of
+// consecutive blocks.
+//
+// Since writes are delayed, so are their errors: writing back a block can fail
+// in any call that evicts it, or in `sync`. Only reads of missing blocks are
+// asynchronous; writes (and the write-backs they cause) never have to wait
+// long enough to bother.
+//
+// The device is never called with the cache locked, since the lock is taken
+// with interrupts disabled, and a driver may need an interrupt to finish the
+// request. A dirty block stays cached, and readable, while it's written back,
+// and it's only evicted once it's clean.
+
+use super::{check_request, BlockDevice, BlockError, BlockFuture};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+use core::ops::Range;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Enough for a FAT and a few directories.
+pub const DEFAULT_CAPACITY: usize = 256;
+
+struct Entry {
+    data: Vec<u8>,
+    dirty: bool,
+    // When the block was last used, for evicting the least recently used one
+    last_used: u64,
+}
+
+struct Cache {
+    blocks: BTreeMap<u64, Entry>,
+    // Counts accesses, to order them
+    clock: u64,
+}
+
+pub struct BlockCache {
+    device: Arc<dyn BlockDevice>,
+    capacity: usize,
+    cache: Mutex<Cache>,
+}
+
+impl BlockCache {
+    /// Caches up to `capacity` blocks of `device`. Once the cache is in use,
+    /// the device shouldn't be accessed directly anymore.
+    pub fn new(device: Arc<dyn BlockDevice>, capacity: usize) -> BlockCache {
+        assert!(capacity > 0, "a block cache needs room for a block");
+
+        BlockCache {
+            device,
+            capacity,
+            cache: Mutex::new(Cache {
+                blocks: BTreeMap::new(),
+                clock: 0,
+            }),
+        }
+    }
+
+    /// The number of dirty blocks, which haven't been written to the device
+    /// yet.
+    pub fn dirty_blocks(&self) -> usize {
+        self.locked(|cache| cache.blocks.values().filter(|entry| entry.dirty).count())
+    }
+
+    /// Adds the blocks in `data`, starting at block `start`, and writes back
+    /// the dirty blocks that have to make room for them.
+    fn insert(&self, start: u64, data: &[u8], dirty: bool) -> Result<(), BlockError> {
+        let block_size = self.block_size();
+        for (i, chunk) in data.chunks(block_size).enumerate() {
+            let block = start + i as u64;
+            // Until the oldest block is a clean one
+            while let Some((oldest, data)) =
+                self.locked(|cache| cache.insert(self.capacity, block, chunk, dirty))
+            {
+                // The block stays cached if it can't be written back.
+                self.device.write_blocks(oldest, &data)?;
+                self.locked(|cache| cache.written(oldest, &data, block_size));
+            }
+        }
+        Ok(())
+    }
+
+    fn locked<R>(&self, f: impl FnOnce(&mut Cache) -> R) -> R {
+        interrupts::without_interrupts(|| f(&mut self.cache.lock()))
+    }
+}
+
+impl Cache {
+    /// Copies the cached blocks of a request into `buf`, and returns the
+    /// missing ones.
+    fn read_cached(&mut self, start: u64, buf: &mut [u8], block_size: usize) -> Vec<u64> {
+        let mut missing = Vec::new();
+        for (i, chunk) in buf.chunks_mut(block_size).enumerate() {
+            let block = start + i as u64;
+            if !self.read(block, chunk) {
+                missing.push(block);
+            }
+        }
+        missing
+    }
+
+    /// Copies a cached block into `buf`, and returns whether it was cached.
+    fn read(&mut self, block: u64, buf: &mut [u8]) -> bool {
+        self.clock += 1;
+        let clock = self.clock;
+
+        match self.blocks.get_mut(&block) {
+            Some(entry) => {
+                entry.last_used = clock;
+                buf.copy_from_slice(&entry.data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Adds or replaces a block, evicting the least recently used block if
+    /// the cache is full. If that one is dirty, nothing happens, and it's
+    /// returned with its data instead, to be written back first.
+    fn insert(
+        &mut self,
+        capacity: usize,
+        block: u64,
+        data: &[u8],
+        dirty: bool,
+    ) -> Option<(u64, Vec<u8>)> {
+        self.clock += 1;
+        let entry = Entry {
+            data: data.to_vec(),
+            dirty,
+            last_used: self.clock,
+        };
+
+        if let Some(old) = self.blocks.get_mut(&block) {
+            // A clean copy doesn't replace changes that weren't written yet.
+            if dirty || !old.dirty {
+                *old = entry;
+            }
+            return None;
+        }
+
+        if self.blocks.len() >= capacity {
+            let oldest = self.blocks.iter().min_by_key(|(_, entry)| entry.last_used);
+            if let Some((&oldest, entry)) = oldest {
+                if entry.dirty {
+                    return Some((oldest, entry.data.clone()));
+                }
+                self.blocks.remove(&oldest);
+            }
+        }
+        self.blocks.insert(block, entry);
+        None
+    }
+
+    /// Copies the dirty blocks, in runs of consecutive blocks, to be written
+    /// back.
+    fn dirty_runs(&self) -> Vec<(u64, Vec<u8>)> {
+        let dirty: Vec<u64> = self
+            .blocks
+            .iter()
+            .filter(|(_, entry)| entry.dirty)
+            .map(|(&block, _)| block)
+            .collect();
+
+        runs(&dirty)
+            .into_iter()
+            .map(|run| {
+                let mut data = Vec::new();
+                for block in run.clone() {
+                    data.extend_from_slice(&self.blocks[&block].data);
+                }
+                (run.start, data)
+            })
+            .collect()
+    }
+
+    /// Marks the blocks starting at `start` clean, now that `data` was
+    /// written back, unless they changed again meanwhile.
+    fn written(&mut self, start: u64, data: &[u8], block_size: usize) {
+        for (i, chunk) in data.chunks(block_size).enumerate() {
+            if let Some(entry) = self.blocks.get_mut(&(start + i as u64)) {
+                if entry.data == chunk {
+                    entry.dirty = false;
+                }
+            }
+        }
+    }
+}
+
+/// Splits sorted block numbers into runs of consecutive blocks.
+fn runs(blocks: &[u64]) -> Vec<Range<u64>> {
+    let mut runs: Vec<Range<u64>> = Vec::new();
+    for &block in blocks {
+        match runs.last_mut() {
+            Some(run) if run.end == block => run.end += 1,
+            _ => runs.push(block..block + 1),
+        }
+    }
+    runs
+}
+
+impl BlockDevice for BlockCache {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.device.num_blocks()
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, start, buf.len())?;
+        let block_size = self.block_size();
+
+        let missing = self.locked(|cache| cache.read_cached(start, buf, block_size));
+        for run in runs(&missing) {
+            let offset = (run.start - start) as usize * block_size;
+            let len = (run.end - run.start) as usize * block_size;
+            let data = &mut buf[offset..offset + len];
+            self.device.read_blocks(run.start, data)?;
+            self.insert(run.start, data, false)?;
+        }
+        Ok(())
+    }
+
+    fn read_blocks_async<'a>(&'a self, start: u64, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            check_request(self, start, buf.len())?;
+            let block_size = self.block_size();
+            let device = &*self.device;
+
+            let missing = self.locked(|cache| cache.read_cached(start, buf, block_size));
+            for run in runs(&missing) {
+                let offset = (run.start - start) as usize * block_size;
+                let len = (run.end - run.start) as usize * block_size;
+                let data = &mut buf[offset..offset + len];
+                device.read_blocks_async(run.start, data).await?;
+                self.insert(run.start, data, false)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self, start, buf.len())?;
+        self.insert(start, buf, true)
+    }
+
+    /// Writes all dirty blocks to the device, consecutive blocks with a single
+    /// write.
+    fn sync(&self) -> Result<(), BlockError> {
+        let block_size = self.block_size();
+        for (start, data) in self.locked(|cache| cache.dirty_runs()) {
+            self.device.write_blocks(start, &data)?;
+            self.locked(|cache| cache.written(start, &data, block_size));
+        }
+        self.device.sync()
+    }
+}
@@ -0,0 +1,120 @@
+// The end of a block request that a device works on by itself (DMA) and that
+// it reports with an interrupt.
+//
+// A driver creates a `Completion` for each request it starts, and its interrupt
+// handler calls `complete` when the device is done. The task that issued the
+// request `wait`s for it, and is woken by `complete` through the executor, the
+// same way the keyboard and serial interrupts wake the tasks reading them. Code
+// that doesn't run in a task (the synchronous `BlockDevice` methods, system
+// calls) uses `wait_blocking` instead, which halts the CPU until an interrupt
+// has finished the request.
+
+use super::BlockError;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+use x86_64::instructions::{self, interrupts};
+
+// The states of a request, and the errors it can end with
+const PENDING: u8 = 0;
+const DONE: u8 = 1;
+const OUT_OF_RANGE: u8 = 2;
+const BAD_BUFFER_SIZE: u8 = 3;
+const READ_ONLY: u8 = 4;
+const IO: u8 = 5;
+
+pub struct Completion {
+    state: AtomicU8,
+    waker: AtomicWaker,
+}
+
+impl Completion {
+    pub const fn new() -> Completion {
+        Completion {
+            state: AtomicU8::new(PENDING),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    /// Finishes the request and wakes whoever is waiting for it. Called by
+    /// interrupt handlers, so it doesn't block or allocate.
+    pub fn complete(&self, result: Result<(), BlockError>) {
+        let state = match result {
+            Ok(()) => DONE,
+            Err(BlockError::OutOfRange) => OUT_OF_RANGE,
+            Err(BlockError::BadBufferSize) => BAD_BUFFER_SIZE,
+            Err(BlockError::ReadOnly) => READ_ONLY,
+            Err(BlockError::Io) => IO,
+        };
+        self.state.store(state, Ordering::Release);
+        self.waker.wake();
+    }
+
+    /// Returns the result of the request if it is finished.
+    pub fn result(&self) -> Option<Result<(), BlockError>> {
+        match self.state.load(Ordering::Acquire) {
+            PENDING => None,
+            DONE => Some(Ok(())),
+            OUT_OF_RANGE => Some(Err(BlockError::OutOfRange)),
+            BAD_BUFFER_SIZE => Some(Err(BlockError::BadBufferSize)),
+            READ_ONLY => Some(Err(BlockError::ReadOnly)),
+            _ => Some(Err(BlockError::Io)),
+        }
+    }
+
+    /// Waits for the request to finish, letting other tasks run meanwhile.
+    pub fn wait(&self) -> Wait<'_> {
+        Wait { completion: self }
+    }
+
+    /// Waits for the request to finish outside of a task. With interrupts
+    /// enabled, the CPU sleeps until the next interrupt instead of spinning.
+    pub fn wait_blocking(&self) -> Result<(), BlockError> {
+        loop {
+            if let Some(result) = self.result() {
+                return result;
+            }
+
+            // An interrupt between the check and `hlt` only delays us until
+            // the next one, at worst the next timer tick.
+            if interrupts::are_enabled() {
+                instructions::hlt();
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+impl Default for Completion {
+    fn default() -> Self {
+        Completion::new()
+    }
+}
+
+pub struct Wait<'a> {
+    completion: &'a Completion,
+}
+
+impl Future for Wait<'_> {
+    type Output = Result<(), BlockError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // Fast path
+        if let Some(result) = self.completion.result() {
+            return Poll::Ready(result);
+        }
+
+        self.completion.waker.register(cx.waker());
+
+        match self.completion.result() {
+            Some(result) => {
+                self.completion.waker.take();
+                Poll::Ready(result)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,292 @@
+// Partition tables. A disk starts with an MBR, whose last two bytes are the
+// signature 0x55 0xAA, and which holds four primary partition entries. A GPT
+// disk has a "protective" MBR with a single partition of type 0xEE covering the
+// disk, followed by the GPT header in block 1, which points to an array of
+// (usually 128) partition entries.
+//
+// Each partition becomes a block device of its own, named after its disk and
+// its number in the table, e.g. "ata0p1", so that filesystems don't need to
+// know about partitions at all.
+//
+// We don't verify the GPT checksums, nor fall back to the backup GPT at the
+// end of the disk.
+
+use super::{check_request, BlockDevice, BlockError, BlockFuture};
+use crate::{info, warn};
+use alloc::{boxed::Box, collections::BTreeSet, format, string::String, sync::Arc, vec, vec::Vec};
+use core::fmt;
+use futures_util::future;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const MBR_SIZE: usize = 512;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_ENTRIES_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_TYPE_GPT: u8 = 0xEE;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_ENTRY_MIN_SIZE: usize = 128;
+/// More entries than this are ignored, so that a corrupted header can't make
+/// us read the whole disk.
+const GPT_MAX_ENTRIES: usize = 256;
+
+/// A GUID, as stored on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl fmt::Display for Guid {
+    /// The usual text form, in which the first three fields are little-endian.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-",
+            b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9]
+        )?;
+        for byte in &b[10..] {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionKind {
+    /// The partition type byte of an MBR entry.
+    Mbr(u8),
+    Gpt {
+        type_guid: Guid,
+        name: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionInfo {
+    /// The position in the table, starting at 1.
+    pub number: usize,
+    pub kind: PartitionKind,
+    /// The first block, in the disk's blocks.
+    pub start: u64,
+    pub num_blocks: u64,
+}
+
+impl fmt::Display for PartitionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The table may say anything, even that the end is past 2^64
+        match self.start.checked_add(self.num_blocks) {
+            Some(end) => write!(f, "#{} blocks {}..{}", self.number, self.start, end)?,
+            None => write!(
+                f,
+                "#{} {} blocks at {}",
+                self.number, self.num_blocks, self.start
+            )?,
+        }
+        match &self.kind {
+            PartitionKind::Mbr(kind) => write!(f, " type {:#04x}", kind),
+            PartitionKind::Gpt { type_guid, name } => {
+                write!(f, " type {} \"{}\"", type_guid, name)
+            }
+        }
+    }
+}
+
+/// Reads the partition table of a disk. A disk without one has no partitions.
+pub fn read_table(disk: &dyn BlockDevice) -> Result<Vec<PartitionInfo>, BlockError> {
+    if disk.block_size() < MBR_SIZE {
+        return Ok(Vec::new());
+    }
+
+    let mut mbr = vec![0; disk.block_size()];
+    disk.read_blocks(0, &mut mbr)?;
+    if mbr[MBR_SIZE - 2..MBR_SIZE] != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+
+    let entries: Vec<&[u8]> = mbr[MBR_ENTRIES_OFFSET..MBR_SIZE - 2]
+        .chunks_exact(MBR_ENTRY_SIZE)
+        .collect();
+    if entries.iter().any(|entry| entry[4] == MBR_TYPE_GPT) {
+        return read_gpt(disk);
+    }
+
+    Ok(entries
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| entry[4] != 0)
+        .map(|(i, entry)| PartitionInfo {
+            number: i + 1,
+            kind: PartitionKind::Mbr(entry[4]),
+            start: u32_at(entry, 8).into(),
+            num_blocks: u32_at(entry, 12).into(),
+        })
+        .filter(|partition| partition.num_blocks != 0)
+        .collect())
+}
+
+fn read_gpt(disk: &dyn BlockDevice) -> Result<Vec<PartitionInfo>, BlockError> {
+    let block_size = disk.block_size();
+    let mut header = vec![0; block_size];
+    disk.read_blocks(1, &mut header)?;
+
+    if &header[..8] != GPT_SIGNATURE {
+        warn!("protective MBR, but no GPT header");
+        return Ok(Vec::new());
+    }
+    let entries_start = u64_at(&header, 72);
+    let num_entries = (u32_at(&header, 80) as usize).min(GPT_MAX_ENTRIES);
+    let entry_size = u32_at(&header, 84) as usize;
+    if entry_size < GPT_ENTRY_MIN_SIZE || entry_size > block_size {
+        warn!("GPT entry size {} not supported", entry_size);
+        return Ok(Vec::new());
+    }
+
+    let blocks = x86_64::align_up((num_entries * entry_size) as u64, block_size as u64);
+    let mut entries = vec![0; blocks as usize];
+    disk.read_blocks(entries_start, &mut entries)?;
+
+    Ok(entries
+        .chunks_exact(entry_size)
+        .take(num_entries)
+        .enumerate()
+        .filter(|(_, entry)| entry[..16].iter().any(|&byte| byte != 0))
+        .filter_map(|(i, entry)| {
+            let start = u64_at(entry, 32);
+            let last = u64_at(entry, 40);
+            let mut type_guid = [0; 16];
+            type_guid.copy_from_slice(&entry[..16]);
+
+            // The name is UTF-16, padded with zeroes.
+            let name = char::decode_utf16(
+                entry[56..128]
+                    .chunks_exact(2)
+                    .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                    .take_while(|&unit| unit != 0),
+            )
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+
+            Some(PartitionInfo {
+                number: i + 1,
+                kind: PartitionKind::Gpt {
+                    type_guid: Guid(type_guid),
+                    name,
+                },
+                start,
+                num_blocks: last.checked_sub(start)?.checked_add(1)?,
+            })
+        })
+        .collect())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(value)
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(value)
+}
+
+/// A part of a disk, as a block device of its own.
+pub struct Partition {
+    disk: Arc<dyn BlockDevice>,
+    start: u64,
+    num_blocks: u64,
+}
+
+impl Partition {
+    /// Returns `None` if the partition doesn't fit on the disk.
+    pub fn new(disk: Arc<dyn BlockDevice>, info: &PartitionInfo) -> Option<Partition> {
+        let end = info.start.checked_add(info.num_blocks)?;
+        if end > disk.num_blocks() {
+            return None;
+        }
+
+        Some(Partition {
+            disk,
+            start: info.start,
+            num_blocks: info.num_blocks,
+        })
+    }
+}
+
+impl BlockDevice for Partition {
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, start, buf.len())?;
+        self.disk.read_blocks(self.start + start, buf)
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self, start, buf.len())?;
+        self.disk.write_blocks(self.start + start, buf)
+    }
+
+    fn read_blocks_async<'a>(&'a self, start: u64, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        if let Err(error) = check_request(self, start, buf.len()) {
+            return Box::pin(future::ready(Err(error)));
+        }
+        self.disk.read_blocks_async(self.start + start, buf)
+    }
+
+    fn write_blocks_async<'a>(&'a self, start: u64, buf: &'a [u8]) -> BlockFuture<'a> {
+        if let Err(error) = check_request(self, start, buf.len()) {
+            return Box::pin(future::ready(Err(error)));
+        }
+        self.disk.write_blocks_async(self.start + start, buf)
+    }
+
+    fn sync(&self) -> Result<(), BlockError> {
+        self.disk.sync()
+    }
+}
+
+// The disks that have been scanned, and the partitions found on them,
+// which aren't scanned themselves.
+static SCANNED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Reads the partition table of the disk registered as `name`, registers its
+/// partitions as block devices, and returns their names.
+pub fn scan(name: &str, disk: &Arc<dyn BlockDevice>) -> Result<Vec<String>, BlockError> {
+    let mut names = Vec::new();
+
+    for info in read_table(&**disk)? {
+        let partition_name = format!("{}p{}", name, info.number);
+        match Partition::new(disk.clone(), &info) {
+            Some(partition) => {
+                info!("{}: {}", partition_name, info);
+                interrupts::without_interrupts(|| SCANNED.lock().insert(partition_name.clone()));
+                super::insert(partition_name.clone(), Arc::new(partition));
+                names.push(partition_name);
+            }
+            None => warn!("{}: {} is past the end of the disk", partition_name, info),
+        }
+    }
+
+    interrupts::without_interrupts(|| SCANNED.lock().insert(String::from(name)));
+    Ok(names)
+}
+
+/// Scans all registered disks that haven't been scanned yet, see `scan`.
+pub fn scan_all() {
+    for (name, disk) in super::devices() {
+        if interrupts::without_interrupts(|| SCANNED.lock().contains(&name)) {
+            continue;
+        }
+
+        if let Err(error) = scan(&name, &disk) {
+            warn!("{}: can't read partition table: {}", name, error);
+        }
+    }
+}
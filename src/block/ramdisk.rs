@@ -0,0 +1,79 @@
+// A block device in memory, for testing filesystems, and for filesystem images
+// that are loaded into memory, e.g. an initrd.
+
+use super::{check_request, BlockDevice, BlockError};
+use alloc::{vec, vec::Vec};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub struct RamDisk {
+    block_size: usize,
+    num_blocks: u64,
+    data: Mutex<Vec<u8>>,
+    read_only: bool,
+}
+
+impl RamDisk {
+    /// Creates a zeroed RAM disk.
+    pub fn new(block_size: usize, num_blocks: u64) -> RamDisk {
+        RamDisk {
+            block_size,
+            num_blocks,
+            data: Mutex::new(vec![0; block_size * num_blocks as usize]),
+            read_only: false,
+        }
+    }
+
+    /// Creates a RAM disk with the given contents, padded with zeroes to a
+    /// whole number of blocks.
+    pub fn from_bytes(block_size: usize, mut data: Vec<u8>) -> RamDisk {
+        let size = x86_64::align_up(data.len() as u64, block_size as u64) as usize;
+        data.resize(size, 0);
+
+        RamDisk {
+            block_size,
+            num_blocks: (size / block_size) as u64,
+            data: Mutex::new(data),
+            read_only: false,
+        }
+    }
+
+    /// Makes writes fail with `BlockError::ReadOnly`.
+    pub fn read_only(mut self) -> RamDisk {
+        self.read_only = true;
+        self
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, start, buf.len())?;
+        let offset = start as usize * self.block_size;
+
+        interrupts::without_interrupts(|| {
+            buf.copy_from_slice(&self.data.lock()[offset..offset + buf.len()]);
+        });
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        check_request(self, start, buf.len())?;
+        let offset = start as usize * self.block_size;
+
+        interrupts::without_interrupts(|| {
+            self.data.lock()[offset..offset + buf.len()].copy_from_slice(buf);
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+// The kernel command line: `key=value` options and bare `flag`s, separated by
+// spaces, like Linux's. A value with spaces in it can be put in double quotes,
+// `key="a b"`. If an option is given twice, the last one counts.
+//
+// Neither version of the bootloader passes a command line, so the kernel's own
+// is baked in when it's built, from the KERNEL_CMDLINE environment variable:
+//
+//     KERNEL_CMDLINE="log=debug heap=4M" cargo run
+//
+// `set` replaces it, for a bootloader that does pass one.
+//
+// Subsystems look up their own options:
+//
+//     log=<level>[,<target>=<level>...]   log levels (logger.rs)
+//     console=serial|vga|both             where the log goes (logger.rs)
+//     heap=<size>[K|M|G]                  the heap's size (allocator.rs)
+//     clocksource=pit|hpet|tsc-deadline   what raises the timer interrupt
+//                                         (time.rs)
+//     serial=plain|framed                 how the serial port is shared
+//                                         (serial/mux.rs)
+//     ntp=<address>                       the NTP server that keeps the wall
+//                                         clock right (time/sntp.rs)
+//     idle=hlt|shallow                    idle with HLT only, or never deeper
+//                                         than C1 (idle.rs)
+//     nokaslr                             keep the heap and the kernel stacks
+//                                         at fixed addresses (memory/layout.rs)
+//     test                                exit QEMU once booted (main.rs)
+//     test_timeout=<seconds>              how long a test may take (lib.rs)
+//     seed=<n>                            the seed of randomized tests
+//                                         (tests/heap_stress.rs)
+//
+// The ones that have a default set when the kernel is built, like the heap's
+// size, take it from config.rs.
+//
+// Nothing here allocates, since the heap's size comes from here too.
+
+use crate::shell;
+#[cfg(test)]
+use crate::HostTest;
+use core::str::FromStr;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// The command line the kernel was built with.
+pub const BUILT_IN: &str = match option_env!("KERNEL_CMDLINE") {
+    Some(cmdline) => cmdline,
+    None => "",
+};
+
+static CMDLINE: Mutex<&'static str> = Mutex::new(BUILT_IN);
+
+/// Replaces the command line.
+pub fn set(cmdline: &'static str) {
+    interrupts::without_interrupts(|| *CMDLINE.lock() = cmdline);
+}
+
+/// The whole command line.
+pub fn get() -> &'static str {
+    interrupts::without_interrupts(|| *CMDLINE.lock())
+}
+
+/// The options on the command line, in order: the key, and the value if
+/// there is one.
+pub fn options() -> Options {
+    Options { rest: get() }
+}
+
+pub struct Options {
+    rest: &'static str,
+}
+
+impl Iterator for Options {
+    type Item = (&'static str, Option<&'static str>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self.rest.trim_start();
+        if rest.is_empty() {
+            return None;
+        }
+
+        let mut quoted = false;
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    quoted = !quoted;
+                }
+                c.is_whitespace() && !quoted
+            })
+            .map_or(rest.len(), |(i, _)| i);
+        let (option, rest) = rest.split_at(end);
+        self.rest = rest;
+
+        Some(match option.find('=') {
+            Some(i) => {
+                let value = &option[i + 1..];
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|value| value.strip_suffix('"'))
+                    .unwrap_or(value);
+                (&option[..i], Some(value))
+            }
+            None => (option, None),
+        })
+    }
+}
+
+/// The value of the option `key`, if it's on the command line with one.
+pub fn value(key: &str) -> Option<&'static str> {
+    options()
+        .filter(|&(k, _)| k == key)
+        .last()
+        .and_then(|(_, value)| value)
+}
+
+/// The value of the option `key`, parsed. `None` if it's missing or doesn't
+/// parse.
+pub fn parse<T: FromStr>(key: &str) -> Option<T> {
+    value(key)?.parse().ok()
+}
+
+/// Whether the flag `key` is on: given without a value, or with one like
+/// "1", "on", "yes" or "true".
+pub fn flag(key: &str) -> bool {
+    match options().filter(|&(k, _)| k == key).last() {
+        Some((_, None)) => true,
+        Some((_, Some(value))) => ["1", "on", "yes", "true"]
+            .iter()
+            .any(|on| value.eq_ignore_ascii_case(on)),
+        None => false,
+    }
+}
+
+/// The value of the option `key` as a size in bytes, like "64K", "4M" or
+/// "4096".
+pub fn size(key: &str) -> Option<usize> {
+    parse_size(value(key)?)
+}
+
+/// Parses a size in bytes, with an optional K, M or G suffix.
+pub fn parse_size(size: &str) -> Option<usize> {
+    let (number, unit) = match size.char_indices().last()? {
+        (i, 'k') | (i, 'K') => (&size[..i], 1 << 10),
+        (i, 'm') | (i, 'M') => (&size[..i], 1 << 20),
+        (i, 'g') | (i, 'G') => (&size[..i], 1 << 30),
+        _ => (size, 1),
+    };
+    number.parse::<usize>().ok()?.checked_mul(unit)
+}
+
+pub fn register_commands() {
+    shell::register("cmdline", "print the kernel command line", |_args, out| {
+        writeln!(out, "{}", get())
+    });
+}
+
+#[test_case]
+static PARSES_SIZES: HostTest = HostTest::new("cmdline::parses_sizes", || {
+    assert_eq!(parse_size("4096"), Some(4096));
+    assert_eq!(parse_size("64K"), Some(64 << 10));
+    assert_eq!(parse_size("4m"), Some(4 << 20));
+    assert_eq!(parse_size("1G"), Some(1 << 30));
+    assert_eq!(parse_size(""), None);
+    assert_eq!(parse_size("M"), None);
+    assert_eq!(parse_size("4T"), None);
+});
@@ -0,0 +1,192 @@
+// The kernel's tunables, all in one place. Each has a default here, which can
+// be changed when the kernel is built, through an environment variable named
+// like the constant with KERNEL_ in front:
+//
+//     KERNEL_HEAP_SIZE=1M KERNEL_LOG_LEVEL=debug cargo run
+//
+//     KERNEL_HEAP_SIZE=<size>[K|M|G]          the heap (allocator.rs)
+//     KERNEL_LOG_LEVEL=<level>                the log level of targets without
+//                                             a filter (logger.rs)
+//     KERNEL_CONSOLE=serial|vga|both          where the log goes (logger.rs)
+//     KERNEL_LOG_RING_SIZE=<size>[K|M|G]      the log kept for `dmesg`
+//     KERNEL_SCANCODE_QUEUE_SIZE=<n>          scancodes waiting to be decoded
+//                                             (task/keyboard.rs)
+//     KERNEL_SCANCODE_QUEUE_MAX_SIZE=<n>      how big that queue can grow when
+//                                             it keeps overflowing
+//     KERNEL_TASK_QUEUE_SIZE=<n>              tasks woken and waiting to run
+//                                             (task/executor.rs)
+//     KERNEL_KASSERT=panic|continue           what a failed `kassert!` does
+//                                             (kassert.rs)
+//
+// Values are parsed at compile time, so one that doesn't parse fails the
+// build, as does a heap size that isn't a whole number of pages or an empty
+// queue. The heap size, the log level and the console can still be changed
+// when booting, with the options of the same name on the kernel command line
+// (see cmdline.rs), which win over the ones here. `config` prints them all.
+//
+// Bigger queues drop less input under load, at the price of memory that's
+// reserved in statics whether it's used or not.
+
+use crate::kassert;
+use crate::logger::{sink, Level};
+use crate::shell;
+#[cfg(test)]
+use crate::HostTest;
+
+pub const HEAP_SIZE: usize = number(option_env!("KERNEL_HEAP_SIZE"), 100 * 1024);
+
+pub const LOG_LEVEL: Level = level(option_env!("KERNEL_LOG_LEVEL"), Level::Info);
+
+/// The `logger::sink`s that get the log.
+pub const CONSOLE: u8 = console(option_env!("KERNEL_CONSOLE"), sink::ALL);
+
+pub const LOG_RING_SIZE: usize = number(option_env!("KERNEL_LOG_RING_SIZE"), 16 * 1024);
+
+pub const SCANCODE_QUEUE_SIZE: usize = number(option_env!("KERNEL_SCANCODE_QUEUE_SIZE"), 100);
+
+/// No bigger than `SCANCODE_QUEUE_SIZE` means the queue never grows.
+pub const SCANCODE_QUEUE_MAX_SIZE: usize =
+    number(option_env!("KERNEL_SCANCODE_QUEUE_MAX_SIZE"), 1000);
+
+pub const TASK_QUEUE_SIZE: usize = number(option_env!("KERNEL_TASK_QUEUE_SIZE"), 100);
+
+pub const KASSERT: kassert::Mode =
+    kassert_mode(option_env!("KERNEL_KASSERT"), kassert::Mode::Panic);
+
+const _: () = assert!(
+    HEAP_SIZE % 4096 == 0,
+    "the heap must be a whole number of pages"
+);
+const _: () = assert!(
+    SCANCODE_QUEUE_SIZE > 0 && TASK_QUEUE_SIZE > 0,
+    "the queues can't be empty"
+);
+
+/// Parses a number, with an optional K, M or G suffix like `cmdline::size`,
+/// or returns `default` if there's none. Panics if it doesn't parse, which
+/// at compile time fails the build.
+const fn number(value: Option<&str>, default: usize) -> usize {
+    let bytes = match value {
+        Some(value) => value.as_bytes(),
+        None => return default,
+    };
+    let (digits, unit) = match bytes {
+        [.., b'k' | b'K'] => (bytes.len() - 1, 1 << 10),
+        [.., b'm' | b'M'] => (bytes.len() - 1, 1 << 20),
+        [.., b'g' | b'G'] => (bytes.len() - 1, 1 << 30),
+        _ => (bytes.len(), 1),
+    };
+    assert!(digits > 0, "not a number");
+
+    let mut number: usize = 0;
+    let mut i = 0;
+    while i < digits {
+        assert!(bytes[i].is_ascii_digit(), "not a number");
+        number = number * 10 + (bytes[i] - b'0') as usize;
+        i += 1;
+    }
+    number * unit
+}
+
+const fn eq_ignore_ascii_case(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if !a[i].eq_ignore_ascii_case(&b[i]) {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+const fn level(value: Option<&str>, default: Level) -> Level {
+    let value = match value {
+        Some(value) => value,
+        None => return default,
+    };
+    let levels = [
+        ("error", Level::Error),
+        ("warn", Level::Warn),
+        ("info", Level::Info),
+        ("debug", Level::Debug),
+        ("trace", Level::Trace),
+    ];
+    let mut i = 0;
+    while i < levels.len() {
+        if eq_ignore_ascii_case(value, levels[i].0) {
+            return levels[i].1;
+        }
+        i += 1;
+    }
+    panic!("not a log level");
+}
+
+const fn console(value: Option<&str>, default: u8) -> u8 {
+    let value = match value {
+        Some(value) => value,
+        None => return default,
+    };
+    if eq_ignore_ascii_case(value, "serial") {
+        sink::SERIAL | sink::RING
+    } else if eq_ignore_ascii_case(value, "vga") {
+        sink::VGA | sink::RING
+    } else if eq_ignore_ascii_case(value, "both") {
+        sink::ALL
+    } else {
+        panic!("not a console")
+    }
+}
+
+const fn kassert_mode(value: Option<&str>, default: kassert::Mode) -> kassert::Mode {
+    match value {
+        None => default,
+        Some(value) if eq_ignore_ascii_case(value, "panic") => kassert::Mode::Panic,
+        Some(value) if eq_ignore_ascii_case(value, "continue") => kassert::Mode::Continue,
+        Some(_) => panic!("not a kassert mode"),
+    }
+}
+
+pub fn register_commands() {
+    shell::register(
+        "config",
+        "print the kernel's build-time settings",
+        |_args, out| {
+            let console = match CONSOLE & (sink::SERIAL | sink::VGA) {
+                sink::SERIAL => "serial",
+                sink::VGA => "vga",
+                _ => "both",
+            };
+            writeln!(out, "heap size           {} KiB", HEAP_SIZE / 1024)?;
+            writeln!(out, "log level           {}", LOG_LEVEL)?;
+            writeln!(out, "console             {}", console)?;
+            writeln!(out, "log ring size       {} KiB", LOG_RING_SIZE / 1024)?;
+            writeln!(out, "scancode queue size {}", SCANCODE_QUEUE_SIZE)?;
+            writeln!(out, "  grows up to       {}", SCANCODE_QUEUE_MAX_SIZE)?;
+            writeln!(out, "task queue size     {}", TASK_QUEUE_SIZE)?;
+            writeln!(out, "kassert             {}", KASSERT)
+        },
+    );
+}
+
+#[test_case]
+static PARSES_VALUES: HostTest = HostTest::new("config::parses_values", || {
+    assert_eq!(number(None, 7), 7);
+    assert_eq!(number(Some("4096"), 7), 4096);
+    assert_eq!(number(Some("64k"), 7), 64 << 10);
+    assert_eq!(number(Some("2M"), 7), 2 << 20);
+    assert_eq!(level(None, Level::Info), Level::Info);
+    assert_eq!(level(Some("DEBUG"), Level::Info), Level::Debug);
+    assert_eq!(
+        console(Some("serial"), sink::ALL),
+        sink::SERIAL | sink::RING
+    );
+    assert_eq!(console(None, sink::ALL), sink::ALL);
+    assert_eq!(
+        kassert_mode(Some("Continue"), kassert::Mode::Panic),
+        kassert::Mode::Continue
+    );
+});
@@ -0,0 +1,250 @@
+// Helpers for identifying the CPU we are running on. We only ever run on the
+// bootstrap processor for now, but data structures that will later be per-CPU
+// (like the trace buffers) are indexed through here already. They're indexed
+// on every interrupt and allocation, so `index` doesn't ask CPUID, which is
+// slow and traps to the hypervisor, and isn't dense either: APIC IDs can have
+// gaps. The CPUs are numbered in the order they load their TSS instead, see
+// `gdt::cpu_index`.
+//
+// `topology` finds all the CPUs, for placing tasks once the others run too.
+// The MADT (the ACPI table "APIC") lists every CPU's local APIC ID. An APIC ID
+// is made of bit fields, from the lowest: the thread within its core, the core
+// within its package, and the package. CPUID says how wide the fields are, in
+// the extended topology leaf 0xB if the CPU has it, or else from the number of
+// logical CPUs (leaf 1) and cores (leaf 4) per package.
+
+use crate::{acpi, gdt, shell};
+use alloc::vec::Vec;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::fmt;
+
+/// The maximum number of CPUs that per-CPU data structures are sized for.
+pub const MAX_CPUS: usize = 4;
+
+/// Returns the initial local APIC ID of the current CPU (CPUID leaf 1).
+pub fn apic_id() -> u8 {
+    let leaf = unsafe { __cpuid(1) };
+
+    (leaf.ebx >> 24) as u8
+}
+
+/// Returns a small index identifying the current CPU, for indexing per-CPU
+/// arrays of `MAX_CPUS` entries. The bootstrap processor is 0.
+pub fn index() -> usize {
+    gdt::cpu_index()
+}
+
+const MADT_ENTRIES: usize = acpi::HEADER_LEN + 8;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_LOCAL_X2APIC: u8 = 9;
+const MADT_ENABLED: u32 = 1 << 0;
+/// A disabled CPU that can be brought online later.
+const MADT_ONLINE_CAPABLE: u32 = 1 << 1;
+
+const EXTENDED_TOPOLOGY_LEAF: u32 = 0xB;
+const LEVEL_SMT: u32 = 1;
+const HTT: u32 = 1 << 28;
+
+/// A logical CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cpu {
+    pub apic_id: u32,
+    pub package: u32,
+    pub core: u32,
+    pub thread: u32,
+    /// Whether the CPU can be started. The MADT may list CPUs that are
+    /// disabled, e.g. sockets without a CPU in them.
+    pub enabled: bool,
+    /// Whether it's the CPU that booted, the one we run on.
+    pub bootstrap: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topology {
+    /// Sorted by APIC ID.
+    pub cpus: Vec<Cpu>,
+}
+
+impl Topology {
+    /// The CPUs that can be started.
+    pub fn enabled(&self) -> impl Iterator<Item = &Cpu> {
+        self.cpus.iter().filter(|cpu| cpu.enabled)
+    }
+
+    pub fn packages(&self) -> usize {
+        self.count(|cpu| (cpu.package, 0))
+    }
+
+    pub fn cores(&self) -> usize {
+        self.count(|cpu| (cpu.package, cpu.core))
+    }
+
+    pub fn threads(&self) -> usize {
+        self.enabled().count()
+    }
+
+    /// How many different keys the enabled CPUs have.
+    fn count(&self, key: impl Fn(&Cpu) -> (u32, u32)) -> usize {
+        let mut keys: Vec<_> = self.enabled().map(key).collect();
+        keys.sort_unstable();
+        keys.dedup();
+        keys.len()
+    }
+}
+
+impl fmt::Display for Topology {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} CPUs: {} packages, {} cores, {} threads",
+            self.threads(),
+            self.packages(),
+            self.cores(),
+            self.threads()
+        )?;
+        for cpu in self.cpus.iter() {
+            write!(
+                f,
+                "  apic {:>3}: package {} core {} thread {}",
+                cpu.apic_id, cpu.package, cpu.core, cpu.thread
+            )?;
+            match (cpu.bootstrap, cpu.enabled) {
+                (true, _) => writeln!(f, " (boot)")?,
+                (false, false) => writeln!(f, " (disabled)")?,
+                (false, true) => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How far the core and package fields of an APIC ID are shifted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdLayout {
+    pub core_shift: u32,
+    pub package_shift: u32,
+}
+
+impl IdLayout {
+    /// Asks CPUID.
+    pub fn detect() -> IdLayout {
+        let max_leaf = unsafe { __cpuid(0) }.eax;
+        if max_leaf >= EXTENDED_TOPOLOGY_LEAF
+            && unsafe { __cpuid_count(EXTENDED_TOPOLOGY_LEAF, 0) }.ebx != 0
+        {
+            let mut layout = IdLayout {
+                core_shift: 0,
+                package_shift: 0,
+            };
+            // One subleaf per level, from the threads up, until one of type 0.
+            // The last one's shift is the package's.
+            for subleaf in 0.. {
+                let level = unsafe { __cpuid_count(EXTENDED_TOPOLOGY_LEAF, subleaf) };
+                let (level_type, shift) = ((level.ecx >> 8) & 0xFF, level.eax & 0x1F);
+                if level_type == 0 {
+                    break;
+                }
+                if level_type == LEVEL_SMT {
+                    layout.core_shift = shift;
+                }
+                layout.package_shift = shift;
+            }
+            return layout;
+        }
+
+        let leaf_1 = unsafe { __cpuid(1) };
+        let logical = match leaf_1.edx & HTT {
+            0 => 1,
+            _ => (leaf_1.ebx >> 16) & 0xFF,
+        };
+        let cores = if max_leaf >= 4 {
+            (unsafe { __cpuid_count(4, 0) }.eax >> 26) + 1
+        } else {
+            1
+        };
+        IdLayout {
+            core_shift: bits_for((logical / cores).max(1)),
+            package_shift: bits_for(logical.max(1)),
+        }
+    }
+
+    /// Splits an APIC ID into package, core and thread.
+    pub fn split(&self, apic_id: u32) -> (u32, u32, u32) {
+        let mask = |bits: u32| 1u32.checked_shl(bits).map_or(u32::MAX, |bit| bit - 1);
+        let core_bits = self.package_shift.saturating_sub(self.core_shift);
+        let thread = apic_id & mask(self.core_shift);
+        let core = apic_id.checked_shr(self.core_shift).unwrap_or(0) & mask(core_bits);
+        let package = apic_id.checked_shr(self.package_shift).unwrap_or(0);
+        (package, core, thread)
+    }
+}
+
+/// How many bits it takes to count to `n` - 1.
+fn bits_for(n: u32) -> u32 {
+    n.next_power_of_two().trailing_zeros()
+}
+
+/// All the CPUs, from the MADT. Just the one we run on if there's no MADT.
+pub fn topology() -> Topology {
+    let layout = IdLayout::detect();
+    let bootstrap = u32::from(apic_id());
+
+    let mut apic_ids: Vec<(u32, bool)> = acpi::find("APIC")
+        .map(|madt| madt_apic_ids(madt.data()))
+        .unwrap_or_default();
+    if !apic_ids.iter().any(|&(apic_id, _)| apic_id == bootstrap) {
+        apic_ids.push((bootstrap, true));
+    }
+    apic_ids.sort_unstable();
+    apic_ids.dedup_by_key(|&mut (apic_id, _)| apic_id);
+
+    let cpus = apic_ids
+        .into_iter()
+        .map(|(apic_id, enabled)| {
+            let (package, core, thread) = layout.split(apic_id);
+            Cpu {
+                apic_id,
+                package,
+                core,
+                thread,
+                enabled,
+                bootstrap: apic_id == bootstrap,
+            }
+        })
+        .collect();
+    Topology { cpus }
+}
+
+/// The APIC IDs in a MADT, and whether their CPUs are enabled.
+pub fn madt_apic_ids(madt: &[u8]) -> Vec<(u32, bool)> {
+    let mut apic_ids = Vec::new();
+    let mut entries = madt.get(MADT_ENTRIES..).unwrap_or(&[]);
+    while let [entry_type, len, ..] = *entries {
+        let len = usize::from(len);
+        if len < 2 || len > entries.len() {
+            break;
+        }
+        let entry = &entries[..len];
+        let parsed = match entry_type {
+            MADT_LOCAL_APIC if len >= 8 => Some((u32::from(entry[3]), acpi::u32_at(entry, 4))),
+            MADT_LOCAL_X2APIC if len >= 16 => {
+                Some((acpi::u32_at(entry, 4), acpi::u32_at(entry, 8)))
+            }
+            _ => None,
+        };
+        if let Some((apic_id, flags)) = parsed {
+            // Neither enabled nor online capable means unusable
+            if flags & (MADT_ENABLED | MADT_ONLINE_CAPABLE) != 0 {
+                apic_ids.push((apic_id, flags & MADT_ENABLED != 0));
+            }
+        }
+        entries = &entries[len..];
+    }
+    apic_ids
+}
+
+pub fn register_commands() {
+    shell::register("lscpu", "list the CPUs", |_args, out| {
+        write!(out, "{}", topology())
+    });
+}
@@ -0,0 +1,178 @@
+// When the kernel dies, we write a crash dump over serial so that a QEMU run
+// (e.g. in CI, with `-serial stdio`) leaves behind everything we know about the
+// crash in a form that a script can pick apart. The dump is line based:
+//
+//   CRASH BEGIN 1                  the number is the format version
+//   reason <text>                  the panic message, one line per message line
+//   uptime_ms <n>
+//   cpu <n>
+//   reg <name> <hex>               rip/cs/rflags/rsp/ss of the faulting code if
+//                                  known, then dump_rbp/dump_rsp (where the dump
+//                                  was written from) and cr0/cr2/cr3/cr4
+//   frame <i> <hex> [<symbol>+<hex offset>]
+//   task count <n>
+//   task current <id> <ms in poll>
+//   heap size|used|cached <bytes>  or `heap locked`
+//   dmesg <line>                   the most recent log lines, oldest first
+//   CRASH END
+//
+// Every line starts with its key, so e.g. `sed -n '/^CRASH BEGIN/,/^CRASH END/p'`
+// extracts the dump from the rest of the output, and `grep '^frame '` the backtrace.
+//
+// Only the first crash writes a dump; if writing it crashes too, or a double
+// fault turns into a panic, we don't want a second one mixed into the first.
+
+use crate::{allocator, cpu, logger, serial, symbols, task::executor, time, unwind::Backtrace};
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::InterruptStackFrame;
+
+const FORMAT_VERSION: u32 = 1;
+const DMESG_LINES: usize = 32;
+
+static WRITTEN: AtomicBool = AtomicBool::new(false);
+
+/// Writes a crash dump over serial, unless one has been written already.
+///
+/// `frame` is the interrupt stack frame of the code that faulted, if the crash
+/// is an exception.
+pub fn write(
+    reason: &dyn fmt::Display,
+    frame: Option<&InterruptStackFrame>,
+    backtrace: &Backtrace,
+) {
+    if WRITTEN.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    // We are not coming back from this, so don't let anything interrupt us.
+    x86_64::instructions::interrupts::disable();
+
+    // The code that crashed may have been printing to serial.
+    unsafe { serial::force_unlock() };
+
+    // Errors can't be reported anywhere, just give up on the rest of the dump.
+    let _ = serial::with_channel(serial::Channel::Log, |out| {
+        write_dump(out, reason, frame, backtrace)
+    });
+}
+
+fn write_dump(
+    out: &mut dyn Write,
+    reason: &dyn fmt::Display,
+    frame: Option<&InterruptStackFrame>,
+    backtrace: &Backtrace,
+) -> fmt::Result {
+    writeln!(out, "CRASH BEGIN {}", FORMAT_VERSION)?;
+
+    let mut lines = Prefixed::new(out, "reason");
+    write!(lines, "{}", reason)?;
+    lines.finish()?;
+
+    writeln!(out, "uptime_ms {}", time::uptime_ms())?;
+    writeln!(out, "cpu {}", cpu::index())?;
+
+    write_registers(out, frame)?;
+
+    for (i, &address) in backtrace.frames().iter().enumerate() {
+        write!(out, "frame {} {:#x}", i, address)?;
+        if let Some(symbol) = symbols::resolve(address) {
+            write!(out, " {}", symbol)?;
+        }
+        writeln!(out)?;
+    }
+
+    writeln!(out, "task count {}", executor::task_count())?;
+    if let Some((id, since)) = executor::current_task() {
+        let polling_ms = (time::ticks() - since) * 1000 / time::TIMER_HZ;
+        writeln!(out, "task current {} {}", id, polling_ms)?;
+    }
+
+    match allocator::stats() {
+        Some(stats) => {
+            writeln!(out, "heap size {}", stats.size)?;
+            writeln!(out, "heap used {}", stats.used)?;
+            writeln!(out, "heap cached {}", stats.cached)?;
+        }
+        None => writeln!(out, "heap locked")?,
+    }
+
+    let mut lines = Prefixed::new(out, "dmesg");
+    if let Some(result) = logger::try_dmesg_tail(&mut lines, DMESG_LINES) {
+        result?;
+    }
+    lines.finish()?;
+
+    writeln!(out, "CRASH END")
+}
+
+fn write_registers(out: &mut dyn Write, frame: Option<&InterruptStackFrame>) -> fmt::Result {
+    if let Some(frame) = frame {
+        writeln!(out, "reg rip {:#x}", frame.instruction_pointer.as_u64())?;
+        writeln!(out, "reg cs {:#x}", frame.code_segment)?;
+        writeln!(out, "reg rflags {:#x}", frame.cpu_flags)?;
+        writeln!(out, "reg rsp {:#x}", frame.stack_pointer.as_u64())?;
+        writeln!(out, "reg ss {:#x}", frame.stack_segment)?;
+    }
+
+    let (rbp, rsp): (u64, u64);
+    unsafe {
+        asm!("mov {}, rbp", "mov {}, rsp", out(reg) rbp, out(reg) rsp, options(nomem, nostack));
+    }
+
+    writeln!(out, "reg dump_rbp {:#x}", rbp)?;
+    writeln!(out, "reg dump_rsp {:#x}", rsp)?;
+    writeln!(out, "reg cr0 {:#x}", Cr0::read_raw())?;
+    writeln!(out, "reg cr2 {:#x}", Cr2::read().as_u64())?;
+    writeln!(out, "reg cr3 {:#x}", Cr3::read().0.start_address().as_u64())?;
+    writeln!(out, "reg cr4 {:#x}", Cr4::read_raw())
+}
+
+/// Starts every line written through it with `prefix` and a space.
+struct Prefixed<'a> {
+    out: &'a mut dyn Write,
+    prefix: &'a str,
+    line_start: bool,
+}
+
+impl<'a> Prefixed<'a> {
+    fn new(out: &'a mut dyn Write, prefix: &'a str) -> Self {
+        Prefixed {
+            out,
+            prefix,
+            line_start: true,
+        }
+    }
+
+    /// Terminates the last line, if it wasn't already.
+    fn finish(self) -> fmt::Result {
+        if self.line_start {
+            Ok(())
+        } else {
+            writeln!(self.out)
+        }
+    }
+}
+
+impl Write for Prefixed<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, line) in s.split('\n').enumerate() {
+            if i > 0 {
+                self.out.write_char('\n')?;
+                self.line_start = true;
+            }
+            if line.is_empty() {
+                continue;
+            }
+            if self.line_start {
+                write!(self.out, "{} ", self.prefix)?;
+                self.line_start = false;
+            }
+            self.out.write_str(line)?;
+        }
+
+        Ok(())
+    }
+}
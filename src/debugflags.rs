@@ -0,0 +1,136 @@
+// Named on/off switches for diagnostics that are too noisy to leave on all the
+// time, like logging every interrupt. Rebuilding and rebooting the kernel just to
+// flip one of these is slow, so they are toggled at runtime instead: F1 toggles
+// the first flag in `FLAGS`, F2 the second, and so on.
+//
+// Subsystems check their flag on hot paths, so reading a flag is a single
+// relaxed atomic load.
+
+use crate::{info, shell};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub struct Flag {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicBool,
+}
+
+impl Flag {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Flag {
+            name,
+            help,
+            value: AtomicBool::new(false),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn help(&self) -> &'static str {
+        self.help
+    }
+
+    #[inline]
+    pub fn get(&self) -> bool {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, value: bool) {
+        self.value.store(value, Ordering::Relaxed);
+    }
+
+    /// Flips the flag and returns its new value.
+    pub fn toggle(&self) -> bool {
+        !self.value.fetch_xor(true, Ordering::Relaxed)
+    }
+}
+
+pub static TRACE_IRQ: Flag =
+    Flag::new("trace_irq", "log every hardware interrupt except the timer");
+pub static ALLOC_TRACE: Flag =
+    Flag::new("alloc_trace", "log every heap allocation and deallocation");
+pub static ECHO_SCANCODES: Flag = Flag::new("echo_scancodes", "print raw keyboard scancodes");
+pub static TRACE_MMIO: Flag = Flag::new("trace_mmio", "log every memory mapped register access");
+
+/// All flags, in hotkey order.
+static FLAGS: [&Flag; 4] = [&TRACE_IRQ, &ALLOC_TRACE, &ECHO_SCANCODES, &TRACE_MMIO];
+
+pub fn all() -> impl Iterator<Item = &'static Flag> {
+    FLAGS.iter().copied()
+}
+
+/// Looks up a flag by name.
+pub fn find(name: &str) -> Option<&'static Flag> {
+    all().find(|flag| flag.name == name)
+}
+
+/// Toggles the flag bound to function key F`n` (starting at 1), if there is one.
+pub fn hotkey(n: usize) {
+    if let Some(flag) = n.checked_sub(1).and_then(|index| FLAGS.get(index)) {
+        let value = flag.toggle();
+        info!(
+            "debug flag {} is now {}",
+            flag.name,
+            if value { "on" } else { "off" }
+        );
+    }
+}
+
+pub fn register_commands() {
+    shell::register(
+        "flag",
+        "list debug flags, or `flag <name> on|off`",
+        flag_command,
+    );
+}
+
+fn flag_command(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    match args {
+        [_] => {
+            for (i, flag) in all().enumerate() {
+                let value = if flag.get() { "on" } else { "off" };
+                writeln!(
+                    out,
+                    "  F{:<2} {:<16} {:<3} {}",
+                    i + 1,
+                    flag.name,
+                    value,
+                    flag.help
+                )?;
+            }
+            Ok(())
+        }
+        [_, name, value] => {
+            let value = match *value {
+                "on" => true,
+                "off" => false,
+                _ => return writeln!(out, "usage: flag <name> on|off"),
+            };
+
+            match find(name) {
+                Some(flag) => {
+                    flag.set(value);
+                    Ok(())
+                }
+                None => writeln!(out, "unknown flag: {}", name),
+            }
+        }
+        _ => writeln!(out, "usage: flag <name> on|off"),
+    }
+}
+
+#[test_case]
+fn test_find_and_toggle() {
+    let flag = find("echo_scancodes").unwrap();
+
+    assert!(!flag.get());
+    assert!(flag.toggle());
+    assert!(ECHO_SCANCODES.get());
+    flag.set(false);
+    assert!(!flag.get());
+
+    assert!(find("no_such_flag").is_none());
+}
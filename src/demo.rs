@@ -0,0 +1,301 @@
+// Games on the text console, started with `demo <name>`. There is one so far:
+// snake, steered with w, a, s and d (q quits). It runs a frame every
+// `FRAME_TICKS` timer ticks, takes whatever keys came in since the last frame
+// without waiting for any, and draws the whole board straight into the VGA
+// buffer. At the end it says how late the frames were and how long drawing
+// took, which is where timer jitter and slow rendering show up.
+//
+// The game itself knows nothing about the screen or the keyboard, so that
+// it can be tested on its own. It takes the random numbers for placing the
+// food from its caller, too.
+
+use crate::tty::{self, RawMode};
+use crate::vga_buffer::{self, Color, BUFFER_HEIGHT, BUFFER_WIDTH, WRITER};
+#[cfg(test)]
+use crate::HostTest;
+use crate::{rand, shell, time};
+use alloc::{boxed::Box, collections::VecDeque, format};
+use core::fmt::{self, Write};
+use core::time::Duration;
+
+/// How many timer ticks a frame takes: 10 frames a second.
+const FRAME_TICKS: u64 = time::TIMER_HZ / 10;
+
+// The board fills the screen, inside a border and below a status line.
+const BOARD_TOP: usize = 2;
+const BOARD_LEFT: usize = 1;
+const BOARD_WIDTH: usize = BUFFER_WIDTH - 2;
+const BOARD_HEIGHT: usize = BUFFER_HEIGHT - 3;
+
+const BORDER: (Color, Color) = (Color::DarkGray, Color::Black);
+const TEXT: (Color, Color) = (Color::White, Color::Black);
+const FOOD: (Color, Color) = (Color::LightRed, Color::Black);
+const BODY: (Color, Color) = (Color::Green, Color::Black);
+const HEAD: (Color, Color) = (Color::LightGreen, Color::Black);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Direction {
+    fn from_key(key: u8) -> Option<Direction> {
+        match key {
+            b'w' | b'W' => Some(Direction::Up),
+            b's' | b'S' => Some(Direction::Down),
+            b'a' | b'A' => Some(Direction::Left),
+            b'd' | b'D' => Some(Direction::Right),
+            _ => None,
+        }
+    }
+
+    fn opposite(self) -> Direction {
+        match self {
+            Direction::Up => Direction::Down,
+            Direction::Down => Direction::Up,
+            Direction::Left => Direction::Right,
+            Direction::Right => Direction::Left,
+        }
+    }
+}
+
+/// A position on the board, (x, y) from the top left.
+type Cell = (usize, usize);
+
+struct Snake {
+    width: usize,
+    height: usize,
+    /// The head comes first.
+    body: VecDeque<Cell>,
+    /// Where the snake moved last. It can't turn back on itself.
+    direction: Direction,
+    /// Where it moves on the next step.
+    next: Direction,
+    food: Cell,
+    score: usize,
+}
+
+impl Snake {
+    /// A snake of three in the middle of the board, heading right.
+    fn new(width: usize, height: usize, random: u64) -> Snake {
+        let (x, y) = (width / 2, height / 2);
+        let mut snake = Snake {
+            width,
+            height,
+            body: (0..3).map(|i| (x - i, y)).collect(),
+            direction: Direction::Right,
+            next: Direction::Right,
+            food: (0, 0),
+            score: 0,
+        };
+        snake.place_food(random);
+        snake
+    }
+
+    fn turn(&mut self, direction: Direction) {
+        if direction != self.direction.opposite() {
+            self.next = direction;
+        }
+    }
+
+    /// Moves the snake by one cell. Returns false if the game is over: it ran
+    /// into a wall or itself, or there is no room left for food.
+    fn step(&mut self, random: u64) -> bool {
+        let (x, y) = self.body[0];
+        let head = match self.next {
+            Direction::Up => y.checked_sub(1).map(|y| (x, y)),
+            Direction::Down => Some((x, y + 1)).filter(|&(_, y)| y < self.height),
+            Direction::Left => x.checked_sub(1).map(|x| (x, y)),
+            Direction::Right => Some((x + 1, y)).filter(|&(x, _)| x < self.width),
+        };
+        let head = match head {
+            Some(head) => head,
+            None => return false,
+        };
+        self.direction = self.next;
+
+        // The tail moves out of the way first, unless the snake grows.
+        let eats = head == self.food;
+        if !eats {
+            self.body.pop_back();
+        }
+        if self.body.contains(&head) {
+            return false;
+        }
+        self.body.push_front(head);
+
+        if eats {
+            self.score += 1;
+            return self.place_food(random);
+        }
+        true
+    }
+
+    /// Puts the food on a free cell, picked by `random`. Returns false if
+    /// there is none.
+    fn place_food(&mut self, random: u64) -> bool {
+        let free = self.width * self.height - self.body.len();
+        if free == 0 {
+            return false;
+        }
+        let cells = (0..self.height).flat_map(|y| (0..self.width).map(move |x| (x, y)));
+        let mut free_cells = cells.filter(|cell| !self.body.contains(cell));
+        match free_cells.nth((random % free as u64) as usize) {
+            Some(cell) => {
+                self.food = cell;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// How the frames went.
+#[derive(Default)]
+struct FrameStats {
+    frames: u64,
+    /// The latest a frame started, after the tick it was due at.
+    max_late: Duration,
+    /// The longest drawing a frame took.
+    max_draw: Duration,
+}
+
+fn draw(snake: &Snake) {
+    let mut writer = WRITER.lock();
+    let status = format!(
+        " snake  score {}   w a s d to steer, q to quit",
+        snake.score
+    );
+    for col in 0..BUFFER_WIDTH {
+        let byte = status.as_bytes().get(col).copied().unwrap_or(b' ');
+        writer.write_at(0, col, byte, TEXT);
+    }
+
+    for row in BOARD_TOP - 1..=BOARD_TOP + BOARD_HEIGHT {
+        for col in BOARD_LEFT - 1..=BOARD_LEFT + BOARD_WIDTH {
+            let inside = (BOARD_TOP..BOARD_TOP + BOARD_HEIGHT).contains(&row)
+                && (BOARD_LEFT..BOARD_LEFT + BOARD_WIDTH).contains(&col);
+            let (byte, colors) = if inside { (b' ', TEXT) } else { (b'#', BORDER) };
+            writer.write_at(row, col, byte, colors);
+        }
+    }
+
+    let mut put = |(x, y): Cell, byte, colors| {
+        writer.write_at(BOARD_TOP + y, BOARD_LEFT + x, byte, colors);
+    };
+    put(snake.food, b'*', FOOD);
+    for &cell in snake.body.iter().skip(1) {
+        put(cell, b'o', BODY);
+    }
+    put(snake.body[0], b'@', HEAD);
+}
+
+pub fn register_commands() {
+    shell::register_async(
+        "demo",
+        "run a demo on the console, `demo snake`",
+        |args, out| Box::pin(demo(args, out)),
+    );
+}
+
+async fn demo(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    match args {
+        [_, "snake"] => snake(out).await,
+        _ => writeln!(out, "usage: demo snake"),
+    }
+}
+
+async fn snake(out: &mut dyn Write) -> fmt::Result {
+    if !vga_buffer::present() {
+        return writeln!(out, "demo: needs the VGA text console");
+    }
+
+    let _raw = RawMode::enter();
+    let mut snake = Snake::new(BOARD_WIDTH, BOARD_HEIGHT, rand::next_u64());
+    let mut stats = FrameStats::default();
+    let start = time::ticks();
+
+    'game: loop {
+        // Whatever came in since the last frame, without waiting for more
+        while let Some(key) = tty::try_read_byte() {
+            match key {
+                b'q' | b'Q' => break 'game,
+                key => {
+                    if let Some(direction) = Direction::from_key(key) {
+                        snake.turn(direction);
+                    }
+                }
+            }
+        }
+        if !snake.step(rand::next_u64()) {
+            break;
+        }
+
+        let drawing = time::now();
+        draw(&snake);
+        stats.max_draw = stats.max_draw.max(time::now() - drawing);
+
+        stats.frames += 1;
+        let due = start + stats.frames * FRAME_TICKS;
+        time::sleep_until(due).await;
+        let due = Duration::from_millis(due * 1000 / time::TIMER_HZ);
+        stats.max_late = stats.max_late.max(time::now().saturating_sub(due));
+    }
+
+    WRITER.lock().clear();
+    writeln!(out, "snake: score {}", snake.score)?;
+    writeln!(
+        out,
+        "{} frames, the latest {} ms late, drawing took at most {} us",
+        stats.frames,
+        stats.max_late.as_millis(),
+        stats.max_draw.as_micros()
+    )
+}
+
+#[cfg(test)]
+fn snake_on_small_board() -> Snake {
+    // Random numbers of 0 put the food on the first free cell, (0, 0)
+    Snake::new(8, 5, 0)
+}
+
+#[test_case]
+static SNAKE_MOVES_AND_TURNS: HostTest = HostTest::new("demo::snake_moves_and_turns", || {
+    let mut snake = snake_on_small_board();
+    assert_eq!(snake.body, [(4, 2), (3, 2), (2, 2)]);
+
+    assert!(snake.step(0));
+    assert_eq!(snake.body, [(5, 2), (4, 2), (3, 2)]);
+
+    // Turning back is ignored, even after a turn that hasn't happened yet
+    snake.turn(Direction::Up);
+    snake.turn(Direction::Left);
+    assert!(snake.step(0));
+    assert_eq!(snake.body[0], (5, 1));
+
+    assert!(snake.step(0));
+    assert!(!snake.step(0), "ran through the top wall");
+});
+
+#[test_case]
+static SNAKE_EATS_AND_GROWS: HostTest = HostTest::new("demo::snake_eats_and_grows", || {
+    let mut snake = snake_on_small_board();
+    snake.food = (5, 2);
+    assert!(snake.step(0));
+    assert_eq!(snake.score, 1);
+    assert_eq!(snake.body.len(), 4);
+    assert_eq!(snake.food, (0, 0));
+
+    // Biting itself ends the game
+    snake.body = [(2, 2), (3, 2), (3, 3), (2, 3), (1, 3), (1, 2)]
+        .iter()
+        .copied()
+        .collect();
+    snake.direction = Direction::Up;
+    snake.turn(Direction::Down);
+    snake.turn(Direction::Right);
+    assert!(!snake.step(0));
+});
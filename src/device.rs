@@ -0,0 +1,335 @@
+// The device tree: every device the kernel found, whatever finds or drives it,
+// under the bus it hangs off. PCI functions are under their PCI bus, the
+// keyboard under the PS/2 controller. Each device gets an ID when it's added,
+// which is never handed out again, so an ID keeps meaning the same device.
+//
+// What a device is depends on who added it, so the tree only knows it as a
+// `Device`: something with a name, which can describe itself for `lsdev`.
+// Devices are never removed, since nothing can be unplugged yet.
+//
+// A device can also have power hooks. Before the power goes off, `power.rs`
+// calls `shutdown_all`, which shuts the devices down children first, so that
+// a card stops its DMA before its PCI function loses bus mastering, and a disk
+// writes out its cache while whatever it's on still works. `suspend_all` and
+// `resume_all` are for sleeping, which nothing does yet. After a panic,
+// `quiesce_all` just stops the devices' DMA, in the same order (see panic.rs).
+
+#[cfg(test)]
+use crate::HostTest;
+use crate::{shell, warn};
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::fmt::{self, Write};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub trait Device: Send + Sync {
+    /// What the device is, like "PCI bus 0" or "keyboard".
+    fn name(&self) -> String;
+
+    /// Writes more about the device, on one line, like where it is.
+    fn describe(&self, _out: &mut dyn Write) -> fmt::Result {
+        Ok(())
+    }
+
+    /// Gets the device ready for the machine to sleep: finishes or stops what
+    /// it's doing, and writes out what it holds back.
+    fn suspend(&self) -> Result<(), PowerError> {
+        Ok(())
+    }
+
+    /// Gets the device going again after `suspend`.
+    fn resume(&self) -> Result<(), PowerError> {
+        Ok(())
+    }
+
+    /// Gets the device ready for the power to go off, like `suspend` but for
+    /// good.
+    fn shutdown(&self) -> Result<(), PowerError> {
+        Ok(())
+    }
+
+    /// Stops the device from touching memory, right away, because the kernel
+    /// panicked. Unlike `shutdown`, it doesn't wait for anything or write
+    /// anything out, and it mustn't allocate or wait for a lock, since the
+    /// panic may have happened with it held.
+    fn quiesce(&self) {}
+}
+
+/// Why a device couldn't be suspended, resumed or shut down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerError {
+    /// It didn't finish what it was doing in time.
+    Timeout,
+    /// Writing out what it held back failed.
+    Io,
+}
+
+impl fmt::Display for PowerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            PowerError::Timeout => "timed out",
+            PowerError::Io => "I/O error",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DeviceId(u64);
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+struct Node {
+    parent: Option<DeviceId>,
+    device: Arc<dyn Device>,
+}
+
+struct Tree {
+    // Sorted by ID, so children come in the order they were added
+    nodes: BTreeMap<DeviceId, Node>,
+    next_id: u64,
+}
+
+impl Tree {
+    const fn new() -> Tree {
+        Tree {
+            nodes: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn add(&mut self, parent: Option<DeviceId>, device: Arc<dyn Device>) -> DeviceId {
+        assert!(
+            parent.is_none_or(|parent| self.nodes.contains_key(&parent)),
+            "no device {:?}",
+            parent
+        );
+        let id = DeviceId(self.next_id);
+        self.next_id += 1;
+        self.nodes.insert(id, Node { parent, device });
+        id
+    }
+
+    fn children(&self, parent: Option<DeviceId>) -> impl Iterator<Item = DeviceId> + '_ {
+        self.nodes
+            .iter()
+            .filter(move |(_, node)| node.parent == parent)
+            .map(|(&id, _)| id)
+    }
+
+    /// The devices in the order they go to sleep: children before their
+    /// parents, and the ones added later first. They wake up the other way
+    /// round.
+    fn sleep_order(&self) -> Vec<DeviceId> {
+        let mut order = Vec::new();
+        self.push_sleep_order(None, &mut order);
+        order
+    }
+
+    fn push_sleep_order(&self, parent: Option<DeviceId>, order: &mut Vec<DeviceId>) {
+        let children: Vec<DeviceId> = self.children(parent).collect();
+        for &id in children.iter().rev() {
+            self.push_sleep_order(Some(id), order);
+            order.push(id);
+        }
+    }
+
+    /// Quiesces the devices under `parent` in sleep order, without allocating
+    /// like `sleep_order` does.
+    fn quiesce(&self, parent: Option<DeviceId>) {
+        let children = self
+            .nodes
+            .iter()
+            .rev()
+            .filter(move |(_, node)| node.parent == parent);
+        for (&id, node) in children {
+            self.quiesce(Some(id));
+            node.device.quiesce();
+        }
+    }
+
+    /// Writes the devices under `parent`, one per line, each indented by
+    /// its depth.
+    fn write(&self, out: &mut dyn Write, parent: Option<DeviceId>, depth: usize) -> fmt::Result {
+        for id in self.children(parent) {
+            let device = &self.nodes[&id].device;
+            write!(
+                out,
+                "{:indent$}{:>3} {}",
+                "",
+                id,
+                device.name(),
+                indent = depth * 2
+            )?;
+            let mut details = String::new();
+            device.describe(&mut details)?;
+            if !details.is_empty() {
+                write!(out, " ({})", details)?;
+            }
+            writeln!(out)?;
+            self.write(out, Some(id), depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+static TREE: Mutex<Tree> = Mutex::new(Tree::new());
+
+/// Adds a device under `parent`, or at the top if it's `None`, and returns
+/// its ID.
+///
+/// Panics if there's no device `parent`.
+pub fn add(parent: Option<DeviceId>, device: Arc<dyn Device>) -> DeviceId {
+    interrupts::without_interrupts(|| TREE.lock().add(parent, device))
+}
+
+pub fn get(id: DeviceId) -> Option<Arc<dyn Device>> {
+    interrupts::without_interrupts(|| TREE.lock().nodes.get(&id).map(|node| node.device.clone()))
+}
+
+/// The device that `id` is under.
+pub fn parent(id: DeviceId) -> Option<DeviceId> {
+    interrupts::without_interrupts(|| TREE.lock().nodes.get(&id)?.parent)
+}
+
+/// The devices right under `parent`, or at the top if it's `None`, in the
+/// order they were added.
+pub fn children(parent: Option<DeviceId>) -> Vec<DeviceId> {
+    interrupts::without_interrupts(|| TREE.lock().children(parent).collect())
+}
+
+/// The devices in `sleep_order`, or the other way round.
+fn devices_in_sleep_order(reverse: bool) -> Vec<(DeviceId, Arc<dyn Device>)> {
+    interrupts::without_interrupts(|| {
+        let tree = TREE.lock();
+        let mut order = tree.sleep_order();
+        if reverse {
+            order.reverse();
+        }
+        order
+            .into_iter()
+            .map(|id| (id, tree.nodes[&id].device.clone()))
+            .collect()
+    })
+}
+
+/// Calls `hook` on every device, in sleep order or the other way round.
+/// Tries all of them, and returns the first error. The tree isn't locked
+/// while a hook runs, since writing out a cache may take interrupts.
+fn call_hooks(
+    action: &str,
+    reverse: bool,
+    hook: fn(&dyn Device) -> Result<(), PowerError>,
+) -> Result<(), PowerError> {
+    let mut result = Ok(());
+    for (id, device) in devices_in_sleep_order(reverse) {
+        if let Err(error) = hook(&*device) {
+            warn!(
+                "device {} ({}): can't {}: {}",
+                id,
+                device.name(),
+                action,
+                error
+            );
+            result = result.and(Err(error));
+        }
+    }
+    result
+}
+
+/// Suspends all devices, children first.
+pub fn suspend_all() -> Result<(), PowerError> {
+    call_hooks("suspend", false, |device| device.suspend())
+}
+
+/// Resumes all devices, parents first.
+pub fn resume_all() -> Result<(), PowerError> {
+    call_hooks("resume", true, |device| device.resume())
+}
+
+/// Shuts all devices down, children first, before the power goes off.
+pub fn shutdown_all() -> Result<(), PowerError> {
+    call_hooks("shut down", false, |device| device.shutdown())
+}
+
+/// Stops all devices from touching memory, children first, because the
+/// kernel panicked. If the tree is locked, the panic happened while it was
+/// being changed, and nothing is stopped.
+pub fn quiesce_all() {
+    if let Some(tree) = TREE.try_lock() {
+        tree.quiesce(None);
+    }
+}
+
+/// A bus, or a controller that devices hang off, which is only there for its
+/// children.
+pub struct Bus(pub String);
+
+impl Device for Bus {
+    fn name(&self) -> String {
+        self.0.clone()
+    }
+}
+
+pub fn register_commands() {
+    shell::register("lsdev", "show the device tree", |_args, out| {
+        // The names are made while the tree is locked, so it's written to a
+        // string first rather than to the console.
+        let mut tree = String::new();
+        interrupts::without_interrupts(|| TREE.lock().write(&mut tree, None, 0))?;
+        out.write_str(&tree)
+    });
+}
+
+#[cfg(test)]
+struct TestDevice(&'static str, &'static str);
+
+#[cfg(test)]
+impl Device for TestDevice {
+    fn name(&self) -> String {
+        self.0.into()
+    }
+
+    fn describe(&self, out: &mut dyn Write) -> fmt::Result {
+        out.write_str(self.1)
+    }
+}
+
+#[test_case]
+static WRITES_THE_TREE: HostTest = HostTest::new("device::writes_the_tree", || {
+    let mut tree = Tree::new();
+    let pci = tree.add(None, Arc::new(Bus("PCI bus 0".into())));
+    let ps2 = tree.add(None, Arc::new(Bus("PS/2 controller".into())));
+    tree.add(Some(ps2), Arc::new(TestDevice("keyboard", "")));
+    let bridge = tree.add(Some(pci), Arc::new(TestDevice("bridge", "00:01.0")));
+    tree.add(Some(bridge), Arc::new(TestDevice("disk", "01:00.0")));
+    tree.add(Some(pci), Arc::new(TestDevice("e1000", "00:03.0")));
+
+    assert_eq!(tree.children(None).collect::<Vec<_>>(), [pci, ps2]);
+    let mut out = String::new();
+    tree.write(&mut out, None, 0).unwrap();
+    assert_eq!(
+        out,
+        "  0 PCI bus 0\n\
+        \x20   3 bridge (00:01.0)\n\
+        \x20     4 disk (01:00.0)\n\
+        \x20   5 e1000 (00:03.0)\n\
+        \x20 1 PS/2 controller\n\
+        \x20   2 keyboard\n"
+    );
+});
+
+#[test_case]
+static SLEEPS_CHILDREN_FIRST: HostTest = HostTest::new("device::sleeps_children_first", || {
+    let mut tree = Tree::new();
+    let pci = tree.add(None, Arc::new(Bus("PCI bus 0".into())));
+    let bridge = tree.add(Some(pci), Arc::new(TestDevice("bridge", "")));
+    let disk = tree.add(Some(bridge), Arc::new(TestDevice("disk", "")));
+    let nic = tree.add(Some(pci), Arc::new(TestDevice("e1000", "")));
+    let ram = tree.add(None, Arc::new(TestDevice("ram0", "")));
+
+    assert_eq!(tree.sleep_order(), [ram, nic, disk, bridge, pci]);
+});
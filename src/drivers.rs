@@ -0,0 +1,246 @@
+// Drivers for devices, on the PCI bus or elsewhere. Each driver registers
+// itself with `driver!`, which puts a `Driver` in the `kdrivers` link section,
+// so nothing has to list them all. The linker makes up `__start_kdrivers` and
+// `__stop_kdrivers` for a section named like that, and between them are the
+// drivers, one after the other, since they all have the same type.
+//
+// A driver says which devices it's for (its `Probe`) and which drivers have
+// to be set up before it. `init_all` works out an order where every driver
+// comes after the ones it depends on, and calls each one's `init` with every
+// PCI device it matches, or once for a platform driver, which finds its device
+// by itself. A driver whose dependencies are missing, or go round in a circle,
+// isn't set up at all. When `init` fails, the error is logged and the rest of
+// the drivers are set up anyway.
+
+pub mod e1000;
+pub mod ps2;
+
+use crate::allocator::accounting::{self, Subsystem};
+use crate::error::KernelError;
+#[cfg(test)]
+use crate::HostTest;
+use crate::{pci, warn};
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Which devices a driver is for.
+#[derive(Debug, Clone, Copy)]
+pub enum Probe {
+    /// PCI devices with one of these vendor and device IDs.
+    PciIds(&'static [(u16, u16)]),
+    /// PCI devices of this class and subclass.
+    PciClass(u8, u8),
+    /// A device that isn't on the PCI bus. The driver's `init` is called once,
+    /// with no device, and finds it by itself.
+    Platform,
+}
+
+impl Probe {
+    fn matches(&self, device: &pci::Device) -> bool {
+        match *self {
+            Probe::PciIds(ids) => ids.contains(&(device.vendor_id, device.device_id)),
+            Probe::PciClass(class, subclass) => {
+                device.class == class && device.subclass == subclass
+            }
+            Probe::Platform => false,
+        }
+    }
+}
+
+pub struct Driver {
+    pub name: &'static str,
+    pub probe: Probe,
+    /// The names of the drivers that have to be set up first.
+    pub depends_on: &'static [&'static str],
+    /// Sets up a device that `probe` matched, or the device of a platform
+    /// driver, with `None`. A platform driver that doesn't find its device
+    /// returns `NotFound`.
+    pub init: fn(Option<&pci::Device>) -> Result<(), KernelError>,
+}
+
+/// Registers a driver, for `init_all`:
+///
+///     driver! {
+///         static DRIVER = Driver {
+///             name: "e1000",
+///             probe: Probe::PciIds(&PCI_IDS),
+///             depends_on: &[],
+///             init: probe,
+///         };
+///     }
+#[macro_export]
+macro_rules! driver {
+    (static $name:ident = $driver:expr;) => {
+        #[used]
+        #[link_section = "kdrivers"]
+        static $name: $crate::drivers::Driver = $driver;
+    };
+}
+
+// Only their addresses mean anything
+extern "C" {
+    static __start_kdrivers: u8;
+    static __stop_kdrivers: u8;
+}
+
+/// All the drivers registered with `driver!`, in no particular order.
+pub fn all() -> &'static [Driver] {
+    // Safe since the linker puts the two symbols around the section, which
+    // holds nothing but drivers.
+    unsafe {
+        let start = &__start_kdrivers as *const u8 as *const Driver;
+        let stop = &__stop_kdrivers as *const u8 as *const Driver;
+        core::slice::from_raw_parts(start, stop.offset_from(start) as usize)
+    }
+}
+
+/// Why a driver can't be set up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DependencyError {
+    /// It depends on a driver that isn't there.
+    Missing(&'static str),
+    /// Its dependencies go round in a circle, or through a driver that
+    /// can't be set up.
+    Unresolved,
+}
+
+impl fmt::Display for DependencyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DependencyError::Missing(name) => write!(f, "depends on {}, which isn't there", name),
+            DependencyError::Unresolved => f.write_str("can't resolve its dependencies"),
+        }
+    }
+}
+
+/// Orders `drivers` so that each comes after the ones it depends on, and
+/// by name otherwise. Also returns the drivers that can't be set up.
+fn init_order(drivers: &[Driver]) -> (Vec<&Driver>, Vec<(&Driver, DependencyError)>) {
+    let mut pending: Vec<&Driver> = drivers.iter().collect();
+    pending.sort_by_key(|driver| driver.name);
+
+    let mut order: Vec<&Driver> = Vec::new();
+    loop {
+        let (ready, rest): (Vec<&Driver>, Vec<&Driver>) = pending.iter().partition(|driver| {
+            driver
+                .depends_on
+                .iter()
+                .all(|name| order.iter().any(|done| done.name == *name))
+        });
+        if ready.is_empty() {
+            break;
+        }
+        order.extend(ready);
+        pending = rest;
+    }
+
+    let skipped = pending
+        .into_iter()
+        .map(|driver| {
+            let missing = driver
+                .depends_on
+                .iter()
+                .find(|name| drivers.iter().all(|other| other.name != **name));
+            let error = match missing {
+                Some(name) => DependencyError::Missing(name),
+                None => DependencyError::Unresolved,
+            };
+            (driver, error)
+        })
+        .collect();
+    (order, skipped)
+}
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Sets up the devices that there are drivers for. Needs the heap and
+/// `pci::init`. Only the first call does anything.
+pub fn init_all() {
+    if INITIALIZED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+
+    let _charge = accounting::charge_to(Subsystem::Drivers);
+    let (order, skipped) = init_order(all());
+    for (driver, error) in skipped {
+        warn!("driver {}: {}", driver.name, error);
+    }
+
+    let devices = pci::devices();
+    for driver in order {
+        match driver.probe {
+            Probe::Platform => {
+                if let Err(error) = (driver.init)(None) {
+                    warn!("driver {}: {}", driver.name, error);
+                }
+            }
+            probe => {
+                for device in devices.iter().filter(|device| probe.matches(device)) {
+                    if let Err(error) = (driver.init)(Some(device)) {
+                        warn!("driver {} ({}): {}", driver.name, device.address, error);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_driver(name: &'static str, depends_on: &'static [&'static str]) -> Driver {
+    Driver {
+        name,
+        probe: Probe::Platform,
+        depends_on,
+        init: |_| Ok(()),
+    }
+}
+
+#[test_case]
+static ORDERS_BY_DEPENDENCIES: HostTest = HostTest::new("drivers::orders_by_dependencies", || {
+    let drivers = [
+        test_driver("net", &["pci-bridge", "dma"]),
+        test_driver("pci-bridge", &[]),
+        test_driver("dma", &["pci-bridge"]),
+        test_driver("audio", &[]),
+    ];
+    let (order, skipped) = init_order(&drivers);
+    let names: Vec<&str> = order.iter().map(|driver| driver.name).collect();
+    assert_eq!(names, ["audio", "pci-bridge", "dma", "net"]);
+    assert!(skipped.is_empty());
+});
+
+#[test_case]
+static SKIPS_UNRESOLVED_DRIVERS: HostTest =
+    HostTest::new("drivers::skips_unresolved_drivers", || {
+        let drivers = [
+            test_driver("a", &["b"]),
+            test_driver("b", &["a"]),
+            test_driver("c", &["gone"]),
+            test_driver("d", &["c"]),
+            test_driver("e", &[]),
+        ];
+        let (order, skipped) = init_order(&drivers);
+        let names: Vec<&str> = order.iter().map(|driver| driver.name).collect();
+        assert_eq!(names, ["e"]);
+        let skipped: Vec<(&str, DependencyError)> = skipped
+            .iter()
+            .map(|(driver, error)| (driver.name, *error))
+            .collect();
+        assert_eq!(
+            skipped,
+            [
+                ("a", DependencyError::Unresolved),
+                ("b", DependencyError::Unresolved),
+                ("c", DependencyError::Missing("gone")),
+                ("d", DependencyError::Unresolved),
+            ]
+        );
+    });
+
+#[test_case]
+static COLLECTS_DRIVERS: HostTest = HostTest::new("drivers::collects_drivers", || {
+    let mut names: Vec<&str> = all().iter().map(|driver| driver.name).collect();
+    names.sort_unstable();
+    assert_eq!(names, ["e1000", "loopback", "ps2"]);
+});
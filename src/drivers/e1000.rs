@@ -0,0 +1,830 @@
+// A driver for Intel's 8254x gigabit Ethernet controllers ("e1000"), the
+// network card that QEMU emulates by default.
+//
+// The card's registers are memory mapped through BAR 0. Packets are passed in
+// two rings of descriptors in memory, one for receiving and one for
+// transmitting. Each descriptor points to a packet buffer, and the card reads
+// and writes the rings and buffers by itself (DMA). A ring is a circular array
+// with a head, which the card advances as it works through the descriptors,
+// and a tail, which the driver advances when it adds descriptors for the card.
+//
+// - Receiving: all receive descriptors but one are handed to the card, each
+//   with an empty buffer. When a packet arrives, the card writes it into the
+//   next buffer, sets the descriptor's "done" bit and raises an interrupt.
+//   `FrameStream` takes the packet out of the buffer and hands the descriptor
+//   back to the card by moving the tail past it.
+// - Transmitting: `transmit` copies the packet into the buffer of the next free
+//   descriptor and moves the tail past it. The card sends it, sets the "done"
+//   bit and raises an interrupt. Packets are numbered in the order they were
+//   queued, and `transmitted` waits until a given packet was sent.
+//
+// The interrupt handler only acknowledges the interrupt and wakes the tasks
+// waiting for the card. The rings are only looked at by tasks, so the handler
+// never has to allocate.
+//
+// The card counts packets, bytes and errors in statistics registers, which
+// reset when they're read. `stats` adds them up in `DeviceStats`.
+//
+// The card is registered as a `NetDevice`, for the network stack, and goes
+// into the device tree under its PCI function. Suspending it or shutting it
+// down lets it send what's queued and then stops its DMA.
+
+use crate::device::{self, Device, PowerError};
+use crate::driver;
+use crate::drivers::{Driver, Probe};
+use crate::error::KernelError;
+use crate::interrupts as irq;
+use crate::memory::{self, DmaFrame};
+use crate::mmio::RegisterBlock;
+use crate::net::{self, DeviceStats, MacAddress, NetDevice, NetError, NetFuture, PacketBuf};
+use crate::pci::{self, Bar};
+use crate::sync::Semaphore;
+use crate::{info, warn};
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+use conquer_once::spin::OnceCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::{fmt, ptr};
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+
+const VENDOR_INTEL: u16 = 0x8086;
+/// 82540EM (QEMU's e1000), 82545EM copper and fiber
+const PCI_IDS: [(u16, u16); 3] = [
+    (VENDOR_INTEL, 0x100E),
+    (VENDOR_INTEL, 0x100F),
+    (VENDOR_INTEL, 0x1011),
+];
+
+// Registers
+const CTRL: usize = 0x0000;
+const STATUS: usize = 0x0008;
+const EERD: usize = 0x0014;
+const ICR: usize = 0x00C0;
+const IMS: usize = 0x00D0;
+const IMC: usize = 0x00D8;
+const RCTL: usize = 0x0100;
+const TCTL: usize = 0x0400;
+const TIPG: usize = 0x0410;
+const RDBAL: usize = 0x2800;
+const RDBAH: usize = 0x2804;
+const RDLEN: usize = 0x2808;
+const RDH: usize = 0x2810;
+const RDT: usize = 0x2818;
+const TDBAL: usize = 0x3800;
+const TDBAH: usize = 0x3804;
+const TDLEN: usize = 0x3808;
+const TDH: usize = 0x3810;
+const TDT: usize = 0x3818;
+const MTA: usize = 0x5200;
+const RAL0: usize = 0x5400;
+const RAH0: usize = 0x5404;
+
+// Statistics registers, which reset when read
+const CRCERRS: usize = 0x4000;
+const ALGNERRC: usize = 0x4004;
+const SYMERRS: usize = 0x4008;
+const RXERRC: usize = 0x400C;
+const MPC: usize = 0x4010;
+const ECOL: usize = 0x4018;
+const LATECOL: usize = 0x4020;
+const COLC: usize = 0x4028;
+const GPRC: usize = 0x4074;
+const GPTC: usize = 0x4080;
+const GORCL: usize = 0x4088;
+const GORCH: usize = 0x408C;
+const GOTCL: usize = 0x4090;
+const GOTCH: usize = 0x4094;
+
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+
+const STATUS_LU: u32 = 1 << 1;
+const STATUS_SPEED_SHIFT: u32 = 6;
+const STATUS_SPEED: u32 = 0b11 << STATUS_SPEED_SHIFT;
+
+const EERD_START: u32 = 1 << 0;
+const EERD_DONE: u32 = 1 << 4;
+
+// Interrupt causes, for ICR, IMS and IMC
+const INT_TXDW: u32 = 1 << 0;
+const INT_LSC: u32 = 1 << 2;
+const INT_RXDMT0: u32 = 1 << 4;
+const INT_RXO: u32 = 1 << 6;
+const INT_RXT0: u32 = 1 << 7;
+const INT_RX: u32 = INT_RXDMT0 | INT_RXO | INT_RXT0;
+const INT_ENABLED: u32 = INT_RX | INT_TXDW | INT_LSC;
+
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_BAM: u32 = 1 << 15;
+const RCTL_BSIZE_2048: u32 = 0b00 << 16;
+const RCTL_SECRC: u32 = 1 << 26;
+
+const TCTL_EN: u32 = 1 << 1;
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x10 << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+
+// The recommended inter packet gap for the 8254x's copper interface
+const TIPG_DEFAULT: u32 = 10 | 8 << 10 | 6 << 20;
+
+const RAH_AV: u32 = 1 << 31;
+
+const DESCRIPTOR_DONE: u8 = 1 << 0;
+const DESCRIPTOR_END_OF_PACKET: u8 = 1 << 1;
+
+const COMMAND_EOP: u8 = 1 << 0;
+const COMMAND_IFCS: u8 = 1 << 1;
+const COMMAND_RS: u8 = 1 << 3;
+
+const RX_DESCRIPTORS: usize = 32;
+const TX_DESCRIPTORS: usize = 32;
+const BUFFER_SIZE: usize = 2048;
+const BUFFERS_PER_FRAME: usize = DmaFrame::SIZE / BUFFER_SIZE;
+
+/// The largest Ethernet frame that can be sent, without the checksum, which
+/// the card adds.
+pub const MAX_FRAME_SIZE: usize = 1514;
+const MTU: usize = 1500;
+
+/// How often to check whether a reset or EEPROM read finished.
+const POLL_ATTEMPTS: usize = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum E1000Error {
+    /// BAR 0 isn't a memory BAR.
+    NoRegisters,
+    /// There is no memory for the rings and buffers.
+    OutOfMemory,
+    /// The card didn't finish a reset or an EEPROM read in time.
+    Timeout,
+    /// Neither MSI nor the legacy interrupt line can be used.
+    NoInterrupt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    /// The frame is larger than `MAX_FRAME_SIZE`.
+    TooLarge,
+    /// All transmit descriptors are in use.
+    QueueFull,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SendError::TooLarge => f.write_str("frame too large"),
+            SendError::QueueFull => f.write_str("transmit queue full"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct RxDescriptor {
+    address: u64,
+    length: u16,
+    checksum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct TxDescriptor {
+    address: u64,
+    length: u16,
+    checksum_offset: u8,
+    command: u8,
+    status: u8,
+    checksum_start: u8,
+    special: u16,
+}
+
+/// A ring of descriptors, and a buffer for each.
+struct Ring<D> {
+    descriptors: DmaFrame,
+    buffers: Vec<DmaFrame>,
+    len: usize,
+    _descriptor: core::marker::PhantomData<D>,
+}
+
+impl<D: Copy + Default> Ring<D> {
+    fn new(len: usize) -> Result<Ring<D>, KernelError> {
+        assert!(len * core::mem::size_of::<D>() <= DmaFrame::SIZE);
+
+        let buffers = (0..len / BUFFERS_PER_FRAME)
+            .map(|_| DmaFrame::new())
+            .collect::<Result<Vec<DmaFrame>, KernelError>>()?;
+
+        Ok(Ring {
+            descriptors: DmaFrame::new()?,
+            buffers,
+            len,
+            _descriptor: core::marker::PhantomData,
+        })
+    }
+
+    fn size(&self) -> u32 {
+        (self.len * core::mem::size_of::<D>()) as u32
+    }
+
+    fn descriptor(&self, index: usize) -> *mut D {
+        self.descriptors
+            .virt_addr()
+            .as_mut_ptr::<D>()
+            .wrapping_add(index)
+    }
+
+    fn read(&self, index: usize) -> D {
+        unsafe { ptr::read_volatile(self.descriptor(index)) }
+    }
+
+    fn write(&self, index: usize, descriptor: D) {
+        unsafe { ptr::write_volatile(self.descriptor(index), descriptor) };
+    }
+
+    fn buffer(&self, index: usize) -> (PhysAddr, VirtAddr) {
+        let frame = &self.buffers[index / BUFFERS_PER_FRAME];
+        let offset = (index % BUFFERS_PER_FRAME * BUFFER_SIZE) as u64;
+        (frame.phys_addr() + offset, frame.virt_addr() + offset)
+    }
+}
+
+struct Rx {
+    ring: Ring<RxDescriptor>,
+    // The next descriptor the card will fill
+    next: usize,
+}
+
+struct Tx {
+    ring: Ring<TxDescriptor>,
+    // The next free descriptor, which is also the ring's tail
+    tail: usize,
+    // The oldest descriptor that the card may still be working on
+    oldest: usize,
+    queued: u64,
+    sent: u64,
+}
+
+impl Tx {
+    /// Frees the descriptors that the card is done with.
+    fn reclaim(&mut self) {
+        while self.oldest != self.tail && self.ring.read(self.oldest).status & DESCRIPTOR_DONE != 0
+        {
+            self.oldest = (self.oldest + 1) % self.ring.len;
+            self.sent += 1;
+        }
+    }
+}
+
+pub struct E1000 {
+    pci: pci::Device,
+    registers: RegisterBlock,
+    mac_address: MacAddress,
+    rx: Mutex<Rx>,
+    tx: Mutex<Tx>,
+    rx_waker: AtomicWaker,
+    // Every task waiting for a packet to be sent, since there can be several
+    tx_wakers: Mutex<Vec<Waker>>,
+    // One permit per transmit descriptor that `send_frame` may fill. The ring
+    // is full with one descriptor left, since tail == oldest means empty.
+    tx_slots: Semaphore,
+    interrupts: AtomicU64,
+    stats: Mutex<DeviceStats>,
+}
+
+static DEVICE: OnceCell<Arc<E1000>> = OnceCell::uninit();
+
+impl E1000 {
+    fn new(pci: pci::Device) -> Result<E1000, E1000Error> {
+        let registers = match pci.bar(0) {
+            Some(Bar::Memory { address, size, .. }) => unsafe {
+                let base = memory::phys_to_virt(PhysAddr::new(address));
+                RegisterBlock::new("e1000", base, size as usize)
+            },
+            _ => return Err(E1000Error::NoRegisters),
+        };
+        pci.enable_bus_mastering();
+
+        let rx = Ring::new(RX_DESCRIPTORS).map_err(|_| E1000Error::OutOfMemory)?;
+        let tx = Ring::new(TX_DESCRIPTORS).map_err(|_| E1000Error::OutOfMemory)?;
+
+        let mut device = E1000 {
+            pci,
+            registers,
+            mac_address: MacAddress::default(),
+            rx: Mutex::new(Rx { ring: rx, next: 0 }),
+            tx: Mutex::new(Tx {
+                ring: tx,
+                tail: 0,
+                oldest: 0,
+                queued: 0,
+                sent: 0,
+            }),
+            rx_waker: AtomicWaker::new(),
+            tx_wakers: Mutex::new(Vec::new()),
+            tx_slots: Semaphore::new(TX_DESCRIPTORS - 1),
+            interrupts: AtomicU64::new(0),
+            stats: Mutex::new(DeviceStats::default()),
+        };
+
+        device.reset()?;
+        device.mac_address = device.read_mac_address()?;
+        device.init_rx();
+        device.init_tx();
+        Ok(device)
+    }
+
+    fn read(&self, register: usize) -> u32 {
+        self.registers.read(register)
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        self.registers.write(register, value);
+    }
+
+    fn poll_until(&self, mut done: impl FnMut() -> bool) -> Result<(), E1000Error> {
+        for _ in 0..POLL_ATTEMPTS {
+            if done() {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(E1000Error::Timeout)
+    }
+
+    fn reset(&self) -> Result<(), E1000Error> {
+        self.write(IMC, !0);
+        self.registers.set(CTRL, CTRL_RST);
+        self.poll_until(|| self.read(CTRL) & CTRL_RST == 0)?;
+
+        // The reset enables interrupts again
+        self.write(IMC, !0);
+        self.read(ICR);
+
+        self.registers.set(CTRL, CTRL_SLU | CTRL_ASDE);
+        Ok(())
+    }
+
+    fn read_eeprom(&self, word: u8) -> Result<u16, E1000Error> {
+        self.write(EERD, EERD_START | u32::from(word) << 8);
+        let mut value = 0;
+        self.poll_until(|| {
+            value = self.read(EERD);
+            value & EERD_DONE != 0
+        })?;
+        Ok((value >> 16) as u16)
+    }
+
+    /// Reads the MAC address from the first receive address register, where
+    /// the card puts the one from its EEPROM, or from the EEPROM itself.
+    fn read_mac_address(&self) -> Result<MacAddress, E1000Error> {
+        let mut mac = [0; 6];
+        let high = self.read(RAH0);
+
+        if high & RAH_AV != 0 {
+            mac[..4].copy_from_slice(&self.read(RAL0).to_le_bytes());
+            mac[4..].copy_from_slice(&(high as u16).to_le_bytes());
+        } else {
+            for word in 0..3 {
+                let bytes = self.read_eeprom(word)?.to_le_bytes();
+                mac[usize::from(word) * 2..][..2].copy_from_slice(&bytes);
+            }
+            self.write(RAL0, u32::from_le_bytes([mac[0], mac[1], mac[2], mac[3]]));
+            self.write(RAH0, u32::from(mac[4]) | u32::from(mac[5]) << 8 | RAH_AV);
+        }
+        Ok(MacAddress(mac))
+    }
+
+    fn init_rx(&self) {
+        let rx = self.rx.lock();
+        let ring = &rx.ring;
+
+        for index in 0..ring.len {
+            ring.write(
+                index,
+                RxDescriptor {
+                    address: ring.buffer(index).0.as_u64(),
+                    ..RxDescriptor::default()
+                },
+            );
+        }
+
+        // No multicast
+        for i in 0..128 {
+            self.write(MTA + i * 4, 0);
+        }
+
+        let base = ring.descriptors.phys_addr().as_u64();
+        self.write(RDBAL, base as u32);
+        self.write(RDBAH, (base >> 32) as u32);
+        self.write(RDLEN, ring.size());
+        self.write(RDH, 0);
+        // All descriptors but the one at the tail belong to the card
+        self.write(RDT, (ring.len - 1) as u32);
+        self.write(RCTL, RCTL_EN | RCTL_BAM | RCTL_BSIZE_2048 | RCTL_SECRC);
+    }
+
+    fn init_tx(&self) {
+        let tx = self.tx.lock();
+        let ring = &tx.ring;
+
+        let base = ring.descriptors.phys_addr().as_u64();
+        self.write(TDBAL, base as u32);
+        self.write(TDBAH, (base >> 32) as u32);
+        self.write(TDLEN, ring.size());
+        self.write(TDH, 0);
+        self.write(TDT, 0);
+        self.write(TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+        self.write(TIPG, TIPG_DEFAULT);
+    }
+
+    /// Routes the card's interrupt to `handle_interrupt`, with MSI if the card
+    /// has it, and enables the interrupts.
+    fn enable_interrupts(&self) -> Result<(), E1000Error> {
+        match self.pci.enable_msi(handle_interrupt) {
+            Ok(_) => {}
+            Err(pci::PciError::NoCapability) => {
+                if !irq::set_irq_handler(self.pci.interrupt_line(), handle_interrupt) {
+                    return Err(E1000Error::NoInterrupt);
+                }
+            }
+            Err(_) => return Err(E1000Error::NoInterrupt),
+        }
+
+        self.write(IMS, INT_ENABLED);
+        Ok(())
+    }
+
+    /// Stops the card's DMA: lets it send what's queued, then turns off
+    /// receiving, sending and its interrupts. Received frames that weren't
+    /// taken yet stay in the ring.
+    fn stop(&self) -> Result<(), E1000Error> {
+        let sent = self.poll_until(|| self.read(TDH) == self.read(TDT));
+        self.registers.clear(RCTL, RCTL_EN);
+        self.registers.clear(TCTL, TCTL_EN);
+        self.write(IMC, !0);
+        sent
+    }
+
+    /// Undoes `stop`.
+    fn start(&self) {
+        self.registers.set(RCTL, RCTL_EN);
+        self.registers.set(TCTL, TCTL_EN);
+        self.write(IMS, INT_ENABLED);
+    }
+
+    /// How many interrupts the card has raised.
+    pub fn interrupts(&self) -> u64 {
+        self.interrupts.load(Ordering::Relaxed)
+    }
+
+    /// Adds the statistics registers to the totals, and returns them.
+    fn update_stats(&self) -> DeviceStats {
+        // The high halves of the octet counters reset both halves, so they're
+        // read last
+        let read_u64 = |low, high| {
+            let low = u64::from(self.read(low));
+            low | u64::from(self.read(high)) << 32
+        };
+        interrupts::without_interrupts(|| {
+            let mut stats = self.stats.lock();
+            stats.rx_packets += u64::from(self.read(GPRC));
+            stats.rx_bytes += read_u64(GORCL, GORCH);
+            stats.rx_errors += [CRCERRS, ALGNERRC, SYMERRS, RXERRC]
+                .iter()
+                .map(|&register| u64::from(self.read(register)))
+                .sum::<u64>();
+            stats.rx_dropped += u64::from(self.read(MPC));
+            stats.tx_packets += u64::from(self.read(GPTC));
+            stats.tx_bytes += read_u64(GOTCL, GOTCH);
+            // Excessive and late collisions abort the transmission
+            stats.tx_errors += u64::from(self.read(ECOL)) + u64::from(self.read(LATECOL));
+            stats.collisions += u64::from(self.read(COLC));
+            *stats
+        })
+    }
+
+    /// Queues an Ethernet frame (without the checksum) for sending, and
+    /// returns its number for `transmitted`.
+    pub fn transmit(&self, frame: &[u8]) -> Result<u64, SendError> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(SendError::TooLarge);
+        }
+
+        interrupts::without_interrupts(|| {
+            let mut tx = self.tx.lock();
+            tx.reclaim();
+
+            let index = tx.tail;
+            let next = (index + 1) % tx.ring.len;
+            if next == tx.oldest {
+                return Err(SendError::QueueFull);
+            }
+
+            let (phys, virt) = tx.ring.buffer(index);
+            unsafe {
+                ptr::copy_nonoverlapping(frame.as_ptr(), virt.as_mut_ptr(), frame.len());
+            }
+            tx.ring.write(
+                index,
+                TxDescriptor {
+                    address: phys.as_u64(),
+                    length: frame.len() as u16,
+                    command: COMMAND_EOP | COMMAND_IFCS | COMMAND_RS,
+                    ..TxDescriptor::default()
+                },
+            );
+
+            tx.tail = next;
+            self.write(TDT, next as u32);
+
+            let number = tx.queued;
+            tx.queued += 1;
+            Ok(number)
+        })
+    }
+
+    /// Waits until the frame with the given number has been sent.
+    pub fn transmitted(&self, number: u64) -> Transmitted<'_> {
+        Transmitted {
+            device: self,
+            number,
+        }
+    }
+
+    /// Sends an Ethernet frame, waiting for room in the queue if necessary,
+    /// and then until it has been sent.
+    pub async fn send_frame(&self, frame: &[u8]) -> Result<(), NetError> {
+        // Senders queue up here instead of all polling a full ring. Frames
+        // queued with `transmit` directly don't take a permit, so the ring can
+        // still be full.
+        let _slot = self.tx_slots.acquire_async().await;
+
+        let number = loop {
+            match self.transmit(frame) {
+                Ok(number) => break number,
+                Err(SendError::TooLarge) => return Err(NetError::TooLarge),
+                Err(SendError::QueueFull) => {
+                    let oldest = interrupts::without_interrupts(|| self.tx.lock().sent);
+                    self.transmitted(oldest).await;
+                }
+            }
+        };
+
+        self.transmitted(number).await;
+        Ok(())
+    }
+
+    /// Takes the next received frame out of the ring, if there is one.
+    pub fn try_receive(&self) -> Option<PacketBuf> {
+        interrupts::without_interrupts(|| {
+            let mut rx = self.rx.lock();
+
+            loop {
+                let index = rx.next;
+                let descriptor = rx.ring.read(index);
+                if descriptor.status & DESCRIPTOR_DONE == 0 {
+                    return None;
+                }
+
+                // Frames always fit into one buffer, so anything else is
+                // broken, like frames with errors.
+                let complete = descriptor.status & DESCRIPTOR_END_OF_PACKET != 0;
+                let frame = if complete && descriptor.errors == 0 {
+                    let len = usize::from(descriptor.length).min(BUFFER_SIZE);
+                    let (_, virt) = rx.ring.buffer(index);
+                    let data = unsafe { core::slice::from_raw_parts(virt.as_ptr(), len) };
+                    Some(PacketBuf::from_slice(data))
+                } else {
+                    None
+                };
+
+                // Hand the descriptor back to the card
+                let (phys, _) = rx.ring.buffer(index);
+                rx.ring.write(
+                    index,
+                    RxDescriptor {
+                        address: phys.as_u64(),
+                        ..RxDescriptor::default()
+                    },
+                );
+                self.write(RDT, index as u32);
+                rx.next = (index + 1) % rx.ring.len;
+
+                if frame.is_some() {
+                    return frame;
+                }
+            }
+        })
+    }
+
+    /// The received frames, as they arrive. Only one task should take them.
+    pub fn frames(&self) -> FrameStream<'_> {
+        FrameStream { device: self }
+    }
+}
+
+/// Acknowledges the card's interrupt and wakes the tasks waiting for it.
+fn handle_interrupt() {
+    let device = match DEVICE.try_get() {
+        Ok(device) => device,
+        Err(_) => return,
+    };
+
+    // Reading the causes acknowledges them.
+    let causes = device.read(ICR);
+    if causes == 0 {
+        return;
+    }
+    device.interrupts.fetch_add(1, Ordering::Relaxed);
+
+    if causes & INT_RX != 0 {
+        device.rx_waker.wake();
+    }
+    if causes & INT_TXDW != 0 {
+        for waker in device.tx_wakers.lock().drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+pub struct FrameStream<'a> {
+    device: &'a E1000,
+}
+
+impl Stream for FrameStream<'_> {
+    type Item = PacketBuf;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<PacketBuf>> {
+        // Fast path
+        if let Some(frame) = self.device.try_receive() {
+            return Poll::Ready(Some(frame));
+        }
+
+        self.device.rx_waker.register(cx.waker());
+
+        match self.device.try_receive() {
+            Some(frame) => {
+                self.device.rx_waker.take();
+                Poll::Ready(Some(frame))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+pub struct Transmitted<'a> {
+    device: &'a E1000,
+    number: u64,
+}
+
+impl Future for Transmitted<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let device = self.device;
+
+        // With interrupts disabled, the interrupt handler can't run between
+        // the check and registering the waker.
+        interrupts::without_interrupts(|| {
+            let mut tx = device.tx.lock();
+            tx.reclaim();
+            if tx.sent > self.number {
+                return Poll::Ready(());
+            }
+
+            let mut wakers = device.tx_wakers.lock();
+            if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                wakers.push(cx.waker().clone());
+            }
+            Poll::Pending
+        })
+    }
+}
+
+impl NetDevice for E1000 {
+    fn mac_address(&self) -> MacAddress {
+        self.mac_address
+    }
+
+    fn mtu(&self) -> usize {
+        MTU
+    }
+
+    fn send(&self, frame: PacketBuf) -> NetFuture<'_, Result<(), NetError>> {
+        Box::pin(async move { self.send_frame(&frame).await })
+    }
+
+    fn receive(&self) -> NetFuture<'_, PacketBuf> {
+        Box::pin(async move { self.frames().next().await.unwrap() })
+    }
+
+    fn link_up(&self) -> bool {
+        self.read(STATUS) & STATUS_LU != 0
+    }
+
+    fn link_speed(&self) -> Option<u32> {
+        if !self.link_up() {
+            return None;
+        }
+        match (self.read(STATUS) & STATUS_SPEED) >> STATUS_SPEED_SHIFT {
+            0b00 => Some(10),
+            0b01 => Some(100),
+            _ => Some(1000),
+        }
+    }
+
+    fn stats(&self) -> Option<DeviceStats> {
+        Some(self.update_stats())
+    }
+}
+
+impl Device for E1000 {
+    fn name(&self) -> String {
+        "e1000 Ethernet".into()
+    }
+
+    fn describe(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        write!(out, "{}", self.mac_address)?;
+        match self.link_speed() {
+            Some(speed) => write!(out, ", link up at {} Mbit/s", speed),
+            None => write!(out, ", link down"),
+        }
+    }
+
+    fn suspend(&self) -> Result<(), PowerError> {
+        self.stop().map_err(|_| PowerError::Timeout)
+    }
+
+    fn resume(&self) -> Result<(), PowerError> {
+        self.start();
+        Ok(())
+    }
+
+    fn shutdown(&self) -> Result<(), PowerError> {
+        self.suspend()
+    }
+
+    // Like `stop`, but without waiting for the queued packets to go out
+    fn quiesce(&self) {
+        self.registers.clear(RCTL, RCTL_EN);
+        self.registers.clear(TCTL, TCTL_EN);
+        self.write(IMC, !0);
+    }
+}
+
+driver! {
+    static DRIVER = Driver {
+        name: "e1000",
+        probe: Probe::PciIds(&PCI_IDS),
+        depends_on: &[],
+        init: probe,
+    };
+}
+
+/// Sets up a card that `drivers::init_all` found. Only the first card is
+/// supported.
+fn probe(pci: Option<&pci::Device>) -> Result<(), KernelError> {
+    let pci = match pci {
+        Some(&pci) => pci,
+        None => return Err(KernelError::InvalidArgument),
+    };
+    if let Some(device) = device() {
+        warn!(
+            "e1000 at {}: only the one at {} is used",
+            pci.address, device.pci.address
+        );
+        return Ok(());
+    }
+
+    // The interrupt handler looks the card up, so it has to be in place
+    // before interrupts are enabled.
+    let result = E1000::new(pci).and_then(|device| {
+        DEVICE
+            .try_init_once(|| Arc::new(device))
+            .expect("e1000 cards probed concurrently");
+        self::device().unwrap().enable_interrupts()
+    });
+
+    result?;
+
+    let device = device().unwrap();
+    let name = net::register("eth", device.clone());
+    device::add(pci::node(pci.address), device.clone());
+    info!("{}: e1000 at {}, {}", name, pci.address, device.mac_address);
+    Ok(())
+}
+
+/// The card set up by `drivers::init_all`.
+pub fn device() -> Option<&'static Arc<E1000>> {
+    DEVICE.try_get().ok()
+}
@@ -0,0 +1,63 @@
+// The PS/2 controller (the 8042) and the keyboard on its first port. The
+// keyboard itself is handled by the keyboard interrupt (see interrupts.rs and
+// task/keyboard.rs) as it always was. This driver only looks for the
+// controller and puts the two in the device tree.
+//
+// The controller's two ports are here for everyone who uses them: the keyboard
+// interrupt reads scancodes from `DATA`, rebooting pulses the reset line
+// through `COMMAND`, and the panic screen polls both for a key press.
+//
+// Without a controller, reading its status port gets all ones, since nothing
+// drives the bus. The BIOS has set up the controller and the keyboard long
+// before, and the mouse port isn't used, so there's nothing to set up.
+
+use crate::device::{self, Bus, Device};
+use crate::driver;
+use crate::drivers::{Driver, Probe};
+use crate::error::KernelError;
+use crate::io::PortRange;
+use crate::pci;
+use alloc::{string::String, sync::Arc};
+use core::fmt::{self, Write};
+
+/// Scancodes, and data for commands.
+pub static DATA: PortRange = PortRange::new("ps2", 0x60, 1);
+/// Reads the status register, writes commands.
+pub static COMMAND: PortRange = PortRange::new("ps2", 0x64, 1);
+
+pub const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+pub const STATUS_INPUT_FULL: u8 = 1 << 1;
+/// The byte waiting in DATA is from the second port, usually a mouse.
+pub const STATUS_AUX_DATA: u8 = 1 << 5;
+pub const COMMAND_PULSE_RESET: u8 = 0xFE;
+
+driver! {
+    static DRIVER = Driver {
+        name: "ps2",
+        probe: Probe::Platform,
+        depends_on: &[],
+        init: probe,
+    };
+}
+
+struct Keyboard;
+
+impl Device for Keyboard {
+    fn name(&self) -> String {
+        "keyboard".into()
+    }
+
+    fn describe(&self, out: &mut dyn Write) -> fmt::Result {
+        out.write_str("IRQ 1, scancode set 1")
+    }
+}
+
+fn probe(_: Option<&pci::Device>) -> Result<(), KernelError> {
+    let status: u8 = unsafe { COMMAND.read(0) };
+    if status == 0xFF {
+        return Err(KernelError::NotFound);
+    }
+    let controller = device::add(None, Arc::new(Bus("PS/2 controller".into())));
+    device::add(Some(controller), Arc::new(Keyboard));
+    Ok(())
+}
@@ -0,0 +1,176 @@
+// Just enough of an ELF parser to load statically linked 64-bit executables:
+// we read the file header and the program headers, and leave sections, symbols
+// and relocations alone. All multi-byte fields are little endian on x86_64.
+//
+// Reference: the System V ABI, "Object Files" chapter, and `man 5 elf`.
+
+use core::fmt;
+
+const MAGIC: &[u8; 4] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const TYPE_EXEC: u16 = 2;
+const MACHINE_X86_64: u16 = 0x3E;
+
+const FILE_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+/// A loadable segment.
+pub const PT_LOAD: u32 = 1;
+
+/// Segment permission flags.
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    TooShort,
+    BadMagic,
+    /// Not a 64-bit little endian x86_64 executable.
+    Unsupported,
+    /// A program header points outside of the file.
+    BadProgramHeader,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            ElfError::TooShort => "file too short",
+            ElfError::BadMagic => "not an ELF file",
+            ElfError::Unsupported => "not a 64-bit x86_64 executable",
+            ElfError::BadProgramHeader => "bad program header",
+        };
+
+        f.write_str(message)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+}
+
+pub struct Elf<'a> {
+    data: &'a [u8],
+    entry: u64,
+    ph_offset: usize,
+    ph_entry_size: usize,
+    ph_count: usize,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(buf)
+}
+
+impl<'a> Elf<'a> {
+    /// Checks the file header and the location of the program headers.
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        if data.len() < FILE_HEADER_SIZE {
+            return Err(ElfError::TooShort);
+        }
+        if &data[..4] != MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != CLASS_64
+            || data[5] != DATA_LITTLE_ENDIAN
+            || read_u16(data, 16) != TYPE_EXEC
+            || read_u16(data, 18) != MACHINE_X86_64
+        {
+            return Err(ElfError::Unsupported);
+        }
+
+        let elf = Elf {
+            data,
+            entry: read_u64(data, 24),
+            ph_offset: read_u64(data, 32) as usize,
+            ph_entry_size: usize::from(read_u16(data, 54)),
+            ph_count: usize::from(read_u16(data, 56)),
+        };
+
+        let ph_end = elf
+            .ph_entry_size
+            .checked_mul(elf.ph_count)
+            .and_then(|size| size.checked_add(elf.ph_offset));
+        if elf.ph_entry_size < PROGRAM_HEADER_SIZE
+            || !matches!(ph_end, Some(end) if end <= data.len())
+        {
+            return Err(ElfError::BadProgramHeader);
+        }
+
+        Ok(elf)
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..self.ph_count).map(move |i| {
+            let offset = self.ph_offset + i * self.ph_entry_size;
+
+            ProgramHeader {
+                kind: read_u32(self.data, offset),
+                flags: read_u32(self.data, offset + 4),
+                offset: read_u64(self.data, offset + 8),
+                vaddr: read_u64(self.data, offset + 16),
+                file_size: read_u64(self.data, offset + 32),
+                mem_size: read_u64(self.data, offset + 40),
+            }
+        })
+    }
+
+    /// Returns the part of the file that the segment is initialized from.
+    pub fn segment_data(&self, header: &ProgramHeader) -> Result<&'a [u8], ElfError> {
+        let start = header.offset as usize;
+        let end = start
+            .checked_add(header.file_size as usize)
+            .ok_or(ElfError::BadProgramHeader)?;
+
+        self.data.get(start..end).ok_or(ElfError::BadProgramHeader)
+    }
+}
+
+#[test_case]
+fn test_parse_header() {
+    let mut data = [0u8; FILE_HEADER_SIZE + PROGRAM_HEADER_SIZE];
+    data[..4].copy_from_slice(MAGIC);
+    data[4] = CLASS_64;
+    data[5] = DATA_LITTLE_ENDIAN;
+    data[16..18].copy_from_slice(&TYPE_EXEC.to_le_bytes());
+    data[18..20].copy_from_slice(&MACHINE_X86_64.to_le_bytes());
+    data[24..32].copy_from_slice(&0x1000_0000_0000u64.to_le_bytes());
+    data[32..40].copy_from_slice(&(FILE_HEADER_SIZE as u64).to_le_bytes());
+    data[54..56].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    data[56..58].copy_from_slice(&1u16.to_le_bytes());
+    data[64..68].copy_from_slice(&PT_LOAD.to_le_bytes());
+    data[68..72].copy_from_slice(&(PF_R | PF_X).to_le_bytes());
+
+    let elf = Elf::parse(&data).unwrap();
+    let header = elf.program_headers().next().unwrap();
+    assert_eq!(elf.entry(), 0x1000_0000_0000);
+    assert_eq!(header.kind, PT_LOAD);
+    assert_eq!(header.flags, PF_R | PF_X);
+
+    // Claim a second program header that doesn't fit in the file
+    data[56..58].copy_from_slice(&2u16.to_le_bytes());
+    assert_eq!(Elf::parse(&data).err(), Some(ElfError::BadProgramHeader));
+    assert_eq!(Elf::parse(&data[..10]).err(), Some(ElfError::TooShort));
+}
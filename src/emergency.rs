@@ -0,0 +1,285 @@
+// Where a double fault ends up, instead of freezing. By then the kernel's own
+// state can't be trusted: the stack that faulted is gone, and whatever lock
+// the faulting code held stays held. So after the crash dump, `enter` moves
+// to a stack of its own and runs a tiny command loop on the serial port, which
+// talks to the UART's registers directly and uses neither the heap nor any
+// lock, to let a person look around before rebooting:
+//
+//     regs               the faulting code's registers
+//     x <addr> [len]     dump memory, if it's mapped
+//     dmesg [lines]      the end of the log, unless the logger was in the middle
+//                        of writing it
+//     reboot
+//
+// With `serial=framed`, it speaks frames on the console channel like the rest
+// of the kernel. Tests and `test` boots skip it and panic as before, since
+// nobody would answer.
+
+use crate::serial::mux::{self, Channel, Deframer, Framing};
+use crate::serial::{self, uart::Uart};
+#[cfg(test)]
+use crate::HostTest;
+use crate::{hlt_loop, logger, memory, power};
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr2, Cr3};
+use x86_64::structures::idt::{InterruptStackFrame, InterruptStackFrameValue};
+use x86_64::VirtAddr;
+
+const STACK_SIZE: usize = 4096 * 4;
+const LINE_MAX: usize = 80;
+/// The most `x` dumps at once.
+const MAX_DUMP: u64 = 512;
+const DEFAULT_DUMP: u64 = 64;
+const DEFAULT_DMESG_LINES: usize = 20;
+
+#[repr(align(16))]
+struct Stack([u8; STACK_SIZE]);
+
+static mut STACK: Stack = Stack([0; STACK_SIZE]);
+static ENTERED: AtomicBool = AtomicBool::new(false);
+
+/// Runs the command loop on its own stack, for good. A second double fault
+/// while it runs just halts.
+pub fn enter(frame: &InterruptStackFrame) -> ! {
+    if ENTERED.swap(true, Ordering::SeqCst) {
+        hlt_loop();
+    }
+
+    let frame: *const InterruptStackFrameValue = &**frame;
+    unsafe {
+        let top = (core::ptr::addr_of_mut!(STACK.0) as *mut u8).add(STACK_SIZE);
+        asm!(
+            "mov rsp, {top}",
+            "call {repl}",
+            top = in(reg) top,
+            repl = sym repl,
+            in("rdi") frame,
+            options(noreturn)
+        );
+    }
+}
+
+extern "C" fn repl(frame: *const InterruptStackFrameValue) -> ! {
+    // The frame is on the double fault stack, which another fault would
+    // overwrite
+    let frame = unsafe { *frame };
+    let mut console = Console {
+        uart: unsafe { serial::steal() },
+        framing: mux::framing(),
+        deframer: Deframer::new(),
+    };
+
+    let _ = writeln!(
+        console,
+        "\ndouble fault at {:#x}, emergency console (`help` for the commands)",
+        frame.instruction_pointer.as_u64()
+    );
+    let mut line = [0u8; LINE_MAX];
+    loop {
+        let _ = console.write_str("!> ");
+        let len = console.read_line(&mut line);
+        let line = core::str::from_utf8(&line[..len]).unwrap_or("");
+        // Writing to the UART can't fail
+        let _ = run(&mut console, &frame, line);
+    }
+}
+
+fn run(out: &mut Console, frame: &InterruptStackFrameValue, line: &str) -> fmt::Result {
+    match parse(line) {
+        Command::Empty => Ok(()),
+        Command::Help => writeln!(
+            out,
+            "regs, x <addr> [len], dmesg [lines], reboot; nothing here uses the heap or a lock"
+        ),
+        Command::Regs => {
+            writeln!(out, "rip    {:#018x}", frame.instruction_pointer.as_u64())?;
+            writeln!(out, "rsp    {:#018x}", frame.stack_pointer.as_u64())?;
+            writeln!(out, "rflags {:#018x}", frame.cpu_flags)?;
+            writeln!(out, "cs     {:#06x}", frame.code_segment)?;
+            writeln!(out, "ss     {:#06x}", frame.stack_segment)?;
+            writeln!(out, "cr2    {:#018x}", Cr2::read_raw())?;
+            writeln!(
+                out,
+                "cr3    {:#018x}",
+                Cr3::read().0.start_address().as_u64()
+            )
+        }
+        Command::Dump { addr, len } => dump(out, addr, len),
+        Command::Dmesg(lines) => match logger::try_dmesg_tail(out, lines) {
+            Some(result) => result,
+            None => writeln!(out, "the log is locked"),
+        },
+        Command::Reboot => power::reset(),
+        Command::Usage(usage) => writeln!(out, "usage: {}", usage),
+        Command::Unknown(name) => writeln!(out, "{}: no such command", name),
+    }
+}
+
+/// Dumps `len` bytes at `addr`, 16 to a line, stopping at the first page
+/// that isn't mapped: a page fault now would be the end.
+fn dump(out: &mut dyn Write, addr: u64, len: u64) -> fmt::Result {
+    let end = addr.saturating_add(len);
+    let mut line = addr & !15;
+    while line < end {
+        let addr = match VirtAddr::try_new(line) {
+            Ok(addr) if memory::translate_addr(addr).is_some() => addr,
+            _ => return writeln!(out, "{:#018x}: not mapped", line),
+        };
+        write!(out, "{:#018x}:", addr.as_u64())?;
+        let bytes = unsafe { core::slice::from_raw_parts(addr.as_ptr::<u8>(), 16) };
+        for &byte in bytes {
+            write!(out, " {:02x}", byte)?;
+        }
+        out.write_str("  ")?;
+        for &byte in bytes {
+            let c = match byte {
+                0x20..=0x7E => char::from(byte),
+                _ => '.',
+            };
+            out.write_char(c)?;
+        }
+        out.write_char('\n')?;
+        line += 16;
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command<'a> {
+    Empty,
+    Help,
+    Regs,
+    Dump { addr: u64, len: u64 },
+    Dmesg(usize),
+    Reboot,
+    Usage(&'static str),
+    Unknown(&'a str),
+}
+
+fn parse(line: &str) -> Command<'_> {
+    fn hex(s: &str) -> Option<u64> {
+        u64::from_str_radix(s.trim_start_matches("0x"), 16).ok()
+    }
+
+    let mut words = line.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name,
+        None => return Command::Empty,
+    };
+    let args = (words.next(), words.next(), words.next());
+    match (name, args) {
+        ("help", _) => Command::Help,
+        ("regs", _) => Command::Regs,
+        ("reboot", _) => Command::Reboot,
+        ("x", (Some(addr), len, None)) => {
+            let len = len.map_or(Some(DEFAULT_DUMP), |len| len.parse().ok());
+            match (hex(addr), len) {
+                (Some(addr), Some(len)) => Command::Dump {
+                    addr,
+                    len: len.min(MAX_DUMP),
+                },
+                _ => Command::Usage("x <hex addr> [len]"),
+            }
+        }
+        ("x", _) => Command::Usage("x <hex addr> [len]"),
+        ("dmesg", (None, _, _)) => Command::Dmesg(DEFAULT_DMESG_LINES),
+        ("dmesg", (Some(lines), None, _)) => match lines.parse() {
+            Ok(lines) => Command::Dmesg(lines),
+            Err(_) => Command::Usage("dmesg [lines]"),
+        },
+        ("dmesg", _) => Command::Usage("dmesg [lines]"),
+        (name, _) => Command::Unknown(name),
+    }
+}
+
+/// The serial port, polled, in the kernel's framing.
+struct Console {
+    uart: Uart,
+    framing: Framing,
+    deframer: Deframer,
+}
+
+impl Console {
+    fn read_byte(&mut self) -> u8 {
+        loop {
+            let byte = match self.uart.try_receive() {
+                Some(byte) => byte,
+                None => {
+                    core::hint::spin_loop();
+                    continue;
+                }
+            };
+            match self.framing {
+                Framing::Plain => return byte,
+                Framing::Framed => {
+                    if let Some((Channel::Console, byte)) = self.deframer.feed(byte) {
+                        return byte;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads a line into `line`, echoing it, and returns its length. What
+    /// doesn't fit is dropped.
+    fn read_line(&mut self, line: &mut [u8]) -> usize {
+        let mut len = 0;
+        loop {
+            match self.read_byte() {
+                b'\r' | b'\n' => {
+                    let _ = self.write_str("\n");
+                    return len;
+                }
+                // Backspace and delete
+                0x08 | 0x7F if len > 0 => {
+                    len -= 1;
+                    let _ = self.write_str("\x08 \x08");
+                }
+                byte @ 0x20..=0x7E if len < line.len() => {
+                    line[len] = byte;
+                    len += 1;
+                    let mut echo = [0; 4];
+                    let _ = self.write_str(char::from(byte).encode_utf8(&mut echo));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let uart = &mut self.uart;
+        let mut send = |byte| uart.send(byte);
+        mux::Writer::new(&mut send, Channel::Console, self.framing).write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[test_case]
+static PARSES_COMMANDS: HostTest = HostTest::new("emergency::parses_commands", || {
+    assert_eq!(parse("  "), Command::Empty);
+    assert_eq!(parse("regs"), Command::Regs);
+    assert_eq!(
+        parse("x 0xb8000"),
+        Command::Dump {
+            addr: 0xb8000,
+            len: DEFAULT_DUMP
+        }
+    );
+    assert_eq!(
+        parse("x ffff8000 4096"),
+        Command::Dump {
+            addr: 0xffff_8000,
+            len: MAX_DUMP
+        }
+    );
+    assert_eq!(parse("x"), Command::Usage("x <hex addr> [len]"));
+    assert_eq!(parse("x zz"), Command::Usage("x <hex addr> [len]"));
+    assert_eq!(parse("dmesg"), Command::Dmesg(DEFAULT_DMESG_LINES));
+    assert_eq!(parse("dmesg 5"), Command::Dmesg(5));
+    assert_eq!(parse("dmesg five"), Command::Usage("dmesg [lines]"));
+    assert_eq!(parse("ls /"), Command::Unknown("ls"));
+});
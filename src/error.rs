@@ -0,0 +1,177 @@
+// The error that goes between subsystems. Each subsystem keeps its own error
+// type for what only it can go wrong with, like `FsError::NotADirectory` or
+// `E1000Error::NoRegisters`, and callers within the subsystem match on it.
+// Once an error leaves for somewhere that doesn't know the subsystem, like
+// `drivers::init_all` calling a driver's `init`, it becomes a `KernelError`,
+// which `?` does through the `From` impls here.
+//
+// The kinds are coarse on purpose, about what the caller could do about it:
+// try again later, free some memory, pass a different argument, or give up.
+
+use crate::block::BlockError;
+use crate::device::PowerError;
+use crate::drivers::e1000::E1000Error;
+use crate::fs::FsError;
+use crate::memory::address_space::AddressSpaceError;
+use crate::net::NetError;
+use crate::pci::PciError;
+use crate::serial::uart::UartError;
+#[cfg(test)]
+use crate::HostTest;
+use core::fmt;
+use x86_64::structures::paging::{mapper::MapToError, Size4KiB};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelError {
+    /// There are no frames, heap or address space left.
+    NoMemory,
+    NotFound,
+    AlreadyExists,
+    /// Someone else is using it, or there's no room left in a fixed-size
+    /// queue or table.
+    Busy,
+    InvalidArgument,
+    /// The hardware, or the data on it, failed.
+    Io,
+    Timeout,
+    ReadOnly,
+    /// The hardware or the data uses a feature that we don't support.
+    Unsupported,
+}
+
+impl fmt::Display for KernelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            KernelError::NoMemory => "out of memory",
+            KernelError::NotFound => "not found",
+            KernelError::AlreadyExists => "already exists",
+            KernelError::Busy => "busy",
+            KernelError::InvalidArgument => "invalid argument",
+            KernelError::Io => "I/O error",
+            KernelError::Timeout => "timed out",
+            KernelError::ReadOnly => "read-only",
+            KernelError::Unsupported => "not supported",
+        })
+    }
+}
+
+impl From<MapToError<Size4KiB>> for KernelError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        match error {
+            MapToError::FrameAllocationFailed => KernelError::NoMemory,
+            MapToError::ParentEntryHugePage | MapToError::PageAlreadyMapped(_) => {
+                KernelError::AlreadyExists
+            }
+        }
+    }
+}
+
+impl From<AddressSpaceError> for KernelError {
+    fn from(error: AddressSpaceError) -> Self {
+        match error {
+            AddressSpaceError::OutsideUserSpace | AddressSpaceError::NotMapped => {
+                KernelError::InvalidArgument
+            }
+            AddressSpaceError::OutOfMemory => KernelError::NoMemory,
+        }
+    }
+}
+
+impl From<BlockError> for KernelError {
+    fn from(error: BlockError) -> Self {
+        match error {
+            BlockError::OutOfRange | BlockError::BadBufferSize => KernelError::InvalidArgument,
+            BlockError::ReadOnly => KernelError::ReadOnly,
+            BlockError::Io => KernelError::Io,
+        }
+    }
+}
+
+impl From<FsError> for KernelError {
+    fn from(error: FsError) -> Self {
+        match error {
+            FsError::NotFound => KernelError::NotFound,
+            FsError::NotADirectory | FsError::IsADirectory | FsError::InvalidPath => {
+                KernelError::InvalidArgument
+            }
+            FsError::AlreadyExists => KernelError::AlreadyExists,
+            FsError::NotEmpty => KernelError::Busy,
+            FsError::Corrupt => KernelError::Io,
+            FsError::Unsupported => KernelError::Unsupported,
+            FsError::ReadOnly => KernelError::ReadOnly,
+            FsError::NoMemory | FsError::NoSpace => KernelError::NoMemory,
+            FsError::FileTooLarge => KernelError::InvalidArgument,
+            FsError::Block(error) => error.into(),
+        }
+    }
+}
+
+impl From<NetError> for KernelError {
+    fn from(error: NetError) -> Self {
+        match error {
+            NetError::TooLarge => KernelError::InvalidArgument,
+            NetError::NoAddress | NetError::NoRoute => KernelError::NotFound,
+            NetError::AddressInUse | NetError::Unresolved => KernelError::Busy,
+            NetError::Down
+            | NetError::Io
+            | NetError::ConnectionRefused
+            | NetError::ConnectionClosed => KernelError::Io,
+        }
+    }
+}
+
+impl From<PciError> for KernelError {
+    fn from(error: PciError) -> Self {
+        match error {
+            PciError::NoCapability | PciError::BadBar => KernelError::Unsupported,
+            PciError::NoVectors => KernelError::Busy,
+            PciError::TooManyVectors => KernelError::InvalidArgument,
+        }
+    }
+}
+
+impl From<E1000Error> for KernelError {
+    fn from(error: E1000Error) -> Self {
+        match error {
+            E1000Error::NoRegisters | E1000Error::NoInterrupt => KernelError::Unsupported,
+            E1000Error::OutOfMemory => KernelError::NoMemory,
+            E1000Error::Timeout => KernelError::Timeout,
+        }
+    }
+}
+
+impl From<UartError> for KernelError {
+    fn from(error: UartError) -> Self {
+        match error {
+            UartError::InvalidBaudRate(_) => KernelError::InvalidArgument,
+            UartError::NotPresent(_) => KernelError::NotFound,
+        }
+    }
+}
+
+impl From<PowerError> for KernelError {
+    fn from(error: PowerError) -> Self {
+        match error {
+            PowerError::Timeout => KernelError::Timeout,
+            PowerError::Io => KernelError::Io,
+        }
+    }
+}
+
+#[test_case]
+static CONVERTS_ERRORS: HostTest = HostTest::new("error::converts_errors", || {
+    fn mount() -> Result<(), FsError> {
+        Err(FsError::Block(BlockError::ReadOnly))
+    }
+    fn init() -> Result<(), KernelError> {
+        mount()?;
+        Ok(())
+    }
+
+    assert_eq!(init(), Err(KernelError::ReadOnly));
+    assert_eq!(
+        KernelError::from(MapToError::<Size4KiB>::FrameAllocationFailed),
+        KernelError::NoMemory
+    );
+    assert_eq!(alloc::format!("{}", KernelError::NoMemory), "out of memory");
+});
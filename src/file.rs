@@ -0,0 +1,248 @@
+// File descriptors. Each process has a table of the things it has open, and
+// refers to them by their index in the table, its file descriptor. A
+// descriptor is either the console or a file in the VFS, which remembers the
+// path it was opened with and where the process is in it.
+//
+// A process starts out with the console as its standard input (0), output (1)
+// and error (2). Like on Unix, `open` hands out the lowest descriptor that is
+// free, so a process that closes its standard input and opens a file gets it
+// as its new standard input.
+//
+// The tables are kept here, by process, rather than in `Process`, like the
+// ports in ipc.rs; `release` drops a process's table when it exits.
+
+use crate::fs::{self, FileType, FsError};
+use crate::process::{self, Pid};
+use crate::tty;
+use alloc::{collections::BTreeMap, string::String, vec, vec::Vec};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+/// How many files a process can have open at once.
+pub const MAX_FDS: usize = 64;
+
+/// The flags for `open`.
+pub mod flags {
+    pub const READ: u64 = 1 << 0;
+    pub const WRITE: u64 = 1 << 1;
+    /// Creates the file if it doesn't exist.
+    pub const CREATE: u64 = 1 << 2;
+    /// Cuts the file off to 0 bytes, if it is opened for writing.
+    pub const TRUNCATE: u64 = 1 << 3;
+    /// Makes every write go to the end of the file.
+    pub const APPEND: u64 = 1 << 4;
+
+    pub const ALL: u64 = READ | WRITE | CREATE | TRUNCATE | APPEND;
+}
+
+/// Where `seek` counts from, with the values of Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Whence {
+    Start,
+    Current,
+    End,
+}
+
+impl Whence {
+    pub fn from_u64(whence: u64) -> Option<Whence> {
+        match whence {
+            0 => Some(Whence::Start),
+            1 => Some(Whence::Current),
+            2 => Some(Whence::End),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileError {
+    /// The descriptor isn't open, or wasn't opened for what was attempted.
+    BadFd,
+    /// The process has `MAX_FDS` files open already.
+    TooManyFiles,
+    /// Unknown flags, or a seek to before the start of the file.
+    InvalidArgument,
+    /// The console has no position to seek to.
+    NotSeekable,
+    Fs(FsError),
+}
+
+impl From<FsError> for FileError {
+    fn from(error: FsError) -> Self {
+        FileError::Fs(error)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Descriptor {
+    Console,
+    File(OpenFile),
+}
+
+#[derive(Debug, Clone)]
+struct OpenFile {
+    path: String,
+    offset: u64,
+    flags: u64,
+}
+
+type Table = Vec<Option<Descriptor>>;
+
+static TABLES: Mutex<BTreeMap<Pid, Table>> = Mutex::new(BTreeMap::new());
+
+fn new_table() -> Table {
+    vec![
+        Some(Descriptor::Console),
+        Some(Descriptor::Console),
+        Some(Descriptor::Console),
+    ]
+}
+
+/// Runs `f` on the current process's table, which is created on first use.
+fn with_table<T>(f: impl FnOnce(&mut Table) -> T) -> T {
+    let current = process::current();
+    interrupts::without_interrupts(|| f(TABLES.lock().entry(current).or_insert_with(new_table)))
+}
+
+fn get(fd: usize) -> Result<Descriptor, FileError> {
+    with_table(|table| table.get(fd).cloned().flatten().ok_or(FileError::BadFd))
+}
+
+/// Opens the file at `path` for the current process, and returns its
+/// descriptor.
+pub fn open(path: &str, flags: u64) -> Result<usize, FileError> {
+    if flags & !flags::ALL != 0 {
+        return Err(FileError::InvalidArgument);
+    }
+    let writable = flags & flags::WRITE != 0;
+
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.file_type == FileType::Directory && writable => {
+            return Err(FsError::IsADirectory.into())
+        }
+        Ok(_) => {}
+        Err(FsError::NotFound) if flags & flags::CREATE != 0 => fs::create_file(path)?,
+        Err(error) => return Err(error.into()),
+    }
+    if writable && flags & flags::TRUNCATE != 0 {
+        fs::truncate(path, 0)?;
+    }
+
+    let descriptor = Descriptor::File(OpenFile {
+        path: String::from(path),
+        offset: 0,
+        flags,
+    });
+
+    with_table(|table| {
+        let fd = match table.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None if table.len() < MAX_FDS => {
+                table.push(None);
+                table.len() - 1
+            }
+            None => return Err(FileError::TooManyFiles),
+        };
+
+        table[fd] = Some(descriptor);
+        Ok(fd)
+    })
+}
+
+pub fn close(fd: usize) -> Result<(), FileError> {
+    with_table(|table| match table.get_mut(fd) {
+        Some(descriptor @ Some(_)) => {
+            *descriptor = None;
+            Ok(())
+        }
+        _ => Err(FileError::BadFd),
+    })
+}
+
+/// Moves the file's position to the given offset, if the descriptor still
+/// refers to the file at `path`. It may have been closed, or even reused, while
+/// we were reading or writing without the lock.
+fn set_offset(fd: usize, path: &str, offset: u64) {
+    with_table(|table| {
+        if let Some(Some(Descriptor::File(file))) = table.get_mut(fd) {
+            if file.path == path {
+                file.offset = offset;
+            }
+        }
+    });
+}
+
+/// Reads from a descriptor of the current process into `buf`, and returns how
+/// much was read. Reading from the console blocks until there is input.
+pub fn read(fd: usize, buf: &mut [u8]) -> Result<usize, FileError> {
+    match get(fd)? {
+        Descriptor::Console => Ok(tty::read(buf)),
+        Descriptor::File(file) => {
+            if file.flags & flags::READ == 0 {
+                return Err(FileError::BadFd);
+            }
+
+            let len = fs::read(&file.path, file.offset, buf)?;
+            set_offset(fd, &file.path, file.offset + len as u64);
+            Ok(len)
+        }
+    }
+}
+
+/// Writes `data` to a descriptor of the current process, and returns how much
+/// was written.
+pub fn write(fd: usize, data: &[u8]) -> Result<usize, FileError> {
+    match get(fd)? {
+        Descriptor::Console => {
+            tty::write(data);
+            Ok(data.len())
+        }
+        Descriptor::File(file) => {
+            if file.flags & flags::WRITE == 0 {
+                return Err(FileError::BadFd);
+            }
+
+            let offset = if file.flags & flags::APPEND != 0 {
+                fs::metadata(&file.path)?.size
+            } else {
+                file.offset
+            };
+            let len = fs::write(&file.path, offset, data)?;
+            set_offset(fd, &file.path, offset + len as u64);
+            Ok(len)
+        }
+    }
+}
+
+/// Moves the position of a file, and returns the new position. It may be past
+/// the end of the file, and writing there fills the gap with zeroes.
+pub fn seek(fd: usize, offset: i64, whence: Whence) -> Result<u64, FileError> {
+    let file = match get(fd)? {
+        Descriptor::Console => return Err(FileError::NotSeekable),
+        Descriptor::File(file) => file,
+    };
+
+    let base = match whence {
+        Whence::Start => 0,
+        Whence::Current => file.offset,
+        Whence::End => fs::metadata(&file.path)?.size,
+    };
+    let position = if offset < 0 {
+        base.checked_sub(offset.unsigned_abs())
+    } else {
+        base.checked_add(offset as u64)
+    }
+    .ok_or(FileError::InvalidArgument)?;
+
+    set_offset(fd, &file.path, position);
+    Ok(position)
+}
+
+/// Closes all files of the process. Called when a process exits.
+pub(crate) fn release(pid: Pid) {
+    interrupts::without_interrupts(|| TABLES.lock().remove(&pid));
+}
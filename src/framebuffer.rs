@@ -0,0 +1,144 @@
+// A text console on a pixel framebuffer, for machines without the VGA text
+// buffer. Booting through UEFI leaves the display in a graphics mode that the
+// firmware's GOP set up, and the bootloader hands us its framebuffer: rows of
+// pixels, `stride` pixels apart, in one of a few pixel formats.
+//
+// Characters are drawn with the Noto Sans Mono bitmap font, whose glyphs are
+// anti-aliased: every pixel of a glyph is an intensity from 0 to 255. They're
+// drawn green on black, like the VGA console. When the screen is full, it
+// scrolls up a line.
+
+use bootloader_api::info::{FrameBuffer, FrameBufferInfo, PixelFormat};
+use core::fmt::{self, Write};
+use noto_sans_mono_bitmap::{get_raster, get_raster_width, FontWeight, RasterHeight};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const FONT_WEIGHT: FontWeight = FontWeight::Regular;
+const CHAR_HEIGHT: RasterHeight = RasterHeight::Size16;
+const CHAR_WIDTH: usize = get_raster_width(FONT_WEIGHT, CHAR_HEIGHT);
+
+const LINE_SPACING: usize = 2;
+const LINE_HEIGHT: usize = CHAR_HEIGHT.val() + LINE_SPACING;
+/// Pixels left free around the edges, which some screens cut off.
+const BORDER: usize = 3;
+
+/// What's drawn for characters the font doesn't have.
+const REPLACEMENT: char = '�';
+
+pub struct Writer {
+    buffer: &'static mut [u8],
+    info: FrameBufferInfo,
+    x: usize,
+    y: usize,
+}
+
+impl Writer {
+    fn new(framebuffer: FrameBuffer) -> Writer {
+        let info = framebuffer.info();
+        let buffer = framebuffer.into_buffer();
+        buffer.fill(0);
+        Writer {
+            buffer,
+            info,
+            x: BORDER,
+            y: BORDER,
+        }
+    }
+
+    fn newline(&mut self) {
+        self.x = BORDER;
+        self.y += LINE_HEIGHT;
+        if self.y + LINE_HEIGHT + BORDER > self.info.height {
+            self.scroll();
+        }
+    }
+
+    /// Moves everything up a line, and clears the last one.
+    fn scroll(&mut self) {
+        let row_len = self.info.stride * self.info.bytes_per_pixel;
+        let line_len = LINE_HEIGHT * row_len;
+        let used = (self.info.height * row_len).min(self.buffer.len());
+
+        self.buffer.copy_within(line_len..used, 0);
+        self.buffer[used - line_len..used].fill(0);
+        self.y -= LINE_HEIGHT;
+    }
+
+    fn write_char(&mut self, c: char) {
+        match c {
+            '\n' => self.newline(),
+            '\r' => self.x = BORDER,
+            c => {
+                if self.x + CHAR_WIDTH + BORDER > self.info.width {
+                    self.newline();
+                }
+                let raster = get_raster(c, FONT_WEIGHT, CHAR_HEIGHT)
+                    .or_else(|| get_raster(REPLACEMENT, FONT_WEIGHT, CHAR_HEIGHT))
+                    .expect("the font has no replacement character");
+                for (y, row) in raster.raster().iter().enumerate() {
+                    for (x, intensity) in row.iter().enumerate() {
+                        self.write_pixel(self.x + x, self.y + y, *intensity);
+                    }
+                }
+                self.x += raster.width();
+            }
+        }
+    }
+
+    fn write_pixel(&mut self, x: usize, y: usize, intensity: u8) {
+        let color = match self.info.pixel_format {
+            PixelFormat::Rgb | PixelFormat::Bgr => [0, intensity, 0, 0],
+            PixelFormat::U8 => [intensity, 0, 0, 0],
+            _ => [intensity, intensity, intensity, 0],
+        };
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        let offset = (y * self.info.stride + x) * bytes_per_pixel;
+        let len = bytes_per_pixel.min(color.len());
+        if let Some(pixel) = self.buffer.get_mut(offset..offset + len) {
+            pixel.copy_from_slice(&color[..len]);
+        }
+    }
+}
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            self.write_char(c);
+        }
+        Ok(())
+    }
+}
+
+static WRITER: Mutex<Option<Writer>> = Mutex::new(None);
+
+/// Takes over the bootloader's framebuffer for the console.
+pub fn init(framebuffer: FrameBuffer) {
+    let writer = Writer::new(framebuffer);
+    interrupts::without_interrupts(|| *WRITER.lock() = Some(writer));
+}
+
+/// Releases the writer's lock if it is held.
+///
+/// # Safety
+///
+/// Only for crash paths: whoever holds the lock must never run again.
+pub unsafe fn force_unlock() {
+    WRITER.force_unlock();
+}
+
+/// Whether `init` found a framebuffer.
+pub fn present() -> bool {
+    interrupts::without_interrupts(|| WRITER.lock().is_some())
+}
+
+/// Prints to the framebuffer console, if there is one. Used by `print!` when
+/// there's no VGA text buffer.
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    interrupts::without_interrupts(|| {
+        if let Some(writer) = WRITER.lock().as_mut() {
+            writer.write_fmt(args).unwrap();
+        }
+    })
+}
@@ -0,0 +1,613 @@
+// Filesystems. Each filesystem driver implements `FileSystem` on top of a block
+// device (or of memory), and is addressed with absolute paths like
+// "/docs/readme.txt", relative to the root of that filesystem.
+//
+// The VFS puts them together into a single tree: a filesystem is `mount`ed at
+// a path, and the functions in here (`read_file`, `write`, ...) pass each
+// request on to the filesystem with the longest mount path that is a prefix of
+// the requested path. The directories above mount points exist even if nothing
+// is mounted there, so that e.g. "/" can be listed to find "/tmp".
+//
+// The shell gets `ls`, `cat`, `mkdir` and `echo`, whose output can be written
+// to a file with `> path` or appended with `>> path`. The shell has no working
+// directory, so their paths all start at the root. `more` shows a file a
+// screen at a time, and reads the keys in raw mode: space for the next page,
+// enter for the next line, q to stop.
+
+use crate::allocator::accounting::{self, Subsystem};
+use crate::block::BlockError;
+use crate::error::KernelError;
+use crate::shell;
+use crate::sync::RwSpinLock;
+use crate::tty::{self, RawMode};
+use crate::vga_buffer::{BUFFER_HEIGHT, BUFFER_WIDTH};
+use alloc::{borrow::ToOwned, boxed::Box, format, string::String, sync::Arc, vec::Vec};
+use core::fmt::{self, Write};
+
+pub mod ext2;
+pub mod fat;
+pub mod ramfs;
+pub mod tar;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    /// Only empty directories can be removed.
+    NotEmpty,
+    /// The path can't be used for the operation, e.g. creating "/".
+    InvalidPath,
+    /// The filesystem doesn't make sense, e.g. a cluster chain that loops.
+    Corrupt,
+    /// The filesystem is valid, but uses a feature that we don't support.
+    Unsupported,
+    ReadOnly,
+    /// There's no heap left for what the operation needs.
+    NoMemory,
+    /// There's no room left on the filesystem for the data.
+    NoSpace,
+    /// A file would grow past the largest size the filesystem allows.
+    FileTooLarge,
+    Block(BlockError),
+}
+
+impl From<BlockError> for FsError {
+    fn from(error: BlockError) -> Self {
+        FsError::Block(error)
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsError::NotFound => f.write_str("no such file or directory"),
+            FsError::NotADirectory => f.write_str("not a directory"),
+            FsError::IsADirectory => f.write_str("is a directory"),
+            FsError::AlreadyExists => f.write_str("file exists"),
+            FsError::NotEmpty => f.write_str("directory not empty"),
+            FsError::InvalidPath => f.write_str("invalid path"),
+            FsError::Corrupt => f.write_str("filesystem is corrupt"),
+            FsError::Unsupported => f.write_str("filesystem feature not supported"),
+            FsError::ReadOnly => f.write_str("read-only filesystem"),
+            FsError::NoMemory => f.write_str("out of memory"),
+            FsError::NoSpace => f.write_str("no space left on device"),
+            FsError::FileTooLarge => f.write_str("file too large"),
+            FsError::Block(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub file_type: FileType,
+    /// In bytes, 0 for directories.
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub metadata: Metadata,
+}
+
+/// A filesystem. Read-only filesystems only implement the first three methods,
+/// and the others fail with `FsError::ReadOnly`.
+pub trait FileSystem: Send + Sync {
+    fn metadata(&self, path: &str) -> Result<Metadata, FsError>;
+
+    /// Lists a directory, without "." and "..".
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError>;
+
+    /// Reads from a file at `offset` into `buf`, and returns how much was read,
+    /// which is less than `buf.len()` only at the end of the file.
+    fn read(&self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError>;
+
+    /// Reads a whole file.
+    fn read_file(&self, path: &str) -> Result<Vec<u8>, FsError> {
+        let metadata = self.metadata(path)?;
+        if metadata.file_type == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+
+        // The size comes from the filesystem, which may be corrupt
+        let size = metadata.size as usize;
+        let mut data = Vec::new();
+        data.try_reserve_exact(size)
+            .map_err(|_| FsError::NoMemory)?;
+        data.resize(size, 0);
+        let len = self.read(path, 0, &mut data)?;
+        data.truncate(len);
+        Ok(data)
+    }
+
+    /// Writes `data` to a file at `offset`, growing the file if needed (with
+    /// zeroes, if `offset` is past its end).
+    fn write(&self, _path: &str, _offset: u64, _data: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Cuts off or zero-extends a file to `size` bytes.
+    fn truncate(&self, _path: &str, _size: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Creates an empty file.
+    fn create_file(&self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn create_dir(&self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Removes a file or an empty directory.
+    fn remove(&self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+}
+
+/// Splits a path into its components, ignoring empty ones and ".", so that
+/// "/a//b/./c" and "a/b/c/" are both "a", "b", "c".
+pub fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+}
+
+/// Splits a path into its parent and the last component, e.g. "/a/b/c" into
+/// "/a/b" and "c". Returns `None` for the root.
+pub fn split_last(path: &str) -> Option<(&str, &str)> {
+    let path = path.trim_end_matches('/');
+    let name = path.rsplit('/').next().filter(|name| !name.is_empty())?;
+    Some((&path[..path.len() - name.len()], name))
+}
+
+/// Turns a path into its canonical form, "/" followed by its components
+/// separated by single slashes.
+fn normalize(path: &str) -> String {
+    let mut normalized = String::new();
+    for component in components(path) {
+        normalized.push('/');
+        normalized.push_str(component);
+    }
+
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
+// Normalized mount paths and their filesystems. Every path lookup reads
+// it, only mount and unmount write.
+static MOUNTS: RwSpinLock<Vec<(String, Arc<dyn FileSystem>)>> =
+    RwSpinLock::new_irq_safe(Vec::new());
+
+/// Mounts ramfs at /tmp.
+pub fn init() -> Result<(), KernelError> {
+    let _charge = accounting::charge_to(Subsystem::Vfs);
+    mount("/tmp", Arc::new(ramfs::RamFs::new()))?;
+    Ok(())
+}
+
+/// Makes the filesystem available at `path`.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let _charge = accounting::charge_to(Subsystem::Vfs);
+    let path = normalize(path);
+
+    let mut mounts = MOUNTS.write();
+    if mounts.iter().any(|(mount_path, _)| *mount_path == path) {
+        return Err(FsError::AlreadyExists);
+    }
+
+    mounts.push((path, fs));
+    Ok(())
+}
+
+/// Removes the filesystem mounted at `path`.
+pub fn unmount(path: &str) -> Result<(), FsError> {
+    let path = normalize(path);
+
+    let fs = {
+        let mut mounts = MOUNTS.write();
+        let index = mounts
+            .iter()
+            .position(|(mount_path, _)| *mount_path == path)
+            .ok_or(FsError::NotFound)?;
+        mounts.remove(index)
+    };
+
+    // Might free the filesystem, which is better done without the lock.
+    drop(fs);
+    Ok(())
+}
+
+/// Returns the mount paths, sorted.
+pub fn mounts() -> Vec<String> {
+    let mut paths: Vec<String> = MOUNTS.read().iter().map(|(path, _)| path.clone()).collect();
+    paths.sort();
+    paths
+}
+
+/// Returns the filesystem that `path` is on, and the path within it.
+fn resolve(path: &str) -> Result<(Arc<dyn FileSystem>, String), FsError> {
+    let path = normalize(path);
+
+    MOUNTS
+        .read()
+        .iter()
+        .filter_map(|(mount_path, fs)| Some((mount_path, fs, relative_to(&path, mount_path)?)))
+        .max_by_key(|(mount_path, _, _)| mount_path.len())
+        .map(|(_, fs, relative)| (fs.clone(), relative))
+        .ok_or(FsError::NotFound)
+}
+
+/// Returns `path` relative to `base`, starting with "/", if it is inside it.
+/// Both have to be normalized.
+fn relative_to(path: &str, base: &str) -> Option<String> {
+    if base == "/" {
+        return Some(path.to_owned());
+    }
+
+    match path.strip_prefix(base)? {
+        "" => Some(String::from("/")),
+        rest if rest.starts_with('/') => Some(rest.to_owned()),
+        _ => None,
+    }
+}
+
+/// Returns the names of the directories directly below `path` that lead to
+/// mount points, e.g. "tmp" for "/" if something is mounted at "/tmp".
+fn mount_children(path: &str) -> Vec<String> {
+    let path = normalize(path);
+    let mut children: Vec<String> = mounts()
+        .iter()
+        .filter_map(|mount_path| {
+            let relative = relative_to(mount_path, &path)?;
+            let child = components(&relative).next().map(String::from);
+            child
+        })
+        .collect();
+    children.dedup();
+    children
+}
+
+pub fn metadata(path: &str) -> Result<Metadata, FsError> {
+    match resolve(path) {
+        Ok((fs, relative)) => fs.metadata(&relative),
+        Err(FsError::NotFound) if !mount_children(path).is_empty() => Ok(Metadata {
+            file_type: FileType::Directory,
+            size: 0,
+        }),
+        Err(error) => Err(error),
+    }
+}
+
+/// Lists a directory, including the mount points right below it.
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    let mut entries = match resolve(path) {
+        Ok((fs, relative)) => fs.read_dir(&relative)?,
+        Err(FsError::NotFound) if !mount_children(path).is_empty() => Vec::new(),
+        Err(error) => return Err(error),
+    };
+
+    for name in mount_children(path) {
+        if !entries.iter().any(|entry| entry.name == name) {
+            entries.push(DirEntry {
+                name,
+                metadata: Metadata {
+                    file_type: FileType::Directory,
+                    size: 0,
+                },
+            });
+        }
+    }
+    Ok(entries)
+}
+
+pub fn read(path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+    let (fs, relative) = resolve(path)?;
+    fs.read(&relative, offset, buf)
+}
+
+pub fn read_file(path: &str) -> Result<Vec<u8>, FsError> {
+    let _charge = accounting::charge_to(Subsystem::Vfs);
+    let (fs, relative) = resolve(path)?;
+    fs.read_file(&relative)
+}
+
+pub fn write(path: &str, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+    let _charge = accounting::charge_to(Subsystem::Vfs);
+    let (fs, relative) = resolve(path)?;
+    fs.write(&relative, offset, data)
+}
+
+pub fn truncate(path: &str, size: u64) -> Result<(), FsError> {
+    let _charge = accounting::charge_to(Subsystem::Vfs);
+    let (fs, relative) = resolve(path)?;
+    fs.truncate(&relative, size)
+}
+
+pub fn create_file(path: &str) -> Result<(), FsError> {
+    let _charge = accounting::charge_to(Subsystem::Vfs);
+    let (fs, relative) = resolve(path)?;
+    fs.create_file(&relative)
+}
+
+pub fn create_dir(path: &str) -> Result<(), FsError> {
+    let _charge = accounting::charge_to(Subsystem::Vfs);
+    let (fs, relative) = resolve(path)?;
+    fs.create_dir(&relative)
+}
+
+/// Removes a file or an empty directory. Mount points can't be removed.
+pub fn remove(path: &str) -> Result<(), FsError> {
+    let (fs, relative) = resolve(path)?;
+    if relative == "/" {
+        return Err(FsError::InvalidPath);
+    }
+    fs.remove(&relative)
+}
+
+/// Where `echo` writes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Redirect<'a> {
+    Truncate(&'a str),
+    Append(&'a str),
+}
+
+/// Splits the arguments of `echo` at a `>` or `>>`, which has to be followed
+/// by exactly one path. Returns `None` if it isn't.
+fn split_redirect<'a>(args: &[&'a str]) -> Option<(Vec<&'a str>, Option<Redirect<'a>>)> {
+    let index = match args.iter().position(|&arg| arg == ">" || arg == ">>") {
+        Some(index) => index,
+        None => return Some((args.to_vec(), None)),
+    };
+    let path = match args[index + 1..] {
+        [path] => path,
+        _ => return None,
+    };
+    let redirect = match args[index] {
+        ">" => Redirect::Truncate(path),
+        _ => Redirect::Append(path),
+    };
+    Some((args[..index].to_vec(), Some(redirect)))
+}
+
+/// Writes `data` to the file at `path`, creating it if needed, and either
+/// replacing what was in it or adding to the end.
+fn write_file(redirect: Redirect, data: &[u8]) -> Result<(), FsError> {
+    let path = match redirect {
+        Redirect::Truncate(path) | Redirect::Append(path) => path,
+    };
+    let size = match metadata(path) {
+        Ok(metadata) if metadata.file_type == FileType::Directory => {
+            return Err(FsError::IsADirectory)
+        }
+        Ok(metadata) => metadata.size,
+        Err(FsError::NotFound) => {
+            create_file(path)?;
+            0
+        }
+        Err(error) => return Err(error),
+    };
+
+    let offset = match redirect {
+        Redirect::Truncate(_) => {
+            truncate(path, 0)?;
+            0
+        }
+        Redirect::Append(_) => size,
+    };
+    write(path, offset, data)?;
+    Ok(())
+}
+
+pub fn register_commands() {
+    shell::register("ls", "list a directory, `ls [path]`", ls);
+    shell::register("cat", "print files, `cat <path>...`", cat);
+    shell::register("mkdir", "create directories, `mkdir <path>...`", mkdir);
+    shell::register(
+        "echo",
+        "print the arguments, or write them to a file with `> path` or `>> path`",
+        echo,
+    );
+    shell::register_async("more", "page through a file, `more <path>`", |args, out| {
+        Box::pin(more(args, out))
+    });
+}
+
+fn ls(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    let path = args.get(1).copied().unwrap_or("/");
+    match metadata(path) {
+        Ok(metadata) if metadata.file_type == FileType::File => {
+            return writeln!(out, "{:<24} {}", path, metadata.size)
+        }
+        Ok(_) => {}
+        Err(error) => return writeln!(out, "ls: {}: {}", path, error),
+    }
+
+    let mut entries = match read_dir(path) {
+        Ok(entries) => entries,
+        Err(error) => return writeln!(out, "ls: {}: {}", path, error),
+    };
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    for entry in entries {
+        match entry.metadata.file_type {
+            FileType::Directory => writeln!(out, "{}/", entry.name)?,
+            FileType::File => writeln!(out, "{:<24} {}", entry.name, entry.metadata.size)?,
+        }
+    }
+    Ok(())
+}
+
+fn cat(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    if args.len() < 2 {
+        return writeln!(out, "usage: cat <path>...");
+    }
+    for path in &args[1..] {
+        match read_file(path) {
+            Ok(data) => {
+                out.write_str(&String::from_utf8_lossy(&data))?;
+                // Keep the prompt on a line of its own
+                if !data.is_empty() && !data.ends_with(b"\n") {
+                    writeln!(out)?;
+                }
+            }
+            Err(error) => writeln!(out, "cat: {}: {}", path, error)?,
+        }
+    }
+    Ok(())
+}
+
+fn mkdir(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    if args.len() < 2 {
+        return writeln!(out, "usage: mkdir <path>...");
+    }
+    for path in &args[1..] {
+        if let Err(error) = create_dir(path) {
+            writeln!(out, "mkdir: {}: {}", path, error)?;
+        }
+    }
+    Ok(())
+}
+
+fn echo(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    let (words, redirect) = match split_redirect(&args[1..]) {
+        Some(split) => split,
+        None => return writeln!(out, "usage: echo [text...] [> path | >> path]"),
+    };
+    let mut line = words.join(" ");
+    line.push('\n');
+
+    match redirect {
+        None => out.write_str(&line),
+        Some(redirect) => match write_file(redirect, line.as_bytes()) {
+            Ok(()) => Ok(()),
+            Err(error) => writeln!(out, "echo: {}", error),
+        },
+    }
+}
+
+/// How many lines `more` shows at once: a screen, less the prompt's line.
+const PAGE_LINES: usize = BUFFER_HEIGHT - 1;
+
+/// Splits text into the lines it takes up on a screen `width` characters
+/// wide. Each byte takes up a character on the screen, like in vga_buffer.rs.
+fn screen_lines(text: &str, width: usize) -> impl Iterator<Item = &str> {
+    text.strip_suffix('\n')
+        .unwrap_or(text)
+        .split('\n')
+        .flat_map(move |line| {
+            // An empty line still takes up a row
+            let mut rest = Some(line);
+            core::iter::from_fn(move || {
+                let line = rest?;
+                let mut end = line.len().min(width);
+                while !line.is_char_boundary(end) {
+                    end -= 1;
+                }
+                let (row, remainder) = line.split_at(end);
+                rest = Some(remainder).filter(|remainder| !remainder.is_empty());
+                Some(row)
+            })
+        })
+}
+
+async fn more(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    let path = match args {
+        [_, path] => *path,
+        _ => return writeln!(out, "usage: more <path>"),
+    };
+    let data = match read_file(path) {
+        Ok(data) => data,
+        Err(error) => return writeln!(out, "more: {}: {}", path, error),
+    };
+    let text = String::from_utf8_lossy(&data);
+    let lines: Vec<&str> = screen_lines(&text, BUFFER_WIDTH).collect();
+
+    let _raw = RawMode::enter();
+    let mut shown = 0;
+    let mut end = PAGE_LINES;
+    loop {
+        let end_of_page = end.min(lines.len());
+        for line in &lines[shown..end_of_page] {
+            writeln!(out, "{}", line)?;
+        }
+        shown = end_of_page;
+        if shown == lines.len() {
+            return Ok(());
+        }
+
+        let prompt = format!("--More--({}%)", shown * 100 / lines.len());
+        out.write_str(&prompt)?;
+        let more_lines = loop {
+            match tty::read_byte().await {
+                b' ' => break PAGE_LINES,
+                b'\r' | b'\n' => break 1,
+                b'q' | b'Q' => break 0,
+                _ => {}
+            }
+        };
+        for _ in 0..prompt.len() {
+            out.write_str("\x08 \x08")?;
+        }
+
+        if more_lines == 0 {
+            return Ok(());
+        }
+        end = shown + more_lines;
+    }
+}
+
+#[test_case]
+fn test_components() {
+    let mut parts = components("/a//b/./c/");
+    assert_eq!(parts.next(), Some("a"));
+    assert_eq!(parts.next(), Some("b"));
+    assert_eq!(parts.next(), Some("c"));
+    assert_eq!(parts.next(), None);
+
+    assert_eq!(components("/").next(), None);
+}
+
+#[test_case]
+fn test_split_last() {
+    assert_eq!(split_last("/a/b/c"), Some(("/a/b/", "c")));
+    assert_eq!(split_last("/a/"), Some(("/", "a")));
+    assert_eq!(split_last("/"), None);
+}
+
+#[test_case]
+fn test_split_redirect() {
+    use alloc::vec;
+
+    assert_eq!(split_redirect(&["a", "b"]), Some((vec!["a", "b"], None)));
+    assert_eq!(
+        split_redirect(&["a", ">", "/tmp/x"]),
+        Some((vec!["a"], Some(Redirect::Truncate("/tmp/x"))))
+    );
+    assert_eq!(
+        split_redirect(&[">>", "/tmp/x"]),
+        Some((vec![], Some(Redirect::Append("/tmp/x"))))
+    );
+    assert_eq!(split_redirect(&["a", ">"]), None);
+    assert_eq!(split_redirect(&["a", ">", "/x", "/y"]), None);
+}
+
+#[test_case]
+fn test_screen_lines() {
+    let lines: Vec<&str> = screen_lines("abcdef\n\nxy\n", 4).collect();
+    assert_eq!(lines, ["abcd", "ef", "", "xy"]);
+
+    // Not split in the middle of a character
+    let lines: Vec<&str> = screen_lines("aé", 2).collect();
+    assert_eq!(lines, ["a", "é"]);
+    assert_eq!(screen_lines("", 4).count(), 1);
+}
@@ -0,0 +1,409 @@
+// A read-only ext2 driver. The volume is divided into blocks (usually of 1 or 4
+// KiB), and the blocks into groups. The superblock, 1024 bytes into the volume, says
+// how big all of these are. It is followed (in the next block) by the table of
+// group descriptors, which tell where each group keeps its bitmaps and its
+// part of the inode table.
+//
+// Every file and directory is an inode, numbered from 1, with the root
+// directory being inode 2. The inode holds the type and size, and the numbers
+// of the file's blocks: 12 direct ones, then one each for a block of block
+// numbers (indirect), a block of those (doubly indirect), and one more level
+// (triply indirect). A block number of 0 is a hole, which reads as zeroes.
+//
+// Directories are files of variable-length entries, each with an inode
+// number, the length of the entry (so that the next one can be found), and
+// the name. Unlike FAT, names are case-sensitive.
+//
+// Features beyond plain ext2 that change the layout, like ext4's extents, are
+// rejected as unsupported.
+
+use super::{components, DirEntry, FileSystem, FileType, FsError, Metadata};
+use crate::block::BlockDevice;
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+
+const SUPERBLOCK_OFFSET: usize = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xEF53;
+
+const ROOT_INODE: u32 = 2;
+/// The inode size of revision 0 volumes, which don't record it.
+const OLD_INODE_SIZE: usize = 128;
+const GROUP_DESCRIPTOR_SIZE: usize = 32;
+/// Anything bigger is taken for a corrupt size.
+const MAX_DIRECTORY_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Directory entries carry the file type as well. We take it from the inodes,
+/// which we need for the sizes anyway.
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// Only changes where the bitmaps and inode tables are, which the group
+/// descriptors tell us anyway.
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG;
+/// Files can be 4 GiB or larger, with the upper half of the size in
+/// `i_dir_acl`.
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+
+const MODE_TYPE_MASK: u16 = 0xF000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_FILE: u16 = 0x8000;
+
+const DIRECT_BLOCKS: usize = 12;
+const INDIRECT: usize = 12;
+const DOUBLY_INDIRECT: usize = 13;
+const TRIPLY_INDIRECT: usize = 14;
+
+pub struct Ext2Fs {
+    device: Arc<dyn BlockDevice>,
+    block_size: usize,
+    // Device blocks per filesystem block
+    blocks_per_block: u64,
+    num_blocks: u32,
+    num_inodes: u32,
+    inodes_per_group: u32,
+    inode_size: usize,
+    // The first block of each group's part of the inode table
+    inode_tables: Vec<u32>,
+    large_files: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Inode {
+    mode: u16,
+    size: u64,
+    block: [u32; 15],
+}
+
+impl Ext2Fs {
+    /// Reads the superblock and the group descriptors.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Ext2Fs, FsError> {
+        let device_block_size = device.block_size() as u64;
+        let len = x86_64::align_up(
+            (SUPERBLOCK_OFFSET + SUPERBLOCK_SIZE) as u64,
+            device_block_size,
+        );
+        let mut start = vec![0; len as usize];
+        device.read_blocks(0, &mut start)?;
+        let superblock = &start[SUPERBLOCK_OFFSET..SUPERBLOCK_OFFSET + SUPERBLOCK_SIZE];
+
+        if u16_at(superblock, 56) != MAGIC {
+            return Err(FsError::Corrupt);
+        }
+
+        let num_inodes = u32_at(superblock, 0);
+        let num_blocks = u32_at(superblock, 4);
+        let first_data_block = u32_at(superblock, 20);
+        let log_block_size = u32_at(superblock, 24);
+        let blocks_per_group = u32_at(superblock, 32);
+        let inodes_per_group = u32_at(superblock, 40);
+        let revision = u32_at(superblock, 76);
+        let (inode_size, incompat, ro_compat) = match revision {
+            0 => (OLD_INODE_SIZE, 0, 0),
+            _ => (
+                usize::from(u16_at(superblock, 88)),
+                u32_at(superblock, 96),
+                u32_at(superblock, 100),
+            ),
+        };
+
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err(FsError::Unsupported);
+        }
+        // Block sizes go up to 64 KiB.
+        if log_block_size > 6 {
+            return Err(FsError::Corrupt);
+        }
+        let block_size = 1024 << log_block_size;
+        if block_size < device.block_size()
+            || block_size / device.block_size() * device.block_size() != block_size
+        {
+            return Err(FsError::Unsupported);
+        }
+        let blocks_per_block = (block_size / device.block_size()) as u64;
+        let num_blocks = num_blocks.min((device.num_blocks() / blocks_per_block) as u32);
+        // A group's blocks are tracked by a bitmap of one block. Tiny groups
+        // are refused as they are by e2fsck, or the descriptors of a small
+        // disk could fill the heap.
+        if blocks_per_group < 8
+            || blocks_per_group as usize > 8 * block_size
+            || inodes_per_group == 0
+            || inode_size < OLD_INODE_SIZE
+            || inode_size > block_size
+            // So that inodes never cross a block
+            || !inode_size.is_power_of_two()
+            || first_data_block >= num_blocks
+        {
+            return Err(FsError::Corrupt);
+        }
+        let num_groups = x86_64::align_up(
+            u64::from(num_blocks - first_data_block),
+            u64::from(blocks_per_group),
+        ) / u64::from(blocks_per_group);
+
+        let mut fs = Ext2Fs {
+            device,
+            block_size,
+            blocks_per_block,
+            num_blocks,
+            num_inodes,
+            inodes_per_group,
+            inode_size,
+            inode_tables: Vec::new(),
+            large_files: ro_compat & RO_COMPAT_LARGE_FILE != 0,
+        };
+
+        // The group descriptors start in the block after the superblock.
+        let table_size = num_groups as usize * GROUP_DESCRIPTOR_SIZE;
+        let table_blocks =
+            x86_64::align_up(table_size as u64, block_size as u64) / block_size as u64;
+        let mut table = Vec::new();
+        table
+            .try_reserve_exact(table_blocks as usize * block_size)
+            .map_err(|_| FsError::NoMemory)?;
+        table.resize(table_blocks as usize * block_size, 0);
+        for (i, block) in table.chunks_mut(block_size).enumerate() {
+            fs.read_block(first_data_block + 1 + i as u32, block)?;
+        }
+
+        fs.inode_tables = table[..table_size]
+            .chunks_exact(GROUP_DESCRIPTOR_SIZE)
+            .map(|descriptor| u32_at(descriptor, 8))
+            .collect();
+
+        if fs.inode(ROOT_INODE)?.file_type() != Some(FileType::Directory) {
+            return Err(FsError::Corrupt);
+        }
+        Ok(fs)
+    }
+
+    fn read_block(&self, block: u32, buf: &mut [u8]) -> Result<(), FsError> {
+        if block >= self.num_blocks {
+            return Err(FsError::Corrupt);
+        }
+        Ok(self
+            .device
+            .read_blocks(u64::from(block) * self.blocks_per_block, buf)?)
+    }
+
+    fn inode(&self, number: u32) -> Result<Inode, FsError> {
+        if number == 0 || number > self.num_inodes {
+            return Err(FsError::Corrupt);
+        }
+        let group = ((number - 1) / self.inodes_per_group) as usize;
+        let offset = ((number - 1) % self.inodes_per_group) as usize * self.inode_size;
+        let table = *self.inode_tables.get(group).ok_or(FsError::Corrupt)?;
+
+        let mut data = vec![0; self.block_size];
+        let block = table.checked_add((offset / self.block_size) as u32);
+        self.read_block(block.ok_or(FsError::Corrupt)?, &mut data)?;
+        let raw = &data[offset % self.block_size..][..OLD_INODE_SIZE];
+
+        let mode = u16_at(raw, 0);
+        let mut size = u64::from(u32_at(raw, 4));
+        if self.large_files && mode & MODE_TYPE_MASK == MODE_FILE {
+            size |= u64::from(u32_at(raw, 108)) << 32;
+        }
+        let mut block = [0; 15];
+        for (i, number) in block.iter_mut().enumerate() {
+            *number = u32_at(raw, 40 + i * 4);
+        }
+
+        Ok(Inode { mode, size, block })
+    }
+
+    /// Returns the number of the `index`th block of the inode's data, or 0 for
+    /// a hole.
+    fn data_block(&self, inode: &Inode, index: u64) -> Result<u32, FsError> {
+        let per_block = (self.block_size / 4) as u64;
+
+        if index < DIRECT_BLOCKS as u64 {
+            return Ok(inode.block[index as usize]);
+        }
+        // Find the tree that the block is in, and its index within that tree.
+        let mut index = index - DIRECT_BLOCKS as u64;
+        let trees = [INDIRECT, DOUBLY_INDIRECT, TRIPLY_INDIRECT];
+        for (levels, &tree) in (1..).zip(&trees) {
+            let blocks = per_block.pow(levels);
+            if index >= blocks {
+                index -= blocks;
+                continue;
+            }
+
+            let mut data = vec![0; self.block_size];
+            let mut block = inode.block[tree];
+            for level in (0..levels).rev() {
+                if block == 0 {
+                    return Ok(0);
+                }
+                self.read_block(block, &mut data)?;
+                let slot = (index / per_block.pow(level) % per_block) as usize;
+                block = u32_at(&data, slot * 4);
+            }
+            return Ok(block);
+        }
+
+        // Past the end of the triply indirect blocks
+        Err(FsError::Corrupt)
+    }
+
+    /// Reads from the inode's data at `offset`, and returns how much was read.
+    fn read_inode(&self, inode: &Inode, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        if offset >= inode.size {
+            return Ok(0);
+        }
+        let len = buf.len().min((inode.size - offset) as usize);
+
+        let block_size = self.block_size as u64;
+        let mut data = vec![0; self.block_size];
+        let mut done = 0;
+
+        while done < len {
+            let position = offset + done as u64;
+            match self.data_block(inode, position / block_size)? {
+                0 => data.fill(0),
+                block => self.read_block(block, &mut data)?,
+            }
+
+            let start = (position % block_size) as usize;
+            let chunk = (len - done).min(data.len() - start);
+            buf[done..done + chunk].copy_from_slice(&data[start..start + chunk]);
+            done += chunk;
+        }
+
+        Ok(len)
+    }
+
+    /// Returns the names and inode numbers in a directory, including "." and
+    /// "..".
+    fn dir_entries(&self, directory: &Inode) -> Result<Vec<(String, u32)>, FsError> {
+        if directory.size > MAX_DIRECTORY_SIZE {
+            return Err(FsError::Corrupt);
+        }
+
+        let mut entries = Vec::new();
+        let mut data = vec![0; self.block_size];
+        let mut position = 0;
+        while position < directory.size {
+            let len = self.read_inode(directory, position, &mut data)?;
+            position += len as u64;
+            let block = &data[..len];
+            let mut offset = 0;
+            // An entry is at least 8 bytes, and never crosses a block.
+            while offset + 8 <= block.len() {
+                let raw = &block[offset..];
+                let inode = u32_at(raw, 0);
+                let record_len = usize::from(u16_at(raw, 4));
+                let name_len = usize::from(raw[6]);
+                if record_len < 8 + name_len || record_len > raw.len() {
+                    return Err(FsError::Corrupt);
+                }
+
+                // Deleted entries keep their space, with inode 0.
+                if inode != 0 {
+                    let name = String::from_utf8_lossy(&raw[8..8 + name_len]).into_owned();
+                    entries.push((name, inode));
+                }
+                offset += record_len;
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn lookup(&self, path: &str) -> Result<Inode, FsError> {
+        let mut inode = self.inode(ROOT_INODE)?;
+
+        for component in components(path) {
+            if inode.file_type() != Some(FileType::Directory) {
+                return Err(FsError::NotADirectory);
+            }
+
+            let number = self
+                .dir_entries(&inode)?
+                .into_iter()
+                .find(|(name, _)| name == component)
+                .map(|(_, number)| number)
+                .ok_or(FsError::NotFound)?;
+            inode = self.inode(number)?;
+        }
+
+        Ok(inode)
+    }
+
+    /// Looks up a file or directory. Symbolic links, devices and the like
+    /// aren't supported.
+    fn lookup_supported(&self, path: &str) -> Result<(Inode, FileType), FsError> {
+        let inode = self.lookup(path)?;
+        let file_type = inode.file_type().ok_or(FsError::Unsupported)?;
+        Ok((inode, file_type))
+    }
+}
+
+impl Inode {
+    fn file_type(&self) -> Option<FileType> {
+        match self.mode & MODE_TYPE_MASK {
+            MODE_FILE => Some(FileType::File),
+            MODE_DIRECTORY => Some(FileType::Directory),
+            _ => None,
+        }
+    }
+
+    fn metadata(&self, file_type: FileType) -> Metadata {
+        Metadata {
+            file_type,
+            size: match file_type {
+                FileType::File => self.size,
+                FileType::Directory => 0,
+            },
+        }
+    }
+}
+
+impl FileSystem for Ext2Fs {
+    fn metadata(&self, path: &str) -> Result<Metadata, FsError> {
+        let (inode, file_type) = self.lookup_supported(path)?;
+        Ok(inode.metadata(file_type))
+    }
+
+    /// Lists a directory. Entries that are neither files nor directories are
+    /// left out.
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        let (directory, file_type) = self.lookup_supported(path)?;
+        if file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+
+        let mut entries = Vec::new();
+        for (name, number) in self.dir_entries(&directory)? {
+            if name == "." || name == ".." {
+                continue;
+            }
+            let inode = self.inode(number)?;
+            if let Some(file_type) = inode.file_type() {
+                entries.push(DirEntry {
+                    name,
+                    metadata: inode.metadata(file_type),
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn read(&self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let (file, file_type) = self.lookup_supported(path)?;
+        if file_type != FileType::File {
+            return Err(FsError::IsADirectory);
+        }
+
+        self.read_inode(&file, offset, buf)
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(value)
+}
@@ -0,0 +1,502 @@
+// A read-only FAT32 driver. The volume starts with the boot sector (the BIOS
+// parameter block, BPB), followed by some reserved sectors, one or more copies
+// of the file allocation table (FAT), and the data area, which is divided into
+// clusters. The FAT has a 32-bit entry per cluster (of which the top 4 bits are
+// reserved), holding the number of the next cluster of the same file, or an
+// end-of-chain marker. Cluster numbers start at 2, at the start of the data area.
+//
+// Directories are files of 32-byte entries. Each holds an 8.3 "short" name, the
+// attributes, the first cluster and the size. Names that don't fit into 8.3
+// are stored in the long file name (LFN) entries in front of it, 13 UTF-16 code
+// units per entry, last part first.
+
+use super::{components, DirEntry, FileSystem, FileType, FsError, Metadata};
+use crate::block::BlockDevice;
+#[cfg(test)]
+use crate::HostTest;
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const DIR_ENTRY_SIZE: usize = 32;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+/// Read-only, hidden, system and volume ID together mark an LFN entry.
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xE5;
+const LFN_LAST: u8 = 0x40;
+const LFN_CHARS: usize = 13;
+/// Long names are at most 255 characters, i.e. 20 entries.
+const LFN_MAX_ENTRIES: u8 = 20;
+
+// In the reserved byte 12 of a short entry, Windows marks names that are all
+// lowercase, so that they don't need LFN entries.
+const LOWERCASE_BASE: u8 = 0x08;
+const LOWERCASE_EXTENSION: u8 = 0x10;
+
+// The largest sizes the specification allows, or that Windows tolerates for
+// clusters. Sectors and clusters are read whole into the heap.
+const MAX_SECTOR_SIZE: usize = 4096;
+const MAX_CLUSTER_SIZE: usize = 64 * 1024;
+
+const FAT_ENTRY_MASK: u32 = 0x0FFF_FFFF;
+const FAT_BAD_CLUSTER: u32 = 0x0FFF_FFF7;
+const FIRST_CLUSTER: u32 = 2;
+
+pub struct FatFs {
+    device: Arc<dyn BlockDevice>,
+    // Device blocks per sector
+    blocks_per_sector: u64,
+    sector_size: usize,
+    sectors_per_cluster: u64,
+    fat_start: u64,
+    data_start: u64,
+    root_cluster: u32,
+    num_clusters: u32,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    name: String,
+    file_type: FileType,
+    cluster: u32,
+    size: u32,
+}
+
+impl FatFs {
+    /// Reads the boot sector of a FAT32 volume.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<FatFs, FsError> {
+        if device.block_size() < 512 {
+            return Err(FsError::Unsupported);
+        }
+        let mut boot = vec![0; device.block_size()];
+        device.read_blocks(0, &mut boot)?;
+        if boot[510..512] != BOOT_SIGNATURE {
+            return Err(FsError::Corrupt);
+        }
+
+        let sector_size = u16_at(&boot, 11) as usize;
+        let sectors_per_cluster = u64::from(boot[13]);
+        let reserved_sectors = u64::from(u16_at(&boot, 14));
+        let num_fats = u64::from(boot[16]);
+        let total_sectors = match u16_at(&boot, 19) {
+            0 => u64::from(u32_at(&boot, 32)),
+            sectors => u64::from(sectors),
+        };
+        let fat_size = u64::from(u32_at(&boot, 36));
+        let root_cluster = u32_at(&boot, 44);
+
+        // FAT12 and FAT16 have a 16-bit FAT size and a fixed root directory.
+        if u16_at(&boot, 22) != 0 {
+            return Err(FsError::Unsupported);
+        }
+        if sector_size < device.block_size()
+            || sector_size / device.block_size() * device.block_size() != sector_size
+            || sector_size > MAX_SECTOR_SIZE
+            || sectors_per_cluster == 0
+            || sector_size * sectors_per_cluster as usize > MAX_CLUSTER_SIZE
+            || num_fats == 0
+            || fat_size == 0
+        {
+            return Err(FsError::Corrupt);
+        }
+
+        let data_start = reserved_sectors + num_fats * fat_size;
+        let blocks_per_sector = (sector_size / device.block_size()) as u64;
+        let total_sectors = total_sectors.min(device.num_blocks() / blocks_per_sector);
+        // The FAT also has to have room for all clusters, including the two
+        // reserved entries at the start.
+        let num_clusters = (total_sectors.saturating_sub(data_start) / sectors_per_cluster)
+            .min((fat_size * sector_size as u64 / 4).saturating_sub(2))
+            .min(u64::from(FAT_BAD_CLUSTER - FIRST_CLUSTER)) as u32;
+
+        let fs = FatFs {
+            device,
+            blocks_per_sector,
+            sector_size,
+            sectors_per_cluster,
+            fat_start: reserved_sectors,
+            data_start,
+            root_cluster,
+            num_clusters,
+        };
+        if !fs.is_valid_cluster(root_cluster) {
+            return Err(FsError::Corrupt);
+        }
+
+        Ok(fs)
+    }
+
+    fn cluster_size(&self) -> usize {
+        self.sector_size * self.sectors_per_cluster as usize
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        (FIRST_CLUSTER..FIRST_CLUSTER + self.num_clusters).contains(&cluster)
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        Ok(self
+            .device
+            .read_blocks(sector * self.blocks_per_sector, buf)?)
+    }
+
+    fn read_cluster(&self, cluster: u32, buf: &mut [u8]) -> Result<(), FsError> {
+        let sector =
+            self.data_start + u64::from(cluster - FIRST_CLUSTER) * self.sectors_per_cluster;
+        self.read_sectors(sector, buf)
+    }
+
+    /// Returns the cluster after `cluster` in its chain, or `None` at the end.
+    /// `sector` is a buffer for a sector of the FAT.
+    fn next_cluster(&self, cluster: u32, sector: &mut [u8]) -> Result<Option<u32>, FsError> {
+        let offset = u64::from(cluster) * 4;
+        let sector_size = self.sector_size as u64;
+        self.read_sectors(self.fat_start + offset / sector_size, sector)?;
+
+        match u32_at(sector, (offset % sector_size) as usize) & FAT_ENTRY_MASK {
+            next if self.is_valid_cluster(next) => Ok(Some(next)),
+            // Everything from 0x0FFFFFF8 up marks the end of the chain.
+            next if next > FAT_BAD_CLUSTER => Ok(None),
+            _ => Err(FsError::Corrupt),
+        }
+    }
+
+    /// Returns the clusters of the file that starts at `first`.
+    fn chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut sector = vec![0; self.sector_size];
+        let mut clusters = Vec::new();
+        let mut cluster = Some(first);
+
+        while let Some(current) = cluster {
+            // A chain longer than the volume must loop.
+            if !self.is_valid_cluster(current) || clusters.len() >= self.num_clusters as usize {
+                return Err(FsError::Corrupt);
+            }
+            clusters.push(current);
+            cluster = self.next_cluster(current, &mut sector)?;
+        }
+
+        Ok(clusters)
+    }
+
+    /// Reads the entries of the directory that starts at `cluster`.
+    fn dir_entries(&self, cluster: u32) -> Result<Vec<Entry>, FsError> {
+        let mut data = vec![0; self.cluster_size()];
+        let mut entries = Vec::new();
+        let mut long_name = LongName::default();
+
+        for cluster in self.chain(cluster)? {
+            self.read_cluster(cluster, &mut data)?;
+
+            for raw in data.chunks_exact(DIR_ENTRY_SIZE) {
+                let attributes = raw[11];
+                match raw[0] {
+                    ENTRY_END => return Ok(entries),
+                    ENTRY_DELETED => long_name = LongName::default(),
+                    _ if attributes & ATTR_LONG_NAME == ATTR_LONG_NAME => long_name.add(raw),
+                    _ if attributes & ATTR_VOLUME_ID != 0 => long_name = LongName::default(),
+                    _ => {
+                        let name = core::mem::take(&mut long_name)
+                            .finish(raw)
+                            .unwrap_or_else(|| short_name(raw));
+                        let cluster = u32::from(u16_at(raw, 20)) << 16 | u32::from(u16_at(raw, 26));
+                        let file_type = if attributes & ATTR_DIRECTORY != 0 {
+                            FileType::Directory
+                        } else {
+                            FileType::File
+                        };
+
+                        entries.push(Entry {
+                            name,
+                            file_type,
+                            // ".." in a directory below the root points to cluster 0.
+                            cluster: if cluster == 0 && file_type == FileType::Directory {
+                                self.root_cluster
+                            } else {
+                                cluster
+                            },
+                            size: u32_at(raw, 28),
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn lookup(&self, path: &str) -> Result<Entry, FsError> {
+        let mut entry = Entry {
+            name: String::from("/"),
+            file_type: FileType::Directory,
+            cluster: self.root_cluster,
+            size: 0,
+        };
+
+        for component in components(path) {
+            if entry.file_type != FileType::Directory {
+                return Err(FsError::NotADirectory);
+            }
+
+            // FAT names are case-insensitive.
+            entry = self
+                .dir_entries(entry.cluster)?
+                .into_iter()
+                .find(|child| child.name.eq_ignore_ascii_case(component))
+                .ok_or(FsError::NotFound)?;
+        }
+
+        Ok(entry)
+    }
+}
+
+impl Entry {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: self.file_type,
+            size: match self.file_type {
+                FileType::File => u64::from(self.size),
+                FileType::Directory => 0,
+            },
+        }
+    }
+}
+
+impl FileSystem for FatFs {
+    fn metadata(&self, path: &str) -> Result<Metadata, FsError> {
+        Ok(self.lookup(path)?.metadata())
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        let directory = self.lookup(path)?;
+        if directory.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+
+        Ok(self
+            .dir_entries(directory.cluster)?
+            .into_iter()
+            .filter(|entry| entry.name != "." && entry.name != "..")
+            .map(|entry| DirEntry {
+                metadata: entry.metadata(),
+                name: entry.name,
+            })
+            .collect())
+    }
+
+    fn read(&self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let file = self.lookup(path)?;
+        if file.file_type != FileType::File {
+            return Err(FsError::IsADirectory);
+        }
+
+        // Empty files have no clusters.
+        let size = u64::from(file.size);
+        if offset >= size || file.cluster == 0 {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+
+        let clusters = self.chain(file.cluster)?;
+        let cluster_size = self.cluster_size() as u64;
+        let mut data = vec![0; cluster_size as usize];
+        let mut done = 0;
+
+        while done < len {
+            let position = offset + done as u64;
+            let cluster = *clusters
+                .get((position / cluster_size) as usize)
+                .ok_or(FsError::Corrupt)?;
+            self.read_cluster(cluster, &mut data)?;
+
+            let start = (position % cluster_size) as usize;
+            let chunk = (len - done).min(data.len() - start);
+            buf[done..done + chunk].copy_from_slice(&data[start..start + chunk]);
+            done += chunk;
+        }
+
+        Ok(len)
+    }
+}
+
+/// Collects the parts of a long name from the LFN entries in front of a short
+/// entry.
+#[derive(Default)]
+struct LongName {
+    units: Vec<u16>,
+    checksum: u8,
+    // The sequence number of the part we expect next, 0 once we have them all.
+    expected: u8,
+    valid: bool,
+}
+
+impl LongName {
+    fn add(&mut self, raw: &[u8]) {
+        let sequence = raw[0] & 0x1F;
+
+        if raw[0] & LFN_LAST != 0 {
+            self.units = vec![0; sequence as usize * LFN_CHARS];
+            self.checksum = raw[13];
+            self.expected = sequence;
+            self.valid = (1..=LFN_MAX_ENTRIES).contains(&sequence);
+        }
+        // Once all parts are in, `expected` is 0, which a part 0 would match
+        if !self.valid || sequence == 0 || sequence != self.expected || raw[13] != self.checksum {
+            self.valid = false;
+            return;
+        }
+
+        let units = raw[1..11]
+            .chunks_exact(2)
+            .chain(raw[14..26].chunks_exact(2))
+            .chain(raw[28..32].chunks_exact(2))
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]));
+        let start = (sequence as usize - 1) * LFN_CHARS;
+        for (slot, unit) in self.units[start..start + LFN_CHARS].iter_mut().zip(units) {
+            *slot = unit;
+        }
+        self.expected = sequence - 1;
+    }
+
+    /// Returns the long name, if it is complete and belongs to the given short
+    /// entry.
+    fn finish(self, raw: &[u8]) -> Option<String> {
+        if !self.valid || self.expected != 0 || checksum(&raw[..11]) != self.checksum {
+            return None;
+        }
+
+        // The name ends with a 0 if there is room for it, followed by padding.
+        let units = self.units.into_iter().take_while(|&unit| unit != 0);
+        Some(
+            char::decode_utf16(units)
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect(),
+        )
+    }
+}
+
+/// The checksum of the short name, which LFN entries carry to show which
+/// short entry they belong to.
+fn checksum(short_name: &[u8]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// Turns "README  TXT" into "README.TXT", or "readme.txt" if it's marked as
+/// lowercase.
+fn short_name(raw: &[u8]) -> String {
+    let flags = raw[12];
+    let part = |bytes: &[u8], lowercase: bool| -> String {
+        let part: String = bytes
+            .iter()
+            .map(|&byte| match byte {
+                // 0x05 stands for a leading 0xE5, which marks deleted entries.
+                0x05 => 0xE5 as char,
+                _ if lowercase => byte.to_ascii_lowercase() as char,
+                _ => byte as char,
+            })
+            .collect();
+        String::from(part.trim_end_matches(' '))
+    };
+
+    let mut name = part(&raw[..8], flags & LOWERCASE_BASE != 0);
+    let extension = part(&raw[8..11], flags & LOWERCASE_EXTENSION != 0);
+    if !extension.is_empty() {
+        name.push('.');
+        name.push_str(&extension);
+    }
+    name
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(value)
+}
+
+#[cfg(test)]
+fn short_entry(name: &[u8; 11], flags: u8) -> [u8; DIR_ENTRY_SIZE] {
+    let mut raw = [0; DIR_ENTRY_SIZE];
+    raw[..11].copy_from_slice(name);
+    raw[12] = flags;
+    raw
+}
+
+/// An LFN entry holding `part` of a long name, padded like Windows does.
+#[cfg(test)]
+fn lfn_entry(sequence: u8, last: bool, checksum: u8, part: &str) -> [u8; DIR_ENTRY_SIZE] {
+    let mut raw = [0; DIR_ENTRY_SIZE];
+    raw[0] = sequence | if last { LFN_LAST } else { 0 };
+    raw[11] = ATTR_LONG_NAME;
+    raw[13] = checksum;
+
+    let units = part
+        .encode_utf16()
+        .chain(core::iter::once(0))
+        .chain(core::iter::repeat(0xFFFF));
+    let slots = (1..11)
+        .step_by(2)
+        .chain((14..26).step_by(2))
+        .chain((28..32).step_by(2));
+    for (offset, unit) in slots.zip(units) {
+        raw[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+    }
+    raw
+}
+
+#[test_case]
+static SHORT_NAMES: HostTest = HostTest::new("fs::fat::short_names", || {
+    assert_eq!(short_name(&short_entry(b"README  TXT", 0)), "README.TXT");
+    assert_eq!(
+        short_name(&short_entry(
+            b"README  TXT",
+            LOWERCASE_BASE | LOWERCASE_EXTENSION
+        )),
+        "readme.txt"
+    );
+    assert_eq!(short_name(&short_entry(b"BIN        ", 0)), "BIN");
+    assert_eq!(short_name(&short_entry(b"\x05BC     D  ", 0)), "\u{e5}BC.D");
+});
+
+#[test_case]
+static LONG_NAMES: HostTest = HostTest::new("fs::fat::long_names", || {
+    let short = short_entry(b"HELLOW~1TXT", 0);
+    let sum = checksum(&short[..11]);
+
+    // The last part comes first.
+    let mut long_name = LongName::default();
+    long_name.add(&lfn_entry(2, true, sum, "xt"));
+    long_name.add(&lfn_entry(1, false, sum, "hello world.t"));
+    assert_eq!(long_name.finish(&short).as_deref(), Some("hello world.txt"));
+
+    // Entries left over from a deleted file don't match the short entry.
+    let mut long_name = LongName::default();
+    long_name.add(&lfn_entry(1, true, sum.wrapping_add(1), "stale.txt"));
+    assert_eq!(long_name.finish(&short), None);
+
+    // A part is missing.
+    let mut long_name = LongName::default();
+    long_name.add(&lfn_entry(2, true, sum, "xt"));
+    assert_eq!(long_name.finish(&short), None);
+
+    // A part 0 after the last one
+    let mut long_name = LongName::default();
+    long_name.add(&lfn_entry(1, true, sum, "hello.txt"));
+    long_name.add(&lfn_entry(0, false, sum, "hello.txt"));
+    assert_eq!(long_name.finish(&short), None);
+});
+
+#[test_case]
+static LITTLE_ENDIAN_FIELDS: HostTest = HostTest::new("fs::fat::little_endian_fields", || {
+    let bytes = [0x34, 0x12, 0x78, 0x56, 0x34, 0x12];
+    assert_eq!(u16_at(&bytes, 0), 0x1234);
+    assert_eq!(u32_at(&bytes, 2), 0x1234_5678);
+});
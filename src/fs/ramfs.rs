@@ -0,0 +1,190 @@
+// A filesystem that lives on the heap, for /tmp and for tests. Its contents are
+// gone when it is dropped. Files can't grow past `MAX_FILE_SIZE`, and growing
+// one fails with `FsError::NoSpace` when the heap is out of room, rather than
+// taking the kernel down.
+
+use super::{components, split_last, DirEntry, FileSystem, FileType, FsError, Metadata};
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// The largest a file can get.
+pub const MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+enum Node {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Node>),
+}
+
+impl Default for Node {
+    fn default() -> Node {
+        Node::Directory(BTreeMap::new())
+    }
+}
+
+impl Node {
+    fn metadata(&self) -> Metadata {
+        match self {
+            Node::File(data) => Metadata {
+                file_type: FileType::File,
+                size: data.len() as u64,
+            },
+            Node::Directory(_) => Metadata {
+                file_type: FileType::Directory,
+                size: 0,
+            },
+        }
+    }
+
+    fn walk(&mut self, path: &str) -> Result<&mut Node, FsError> {
+        let mut node = self;
+        for component in components(path) {
+            node = match node {
+                Node::Directory(children) => {
+                    children.get_mut(component).ok_or(FsError::NotFound)?
+                }
+                Node::File(_) => return Err(FsError::NotADirectory),
+            };
+        }
+        Ok(node)
+    }
+
+    /// Cuts off or zero-extends `file` to `size` bytes.
+    fn resize(file: &mut Vec<u8>, size: u64) -> Result<(), FsError> {
+        if size > MAX_FILE_SIZE {
+            return Err(FsError::FileTooLarge);
+        }
+        let size = size as usize;
+        file.try_reserve(size.saturating_sub(file.len()))
+            .map_err(|_| FsError::NoSpace)?;
+        file.resize(size, 0);
+        Ok(())
+    }
+
+    fn file(&mut self, path: &str) -> Result<&mut Vec<u8>, FsError> {
+        match self.walk(path)? {
+            Node::File(data) => Ok(data),
+            Node::Directory(_) => Err(FsError::IsADirectory),
+        }
+    }
+
+    /// Returns the directory that would hold `path`, and the name in it.
+    fn parent<'a>(
+        &mut self,
+        path: &'a str,
+    ) -> Result<(&mut BTreeMap<String, Node>, &'a str), FsError> {
+        let (parent, name) = split_last(path).ok_or(FsError::InvalidPath)?;
+        if name == "." || name == ".." {
+            return Err(FsError::InvalidPath);
+        }
+
+        match self.walk(parent)? {
+            Node::Directory(children) => Ok((children, name)),
+            Node::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct RamFs {
+    root: Mutex<Node>,
+}
+
+impl RamFs {
+    /// Creates an empty filesystem.
+    pub fn new() -> RamFs {
+        RamFs::default()
+    }
+
+    fn with_root<R>(&self, f: impl FnOnce(&mut Node) -> Result<R, FsError>) -> Result<R, FsError> {
+        interrupts::without_interrupts(|| f(&mut self.root.lock()))
+    }
+
+    fn create(&self, path: &str, node: Node) -> Result<(), FsError> {
+        self.with_root(|root| {
+            let (children, name) = root.parent(path)?;
+            if children.contains_key(name) {
+                return Err(FsError::AlreadyExists);
+            }
+
+            children.insert(String::from(name), node);
+            Ok(())
+        })
+    }
+}
+
+impl FileSystem for RamFs {
+    fn metadata(&self, path: &str) -> Result<Metadata, FsError> {
+        self.with_root(|root| Ok(root.walk(path)?.metadata()))
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        self.with_root(|root| match root.walk(path)? {
+            Node::Directory(children) => Ok(children
+                .iter()
+                .map(|(name, node)| DirEntry {
+                    name: name.clone(),
+                    metadata: node.metadata(),
+                })
+                .collect()),
+            Node::File(_) => Err(FsError::NotADirectory),
+        })
+    }
+
+    fn read(&self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.with_root(|root| {
+            let data = root.file(path)?;
+            let start = (offset as usize).min(data.len());
+            let len = buf.len().min(data.len() - start);
+
+            buf[..len].copy_from_slice(&data[start..start + len]);
+            Ok(len)
+        })
+    }
+
+    fn write(&self, path: &str, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        self.with_root(|root| {
+            let file = root.file(path)?;
+            let end = offset
+                .checked_add(data.len() as u64)
+                .ok_or(FsError::FileTooLarge)?;
+
+            if (file.len() as u64) < end {
+                Node::resize(file, end)?;
+            }
+            file[offset as usize..end as usize].copy_from_slice(data);
+            Ok(data.len())
+        })
+    }
+
+    fn truncate(&self, path: &str, size: u64) -> Result<(), FsError> {
+        self.with_root(|root| Node::resize(root.file(path)?, size))
+    }
+
+    fn create_file(&self, path: &str) -> Result<(), FsError> {
+        self.create(path, Node::File(Vec::new()))
+    }
+
+    fn create_dir(&self, path: &str) -> Result<(), FsError> {
+        self.create(path, Node::default())
+    }
+
+    fn remove(&self, path: &str) -> Result<(), FsError> {
+        let node = self.with_root(|root| {
+            let (children, name) = root.parent(path)?;
+            match children.get(name) {
+                None => return Err(FsError::NotFound),
+                Some(Node::Directory(grandchildren)) if !grandchildren.is_empty() => {
+                    return Err(FsError::NotEmpty)
+                }
+                Some(_) => {}
+            }
+
+            Ok(children.remove(name))
+        })?;
+
+        // A large file is better freed without the lock.
+        drop(node);
+        Ok(())
+    }
+}
@@ -0,0 +1,236 @@
+// A read-only filesystem on top of a ustar archive in memory, for the initrd.
+//
+// The archive is a sequence of entries, each a 512-byte header followed by the
+// entry's data, padded to whole blocks. Two zeroed blocks mark the end. The
+// header holds the path (a name of up to 100 bytes, and a prefix of up to 155
+// bytes for longer paths), and the size, in octal ASCII like all numbers. Only
+// regular files and directories are supported; other entries are skipped.
+//
+// The archive is indexed once, by `new`, so lookups don't have to scan it.
+
+use super::{components, split_last, DirEntry, FileSystem, FileType, FsError, Metadata};
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+
+const BLOCK_SIZE: usize = 512;
+
+const TYPE_FILE: u8 = b'0';
+/// Old archives mark regular files with a zero instead.
+const TYPE_FILE_OLD: u8 = 0;
+const TYPE_DIRECTORY: u8 = b'5';
+
+#[derive(Debug, Clone, Copy)]
+enum Node {
+    /// The offset and size of the data in the archive
+    File {
+        offset: usize,
+        size: usize,
+    },
+    Directory,
+}
+
+pub struct TarFs {
+    data: &'static [u8],
+    // Normalized paths without the leading "/", the root being ""
+    nodes: BTreeMap<String, Node>,
+}
+
+impl TarFs {
+    /// Indexes the archive. Fails if it is truncated or a header checksum is
+    /// wrong.
+    pub fn new(data: &'static [u8]) -> Result<TarFs, FsError> {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(String::new(), Node::Directory);
+
+        let mut offset = 0;
+        while offset + BLOCK_SIZE <= data.len() {
+            let header = &data[offset..offset + BLOCK_SIZE];
+            if header.iter().all(|&byte| byte == 0) {
+                break;
+            }
+            if &header[257..262] != b"ustar" || !checksum_matches(header) {
+                return Err(FsError::Corrupt);
+            }
+
+            let size = octal(&header[124..136]).ok_or(FsError::Corrupt)? as usize;
+            let data_offset = offset + BLOCK_SIZE;
+            let data_end = data_offset.checked_add(size).ok_or(FsError::Corrupt)?;
+            if data_end > data.len() {
+                return Err(FsError::Corrupt);
+            }
+
+            let node = match header[156] {
+                TYPE_FILE | TYPE_FILE_OLD => Some(Node::File {
+                    offset: data_offset,
+                    size,
+                }),
+                TYPE_DIRECTORY => Some(Node::Directory),
+                _ => None,
+            };
+            if let Some(node) = node {
+                insert(&mut nodes, &path(header), node);
+            }
+
+            offset = data_offset + x86_64::align_up(size as u64, BLOCK_SIZE as u64) as usize;
+        }
+
+        Ok(TarFs { data, nodes })
+    }
+
+    fn lookup(&self, path: &str) -> Result<Node, FsError> {
+        let key = key(path);
+        if let Some(node) = self.nodes.get(&key) {
+            return Ok(*node);
+        }
+
+        // Tell "not found" from a path through a file
+        let mut prefix = String::new();
+        for component in components(path) {
+            if let Some(Node::File { .. }) = self.nodes.get(&prefix) {
+                return Err(FsError::NotADirectory);
+            }
+            if !prefix.is_empty() {
+                prefix.push('/');
+            }
+            prefix.push_str(component);
+        }
+        Err(FsError::NotFound)
+    }
+}
+
+/// Adds a node, and the directories above it if the archive doesn't list
+/// them itself.
+fn insert(nodes: &mut BTreeMap<String, Node>, path: &str, node: Node) {
+    let key = key(path);
+    if key.is_empty() {
+        return;
+    }
+
+    if let Some((parent, _)) = split_last(&key) {
+        if !nodes.contains_key(&self::key(parent)) {
+            insert(nodes, parent, Node::Directory);
+        }
+    }
+    nodes.insert(key, node);
+}
+
+/// Turns a path into the key of its node, e.g. "/a//b/" into "a/b".
+fn key(path: &str) -> String {
+    let mut key = String::new();
+    for component in components(path) {
+        if !key.is_empty() {
+            key.push('/');
+        }
+        key.push_str(component);
+    }
+    key
+}
+
+/// Returns the full path of an entry.
+fn path(header: &[u8]) -> String {
+    let name = string(&header[..100]);
+    let prefix = string(&header[345..500]);
+
+    if prefix.is_empty() {
+        name
+    } else {
+        format!("{}/{}", prefix, name)
+    }
+}
+
+/// Returns a NUL-terminated (or -padded) string field.
+fn string(field: &[u8]) -> String {
+    let len = field
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..len]).into_owned()
+}
+
+/// Parses an octal number, which may be padded with spaces or NULs.
+fn octal(field: &[u8]) -> Option<u64> {
+    let digits = field
+        .iter()
+        .skip_while(|&&byte| byte == b' ')
+        .take_while(|&&byte| byte != b' ' && byte != 0);
+
+    let mut value: u64 = 0;
+    for &digit in digits {
+        if !(b'0'..=b'7').contains(&digit) {
+            return None;
+        }
+        value = value.checked_mul(8)? + u64::from(digit - b'0');
+    }
+    Some(value)
+}
+
+/// The checksum is the sum of the header's bytes, with the checksum field
+/// itself counting as spaces.
+fn checksum_matches(header: &[u8]) -> bool {
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &byte)| match i {
+            148..=155 => u64::from(b' '),
+            _ => u64::from(byte),
+        })
+        .sum();
+
+    octal(&header[148..156]) == Some(sum)
+}
+
+impl Node {
+    fn metadata(&self) -> Metadata {
+        match *self {
+            Node::File { size, .. } => Metadata {
+                file_type: FileType::File,
+                size: size as u64,
+            },
+            Node::Directory => Metadata {
+                file_type: FileType::Directory,
+                size: 0,
+            },
+        }
+    }
+}
+
+impl FileSystem for TarFs {
+    fn metadata(&self, path: &str) -> Result<Metadata, FsError> {
+        Ok(self.lookup(path)?.metadata())
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        if let Node::File { .. } = self.lookup(path)? {
+            return Err(FsError::NotADirectory);
+        }
+
+        let directory = key(path);
+        Ok(self
+            .nodes
+            .iter()
+            .filter_map(|(child, node)| {
+                let (parent, name) = split_last(child)?;
+                if key(parent) != directory {
+                    return None;
+                }
+
+                Some(DirEntry {
+                    name: String::from(name),
+                    metadata: node.metadata(),
+                })
+            })
+            .collect())
+    }
+
+    fn read(&self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let (start, size) = match self.lookup(path)? {
+            Node::File { offset, size } => (offset, size),
+            Node::Directory => return Err(FsError::IsADirectory),
+        };
+
+        let file = &self.data[start..start + size];
+        let skip = (offset as usize).min(size);
+        let len = buf.len().min(size - skip);
+        buf[..len].copy_from_slice(&file[skip..skip + len]);
+        Ok(len)
+    }
+}
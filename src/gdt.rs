@@ -1,52 +1,116 @@
-use lazy_static::lazy_static;
+use crate::sync::Lazy;
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU16, Ordering};
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
 
-// We use lazy_static because Rust’s const evaluator is not yet
+// The TSS is read by the CPU whenever an interrupt arrives in ring 3: it switches
+// to the stack in `privilege_stack_table[0]` before pushing the interrupt frame.
+// Since every process has its own kernel stack, that entry changes on every
+// process switch, so the TSS lives in an UnsafeCell instead of behind a `&`.
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+// Only written by `set_kernel_stack`, with interrupts disabled.
+unsafe impl Sync for Tss {}
+
+// We use Lazy because Rust’s const evaluator is not yet
 // powerful enough to do this initialization at compile time.
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-
-            stack_start + STACK_SIZE // stack_end
-        };
-        tss
+static TSS: Lazy<Tss> = Lazy::new(|| {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+        static mut STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+
+        stack_start + DOUBLE_FAULT_STACK_SIZE // stack_end
     };
-}
+    Tss(UnsafeCell::new(tss))
+});
 
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        let s = Selectors {
-            code_selector,
-            tss_selector,
-        };
-        (gdt, s)
+// The order of the segments is dictated by the SYSCALL/SYSRET instructions,
+// which derive all four selectors from two base values: the kernel data segment
+// must directly follow the kernel code segment, and the user code segment must
+// directly follow the user data segment.
+static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*TSS.0.get() }));
+    let s = Selectors {
+        code_selector,
+        data_selector,
+        user_code_selector,
+        user_data_selector,
+        tss_selector,
     };
-}
+    (gdt, s)
+});
+
+/// The selector of the first CPU's TSS, once `init` has loaded it.
+static FIRST_TSS: AtomicU16 = AtomicU16::new(0);
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    /// Already has the requested privilege level set to 3.
+    pub user_code_selector: SegmentSelector,
+    /// Already has the requested privilege level set to 3.
+    pub user_data_selector: SegmentSelector,
+    pub tss_selector: SegmentSelector,
 }
 
 pub fn init() {
-    use x86_64::instructions::segmentation::{Segment, CS};
+    use x86_64::instructions::segmentation::{Segment, CS, SS};
     use x86_64::instructions::tables::load_tss;
 
     GDT.0.load();
 
     unsafe {
         CS::set_reg(GDT.1.code_selector);
+        SS::set_reg(GDT.1.data_selector);
         load_tss(GDT.1.tss_selector);
     }
+    FIRST_TSS.store(GDT.1.tss_selector.0, Ordering::Relaxed);
+}
+
+/// Which CPU this is, counting from 0, going by the TSS it has loaded: every
+/// CPU needs a TSS of its own, and they follow each other in the GDT, 16 bytes
+/// apart. Unlike CPUID, STR is cheap and doesn't exit to a hypervisor, and
+/// ring 3 can't change it. 0 until `init`.
+pub fn cpu_index() -> usize {
+    let first = FIRST_TSS.load(Ordering::Relaxed);
+    if first == 0 {
+        return 0;
+    }
+    let tr: u16;
+    unsafe { asm!("str {:x}", out(reg) tr, options(nomem, nostack, preserves_flags)) };
+    usize::from(tr.wrapping_sub(first) / 16)
+}
+
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
+}
+
+/// The top of the stack the CPU switches to for double faults, so that a
+/// kernel stack overflow doesn't turn into a triple fault.
+pub fn double_fault_stack_top() -> VirtAddr {
+    unsafe { (*TSS.0.get()).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] }
+}
+
+/// Sets the stack the CPU switches to when an interrupt or exception arrives
+/// while running in ring 3.
+///
+/// # Safety
+///
+/// Must be called with interrupts disabled, and the stack must stay valid for
+/// as long as ring 3 code may run with it.
+pub unsafe fn set_kernel_stack(stack_top: VirtAddr) {
+    (*TSS.0.get()).privilege_stack_table[0] = stack_top;
 }
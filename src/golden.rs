@@ -0,0 +1,43 @@
+// Golden output: tests print what they want compared, like a shell command's
+// output or a panic message, as a transcript framed by marker lines,
+//
+//   GOLDEN BEGIN <name>
+//   ...
+//   GOLDEN END <name>
+//
+// and `tools/golden.py` compares the transcripts in a serial log with the
+// expected ones in tests/golden/, so that a change in formatting shows up as a
+// failing diff instead of going unnoticed:
+//
+//     cargo test --test golden | tools/golden.py
+//
+// `tools/golden.py --update` writes the transcripts from the log to
+// tests/golden/ instead, after a change that's meant to alter the output.
+//
+// A transcript is only useful if it's the same on every run, so it mustn't
+// hold timestamps, addresses or anything else that changes between boots.
+
+use crate::serial;
+use core::fmt::{self, Write};
+
+/// Prints a transcript called `name` with what `f` writes, framed by the
+/// markers. The markers start on a line of their own, even if the test runner
+/// left the test's name on the current line.
+pub fn transcript(name: &str, f: impl FnOnce(&mut dyn Write) -> fmt::Result) {
+    let mut out = Serial;
+    // Writing to the serial port doesn't fail.
+    let _ = writeln!(out, "\nGOLDEN BEGIN {}", name);
+    let _ = f(&mut out);
+    let _ = writeln!(out, "GOLDEN END {}", name);
+}
+
+/// Writes to the serial port a piece at a time, so that `f` can print or log
+/// in between without waiting for the port's lock.
+struct Serial;
+
+impl Write for Serial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        serial::_print(format_args!("{}", s));
+        Ok(())
+    }
+}
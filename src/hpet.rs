@@ -0,0 +1,155 @@
+// The High Precision Event Timer (HPET): a counter that runs at a fixed rate,
+// usually 10 MHz or more, and a few comparators that raise an interrupt when
+// the counter reaches them. The ACPI "HPET" table says where its registers
+// are, and they're memory mapped, in the same region below 4 GiB as the local
+// APIC's, so `memory::phys_to_virt` reaches them (see apic.rs).
+//
+// `init` finds it and starts the counter. The counter is there for anyone
+// who wants a clock that isn't the TSC, and `time` can make timer 0 raise the
+// timer interrupt instead of the PIT. That uses the "legacy replacement"
+// route, which connects timer 0 to IRQ0 (and timer 1 to IRQ8, which the RTC
+// doesn't use here) and disconnects the PIT, so nothing else about the timer
+// interrupt changes.
+
+use crate::acpi;
+use crate::memory;
+use crate::mmio::RegisterBlock;
+#[cfg(test)]
+use crate::HostTest;
+use spin::Once;
+use x86_64::PhysAddr;
+
+const REGISTERS_SIZE: usize = 0x400;
+
+// Register offsets
+const CAPABILITIES: usize = 0x000;
+const CONFIG: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0F0;
+
+const fn timer_config(timer: usize) -> usize {
+    0x100 + 0x20 * timer
+}
+
+const fn timer_comparator(timer: usize) -> usize {
+    0x108 + 0x20 * timer
+}
+
+const CAPABILITIES_LEGACY_REPLACEMENT: u64 = 1 << 15;
+const CAPABILITIES_PERIOD_SHIFT: u64 = 32;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_REPLACEMENT: u64 = 1 << 1;
+
+const TIMER_INT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+const TIMER_VALUE_SET: u64 = 1 << 6;
+const TIMER_32_BIT: u64 = 1 << 8;
+
+/// The longest counter period that the specification allows, 100 ns, in
+/// femtoseconds.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+/// Where the base address is in the ACPI table: a generic address structure
+/// at 40, whose address is 4 bytes in.
+const TABLE_ADDRESS_SPACE: usize = 40;
+const TABLE_ADDRESS: usize = 44;
+const TABLE_LEN: usize = 56;
+const ADDRESS_SPACE_MEMORY: u8 = 0;
+
+pub struct Hpet {
+    registers: RegisterBlock,
+    period_fs: u64,
+}
+
+static HPET: Once<Option<Hpet>> = Once::new();
+
+/// Finds the HPET and starts its counter. Called after `acpi::init`.
+pub fn init() {
+    HPET.call_once(|| {
+        let address = base_address(acpi::find("HPET")?.data())?;
+        unsafe { Hpet::at(address) }
+    });
+}
+
+/// The HPET, if `init` found one.
+pub fn get() -> Option<&'static Hpet> {
+    HPET.r#try()?.as_ref()
+}
+
+/// The address of the registers, from the ACPI table.
+fn base_address(table: &[u8]) -> Option<PhysAddr> {
+    if table.len() < TABLE_LEN || table[TABLE_ADDRESS_SPACE] != ADDRESS_SPACE_MEMORY {
+        return None;
+    }
+    PhysAddr::try_new(acpi::u64_at(table, TABLE_ADDRESS)).ok()
+}
+
+impl Hpet {
+    /// Unsafe because `address` must be where the HPET's registers are.
+    unsafe fn at(address: PhysAddr) -> Option<Hpet> {
+        let base = memory::phys_to_virt(address);
+        let registers = RegisterBlock::new("hpet", base, REGISTERS_SIZE);
+        let period_fs = registers.read::<u64>(CAPABILITIES) >> CAPABILITIES_PERIOD_SHIFT;
+        if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+            return None;
+        }
+
+        registers.set(CONFIG, CONFIG_ENABLE);
+        Some(Hpet {
+            registers,
+            period_fs,
+        })
+    }
+
+    /// How fast the counter runs.
+    pub fn frequency_hz(&self) -> u64 {
+        FS_PER_SECOND / self.period_fs
+    }
+
+    /// The counter, which started at `init`, or when the timer interrupt
+    /// moved here.
+    pub fn counter(&self) -> u64 {
+        self.registers.read(MAIN_COUNTER)
+    }
+
+    /// Whether timer 0 can raise the timer interrupt, for `start_ticking`.
+    pub fn can_tick(&self) -> bool {
+        self.registers.read::<u64>(CAPABILITIES) & CAPABILITIES_LEGACY_REPLACEMENT != 0
+            && self.registers.read::<u64>(timer_config(0)) & TIMER_PERIODIC_CAPABLE != 0
+    }
+
+    /// Makes timer 0 raise the timer interrupt (IRQ0) `hz` times a second,
+    /// instead of the PIT. Only for `time`, which checked `can_tick`.
+    pub(crate) fn start_ticking(&self, hz: u64) {
+        let period = self.frequency_hz() / hz;
+
+        // The counter is stopped and restarted at 0, so that the first
+        // interrupt comes a whole period from now.
+        self.registers.clear(CONFIG, CONFIG_ENABLE);
+        self.registers.write(MAIN_COUNTER, 0u64);
+        self.registers.modify(timer_config(0), |config: u64| {
+            config & !TIMER_32_BIT | TIMER_INT_ENABLE | TIMER_PERIODIC | TIMER_VALUE_SET
+        });
+        // With TIMER_VALUE_SET, the first write is the comparator, and the
+        // second how far it moves on after each interrupt.
+        self.registers.write(timer_comparator(0), period);
+        self.registers.write(timer_comparator(0), period);
+        self.registers
+            .set(CONFIG, CONFIG_ENABLE | CONFIG_LEGACY_REPLACEMENT);
+    }
+}
+
+#[test_case]
+static FINDS_THE_REGISTERS: HostTest = HostTest::new("hpet::finds_the_registers", || {
+    // QEMU's table, after the header
+    let mut table = [0u8; TABLE_LEN];
+    table[36..40].copy_from_slice(&0x8086_A201u32.to_le_bytes());
+    table[TABLE_ADDRESS..TABLE_ADDRESS + 8].copy_from_slice(&0xFED0_0000u64.to_le_bytes());
+    assert_eq!(base_address(&table), Some(PhysAddr::new(0xFED0_0000)));
+
+    table[TABLE_ADDRESS_SPACE] = 1;
+    assert_eq!(base_address(&table), None);
+    assert_eq!(base_address(&table[..40]), None);
+});
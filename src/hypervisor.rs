@@ -0,0 +1,275 @@
+// Finding out whether we run in a virtual machine, and in which. Hypervisors
+// set the "hypervisor present" bit of CPUID leaf 1, and answer the CPUID
+// leaves from 0x40000000 on themselves: that one gives the highest of their
+// leaves and a 12 character signature, like "KVMKVMKVM" or "VMwareVMware".
+// KVM with Hyper-V enlightenments answers as Hyper-V there, and as KVM from
+// 0x40000100 on.
+//
+// Under a hypervisor, measuring the TSC against the PIT (see time.rs) goes
+// wrong when the host deschedules us during the measurement. KVM tells us the
+// TSC's frequency exactly through kvmclock: we hand it the physical address
+// of a `PvclockTimeInfo` in an MSR, and it fills that in with how to turn TSC
+// cycles into nanoseconds. VMware, and KVM with some CPU models, put the
+// frequency into CPUID leaf 0x40000010 instead. `init` replaces the measured
+// frequency with whichever there is.
+
+use crate::{info, memory, time};
+use core::arch::x86_64::__cpuid;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{self, AtomicBool, Ordering};
+use spin::Once;
+use x86_64::registers::model_specific::Msr;
+use x86_64::VirtAddr;
+
+const HYPERVISOR_PRESENT: u32 = 1 << 31;
+const FIRST_LEAF: u32 = 0x4000_0000;
+const TIMING_LEAF: u32 = 0x4000_0010;
+
+/// Where KVM's leaves start if there's a Hyper-V interface in front of them.
+const KVM_ALTERNATE_LEAF: u32 = 0x4000_0100;
+/// From the KVM features leaf, right after the signature's.
+const KVM_FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+const KVM_SYSTEM_TIME_ENABLE: u64 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hypervisor {
+    Kvm,
+    /// QEMU without KVM, emulating the CPU itself (TCG).
+    Qemu,
+    VMware,
+    HyperV,
+    Xen,
+    VirtualBox,
+    /// A hypervisor with a signature we don't know.
+    Other([u8; 12]),
+}
+
+impl Hypervisor {
+    fn from_signature(signature: &[u8; 12]) -> Hypervisor {
+        match signature {
+            b"KVMKVMKVM\0\0\0" => Hypervisor::Kvm,
+            b"TCGTCGTCGTCG" => Hypervisor::Qemu,
+            b"VMwareVMware" => Hypervisor::VMware,
+            b"Microsoft Hv" => Hypervisor::HyperV,
+            b"XenVMMXenVMM" => Hypervisor::Xen,
+            b"VBoxVBoxVBox" => Hypervisor::VirtualBox,
+            _ => Hypervisor::Other(*signature),
+        }
+    }
+}
+
+impl fmt::Display for Hypervisor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Hypervisor::Kvm => write!(f, "KVM"),
+            Hypervisor::Qemu => write!(f, "QEMU"),
+            Hypervisor::VMware => write!(f, "VMware"),
+            Hypervisor::HyperV => write!(f, "Hyper-V"),
+            Hypervisor::Xen => write!(f, "Xen"),
+            Hypervisor::VirtualBox => write!(f, "VirtualBox"),
+            Hypervisor::Other(signature) => {
+                let signature = signature.split(|byte| *byte == 0).next().unwrap_or(&[]);
+                write!(
+                    f,
+                    "{}",
+                    core::str::from_utf8(signature).unwrap_or("unknown")
+                )
+            }
+        }
+    }
+}
+
+static DETECTED: Once<Option<Hypervisor>> = Once::new();
+
+/// The hypervisor we run under, if any.
+pub fn detect() -> Option<Hypervisor> {
+    *DETECTED.call_once(|| {
+        if unsafe { __cpuid(1) }.ecx & HYPERVISOR_PRESENT == 0 {
+            return None;
+        }
+        if kvm_leaf().is_some() {
+            return Some(Hypervisor::Kvm);
+        }
+        signature(FIRST_LEAF).map(|(signature, _)| Hypervisor::from_signature(&signature))
+    })
+}
+
+/// Whether we run in a virtual machine. Drivers can use this to skip
+/// workarounds for real hardware, or delays that only real hardware needs.
+pub fn is_virtualized() -> bool {
+    detect().is_some()
+}
+
+/// The signature at `leaf`, and the highest hypervisor leaf.
+fn signature(leaf: u32) -> Option<([u8; 12], u32)> {
+    let result = unsafe { __cpuid(leaf) };
+    if result.eax < leaf {
+        return None;
+    }
+
+    let mut signature = [0; 12];
+    signature[0..4].copy_from_slice(&result.ebx.to_le_bytes());
+    signature[4..8].copy_from_slice(&result.ecx.to_le_bytes());
+    signature[8..12].copy_from_slice(&result.edx.to_le_bytes());
+    Some((signature, result.eax))
+}
+
+/// The first of KVM's leaves, if we run under KVM.
+fn kvm_leaf() -> Option<u32> {
+    [FIRST_LEAF, KVM_ALTERNATE_LEAF]
+        .iter()
+        .copied()
+        .find(|&leaf| {
+            signature(leaf).map(|(signature, _)| Hypervisor::from_signature(&signature))
+                == Some(Hypervisor::Kvm)
+        })
+}
+
+/// What KVM fills in for kvmclock.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct PvclockTimeInfo {
+    /// Odd while KVM updates the rest.
+    version: u32,
+    _pad0: u32,
+    tsc_timestamp: u64,
+    /// Nanoseconds since the VM started, at `tsc_timestamp`.
+    system_time: u64,
+    /// Nanoseconds per TSC cycle, scaled by 2^-32 after shifting the cycles
+    /// by `tsc_shift` (left if positive).
+    tsc_to_system_mul: u32,
+    tsc_shift: i8,
+    flags: u8,
+    _pad: [u8; 2],
+}
+
+impl PvclockTimeInfo {
+    fn cycles_to_ns(&self, cycles: u64) -> u64 {
+        let cycles = match self.tsc_shift {
+            shift if shift >= 0 => cycles << shift,
+            shift => cycles >> -shift,
+        };
+        ((u128::from(cycles) * u128::from(self.tsc_to_system_mul)) >> 32) as u64
+    }
+
+    fn tsc_hz(&self) -> Option<u64> {
+        if self.tsc_to_system_mul == 0 {
+            return None;
+        }
+        let hz = (1_000_000_000u128 << 32) / u128::from(self.tsc_to_system_mul);
+        let hz = match self.tsc_shift {
+            shift if shift >= 0 => hz >> shift,
+            shift => hz << -shift,
+        };
+        Some(hz as u64)
+    }
+}
+
+/// KVM writes the time info as it likes, so it lives in an `UnsafeCell` and
+/// is only ever read volatile. The alignment keeps it within one page.
+#[repr(C, align(32))]
+struct KvmClock(UnsafeCell<PvclockTimeInfo>);
+
+unsafe impl Sync for KvmClock {}
+
+static KVM_CLOCK: KvmClock = KvmClock(UnsafeCell::new(PvclockTimeInfo {
+    version: 0,
+    _pad0: 0,
+    tsc_timestamp: 0,
+    system_time: 0,
+    tsc_to_system_mul: 0,
+    tsc_shift: 0,
+    flags: 0,
+    _pad: [0; 2],
+}));
+static KVM_CLOCK_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turns kvmclock on, if KVM has it. Needs `memory::init` for the time info's
+/// physical address.
+fn enable_kvm_clock() -> bool {
+    let features = match kvm_leaf() {
+        Some(leaf) => unsafe { __cpuid(leaf + 1) }.eax,
+        None => return false,
+    };
+    if features & KVM_FEATURE_CLOCKSOURCE2 == 0 {
+        return false;
+    }
+    let address = match memory::translate_addr(VirtAddr::from_ptr(KVM_CLOCK.0.get())) {
+        Some(address) => address,
+        None => return false,
+    };
+
+    unsafe { Msr::new(MSR_KVM_SYSTEM_TIME_NEW).write(address.as_u64() | KVM_SYSTEM_TIME_ENABLE) };
+    KVM_CLOCK_ENABLED.store(true, Ordering::Release);
+    true
+}
+
+/// A consistent copy of kvmclock's time info, if it's on.
+fn kvm_clock() -> Option<PvclockTimeInfo> {
+    if !KVM_CLOCK_ENABLED.load(Ordering::Acquire) {
+        return None;
+    }
+    let info = KVM_CLOCK.0.get();
+    loop {
+        let version = unsafe { ptr::read_volatile(ptr::addr_of!((*info).version)) };
+        atomic::fence(Ordering::Acquire);
+        let copy = unsafe { ptr::read_volatile(info) };
+        atomic::fence(Ordering::Acquire);
+        let version_after = unsafe { ptr::read_volatile(ptr::addr_of!((*info).version)) };
+        if version % 2 == 0 && version == version_after {
+            return Some(copy);
+        }
+    }
+}
+
+/// Nanoseconds since the VM started, by kvmclock.
+pub fn kvm_clock_ns() -> Option<u64> {
+    let info = kvm_clock()?;
+    let cycles = time::tsc().saturating_sub(info.tsc_timestamp);
+    Some(info.system_time + info.cycles_to_ns(cycles))
+}
+
+/// The TSC frequency as the hypervisor says it is, from kvmclock or the
+/// timing leaf.
+pub fn tsc_hz() -> Option<u64> {
+    if let Some(hz) = kvm_clock().and_then(|info| info.tsc_hz()) {
+        return Some(hz);
+    }
+
+    detect()?;
+    match signature(FIRST_LEAF) {
+        Some((_, max_leaf)) if max_leaf >= TIMING_LEAF => {
+            match unsafe { __cpuid(TIMING_LEAF) }.eax {
+                0 => None,
+                khz => Some(u64::from(khz) * 1000),
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Detects the hypervisor and takes the TSC frequency from it, if it says.
+/// Called once memory is set up.
+pub fn init() {
+    let hypervisor = match detect() {
+        Some(hypervisor) => hypervisor,
+        None => return,
+    };
+    let kvm_clock = enable_kvm_clock();
+
+    match tsc_hz() {
+        Some(hz) => {
+            time::set_tsc_hz(hz);
+            info!(
+                "running under {}, TSC at {} kHz{}",
+                hypervisor,
+                hz / 1000,
+                if kvm_clock { " (kvmclock)" } else { "" }
+            );
+        }
+        None => info!("running under {}", hypervisor),
+    }
+}
@@ -0,0 +1,252 @@
+// What the CPU does when there's nothing to run. The executor and the
+// scheduler call `wait` with interrupts disabled once they've seen that there's
+// no work left, and it returns after the next interrupt.
+//
+// The plain way is `sti; hlt`, which only ever gets the CPU into C1, and has
+// the interrupt handler run before the CPU is back in the idle path. Where the
+// CPU has MONITOR and MWAIT, and MWAIT can be woken by interrupts that are
+// still masked, `init` switches to those: MWAIT takes a hint of how deep a
+// C-state to go to, and CPUID leaf 5 lists the ones there are. Deeper states
+// save more power but take longer to wake up from, so drivers that can't
+// stand the latency hold a `NoDeepIdle` while they're active, and as long as
+// anyone does, MWAIT only goes to C1. The deep states are also off limits
+// where the local APIC timer stops in them (no ARAT in CPUID leaf 6), and,
+// when the timer interrupt comes from TSC-deadline mode, where the TSC does
+// (no invariant TSC): the CPU would sleep through its next tick, or never
+// wake up. MONITOR also watches a cache line that the caller names, like the
+// tail of the executor's queue, so that a write to it wakes the CPU without an
+// interrupt.
+//
+// `idle=hlt` on the command line sticks to HLT, and `idle=shallow` never goes
+// deeper than C1. `idle` lists how long each CPU spent in which state.
+
+use crate::time::{self, TickSource};
+#[cfg(test)]
+use crate::HostTest;
+use crate::{cmdline, info, per_cpu, shell};
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+/// The CPU has MONITOR and MWAIT (CPUID leaf 1, ECX).
+const CPUID_MONITOR: u32 = 1 << 3;
+const MWAIT_LEAF: u32 = 5;
+/// CPUID leaf 5, ECX: the extensions below are listed, and an interrupt
+/// wakes MWAIT even if it's masked.
+const MWAIT_EXTENSIONS: u32 = 1 << 0;
+const MWAIT_INTERRUPT_BREAK: u32 = 1 << 1;
+/// MWAIT's ECX, to be woken by masked interrupts.
+const MWAIT_BREAK_ON_INTERRUPT: u64 = 1 << 0;
+/// The hint for C1.
+const C1_HINT: u32 = 0;
+const POWER_LEAF: u32 = 6;
+/// CPUID leaf 6, EAX: the local APIC timer keeps running in deep C-states.
+const CPUID_ARAT: u32 = 1 << 2;
+const INVARIANT_TSC_LEAF: u32 = 0x8000_0007;
+/// CPUID leaf 0x8000_0007, EDX: the TSC keeps running in deep C-states.
+const CPUID_INVARIANT_TSC: u32 = 1 << 8;
+
+static MWAIT: AtomicBool = AtomicBool::new(false);
+static DEEP_HINT: AtomicU32 = AtomicU32::new(C1_HINT);
+static DEEP_BLOCKERS: AtomicUsize = AtomicUsize::new(0);
+
+/// How deep the CPU went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Depth {
+    /// C1, with HLT or MWAIT.
+    Shallow = 0,
+    Deep = 1,
+}
+
+struct Residency {
+    entries: [AtomicU64; 2],
+    /// TSC cycles, from going idle to being woken.
+    cycles: [AtomicU64; 2],
+}
+
+impl Residency {
+    const fn new() -> Residency {
+        Residency {
+            entries: [AtomicU64::new(0), AtomicU64::new(0)],
+            cycles: [AtomicU64::new(0), AtomicU64::new(0)],
+        }
+    }
+}
+
+per_cpu! {
+    static RESIDENCY: Residency = Residency::new();
+}
+
+/// The MWAIT hint of the deepest C-state in CPUID leaf 5's EDX, which counts
+/// the sub-states of C0 to C7 in 4 bits each, and which C-state that is.
+fn deepest_state(substates: u32) -> Option<(u32, u32)> {
+    (1..8u32)
+        .rev()
+        .find(|state| substates >> (state * 4) & 0xf != 0)
+        .map(|state| (state, (state - 1) << 4))
+}
+
+/// Which timer stops in the deep C-states and would keep the CPU from waking
+/// up in time, if one does.
+fn timer_stopping_in_deep_states() -> Option<&'static str> {
+    let arat = unsafe { __cpuid(0) }.eax >= POWER_LEAF
+        && unsafe { __cpuid(POWER_LEAF) }.eax & CPUID_ARAT != 0;
+    if !arat {
+        return Some("APIC timer");
+    }
+
+    let invariant_tsc = unsafe { __cpuid(0x8000_0000) }.eax >= INVARIANT_TSC_LEAF
+        && unsafe { __cpuid(INVARIANT_TSC_LEAF) }.edx & CPUID_INVARIANT_TSC != 0;
+    if time::tick_source() == TickSource::TscDeadline && !invariant_tsc {
+        return Some("TSC");
+    }
+    None
+}
+
+/// Picks how to idle, from the CPU, the command line and the timer interrupt's
+/// source, so it goes after `time::select_tick_source`.
+pub fn init() {
+    let idle = cmdline::value("idle");
+    if idle == Some("hlt") || unsafe { __cpuid(1) }.ecx & CPUID_MONITOR == 0 {
+        info!("idle: hlt");
+        return;
+    }
+    if unsafe { __cpuid(0) }.eax < MWAIT_LEAF {
+        info!("idle: hlt, no MWAIT leaf");
+        return;
+    }
+    let leaf = unsafe { __cpuid(MWAIT_LEAF) };
+    if leaf.ecx & (MWAIT_EXTENSIONS | MWAIT_INTERRUPT_BREAK)
+        != MWAIT_EXTENSIONS | MWAIT_INTERRUPT_BREAK
+    {
+        info!("idle: hlt, MWAIT can't be woken by masked interrupts");
+        return;
+    }
+
+    MWAIT.store(true, Ordering::Relaxed);
+    match deepest_state(leaf.edx).filter(|_| idle != Some("shallow")) {
+        Some((state, hint)) if hint != C1_HINT => match timer_stopping_in_deep_states() {
+            Some(timer) => info!("idle: mwait, C1 only, the {} stops in C{}", timer, state),
+            None => {
+                DEEP_HINT.store(hint, Ordering::Relaxed);
+                info!("idle: mwait, down to C{}", state);
+            }
+        },
+        _ => info!("idle: mwait, C1 only"),
+    }
+}
+
+/// Keeps the CPUs out of the deep idle states until it's dropped, see
+/// `disable_deep`.
+#[must_use = "deep idle is allowed again when it's dropped"]
+pub struct NoDeepIdle(());
+
+/// Keeps the CPUs out of the deep idle states until the returned guard is
+/// dropped, for drivers that need to react to interrupts quickly.
+pub fn disable_deep() -> NoDeepIdle {
+    DEEP_BLOCKERS.fetch_add(1, Ordering::Relaxed);
+    NoDeepIdle(())
+}
+
+impl Drop for NoDeepIdle {
+    fn drop(&mut self) {
+        DEEP_BLOCKERS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Waits for the next interrupt, or for a write to the cache line of `watch`
+/// with MWAIT. Must be called with interrupts disabled, after checking that
+/// there's nothing to do, so that an interrupt in between isn't missed;
+/// returns with them enabled, once the interrupt has been handled.
+pub fn wait(watch: *const u8) {
+    let hint = match DEEP_BLOCKERS.load(Ordering::Relaxed) {
+        0 => DEEP_HINT.load(Ordering::Relaxed),
+        _ => C1_HINT,
+    };
+    let depth = match hint {
+        C1_HINT => Depth::Shallow,
+        _ => Depth::Deep,
+    };
+
+    let start = time::tsc();
+    if MWAIT.load(Ordering::Relaxed) {
+        // A masked interrupt still wakes MWAIT, and is taken after `sti`.
+        unsafe {
+            asm!("monitor", in("rax") watch, in("ecx") 0, in("edx") 0, options(nostack));
+            asm!(
+                "mwait",
+                in("eax") hint,
+                in("rcx") MWAIT_BREAK_ON_INTERRUPT,
+                options(nostack)
+            );
+        }
+        record(depth, start);
+        x86_64::instructions::interrupts::enable();
+    } else {
+        x86_64::instructions::interrupts::enable_and_hlt();
+        record(depth, start);
+    }
+}
+
+fn record(depth: Depth, start: u64) {
+    let residency = RESIDENCY.get();
+    residency.entries[depth as usize].fetch_add(1, Ordering::Relaxed);
+    residency.cycles[depth as usize].fetch_add(time::tsc().wrapping_sub(start), Ordering::Relaxed);
+}
+
+pub fn register_commands() {
+    shell::register("idle", "print how the CPUs idle and for how long", idle);
+}
+
+fn idle(_args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    let method = match MWAIT.load(Ordering::Relaxed) {
+        true => "mwait",
+        false => "hlt",
+    };
+    write!(out, "method:  {}\ndeep:    ", method)?;
+    match (
+        DEEP_HINT.load(Ordering::Relaxed),
+        DEEP_BLOCKERS.load(Ordering::Relaxed),
+    ) {
+        (C1_HINT, _) => writeln!(out, "none")?,
+        (hint, 0) => writeln!(out, "C{}", (hint >> 4) + 1)?,
+        (hint, blockers) => writeln!(out, "C{}, held off by {}", (hint >> 4) + 1, blockers)?,
+    }
+
+    let tsc_hz = match time::tsc_hz() {
+        Some(hz) => hz,
+        None => return writeln!(out, "the TSC isn't calibrated, no residency"),
+    };
+    let uptime_ms = time::uptime_ms().max(1);
+    writeln!(out, "CPU  STATE    ENTRIES        TIME")?;
+    for (cpu, residency) in RESIDENCY.iter().enumerate() {
+        for (depth, name) in [(Depth::Shallow, "shallow"), (Depth::Deep, "deep")] {
+            let entries = residency.entries[depth as usize].load(Ordering::Relaxed);
+            if entries == 0 {
+                continue;
+            }
+            let ms = residency.cycles[depth as usize].load(Ordering::Relaxed) * 1000 / tsc_hz;
+            writeln!(
+                out,
+                "{:>3}  {:<7}  {:>7}  {:>7} ms ({}%)",
+                cpu,
+                name,
+                entries,
+                ms,
+                ms * 100 / uptime_ms
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[test_case]
+static FINDS_DEEPEST_STATE: HostTest = HostTest::new("idle::finds_deepest_state", || {
+    // C0 and C1 with 2 sub-states each, C2 with 1, nothing deeper
+    assert_eq!(deepest_state(0x0122), Some((2, 0x10)));
+    // C6 with a sub-state
+    assert_eq!(deepest_state(0x0100_0020), Some((6, 0x50)));
+    assert_eq!(deepest_state(0x0020), Some((1, C1_HINT)));
+    assert_eq!(deepest_state(0x0002), None);
+});
@@ -0,0 +1,28 @@
+// The initial ramdisk: a tar archive with the user programs (in /bin) and the
+// contents of initrd/ in the repository, which build.rs packs and the kernel
+// embeds. The bootloader has no way of loading one from disk for us, but
+// embedding it has the same effect: the files are there before any disk driver
+// is.
+
+use crate::error::KernelError;
+use crate::fs::{self, tar::TarFs};
+use crate::info;
+use alloc::sync::Arc;
+
+/// Where the initrd is mounted.
+pub const MOUNT_PATH: &str = "/init";
+
+pub static ARCHIVE: &[u8] = include_bytes!(env!("INITRD"));
+
+/// Mounts the initrd at `MOUNT_PATH`.
+pub fn init() -> Result<(), KernelError> {
+    let initrd = TarFs::new(ARCHIVE)?;
+    fs::mount(MOUNT_PATH, Arc::new(initrd))?;
+
+    info!(
+        "initrd: {} KiB mounted at {}",
+        ARCHIVE.len() / 1024,
+        MOUNT_PATH
+    );
+    Ok(())
+}
@@ -1,5 +1,8 @@
-use crate::{gdt, hlt_loop, print, println};
-use lazy_static::lazy_static;
+use crate::io::PortRange;
+use crate::sync::Lazy;
+use crate::{apic, gdt, hlt_loop, println, shell, symbols, unwind, usercopy};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use pic8259::ChainedPics;
 use spin;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
@@ -10,11 +13,18 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
-#[derive(Debug, Clone, Copy)]
+// The pic8259 crate does its own port accesses, so these are only claimed,
+// except that `is_spurious` reads the in-service registers through them
+pub static PIC_1_PORTS: PortRange = PortRange::new("pic1", 0x20, 2);
+pub static PIC_2_PORTS: PortRange = PortRange::new("pic2", 0xA0, 2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    SerialSecondary = PIC_1_OFFSET + 3, // COM2 and COM4 share IRQ3
+    Serial = PIC_1_OFFSET + 4,          // COM1 and COM3 share IRQ4
 }
 
 impl InterruptIndex {
@@ -27,29 +37,375 @@ impl InterruptIndex {
     }
 }
 
-lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+crate::per_cpu! {
+    /// How often each legacy IRQ line has fired on the CPU.
+    static IRQ_COUNTS: [AtomicU64; 16] = [ZERO; 16];
+    /// How often each vector of `allocate_vector` has been raised on the CPU.
+    static VECTOR_COUNTS: [AtomicU64; DYNAMIC_VECTORS] = [ZERO; DYNAMIC_VECTORS];
+    /// How many interrupt handlers the CPU is in.
+    static NESTING: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// Counts the CPU as being in an interrupt handler until it's dropped.
+struct InInterrupt;
+
+impl InInterrupt {
+    fn enter() -> InInterrupt {
+        NESTING.get().fetch_add(1, Ordering::Relaxed);
+        InInterrupt
+    }
+}
+
+impl Drop for InInterrupt {
+    fn drop(&mut self) {
+        NESTING.get().fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Whether the current CPU is running an interrupt handler, where nothing
+/// may block.
+pub fn in_interrupt() -> bool {
+    NESTING.get().load(Ordering::Relaxed) > 0
+}
+
+fn count_irq(index: InterruptIndex) {
+    let irq = usize::from(index.as_u8() - PIC_1_OFFSET);
+    IRQ_COUNTS.get()[irq].fetch_add(1, Ordering::Relaxed);
+
+    // The timer would drown out everything else.
+    if crate::debugflags::TRACE_IRQ.get() && index != InterruptIndex::Timer {
+        crate::info!(target: "irq", "irq {} ({:?})", irq, index);
+    }
+}
+
+/// Returns how often the given legacy IRQ line (0-15) has fired since boot.
+pub fn irq_count(irq: u8) -> u64 {
+    IRQ_COUNTS
+        .iter()
+        .map(|counts| counts[usize::from(irq)].load(Ordering::Relaxed))
+        .sum()
+}
+
+/// The first of the vectors that `allocate_vector` hands out, right after the
+/// PICs' vectors.
+pub const FIRST_DYNAMIC_VECTOR: u8 = PIC_2_OFFSET + 8;
+/// How many vectors `allocate_vector` can hand out.
+pub const DYNAMIC_VECTORS: usize = 16;
+
+// The handlers of the allocated vectors, as `fn()` pointers, or 0 for a free
+// vector. Atomics rather than a lock, since interrupt handlers read them.
+const FREE: AtomicUsize = AtomicUsize::new(0);
+static VECTOR_HANDLERS: [AtomicUsize; DYNAMIC_VECTORS] = [FREE; DYNAMIC_VECTORS];
+
+/// Reserves a vector for MSIs, and returns it. `handler` runs (with
+/// interrupts disabled) whenever the vector is raised; the end of interrupt
+/// is sent by the caller, to the local APIC.
+///
+/// Returns `None` if all vectors are taken.
+pub fn allocate_vector(handler: fn()) -> Option<u8> {
+    let index = VECTOR_HANDLERS.iter().position(|slot| {
+        slot.compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+    })?;
+    Some(FIRST_DYNAMIC_VECTOR + index as u8)
+}
+
+/// Gives a vector from `allocate_vector` back. The device that raised it
+/// must not do so anymore.
+pub fn free_vector(vector: u8) {
+    let index = usize::from(vector - FIRST_DYNAMIC_VECTOR);
+    VECTOR_HANDLERS[index].store(0, Ordering::Release);
+}
+
+/// Returns how often the given vector from `allocate_vector` has been raised
+/// since boot, by whichever device had it.
+pub fn vector_count(vector: u8) -> u64 {
+    let index = usize::from(vector - FIRST_DYNAMIC_VECTOR);
+    VECTOR_COUNTS
+        .iter()
+        .map(|counts| counts[index].load(Ordering::Relaxed))
+        .sum()
+}
+
+fn dynamic_interrupt(index: usize) {
+    let _in_interrupt = InInterrupt::enter();
+    VECTOR_COUNTS.get()[index].fetch_add(1, Ordering::Relaxed);
+    let handler = VECTOR_HANDLERS[index].load(Ordering::Acquire);
+    if handler != 0 {
+        // Only ever stored from a `fn()` by `allocate_vector`
+        let handler: fn() = unsafe { core::mem::transmute(handler) };
+        handler();
+    }
+
+    apic::end_of_interrupt();
+}
+
+/// The legacy IRQ lines that devices may use with `set_irq_handler`: all but
+/// the ones with a handler of their own and the PICs' cascade (IRQ2).
+pub const DEVICE_IRQS: core::ops::RangeInclusive<u8> = 5..=15;
 
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+// The handlers of the device IRQ lines, like `VECTOR_HANDLERS`
+static IRQ_HANDLERS: [AtomicUsize; 16] = [FREE; 16];
+
+/// Makes `handler` run whenever the legacy IRQ line `irq` is raised, and
+/// unmasks it. This is for PCI devices without MSI, whose interrupt line the
+/// firmware routed to one of the PICs.
+///
+/// Returns false if the line isn't one of `DEVICE_IRQS`, or already has a
+/// handler. Lines can't be shared.
+pub fn set_irq_handler(irq: u8, handler: fn()) -> bool {
+    if !DEVICE_IRQS.contains(&irq) {
+        return false;
+    }
+    let slot = &IRQ_HANDLERS[usize::from(irq)];
+    if slot
+        .compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Relaxed)
+        .is_err()
+    {
+        return false;
+    }
+
+    unmask_irq(irq);
+    true
+}
+
+fn device_interrupt(irq: u8) {
+    let _in_interrupt = InInterrupt::enter();
+    IRQ_COUNTS.get()[usize::from(irq)].fetch_add(1, Ordering::Relaxed);
+    if crate::debugflags::TRACE_IRQ.get() {
+        crate::info!(target: "irq", "irq {} (device)", irq);
+    }
+
+    if is_spurious(irq) {
+        crate::log_rate_limited!(
+            SPURIOUS_WARN_INTERVAL_MS,
+            target: "irq",
+            crate::logger::Level::Warn,
+            "spurious irq {}",
+            irq
+        );
+        // The PIC that made it up wants no end of interrupt, but for IRQ 15
+        // the master saw a real one, from the slave.
+        if irq == 15 {
+            unsafe { PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + 2) };
         }
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        return;
+    }
+
+    let handler = IRQ_HANDLERS[usize::from(irq)].load(Ordering::Acquire);
+    if handler != 0 {
+        // Only ever stored from a `fn()` by `set_irq_handler`
+        let handler: fn() = unsafe { core::mem::transmute(handler) };
+        handler();
+    } else {
+        crate::log_rate_limited!(
+            SPURIOUS_WARN_INTERVAL_MS,
+            target: "irq",
+            crate::logger::Level::Warn,
+            "irq {} has no handler",
+            irq
+        );
+    }
 
-        idt.page_fault.set_handler_fn(page_fault_handler);
+    unsafe { PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq) };
+}
+
+/// How often interrupts nobody asked for are warned about, at most.
+const SPURIOUS_WARN_INTERVAL_MS: u64 = 10_000;
+
+/// Whether `irq` was made up by a PIC. When a line goes quiet before the CPU
+/// asks which one it was, the PIC answers with its lowest-priority line, 7,
+/// without marking it in service.
+fn is_spurious(irq: u8) -> bool {
+    let ports = match irq {
+        7 => &PIC_1_PORTS,
+        15 => &PIC_2_PORTS,
+        _ => return false,
+    };
+    // OCW3: read the in-service register next
+    const READ_ISR: u8 = 0x0B;
+    let _pics = PICS.lock();
+    let in_service: u8 = unsafe {
+        ports.write(0, READ_ISR);
+        ports.read(0)
+    };
+    in_service & (1 << 7) == 0
+}
 
-        idt
+// One handler per vector, since a handler can't tell which vector it was
+// called for. Each calls `$dispatch` with its number.
+macro_rules! handlers {
+    ($dispatch:ident: $($number:literal)*) => {
+        [$({
+            extern "x86-interrupt" fn handler(_stack_frame: InterruptStackFrame) {
+                $dispatch($number);
+            }
+            handler as extern "x86-interrupt" fn(InterruptStackFrame)
+        },)*]
     };
 }
 
+type HandlerFn = extern "x86-interrupt" fn(InterruptStackFrame);
+
+static DYNAMIC_HANDLERS: [HandlerFn; DYNAMIC_VECTORS] =
+    handlers!(dynamic_interrupt: 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15);
+
+static DEVICE_IRQ_HANDLERS: [HandlerFn; 11] =
+    handlers!(device_interrupt: 5 6 7 8 9 10 11 12 13 14 15);
+
+static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
+    let mut idt = InterruptDescriptorTable::new();
+
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+    idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
+    idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+    idt[InterruptIndex::Serial.as_usize()].set_handler_fn(serial_interrupt_handler);
+    idt[InterruptIndex::SerialSecondary.as_usize()]
+        .set_handler_fn(serial_secondary_interrupt_handler);
+
+    for (i, &handler) in DYNAMIC_HANDLERS.iter().enumerate() {
+        idt[usize::from(FIRST_DYNAMIC_VECTOR) + i].set_handler_fn(handler);
+    }
+    for (irq, &handler) in DEVICE_IRQS.zip(DEVICE_IRQ_HANDLERS.iter()) {
+        idt[usize::from(PIC_1_OFFSET + irq)].set_handler_fn(handler);
+    }
+    idt[usize::from(apic::TIMER_VECTOR)].set_handler_fn(apic_timer_interrupt_handler);
+    idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
+
+    idt.page_fault.set_handler_fn(page_fault_handler);
+
+    idt
+});
+
 pub fn init_idt() {
     IDT.load();
 }
 
+/// Clears the mask bit of the given legacy IRQ line (0-15) in the PICs.
+///
+/// The PIC init sequence restores whatever masks the BIOS left behind, which
+/// only reliably includes the timer and the keyboard.
+pub fn unmask_irq(irq: u8) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let [mut master, mut slave] = unsafe { pics.read_masks() };
+
+        if irq < 8 {
+            master &= !(1 << irq);
+        } else {
+            slave &= !(1 << (irq - 8));
+            master &= !(1 << 2); // The slave PIC is cascaded through IRQ2
+        }
+
+        unsafe { pics.write_masks(master, slave) };
+    });
+}
+
+/// Sets the mask bit of the given legacy IRQ line (0-15) in the PICs.
+pub fn mask_irq(irq: u8) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let [mut master, mut slave] = unsafe { pics.read_masks() };
+
+        if irq < 8 {
+            master |= 1 << irq;
+        } else {
+            slave |= 1 << (irq - 8);
+        }
+
+        unsafe { pics.write_masks(master, slave) };
+    });
+}
+
+/// Returns the PICs' mask register: bit `irq` is set if the line is masked.
+pub fn irq_masks() -> u16 {
+    use x86_64::instructions::interrupts;
+
+    let [master, slave] = interrupts::without_interrupts(|| unsafe { PICS.lock().read_masks() });
+    u16::from(slave) << 8 | u16::from(master)
+}
+
+/// What the legacy IRQ lines with a handler of their own are used for.
+fn fixed_irq_name(irq: u8) -> Option<&'static str> {
+    match irq {
+        0 => Some("timer"),
+        1 => Some("keyboard"),
+        2 => Some("cascade"),
+        3 => Some("serial (COM2, COM4)"),
+        4 => Some("serial (COM1, COM3)"),
+        _ => None,
+    }
+}
+
+/// Writes the name of a handler from `set_irq_handler` or `allocate_vector`,
+/// or its address if there is no symbol table.
+fn write_handler(out: &mut dyn Write, handler: usize) -> fmt::Result {
+    match symbols::resolve(handler as u64) {
+        Some(symbol) => writeln!(out, "{}", symbol.name),
+        None => writeln!(out, "{:#x}", handler),
+    }
+}
+
+pub fn register_commands() {
+    shell::register(
+        "irqstat",
+        "print the interrupt counts, handlers and masks",
+        irqstat,
+    );
+}
+
+fn irqstat(_args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    let masks = irq_masks();
+    writeln!(out, "irq  vector  masked        count  handler")?;
+    for irq in 0..16u8 {
+        let masked = if masks & 1 << irq != 0 { "yes" } else { "no" };
+        write!(
+            out,
+            "{:>3}  {:>6}  {:<6}  {:>11}  ",
+            irq,
+            PIC_1_OFFSET + irq,
+            masked,
+            irq_count(irq)
+        )?;
+        match (
+            fixed_irq_name(irq),
+            IRQ_HANDLERS[usize::from(irq)].load(Ordering::Acquire),
+        ) {
+            (Some(name), _) => writeln!(out, "{}", name)?,
+            (None, 0) => writeln!(out, "-")?,
+            (None, handler) => write_handler(out, handler)?,
+        }
+    }
+
+    writeln!(out, "\nvector        count  handler (MSI)")?;
+    for (index, slot) in VECTOR_HANDLERS.iter().enumerate() {
+        let vector = FIRST_DYNAMIC_VECTOR + index as u8;
+        let count = vector_count(vector);
+        let handler = slot.load(Ordering::Acquire);
+        // Freed vectors keep their counts
+        if handler == 0 && count == 0 {
+            continue;
+        }
+        write!(out, "{:>6}  {:>11}  ", vector, count)?;
+        match handler {
+            0 => writeln!(out, "-")?,
+            handler => write_handler(out, handler)?,
+        }
+    }
+    Ok(())
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
@@ -62,24 +418,67 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    // The panic handler would only see the double fault stack, so walk the
+    // stack of the code that faulted while we are still in the handler.
+    let backtrace = unwind::backtrace_from(
+        stack_frame.instruction_pointer.as_u64(),
+        unwind::interrupted_frame_pointer(),
+    );
+
+    // The panic below won't write another dump, so write it while we still
+    // have the faulting code's registers.
+    crate::crashdump::write(&"EXCEPTION: DOUBLE FAULT", Some(&stack_frame), &backtrace);
+
+    // Someone may be there to look around, unless this is a test
+    if !crate::running_tests() && !crate::cmdline::flag("test") {
+        crate::emergency::enter(&stack_frame);
+    }
+    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}\n{}", stack_frame, backtrace);
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
-    unsafe {
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    timer_interrupt(&stack_frame, || unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+    });
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
+/// The timer interrupt, when the local APIC's timer raises it rather than
+/// IRQ0 (see `time::TickSource`).
+extern "x86-interrupt" fn apic_timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    crate::time::arm_next_deadline();
+    timer_interrupt(&stack_frame, apic::end_of_interrupt);
+}
+
+fn timer_interrupt(stack_frame: &InterruptStackFrame, end_of_interrupt: impl FnOnce()) {
+    let in_interrupt = InInterrupt::enter();
+    count_irq(InterruptIndex::Timer);
+    crate::time::tick();
+    crate::watchdog::check();
+    crate::check_test_timeout();
 
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
+    end_of_interrupt();
+
+    // This may switch to another process and only come back much later, so
+    // the end of interrupt has to be sent first, and the process we switch to
+    // isn't in our handler.
+    drop(in_interrupt);
+    crate::process::timer_tick(stack_frame);
+}
 
+extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let rbp = crate::unwind::interrupted_frame_pointer();
+    let _in_interrupt = InInterrupt::enter();
+    count_irq(InterruptIndex::Keyboard);
+
+    let scancode: u8 = unsafe { crate::drivers::ps2::DATA.read(0) };
+    crate::trace_event!("irq", "keyboard scancode {:#04x}", scancode);
+
+    if crate::kdb::magic_key(scancode) {
+        crate::kdb::enter(&stack_frame, rbp);
+    }
     crate::task::keyboard::add_scancode(scancode);
+    crate::rand::add_interrupt_timing();
 
     unsafe {
         PICS.lock()
@@ -87,18 +486,73 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     }
 }
 
+extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _in_interrupt = InInterrupt::enter();
+    count_irq(InterruptIndex::Serial);
+    crate::trace_event!("irq", "serial irq4");
+    crate::serial::handle_interrupt();
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Serial.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn serial_secondary_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _in_interrupt = InInterrupt::enter();
+    count_irq(InterruptIndex::SerialSecondary);
+    crate::trace_event!("irq", "serial irq3");
+    crate::serial::handle_interrupt();
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::SerialSecondary.as_u8());
+    }
+}
+
+// The local APIC raises these when an interrupt went away before it could be
+// delivered. They must not be acknowledged.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::log_rate_limited!(
+        SPURIOUS_WARN_INTERVAL_MS,
+        target: "irq",
+        crate::logger::Level::Warn,
+        "spurious interrupt from the local APIC"
+    );
+}
+
 // The CR2 register is automatically set by the CPU on a page fault and
 // contains the accessed virtual address that caused the page fault
 extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
+    mut stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
     use x86_64::registers::control::Cr2;
 
+    // A copy to or from user memory that faults fails, rather than taking the
+    // kernel down
+    let user_address = crate::memory::address_space::is_user_range(Cr2::read(), 1);
+    if user_address && !error_code.contains(PageFaultErrorCode::USER_MODE) {
+        if let Some(fixup) = usercopy::fixup(stack_frame.instruction_pointer) {
+            unsafe {
+                stack_frame
+                    .as_mut()
+                    .update(|frame| frame.instruction_pointer = fixup)
+            };
+            return;
+        }
+    }
+
+    let backtrace = unwind::backtrace_from(
+        stack_frame.instruction_pointer.as_u64(),
+        unwind::interrupted_frame_pointer(),
+    );
+
     println!("EXCEPTION: PAGE FAULT");
     println!("Accessed Address: {:?}", Cr2::read());
     println!("Error Code: {:?}", error_code);
     println!("{:#?}", stack_frame);
+    println!("{}", backtrace);
 
     hlt_loop();
 }
@@ -0,0 +1,172 @@
+// I/O ports, in ranges that belong to someone. Every piece of code that uses
+// ports describes them with a `PortRange` static, and does all its accesses
+// through it. The first access claims the range in a registry, which panics if
+// it overlaps a range that someone else claimed, rather than have two drivers
+// quietly poke the same ports. `ioports` lists the claims, like Linux's
+// /proc/ioports.
+//
+// Claiming happens on the first access rather than up front, since some ports
+// are used long before anything else is set up (the serial port, for one), and
+// some may never be used at all. The registry is a fixed-size table, so that
+// claiming works without a heap and from interrupt handlers.
+//
+// Ports that a crate accesses by itself, like the PIC's, can't go through a
+// range, but `claim` still keeps others off them.
+
+use crate::shell;
+#[cfg(test)]
+use crate::HostTest;
+use core::fmt;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::{PortRead, PortWrite};
+
+const MAX_RANGES: usize = 32;
+
+pub struct PortRange {
+    owner: &'static str,
+    base: u16,
+    len: u16,
+    claimed: AtomicBool,
+}
+
+impl PortRange {
+    /// The `len` ports from `base`, for `owner`. Nothing is claimed until the
+    /// first access.
+    pub const fn new(owner: &'static str, base: u16, len: u16) -> PortRange {
+        PortRange {
+            owner,
+            base,
+            len,
+            claimed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    fn end(&self) -> u32 {
+        u32::from(self.base) + u32::from(self.len)
+    }
+
+    fn overlaps(&self, other: &PortRange) -> bool {
+        u32::from(self.base) < other.end() && u32::from(other.base) < self.end()
+    }
+
+    /// Claims the ports, unless that happened already.
+    ///
+    /// Panics if they overlap a range that's claimed.
+    pub fn claim(&'static self) {
+        if self.claimed.load(Ordering::Acquire) {
+            return;
+        }
+        let result = interrupts::without_interrupts(|| REGISTRY.lock().claim(self));
+        if let Err(other) = result {
+            panic!(
+                "I/O ports {} of {} overlap {} of {}",
+                self, self.owner, other, other.owner
+            );
+        }
+    }
+
+    /// Checks that a `T` at `offset` is within the range, and claims it.
+    fn port<T>(&'static self, offset: u16) -> u16 {
+        assert!(
+            usize::from(offset) + mem::size_of::<T>() <= usize::from(self.len),
+            "offset {:#x} is outside of I/O ports {} of {}",
+            offset,
+            self,
+            self.owner
+        );
+        self.claim();
+        self.base + offset
+    }
+
+    /// Reads the port at `offset` into the range.
+    ///
+    /// # Safety
+    ///
+    /// Reading a port can have side effects, which the caller has to expect.
+    pub unsafe fn read<T: PortRead>(&'static self, offset: u16) -> T {
+        T::read_from_port(self.port::<T>(offset))
+    }
+
+    /// Writes to the port at `offset` into the range.
+    ///
+    /// # Safety
+    ///
+    /// Writing a port can do anything at all to the machine, so the caller
+    /// has to know what the value does.
+    pub unsafe fn write<T: PortWrite>(&'static self, offset: u16, value: T) {
+        T::write_to_port(self.port::<T>(offset), value)
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#06x}-{:#06x}", self.base, self.end() - 1)
+    }
+}
+
+struct Registry {
+    ranges: [Option<&'static PortRange>; MAX_RANGES],
+}
+
+impl Registry {
+    const fn new() -> Registry {
+        Registry {
+            ranges: [None; MAX_RANGES],
+        }
+    }
+
+    /// Adds `range`, or returns the range it overlaps.
+    fn claim(&mut self, range: &'static PortRange) -> Result<(), &'static PortRange> {
+        // Another CPU may have claimed the same range since we looked
+        if self.ranges.iter().flatten().any(|&r| ptr::eq(r, range)) {
+            return Ok(());
+        }
+        if let Some(&other) = self.ranges.iter().flatten().find(|r| r.overlaps(range)) {
+            return Err(other);
+        }
+
+        let slot = self
+            .ranges
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .expect("I/O port registry full");
+        *slot = Some(range);
+        range.claimed.store(true, Ordering::Release);
+        Ok(())
+    }
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry::new());
+
+pub fn register_commands() {
+    shell::register("ioports", "list the claimed I/O ports", |_args, out| {
+        let mut ranges = interrupts::without_interrupts(|| REGISTRY.lock().ranges);
+        ranges.sort_by_key(|range| range.map(|range| range.base));
+        for range in ranges.iter().flatten() {
+            writeln!(out, "{}  {}", range, range.owner)?;
+        }
+        Ok(())
+    });
+}
+
+#[test_case]
+static REJECTS_OVERLAPS: HostTest = HostTest::new("io::rejects_overlaps", || {
+    static SERIAL: PortRange = PortRange::new("serial", 0x3F8, 8);
+    static OVERLAPPING: PortRange = PortRange::new("other", 0x3FF, 2);
+    static NEXT: PortRange = PortRange::new("next", 0x400, 1);
+
+    let mut registry = Registry::new();
+    assert!(registry.claim(&SERIAL).is_ok());
+    assert!(registry.claim(&SERIAL).is_ok());
+    assert!(ptr::eq(registry.claim(&OVERLAPPING).unwrap_err(), &SERIAL));
+    assert!(registry.claim(&NEXT).is_ok());
+    assert_eq!(alloc::format!("{}", SERIAL), "0x03f8-0x03ff");
+});
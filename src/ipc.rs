@@ -0,0 +1,202 @@
+// Message passing between processes, and between processes and kernel tasks.
+// A port is a queue of small, fixed-size messages kept by the kernel. Anyone who
+// knows a port's ID can send to it, but only the process that created it can
+// receive from it. Sending never blocks: a full queue is an error, and the
+// sender may try again later. Receiving blocks until a message arrives, either
+// by blocking the process (`recv`) or by suspending the task (`recv_async`).
+//
+// Ports are destroyed when the process that owns them exits. Kernel tasks all
+// run as the kernel's process, so their ports live forever.
+
+use crate::process::{self, Pid};
+use alloc::collections::{BTreeMap, VecDeque};
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// The maximum length of a message.
+pub const MESSAGE_SIZE: usize = 64;
+
+/// How many messages a port holds before `send` fails.
+const QUEUE_CAPACITY: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PortId(u64);
+
+impl PortId {
+    pub fn from_u64(id: u64) -> PortId {
+        PortId(id)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for PortId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpcError {
+    NoSuchPort,
+    /// Only the process that created a port can receive from it.
+    NotOwner,
+    /// The message is longer than `MESSAGE_SIZE`.
+    TooLong,
+    QueueFull,
+}
+
+#[derive(Clone, Copy)]
+pub struct Message {
+    sender: Pid,
+    len: usize,
+    data: [u8; MESSAGE_SIZE],
+}
+
+impl Message {
+    pub fn sender(&self) -> Pid {
+        self.sender
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+struct Port {
+    owner: Pid,
+    queue: VecDeque<Message>,
+    // The task waiting in `recv_async`, if any. Blocked processes are woken
+    // through the scheduler instead.
+    waker: Option<Waker>,
+}
+
+struct Ports {
+    ports: BTreeMap<PortId, Port>,
+    next_id: u64,
+}
+
+static PORTS: Mutex<Ports> = Mutex::new(Ports {
+    ports: BTreeMap::new(),
+    next_id: 1,
+});
+
+/// Creates a port owned by the current process.
+pub fn create_port() -> PortId {
+    let owner = process::current();
+
+    interrupts::without_interrupts(|| {
+        let mut ports = PORTS.lock();
+        let id = PortId(ports.next_id);
+        ports.next_id += 1;
+
+        ports.ports.insert(
+            id,
+            Port {
+                owner,
+                queue: VecDeque::with_capacity(QUEUE_CAPACITY),
+                waker: None,
+            },
+        );
+
+        id
+    })
+}
+
+/// Queues a message on the given port.
+pub fn send(port: PortId, data: &[u8]) -> Result<(), IpcError> {
+    if data.len() > MESSAGE_SIZE {
+        return Err(IpcError::TooLong);
+    }
+
+    let mut message = Message {
+        sender: process::current(),
+        len: data.len(),
+        data: [0; MESSAGE_SIZE],
+    };
+    message.data[..data.len()].copy_from_slice(data);
+
+    interrupts::without_interrupts(|| {
+        let mut ports = PORTS.lock();
+        let port = ports.ports.get_mut(&port).ok_or(IpcError::NoSuchPort)?;
+        if port.queue.len() >= QUEUE_CAPACITY {
+            return Err(IpcError::QueueFull);
+        }
+
+        port.queue.push_back(message);
+
+        // Whoever is waiting for the message checks for it again.
+        if let Some(waker) = port.waker.take() {
+            waker.wake();
+        }
+        process::wake(port.owner);
+
+        Ok(())
+    })
+}
+
+/// Takes the next message from the given port, if there is one.
+pub fn try_recv(port: PortId) -> Result<Option<Message>, IpcError> {
+    let current = process::current();
+
+    interrupts::without_interrupts(|| {
+        let mut ports = PORTS.lock();
+        let port = ports.ports.get_mut(&port).ok_or(IpcError::NoSuchPort)?;
+        if port.owner != current {
+            return Err(IpcError::NotOwner);
+        }
+
+        Ok(port.queue.pop_front())
+    })
+}
+
+/// Takes the next message from the given port, blocking the current process
+/// until one arrives.
+pub fn recv(port: PortId) -> Result<Message, IpcError> {
+    // With interrupts disabled, nobody can send between our check and `block`.
+    interrupts::without_interrupts(|| loop {
+        if let Some(message) = try_recv(port)? {
+            return Ok(message);
+        }
+
+        process::block();
+    })
+}
+
+/// Takes the next message from the given port, for kernel tasks.
+pub fn recv_async(port: PortId) -> impl Future<Output = Result<Message, IpcError>> {
+    Recv { port }
+}
+
+struct Recv {
+    port: PortId,
+}
+
+impl Future for Recv {
+    type Output = Result<Message, IpcError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        interrupts::without_interrupts(|| match try_recv(self.port) {
+            Ok(Some(message)) => Poll::Ready(Ok(message)),
+            Ok(None) => {
+                // `try_recv` just checked that the port exists
+                if let Some(port) = PORTS.lock().ports.get_mut(&self.port) {
+                    port.waker = Some(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+            Err(error) => Poll::Ready(Err(error)),
+        })
+    }
+}
+
+/// Destroys the ports owned by the given process. Called when it exits.
+pub(crate) fn release(owner: Pid) {
+    interrupts::without_interrupts(|| PORTS.lock().ports.retain(|_, port| port.owner != owner));
+}
@@ -0,0 +1,238 @@
+// Assertions for invariants the kernel can survive breaking. `assert!` stops
+// everything over the smallest inconsistency; `kassert!` and `kassert_eq!`
+// name the subsystem the invariant belongs to, and when one fails they log
+// the expression, where it is, and the subsystem's most recent trace events,
+// since the subsystem is also its `trace_event!` category:
+//
+//     kassert!("executor", listed, "task {} wasn't listed", id);
+//     kassert_eq!("net", header.len(), 20);
+//
+// What happens next is decided when the kernel is built, by KERNEL_KASSERT
+// (see config.rs): `panic`, the default, panics like `assert!`, and
+// `continue` carries on, logging the same call site's failures at most once
+// per `LOG_INTERVAL_MS`. Either way, every call site counts its failures, and
+// `kasserts` lists the ones that failed. Invariants that the kernel isn't safe
+// to run without, like the allocator's, stay with `assert!`.
+//
+// Nothing in here allocates, so the macros are fine in interrupt handlers.
+
+use crate::logger::RateLimit;
+use crate::sync::SpinLock;
+use crate::{config, error, shell, time, trace};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// How many recent trace events of its subsystem a failure logs.
+const RECENT_EVENTS: usize = 8;
+/// How often one call site's failures are logged, at most, when they don't
+/// panic.
+const LOG_INTERVAL_MS: u64 = 1000;
+/// How many failed call sites `kasserts` can list.
+const MAX_SITES: usize = 32;
+
+/// What a failed `kassert!` does after logging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Panic,
+    Continue,
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Mode::Panic => "panic",
+            Mode::Continue => "continue",
+        })
+    }
+}
+
+/// One `kassert!` call site.
+#[doc(hidden)]
+pub struct Site {
+    subsystem: &'static str,
+    expr: &'static str,
+    file: &'static str,
+    line: u32,
+    failures: AtomicU64,
+    limit: RateLimit,
+}
+
+impl Site {
+    pub const fn new(
+        subsystem: &'static str,
+        expr: &'static str,
+        file: &'static str,
+        line: u32,
+    ) -> Site {
+        Site {
+            subsystem,
+            expr,
+            file,
+            line,
+            failures: AtomicU64::new(0),
+            limit: RateLimit::new(),
+        }
+    }
+}
+
+// The call sites that have failed, in the order they first did.
+static FAILED: SpinLock<[Option<&'static Site>; MAX_SITES]> = SpinLock::new([None; MAX_SITES]);
+
+#[doc(hidden)]
+pub fn _failed(site: &'static Site, message: Option<fmt::Arguments>) {
+    if site.failures.fetch_add(1, Ordering::Relaxed) == 0 {
+        // One that doesn't fit still counts, it just isn't listed.
+        if let Some(slot) = FAILED.lock().iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(site);
+        }
+    }
+
+    let suppressed = match config::KASSERT {
+        Mode::Panic => Some(0),
+        Mode::Continue => site
+            .limit
+            .check(time::ms_to_ticks(LOG_INTERVAL_MS), time::ticks()),
+    };
+    if let Some(suppressed) = suppressed {
+        log(site, message, suppressed);
+    }
+
+    if config::KASSERT == Mode::Panic {
+        match message {
+            Some(message) => panic!(
+                "kassert failed in {}: {}, {} ({}:{})",
+                site.subsystem, site.expr, message, site.file, site.line
+            ),
+            None => panic!(
+                "kassert failed in {}: {} ({}:{})",
+                site.subsystem, site.expr, site.file, site.line
+            ),
+        }
+    }
+}
+
+fn log(site: &Site, message: Option<fmt::Arguments>, suppressed: u64) {
+    struct Message<'a>(Option<fmt::Arguments<'a>>);
+
+    impl fmt::Display for Message<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self.0 {
+                Some(message) => write!(f, ", {}", message),
+                None => Ok(()),
+            }
+        }
+    }
+
+    error!(
+        target: site.subsystem,
+        "kassert failed: {}{} ({}:{})",
+        site.expr,
+        Message(message),
+        site.file,
+        site.line
+    );
+    if suppressed > 0 {
+        error!(
+            target: site.subsystem,
+            "  and {} more times since it was last logged", suppressed
+        );
+    }
+    trace::for_each_recent::<RECENT_EVENTS>(site.subsystem, |tsc, event| {
+        error!(target: site.subsystem, "  trace {} {}", tsc, event);
+    });
+}
+
+pub fn register_commands() {
+    shell::register(
+        "kasserts",
+        "list the kernel assertions that failed",
+        |_args, out| {
+            let failed = FAILED.lock();
+            if failed[0].is_none() {
+                return writeln!(out, "no kassert has failed ({} mode)", config::KASSERT);
+            }
+            writeln!(out, "FAILURES  SUBSYSTEM   WHERE")?;
+            for site in failed.iter().flatten() {
+                writeln!(
+                    out,
+                    "{:>8}  {:<10}  {}:{}\n          {}",
+                    site.failures.load(Ordering::Relaxed),
+                    site.subsystem,
+                    site.file,
+                    site.line,
+                    site.expr
+                )?;
+            }
+            Ok(())
+        },
+    );
+}
+
+/// Checks an invariant of `subsystem`, see the module's comment.
+///
+/// ```ignore
+/// kassert!("executor", listed, "task {} wasn't listed", id);
+/// ```
+#[macro_export]
+macro_rules! kassert {
+    ($subsystem:expr, $cond:expr $(,)?) => {
+        if !$cond {
+            static SITE: $crate::kassert::Site =
+                $crate::kassert::Site::new($subsystem, stringify!($cond), file!(), line!());
+            $crate::kassert::_failed(&SITE, None);
+        }
+    };
+    ($subsystem:expr, $cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            static SITE: $crate::kassert::Site =
+                $crate::kassert::Site::new($subsystem, stringify!($cond), file!(), line!());
+            $crate::kassert::_failed(&SITE, Some(format_args!($($arg)+)));
+        }
+    };
+}
+
+/// Checks that two values of `subsystem` are equal, see the module's comment.
+#[macro_export]
+macro_rules! kassert_eq {
+    ($subsystem:expr, $left:expr, $right:expr $(,)?) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if *left != *right {
+                    static SITE: $crate::kassert::Site = $crate::kassert::Site::new(
+                        $subsystem,
+                        concat!(stringify!($left), " == ", stringify!($right)),
+                        file!(),
+                        line!(),
+                    );
+                    $crate::kassert::_failed(
+                        &SITE,
+                        Some(format_args!("left: {:?}, right: {:?}", left, right)),
+                    );
+                }
+            }
+        }
+    };
+    ($subsystem:expr, $left:expr, $right:expr, $($arg:tt)+) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if *left != *right {
+                    static SITE: $crate::kassert::Site = $crate::kassert::Site::new(
+                        $subsystem,
+                        concat!(stringify!($left), " == ", stringify!($right)),
+                        file!(),
+                        line!(),
+                    );
+                    $crate::kassert::_failed(
+                        &SITE,
+                        Some(format_args!(
+                            "left: {:?}, right: {:?}: {}",
+                            left,
+                            right,
+                            format_args!($($arg)+)
+                        )),
+                    );
+                }
+            }
+        }
+    };
+}
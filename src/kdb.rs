@@ -0,0 +1,233 @@
+// A debug monitor on the keyboard and the screen, for when the shell or the
+// console is what's broken, or there's no serial line to look at, like on real
+// hardware. Pressing Scroll Lock twice within `MAGIC_KEY_MS` brings it up:
+//
+//     tasks           the executor's tasks, and which one was running
+//     regs            where the interrupted code was
+//     bt              its backtrace
+//     stack [len]     its stack, like `hexdump`
+//     x <addr> [len]  memory, like `hexdump`
+//     reboot
+//     c               carry on
+//
+// It runs inside the keyboard interrupt handler, with interrupts off, so
+// everything else is frozen until it's left: no timer ticks (the time spent
+// in it is lost to `time::ticks`), and the executor stays in the middle of
+// whatever it was doing. It polls the keyboard controller for input and
+// prints with `print!`, which only ever goes to the screen. Like the panic
+// path, it doesn't allocate, and the locks it takes are `SpinLock`s, which no
+// interrupted code can be holding. The flip side is that code spinning with
+// interrupts off can't be interrupted into the monitor either.
+
+use crate::drivers::ps2;
+use crate::task::executor;
+#[cfg(test)]
+use crate::HostTest;
+use crate::{power, print, println, time, unwind, util};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use spin::Mutex;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+/// How soon the second Scroll Lock has to follow the first.
+const MAGIC_KEY_MS: u64 = 500;
+
+const SCANCODE_SCROLL_LOCK: u8 = 0x46;
+/// Comes before the scancodes of keys that the original keyboard didn't have.
+/// Ctrl+Break is 0x46 after it.
+const SCANCODE_EXTENDED: u8 = 0xE0;
+
+const MAX_LINE: usize = 64;
+/// How much `x` and `stack` dump if not told.
+const DEFAULT_DUMP_LEN: u64 = 128;
+/// The most they dump: more scrolls off the screen.
+const MAX_DUMP_LEN: u64 = 256;
+
+/// Watches the scancodes for the magic key.
+struct MagicKey {
+    /// The tick of the last Scroll Lock.
+    last_press: Option<u64>,
+    extended: bool,
+}
+
+impl MagicKey {
+    const fn new() -> MagicKey {
+        MagicKey {
+            last_press: None,
+            extended: false,
+        }
+    }
+
+    /// Whether `scancode`, which came at `tick`, completes the magic key.
+    fn feed(&mut self, scancode: u8, tick: u64) -> bool {
+        let extended = core::mem::replace(&mut self.extended, scancode == SCANCODE_EXTENDED);
+        if scancode != SCANCODE_SCROLL_LOCK || extended {
+            return false;
+        }
+        match self.last_press.replace(tick) {
+            Some(last) if tick - last <= time::ms_to_ticks(MAGIC_KEY_MS) => {
+                self.last_press = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+// Only the keyboard interrupt handler takes it.
+static MAGIC_KEY: Mutex<MagicKey> = Mutex::new(MagicKey::new());
+
+/// Called by the keyboard interrupt handler with every scancode. Returns
+/// whether it was the magic key.
+pub(crate) fn magic_key(scancode: u8) -> bool {
+    MAGIC_KEY.lock().feed(scancode, time::ticks())
+}
+
+/// Where the code that was interrupted into the monitor was.
+struct Interrupted<'a> {
+    stack_frame: &'a InterruptStackFrame,
+    /// Its frame pointer.
+    rbp: u64,
+}
+
+/// Runs the monitor until it's told to carry on. `rbp` is the interrupted
+/// code's frame pointer, see `unwind::interrupted_frame_pointer`.
+pub(crate) fn enter(stack_frame: &InterruptStackFrame, rbp: u64) {
+    let interrupted = Interrupted { stack_frame, rbp };
+    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
+    let mut line = [0u8; MAX_LINE];
+
+    println!("\nkdb: everything is stopped, `help` for commands");
+    loop {
+        print!("kdb> ");
+        let line = read_line(&mut keyboard, &mut line);
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("c") | Some("continue") => break,
+            Some(command) => run(command, &mut words, &interrupted),
+            None => {}
+        }
+    }
+    println!("kdb: carrying on");
+}
+
+fn run<'a>(command: &str, args: &mut impl Iterator<Item = &'a str>, interrupted: &Interrupted) {
+    let stack_frame = interrupted.stack_frame;
+    match command {
+        "help" => println!("tasks, regs, bt, stack [len], x <addr> [len], reboot, c"),
+        "tasks" => {
+            let running = executor::current_task().map(|(id, _)| id);
+            println!("  ID  NAME");
+            executor::for_each_task(|task| {
+                let mark = if Some(task.id) == running { '*' } else { ' ' };
+                println!("{:>4}{} {}", task.id, mark, task.name);
+            });
+        }
+        "regs" => println!(
+            "rip {:#018x} rsp {:#018x} rbp {:#018x}\nrflags {:#x} cs {:#x}",
+            stack_frame.instruction_pointer.as_u64(),
+            stack_frame.stack_pointer.as_u64(),
+            interrupted.rbp,
+            stack_frame.cpu_flags,
+            stack_frame.code_segment
+        ),
+        "bt" => print!(
+            "{}",
+            unwind::backtrace_from(stack_frame.instruction_pointer.as_u64(), interrupted.rbp)
+        ),
+        "stack" => match dump_len(args.next()) {
+            Some(len) => print!("{}", util::hexdump(stack_frame.stack_pointer, len)),
+            None => println!("usage: stack [len]"),
+        },
+        "x" => {
+            let addr = args
+                .next()
+                .and_then(util::parse_number)
+                .and_then(|addr| VirtAddr::try_new(addr).ok());
+            match (addr, dump_len(args.next())) {
+                (Some(addr), Some(len)) => print!("{}", util::hexdump(addr, len)),
+                _ => println!("usage: x <addr> [len]"),
+            }
+        }
+        "reboot" => power::reset(),
+        _ => println!("{}: no such command", command),
+    }
+}
+
+fn dump_len(arg: Option<&str>) -> Option<usize> {
+    let len = match arg {
+        Some(arg) => util::parse_number(arg)?,
+        None => DEFAULT_DUMP_LEN,
+    };
+    Some(len.min(MAX_DUMP_LEN) as usize)
+}
+
+/// Reads a line from the keyboard, echoing it.
+fn read_line<'a>(
+    keyboard: &mut Keyboard<layouts::Us104Key, ScancodeSet1>,
+    buf: &'a mut [u8; MAX_LINE],
+) -> &'a str {
+    let mut len = 0;
+    loop {
+        match read_char(keyboard) {
+            '\n' => break,
+            '\u{8}' if len > 0 => {
+                len -= 1;
+                print!("\u{8}");
+            }
+            c if (c.is_ascii_graphic() || c == ' ') && len < MAX_LINE => {
+                buf[len] = c as u8;
+                len += 1;
+                print!("{}", c);
+            }
+            _ => {}
+        }
+    }
+    println!();
+    // Only ASCII went in
+    core::str::from_utf8(&buf[..len]).unwrap_or("")
+}
+
+/// Waits for a key that types a character.
+fn read_char(keyboard: &mut Keyboard<layouts::Us104Key, ScancodeSet1>) -> char {
+    loop {
+        let status: u8 = unsafe { ps2::COMMAND.read(0) };
+        if status & ps2::STATUS_OUTPUT_FULL == 0 {
+            core::hint::spin_loop();
+            continue;
+        }
+        let scancode: u8 = unsafe { ps2::DATA.read(0) };
+        if status & ps2::STATUS_AUX_DATA != 0 {
+            continue;
+        }
+        if let Ok(Some(event)) = keyboard.add_byte(scancode) {
+            if let Some(DecodedKey::Unicode(c)) = keyboard.process_keyevent(event) {
+                return c;
+            }
+        }
+    }
+}
+
+#[test_case]
+static DETECTS_THE_MAGIC_KEY: HostTest = HostTest::new("kdb::detects_the_magic_key", || {
+    let soon = time::ms_to_ticks(MAGIC_KEY_MS);
+    let mut magic = MagicKey::new();
+    assert!(!magic.feed(SCANCODE_SCROLL_LOCK, 100));
+    assert!(!magic.feed(SCANCODE_SCROLL_LOCK | 0x80, 100));
+    assert!(magic.feed(SCANCODE_SCROLL_LOCK, 100 + soon));
+    // A third press starts over
+    assert!(!magic.feed(SCANCODE_SCROLL_LOCK, 101 + soon));
+
+    // Too slow
+    let mut magic = MagicKey::new();
+    assert!(!magic.feed(SCANCODE_SCROLL_LOCK, 0));
+    assert!(!magic.feed(SCANCODE_SCROLL_LOCK, soon + 1));
+    assert!(magic.feed(SCANCODE_SCROLL_LOCK, soon + 2));
+
+    // Ctrl+Break, twice
+    let mut magic = MagicKey::new();
+    for _ in 0..2 {
+        assert!(!magic.feed(SCANCODE_EXTENDED, 0));
+        assert!(!magic.feed(SCANCODE_SCROLL_LOCK, 0));
+    }
+});
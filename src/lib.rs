@@ -1,35 +1,94 @@
 // Like the main.rs, the lib.rs is a special file that is automatically recognized by cargo.
 // The library is a separate compilation unit, so we need to specify the #![no_std] attribute again.
-#![no_std]
-#![cfg_attr(test, no_main)]
+#![cfg_attr(not(all(test, feature = "std-tests")), no_std)]
+#![cfg_attr(all(test, not(feature = "std-tests")), no_main)]
 #![feature(custom_test_frameworks)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)]
+#![feature(allocator_api)]
 #![feature(const_mut_refs)]
 
 extern crate alloc;
 
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod bench;
+pub mod bf;
+pub mod block;
+pub mod cmdline;
+pub mod config;
+pub mod cpu;
+pub mod crashdump;
+pub mod debugflags;
+pub mod demo;
+pub mod device;
+pub mod drivers;
+pub mod elf;
+pub mod emergency;
+pub mod error;
+pub mod file;
+#[cfg(feature = "uefi")]
+pub mod framebuffer;
+pub mod fs;
 pub mod gdt;
+pub mod golden;
+pub mod hpet;
+pub mod hypervisor;
+pub mod idle;
+pub mod initrd;
 pub mod interrupts;
+pub mod io;
+pub mod ipc;
+pub mod kassert;
+pub mod kdb;
+pub mod logger;
 pub mod memory;
+pub mod mmio;
+pub mod net;
+pub mod panic;
+pub mod pci;
+pub mod percpu;
+pub mod power;
+pub mod process;
+pub mod programs;
+pub mod pstore;
+pub mod rand;
+pub mod rtc;
 pub mod serial;
+pub mod shell;
+pub mod shm;
+pub mod symbols;
+pub mod sync;
+pub mod syscall;
 pub mod task;
+pub mod time;
+pub mod trace;
+pub mod tty;
+pub mod unwind;
+pub mod usercopy;
+pub mod util;
 pub mod vga_buffer;
+pub mod watchdog;
 
+use core::fmt::{self, Write};
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use sync::SpinLock;
+use x86_64::structures::paging::OffsetPageTable;
+use x86_64::VirtAddr;
 
-#[cfg(test)]
+#[cfg(all(test, not(feature = "std-tests")))]
 use bootloader::{entry_point, BootInfo};
 
-#[cfg(test)]
+#[cfg(all(test, not(feature = "std-tests")))]
 entry_point!(test_kernel_main);
 
 // Remember, this _start function is used when running cargo test --lib,
 // since Rust tests the lib.rs completely independently of the main.rs
 /// Entry point for `cargo test`
-#[cfg(test)]
+#[cfg(all(test, not(feature = "std-tests")))]
 fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
     init();
     test_main();
@@ -37,9 +96,19 @@ fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
 }
 
 pub fn init() {
+    logger::init();
     interrupts::init_idt();
+    time::boot_phase("idt");
     gdt::init();
+    time::boot_phase("gdt");
+    interrupts::PIC_1_PORTS.claim();
+    interrupts::PIC_2_PORTS.claim();
     unsafe { interrupts::PICS.lock().initialize() };
+    time::boot_phase("pic");
+    time::init();
+    time::boot_phase("timer");
+    serial::init();
+    time::boot_phase("serial");
     x86_64::instructions::interrupts::enable();
 }
 
@@ -53,6 +122,11 @@ pub fn hlt_loop() -> ! {
 
 pub trait Testable {
     fn run(&self);
+
+    /// Whether the test can run on the host too, see `HostTest`.
+    fn runs_on_host(&self) -> bool {
+        false
+    }
 }
 
 impl<T> Testable for T
@@ -60,10 +134,140 @@ where
     T: Fn(),
 {
     fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>());
-        self();
-        serial_println!("[ok]");
+        run_test(core::any::type_name::<T>(), self);
+    }
+}
+
+// Tests of pure logic, which don't touch the hardware, can run on the host as
+// well, with plain `cargo test` instead of booting QEMU:
+//
+//     cargo test --lib --features std-tests --target x86_64-unknown-linux-gnu \
+//         -Z build-std=std,panic_unwind
+//
+// (`cargo host-test` for short.) With the `std-tests` feature, the library's
+// unit tests are built with std, and the test runner only runs the tests that
+// are declared as `HostTest`s:
+//
+//     #[test_case]
+//     static ALIGN_UP: HostTest = HostTest::new("allocator::align_up", || {
+//         assert_eq!(align_up(5, 4), 8);
+//     });
+//
+// Everything else is still compiled, but nothing that needs the kernel's
+// privileges, like a `SpinLock` (which disables interrupts), may run. Host
+// tests run in QEMU with the rest, too.
+
+pub struct HostTest {
+    name: &'static str,
+    test: fn(),
+}
+
+impl HostTest {
+    pub const fn new(name: &'static str, test: fn()) -> HostTest {
+        HostTest { name, test }
+    }
+}
+
+impl Testable for HostTest {
+    fn run(&self) {
+        run_test(self.name, self.test);
+    }
+
+    fn runs_on_host(&self) -> bool {
+        true
+    }
+}
+
+/// Runs one test under the test runner's deadline, and reports it passed
+/// unless it panicked.
+#[cfg(not(all(test, feature = "std-tests")))]
+pub(crate) fn run_test(name: &'static str, test: impl FnOnce()) {
+    serial_print!("{}...\t", name);
+    arm_test_timeout(name);
+    test();
+    disarm_test_timeout();
+    serial_println!("[ok]");
+    TESTS_PASSED.fetch_add(1, Ordering::Relaxed);
+}
+
+#[cfg(all(test, feature = "std-tests"))]
+pub(crate) fn run_test(name: &'static str, test: impl FnOnce()) {
+    std::print!("{}...\t", name);
+    test();
+    std::println!("[ok]");
+}
+
+static TESTS_TOTAL: AtomicUsize = AtomicUsize::new(0);
+static TESTS_PASSED: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the kernel is running tests, for crash paths that would otherwise
+/// wait for a person.
+pub fn running_tests() -> bool {
+    TESTS_TOTAL.load(Ordering::Relaxed) != 0
+}
+
+/// Prints how many tests passed and failed, and how many didn't get to run
+/// because a failure ended the binary.
+fn print_test_summary(failed: usize) {
+    let total = TESTS_TOTAL.load(Ordering::Relaxed);
+    let passed = TESTS_PASSED.load(Ordering::Relaxed);
+    if total == 0 {
+        return;
+    }
+    serial_println!(
+        "{} passed, {} failed, {} not run",
+        passed,
+        failed,
+        total.saturating_sub(passed + failed)
+    );
+}
+
+// A test that hangs would otherwise hang the whole run, until whatever runs the
+// tests gives up on QEMU. So each test gets a deadline, which the timer
+// interrupt checks: once it has passed, the test fails with `[timeout]`. That
+// can't catch a test that hangs with interrupts disabled, or one that runs
+// before `init` starts the timer.
+
+/// How long a test may take, unless the command line says otherwise with
+/// `test_timeout=<seconds>`.
+pub const TEST_TIMEOUT_SECS: u64 = 60;
+
+/// The tick by which the running test has to finish, or 0 if there's none.
+static TEST_DEADLINE: AtomicU64 = AtomicU64::new(0);
+static CURRENT_TEST: SpinLock<Option<&'static str>> = SpinLock::new(None);
+
+fn test_timeout_secs() -> u64 {
+    cmdline::parse("test_timeout").unwrap_or(TEST_TIMEOUT_SECS)
+}
+
+fn arm_test_timeout(name: &'static str) {
+    *CURRENT_TEST.lock() = Some(name);
+    let deadline = time::ticks() + test_timeout_secs() * time::TIMER_HZ;
+    TEST_DEADLINE.store(deadline, Ordering::Relaxed);
+}
+
+fn disarm_test_timeout() {
+    TEST_DEADLINE.store(0, Ordering::Relaxed);
+    *CURRENT_TEST.lock() = None;
+}
+
+/// Called by the timer interrupt handler
+pub(crate) fn check_test_timeout() {
+    let deadline = TEST_DEADLINE.load(Ordering::Relaxed);
+    if deadline == 0 || time::ticks() < deadline {
+        return;
     }
+
+    let name = CURRENT_TEST.lock().unwrap_or("the test");
+    serial_println!("[timeout]\n");
+    serial_println!(
+        "Error: {} didn't finish within {} s\n",
+        name,
+        test_timeout_secs()
+    );
+    print_test_summary(1);
+    exit_qemu(QemuExitCode::Timeout);
+    hlt_loop();
 }
 
 // Our runner just prints a short debug message and then calls each test function
@@ -71,47 +275,268 @@ where
 // of the Fn() trait. It is basically a list of references to types that can be
 // called like a function. Since the function is useless for non-test runs, we use
 // the #[cfg(test)] attribute to include it only for tests.
+#[cfg(not(all(test, feature = "std-tests")))]
 pub fn test_runner(tests: &[&dyn Testable]) {
     serial_println!("Running {} tests", tests.len());
+    TESTS_TOTAL.store(tests.len(), Ordering::Relaxed);
     for test in tests {
         test.run();
     }
+    print_test_summary(0);
     exit_qemu(QemuExitCode::Success);
 }
 
+#[cfg(all(test, feature = "std-tests"))]
+pub fn test_runner(tests: &[&dyn Testable]) {
+    let host_tests = tests.iter().filter(|test| test.runs_on_host());
+    std::println!("Running {} tests on the host", host_tests.clone().count());
+    for test in host_tests {
+        test.run();
+    }
+}
+
 #[test_case]
 fn trivial_assertion() {
     assert_eq!(1, 1);
 }
 
+// QEMU exits with `(code << 1) | 1` for the code written to isa-debug-exit, so
+// a failed run tells what went wrong by its exit status alone: 35 for a test
+// that failed, 37 for a panic, 39 for a timeout, 41 for a double fault and 43
+// for running out of memory. 33 is success (`test-success-exit-code`).
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
 pub enum QemuExitCode {
     Success = 0x10,
+    /// A test failed without panicking, e.g. one that should have.
     Failure = 0x11,
+    Panic = 0x12,
+    Timeout = 0x13,
+    DoubleFault = 0x14,
+    AllocFailure = 0x15,
 }
 
-// The function creates a new Port at 0xf4, which is the iobase of the isa-debug-exit device.
-// Then it writes the passed exit code to the port. We use u32 because we specified the iosize
-// of the isa-debug-exit device as 4 bytes. Both operations are unsafe because writing to an
-// I/O port can generally result in arbitrary behavior.
-pub fn exit_qemu(exit_code: QemuExitCode) {
-    use x86_64::instructions::port::Port;
+impl QemuExitCode {
+    /// The exit code for a test that panicked with `info`. Double faults and
+    /// failed allocations end in panics too, which are told apart by their
+    /// message.
+    pub fn for_panic(info: &PanicInfo) -> QemuExitCode {
+        if message_starts_with(info, "EXCEPTION: DOUBLE FAULT") {
+            QemuExitCode::DoubleFault
+        } else if message_starts_with(info, "memory allocation of ") {
+            // From the default `handle_alloc_error`
+            QemuExitCode::AllocFailure
+        } else {
+            QemuExitCode::Panic
+        }
+    }
+}
 
-    unsafe {
-        let mut port = Port::new(0xF4);
-        port.write(exit_code as u32)
+fn message_starts_with(info: &PanicInfo, prefix: &str) -> bool {
+    // Compares the message as it's formatted, since there's no heap to
+    // format it into when the heap is what failed.
+    struct StartsWith<'a> {
+        rest: &'a [u8],
+        matches: bool,
     }
+
+    impl fmt::Write for StartsWith<'_> {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let len = s.len().min(self.rest.len());
+            if s.as_bytes()[..len] != self.rest[..len] {
+                self.matches = false;
+            }
+            self.rest = &self.rest[len..];
+            if !self.matches || self.rest.is_empty() {
+                return Err(fmt::Error); // Decided, no need for the rest
+            }
+            Ok(())
+        }
+    }
+
+    let mut message = StartsWith {
+        rest: prefix.as_bytes(),
+        matches: true,
+    };
+    let _ = write!(message, "{}", info.message());
+    message.matches && message.rest.is_empty()
+}
+
+// 0xf4 is the iobase of the isa-debug-exit device, and its iosize is 4 bytes
+// (see Cargo.toml), so the exit code is written as a u32. Writing is unsafe
+// because writing to an I/O port can generally result in arbitrary behavior.
+static QEMU_EXIT: io::PortRange = io::PortRange::new("isa-debug-exit", 0xF4, 4);
+
+pub fn exit_qemu(exit_code: QemuExitCode) {
+    unsafe { QEMU_EXIT.write(0, exit_code as u32) }
 }
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    serial_println!("{}", unwind::backtrace());
+    print_test_summary(1);
+    exit_qemu(QemuExitCode::for_panic(info));
+    hlt_loop();
+}
+
+// A test that should panic can't share a binary with other tests: there's no
+// unwinding, so the panic handler is the end of the binary either way. Each one
+// gets an integration test of its own, with `harness = false` in Cargo.toml,
+// whose panic handler exits QEMU with success. `should_panic!` writes that
+// boilerplate:
+//
+//     #![no_std]
+//     #![no_main]
+//
+//     rust_os_playground::should_panic! {
+//         fn should_fail() {
+//             assert_eq!(5, 10);
+//         }
+//     }
+//
+// The test runs after `init`. It can take the `BootInfo` too, e.g. to set up
+// the heap: `fn leaks(boot_info) { ... }`. The panic message is printed as a
+// golden transcript (see src/golden.rs).
+
+#[macro_export]
+macro_rules! should_panic {
+    ($(#[$attr:meta])* fn $name:ident() $body:block) => {
+        $crate::should_panic! { $(#[$attr])* fn $name(_) $body }
+    };
+    ($(#[$attr:meta])* fn $name:ident($boot_info:pat) $body:block) => {
+        $(#[$attr])*
+        fn $name($boot_info: &'static ::bootloader::BootInfo) $body
+
+        ::bootloader::entry_point!(__should_panic_main);
+
+        const __SHOULD_PANIC_NAME: &str = concat!(module_path!(), "::", stringify!($name));
+
+        fn __should_panic_main(boot_info: &'static ::bootloader::BootInfo) -> ! {
+            $crate::init();
+            $crate::run_should_panic(__SHOULD_PANIC_NAME, || $name(boot_info))
+        }
+
+        #[panic_handler]
+        fn __should_panic_handler(info: &::core::panic::PanicInfo) -> ! {
+            $crate::should_panic_handler(__SHOULD_PANIC_NAME, info)
+        }
+    };
+}
+
+/// Runs a test that should panic, and fails it if it returns. See
+/// `should_panic!`.
+pub fn run_should_panic(name: &'static str, test: impl FnOnce()) -> ! {
+    serial_print!("{}...\t", name);
+    TESTS_TOTAL.store(1, Ordering::Relaxed);
+    arm_test_timeout(name);
+    test();
+    disarm_test_timeout();
+    serial_println!("[test did not panic]");
+    print_test_summary(1);
+    exit_qemu(QemuExitCode::Failure);
+    hlt_loop();
+}
+
+/// The panic handler of a test that should panic. See `should_panic!`.
+///
+/// The panic message goes into a golden transcript named after the test, so
+/// that tests/golden/ can pin down how panics are formatted.
+pub fn should_panic_handler(name: &str, info: &PanicInfo) -> ! {
+    golden::transcript(name, |out| writeln!(out, "{}", info));
+    serial_println!("[ok]");
+    TESTS_PASSED.fetch_add(1, Ordering::Relaxed);
+    print_test_summary(0);
     exit_qemu(QemuExitCode::Success);
     hlt_loop();
 }
 
-#[cfg(test)]
+// Every integration test starts the same way: an entry point that runs `init`,
+// sets up whatever the tests need and calls the harness, and a panic handler
+// that fails the test. `kernel_test_main!` writes that, given what to set up:
+//
+//     #![no_std]
+//     #![no_main]
+//     #![feature(custom_test_frameworks)]
+//     #![test_runner(rust_os_playground::test_runner)]
+//     #![reexport_test_harness_main = "test_main"]
+//
+//     rust_os_playground::kernel_test_main!(memory, process);
+//
+// `heap` maps the heap, `memory` does that and also hands the page tables and
+// frame allocator to `memory::init_global`, for tests that map pages of their
+// own. `drivers` runs `drivers::init_all`, and `fs` mounts /tmp with `fs::init`.
+// Anything else is a module whose `init` gets called, in the order given, like
+// `pci` or `process`. A block after `=>` runs last, for setup that doesn't fit
+// that mold:
+//
+//     kernel_test_main!(memory, process => {
+//         initrd::init().expect("can't mount the initrd");
+//     });
+//
+// The crate attributes have to stay in the test: a macro can't write those.
+
+#[macro_export]
+macro_rules! kernel_test_main {
+    ($($($subsystem:ident)::+),* $(,)? $(=> $setup:block)?) => {
+        ::bootloader::entry_point!(__kernel_test_main);
+
+        #[allow(unused_variables)]
+        fn __kernel_test_main(boot_info: &'static ::bootloader::BootInfo) -> ! {
+            $crate::init();
+            $($crate::__kernel_test_init!(boot_info; $($subsystem)::+);)*
+            $($setup)?
+
+            test_main();
+
+            $crate::hlt_loop();
+        }
+
+        #[panic_handler]
+        fn __kernel_test_panic(info: &::core::panic::PanicInfo) -> ! {
+            $crate::test_panic_handler(info)
+        }
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __kernel_test_init {
+    ($boot_info:ident; heap) => {
+        $crate::init_test_heap($boot_info);
+    };
+    ($boot_info:ident; memory) => {
+        let (mapper, frame_allocator) = $crate::init_test_heap($boot_info);
+        $crate::memory::init_global(mapper, frame_allocator);
+    };
+    ($boot_info:ident; drivers) => {
+        $crate::drivers::init_all();
+    };
+    ($boot_info:ident; fs) => {
+        $crate::fs::init().expect("can't mount /tmp");
+    };
+    ($boot_info:ident; $($module:ident)::+) => {
+        $crate::$($module)::+::init();
+    };
+}
+
+/// Sets up paging and the heap for an integration test, and returns what's
+/// needed to map more. See `kernel_test_main!`.
+pub fn init_test_heap(
+    boot_info: &'static bootloader::BootInfo,
+) -> (OffsetPageTable<'static>, memory::BootInfoFrameAllocator) {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("test heap initialization failed");
+    (mapper, frame_allocator)
+}
+
+#[cfg(all(test, not(feature = "std-tests")))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     test_panic_handler(info)
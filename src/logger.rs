@@ -0,0 +1,497 @@
+// A small in-house replacement for the `log` crate facade. Every record carries
+// a level and a target (the module path of the call site by default), which we
+// check against a table of per-target filters before formatting anything. A
+// record that passes is written, with a timestamp from the tick counter, to any
+// combination of the serial port, the VGA buffer, and an in-memory ring buffer
+// that keeps the most recent messages around (like dmesg on Linux). Once
+// `pstore::init` has run, every record also goes to the persistent log, which
+// the next boot can read.
+//
+// Nothing in here allocates, so logging works before the heap is initialized
+// and from interrupt handlers.
+//
+// `log_rate_limited!` is for messages that something outside the kernel's
+// control, like a misbehaving device, can make it log over and over: each call
+// site lets one record through per interval and counts the ones it holds back.
+
+use crate::{cmdline, config, shell, time};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn from_u8(value: u8) -> Option<Level> {
+        match value {
+            1 => Some(Level::Error),
+            2 => Some(Level::Warn),
+            3 => Some(Level::Info),
+            4 => Some(Level::Debug),
+            5 => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+
+    /// Parses a level name case-insensitively, e.g. "debug".
+    pub fn parse(name: &str) -> Option<Level> {
+        [
+            Level::Error,
+            Level::Warn,
+            Level::Info,
+            Level::Debug,
+            Level::Trace,
+        ]
+        .iter()
+        .copied()
+        .find(|level| level.as_str().eq_ignore_ascii_case(name))
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+/// Output destinations, combined with `|` and passed to `set_sinks`.
+pub mod sink {
+    pub const SERIAL: u8 = 1 << 0;
+    pub const VGA: u8 = 1 << 1;
+    pub const RING: u8 = 1 << 2;
+    pub const ALL: u8 = SERIAL | VGA | RING;
+}
+
+const MAX_FILTERS: usize = 16;
+
+static DEFAULT_LEVEL: AtomicU8 = AtomicU8::new(config::LOG_LEVEL as u8);
+static SINKS: AtomicU8 = AtomicU8::new(config::CONSOLE);
+
+// The filters are a fixed-size table so that they can live in a static without
+// a heap. The count lets the common case (no filters) skip the lock entirely.
+static FILTERS: Mutex<[Option<(&'static str, Level)>; MAX_FILTERS]> =
+    Mutex::new([None; MAX_FILTERS]);
+static FILTER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+static RING: Mutex<RingBuffer> = Mutex::new(RingBuffer::new());
+
+/// Sets the level used for targets that have no filter of their own.
+pub fn set_default_level(level: Level) {
+    DEFAULT_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Sets the maximum level for all targets starting with `target_prefix`,
+/// e.g. `"rust_os_playground::task"`. The longest matching prefix wins.
+///
+/// Panics if the filter table is full.
+pub fn set_level(target_prefix: &'static str, level: Level) {
+    interrupts::without_interrupts(|| {
+        let mut filters = FILTERS.lock();
+
+        if let Some(filter) = filters
+            .iter_mut()
+            .flatten()
+            .find(|(prefix, _)| *prefix == target_prefix)
+        {
+            filter.1 = level;
+            return;
+        }
+
+        let slot = filters
+            .iter_mut()
+            .find(|filter| filter.is_none())
+            .expect("log filter table full");
+        *slot = Some((target_prefix, level));
+        FILTER_COUNT.fetch_add(1, Ordering::Relaxed);
+    });
+}
+
+/// Selects where log records are written to, see the `sink` module.
+pub fn set_sinks(sinks: u8) {
+    SINKS.store(sinks, Ordering::Relaxed);
+}
+
+/// Applies the command line's `log=` and `console=` options, like
+/// `log=debug,rust_os_playground::net=trace console=serial`. The ring buffer
+/// always gets the log.
+pub fn init() {
+    if let Some(levels) = cmdline::value("log") {
+        for level in levels.split(',') {
+            let (target, name) = match level.rfind('=') {
+                Some(i) => (Some(&level[..i]), &level[i + 1..]),
+                None => (None, level),
+            };
+            match (target, Level::parse(name)) {
+                (Some(target), Some(level)) => set_level(target, level),
+                (None, Some(level)) => set_default_level(level),
+                (_, None) => crate::warn!("unknown log level in \"{}\"", level),
+            }
+        }
+    }
+
+    match cmdline::value("console") {
+        Some("serial") => set_sinks(sink::SERIAL | sink::RING),
+        Some("vga") => set_sinks(sink::VGA | sink::RING),
+        Some("both") => set_sinks(sink::ALL),
+        None => {}
+        Some(console) => crate::warn!("unknown console \"{}\"", console),
+    }
+}
+
+/// Returns whether a record with the given level and target would be logged.
+pub fn enabled(level: Level, target: &str) -> bool {
+    let default =
+        Level::from_u8(DEFAULT_LEVEL.load(Ordering::Relaxed)).unwrap_or(config::LOG_LEVEL);
+
+    if FILTER_COUNT.load(Ordering::Relaxed) == 0 {
+        return level <= default;
+    }
+
+    let max_level = interrupts::without_interrupts(|| {
+        FILTERS
+            .lock()
+            .iter()
+            .flatten()
+            .filter(|(prefix, _)| target.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|&(_, level)| level)
+    });
+
+    level <= max_level.unwrap_or(default)
+}
+
+/// Writes the contents of the ring buffer, oldest message first.
+pub fn dmesg(out: &mut dyn Write) -> fmt::Result {
+    interrupts::without_interrupts(|| RING.lock().write_to(out))
+}
+
+/// Writes the last `max_lines` lines of the ring buffer, unless the ring buffer
+/// is locked. For crash paths, where waiting for the lock could hang forever.
+pub(crate) fn try_dmesg_tail(out: &mut dyn Write, max_lines: usize) -> Option<fmt::Result> {
+    RING.try_lock()
+        .map(|ring| ring.write_tail_to(out, max_lines))
+}
+
+pub fn register_commands() {
+    shell::register("dmesg", "print the kernel log", |_args, out| dmesg(out));
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, target: &str, args: fmt::Arguments) {
+    if !enabled(level, target) {
+        return;
+    }
+
+    let record = Record {
+        uptime_ms: time::uptime_ms(),
+        level,
+        target,
+        args,
+    };
+    let sinks = SINKS.load(Ordering::Relaxed);
+
+    // Log records have to come out in one piece, so keep the timer (and any
+    // other interrupt that might log) away until we're done.
+    interrupts::without_interrupts(|| {
+        if sinks & sink::SERIAL != 0 {
+            crate::serial::_print_to(crate::serial::Channel::Log, format_args!("{}", record));
+        }
+        if sinks & sink::VGA != 0 {
+            crate::vga_buffer::_print(format_args!("{}", record));
+        }
+        if sinks & sink::RING != 0 {
+            // The ring buffer never fails to accept bytes.
+            let _ = write!(RING.lock(), "{}", record);
+        }
+        crate::pstore::write_record(&record);
+    });
+}
+
+/// The state of one `log_rate_limited!` call site.
+#[doc(hidden)]
+pub struct RateLimit {
+    /// The first tick at which the next record may go through.
+    next: AtomicU64,
+    suppressed: AtomicU64,
+}
+
+impl RateLimit {
+    pub const fn new() -> RateLimit {
+        RateLimit {
+            next: AtomicU64::new(0),
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether a record at `tick` may go through, with at least `interval`
+    /// ticks since the last one that did. If so, returns how many were held
+    /// back in between.
+    pub fn check(&self, interval: u64, tick: u64) -> Option<u64> {
+        let next = self.next.load(Ordering::Relaxed);
+        // Of records racing each other, e.g. from an interrupt handler and
+        // the code it interrupted, only the one that moves `next` goes through.
+        if tick < next
+            || self
+                .next
+                .compare_exchange(next, tick + interval, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(self.suppressed.swap(0, Ordering::Relaxed))
+    }
+}
+
+struct Record<'a> {
+    uptime_ms: u64,
+    level: Level,
+    target: &'a str,
+    args: fmt::Arguments<'a>,
+}
+
+impl fmt::Display for Record<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "[{:>5}.{:03}] {:<5} {}: {}",
+            self.uptime_ms / 1000,
+            self.uptime_ms % 1000,
+            self.level,
+            self.target,
+            self.args
+        )
+    }
+}
+
+const RING_SIZE: usize = config::LOG_RING_SIZE;
+
+/// A byte ring buffer that overwrites the oldest data once it is full.
+struct RingBuffer {
+    buf: [u8; RING_SIZE],
+    // Total number of bytes ever written; the write position is `written % RING_SIZE`.
+    written: usize,
+}
+
+impl RingBuffer {
+    const fn new() -> Self {
+        RingBuffer {
+            buf: [0; RING_SIZE],
+            written: 0,
+        }
+    }
+
+    /// Returns the buffered bytes, oldest first, as two slices.
+    fn contents(&self) -> (&[u8], &[u8]) {
+        let (older, newer) = if self.written < RING_SIZE {
+            (&self.buf[..0], &self.buf[..self.written])
+        } else {
+            let pos = self.written % RING_SIZE;
+            (&self.buf[pos..], &self.buf[..pos])
+        };
+
+        // The oldest line was most likely cut in half by the wrap around.
+        let older = match older.iter().position(|&b| b == b'\n') {
+            Some(newline) => &older[newline + 1..],
+            None => older,
+        };
+
+        (older, newer)
+    }
+
+    fn write_to(&self, out: &mut dyn Write) -> fmt::Result {
+        self.write_tail_to(out, usize::MAX)
+    }
+
+    fn write_tail_to(&self, out: &mut dyn Write, max_lines: usize) -> fmt::Result {
+        let (older, newer) = self.contents();
+        let bytes = || older.iter().chain(newer).copied();
+
+        let lines = bytes().filter(|&b| b == b'\n').count();
+        let mut skip = lines.saturating_sub(max_lines);
+
+        for byte in bytes() {
+            if skip > 0 {
+                if byte == b'\n' {
+                    skip -= 1;
+                }
+                continue;
+            }
+            out.write_char(char::from(byte))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Write for RingBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.buf[self.written % RING_SIZE] = byte;
+            self.written += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Logs a message at the given level, like `log::log!`.
+#[macro_export]
+macro_rules! log {
+    (target: $target:expr, $level:expr, $($arg:tt)+) => {
+        $crate::logger::_log($level, $target, format_args!($($arg)+))
+    };
+    ($level:expr, $($arg:tt)+) => {
+        $crate::log!(target: module_path!(), $level, $($arg)+)
+    };
+}
+
+/// Like `log!`, but lets through at most one record every `interval_ms` from
+/// this call site, and says how many were held back since the last one.
+#[macro_export]
+macro_rules! log_rate_limited {
+    ($interval_ms:expr, target: $target:expr, $level:expr, $($arg:tt)+) => {{
+        static LIMIT: $crate::logger::RateLimit = $crate::logger::RateLimit::new();
+        let interval = $crate::time::ms_to_ticks($interval_ms);
+        match LIMIT.check(interval, $crate::time::ticks()) {
+            Some(0) => $crate::log!(target: $target, $level, $($arg)+),
+            Some(suppressed) => $crate::log!(
+                target: $target,
+                $level,
+                "{} ({} more suppressed)",
+                format_args!($($arg)+),
+                suppressed
+            ),
+            None => {}
+        }
+    }};
+    ($interval_ms:expr, $level:expr, $($arg:tt)+) => {
+        $crate::log_rate_limited!($interval_ms, target: module_path!(), $level, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    (target: $target:expr, $($arg:tt)+) => ($crate::log!(target: $target, $crate::logger::Level::Error, $($arg)+));
+    ($($arg:tt)+) => ($crate::log!($crate::logger::Level::Error, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! warn {
+    (target: $target:expr, $($arg:tt)+) => ($crate::log!(target: $target, $crate::logger::Level::Warn, $($arg)+));
+    ($($arg:tt)+) => ($crate::log!($crate::logger::Level::Warn, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! info {
+    (target: $target:expr, $($arg:tt)+) => ($crate::log!(target: $target, $crate::logger::Level::Info, $($arg)+));
+    ($($arg:tt)+) => ($crate::log!($crate::logger::Level::Info, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! debug {
+    (target: $target:expr, $($arg:tt)+) => ($crate::log!(target: $target, $crate::logger::Level::Debug, $($arg)+));
+    ($($arg:tt)+) => ($crate::log!($crate::logger::Level::Debug, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! trace {
+    (target: $target:expr, $($arg:tt)+) => ($crate::log!(target: $target, $crate::logger::Level::Trace, $($arg)+));
+    ($($arg:tt)+) => ($crate::log!($crate::logger::Level::Trace, $($arg)+));
+}
+
+#[test_case]
+fn test_level_filters() {
+    set_level("rust_os_playground::logger::test_filter", Level::Trace);
+    set_level(
+        "rust_os_playground::logger::test_filter::quiet",
+        Level::Error,
+    );
+
+    assert!(enabled(
+        Level::Trace,
+        "rust_os_playground::logger::test_filter"
+    ));
+    assert!(!enabled(
+        Level::Warn,
+        "rust_os_playground::logger::test_filter::quiet"
+    ));
+    assert!(enabled(
+        Level::Error,
+        "rust_os_playground::logger::test_filter::quiet"
+    ));
+    assert!(!enabled(Level::Trace, "rust_os_playground::elsewhere"));
+}
+
+#[test_case]
+fn test_ring_buffer_wraps() {
+    // Counts the bytes written and remembers the first line.
+    struct Collect {
+        first_line: [u8; 8],
+        len: usize,
+    }
+
+    impl Write for Collect {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            for byte in s.bytes() {
+                if self.len < self.first_line.len() {
+                    self.first_line[self.len] = byte;
+                }
+                self.len += 1;
+            }
+            Ok(())
+        }
+    }
+
+    let mut ring = RingBuffer::new();
+    for _ in 0..RING_SIZE / 8 + 1 {
+        write!(ring, "abcdefg\n").unwrap();
+    }
+
+    let mut collect = Collect {
+        first_line: [0; 8],
+        len: 0,
+    };
+    ring.write_to(&mut collect).unwrap();
+
+    // The partially overwritten oldest line is dropped, whole lines remain.
+    assert_eq!(collect.len % 8, 0);
+    assert_eq!(&collect.first_line, b"abcdefg\n");
+
+    let mut collect = Collect {
+        first_line: [0; 8],
+        len: 0,
+    };
+    ring.write_tail_to(&mut collect, 3).unwrap();
+
+    assert_eq!(collect.len, 3 * 8);
+    assert_eq!(&collect.first_line, b"abcdefg\n");
+}
+
+#[test_case]
+fn test_rate_limit() {
+    let limit = RateLimit::new();
+    assert_eq!(limit.check(10, 5), Some(0));
+    assert_eq!(limit.check(10, 6), None);
+    assert_eq!(limit.check(10, 14), None);
+    assert_eq!(limit.check(10, 15), Some(2));
+    assert_eq!(limit.check(10, 100), Some(0));
+}
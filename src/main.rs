@@ -6,13 +6,54 @@
 
 extern crate alloc;
 
+#[cfg(not(feature = "uefi"))]
 use bootloader::{entry_point, BootInfo};
+#[cfg(feature = "uefi")]
+use bootloader_api::config::{BootloaderConfig, Mapping};
 use core::panic::PanicInfo;
-use rust_os_playground::allocator;
+use rust_os_playground::acpi;
+use rust_os_playground::allocator::{self, accounting::Subsystem};
+use rust_os_playground::bf;
+use rust_os_playground::block;
+use rust_os_playground::cmdline;
+use rust_os_playground::config;
+use rust_os_playground::cpu;
+use rust_os_playground::debugflags;
+use rust_os_playground::demo;
+use rust_os_playground::device;
+use rust_os_playground::drivers;
+#[cfg(feature = "uefi")]
+use rust_os_playground::framebuffer;
+use rust_os_playground::fs;
+use rust_os_playground::hpet;
+use rust_os_playground::hypervisor;
+use rust_os_playground::idle;
+use rust_os_playground::initrd;
+use rust_os_playground::interrupts;
+use rust_os_playground::io;
+use rust_os_playground::kassert;
+use rust_os_playground::logger;
 use rust_os_playground::memory;
+use rust_os_playground::net::{self, ipv4, udp, Ipv4Address};
+use rust_os_playground::pci;
+use rust_os_playground::power;
+use rust_os_playground::print;
 use rust_os_playground::println;
-use rust_os_playground::task::{executor::Executor, keyboard, Task};
-use x86_64::VirtAddr;
+use rust_os_playground::process;
+use rust_os_playground::programs;
+use rust_os_playground::pstore;
+use rust_os_playground::rtc;
+use rust_os_playground::serial_print;
+use rust_os_playground::shell;
+use rust_os_playground::task::{
+    executor::{self, Executor},
+    keyboard, Task,
+};
+use rust_os_playground::time;
+use rust_os_playground::tty;
+use rust_os_playground::util;
+use rust_os_playground::QemuExitCode;
+use x86_64::{PhysAddr, VirtAddr};
 
 // Don't mangle function name (_start) - this is the entry point since
 // the linker looks for a function named `_start` by default. Update:
@@ -21,25 +62,156 @@ use x86_64::VirtAddr;
 // expects, the bootloader crate provides an entry_point macro that
 // provides a type-checked way to define a Rust function as the entry
 // point. Let’s rewrite our entry point function to use this macro:
+#[cfg(not(feature = "uefi"))]
 entry_point!(kernel_main);
+
+#[cfg(not(feature = "uefi"))]
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    time::boot_begin();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let frame_allocator = unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    start(phys_mem_offset, frame_allocator, None)
+}
+
+// With the "uefi" feature, version 0.11 of the bootloader boots the kernel,
+// from UEFI or the BIOS. It only maps all of physical memory if asked to, and
+// puts that and its other mappings wherever there's room unless told
+// otherwise, which could be where the heap or user programs go.
+#[cfg(feature = "uefi")]
+static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config.mappings.dynamic_range_start = Some(0xFFFF_8000_0000_0000);
+    config
+};
+
+#[cfg(feature = "uefi")]
+bootloader_api::entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
+
+#[cfg(feature = "uefi")]
+fn kernel_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    time::boot_begin();
+
+    if let Some(framebuffer) = boot_info.framebuffer.take() {
+        framebuffer::init(framebuffer);
+    }
+    let boot_info: &'static bootloader_api::BootInfo = boot_info;
+    let phys_mem_offset = boot_info
+        .physical_memory_offset
+        .into_option()
+        .expect("the bootloader didn't map physical memory");
+    let rsdp = boot_info.rsdp_addr.into_option().map(PhysAddr::new);
+    let frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init_uefi(&boot_info.memory_regions) };
+    start(VirtAddr::new(phys_mem_offset), frame_allocator, rsdp)
+}
+
+/// Boots the kernel, whichever bootloader started it. `rsdp` is where the
+/// ACPI tables' root pointer is, if the bootloader knows.
+fn start(
+    phys_mem_offset: VirtAddr,
+    mut frame_allocator: memory::BootInfoFrameAllocator,
+    rsdp: Option<PhysAddr>,
+) -> ! {
     println!("Welcome to the system{}", "!");
 
     rust_os_playground::init();
 
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator =
-        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    time::boot_phase("paging");
 
+    memory::layout::init();
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    time::boot_phase("heap");
+    pstore::init(&mut frame_allocator);
+
+    memory::init_global(mapper, frame_allocator);
+    match rsdp {
+        Some(rsdp) => acpi::init_at(rsdp),
+        None => acpi::init(),
+    }
+    power::init();
+    hypervisor::init();
+    hpet::init();
+    time::select_tick_source();
+    idle::init();
+    print!("{}", cpu::topology());
+    process::init();
+    time::boot_phase("processes");
+
+    pci::init();
+    drivers::init_all();
+    // QEMU's user networking hands the guest this address, and there's no
+    // DHCP client to ask for it yet.
+    if let Some(eth0) = net::interface("eth0") {
+        eth0.set_ipv4_config(Some(ipv4::Config {
+            address: Ipv4Address::new(10, 0, 2, 15),
+            prefix_len: 24,
+            gateway: Some(Ipv4Address::new(10, 0, 2, 2)),
+        }));
+    }
+    time::boot_phase("drivers");
+
+    cmdline::register_commands();
+    config::register_commands();
+    cpu::register_commands();
+    debugflags::register_commands();
+    logger::register_commands();
+    pstore::register_commands();
+    kassert::register_commands();
+    time::register_commands();
+    programs::register_commands();
+    block::register_commands();
+    pci::register_commands();
+    device::register_commands();
+    io::register_commands();
+    power::register_commands();
+    idle::register_commands();
+    rtc::register_commands();
+    util::register_commands();
+    memory::register_commands();
+    executor::register_commands();
+    keyboard::register_commands();
+    net::register_commands();
+    fs::register_commands();
+    interrupts::register_commands();
+    allocator::register_commands();
+    bf::register_commands();
+    demo::register_commands();
+    block::partitions::scan_all();
+    fs::init().expect("can't mount /tmp");
+    initrd::init().expect("can't mount the initrd");
 
     #[cfg(test)]
     test_main();
 
     let mut executor = Executor::new();
     executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.spawn(Task::new(tty::run()));
+    executor.spawn(Task::named("shell", async {
+        shell::run().await;
+        power::shutdown();
+    }));
+    for interface in net::interfaces() {
+        #[cfg(feature = "smoltcp")]
+        if interface.name() == "eth0" {
+            executor.spawn(Task::new(net::smol::run(interface)).charged_to(Subsystem::Net));
+            continue;
+        }
+        executor.spawn(Task::new(net::run(interface)).charged_to(Subsystem::Net));
+    }
+    executor.spawn(Task::new(udp::echo(7)).charged_to(Subsystem::Net));
+    executor.spawn(Task::named("sntp", time::sntp::run()).charged_to(Subsystem::Net));
+    #[cfg(feature = "smoltcp")]
+    executor.spawn(Task::new(net::smol::echo(7)).charged_to(Subsystem::Net));
+    time::boot_phase("executor");
+
+    serial_print!("{}", time::boot_report());
+    // For checking that the kernel boots at all, e.g. in CI
+    if cmdline::flag("test") {
+        rust_os_playground::exit_qemu(QemuExitCode::Success);
+    }
     executor.run();
 }
 
@@ -58,8 +230,7 @@ async fn example_task() {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
-    rust_os_playground::hlt_loop();
+    rust_os_playground::panic::handle(info);
 }
 
 #[cfg(test)]
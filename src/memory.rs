@@ -1,9 +1,55 @@
+use crate::allocator;
+use crate::shell;
+#[cfg(test)]
+use crate::HostTest;
+use alloc::vec::Vec;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+#[cfg(feature = "uefi")]
+use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use conquer_once::spin::OnceCell;
+use core::fmt::{self, Write};
+use core::ops::Range;
+use spin::Mutex;
 use x86_64::{
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    instructions::interrupts,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, OffsetPageTable, PageTable, PageTableFlags, PhysFrame,
+        Size4KiB,
+    },
     PhysAddr, VirtAddr,
 };
 
+pub mod address_space;
+pub mod dma;
+pub mod layout;
+pub mod shared;
+
+pub use address_space::AddressSpace;
+use address_space::{USER_END, USER_START};
+pub use dma::DmaFrame;
+pub use shared::SharedMemory;
+
+// Remembered by `init` so that code without access to the mapper (e.g. the
+// backtrace printer in a panic handler) can still look at the page tables.
+static PHYSICAL_MEMORY_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
+
+// Once the heap is set up, `kernel_main` hands the kernel's mapper and the frame
+// allocator over to `init_global`, so that processes, kernel stacks and drivers
+// can map memory long after boot.
+static KERNEL_MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+
+// The bootloader's memory map can only hand out frames, never take them back, so
+// frames freed through `GlobalFrameAllocator` go on a free list, which is used
+// first. The list is threaded through the free frames themselves: each one
+// starts with the physical address of the next.
+static FREE_FRAMES: Mutex<FreeFrames> = Mutex::new(FreeFrames { head: None, len: 0 });
+
+struct FreeFrames {
+    head: Option<PhysFrame>,
+    len: usize,
+}
+
 /// Initializes a new OffsetPageTable.
 ///
 /// # Safety
@@ -13,11 +59,58 @@ use x86_64::{
 /// `physical_memory_offset`. Also, this function must only be called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET
+        .try_init_once(|| physical_memory_offset)
+        .expect("memory::init should only be called once");
+
     let level_4_table = active_level_4_table(physical_memory_offset);
 
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// Makes the kernel's mapper and the frame allocator available globally, see
+/// `with_kernel_mapper` and `GlobalFrameAllocator`.
+pub fn init_global(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    interrupts::without_interrupts(|| {
+        *KERNEL_MAPPER.lock() = Some(mapper);
+        *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+    });
+}
+
+/// Runs `f` with the mapper for the kernel's page table and the global frame
+/// allocator.
+///
+/// Panics if `init_global` hasn't been called yet.
+pub fn with_kernel_mapper<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut GlobalFrameAllocator) -> R,
+) -> R {
+    interrupts::without_interrupts(|| {
+        let mut mapper = KERNEL_MAPPER.lock();
+        let mapper = mapper.as_mut().expect("memory::init_global not called");
+
+        f(mapper, &mut GlobalFrameAllocator)
+    })
+}
+
+/// Returns the virtual address at which the given physical address is mapped.
+///
+/// Panics if `init` hasn't been called yet.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    let physical_memory_offset = PHYSICAL_MEMORY_OFFSET
+        .try_get()
+        .expect("memory::init not called");
+
+    *physical_memory_offset + addr.as_u64()
+}
+
+/// Like `phys_to_virt`, but for addresses that may be past the end of the
+/// mapping of physical memory, which have no virtual address.
+pub fn try_phys_to_virt(addr: PhysAddr) -> Option<VirtAddr> {
+    let physical_memory_offset = PHYSICAL_MEMORY_OFFSET.try_get().ok()?;
+    let virt = physical_memory_offset.as_u64().checked_add(addr.as_u64())?;
+    VirtAddr::try_new(virt).ok()
+}
+
 /// Returns a mutable reference to the active level 4 table.
 ///
 /// # Safety
@@ -36,15 +129,90 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
 
     &mut *page_table_ptr // unsafe
 }
+/// Translates the given virtual address to the mapped physical address, or
+/// `None` if the address is not mapped (or `init` has not been called yet).
+///
+/// Unlike the mapper returned by `init`, this only ever reads the active page
+/// tables, so it is fine to call from anywhere, including panic handlers.
+pub fn translate_addr(addr: VirtAddr) -> Option<PhysAddr> {
+    translate_with_flags(addr).map(|(phys, _)| phys)
+}
+
+/// Like `translate_addr`, but also returns the flags that apply to the
+/// address. Those are the last level's, except that `WRITABLE` and
+/// `USER_ACCESSIBLE` are only set if every level allows it, and `NO_EXECUTE`
+/// is set if any level has it, the way the CPU combines them.
+pub fn translate_with_flags(addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+    use x86_64::registers::control::Cr3;
+
+    const EVERY_LEVEL: PageTableFlags =
+        PageTableFlags::WRITABLE.union(PageTableFlags::USER_ACCESSIBLE);
+
+    let physical_memory_offset = *PHYSICAL_MEMORY_OFFSET.try_get().ok()?;
+    let (level_4_table_frame, _) = Cr3::read();
+    let table_indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    let mut frame = level_4_table_frame;
+    let mut flags = PageTableFlags::empty();
+    let mut every_level = EVERY_LEVEL;
+    let mut no_execute = PageTableFlags::empty();
+
+    // Traverse the multi-level page table
+    for (level, &index) in table_indexes.iter().enumerate() {
+        let virt = physical_memory_offset + frame.start_address().as_u64();
+        let table: &PageTable = unsafe { &*virt.as_ptr() };
+        let entry = &table[index];
+        flags = entry.flags();
+
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        every_level &= flags;
+        no_execute |= flags & PageTableFlags::NO_EXECUTE;
+
+        if flags.contains(PageTableFlags::HUGE_PAGE) {
+            // Huge pages end the walk early: a level 3 entry maps 1 GiB and a
+            // level 2 entry maps 2 MiB directly.
+            let page_size: u64 = match level {
+                1 => 1 << 30,
+                2 => 1 << 21,
+                _ => return None,
+            };
+            let phys = entry.addr() + (addr.as_u64() & (page_size - 1));
+            return Some((phys, (flags - EVERY_LEVEL) | every_level | no_execute));
+        }
+
+        frame = PhysFrame::containing_address(entry.addr());
+    }
+
+    let phys = frame.start_address() + u64::from(addr.page_offset());
+    Some((phys, (flags - EVERY_LEVEL) | every_level | no_execute))
+}
+
 // We don’t need to use an unsafe block here because Rust treats the complete body of an unsafe fn
 // like a large unsafe block. This makes our code more dangerous since we could accidentally introduce
 // an unsafe operation in previous lines without noticing. It also makes it much more difficult to
 // spot unsafe operations in between safe operations. There is an RFC to change this behavior.
 
+/// The bootloader's memory map, from either version of the bootloader (see
+/// the "uefi" feature).
+#[derive(Clone, Copy)]
+enum BootMemoryMap {
+    Bios(&'static MemoryMap),
+    #[cfg(feature = "uefi")]
+    Uefi(&'static MemoryRegions),
+}
+
 /// A FrameAllocator that returns usable frames from the bootloader's memory map.
 pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
+    memory_map: BootMemoryMap,
     next: usize,
+    /// Usable memory that's kept out of the allocator, see `reserve_top`.
+    reserved: Range<u64>,
 }
 
 impl BootInfoFrameAllocator {
@@ -57,26 +225,141 @@ impl BootInfoFrameAllocator {
     /// as `USABLE` in it are really unused.
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
         BootInfoFrameAllocator {
-            memory_map,
+            memory_map: BootMemoryMap::Bios(memory_map),
             next: 0,
+            reserved: 0..0,
         }
     }
 
-    /// Returns an iterator over the usable frames specified in the memory map.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // Get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
+    /// Like `init`, for the memory map of the UEFI capable bootloader.
+    ///
+    /// # Safety
+    ///
+    /// Same as for `init`.
+    #[cfg(feature = "uefi")]
+    pub unsafe fn init_uefi(memory_regions: &'static MemoryRegions) -> Self {
+        BootInfoFrameAllocator {
+            memory_map: BootMemoryMap::Uefi(memory_regions),
+            next: 0,
+            reserved: 0..0,
+        }
+    }
 
-        // Map each region to its address range
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
+    /// Returns the regions of the memory map, whichever kind it is.
+    fn regions(&self) -> Vec<MemoryRegion> {
+        match self.memory_map {
+            BootMemoryMap::Bios(memory_map) => memory_map
+                .iter()
+                .map(|r| MemoryRegion {
+                    start: PhysAddr::new(r.range.start_addr()),
+                    end: PhysAddr::new(r.range.end_addr()),
+                    kind: match r.region_type {
+                        MemoryRegionType::Usable => "usable",
+                        MemoryRegionType::InUse => "in use",
+                        MemoryRegionType::Reserved => "reserved",
+                        MemoryRegionType::AcpiReclaimable => "ACPI reclaimable",
+                        MemoryRegionType::AcpiNvs => "ACPI NVS",
+                        MemoryRegionType::BadMemory => "bad",
+                        MemoryRegionType::Kernel => "kernel",
+                        MemoryRegionType::KernelStack => "kernel stack",
+                        MemoryRegionType::PageTable => "page tables",
+                        MemoryRegionType::Bootloader => "bootloader",
+                        MemoryRegionType::FrameZero => "frame zero",
+                        MemoryRegionType::BootInfo => "boot info",
+                        MemoryRegionType::Package => "package",
+                        _ => "unknown",
+                    },
+                })
+                .collect(),
+            #[cfg(feature = "uefi")]
+            BootMemoryMap::Uefi(memory_regions) => memory_regions
+                .iter()
+                .map(|r| MemoryRegion {
+                    start: PhysAddr::new(r.start),
+                    end: PhysAddr::new(r.end),
+                    kind: match r.kind {
+                        MemoryRegionKind::Usable => "usable",
+                        MemoryRegionKind::Bootloader => "bootloader",
+                        _ => "reserved",
+                    },
+                })
+                .collect(),
+        }
+    }
+
+    /// Takes `size` bytes at the top of the highest usable region that has
+    /// them out of the allocator, for memory that has to be at the same place
+    /// on every boot, and returns where they start. Only once, and returns
+    /// `None` if the allocator has handed out any of them already.
+    pub fn reserve_top(&mut self, size: u64) -> Option<PhysAddr> {
+        let reserved = top_of(self.usable_ranges(), size)?;
+        // The allocator hands the frames out in order, so these are all it has
+        let handed_out = self
+            .usable_frames()
+            .take(self.next)
+            .any(|frame| reserved.contains(&frame.start_address().as_u64()));
+        if handed_out {
+            return None;
+        }
 
+        self.reserved = reserved;
+        Some(PhysAddr::new(self.reserved.start))
+    }
+
+    /// Returns an iterator over the usable frames specified in the memory map.
+    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
         // Transform to an iterator of frame start addressses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
+        let reserved = self.reserved.clone();
+        let frame_addresses = self
+            .usable_ranges()
+            .flat_map(|r| r.step_by(4096))
+            .filter(move |addr| !reserved.contains(addr));
 
         // Create `PhysFrame` types from the start addresses
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
+
+    /// Returns the address ranges of the usable regions in the memory map.
+    fn usable_ranges(&self) -> impl Iterator<Item = Range<u64>> {
+        // Get the address ranges of the usable regions from whichever memory
+        // map we have
+        let bios = match self.memory_map {
+            BootMemoryMap::Bios(memory_map) => Some(memory_map),
+            #[cfg(feature = "uefi")]
+            BootMemoryMap::Uefi(_) => None,
+        };
+        let addr_ranges = bios.into_iter().flat_map(|memory_map| {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+                .map(|r| r.range.start_addr()..r.range.end_addr())
+        });
+        #[cfg(feature = "uefi")]
+        let addr_ranges = {
+            let uefi = match self.memory_map {
+                BootMemoryMap::Uefi(memory_regions) => Some(memory_regions),
+                BootMemoryMap::Bios(_) => None,
+            };
+            addr_ranges.chain(uefi.into_iter().flat_map(|memory_regions| {
+                memory_regions
+                    .iter()
+                    .filter(|r| r.kind == MemoryRegionKind::Usable)
+                    .map(|r| r.start..r.end)
+            }))
+        };
+        addr_ranges
+    }
+}
+
+/// The last `size` bytes, on page boundaries, of the highest of `ranges` that
+/// has that many.
+fn top_of(ranges: impl Iterator<Item = Range<u64>>, size: u64) -> Option<Range<u64>> {
+    let size = size.next_multiple_of(4096);
+    ranges
+        .map(|range| range.start.next_multiple_of(4096)..range.end & !4095)
+        .filter(|range| range.end >= range.start + size)
+        .max_by_key(|range| range.end)
+        .map(|range| range.end - size..range.end)
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
@@ -91,3 +374,247 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
         frame
     }
 }
+
+/// Hands out frames from the allocator passed to `init_global`.
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        interrupts::without_interrupts(|| {
+            let mut free = FREE_FRAMES.lock();
+            if let Some(frame) = free.head {
+                let next = unsafe { *phys_to_virt(frame.start_address()).as_ptr::<u64>() };
+                free.head = match next {
+                    0 => None,
+                    next => Some(PhysFrame::containing_address(PhysAddr::new(next))),
+                };
+                free.len -= 1;
+                return Some(frame);
+            }
+            drop(free);
+
+            FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame()
+        })
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    /// Puts the frame on the free list.
+    ///
+    /// Frame 0 is never handed out (it isn't usable memory), so a 0 can mark
+    /// the end of the list.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        interrupts::without_interrupts(|| {
+            let mut free = FREE_FRAMES.lock();
+            let next = free.head.map_or(0, |head| head.start_address().as_u64());
+
+            *phys_to_virt(frame.start_address()).as_mut_ptr::<u64>() = next;
+            free.head = Some(frame);
+            free.len += 1;
+        });
+    }
+}
+
+/// Returns the number of frames that are currently allocated through
+/// `GlobalFrameAllocator`, including those taken before `init_global`.
+pub fn allocated_frames() -> usize {
+    interrupts::without_interrupts(|| {
+        let taken = FRAME_ALLOCATOR
+            .lock()
+            .as_ref()
+            .map_or(0, |allocator| allocator.next);
+
+        taken - FREE_FRAMES.lock().len
+    })
+}
+
+/// A range of physical memory from the bootloader's memory map.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryRegion {
+    pub start: PhysAddr,
+    pub end: PhysAddr,
+    /// What the bootloader says is there, like "usable" or "kernel".
+    pub kind: &'static str,
+}
+
+/// Returns the bootloader's memory map, or nothing before `init_global`.
+pub fn memory_map() -> Vec<MemoryRegion> {
+    interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR
+            .lock()
+            .as_ref()
+            .map_or_else(Vec::new, |allocator| allocator.regions())
+    })
+}
+
+/// How the frames of usable memory are used.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    /// All frames in usable memory.
+    pub usable: usize,
+    /// The frames that are allocated right now, see `allocated_frames`.
+    pub allocated: usize,
+    /// The frames that were freed, and are waiting to be allocated again.
+    pub free_listed: usize,
+}
+
+impl FrameStats {
+    /// The frames that haven't been allocated yet.
+    pub fn never_used(&self) -> usize {
+        self.usable - self.allocated - self.free_listed
+    }
+}
+
+/// Returns how the frames are used, or all zeros before `init_global`.
+pub fn frame_stats() -> FrameStats {
+    interrupts::without_interrupts(|| {
+        let usable = FRAME_ALLOCATOR
+            .lock()
+            .as_ref()
+            .map_or(0, |allocator| allocator.usable_frames().count());
+
+        FrameStats {
+            usable,
+            allocated: allocated_frames(),
+            free_listed: FREE_FRAMES.lock().len,
+        }
+    })
+}
+
+impl GlobalFrameAllocator {
+    /// Allocates a frame and fills it with zeros.
+    pub fn allocate_zeroed_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.allocate_frame()?;
+        let ptr: *mut u8 = phys_to_virt(frame.start_address()).as_mut_ptr();
+
+        unsafe { core::ptr::write_bytes(ptr, 0, frame.size() as usize) };
+
+        Some(frame)
+    }
+}
+
+/// Formats a size in bytes with the biggest binary unit it has one of, to a
+/// tenth, like `4 KiB` or `127.8 MiB`.
+pub struct Size(pub u64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+        let exponent = (0..UNITS.len())
+            .rev()
+            .find(|&i| self.0 >> (10 * i) != 0)
+            .unwrap_or(0);
+        let unit = 1u64 << (10 * exponent);
+        let (whole, rest) = (self.0 / unit, self.0 % unit);
+        match rest * 10 / unit {
+            0 if rest == 0 => write!(f, "{} {}", whole, UNITS[exponent]),
+            tenths => write!(f, "{}.{} {}", whole, tenths, UNITS[exponent]),
+        }
+    }
+}
+
+pub fn register_commands() {
+    shell::register(
+        "lsmem",
+        "print the memory map, frame usage and address space layout",
+        lsmem,
+    );
+}
+
+fn lsmem(_args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    let regions = memory_map();
+    writeln!(out, "physical memory:")?;
+    for region in &regions {
+        let size = Size(region.end - region.start);
+        writeln!(
+            out,
+            "  {:#018x}-{:#018x} {:>10}  {}",
+            region.start, region.end, size, region.kind
+        )?;
+    }
+
+    let frames = frame_stats();
+    writeln!(
+        out,
+        "frames: {} usable, {} allocated, {} freed, {} never used",
+        frames.usable,
+        frames.allocated,
+        frames.free_listed,
+        frames.never_used()
+    )?;
+
+    let physical_memory_offset = PHYSICAL_MEMORY_OFFSET
+        .try_get()
+        .map_or(0, |offset| offset.as_u64());
+    let physical_memory_end = regions
+        .iter()
+        .map(|region| region.end.as_u64())
+        .max()
+        .unwrap_or(0);
+    let heap_start = layout::heap_start() as u64;
+    let kernel_stacks = layout::kernel_stacks();
+    let mut layout = [
+        ("user space", USER_START, USER_END),
+        (
+            "kernel heap",
+            heap_start,
+            heap_start + allocator::heap_size() as u64,
+        ),
+        (
+            "physical memory",
+            physical_memory_offset,
+            physical_memory_offset + physical_memory_end,
+        ),
+        ("kernel stacks", kernel_stacks.start, kernel_stacks.end),
+    ];
+    layout.sort_unstable_by_key(|&(_, start, _)| start);
+
+    let randomized = if layout::randomized() {
+        " (randomized)"
+    } else {
+        ""
+    };
+    writeln!(out, "virtual memory{}:", randomized)?;
+    for (name, start, end) in layout {
+        writeln!(
+            out,
+            "  {:#018x}-{:#018x} {:>10}  {}",
+            start,
+            end,
+            Size(end - start),
+            name
+        )?;
+    }
+    Ok(())
+}
+
+#[test_case]
+static FORMATS_SIZES: HostTest = HostTest::new("memory::formats_sizes", || {
+    let size = |bytes| alloc::format!("{}", Size(bytes));
+    assert_eq!(size(0), "0 B");
+    assert_eq!(size(1023), "1023 B");
+    assert_eq!(size(4096), "4 KiB");
+    assert_eq!(size(639 * 1024), "639 KiB");
+    assert_eq!(size(0x7fe_0000), "127.8 MiB");
+    assert_eq!(size(16 << 40), "16 TiB");
+});
+
+#[test_case]
+static RESERVES_TOP: HostTest = HostTest::new("memory::reserves_top", || {
+    let ranges = [
+        0x1000..0x9f000,
+        0x10_0000..0x7fe_0000,
+        0x8000_0000..0x8000_2000,
+    ];
+    assert_eq!(
+        top_of(ranges.iter().cloned(), 0x10000),
+        Some(0x7fd_0000..0x7fe_0000)
+    );
+    // Rounded up to pages, and to within pages
+    assert_eq!(
+        top_of(core::iter::once(0x1800..0x5800), 100),
+        Some(0x4000..0x5000)
+    );
+    assert_eq!(top_of(ranges.iter().cloned(), 1 << 30), None);
+});
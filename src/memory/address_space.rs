@@ -0,0 +1,379 @@
+// Every process gets its own level 4 page table. The kernel has to stay mapped
+// in all of them, since interrupts arriving in ring 3 are handled without
+// switching page tables. So a new address space starts out as a copy of the
+// kernel's level 4 table: the copied entries point to the same level 3 tables as
+// the kernel's, which shares all kernel mappings below them. None of the copied
+// entries are USER_ACCESSIBLE, so ring 3 code can't touch any of it.
+//
+// User mappings live in a range of level 4 entries that the kernel doesn't use,
+// so mapping them only ever creates page tables that belong to this address
+// space alone, and never modifies the shared ones. That also makes it easy to
+// free an address space: everything below the user entries is ours, except for
+// the frames of shared memory, which are marked with `SHARED` and freed by
+// `SharedMemory` itself.
+
+use super::{phys_to_virt, with_kernel_mapper, GlobalFrameAllocator, SharedMemory};
+use alloc::{sync::Arc, vec::Vec};
+use core::ops::Range;
+use x86_64::{
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::{
+        mapper::{MapToError, TranslateResult},
+        FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
+        Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// The start of the user part of every address space (level 4 entry 32).
+pub const USER_START: u64 = 0x0000_1000_0000_0000;
+/// The end (exclusive) of the user part of every address space (level 4 entry 64).
+pub const USER_END: u64 = 0x0000_2000_0000_0000;
+
+const USER_LEVEL_4_ENTRIES: Range<usize> = 32..64;
+
+// Shared memory is mapped somewhere in here, out of the way of executables,
+// which are usually linked near USER_START, and the stack at USER_END.
+const SHARED_START: u64 = 0x0000_1800_0000_0000;
+const SHARED_END: u64 = 0x0000_1C00_0000_0000;
+
+/// One of the page table bits that are free for the OS to use, marking pages
+/// whose frames belong to a `SharedMemory`.
+const SHARED: PageTableFlags = PageTableFlags::BIT_9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpaceError {
+    /// The range isn't completely inside `USER_START..USER_END`.
+    OutsideUserSpace,
+    /// The address isn't mapped.
+    NotMapped,
+    OutOfMemory,
+}
+
+impl From<MapToError<Size4KiB>> for AddressSpaceError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        match error {
+            MapToError::FrameAllocationFailed => AddressSpaceError::OutOfMemory,
+            // We never create huge pages in the user range, and `map_user`
+            // checks for existing mappings itself.
+            MapToError::ParentEntryHugePage | MapToError::PageAlreadyMapped(_) => {
+                unreachable!("unexpected mapping in user space: {:?}", error)
+            }
+        }
+    }
+}
+
+/// Returns whether `start..start + size` lies inside the user part of the address space.
+pub fn is_user_range(start: VirtAddr, size: u64) -> bool {
+    match start.as_u64().checked_add(size) {
+        Some(end) => start.as_u64() >= USER_START && end <= USER_END,
+        None => false,
+    }
+}
+
+/// A range of user memory mapped with `map_user`.
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub writable: bool,
+}
+
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    vmas: Vec<Vma>,
+    // Keeps the mapped shared memory alive.
+    shared: Vec<Arc<SharedMemory>>,
+}
+
+impl AddressSpace {
+    /// Creates an address space that contains the kernel mappings and nothing else.
+    pub fn new() -> Result<AddressSpace, AddressSpaceError> {
+        with_kernel_mapper(|kernel, frame_allocator| {
+            let level_4_frame = frame_allocator
+                .allocate_zeroed_frame()
+                .ok_or(AddressSpaceError::OutOfMemory)?;
+            let table = unsafe { table_mut(level_4_frame) };
+
+            for (i, entry) in kernel.level_4_table().iter().enumerate() {
+                if USER_LEVEL_4_ENTRIES.contains(&i) {
+                    assert!(
+                        entry.is_unused(),
+                        "kernel mapping in user space (level 4 entry {})",
+                        i
+                    );
+                    continue;
+                }
+                table[i] = entry.clone();
+            }
+
+            Ok(AddressSpace {
+                level_4_frame,
+                vmas: Vec::new(),
+                shared: Vec::new(),
+            })
+        })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    pub fn vmas(&self) -> &[Vma] {
+        &self.vmas
+    }
+
+    /// Returns whether all of `start..start + size` is mapped for ring 3 code,
+    /// and writable if `write` is set. This is how system calls check the
+    /// pointers they get from user space.
+    pub fn is_accessible(&self, start: VirtAddr, size: u64, write: bool) -> bool {
+        let end = match start.as_u64().checked_add(size) {
+            Some(end) => end,
+            None => return false,
+        };
+        let mut addr = start.as_u64();
+
+        while addr < end {
+            let vma = self
+                .vmas
+                .iter()
+                .find(|vma| vma.start.as_u64() <= addr && addr < vma.end.as_u64());
+
+            match vma {
+                Some(vma) if vma.writable || !write => addr = vma.end.as_u64(),
+                _ => return false,
+            }
+        }
+
+        true
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        unsafe {
+            OffsetPageTable::new(
+                table_mut(self.level_4_frame),
+                phys_to_virt(PhysAddr::new(0)),
+            )
+        }
+    }
+
+    /// Maps zeroed memory into the user range, readable and executable by ring 3
+    /// code, and also writable if `writable` is set.
+    ///
+    /// Pages that are already mapped are kept (two ELF segments may share a
+    /// page), but become writable if `writable` is set.
+    pub fn map_user(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        writable: bool,
+    ) -> Result<(), AddressSpaceError> {
+        if size == 0 {
+            return Ok(());
+        }
+        if !is_user_range(start, size) {
+            return Err(AddressSpaceError::OutsideUserSpace);
+        }
+
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+
+        let first: Page<Size4KiB> = Page::containing_address(start);
+        let last = Page::containing_address(start + size - 1u64);
+        self.vmas.push(Vma {
+            start: first.start_address(),
+            end: last.start_address() + last.size(),
+            writable,
+        });
+
+        let mut mapper = self.mapper();
+        let mut frame_allocator = GlobalFrameAllocator;
+
+        for page in Page::range_inclusive(first, last) {
+            if let TranslateResult::Mapped {
+                flags: old_flags, ..
+            } = mapper.translate(page.start_address())
+            {
+                // Flushing is only needed if this is the active address space,
+                // but doesn't hurt otherwise.
+                if let Ok(flush) = unsafe { mapper.update_flags(page, old_flags | flags) } {
+                    flush.flush();
+                }
+                continue;
+            }
+
+            let frame = frame_allocator
+                .allocate_zeroed_frame()
+                .ok_or(AddressSpaceError::OutOfMemory)?;
+            unsafe {
+                mapper
+                    .map_to(page, frame, flags, &mut frame_allocator)?
+                    .flush()
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Maps the shared memory at a free address, writable for ring 3 code, and
+    /// returns that address.
+    ///
+    /// Fails with `OutOfMemory` if there is no room left for it, too.
+    pub fn map_shared(&mut self, memory: Arc<SharedMemory>) -> Result<VirtAddr, AddressSpaceError> {
+        let size = memory.size();
+
+        // The first gap that fits, after the last shared mapping
+        let mut start = self
+            .vmas
+            .iter()
+            .map(|vma| vma.end.as_u64())
+            .filter(|&end| (SHARED_START..SHARED_END).contains(&end))
+            .max()
+            .unwrap_or(SHARED_START);
+        while let Some(vma) = self
+            .vmas
+            .iter()
+            .find(|vma| vma.start.as_u64() < start + size && start < vma.end.as_u64())
+        {
+            start = vma.end.as_u64();
+        }
+        if start + size > SHARED_END {
+            return Err(AddressSpaceError::OutOfMemory);
+        }
+
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::USER_ACCESSIBLE
+            | PageTableFlags::WRITABLE
+            | SHARED;
+        let start = VirtAddr::new(start);
+        let mut mapper = self.mapper();
+        let mut frame_allocator = GlobalFrameAllocator;
+
+        for (i, &frame) in memory.frames().iter().enumerate() {
+            let page: Page<Size4KiB> = Page::containing_address(start + i * 4096);
+            unsafe {
+                mapper
+                    .map_to(page, frame, flags, &mut frame_allocator)?
+                    .flush()
+            };
+        }
+
+        self.vmas.push(Vma {
+            start,
+            end: start + size,
+            writable: true,
+        });
+        self.shared.push(memory);
+
+        Ok(start)
+    }
+
+    /// Unmaps the page and frees its frame, but keeps the VMA it's in. Nothing
+    /// else lets the page tables and the VMAs disagree like that; it's for
+    /// testing that `usercopy` copes when they do.
+    pub fn unmap_page(&mut self, page: Page<Size4KiB>) -> Result<(), AddressSpaceError> {
+        let mut mapper = self.mapper();
+        let shared = match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => flags.contains(SHARED),
+            _ => return Err(AddressSpaceError::NotMapped),
+        };
+        let (frame, flush) = mapper
+            .unmap(page)
+            .map_err(|_| AddressSpaceError::NotMapped)?;
+        flush.flush();
+
+        if !shared {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        }
+        Ok(())
+    }
+
+    /// Copies `bytes` to the given (mapped) address in this address space.
+    pub fn copy_to(&mut self, addr: VirtAddr, bytes: &[u8]) -> Result<(), AddressSpaceError> {
+        let mapper = self.mapper();
+        let mut copied = 0;
+
+        while copied < bytes.len() {
+            let dest = addr + copied;
+            let phys = mapper
+                .translate_addr(dest)
+                .ok_or(AddressSpaceError::NotMapped)?;
+            let len = (bytes.len() - copied).min(4096 - usize::from(dest.page_offset()));
+
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    bytes[copied..].as_ptr(),
+                    phys_to_virt(phys).as_mut_ptr(),
+                    len,
+                );
+            }
+            copied += len;
+        }
+
+        Ok(())
+    }
+
+    /// Switches to this address space.
+    ///
+    /// # Safety
+    ///
+    /// The address space must stay alive for as long as it is active.
+    pub unsafe fn activate(&self) {
+        Cr3::write(self.level_4_frame, Cr3Flags::empty());
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Frees all user memory, the page tables that map it, and the level 4 table.
+    ///
+    /// The address space must not be active anymore.
+    fn drop(&mut self) {
+        debug_assert!(
+            Cr3::read().0 != self.level_4_frame,
+            "dropping the active address space"
+        );
+
+        let level_4_table = unsafe { table_mut(self.level_4_frame) };
+
+        for i in USER_LEVEL_4_ENTRIES {
+            if let Ok(frame) = level_4_table[i].frame() {
+                unsafe { free_table(frame, 3) };
+            }
+        }
+
+        unsafe { GlobalFrameAllocator.deallocate_frame(self.level_4_frame) };
+    }
+}
+
+unsafe fn table_mut(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+/// Frees the given page table, everything mapped through it, and the tables below
+/// it. `level` is 1 for a table that maps pages.
+unsafe fn free_table(frame: PhysFrame, level: u8) {
+    for entry in table_mut(frame).iter() {
+        // `frame()` fails for unused entries (and huge pages, which we don't create)
+        if let Ok(child) = entry.frame() {
+            if level == 1 {
+                if !entry.flags().contains(SHARED) {
+                    GlobalFrameAllocator.deallocate_frame(child);
+                }
+            } else {
+                free_table(child, level - 1);
+            }
+        }
+    }
+
+    GlobalFrameAllocator.deallocate_frame(frame);
+}
+
+#[test_case]
+fn test_is_user_range() {
+    assert!(is_user_range(VirtAddr::new(USER_START), 4096));
+    assert!(is_user_range(VirtAddr::new(USER_END - 4096), 4096));
+    assert!(!is_user_range(VirtAddr::new(USER_END - 4096), 4097));
+    assert!(!is_user_range(VirtAddr::new(0x40_0000), 4096));
+}
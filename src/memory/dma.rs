@@ -0,0 +1,49 @@
+// Memory that devices read and write by themselves (DMA), like descriptor
+// rings and packet buffers. Devices only know physical addresses, so a buffer
+// has to be physically contiguous. The frame allocator hands out single frames,
+// so a `DmaFrame` is one frame, which holds a descriptor ring or a couple of
+// packet buffers.
+//
+// The kernel reaches the memory through the physical memory mapping. The device
+// may change it at any time, so drivers access it with volatile reads and
+// writes.
+
+use super::{phys_to_virt, GlobalFrameAllocator};
+use crate::error::KernelError;
+use x86_64::{
+    structures::paging::{FrameDeallocator, PageSize, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+pub struct DmaFrame {
+    frame: PhysFrame,
+}
+
+impl DmaFrame {
+    pub const SIZE: usize = Size4KiB::SIZE as usize;
+
+    /// Allocates a zeroed frame.
+    pub fn new() -> Result<DmaFrame, KernelError> {
+        let frame = GlobalFrameAllocator
+            .allocate_zeroed_frame()
+            .ok_or(KernelError::NoMemory)?;
+        Ok(DmaFrame { frame })
+    }
+
+    /// The address to give to the device.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.frame.start_address()
+    }
+
+    /// The address for the kernel.
+    pub fn virt_addr(&self) -> VirtAddr {
+        phys_to_virt(self.phys_addr())
+    }
+}
+
+// The device must be done with the frame by the time it is dropped.
+impl Drop for DmaFrame {
+    fn drop(&mut self) {
+        unsafe { GlobalFrameAllocator.deallocate_frame(self.frame) };
+    }
+}
@@ -0,0 +1,97 @@
+// Where the kernel puts its own things in the virtual address space. Each
+// area has a window of one level 4 entry (512 GiB) to itself, and `init` puts
+// it at a random page in there when booting, so that an attacker who gets the
+// kernel to write somewhere it shouldn't still has to guess where anything
+// is. `nokaslr` on the command line puts them at their fixed addresses
+// instead, which are easier to recognize when debugging:
+//
+//     level 4 entry   area                      fixed address
+//     32..64          user space                (address_space.rs)
+//     136             the heap                  0x0000_4444_4444_0000
+//     510             kernel stacks             0xffff_ff00_0000_0000
+//
+// The kernel's code and the map of all physical memory, through which the
+// device registers are reached too, are wherever the bootloader put them.
+//
+// Until `init`, everything is at its fixed address, which is where the tests
+// keep it.
+
+use crate::process::kernel_stack;
+#[cfg(test)]
+use crate::HostTest;
+use crate::{allocator, cmdline, debug, rand};
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const ENTRY_SIZE: u64 = 1 << 39;
+const PAGE_SIZE: u64 = 4096;
+
+const HEAP_WINDOW: Range<u64> = 136 * ENTRY_SIZE..137 * ENTRY_SIZE;
+const HEAP_FIXED: u64 = 0x_4444_4444_0000;
+
+/// Sign-extended, since it's in the upper half.
+const KERNEL_STACKS_WINDOW: Range<u64> = 0xFFFF_FF00_0000_0000..0xFFFF_FF80_0000_0000;
+const KERNEL_STACKS_FIXED: u64 = KERNEL_STACKS_WINDOW.start;
+
+static HEAP_START: AtomicU64 = AtomicU64::new(HEAP_FIXED);
+static KERNEL_STACKS_START: AtomicU64 = AtomicU64::new(KERNEL_STACKS_FIXED);
+
+/// Picks where the areas go, unless the command line says `nokaslr`. Must
+/// come before the heap and the kernel stacks are set up.
+pub fn init() {
+    if cmdline::flag("nokaslr") {
+        return;
+    }
+
+    let heap = place(HEAP_WINDOW, allocator::heap_size() as u64, rand::next_u64());
+    let stacks = place(
+        KERNEL_STACKS_WINDOW,
+        kernel_stack::REGION_SIZE,
+        rand::next_u64(),
+    );
+    HEAP_START.store(heap, Ordering::Relaxed);
+    KERNEL_STACKS_START.store(stacks, Ordering::Relaxed);
+    debug!("kaslr: heap at {:#x}, kernel stacks at {:#x}", heap, stacks);
+}
+
+/// Where an area of `size` bytes goes in `window`, on a page boundary, for
+/// the random number `random`.
+fn place(window: Range<u64>, size: u64, random: u64) -> u64 {
+    let pages = (window.end - window.start).saturating_sub(size) / PAGE_SIZE + 1;
+    window.start + random % pages * PAGE_SIZE
+}
+
+pub fn heap_start() -> usize {
+    HEAP_START.load(Ordering::Relaxed) as usize
+}
+
+/// The addresses the kernel stacks can be at.
+pub fn kernel_stacks() -> Range<u64> {
+    let start = KERNEL_STACKS_START.load(Ordering::Relaxed);
+    start..start + kernel_stack::REGION_SIZE
+}
+
+/// Whether `init` moved the areas away from their fixed addresses.
+pub fn randomized() -> bool {
+    HEAP_START.load(Ordering::Relaxed) != HEAP_FIXED
+        || KERNEL_STACKS_START.load(Ordering::Relaxed) != KERNEL_STACKS_FIXED
+}
+
+#[test_case]
+static PLACES_AREAS: HostTest = HostTest::new("memory::layout::places_areas", || {
+    let size = 16 * PAGE_SIZE;
+    assert_eq!(place(HEAP_WINDOW, size, 0), HEAP_WINDOW.start);
+    assert_eq!(
+        place(HEAP_WINDOW, size, 3),
+        HEAP_WINDOW.start + 3 * PAGE_SIZE
+    );
+
+    // The last page it can start at, and then round again
+    let last = HEAP_WINDOW.end - size;
+    let pages = (last - HEAP_WINDOW.start) / PAGE_SIZE;
+    assert_eq!(place(HEAP_WINDOW, size, pages), last);
+    assert_eq!(place(HEAP_WINDOW, size, pages + 1), HEAP_WINDOW.start);
+    assert!(place(KERNEL_STACKS_WINDOW, size, u64::MAX) + size <= KERNEL_STACKS_WINDOW.end);
+
+    assert!(HEAP_WINDOW.contains(&HEAP_FIXED));
+});
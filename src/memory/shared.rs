@@ -0,0 +1,110 @@
+// Memory that can be mapped into several address spaces at once. The frames are
+// reference counted through an `Arc`: every address space that maps them holds
+// a reference, and they are freed when the last one goes away. Address spaces
+// mark the pages as shared in their page tables, so that they don't free the
+// frames themselves when they are dropped.
+
+use super::{phys_to_virt, GlobalFrameAllocator};
+use crate::error::KernelError;
+use alloc::{sync::Arc, vec::Vec};
+use x86_64::{
+    structures::paging::{FrameDeallocator, PageSize, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+/// The largest shared memory region that can be created.
+pub const MAX_SIZE: u64 = 16 * 1024 * 1024;
+
+pub struct SharedMemory {
+    frames: Vec<PhysFrame>,
+}
+
+impl SharedMemory {
+    /// Allocates `size` bytes (rounded up to whole pages) of zeroed memory.
+    ///
+    /// Fails with `InvalidArgument` if `size` is zero or larger than
+    /// `MAX_SIZE`.
+    pub fn new(size: u64) -> Result<Arc<SharedMemory>, KernelError> {
+        if size == 0 || size > MAX_SIZE {
+            return Err(KernelError::InvalidArgument);
+        }
+
+        let count = (x86_64::align_up(size, Size4KiB::SIZE) / Size4KiB::SIZE) as usize;
+        let mut memory = SharedMemory {
+            frames: Vec::with_capacity(count),
+        };
+
+        // If we run out of frames, dropping `memory` frees the ones we got.
+        for _ in 0..count {
+            let frame = GlobalFrameAllocator
+                .allocate_zeroed_frame()
+                .ok_or(KernelError::NoMemory)?;
+            memory.frames.push(frame);
+        }
+
+        Ok(Arc::new(memory))
+    }
+
+    pub fn size(&self) -> u64 {
+        self.frames.len() as u64 * Size4KiB::SIZE
+    }
+
+    pub fn frames(&self) -> &[PhysFrame] {
+        &self.frames
+    }
+
+    /// Copies `bytes` into the memory at `offset`, for the kernel side of a
+    /// shared buffer.
+    ///
+    /// Panics if the range is out of bounds.
+    pub fn write(&self, offset: u64, bytes: &[u8]) {
+        self.for_each_chunk(offset, bytes.len(), |phys, start, len| unsafe {
+            core::ptr::copy_nonoverlapping(
+                bytes[start..].as_ptr(),
+                phys_to_virt(phys).as_mut_ptr(),
+                len,
+            );
+        });
+    }
+
+    /// Copies the memory at `offset` into `buf`.
+    ///
+    /// Panics if the range is out of bounds.
+    pub fn read(&self, offset: u64, buf: &mut [u8]) {
+        self.for_each_chunk(offset, buf.len(), |phys, start, len| unsafe {
+            core::ptr::copy_nonoverlapping(
+                phys_to_virt(phys).as_ptr(),
+                buf[start..].as_mut_ptr(),
+                len,
+            );
+        });
+    }
+
+    /// Splits `offset..offset + len` at frame boundaries and calls `f` with the
+    /// physical address, the offset into the range and the length of each part.
+    fn for_each_chunk(&self, offset: u64, len: usize, mut f: impl FnMut(PhysAddr, usize, usize)) {
+        assert!(
+            offset.saturating_add(len as u64) <= self.size(),
+            "shared memory access out of bounds"
+        );
+
+        let mut done = 0;
+        while done < len {
+            let addr = offset + done as u64;
+            let frame = self.frames[(addr / Size4KiB::SIZE) as usize];
+            let page_offset = addr % Size4KiB::SIZE;
+            let chunk = (len - done).min((Size4KiB::SIZE - page_offset) as usize);
+
+            f(frame.start_address() + page_offset, done, chunk);
+            done += chunk;
+        }
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        for &frame in &self.frames {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        }
+    }
+}
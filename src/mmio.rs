@@ -0,0 +1,162 @@
+// Memory mapped device registers. A `RegisterBlock` is a device's window of
+// registers, like the local APIC's page or a network card's BAR, and does all
+// accesses into it: volatile, of the register's width, and checked to be
+// inside the window. Drivers name their registers by offset, as the data
+// sheets do, and read and write them as u8 to u64.
+//
+// Most changes to a register only touch some of its bits, so `modify`, `set`
+// and `clear` read it, change it and write it back.
+//
+// With the `trace_mmio` debug flag on, every access is logged with the block's
+// name, which shows what a driver really did to its device. Reading a
+// register can have side effects, like the e1000's statistics registers that
+// reset, so reads are logged with the value they got rather than read again.
+
+use crate::debugflags;
+#[cfg(test)]
+use crate::HostTest;
+use core::fmt;
+use core::mem;
+use core::ops::{BitAnd, BitOr, Not};
+use core::ptr;
+use x86_64::VirtAddr;
+
+/// The widths a register can have.
+pub trait Register:
+    Copy
+    + fmt::LowerHex
+    + BitAnd<Output = Self>
+    + BitOr<Output = Self>
+    + Not<Output = Self>
+    + private::Sealed
+{
+}
+
+impl Register for u8 {}
+impl Register for u16 {}
+impl Register for u32 {}
+impl Register for u64 {}
+
+mod private {
+    pub trait Sealed {}
+
+    impl Sealed for u8 {}
+    impl Sealed for u16 {}
+    impl Sealed for u32 {}
+    impl Sealed for u64 {}
+}
+
+pub struct RegisterBlock {
+    name: &'static str,
+    base: VirtAddr,
+    size: usize,
+}
+
+impl RegisterBlock {
+    /// The `size` bytes of registers at `base`, which are called `name` in
+    /// the log.
+    ///
+    /// # Safety
+    ///
+    /// `base` has to map `size` bytes of a device's registers for as long as
+    /// the block is used, and accessing them mustn't break memory safety (a
+    /// card that's told to DMA somewhere can do anything).
+    pub const unsafe fn new(name: &'static str, base: VirtAddr, size: usize) -> RegisterBlock {
+        RegisterBlock { name, base, size }
+    }
+
+    pub fn base(&self) -> VirtAddr {
+        self.base
+    }
+
+    /// The `size` bytes of registers at `offset`, like one entry of a table.
+    pub fn subblock(&self, offset: usize, size: usize) -> RegisterBlock {
+        assert!(
+            offset + size <= self.size,
+            "{:#x} bytes at {:#x} are outside of {}",
+            size,
+            offset,
+            self.name
+        );
+        RegisterBlock {
+            name: self.name,
+            base: self.base + offset,
+            size,
+        }
+    }
+
+    /// Checks that a `T` at `offset` is inside the block, and aligned.
+    fn check<T>(&self, offset: usize) {
+        assert!(
+            offset + mem::size_of::<T>() <= self.size,
+            "offset {:#x} is outside of {}",
+            offset,
+            self.name
+        );
+        assert!(
+            offset % mem::align_of::<T>() == 0,
+            "offset {:#x} of {} is unaligned",
+            offset,
+            self.name
+        );
+    }
+
+    pub fn read<T: Register>(&self, offset: usize) -> T {
+        self.check::<T>(offset);
+        let value = unsafe { ptr::read_volatile((self.base + offset).as_ptr::<T>()) };
+        if debugflags::TRACE_MMIO.get() {
+            crate::info!(target: "mmio", "{} {:#06x} -> {:#x}", self.name, offset, value);
+        }
+        value
+    }
+
+    pub fn write<T: Register>(&self, offset: usize, value: T) {
+        self.check::<T>(offset);
+        if debugflags::TRACE_MMIO.get() {
+            crate::info!(target: "mmio", "{} {:#06x} <- {:#x}", self.name, offset, value);
+        }
+        unsafe { ptr::write_volatile((self.base + offset).as_mut_ptr::<T>(), value) };
+    }
+
+    /// Reads the register at `offset`, and writes back what `f` makes of it.
+    pub fn modify<T: Register>(&self, offset: usize, f: impl FnOnce(T) -> T) {
+        let value = self.read(offset);
+        self.write(offset, f(value));
+    }
+
+    /// Sets `bits` in the register at `offset`.
+    pub fn set<T: Register>(&self, offset: usize, bits: T) {
+        self.modify(offset, |value: T| value | bits);
+    }
+
+    /// Clears `bits` in the register at `offset`.
+    pub fn clear<T: Register>(&self, offset: usize, bits: T) {
+        self.modify(offset, |value: T| value & !bits);
+    }
+}
+
+impl fmt::Debug for RegisterBlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at {:#x} ({:#x} bytes)",
+            self.name, self.base, self.size
+        )
+    }
+}
+
+#[test_case]
+static ACCESSES_REGISTERS: HostTest = HostTest::new("mmio::accesses_registers", || {
+    let mut memory = [0u32; 4];
+    let base = VirtAddr::from_ptr(memory.as_mut_ptr());
+    let block = unsafe { RegisterBlock::new("test", base, 16) };
+
+    block.write(4, 0x1234_5678u32);
+    block.set(4, 1u32 << 31);
+    block.clear(4, 0xFFu32);
+    assert_eq!(block.read::<u32>(4), 0x9234_5600);
+    assert_eq!(block.read::<u16>(6), 0x9234);
+    block.modify(8, |value: u64| value + 2);
+    assert_eq!(block.subblock(8, 8).read::<u32>(0), 2);
+    assert_eq!(memory, [0, 0x9234_5600, 2, 0]);
+});
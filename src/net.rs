@@ -0,0 +1,375 @@
+// Networking. Network cards (and anything that looks like one) implement
+// `NetDevice` and `register` themselves, so that the protocol code on top
+// works with any of them without knowing which driver is underneath, like
+// block devices and filesystems.
+//
+// A device sends and receives Ethernet frames in `PacketBuf`s, which leave room
+// in front of the data for the headers of the layers below (see buf.rs). Both
+// are asynchronous: a driver whose card works by itself returns futures that
+// wait for its interrupts, and the tasks of the network stack run meanwhile.
+//
+// Devices are shared through an `Arc`, so the methods take `&self` and each
+// device does its own locking.
+//
+// Each registered device becomes an `Interface`, which holds what the protocols
+// keep per device: its IPv4 configuration and its ARP cache. `run` is the task
+// that takes an interface's received frames and passes them up the layers:
+// eth.rs, then arp.rs or ipv4.rs, then icmp.rs or udp.rs. With the "smoltcp"
+// feature, smol.rs can run smoltcp on an interface instead.
+//
+// loopback.rs has a device that hands whatever is sent through it back, for
+// 127.0.0.1.
+
+use crate::error::KernelError;
+use crate::shell;
+use alloc::{boxed::Box, collections::BTreeMap, format, string::String, sync::Arc, vec, vec::Vec};
+use core::{fmt, future::Future, pin::Pin, str::FromStr};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub mod arp;
+pub mod buf;
+pub mod eth;
+pub mod icmp;
+pub mod ipv4;
+pub mod loopback;
+#[cfg(feature = "smoltcp")]
+pub mod smol;
+pub mod udp;
+
+pub use buf::PacketBuf;
+
+/// The hardware address of a network card.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: MacAddress = MacAddress([0xFF; 6]);
+
+    pub fn is_broadcast(&self) -> bool {
+        *self == MacAddress::BROADCAST
+    }
+
+    /// Multicast addresses (including broadcast) have the lowest bit of the
+    /// first byte set.
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 1 != 0
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+/// An IPv4 address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Ipv4Address(pub [u8; 4]);
+
+impl Ipv4Address {
+    pub const UNSPECIFIED: Ipv4Address = Ipv4Address([0; 4]);
+    pub const BROADCAST: Ipv4Address = Ipv4Address([255; 4]);
+    pub const LOOPBACK: Ipv4Address = Ipv4Address([127, 0, 0, 1]);
+
+    pub const fn new(a: u8, b: u8, c: u8, d: u8) -> Ipv4Address {
+        Ipv4Address([a, b, c, d])
+    }
+}
+
+impl fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+impl From<u32> for Ipv4Address {
+    fn from(address: u32) -> Ipv4Address {
+        Ipv4Address(address.to_be_bytes())
+    }
+}
+
+impl From<Ipv4Address> for u32 {
+    fn from(address: Ipv4Address) -> u32 {
+        u32::from_be_bytes(address.0)
+    }
+}
+
+/// Parses dotted decimal, like "10.0.2.2".
+impl FromStr for Ipv4Address {
+    type Err = KernelError;
+
+    fn from_str(s: &str) -> Result<Ipv4Address, KernelError> {
+        let mut address = Ipv4Address::default();
+        let mut parts = s.split('.');
+        for byte in address.0.iter_mut() {
+            let part = parts.next().ok_or(KernelError::InvalidArgument)?;
+            *byte = part.parse().map_err(|_| KernelError::InvalidArgument)?;
+        }
+        match parts.next() {
+            Some(_) => Err(KernelError::InvalidArgument),
+            None => Ok(address),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    /// The frame is larger than the device can send.
+    TooLarge,
+    /// The device has no link, or was shut down.
+    Down,
+    /// The device reported an error.
+    Io,
+    /// The interface has no IPv4 address yet.
+    NoAddress,
+    /// No interface is on the destination's network, or has a gateway to it.
+    NoRoute,
+    /// The port is taken by another socket.
+    AddressInUse,
+    /// The destination's MAC address isn't known yet, and too many packets
+    /// are already waiting for it.
+    Unresolved,
+    /// The peer didn't accept the connection.
+    ConnectionRefused,
+    /// The connection was closed or reset.
+    ConnectionClosed,
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            NetError::TooLarge => "frame too large",
+            NetError::Down => "network device is down",
+            NetError::Io => "I/O error",
+            NetError::NoAddress => "no IPv4 address configured",
+            NetError::NoRoute => "no route to host",
+            NetError::AddressInUse => "address in use",
+            NetError::Unresolved => "address not resolved",
+            NetError::ConnectionRefused => "connection refused",
+            NetError::ConnectionClosed => "connection closed",
+        })
+    }
+}
+
+/// A network operation that is in progress.
+pub type NetFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+pub trait NetDevice: Send + Sync {
+    fn mac_address(&self) -> MacAddress;
+
+    /// The largest payload of a frame, without the Ethernet header. 1500 for
+    /// Ethernet.
+    fn mtu(&self) -> usize;
+
+    /// Sends an Ethernet frame, without the checksum (the device adds it).
+    /// Finishes once the device is done with the frame.
+    fn send(&self, frame: PacketBuf) -> NetFuture<'_, Result<(), NetError>>;
+
+    /// Waits for the next received Ethernet frame, without the checksum.
+    /// Frames go to one receiver each, so only one task (the network stack's)
+    /// should wait for them.
+    fn receive(&self) -> NetFuture<'_, PacketBuf>;
+
+    fn link_up(&self) -> bool {
+        true
+    }
+
+    /// The link's speed in Mbit/s, if the device knows it.
+    fn link_speed(&self) -> Option<u32> {
+        None
+    }
+
+    /// What the device counted since it was set up, if it counts.
+    fn stats(&self) -> Option<DeviceStats> {
+        None
+    }
+
+    /// Whether the frames sent through the device come back to us, like
+    /// with `loopback::Loopback`. Such a device needs no ARP.
+    fn is_loopback(&self) -> bool {
+        false
+    }
+}
+
+/// Counters of a `NetDevice`, for `ifconfig`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DeviceStats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    /// Frames that arrived broken: bad checksums, bad lengths, ...
+    pub rx_errors: u64,
+    /// Frames that arrived fine but had nowhere to go, e.g. with the receive
+    /// ring full.
+    pub rx_dropped: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    /// Frames that couldn't be sent, e.g. after too many collisions.
+    pub tx_errors: u64,
+    pub collisions: u64,
+}
+
+/// A registered device, and the state the protocols keep for it.
+pub struct Interface {
+    name: String,
+    device: Arc<dyn NetDevice>,
+    ipv4: Mutex<Option<ipv4::Config>>,
+    arp: Mutex<arp::Cache>,
+}
+
+impl Interface {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn device(&self) -> &Arc<dyn NetDevice> {
+        &self.device
+    }
+
+    pub fn mac_address(&self) -> MacAddress {
+        self.device.mac_address()
+    }
+
+    pub fn ipv4_config(&self) -> Option<ipv4::Config> {
+        interrupts::without_interrupts(|| *self.ipv4.lock())
+    }
+
+    pub fn set_ipv4_config(&self, config: Option<ipv4::Config>) {
+        interrupts::without_interrupts(|| *self.ipv4.lock() = config);
+    }
+
+    pub fn ipv4_address(&self) -> Option<Ipv4Address> {
+        self.ipv4_config().map(|config| config.address)
+    }
+
+    /// Runs `f` on the interface's ARP cache.
+    fn with_arp<T>(&self, f: impl FnOnce(&mut arp::Cache) -> T) -> T {
+        interrupts::without_interrupts(|| f(&mut self.arp.lock()))
+    }
+}
+
+static INTERFACES: Mutex<BTreeMap<String, Arc<Interface>>> = Mutex::new(BTreeMap::new());
+
+/// Adds a device and returns the name it was given: `kind` followed by the
+/// first number that isn't taken yet, e.g. "eth0".
+pub fn register(kind: &str, device: Arc<dyn NetDevice>) -> String {
+    interrupts::without_interrupts(|| {
+        let mut interfaces = INTERFACES.lock();
+        let name = (0..)
+            .map(|i| format!("{}{}", kind, i))
+            .find(|name| !interfaces.contains_key(name))
+            .unwrap();
+
+        let interface = Interface {
+            name: name.clone(),
+            device,
+            ipv4: Mutex::new(None),
+            arp: Mutex::new(arp::Cache::new()),
+        };
+        interfaces.insert(name.clone(), Arc::new(interface));
+        name
+    })
+}
+
+/// Returns the device with the given name.
+pub fn get(name: &str) -> Option<Arc<dyn NetDevice>> {
+    interface(name).map(|interface| interface.device.clone())
+}
+
+/// Returns all devices, sorted by name.
+pub fn devices() -> Vec<(String, Arc<dyn NetDevice>)> {
+    interfaces()
+        .into_iter()
+        .map(|interface| (interface.name.clone(), interface.device.clone()))
+        .collect()
+}
+
+/// Returns the interface of the device with the given name.
+pub fn interface(name: &str) -> Option<Arc<Interface>> {
+    interrupts::without_interrupts(|| INTERFACES.lock().get(name).cloned())
+}
+
+/// Returns all interfaces, sorted by name.
+pub fn interfaces() -> Vec<Arc<Interface>> {
+    interrupts::without_interrupts(|| INTERFACES.lock().values().cloned().collect())
+}
+
+/// Receives the interface's frames and handles them, forever. There should be
+/// one such task per interface.
+pub async fn run(interface: Arc<Interface>) {
+    loop {
+        let frame = interface.device.receive().await;
+        eth::handle(&interface, frame).await;
+    }
+}
+
+pub fn register_commands() {
+    shell::register_async(
+        "ping",
+        "send ICMP echo requests: ping <ip> [count]",
+        |args, out| Box::pin(icmp::ping(args, out)),
+    );
+    shell::register(
+        "ifconfig",
+        "show the network interfaces: ifconfig [name]",
+        |args, out| {
+            let interfaces = match args.get(1) {
+                Some(name) => match interface(name) {
+                    Some(interface) => vec![interface],
+                    None => return writeln!(out, "ifconfig: no interface {}", name),
+                },
+                None => interfaces(),
+            };
+            for interface in interfaces {
+                write_interface(&interface, out)?;
+            }
+            Ok(())
+        },
+    );
+}
+
+fn write_interface(interface: &Interface, out: &mut dyn fmt::Write) -> fmt::Result {
+    let device = interface.device();
+    write!(
+        out,
+        "{}: {}",
+        interface.name(),
+        if device.link_up() { "up" } else { "down" }
+    )?;
+    if let Some(speed) = device.link_speed() {
+        write!(out, " {} Mbit/s", speed)?;
+    }
+    writeln!(out, ", mtu {}", device.mtu())?;
+
+    if device.is_loopback() {
+        writeln!(out, "    loopback")?;
+    } else {
+        writeln!(out, "    ether {}", device.mac_address())?;
+    }
+    if let Some(config) = interface.ipv4_config() {
+        write!(out, "    inet {}/{}", config.address, config.prefix_len)?;
+        if let Some(gateway) = config.gateway {
+            write!(out, " gateway {}", gateway)?;
+        }
+        writeln!(out)?;
+    }
+    if let Some(stats) = device.stats() {
+        writeln!(
+            out,
+            "    rx {} packets, {} bytes, {} errors, {} dropped",
+            stats.rx_packets, stats.rx_bytes, stats.rx_errors, stats.rx_dropped
+        )?;
+        writeln!(
+            out,
+            "    tx {} packets, {} bytes, {} errors, {} collisions",
+            stats.tx_packets, stats.tx_bytes, stats.tx_errors, stats.collisions
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,343 @@
+// ARP (RFC 826): finding the MAC address that belongs to an IPv4 address on
+// the local network. To send to an address the cache doesn't know yet, the
+// packet is queued and a request is broadcast ("who has 10.0.2.2?"); the owner
+// replies, which resolves the address and sends the queued packets.
+//
+// Each interface has a cache of its own. Resolved addresses are forgotten
+// after `LIFETIME`, so that a host that changed cards is found again. There is
+// no timer to retry requests with: a request is repeated when another packet
+// is sent to an address that still isn't resolved after `RETRY_INTERVAL`, and
+// the packets queued for it are dropped after `GIVE_UP`.
+
+use super::{eth, Interface, Ipv4Address, MacAddress, NetError, PacketBuf};
+use crate::time::{self, TIMER_HZ};
+use alloc::{collections::BTreeMap, collections::VecDeque, vec::Vec};
+
+/// The length of an ARP packet for IPv4 over Ethernet.
+pub const PACKET_LEN: usize = 28;
+
+pub const OPERATION_REQUEST: u16 = 1;
+pub const OPERATION_REPLY: u16 = 2;
+
+const HARDWARE_ETHERNET: u16 = 1;
+
+/// How long a resolved address is used, in ticks.
+pub const LIFETIME: u64 = 5 * 60 * TIMER_HZ;
+
+/// How long to wait for a reply before asking again, in ticks.
+pub const RETRY_INTERVAL: u64 = TIMER_HZ;
+
+/// How long packets wait for an address to be resolved, in ticks.
+pub const GIVE_UP: u64 = 3 * TIMER_HZ;
+
+/// How many packets can wait for an address.
+pub const MAX_PENDING: usize = 8;
+
+/// How many addresses a cache holds.
+pub const MAX_ENTRIES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Packet {
+    pub operation: u16,
+    pub sender_mac: MacAddress,
+    pub sender_ip: Ipv4Address,
+    pub target_mac: MacAddress,
+    pub target_ip: Ipv4Address,
+}
+
+impl Packet {
+    /// Parses an ARP packet for IPv4 over Ethernet, ignoring any padding
+    /// after it.
+    pub fn parse(data: &[u8]) -> Option<Packet> {
+        if data.len() < PACKET_LEN {
+            return None;
+        }
+        let u16_at = |offset: usize| u16::from_be_bytes([data[offset], data[offset + 1]]);
+        if u16_at(0) != HARDWARE_ETHERNET
+            || u16_at(2) != eth::ETHERTYPE_IPV4
+            || data[4] != 6
+            || data[5] != 4
+        {
+            return None;
+        }
+
+        let mut packet = Packet {
+            operation: u16_at(6),
+            sender_mac: MacAddress::default(),
+            sender_ip: Ipv4Address::default(),
+            target_mac: MacAddress::default(),
+            target_ip: Ipv4Address::default(),
+        };
+        packet.sender_mac.0.copy_from_slice(&data[8..14]);
+        packet.sender_ip.0.copy_from_slice(&data[14..18]);
+        packet.target_mac.0.copy_from_slice(&data[18..24]);
+        packet.target_ip.0.copy_from_slice(&data[24..28]);
+        Some(packet)
+    }
+
+    /// Writes the packet into the first `PACKET_LEN` bytes of `data`.
+    pub fn write(&self, data: &mut [u8]) {
+        data[0..2].copy_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+        data[2..4].copy_from_slice(&eth::ETHERTYPE_IPV4.to_be_bytes());
+        data[4] = 6;
+        data[5] = 4;
+        data[6..8].copy_from_slice(&self.operation.to_be_bytes());
+        data[8..14].copy_from_slice(&self.sender_mac.0);
+        data[14..18].copy_from_slice(&self.sender_ip.0);
+        data[18..24].copy_from_slice(&self.target_mac.0);
+        data[24..28].copy_from_slice(&self.target_ip.0);
+    }
+
+    fn to_buf(self) -> PacketBuf {
+        let mut buf = PacketBuf::new();
+        self.write(buf.push(PACKET_LEN));
+        buf
+    }
+}
+
+enum Entry {
+    Resolved {
+        mac: MacAddress,
+        learned_at: u64,
+    },
+    Pending {
+        packets: VecDeque<(u16, PacketBuf)>,
+        first_request: u64,
+        last_request: u64,
+    },
+}
+
+impl Entry {
+    fn is_stale(&self, now: u64) -> bool {
+        match *self {
+            Entry::Resolved { learned_at, .. } => now - learned_at >= LIFETIME,
+            Entry::Pending { first_request, .. } => now - first_request >= GIVE_UP,
+        }
+    }
+
+    fn age(&self, now: u64) -> u64 {
+        match *self {
+            Entry::Resolved { learned_at, .. } => now - learned_at,
+            Entry::Pending { first_request, .. } => now - first_request,
+        }
+    }
+}
+
+/// What to do with a packet for an address.
+enum Resolution {
+    Resolved(MacAddress, PacketBuf),
+    Pending { queued: bool, request: bool },
+}
+
+/// The ARP cache of an interface.
+pub(super) struct Cache {
+    entries: BTreeMap<Ipv4Address, Entry>,
+}
+
+impl Cache {
+    pub(super) fn new() -> Cache {
+        Cache {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    fn lookup(&self, address: Ipv4Address, now: u64) -> Option<MacAddress> {
+        match self.entries.get(&address)? {
+            entry @ Entry::Resolved { mac, .. } if !entry.is_stale(now) => Some(*mac),
+            _ => None,
+        }
+    }
+
+    /// Returns the MAC address to send the packet to, or queues it until
+    /// there is one.
+    fn resolve(
+        &mut self,
+        address: Ipv4Address,
+        ethertype: u16,
+        packet: PacketBuf,
+        now: u64,
+    ) -> Resolution {
+        if let Some(mac) = self.lookup(address, now) {
+            return Resolution::Resolved(mac, packet);
+        }
+
+        match self.entries.get_mut(&address) {
+            Some(Entry::Pending {
+                packets,
+                first_request,
+                last_request,
+            }) if now - *first_request < GIVE_UP => {
+                let request = now - *last_request >= RETRY_INTERVAL;
+                if request {
+                    *last_request = now;
+                }
+                let queued = packets.len() < MAX_PENDING;
+                if queued {
+                    packets.push_back((ethertype, packet));
+                }
+                Resolution::Pending { queued, request }
+            }
+            _ => {
+                let mut packets = VecDeque::new();
+                packets.push_back((ethertype, packet));
+                let entry = Entry::Pending {
+                    packets,
+                    first_request: now,
+                    last_request: now,
+                };
+                self.insert(address, entry, now);
+                Resolution::Pending {
+                    queued: true,
+                    request: true,
+                }
+            }
+        }
+    }
+
+    /// Records that `address` belongs to `mac`, and returns the packets that
+    /// were waiting for it, unless they were given up on. Only updates
+    /// addresses the cache already has, unless `create` is set.
+    fn learn(
+        &mut self,
+        address: Ipv4Address,
+        mac: MacAddress,
+        create: bool,
+        now: u64,
+    ) -> Vec<(u16, PacketBuf)> {
+        if !create && !self.entries.contains_key(&address) {
+            return Vec::new();
+        }
+
+        let entry = Entry::Resolved {
+            mac,
+            learned_at: now,
+        };
+        match self.insert(address, entry, now) {
+            Some(Entry::Pending {
+                packets,
+                first_request,
+                ..
+            }) if now - first_request < GIVE_UP => packets.into(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Inserts an entry, making room for it if the cache is full: stale
+    /// entries go first, then the oldest one.
+    fn insert(&mut self, address: Ipv4Address, entry: Entry, now: u64) -> Option<Entry> {
+        if self.entries.len() >= MAX_ENTRIES && !self.entries.contains_key(&address) {
+            self.entries.retain(|_, entry| !entry.is_stale(now));
+        }
+        if self.entries.len() >= MAX_ENTRIES && !self.entries.contains_key(&address) {
+            let oldest = self
+                .entries
+                .iter()
+                .max_by_key(|(_, entry)| entry.age(now))
+                .map(|(&address, _)| address);
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(address, entry)
+    }
+}
+
+/// Returns the MAC address of `address`, if the interface's cache has it.
+pub fn lookup(interface: &Interface, address: Ipv4Address) -> Option<MacAddress> {
+    let now = time::ticks();
+    interface.with_arp(|cache| cache.lookup(address, now))
+}
+
+/// Sends an IPv4 (or other `ethertype`) packet to `destination` on the
+/// interface's network, resolving its MAC address first if needed.
+///
+/// Returns once the packet is sent or queued; `NetError::Unresolved` means the
+/// queue for `destination` was full and the packet was dropped.
+pub async fn send(
+    interface: &Interface,
+    destination: Ipv4Address,
+    ethertype: u16,
+    packet: PacketBuf,
+) -> Result<(), NetError> {
+    if destination == Ipv4Address::BROADCAST {
+        return eth::send(interface, MacAddress::BROADCAST, ethertype, packet).await;
+    }
+    // Everything sent on a loopback device comes back to us anyway
+    if interface.device().is_loopback() {
+        return eth::send(interface, interface.mac_address(), ethertype, packet).await;
+    }
+    let source = interface.ipv4_address().ok_or(NetError::NoAddress)?;
+
+    let now = time::ticks();
+    let resolution = interface.with_arp(|cache| cache.resolve(destination, ethertype, packet, now));
+    match resolution {
+        Resolution::Resolved(mac, packet) => eth::send(interface, mac, ethertype, packet).await,
+        Resolution::Pending { queued, request } => {
+            if request {
+                let request = Packet {
+                    operation: OPERATION_REQUEST,
+                    sender_mac: interface.mac_address(),
+                    sender_ip: source,
+                    target_mac: MacAddress::default(),
+                    target_ip: destination,
+                };
+                eth::send(
+                    interface,
+                    MacAddress::BROADCAST,
+                    eth::ETHERTYPE_ARP,
+                    request.to_buf(),
+                )
+                .await?;
+            }
+            if queued {
+                Ok(())
+            } else {
+                Err(NetError::Unresolved)
+            }
+        }
+    }
+}
+
+/// Handles a received ARP packet: learns the sender's address, sends what was
+/// waiting for it, and answers requests for the interface's address.
+pub async fn handle(interface: &Interface, frame: PacketBuf) {
+    let packet = match Packet::parse(&frame) {
+        Some(packet) => packet,
+        None => return,
+    };
+
+    let our_address = match interface.ipv4_address() {
+        Some(address) => address,
+        None => return,
+    };
+    let for_us = packet.target_ip == our_address;
+
+    // Probes (RFC 5227) come from 0.0.0.0, which isn't anyone's address. As
+    // the RFC says, only add a new entry if the sender is talking to us, but
+    // update one we have either way.
+    if packet.sender_ip != Ipv4Address::UNSPECIFIED && !packet.sender_mac.is_multicast() {
+        let now = time::ticks();
+        let pending = interface
+            .with_arp(|cache| cache.learn(packet.sender_ip, packet.sender_mac, for_us, now));
+        for (ethertype, queued) in pending {
+            let _ = eth::send(interface, packet.sender_mac, ethertype, queued).await;
+        }
+    }
+
+    if for_us && packet.operation == OPERATION_REQUEST {
+        let reply = Packet {
+            operation: OPERATION_REPLY,
+            sender_mac: interface.mac_address(),
+            sender_ip: our_address,
+            target_mac: packet.sender_mac,
+            target_ip: packet.sender_ip,
+        };
+        let _ = eth::send(
+            interface,
+            packet.sender_mac,
+            eth::ETHERTYPE_ARP,
+            reply.to_buf(),
+        )
+        .await;
+    }
+}
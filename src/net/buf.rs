@@ -0,0 +1,192 @@
+// Packet buffers. A `PacketBuf` holds one frame in a fixed-size buffer, with
+// room in front of the data ("headroom") so that each layer can put its header
+// in front of its payload without copying it: UDP writes the payload, then IPv4
+// and Ethernet `push` their headers in front. On the way up, each layer `pull`s
+// its header off the front and passes the rest on.
+//
+// Buffers are big enough for any Ethernet frame, and are kept in a pool when
+// they are dropped, so that a busy network doesn't keep the heap allocator
+// busy too. The pool is bounded, so a burst doesn't keep memory forever.
+// The buffers are charged to the network in `heap`, whoever allocates them.
+
+use crate::allocator::accounting::{Charged, Subsystem};
+use alloc::{boxed::Box, vec::Vec};
+use core::{fmt, ops};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// The size of a buffer: an Ethernet frame, headers and all, and some.
+pub const CAPACITY: usize = 2048;
+
+/// The headroom of new buffers, enough for the headers of all layers.
+pub const DEFAULT_HEADROOM: usize = 128;
+
+/// How many free buffers the pool holds on to.
+pub const POOL_SIZE: usize = 64;
+
+type Storage = Box<[u8; CAPACITY], Charged>;
+
+static POOL: Mutex<Vec<Storage>> = Mutex::new(Vec::new());
+
+fn take_storage() -> Storage {
+    interrupts::without_interrupts(|| POOL.lock().pop())
+        .unwrap_or_else(|| Box::new_in([0; CAPACITY], Charged(Subsystem::Net)))
+}
+
+/// The number of free buffers in the pool.
+pub fn pooled() -> usize {
+    interrupts::without_interrupts(|| POOL.lock().len())
+}
+
+pub struct PacketBuf {
+    // Only `None` while being dropped
+    storage: Option<Storage>,
+    start: usize,
+    end: usize,
+}
+
+impl PacketBuf {
+    /// An empty buffer with `DEFAULT_HEADROOM`.
+    pub fn new() -> PacketBuf {
+        PacketBuf::with_headroom(DEFAULT_HEADROOM)
+    }
+
+    /// An empty buffer with the given headroom.
+    pub fn with_headroom(headroom: usize) -> PacketBuf {
+        assert!(headroom <= CAPACITY, "headroom larger than a packet buffer");
+        PacketBuf {
+            storage: Some(take_storage()),
+            start: headroom,
+            end: headroom,
+        }
+    }
+
+    /// A buffer holding a copy of `data`, with no headroom, e.g. for a
+    /// received frame.
+    ///
+    /// Panics if `data` doesn't fit.
+    pub fn from_slice(data: &[u8]) -> PacketBuf {
+        let mut buf = PacketBuf::with_headroom(0);
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    fn storage(&self) -> &[u8; CAPACITY] {
+        self.storage.as_ref().unwrap()
+    }
+
+    fn storage_mut(&mut self) -> &mut [u8; CAPACITY] {
+        self.storage.as_mut().unwrap()
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// How much can be pushed in front of the data.
+    pub fn headroom(&self) -> usize {
+        self.start
+    }
+
+    /// How much can be appended to the data.
+    pub fn tailroom(&self) -> usize {
+        CAPACITY - self.end
+    }
+
+    /// Makes room for a header of `len` bytes in front of the data, and
+    /// returns it, zeroed, for the caller to fill in.
+    ///
+    /// Panics if there isn't enough headroom.
+    pub fn push(&mut self, len: usize) -> &mut [u8] {
+        assert!(len <= self.start, "not enough headroom in packet buffer");
+        self.start -= len;
+
+        let (start, end) = (self.start, self.start + len);
+        let header = &mut self.storage_mut()[start..end];
+        header.fill(0);
+        header
+    }
+
+    /// Removes a header of `len` bytes from the front of the data, and
+    /// returns it. Returns `None`, and leaves the data alone, if there are
+    /// fewer than `len` bytes.
+    pub fn pull(&mut self, len: usize) -> Option<&[u8]> {
+        if len > self.len() {
+            return None;
+        }
+        self.start += len;
+
+        let start = self.start;
+        Some(&self.storage()[start - len..start])
+    }
+
+    /// Appends `data`.
+    ///
+    /// Panics if there isn't enough tailroom.
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        assert!(data.len() <= self.tailroom(), "packet buffer overflow");
+        let (start, end) = (self.end, self.end + data.len());
+        self.storage_mut()[start..end].copy_from_slice(data);
+        self.end = end;
+    }
+
+    /// Cuts the data off after `len` bytes, e.g. to drop the padding of a
+    /// short frame.
+    pub fn truncate(&mut self, len: usize) {
+        self.end = self.end.min(self.start + len);
+    }
+}
+
+impl Default for PacketBuf {
+    fn default() -> Self {
+        PacketBuf::new()
+    }
+}
+
+impl Clone for PacketBuf {
+    fn clone(&self) -> Self {
+        let mut buf = PacketBuf::with_headroom(self.start);
+        buf.extend_from_slice(self);
+        buf
+    }
+}
+
+impl Drop for PacketBuf {
+    fn drop(&mut self) {
+        let storage = self.storage.take().unwrap();
+        interrupts::without_interrupts(|| {
+            let mut pool = POOL.lock();
+            if pool.len() < POOL_SIZE {
+                pool.push(storage);
+            }
+        });
+    }
+}
+
+impl ops::Deref for PacketBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.storage()[self.start..self.end]
+    }
+}
+
+impl ops::DerefMut for PacketBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        let (start, end) = (self.start, self.end);
+        &mut self.storage_mut()[start..end]
+    }
+}
+
+impl fmt::Debug for PacketBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PacketBuf")
+            .field("headroom", &self.headroom())
+            .field("len", &self.len())
+            .finish()
+    }
+}
@@ -0,0 +1,84 @@
+// Ethernet. A frame is a 14 byte header (destination and source MAC address,
+// and the type of the payload) followed by the payload; the device takes care
+// of the preamble and the checksum.
+//
+// Received frames for us (or for everyone) are handed to the layer their
+// ethertype names. Frames for other cards, which a switch or a card in
+// promiscuous mode can let through, and unknown ethertypes are dropped.
+
+use super::{arp, ipv4, Interface, MacAddress, NetError, PacketBuf};
+
+pub const HEADER_LEN: usize = 14;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub destination: MacAddress,
+    pub source: MacAddress,
+    pub ethertype: u16,
+}
+
+impl Header {
+    /// Pulls the header off the front of `frame`.
+    pub fn pull(frame: &mut PacketBuf) -> Option<Header> {
+        let header = frame.pull(HEADER_LEN)?;
+        let mut destination = MacAddress::default();
+        let mut source = MacAddress::default();
+        destination.0.copy_from_slice(&header[0..6]);
+        source.0.copy_from_slice(&header[6..12]);
+
+        Some(Header {
+            destination,
+            source,
+            ethertype: u16::from_be_bytes([header[12], header[13]]),
+        })
+    }
+
+    /// Pushes the header in front of the payload in `frame`.
+    pub fn push(&self, frame: &mut PacketBuf) {
+        let header = frame.push(HEADER_LEN);
+        header[0..6].copy_from_slice(&self.destination.0);
+        header[6..12].copy_from_slice(&self.source.0);
+        header[12..14].copy_from_slice(&self.ethertype.to_be_bytes());
+    }
+}
+
+/// Sends `payload` to `destination` in a frame from the interface.
+pub async fn send(
+    interface: &Interface,
+    destination: MacAddress,
+    ethertype: u16,
+    mut payload: PacketBuf,
+) -> Result<(), NetError> {
+    let device = interface.device();
+    if payload.len() > device.mtu() {
+        return Err(NetError::TooLarge);
+    }
+
+    let header = Header {
+        destination,
+        source: device.mac_address(),
+        ethertype,
+    };
+    header.push(&mut payload);
+    device.send(payload).await
+}
+
+/// Handles a frame the interface received.
+pub async fn handle(interface: &Interface, mut frame: PacketBuf) {
+    let header = match Header::pull(&mut frame) {
+        Some(header) => header,
+        None => return,
+    };
+    if header.destination != interface.mac_address() && !header.destination.is_broadcast() {
+        return;
+    }
+
+    match header.ethertype {
+        ETHERTYPE_ARP => arp::handle(interface, frame).await,
+        ETHERTYPE_IPV4 => ipv4::handle(interface, frame).await,
+        _ => {}
+    }
+}
@@ -0,0 +1,289 @@
+// ICMP (RFC 792), for now only echo requests and replies, i.e. ping. Requests
+// for our address are answered with the same identifier, sequence number and
+// data. Replies go to the `EchoSocket` with their identifier, if there is one.
+//
+// Like most systems, we don't answer pings to a broadcast address.
+
+use super::{ipv4, Interface, Ipv4Address, NetError, PacketBuf};
+use crate::time::{self, TIMER_HZ};
+use alloc::{collections::BTreeMap, collections::VecDeque};
+use core::fmt::{self, Write};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU16, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const HEADER_LEN: usize = 8;
+
+pub const TYPE_ECHO_REPLY: u8 = 0;
+pub const TYPE_ECHO_REQUEST: u8 = 8;
+
+/// How many replies a socket keeps before it drops new ones.
+const MAX_QUEUED_REPLIES: usize = 16;
+
+/// The header of an echo request or reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EchoHeader {
+    pub kind: u8,
+    pub identifier: u16,
+    pub sequence: u16,
+}
+
+impl EchoHeader {
+    /// Pulls the header off the front of `packet`, if it's an echo request
+    /// or reply with the right checksum.
+    pub fn pull(packet: &mut PacketBuf) -> Option<EchoHeader> {
+        if packet.len() < HEADER_LEN || ipv4::checksum(packet) != 0 {
+            return None;
+        }
+        let header = packet.pull(HEADER_LEN)?;
+        let kind = header[0];
+        if (kind != TYPE_ECHO_REQUEST && kind != TYPE_ECHO_REPLY) || header[1] != 0 {
+            return None;
+        }
+        Some(EchoHeader {
+            kind,
+            identifier: u16::from_be_bytes([header[4], header[5]]),
+            sequence: u16::from_be_bytes([header[6], header[7]]),
+        })
+    }
+
+    /// Pushes the header in front of the data in `packet`.
+    pub fn push(&self, packet: &mut PacketBuf) {
+        let header = packet.push(HEADER_LEN);
+        header[0] = self.kind;
+        header[4..6].copy_from_slice(&self.identifier.to_be_bytes());
+        header[6..8].copy_from_slice(&self.sequence.to_be_bytes());
+
+        let checksum = ipv4::checksum(packet);
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+}
+
+/// An echo reply that an `EchoSocket` received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EchoReply {
+    pub source: Ipv4Address,
+    pub sequence: u16,
+    pub ttl: u8,
+    /// The length of the data.
+    pub len: usize,
+    /// When it was received, see `time::tsc`.
+    pub received_at: u64,
+}
+
+#[derive(Default)]
+struct Waiter {
+    replies: VecDeque<EchoReply>,
+    waker: Option<Waker>,
+}
+
+static SOCKETS: Mutex<BTreeMap<u16, Waiter>> = Mutex::new(BTreeMap::new());
+
+/// Sends echo requests, and receives the replies to them.
+pub struct EchoSocket {
+    identifier: u16,
+}
+
+impl EchoSocket {
+    /// Opens a socket with an identifier that no other socket has.
+    pub fn open() -> EchoSocket {
+        static NEXT_IDENTIFIER: AtomicU16 = AtomicU16::new(1);
+
+        interrupts::without_interrupts(|| {
+            let mut sockets = SOCKETS.lock();
+            let identifier = loop {
+                let identifier = NEXT_IDENTIFIER.fetch_add(1, Ordering::Relaxed);
+                if !sockets.contains_key(&identifier) {
+                    break identifier;
+                }
+            };
+            sockets.insert(identifier, Waiter::default());
+            EchoSocket { identifier }
+        })
+    }
+
+    pub fn identifier(&self) -> u16 {
+        self.identifier
+    }
+
+    /// Sends an echo request with `data` to `destination`.
+    pub async fn send(
+        &self,
+        destination: Ipv4Address,
+        sequence: u16,
+        data: &[u8],
+    ) -> Result<(), NetError> {
+        let mut packet = PacketBuf::new();
+        packet.extend_from_slice(data);
+        let header = EchoHeader {
+            kind: TYPE_ECHO_REQUEST,
+            identifier: self.identifier,
+            sequence,
+        };
+        header.push(&mut packet);
+        ipv4::send(destination, ipv4::PROTOCOL_ICMP, packet).await
+    }
+
+    /// Returns the next reply, if one was received.
+    pub fn try_receive(&self) -> Option<EchoReply> {
+        interrupts::without_interrupts(|| {
+            let mut sockets = SOCKETS.lock();
+            sockets.get_mut(&self.identifier)?.replies.pop_front()
+        })
+    }
+
+    /// Waits for the next reply.
+    pub fn receive(&self) -> Receive<'_> {
+        Receive { socket: self }
+    }
+}
+
+impl Drop for EchoSocket {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| SOCKETS.lock().remove(&self.identifier));
+    }
+}
+
+/// A future for the next reply of an `EchoSocket`.
+pub struct Receive<'a> {
+    socket: &'a EchoSocket,
+}
+
+impl Future for Receive<'_> {
+    type Output = EchoReply;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<EchoReply> {
+        let identifier = self.socket.identifier;
+        interrupts::without_interrupts(|| {
+            let mut sockets = SOCKETS.lock();
+            let waiter = sockets.get_mut(&identifier).unwrap();
+            match waiter.replies.pop_front() {
+                Some(reply) => Poll::Ready(reply),
+                None => {
+                    waiter.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+    }
+}
+
+/// Handles an ICMP message the interface received.
+pub async fn handle(interface: &Interface, ip_header: &ipv4::Header, mut packet: PacketBuf) {
+    let header = match EchoHeader::pull(&mut packet) {
+        Some(header) => header,
+        None => return,
+    };
+
+    match header.kind {
+        TYPE_ECHO_REQUEST if Some(ip_header.destination) == interface.ipv4_address() => {
+            let reply = EchoHeader {
+                kind: TYPE_ECHO_REPLY,
+                ..header
+            };
+            let mut data = PacketBuf::new();
+            data.extend_from_slice(&packet);
+            reply.push(&mut data);
+            let _ = ipv4::send_on(interface, ip_header.source, ipv4::PROTOCOL_ICMP, data).await;
+        }
+        TYPE_ECHO_REPLY => {
+            let reply = EchoReply {
+                source: ip_header.source,
+                sequence: header.sequence,
+                ttl: ip_header.ttl,
+                len: packet.len(),
+                received_at: time::tsc(),
+            };
+            let waker = interrupts::without_interrupts(|| {
+                let mut sockets = SOCKETS.lock();
+                let waiter = sockets.get_mut(&header.identifier)?;
+                if waiter.replies.len() < MAX_QUEUED_REPLIES {
+                    waiter.replies.push_back(reply);
+                }
+                waiter.waker.take()
+            });
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The `ping` shell command: sends an echo request a second, and prints the
+/// round trip time of the replies.
+pub async fn ping(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    const DATA_LEN: usize = 56;
+
+    let destination: Ipv4Address = match args.get(1).map(|arg| arg.parse()) {
+        Some(Ok(address)) => address,
+        _ => return writeln!(out, "usage: ping <ip> [count]"),
+    };
+    let count: u16 = match args.get(2).map(|arg| arg.parse()) {
+        None => 4,
+        Some(Ok(count)) => count,
+        Some(Err(_)) => return writeln!(out, "usage: ping <ip> [count]"),
+    };
+
+    writeln!(out, "PING {} {} data bytes", destination, DATA_LEN)?;
+    let socket = EchoSocket::open();
+    let data = [0x42; DATA_LEN];
+    let mut received = 0;
+
+    for sequence in 0..count {
+        let next = time::ticks() + TIMER_HZ;
+        let sent_at = time::tsc();
+        // The first requests may only be queued until the destination's (or
+        // the gateway's) MAC address is known, which is fine.
+        if let Err(error) = socket.send(destination, sequence, &data).await {
+            writeln!(out, "ping: {}", error)?;
+            return Ok(());
+        }
+
+        let reply = time::timeout(TIMER_HZ, async {
+            loop {
+                let reply = socket.receive().await;
+                if reply.sequence == sequence {
+                    return reply;
+                }
+            }
+        })
+        .await;
+
+        match reply {
+            Some(reply) => {
+                received += 1;
+                let us = time::tsc_to_us(reply.received_at - sent_at).unwrap_or(0);
+                writeln!(
+                    out,
+                    "{} bytes from {}: icmp_seq={} ttl={} time={}.{:03} ms",
+                    reply.len + HEADER_LEN,
+                    reply.source,
+                    reply.sequence,
+                    reply.ttl,
+                    us / 1000,
+                    us % 1000
+                )?;
+            }
+            None => writeln!(out, "request timeout for icmp_seq {}", sequence)?,
+        }
+
+        if sequence + 1 < count {
+            time::sleep_until(next).await;
+        }
+    }
+
+    let loss = if count == 0 {
+        0
+    } else {
+        100 - received * 100 / u32::from(count)
+    };
+    writeln!(
+        out,
+        "{} packets transmitted, {} received, {}% packet loss",
+        count, received, loss
+    )
+}
@@ -0,0 +1,252 @@
+// IPv4 (RFC 791). Packets get a 20 byte header without options, and are sent
+// to the destination directly if it's on the network of an interface, or to
+// that interface's gateway otherwise.
+//
+// There is no fragmentation: packets that don't fit into the MTU aren't sent
+// (`NetError::TooLarge`), and received fragments are dropped. Packets are
+// sent with the "don't fragment" flag, so that routers on the way tell us
+// about a smaller MTU instead of fragmenting them.
+
+use super::{arp, eth, icmp, interfaces, udp, Interface, Ipv4Address, NetError, PacketBuf};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+pub const HEADER_LEN: usize = 20;
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_UDP: u8 = 17;
+
+/// The time to live of sent packets, i.e. how many routers they may pass.
+pub const DEFAULT_TTL: u8 = 64;
+
+const VERSION: u8 = 4;
+const FLAG_DONT_FRAGMENT: u16 = 1 << 14;
+const FLAG_MORE_FRAGMENTS: u16 = 1 << 13;
+const FRAGMENT_OFFSET: u16 = 0x1FFF;
+
+/// How an interface is set up for IPv4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub address: Ipv4Address,
+    /// The length of the network part of the address, e.g. 24 for a
+    /// 255.255.255.0 netmask.
+    pub prefix_len: u8,
+    /// The router to addresses outside of the network.
+    pub gateway: Option<Ipv4Address>,
+}
+
+impl Config {
+    pub fn netmask(&self) -> Ipv4Address {
+        let mask = u32::MAX
+            .checked_shl(32 - u32::from(self.prefix_len))
+            .unwrap_or(0);
+        Ipv4Address::from(mask)
+    }
+
+    /// The address that reaches all hosts on the network.
+    pub fn broadcast(&self) -> Ipv4Address {
+        Ipv4Address::from(u32::from(self.address) | !u32::from(self.netmask()))
+    }
+
+    /// Whether `address` is on the network.
+    pub fn contains(&self, address: Ipv4Address) -> bool {
+        let netmask = u32::from(self.netmask());
+        u32::from(address) & netmask == u32::from(self.address) & netmask
+    }
+
+    /// The address to send a packet for `destination` to: the destination
+    /// itself if it's on the network, the gateway otherwise.
+    pub fn next_hop(&self, destination: Ipv4Address) -> Option<Ipv4Address> {
+        if destination == Ipv4Address::BROADCAST || self.contains(destination) {
+            Some(destination)
+        } else {
+            self.gateway
+        }
+    }
+}
+
+/// The internet checksum (RFC 1071): the ones' complement of the ones'
+/// complement sum of the data's 16 bit words.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Checksum {
+    sum: u32,
+}
+
+impl Checksum {
+    pub fn new() -> Checksum {
+        Checksum { sum: 0 }
+    }
+
+    /// Adds `data` to the sum. Only the last part of the data may have an odd
+    /// length.
+    pub fn add(&mut self, data: &[u8]) {
+        let mut words = data.chunks_exact(2);
+        for word in &mut words {
+            self.add_word(u16::from_be_bytes([word[0], word[1]]));
+        }
+        if let [last] = words.remainder() {
+            self.add_word(u16::from_be_bytes([*last, 0]));
+        }
+    }
+
+    fn add_word(&mut self, word: u16) {
+        self.sum += u32::from(word);
+        self.sum = (self.sum & 0xFFFF) + (self.sum >> 16);
+    }
+
+    pub fn finish(&self) -> u16 {
+        !(self.sum as u16)
+    }
+}
+
+/// The internet checksum of `data`. Data that includes its (correct) checksum
+/// sums up to zero.
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut checksum = Checksum::new();
+    checksum.add(data);
+    checksum.finish()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub source: Ipv4Address,
+    pub destination: Ipv4Address,
+    pub protocol: u8,
+    pub ttl: u8,
+    pub identification: u16,
+}
+
+impl Header {
+    /// Pulls the header (and any options) off the front of `packet`, and
+    /// cuts off anything after the payload, like the padding of a short
+    /// Ethernet frame. Returns `None` for packets that are broken or
+    /// fragmented.
+    pub fn pull(packet: &mut PacketBuf) -> Option<Header> {
+        let data = &packet[..];
+        if data.len() < HEADER_LEN || data[0] >> 4 != VERSION {
+            return None;
+        }
+        let header_len = usize::from(data[0] & 0xF) * 4;
+        let total_len = usize::from(u16::from_be_bytes([data[2], data[3]]));
+        if header_len < HEADER_LEN
+            || total_len < header_len
+            || total_len > data.len()
+            || checksum(&data[..header_len]) != 0
+        {
+            return None;
+        }
+
+        let flags = u16::from_be_bytes([data[6], data[7]]);
+        if flags & FLAG_MORE_FRAGMENTS != 0 || flags & FRAGMENT_OFFSET != 0 {
+            return None;
+        }
+
+        let mut header = Header {
+            source: Ipv4Address::default(),
+            destination: Ipv4Address::default(),
+            protocol: data[9],
+            ttl: data[8],
+            identification: u16::from_be_bytes([data[4], data[5]]),
+        };
+        header.source.0.copy_from_slice(&data[12..16]);
+        header.destination.0.copy_from_slice(&data[16..20]);
+
+        packet.truncate(total_len);
+        packet.pull(header_len);
+        Some(header)
+    }
+
+    /// Pushes the header in front of the payload in `packet`.
+    pub fn push(&self, packet: &mut PacketBuf) {
+        let total_len = (HEADER_LEN + packet.len()) as u16;
+        let header = packet.push(HEADER_LEN);
+        header[0] = VERSION << 4 | (HEADER_LEN / 4) as u8;
+        header[2..4].copy_from_slice(&total_len.to_be_bytes());
+        header[4..6].copy_from_slice(&self.identification.to_be_bytes());
+        header[6..8].copy_from_slice(&FLAG_DONT_FRAGMENT.to_be_bytes());
+        header[8] = self.ttl;
+        header[9] = self.protocol;
+        header[12..16].copy_from_slice(&self.source.0);
+        header[16..20].copy_from_slice(&self.destination.0);
+
+        let checksum = checksum(header);
+        header[10..12].copy_from_slice(&checksum.to_be_bytes());
+    }
+}
+
+/// Picks the interface to send to `destination` through: one on the
+/// destination's network, or else one with a gateway.
+pub fn route(destination: Ipv4Address) -> Option<Arc<Interface>> {
+    let interfaces = interfaces();
+    let on_network = interfaces.iter().find(
+        |interface| matches!(interface.ipv4_config(), Some(config) if config.contains(destination)),
+    );
+    let through_gateway = || {
+        interfaces.iter().find(
+            |interface| matches!(interface.ipv4_config(), Some(config) if config.gateway.is_some()),
+        )
+    };
+    on_network.or_else(through_gateway).cloned()
+}
+
+/// Sends `payload` to `destination`, through the interface `route` picks.
+pub async fn send(
+    destination: Ipv4Address,
+    protocol: u8,
+    payload: PacketBuf,
+) -> Result<(), NetError> {
+    let interface = route(destination).ok_or(NetError::NoRoute)?;
+    send_on(&interface, destination, protocol, payload).await
+}
+
+/// Sends `payload` to `destination` through `interface`, e.g. to answer
+/// through the interface that a request came in on.
+pub async fn send_on(
+    interface: &Interface,
+    destination: Ipv4Address,
+    protocol: u8,
+    mut payload: PacketBuf,
+) -> Result<(), NetError> {
+    static NEXT_IDENTIFICATION: AtomicU16 = AtomicU16::new(0);
+
+    let config = interface.ipv4_config().ok_or(NetError::NoAddress)?;
+    let next_hop = config.next_hop(destination).ok_or(NetError::NoRoute)?;
+    if HEADER_LEN + payload.len() > interface.device().mtu() {
+        return Err(NetError::TooLarge);
+    }
+
+    let header = Header {
+        source: config.address,
+        destination,
+        protocol,
+        ttl: DEFAULT_TTL,
+        identification: NEXT_IDENTIFICATION.fetch_add(1, Ordering::Relaxed),
+    };
+    header.push(&mut payload);
+    arp::send(interface, next_hop, eth::ETHERTYPE_IPV4, payload).await
+}
+
+/// Handles a packet the interface received.
+pub async fn handle(interface: &Interface, mut packet: PacketBuf) {
+    let header = match Header::pull(&mut packet) {
+        Some(header) => header,
+        None => return,
+    };
+    let config = match interface.ipv4_config() {
+        Some(config) => config,
+        None => return,
+    };
+    let destination = header.destination;
+    if destination != config.address
+        && destination != config.broadcast()
+        && destination != Ipv4Address::BROADCAST
+    {
+        return;
+    }
+
+    match header.protocol {
+        PROTOCOL_ICMP => icmp::handle(interface, &header, packet).await,
+        PROTOCOL_UDP => udp::handle(interface, &header, packet).await,
+        _ => {}
+    }
+}
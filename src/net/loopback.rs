@@ -0,0 +1,124 @@
+// The loopback device: frames sent through it come straight back as received
+// ones, so that the stack can talk to itself, on 127.0.0.1 or any other
+// address of the interface. It needs no card, so the protocols and sockets can
+// be tested without one.
+//
+// Like on Linux, the device's MAC address is all zeros, and there's no ARP on
+// it (see `NetDevice::is_loopback`): frames go to its own address.
+//
+// `drivers::init_all` registers one as "lo", as a platform driver.
+
+use super::{
+    ipv4, DeviceStats, Ipv4Address, MacAddress, NetDevice, NetError, NetFuture, PacketBuf,
+};
+use crate::driver;
+use crate::drivers::{Driver, Probe};
+use crate::error::KernelError;
+use crate::pci;
+use alloc::{boxed::Box, collections::VecDeque, string::String, sync::Arc};
+use core::task::Poll;
+use futures_util::{future, task::AtomicWaker};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Frames are `PacketBuf`s, so there's no point in Linux's 64 KiB.
+pub const MTU: usize = 1500;
+
+/// How many frames wait to be received before new ones are dropped, like
+/// with a card's full receive ring.
+pub const MAX_QUEUED: usize = 64;
+
+pub struct Loopback {
+    frames: Mutex<VecDeque<PacketBuf>>,
+    waker: AtomicWaker,
+    stats: Mutex<DeviceStats>,
+}
+
+impl Loopback {
+    pub fn new() -> Loopback {
+        Loopback {
+            frames: Mutex::new(VecDeque::new()),
+            waker: AtomicWaker::new(),
+            stats: Mutex::new(DeviceStats::default()),
+        }
+    }
+}
+
+impl Default for Loopback {
+    fn default() -> Loopback {
+        Loopback::new()
+    }
+}
+
+impl NetDevice for Loopback {
+    fn mac_address(&self) -> MacAddress {
+        MacAddress::default()
+    }
+
+    fn mtu(&self) -> usize {
+        MTU
+    }
+
+    fn send(&self, frame: PacketBuf) -> NetFuture<'_, Result<(), NetError>> {
+        interrupts::without_interrupts(|| {
+            let mut frames = self.frames.lock();
+            let mut stats = self.stats.lock();
+            let len = frame.len() as u64;
+            stats.tx_packets += 1;
+            stats.tx_bytes += len;
+            if frames.len() < MAX_QUEUED {
+                frames.push_back(frame);
+                stats.rx_packets += 1;
+                stats.rx_bytes += len;
+            } else {
+                stats.rx_dropped += 1;
+            }
+        });
+        self.waker.wake();
+        Box::pin(future::ready(Ok(())))
+    }
+
+    fn receive(&self) -> NetFuture<'_, PacketBuf> {
+        Box::pin(future::poll_fn(move |cx| {
+            self.waker.register(cx.waker());
+            match interrupts::without_interrupts(|| self.frames.lock().pop_front()) {
+                Some(frame) => Poll::Ready(frame),
+                None => Poll::Pending,
+            }
+        }))
+    }
+
+    fn stats(&self) -> Option<DeviceStats> {
+        Some(interrupts::without_interrupts(|| *self.stats.lock()))
+    }
+
+    fn is_loopback(&self) -> bool {
+        true
+    }
+}
+
+driver! {
+    static DRIVER = Driver {
+        name: "loopback",
+        probe: Probe::Platform,
+        depends_on: &[],
+        init: probe,
+    };
+}
+
+fn probe(_: Option<&pci::Device>) -> Result<(), KernelError> {
+    register();
+    Ok(())
+}
+
+/// Registers a loopback device with 127.0.0.1/8, and returns its name.
+pub fn register() -> String {
+    let name = super::register("lo", Arc::new(Loopback::new()));
+    let interface = super::interface(&name).unwrap();
+    interface.set_ipv4_config(Some(ipv4::Config {
+        address: Ipv4Address::LOOPBACK,
+        prefix_len: 8,
+        gateway: None,
+    }));
+    name
+}
@@ -0,0 +1,612 @@
+// smoltcp (https://github.com/smoltcp-rs/smoltcp) as an alternative to our own
+// network stack, behind the "smoltcp" feature, mostly for its TCP. `run` takes
+// over an interface: its frames go to smoltcp instead of eth.rs, and its IPv4
+// configuration moves to smoltcp too, so that our own stack no longer routes
+// through it. There is one smoltcp stack, on one interface.
+//
+// smoltcp is polled: `Interface::poll` takes the received frames, hands out
+// the frames to send, and says when it wants to be polled again for its timers
+// (retransmissions, ARP requests, ...). Its `Device` is synchronous, so
+// `Frames` only queues frames, and `run` moves them between the queues and
+// the `NetDevice`. It polls whenever a frame comes in, a timer is due, or a
+// socket has something new to send.
+//
+// `TcpStream`, `TcpListener` and `UdpSocket` wrap smoltcp's sockets into async
+// APIs like udp.rs's, and wait with smoltcp's own socket wakers.
+
+use super::udp::{Endpoint, FIRST_EPHEMERAL_PORT};
+use super::{eth, ipv4, udp as our_udp, Interface, Ipv4Address, NetError, PacketBuf};
+use crate::time::{self, TIMER_HZ};
+use crate::{rand, warn};
+use alloc::{collections::BTreeSet, collections::VecDeque, sync::Arc, vec, vec::Vec};
+use core::future::Future;
+use core::mem;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
+use futures_util::future::{self, Either};
+use futures_util::task::AtomicWaker;
+use smoltcp::iface::{self, SocketHandle, SocketSet};
+use smoltcp::phy::{self, DeviceCapabilities, Medium};
+use smoltcp::socket::{tcp, udp, Socket};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{EthernetAddress, HardwareAddress, IpAddress, IpCidr, IpEndpoint};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// The size of the send and the receive buffer of a TCP connection.
+pub const TCP_BUFFER_SIZE: usize = 16 * 1024;
+
+/// How long a TCP connection waits for the peer to acknowledge anything
+/// before it gives up, e.g. for a host that doesn't answer a connect.
+pub const TCP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many datagrams a UDP socket keeps each way, and how many bytes they
+/// may have in all.
+const UDP_MAX_QUEUED: usize = our_udp::MAX_QUEUED;
+const UDP_BUFFER_SIZE: usize = 16 * 1024;
+
+const EPHEMERAL_PORTS: u16 = u16::MAX - FIRST_EPHEMERAL_PORT + 1;
+
+/// The frames between smoltcp and the device.
+struct Frames {
+    received: VecDeque<PacketBuf>,
+    to_send: VecDeque<PacketBuf>,
+    mtu: usize,
+}
+
+impl phy::Device for Frames {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(RxToken, TxToken<'_>)> {
+        let frame = self.received.pop_front()?;
+        Some((RxToken(frame), TxToken(&mut self.to_send)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<TxToken<'_>> {
+        Some(TxToken(&mut self.to_send))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.medium = Medium::Ethernet;
+        // smoltcp counts the Ethernet header in
+        capabilities.max_transmission_unit = eth::HEADER_LEN + self.mtu;
+        capabilities
+    }
+}
+
+struct RxToken(PacketBuf);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(&mut self.0)
+    }
+}
+
+struct TxToken<'a>(&'a mut VecDeque<PacketBuf>);
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut frame = PacketBuf::with_headroom(len);
+        let result = f(frame.push(len));
+        self.0.push_back(frame);
+        result
+    }
+}
+
+struct Stack {
+    interface: iface::Interface,
+    frames: Frames,
+    sockets: SocketSet<'static>,
+    /// The ports of the `TcpListener`s.
+    listening: BTreeSet<u16>,
+    /// The TCP connections that were dropped, which are removed once they're
+    /// closed.
+    closing: Vec<SocketHandle>,
+    next_ephemeral: u16,
+}
+
+static STACK: Mutex<Option<Stack>> = Mutex::new(None);
+
+/// Wakes `run`, after a socket got something to send.
+static POLL_WAKER: AtomicWaker = AtomicWaker::new();
+static POLL_WANTED: AtomicBool = AtomicBool::new(false);
+
+impl Stack {
+    fn new(interface: &Interface) -> Stack {
+        let mut frames = Frames {
+            received: VecDeque::new(),
+            to_send: VecDeque::new(),
+            mtu: interface.device().mtu(),
+        };
+        let mac = EthernetAddress(interface.mac_address().0);
+        let mut config = iface::Config::new(HardwareAddress::Ethernet(mac));
+        // For TCP's initial sequence numbers, among others
+        config.random_seed = rand::next_u64();
+        let mut smol = iface::Interface::new(config, &mut frames, now());
+
+        if let Some(ipv4) = interface.ipv4_config() {
+            smol.update_ip_addrs(|addresses| {
+                let address = IpCidr::new(to_smol_address(ipv4.address), ipv4.prefix_len);
+                let _ = addresses.push(address);
+            });
+            if let Some(gateway) = ipv4.gateway {
+                let _ = smol
+                    .routes_mut()
+                    .add_default_ipv4_route(smoltcp::wire::Ipv4Address(gateway.0));
+            }
+        }
+
+        Stack {
+            interface: smol,
+            frames,
+            sockets: SocketSet::new(Vec::new()),
+            listening: BTreeSet::new(),
+            closing: Vec::new(),
+            next_ephemeral: rand::next_u32() as u16,
+        }
+    }
+
+    /// Lets smoltcp handle the received frames and its timers. Returns the
+    /// frames to send, and how long smoltcp can wait before the next poll.
+    fn poll(&mut self) -> (Vec<PacketBuf>, Option<Duration>) {
+        let now = now();
+        self.interface
+            .poll(now, &mut self.frames, &mut self.sockets);
+
+        let sockets = &mut self.sockets;
+        self.closing.retain(|&handle| {
+            let closed = sockets.get::<tcp::Socket>(handle).state() == tcp::State::Closed;
+            if closed {
+                sockets.remove(handle);
+            }
+            !closed
+        });
+
+        let frames = self.frames.to_send.drain(..).collect();
+        (frames, self.interface.poll_delay(now, &self.sockets))
+    }
+
+    fn tcp_port_in_use(&self, port: u16) -> bool {
+        self.listening.contains(&port)
+            || self.sockets.iter().any(|(_, socket)| match socket {
+                Socket::Tcp(socket) => {
+                    matches!(socket.local_endpoint(), Some(local) if local.port == port)
+                }
+                _ => false,
+            })
+    }
+
+    fn udp_port_in_use(&self, port: u16) -> bool {
+        self.sockets.iter().any(|(_, socket)| match socket {
+            Socket::Udp(socket) => socket.endpoint().port == port,
+            _ => false,
+        })
+    }
+
+    /// Returns `port` if it's free, or a free ephemeral port if `port` is 0,
+    /// like `udp::UdpSocket::bind`.
+    fn pick_port(&mut self, port: u16, in_use: fn(&Stack, u16) -> bool) -> Result<u16, NetError> {
+        if port != 0 {
+            return if in_use(self, port) {
+                Err(NetError::AddressInUse)
+            } else {
+                Ok(port)
+            };
+        }
+        for _ in 0..EPHEMERAL_PORTS {
+            let port = FIRST_EPHEMERAL_PORT + self.next_ephemeral % EPHEMERAL_PORTS;
+            self.next_ephemeral = self.next_ephemeral.wrapping_add(1);
+            if !in_use(self, port) {
+                return Ok(port);
+            }
+        }
+        Err(NetError::AddressInUse)
+    }
+}
+
+fn now() -> Instant {
+    Instant::from_micros(time::now().as_micros() as i64)
+}
+
+fn to_smol_address(address: Ipv4Address) -> IpAddress {
+    IpAddress::Ipv4(smoltcp::wire::Ipv4Address(address.0))
+}
+
+fn to_smol(endpoint: Endpoint) -> IpEndpoint {
+    IpEndpoint::new(to_smol_address(endpoint.address), endpoint.port)
+}
+
+fn from_smol(endpoint: IpEndpoint) -> Endpoint {
+    let IpAddress::Ipv4(address) = endpoint.addr;
+    Endpoint::new(Ipv4Address(address.0), endpoint.port)
+}
+
+/// Runs `f` on the stack, or fails if `run` hasn't started it.
+fn try_with_stack<T>(f: impl FnOnce(&mut Stack) -> T) -> Result<T, NetError> {
+    interrupts::without_interrupts(|| STACK.lock().as_mut().map(f).ok_or(NetError::Down))
+}
+
+/// Runs `f` on the stack, for a socket. Sockets only exist once the stack
+/// does, and the stack stays.
+fn with_stack<T>(f: impl FnOnce(&mut Stack) -> T) -> T {
+    try_with_stack(f).expect("smoltcp socket without a stack")
+}
+
+/// Has `run` poll the stack soon, e.g. to send what a socket queued.
+fn poll_soon() {
+    POLL_WANTED.store(true, Ordering::Release);
+    POLL_WAKER.wake();
+}
+
+fn poll_wanted() -> impl Future<Output = ()> {
+    future::poll_fn(|cx| {
+        POLL_WAKER.register(cx.waker());
+        match POLL_WANTED.swap(false, Ordering::AcqRel) {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+        }
+    })
+}
+
+/// Runs smoltcp on `interface`, in place of `net::run`, forever. Returns
+/// right away if smoltcp runs on another interface already.
+pub async fn run(interface: Arc<Interface>) {
+    let started = interrupts::without_interrupts(|| {
+        let mut stack = STACK.lock();
+        if stack.is_some() {
+            return false;
+        }
+        *stack = Some(Stack::new(&interface));
+        true
+    });
+    if !started {
+        warn!(
+            "smoltcp: already running elsewhere, not on {}",
+            interface.name()
+        );
+        return;
+    }
+    interface.set_ipv4_config(None);
+
+    let device = interface.device().clone();
+    loop {
+        let (frames, delay) = with_stack(Stack::poll);
+        for frame in frames {
+            // A frame that doesn't make it is lost like on the wire, and TCP
+            // sends it again.
+            let _ = device.send(frame).await;
+        }
+
+        // Without timers, smoltcp doesn't need polling until something
+        // happens, but a poll a second doesn't hurt either. Waiting a tick
+        // at least gives the other tasks a turn.
+        let ticks = delay.map_or(TIMER_HZ, |delay| {
+            (delay.total_millis() * TIMER_HZ).div_ceil(1000).max(1)
+        });
+        let woken = future::select(time::sleep(ticks), poll_wanted());
+        if let Either::Left((frame, _)) = future::select(device.receive(), woken).await {
+            with_stack(|stack| stack.frames.received.push_back(frame));
+        }
+    }
+}
+
+/// Runs `f` on the TCP socket with `handle`.
+fn with_tcp<T>(handle: SocketHandle, f: impl FnOnce(&mut tcp::Socket) -> T) -> T {
+    with_stack(|stack| f(stack.sockets.get_mut::<tcp::Socket>(handle)))
+}
+
+fn tcp_socket() -> tcp::Socket<'static> {
+    let mut socket = tcp::Socket::new(
+        tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+        tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+    );
+    socket.set_timeout(Some(TCP_TIMEOUT));
+    socket
+}
+
+/// A TCP connection.
+pub struct TcpStream {
+    handle: SocketHandle,
+}
+
+impl TcpStream {
+    /// Connects to `remote` from an ephemeral port.
+    pub async fn connect(remote: Endpoint) -> Result<TcpStream, NetError> {
+        let handle = try_with_stack(|stack| {
+            let port = stack.pick_port(0, Stack::tcp_port_in_use)?;
+            let handle = stack.sockets.add(tcp_socket());
+            let socket = stack.sockets.get_mut::<tcp::Socket>(handle);
+            match socket.connect(stack.interface.context(), to_smol(remote), port) {
+                Ok(()) => Ok(handle),
+                Err(_) => {
+                    stack.sockets.remove(handle);
+                    Err(NetError::NoRoute)
+                }
+            }
+        })??;
+        // Dropping the stream cleans up if the connect fails
+        let stream = TcpStream { handle };
+        poll_soon();
+
+        future::poll_fn(|cx| {
+            with_tcp(handle, |socket| match socket.state() {
+                tcp::State::Established => Poll::Ready(Ok(())),
+                tcp::State::SynSent | tcp::State::SynReceived => {
+                    socket.register_send_waker(cx.waker());
+                    Poll::Pending
+                }
+                _ => Poll::Ready(Err(NetError::ConnectionRefused)),
+            })
+        })
+        .await?;
+        Ok(stream)
+    }
+
+    pub fn local_endpoint(&self) -> Option<Endpoint> {
+        with_tcp(self.handle, |socket| socket.local_endpoint().map(from_smol))
+    }
+
+    pub fn remote_endpoint(&self) -> Option<Endpoint> {
+        with_tcp(self.handle, |socket| {
+            socket.remote_endpoint().map(from_smol)
+        })
+    }
+
+    /// Reads up to `buf.len()` received bytes, and waits for some if there
+    /// are none. Returns 0 once the peer closed the connection.
+    pub async fn read(&self, buf: &mut [u8]) -> Result<usize, NetError> {
+        let len = future::poll_fn(|cx| {
+            with_tcp(self.handle, |socket| {
+                if socket.can_recv() {
+                    Poll::Ready(
+                        socket
+                            .recv_slice(buf)
+                            .map_err(|_| NetError::ConnectionClosed),
+                    )
+                } else if socket.may_recv() {
+                    socket.register_recv_waker(cx.waker());
+                    Poll::Pending
+                } else {
+                    Poll::Ready(Ok(0))
+                }
+            })
+        })
+        .await?;
+        // The peer may be waiting for the window that this opened
+        poll_soon();
+        Ok(len)
+    }
+
+    /// Queues as much of `data` as fits into the send buffer, waiting for
+    /// room if there is none, and returns how much that was.
+    pub async fn write(&self, data: &[u8]) -> Result<usize, NetError> {
+        let len = future::poll_fn(|cx| {
+            with_tcp(self.handle, |socket| {
+                if !socket.may_send() {
+                    Poll::Ready(Err(NetError::ConnectionClosed))
+                } else if socket.can_send() {
+                    Poll::Ready(
+                        socket
+                            .send_slice(data)
+                            .map_err(|_| NetError::ConnectionClosed),
+                    )
+                } else {
+                    socket.register_send_waker(cx.waker());
+                    Poll::Pending
+                }
+            })
+        })
+        .await?;
+        poll_soon();
+        Ok(len)
+    }
+
+    pub async fn write_all(&self, mut data: &[u8]) -> Result<(), NetError> {
+        while !data.is_empty() {
+            let len = self.write(data).await?;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    /// Closes our side of the connection once everything written was sent.
+    /// The peer can still send until it closes its side too.
+    pub fn close(&self) {
+        with_tcp(self.handle, |socket| socket.close());
+        poll_soon();
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        with_stack(|stack| {
+            stack.sockets.get_mut::<tcp::Socket>(self.handle).close();
+            stack.closing.push(self.handle);
+        });
+        poll_soon();
+    }
+}
+
+/// Accepts TCP connections on a port. A smoltcp socket takes one connection,
+/// so a new one starts listening whenever `accept` hands one out.
+pub struct TcpListener {
+    port: u16,
+    handle: SocketHandle,
+}
+
+impl TcpListener {
+    /// Listens on `port`, or on an ephemeral port if `port` is 0.
+    pub fn bind(port: u16) -> Result<TcpListener, NetError> {
+        try_with_stack(|stack| {
+            let port = stack.pick_port(port, Stack::tcp_port_in_use)?;
+            let handle = stack.sockets.add(tcp_socket());
+            let socket = stack.sockets.get_mut::<tcp::Socket>(handle);
+            socket.listen(port).map_err(|_| NetError::AddressInUse)?;
+            stack.listening.insert(port);
+            Ok(TcpListener { port, handle })
+        })?
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Waits for the next connection.
+    pub async fn accept(&mut self) -> Result<TcpStream, NetError> {
+        future::poll_fn(|cx| {
+            with_tcp(self.handle, |socket| match socket.state() {
+                tcp::State::Listen | tcp::State::SynReceived => {
+                    socket.register_recv_waker(cx.waker());
+                    Poll::Pending
+                }
+                _ => Poll::Ready(()),
+            })
+        })
+        .await;
+
+        let port = self.port;
+        let handle = with_stack(|stack| {
+            let handle = stack.sockets.add(tcp_socket());
+            let socket = stack.sockets.get_mut::<tcp::Socket>(handle);
+            match socket.listen(port) {
+                Ok(()) => Ok(handle),
+                Err(_) => {
+                    stack.sockets.remove(handle);
+                    Err(NetError::AddressInUse)
+                }
+            }
+        })?;
+        Ok(TcpStream {
+            handle: mem::replace(&mut self.handle, handle),
+        })
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        with_stack(|stack| {
+            stack.sockets.remove(self.handle);
+            stack.listening.remove(&self.port);
+        });
+    }
+}
+
+/// Runs `f` on the UDP socket with `handle`.
+fn with_udp<T>(handle: SocketHandle, f: impl FnOnce(&mut udp::Socket) -> T) -> T {
+    with_stack(|stack| f(stack.sockets.get_mut::<udp::Socket>(handle)))
+}
+
+fn udp_buffer() -> udp::PacketBuffer<'static> {
+    udp::PacketBuffer::new(
+        vec![udp::PacketMetadata::EMPTY; UDP_MAX_QUEUED],
+        vec![0; UDP_BUFFER_SIZE],
+    )
+}
+
+/// A UDP socket, like `udp::UdpSocket`.
+pub struct UdpSocket {
+    port: u16,
+    handle: SocketHandle,
+}
+
+impl UdpSocket {
+    /// Binds a socket to `port`, or to a free ephemeral port if `port` is 0.
+    pub fn bind(port: u16) -> Result<UdpSocket, NetError> {
+        try_with_stack(|stack| {
+            let port = stack.pick_port(port, Stack::udp_port_in_use)?;
+            let mut socket = udp::Socket::new(udp_buffer(), udp_buffer());
+            socket.bind(port).map_err(|_| NetError::AddressInUse)?;
+            let handle = stack.sockets.add(socket);
+            Ok(UdpSocket { port, handle })
+        })?
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Sends `data` in a datagram to `destination`, waiting for room in the
+    /// send buffer if there is none.
+    pub async fn send_to(&self, data: &[u8], destination: Endpoint) -> Result<(), NetError> {
+        // smoltcp would drop a datagram that needs fragmenting without a word
+        let mtu = with_stack(|stack| stack.frames.mtu);
+        if ipv4::HEADER_LEN + our_udp::HEADER_LEN + data.len() > mtu {
+            return Err(NetError::TooLarge);
+        }
+
+        future::poll_fn(|cx| {
+            with_udp(self.handle, |socket| {
+                match socket.send_slice(data, to_smol(destination)) {
+                    Ok(()) => Poll::Ready(Ok(())),
+                    Err(udp::SendError::BufferFull) => {
+                        socket.register_send_waker(cx.waker());
+                        Poll::Pending
+                    }
+                    Err(udp::SendError::Unaddressable) => Poll::Ready(Err(NetError::NoRoute)),
+                }
+            })
+        })
+        .await?;
+        poll_soon();
+        Ok(())
+    }
+
+    /// Waits for the next datagram and copies it into `buf`, like
+    /// `udp::UdpSocket::recv_from`.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> (usize, Endpoint) {
+        future::poll_fn(|cx| {
+            with_udp(self.handle, |socket| match socket.recv() {
+                Ok((data, metadata)) => {
+                    let len = data.len().min(buf.len());
+                    buf[..len].copy_from_slice(&data[..len]);
+                    Poll::Ready((len, from_smol(metadata.endpoint)))
+                }
+                Err(_) => {
+                    socket.register_recv_waker(cx.waker());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        with_stack(|stack| stack.sockets.remove(self.handle));
+    }
+}
+
+/// A TCP echo service (RFC 862): sends everything received on `port` back,
+/// one connection at a time.
+pub async fn echo(port: u16) {
+    let mut listener = match TcpListener::bind(port) {
+        Ok(listener) => listener,
+        Err(error) => {
+            warn!("smoltcp: tcp echo on port {}: {}", port, error);
+            return;
+        }
+    };
+
+    let mut buf = [0; 1024];
+    while let Ok(stream) = listener.accept().await {
+        loop {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(len) => {
+                    if stream.write_all(&buf[..len]).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
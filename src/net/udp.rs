@@ -0,0 +1,272 @@
+// UDP (RFC 768). A `UdpSocket` is bound to a local port, and received
+// datagrams go to the socket bound to their destination port, on any
+// interface. Datagrams for ports that nobody is bound to are dropped, and so
+// are new ones for a socket that has `MAX_QUEUED` waiting already.
+//
+// Receiving is async: `recv_from` leaves the task's waker with the socket, and
+// the network task wakes it when a datagram arrives.
+
+use super::{ipv4, Interface, Ipv4Address, NetError, PacketBuf};
+use crate::sync::Lazy;
+use crate::{rand, warn};
+use alloc::collections::{BTreeMap, VecDeque};
+use core::fmt;
+use core::sync::atomic::{AtomicU16, Ordering};
+use core::task::{Context, Poll, Waker};
+use futures_util::future;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const HEADER_LEN: usize = 8;
+
+/// The largest payload that fits into a `PacketBuf` behind the headers. The
+/// MTU usually allows less, 1472 bytes on Ethernet.
+pub const MAX_PAYLOAD: usize = super::buf::CAPACITY - super::buf::DEFAULT_HEADROOM;
+
+/// How many received datagrams a socket keeps.
+pub const MAX_QUEUED: usize = 32;
+
+/// The first of the ports that `bind(0)` picks from, which go up to 65535
+/// (the dynamic ports of RFC 6335).
+pub const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+const EPHEMERAL_PORTS: u16 = u16::MAX - FIRST_EPHEMERAL_PORT + 1;
+
+/// An IPv4 address and a port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Endpoint {
+    pub address: Ipv4Address,
+    pub port: u16,
+}
+
+impl Endpoint {
+    pub const fn new(address: Ipv4Address, port: u16) -> Endpoint {
+        Endpoint { address, port }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.address, self.port)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub source_port: u16,
+    pub destination_port: u16,
+}
+
+impl Header {
+    /// Pulls the header off the front of the payload of the IPv4 packet with
+    /// `ip_header`, and cuts off anything after the datagram. Returns `None`
+    /// if the length or the checksum is wrong.
+    pub fn pull(ip_header: &ipv4::Header, packet: &mut PacketBuf) -> Option<Header> {
+        if packet.len() < HEADER_LEN {
+            return None;
+        }
+        let u16_at = |offset: usize| u16::from_be_bytes([packet[offset], packet[offset + 1]]);
+        let header = Header {
+            source_port: u16_at(0),
+            destination_port: u16_at(2),
+        };
+        let len = usize::from(u16_at(4));
+        let has_checksum = u16_at(6) != 0;
+        if len < HEADER_LEN || len > packet.len() {
+            return None;
+        }
+        packet.truncate(len);
+
+        // A zero checksum means the sender didn't compute one
+        if has_checksum && checksum(ip_header.source, ip_header.destination, packet) != 0 {
+            return None;
+        }
+
+        packet.pull(HEADER_LEN);
+        Some(header)
+    }
+
+    /// Pushes the header in front of the payload in `packet`, which goes from
+    /// `source` to `destination`.
+    pub fn push(&self, source: Ipv4Address, destination: Ipv4Address, packet: &mut PacketBuf) {
+        let len = (HEADER_LEN + packet.len()) as u16;
+        let header = packet.push(HEADER_LEN);
+        header[0..2].copy_from_slice(&self.source_port.to_be_bytes());
+        header[2..4].copy_from_slice(&self.destination_port.to_be_bytes());
+        header[4..6].copy_from_slice(&len.to_be_bytes());
+
+        // Zero would mean "no checksum", so a zero checksum is sent as its
+        // other ones' complement form.
+        let checksum = match checksum(source, destination, packet) {
+            0 => 0xFFFF,
+            checksum => checksum,
+        };
+        packet[6..8].copy_from_slice(&checksum.to_be_bytes());
+    }
+}
+
+/// The checksum of a datagram, which includes a "pseudo header" with the
+/// addresses from the IPv4 header.
+fn checksum(source: Ipv4Address, destination: Ipv4Address, datagram: &[u8]) -> u16 {
+    let mut checksum = ipv4::Checksum::new();
+    checksum.add(&source.0);
+    checksum.add(&destination.0);
+    checksum.add(&[0, ipv4::PROTOCOL_UDP]);
+    checksum.add(&(datagram.len() as u16).to_be_bytes());
+    checksum.add(datagram);
+    checksum.finish()
+}
+
+struct Datagram {
+    source: Endpoint,
+    data: PacketBuf,
+}
+
+#[derive(Default)]
+struct Queue {
+    datagrams: VecDeque<Datagram>,
+    waker: Option<Waker>,
+}
+
+static SOCKETS: Mutex<BTreeMap<u16, Queue>> = Mutex::new(BTreeMap::new());
+static EPHEMERAL_START: Lazy<u16> = Lazy::new(|| rand::next_u32() as u16);
+
+pub struct UdpSocket {
+    port: u16,
+}
+
+impl UdpSocket {
+    /// Binds a socket to `port` on all interfaces, or to a free ephemeral
+    /// port if `port` is 0.
+    pub fn bind(port: u16) -> Result<UdpSocket, NetError> {
+        static NEXT_EPHEMERAL: AtomicU16 = AtomicU16::new(0);
+
+        interrupts::without_interrupts(|| {
+            let mut sockets = SOCKETS.lock();
+            let port = if port != 0 {
+                if sockets.contains_key(&port) {
+                    return Err(NetError::AddressInUse);
+                }
+                port
+            } else {
+                // Go round the ports rather than reusing the lowest free
+                // one, so that a new socket doesn't get the datagrams meant
+                // for one that was just closed. Starting somewhere random
+                // makes the ports harder to guess.
+                let start = *EPHEMERAL_START;
+                (0..EPHEMERAL_PORTS)
+                    .map(|_| {
+                        let next = NEXT_EPHEMERAL.fetch_add(1, Ordering::Relaxed);
+                        FIRST_EPHEMERAL_PORT + next.wrapping_add(start) % EPHEMERAL_PORTS
+                    })
+                    .find(|port| !sockets.contains_key(port))
+                    .ok_or(NetError::AddressInUse)?
+            };
+
+            sockets.insert(port, Queue::default());
+            Ok(UdpSocket { port })
+        })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Sends `data` in a datagram to `destination`.
+    pub async fn send_to(&self, data: &[u8], destination: Endpoint) -> Result<(), NetError> {
+        if data.len() > MAX_PAYLOAD {
+            return Err(NetError::TooLarge);
+        }
+        let interface = ipv4::route(destination.address).ok_or(NetError::NoRoute)?;
+        let source = interface.ipv4_address().ok_or(NetError::NoAddress)?;
+
+        let mut packet = PacketBuf::new();
+        packet.extend_from_slice(data);
+        let header = Header {
+            source_port: self.port,
+            destination_port: destination.port,
+        };
+        header.push(source, destination.address, &mut packet);
+        ipv4::send_on(&interface, destination.address, ipv4::PROTOCOL_UDP, packet).await
+    }
+
+    /// Copies the next datagram into `buf`, if one was received, and returns
+    /// its length and where it came from. The rest of a datagram that
+    /// doesn't fit into `buf` is dropped.
+    pub fn try_recv_from(&self, buf: &mut [u8]) -> Option<(usize, Endpoint)> {
+        let datagram = interrupts::without_interrupts(|| {
+            let mut sockets = SOCKETS.lock();
+            sockets.get_mut(&self.port)?.datagrams.pop_front()
+        })?;
+
+        let len = datagram.data.len().min(buf.len());
+        buf[..len].copy_from_slice(&datagram.data[..len]);
+        Some((len, datagram.source))
+    }
+
+    /// Waits for the next datagram, see `try_recv_from`.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> (usize, Endpoint) {
+        future::poll_fn(|cx| self.poll_recv_from(cx, buf)).await
+    }
+
+    fn poll_recv_from(&self, cx: &mut Context, buf: &mut [u8]) -> Poll<(usize, Endpoint)> {
+        // Registering the waker first means a datagram that arrives after
+        // the check still wakes us.
+        interrupts::without_interrupts(|| {
+            if let Some(queue) = SOCKETS.lock().get_mut(&self.port) {
+                queue.waker = Some(cx.waker().clone());
+            }
+        });
+        match self.try_recv_from(buf) {
+            Some(received) => Poll::Ready(received),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| SOCKETS.lock().remove(&self.port));
+    }
+}
+
+/// Handles a datagram the interface received.
+pub async fn handle(_interface: &Interface, ip_header: &ipv4::Header, mut packet: PacketBuf) {
+    let header = match Header::pull(ip_header, &mut packet) {
+        Some(header) => header,
+        None => return,
+    };
+    let datagram = Datagram {
+        source: Endpoint::new(ip_header.source, header.source_port),
+        data: packet,
+    };
+
+    let waker = interrupts::without_interrupts(|| {
+        let mut sockets = SOCKETS.lock();
+        let queue = sockets.get_mut(&header.destination_port)?;
+        if queue.datagrams.len() < MAX_QUEUED {
+            queue.datagrams.push_back(datagram);
+        }
+        queue.waker.take()
+    });
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+/// An echo service (RFC 862): sends every datagram received on `port` back.
+pub async fn echo(port: u16) {
+    let socket = match UdpSocket::bind(port) {
+        Ok(socket) => socket,
+        Err(error) => {
+            warn!("udp echo on port {}: {}", port, error);
+            return;
+        }
+    };
+
+    let mut buf = [0; MAX_PAYLOAD];
+    loop {
+        let (len, source) = socket.recv_from(&mut buf).await;
+        let _ = socket.send_to(&buf[..len], source).await;
+    }
+}
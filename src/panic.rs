@@ -0,0 +1,165 @@
+// What happens when the kernel panics. The code that panicked can't be trusted
+// anymore, and neither can anything it may have been in the middle of, so
+// `handle` stops everything before it reports anything:
+//
+// 1. Interrupts go off, for good.
+// 2. Other CPUs would get an NMI that halts them, once there are any (only
+//    the bootstrap processor runs so far, see cpu.rs).
+// 3. Devices stop their DMA (see `device::quiesce_all`), so that a network
+//    card doesn't keep writing packets into memory that may have been freed
+//    and handed out again.
+// 4. The crash dump goes out over serial (see crashdump.rs), and then the
+//    panic screen goes up, so that the dump is out even if drawing the screen
+//    goes wrong.
+// 5. Pressing a key reboots.
+//
+// None of it allocates or waits for a lock that the panicking code may hold:
+// the consoles' locks are forced open, since whoever held them won't run
+// again, and the keyboard is polled. A panic while handling a panic just
+// halts.
+
+use crate::drivers::ps2;
+use crate::unwind::{self, Backtrace};
+use crate::vga_buffer::{self, Color, BUFFER_HEIGHT, BUFFER_WIDTH};
+#[cfg(test)]
+use crate::HostTest;
+use crate::{crashdump, device, hlt_loop, power, println};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+
+const COLORS: (Color, Color) = (Color::White, Color::Red);
+
+/// Scancodes of key releases have the top bit set.
+const SCANCODE_RELEASED: u8 = 0x80;
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+pub fn handle(info: &PanicInfo) -> ! {
+    interrupts::disable();
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        hlt_loop();
+    }
+
+    let backtrace = unwind::backtrace();
+    device::quiesce_all();
+    crashdump::write(info, None, &backtrace);
+    show(info, &backtrace);
+
+    wait_for_key();
+    power::reset();
+}
+
+/// Puts the panic on the screen.
+fn show(info: &PanicInfo, backtrace: &Backtrace) {
+    // Whoever held the locks was interrupted by the panic, and won't run again.
+    if !vga_buffer::present() {
+        #[cfg(feature = "uefi")]
+        unsafe {
+            crate::framebuffer::force_unlock();
+        }
+        println!(
+            "KERNEL PANIC\n{}\n{}\npress any key to reboot",
+            info, backtrace
+        );
+        return;
+    }
+
+    unsafe { vga_buffer::force_unlock() };
+    let mut writer = vga_buffer::WRITER.lock();
+    let mut put = |row, col, byte| writer.write_at(row, col, byte, COLORS);
+    for row in 0..BUFFER_HEIGHT {
+        for col in 0..BUFFER_WIDTH {
+            put(row, col, b' ');
+        }
+    }
+
+    let mut screen = Screen::new(&mut put, 1, BUFFER_HEIGHT - 2);
+    let _ = write!(screen, "KERNEL PANIC\n\n{}\n\n{}", info, backtrace);
+    let mut last_row = Screen::new(&mut put, BUFFER_HEIGHT - 1, BUFFER_HEIGHT - 1);
+    let _ = last_row.write_str("press any key to reboot");
+}
+
+/// Text on the screen, from one row to another: long lines wrap, and what
+/// doesn't fit is dropped.
+struct Screen<'a> {
+    put: &'a mut dyn FnMut(usize, usize, u8),
+    row: usize,
+    col: usize,
+    last_row: usize,
+}
+
+impl<'a> Screen<'a> {
+    fn new(put: &'a mut dyn FnMut(usize, usize, u8), row: usize, last_row: usize) -> Screen<'a> {
+        Screen {
+            put,
+            row,
+            col: 0,
+            last_row,
+        }
+    }
+}
+
+impl Write for Screen<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' || self.col == BUFFER_WIDTH {
+                self.row += 1;
+                self.col = 0;
+                if byte == b'\n' {
+                    continue;
+                }
+            }
+            if self.row <= self.last_row {
+                let byte = match byte {
+                    0x20..=0x7E => byte,
+                    _ => 0xFE,
+                };
+                (self.put)(self.row, self.col, byte);
+            }
+            self.col += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Waits until a key is pressed. Keys that were pressed before don't count.
+/// Without a keyboard controller, that's forever.
+fn wait_for_key() {
+    let status = || unsafe { ps2::COMMAND.read::<u8>(0) };
+    if status() == 0xFF {
+        hlt_loop();
+    }
+
+    while status() & ps2::STATUS_OUTPUT_FULL != 0 {
+        unsafe { ps2::DATA.read::<u8>(0) };
+    }
+    loop {
+        if status() & ps2::STATUS_OUTPUT_FULL != 0 {
+            let scancode: u8 = unsafe { ps2::DATA.read(0) };
+            if scancode & SCANCODE_RELEASED == 0 {
+                return;
+            }
+        }
+        core::hint::spin_loop();
+    }
+}
+
+#[test_case]
+static WRAPS_TEXT: HostTest = HostTest::new("panic::wraps_text", || {
+    let mut rows = [[b'.'; BUFFER_WIDTH]; 3];
+    let mut put = |row: usize, col: usize, byte| rows[row][col] = byte;
+    let mut screen = Screen::new(&mut put, 1, 2);
+    let long = [b'x'; BUFFER_WIDTH + 2];
+    write!(
+        screen,
+        "a\u{e9}\n{}\ndropped",
+        core::str::from_utf8(&long).unwrap()
+    )
+    .unwrap();
+
+    assert_eq!(&rows[0], &[b'.'; BUFFER_WIDTH]);
+    assert_eq!(&rows[1][..4], &[b'a', 0xFE, 0xFE, b'.']);
+    assert_eq!(&rows[2], &[b'x'; BUFFER_WIDTH]);
+});
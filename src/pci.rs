@@ -0,0 +1,603 @@
+// The PCI bus: finding devices and setting them up for their drivers.
+//
+// Each PCI function has 256 bytes of configuration space, which holds its
+// vendor and device IDs, its class, the base address registers (BARs) saying
+// where its registers are, and a list of capabilities. The configuration space
+// is reached through two I/O ports: the address of a function and register is
+// written to CONFIG_ADDRESS, and the register is then read or written through
+// CONFIG_DATA. Since that takes two accesses, they are done under a lock.
+//
+// `init` scans all buses once (by brute force, trying every bus, device and
+// function), and drivers look their devices up with `find`. Devices are
+// described by plain `Device` values; all state lives in the configuration
+// space itself. Interrupts are set up with MSI or MSI-X, see msi.rs.
+//
+// `init` also adds each bus to the device tree, with its functions under it.
+
+use crate::device::{self, Bus, DeviceId, PowerError};
+use crate::io::PortRange;
+use crate::memory::Size;
+use crate::shell;
+#[cfg(test)]
+use crate::HostTest;
+use alloc::{collections::BTreeMap, format, string::String, sync::Arc, vec::Vec};
+use core::fmt::{self, Write};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub mod msi;
+
+pub use msi::PciError;
+
+static CONFIG: PortRange = PortRange::new("pci config", 0xCF8, 8);
+const CONFIG_ADDRESS: u16 = 0;
+const CONFIG_DATA: u16 = 4;
+
+// Registers of the configuration space header
+const VENDOR_ID: u8 = 0x00;
+const DEVICE_ID: u8 = 0x02;
+const COMMAND: u8 = 0x04;
+const STATUS: u8 = 0x06;
+const REVISION: u8 = 0x08;
+const HEADER_TYPE: u8 = 0x0E;
+const BAR0: u8 = 0x10;
+const CAPABILITIES_POINTER: u8 = 0x34;
+const INTERRUPT_LINE: u8 = 0x3C;
+
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+const HEADER_MULTIFUNCTION: u8 = 1 << 7;
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+
+/// Capability IDs
+pub const CAPABILITY_POWER_MANAGEMENT: u8 = 0x01;
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_VENDOR: u8 = 0x09;
+pub const CAPABILITY_PCI_EXPRESS: u8 = 0x10;
+pub const CAPABILITY_MSIX: u8 = 0x11;
+
+// Serializes the accesses to CONFIG_ADDRESS and CONFIG_DATA
+static CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+static DEVICES: Mutex<Vec<Device>> = Mutex::new(Vec::new());
+
+// Where `init` put each function in the device tree
+static NODES: Mutex<BTreeMap<Address, DeviceId>> = Mutex::new(BTreeMap::new());
+
+/// Where a function sits on the bus, written like "00:03.0".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Address {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Address {
+    pub fn new(bus: u8, device: u8, function: u8) -> Address {
+        assert!(device < 32 && function < 8, "invalid PCI address");
+        Address {
+            bus,
+            device,
+            function,
+        }
+    }
+
+    fn config_address(self, offset: u8) -> u32 {
+        1 << 31
+            | u32::from(self.bus) << 16
+            | u32::from(self.device) << 11
+            | u32::from(self.function) << 8
+            | u32::from(offset & 0xFC)
+    }
+
+    /// Runs `f` on the data port of the register at `offset` (as an offset
+    /// into `CONFIG`), which is narrower than the 32 bits of CONFIG_DATA for
+    /// 8- and 16-bit registers.
+    fn with_data_port<T>(self, offset: u8, f: impl FnOnce(u16) -> T) -> T {
+        interrupts::without_interrupts(|| {
+            let _lock = CONFIG_LOCK.lock();
+            unsafe { CONFIG.write(CONFIG_ADDRESS, self.config_address(offset)) };
+            f(CONFIG_DATA + u16::from(offset & 3))
+        })
+    }
+
+    pub fn read_u8(self, offset: u8) -> u8 {
+        self.with_data_port(offset, |port| unsafe { CONFIG.read(port) })
+    }
+
+    pub fn read_u16(self, offset: u8) -> u16 {
+        self.with_data_port(offset, |port| unsafe { CONFIG.read(port) })
+    }
+
+    pub fn read_u32(self, offset: u8) -> u32 {
+        self.with_data_port(offset, |port| unsafe { CONFIG.read(port) })
+    }
+
+    pub fn write_u8(self, offset: u8, value: u8) {
+        self.with_data_port(offset, |port| unsafe { CONFIG.write(port, value) })
+    }
+
+    pub fn write_u16(self, offset: u8, value: u16) {
+        self.with_data_port(offset, |port| unsafe { CONFIG.write(port, value) })
+    }
+
+    pub fn write_u32(self, offset: u8, value: u32) {
+        self.with_data_port(offset, |port| unsafe { CONFIG.write(port, value) })
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// A function on the bus, with the fields of its header that identify it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Device {
+    pub address: Address,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+}
+
+/// Where a BAR's registers are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+    },
+    Io {
+        port: u16,
+        size: u16,
+    },
+}
+
+impl fmt::Display for Bar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Bar::Memory {
+                address,
+                size,
+                prefetchable,
+            } => {
+                write!(f, "memory at {:#x} ({}", address, Size(size))?;
+                if prefetchable {
+                    write!(f, ", prefetchable")?;
+                }
+                write!(f, ")")
+            }
+            Bar::Io { port, size } => write!(f, "I/O at {:#x} ({})", port, Size(size.into())),
+        }
+    }
+}
+
+/// A capability in a device's capability list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// Where the capability starts in the configuration space
+    pub offset: u8,
+}
+
+impl Device {
+    /// Reads the header of the function at `address`, if there is one.
+    pub fn probe(address: Address) -> Option<Device> {
+        let vendor_id = address.read_u16(VENDOR_ID);
+        if vendor_id == 0xFFFF {
+            return None;
+        }
+
+        let class = address.read_u32(REVISION);
+        Some(Device {
+            address,
+            vendor_id,
+            device_id: address.read_u16(DEVICE_ID),
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+        })
+    }
+
+    pub fn command(&self) -> u16 {
+        self.address.read_u16(COMMAND)
+    }
+
+    pub fn set_command(&self, command: u16) {
+        self.address.write_u16(COMMAND, command);
+    }
+
+    /// Lets the device access memory by itself (DMA), and enables the memory
+    /// and I/O ranges of its BARs. Needed for MSIs too, which are memory
+    /// writes of the device.
+    pub fn enable_bus_mastering(&self) {
+        self.set_command(
+            self.command() | COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER,
+        );
+    }
+
+    /// Stops the device from accessing memory by itself, the opposite of
+    /// `enable_bus_mastering`.
+    pub fn disable_bus_mastering(&self) {
+        self.set_command(self.command() & !COMMAND_BUS_MASTER);
+    }
+
+    /// Stops the device from raising its legacy (INTx) interrupt line.
+    pub fn disable_intx(&self) {
+        self.set_command(self.command() | COMMAND_INTX_DISABLE);
+    }
+
+    /// The legacy IRQ line that the firmware routed the device's interrupt to.
+    pub fn interrupt_line(&self) -> u8 {
+        self.address.read_u8(INTERRUPT_LINE)
+    }
+
+    /// Reads BAR `index` (0-5), and its size. A 64-bit memory BAR takes up two
+    /// slots, `index` and `index + 1`.
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        if index > 5 {
+            return None;
+        }
+        let offset = BAR0 + index * 4;
+        let low = self.address.read_u32(offset);
+        let is_64_bit = low & 1 == 0 && (low >> 1) & 0b11 == 0b10;
+
+        // The size is found by writing all ones and seeing which bits stick.
+        // The device mustn't decode the BAR while it points elsewhere.
+        let command = self.command();
+        self.set_command(command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+        let mask = |offset: u8| {
+            let value = self.address.read_u32(offset);
+            self.address.write_u32(offset, 0xFFFF_FFFF);
+            let mask = self.address.read_u32(offset);
+            self.address.write_u32(offset, value);
+            (value, mask)
+        };
+        let (_, low_mask) = mask(offset);
+        let high = if is_64_bit && index < 5 {
+            Some(mask(offset + 4))
+        } else {
+            None
+        };
+        self.set_command(command);
+
+        // An unused BAR has no address bits that stick
+        if low & 1 == 1 {
+            let mask = (low_mask & 0xFFFC) as u16;
+            if mask == 0 {
+                return None;
+            }
+            return Some(Bar::Io {
+                port: (low & 0xFFFC) as u16,
+                size: (!mask).checked_add(1)?,
+            });
+        }
+
+        let high_sticks = matches!(high, Some((_, high_mask)) if high_mask != 0);
+        if low_mask & 0xFFFF_FFF0 == 0 && !high_sticks {
+            return None;
+        }
+        let (high, high_mask) = high.unwrap_or((0, 0xFFFF_FFFF));
+        let address = u64::from(high) << 32 | u64::from(low & 0xFFFF_FFF0);
+        let mask = u64::from(high_mask) << 32 | u64::from(low_mask & 0xFFFF_FFF0);
+
+        Some(Bar::Memory {
+            address,
+            size: (!mask).checked_add(1)?,
+            prefetchable: low & (1 << 3) != 0,
+        })
+    }
+
+    /// Walks the capability list.
+    pub fn capabilities(&self) -> Capabilities {
+        let next = if self.address.read_u16(STATUS) & STATUS_CAPABILITIES != 0 {
+            self.address.read_u8(CAPABILITIES_POINTER)
+        } else {
+            0
+        };
+
+        Capabilities {
+            address: self.address,
+            next,
+            remaining: MAX_CAPABILITIES,
+        }
+    }
+
+    /// Returns the offset of the first capability with the given ID.
+    pub fn find_capability(&self, id: u8) -> Option<u8> {
+        self.capabilities()
+            .find(|capability| capability.id == id)
+            .map(|capability| capability.offset)
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:04x}:{:04x} {}",
+            self.address,
+            self.vendor_id,
+            self.device_id,
+            class_name(self.class, self.subclass)
+        )
+    }
+}
+
+impl device::Device for Device {
+    fn name(&self) -> String {
+        match device_name(self.vendor_id, self.device_id) {
+            Some(name) => name.into(),
+            None => class_name(self.class, self.subclass).into(),
+        }
+    }
+
+    fn describe(&self, out: &mut dyn Write) -> fmt::Result {
+        write!(
+            out,
+            "PCI {} {:04x}:{:04x}",
+            self.address, self.vendor_id, self.device_id
+        )
+    }
+
+    // Whatever its driver didn't stop, can't do DMA anymore. It isn't undone
+    // on resume, since the function may not have been a bus master before.
+    fn shutdown(&self) -> Result<(), PowerError> {
+        self.disable_bus_mastering();
+        Ok(())
+    }
+
+    fn quiesce(&self) {
+        // Unless the panic came while configuration space was being accessed.
+        // The guard is dropped right away, before the access locks it again.
+        if CONFIG_LOCK.try_lock().is_some() {
+            self.disable_bus_mastering();
+        }
+    }
+}
+
+/// More than fit into the configuration space, so a list that loops is cut
+/// off.
+const MAX_CAPABILITIES: usize = 48;
+
+pub struct Capabilities {
+    address: Address,
+    next: u8,
+    remaining: usize,
+}
+
+impl Iterator for Capabilities {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Capability> {
+        // The pointers are dword aligned, and the capabilities come after
+        // the 64-byte header.
+        let offset = self.next & 0xFC;
+        if offset < 0x40 || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let header = self.address.read_u16(offset);
+        self.next = (header >> 8) as u8;
+        Some(Capability {
+            id: header as u8,
+            offset,
+        })
+    }
+}
+
+fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x01) => "IDE controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "NVMe controller",
+        (0x01, _) => "storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "network controller",
+        (0x03, _) => "display controller",
+        (0x04, _) => "multimedia controller",
+        (0x06, 0x00) => "host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "bridge",
+        (0x0C, 0x03) => "USB controller",
+        (0x0C, 0x05) => "SMBus controller",
+        _ => "device",
+    }
+}
+
+/// Names of the devices QEMU commonly emulates. Anything else is just listed
+/// by its IDs.
+const DEVICE_NAMES: &[(u16, u16, &str)] = &[
+    (0x8086, 0x1237, "Intel 440FX host bridge"),
+    (0x8086, 0x7000, "Intel PIIX3 ISA bridge"),
+    (0x8086, 0x7010, "Intel PIIX3 IDE"),
+    (0x8086, 0x7020, "Intel PIIX3 USB"),
+    (0x8086, 0x7113, "Intel PIIX4 ACPI"),
+    (0x8086, 0x29C0, "Intel Q35 host bridge"),
+    (0x8086, 0x2918, "Intel ICH9 LPC bridge"),
+    (0x8086, 0x2922, "Intel ICH9 AHCI"),
+    (0x8086, 0x2930, "Intel ICH9 SMBus"),
+    (0x8086, 0x100E, "Intel 82540EM (e1000)"),
+    (0x8086, 0x100F, "Intel 82545EM (e1000)"),
+    (0x8086, 0x10D3, "Intel 82574L (e1000e)"),
+    (0x8086, 0x2415, "Intel 82801AA AC'97"),
+    (0x8086, 0x293E, "Intel ICH9 HD Audio"),
+    (0x1234, 0x1111, "QEMU standard VGA"),
+    (0x1234, 0x11E8, "QEMU edu"),
+    (0x1B36, 0x0008, "QEMU PCIe host bridge"),
+    (0x1B36, 0x000D, "QEMU xHCI"),
+    (0x1AF4, 0x1000, "virtio network"),
+    (0x1AF4, 0x1001, "virtio block"),
+    (0x1AF4, 0x1050, "virtio GPU"),
+    (0x10EC, 0x8139, "Realtek RTL8139"),
+];
+
+/// The name of a device, if it's one QEMU commonly emulates.
+pub fn device_name(vendor_id: u16, device_id: u16) -> Option<&'static str> {
+    DEVICE_NAMES
+        .iter()
+        .find(|&&(vendor, device, _)| vendor == vendor_id && device == device_id)
+        .map(|&(_, _, name)| name)
+}
+
+fn capability_name(id: u8) -> Option<&'static str> {
+    match id {
+        CAPABILITY_POWER_MANAGEMENT => Some("pm"),
+        CAPABILITY_MSI => Some("msi"),
+        CAPABILITY_VENDOR => Some("vendor"),
+        CAPABILITY_PCI_EXPRESS => Some("pcie"),
+        CAPABILITY_MSIX => Some("msi-x"),
+        _ => None,
+    }
+}
+
+/// Scans the buses for devices.
+fn scan() -> Vec<Device> {
+    let mut devices = Vec::new();
+    for bus in 0..=255 {
+        for device in 0..32 {
+            let first = match Device::probe(Address::new(bus, device, 0)) {
+                Some(first) => first,
+                None => continue,
+            };
+            devices.push(first);
+
+            if first.address.read_u8(HEADER_TYPE) & HEADER_MULTIFUNCTION != 0 {
+                devices.extend(
+                    (1..8)
+                        .filter_map(|function| Device::probe(Address::new(bus, device, function))),
+                );
+            }
+        }
+    }
+    devices
+}
+
+/// Finds the devices on the bus. Drivers look them up afterwards.
+pub fn init() {
+    let devices = scan();
+
+    let mut bus = None;
+    let mut nodes = BTreeMap::new();
+    for &pci in &devices {
+        let parent = match bus {
+            Some((number, id)) if number == pci.address.bus => id,
+            _ => {
+                let name = format!("PCI bus {}", pci.address.bus);
+                let id = device::add(None, Arc::new(Bus(name)));
+                bus = Some((pci.address.bus, id));
+                id
+            }
+        };
+        nodes.insert(pci.address, device::add(Some(parent), Arc::new(pci)));
+    }
+
+    interrupts::without_interrupts(|| {
+        *DEVICES.lock() = devices;
+        *NODES.lock() = nodes;
+    });
+}
+
+/// Returns all devices found by `init`, sorted by address.
+pub fn devices() -> Vec<Device> {
+    interrupts::without_interrupts(|| DEVICES.lock().clone())
+}
+
+/// Where the function at `address` is in the device tree, for its driver to
+/// add its own devices under.
+pub fn node(address: Address) -> Option<DeviceId> {
+    interrupts::without_interrupts(|| NODES.lock().get(&address).copied())
+}
+
+/// Returns the first device with the given vendor and device IDs.
+pub fn find(vendor_id: u16, device_id: u16) -> Option<Device> {
+    devices()
+        .into_iter()
+        .find(|device| device.vendor_id == vendor_id && device.device_id == device_id)
+}
+
+pub fn register_commands() {
+    shell::register("lspci", "list the PCI devices", |_args, out| {
+        for device in devices() {
+            write!(
+                out,
+                "{} [{:02x}{:02x}]",
+                device, device.class, device.subclass
+            )?;
+            if let Some(name) = device_name(device.vendor_id, device.device_id) {
+                write!(out, ": {}", name)?;
+            }
+
+            let mut names = device
+                .capabilities()
+                .filter_map(|capability| capability_name(capability.id));
+            if let Some(first) = names.next() {
+                write!(out, " [{}", first)?;
+                for name in names {
+                    write!(out, ", {}", name)?;
+                }
+                write!(out, "]")?;
+            }
+            writeln!(out)?;
+
+            // Sizing a BAR stops the device from decoding it for a moment, so
+            // its driver's interrupt handler mustn't run in between.
+            let bars = interrupts::without_interrupts(|| bars(&device));
+            for (index, bar) in bars {
+                writeln!(out, "    BAR{}: {}", index, bar)?;
+            }
+        }
+        Ok(())
+    });
+}
+
+/// The BARs the device uses, with their indices. A 64-bit memory BAR takes
+/// up the slot after it too, and a PCI bridge only has the first two.
+fn bars(device: &Device) -> Vec<(u8, Bar)> {
+    let count = match device.address.read_u8(HEADER_TYPE) & !HEADER_MULTIFUNCTION {
+        HEADER_TYPE_BRIDGE => 2,
+        _ => 6,
+    };
+    let mut bars = Vec::new();
+    let mut index = 0;
+    while index < count {
+        let is_64_bit = device.address.read_u32(BAR0 + index * 4) & 0b111 == 0b100;
+        if let Some(bar) = device.bar(index) {
+            bars.push((index, bar));
+        }
+        index += if is_64_bit { 2 } else { 1 };
+    }
+    bars
+}
+
+#[test_case]
+static NAMES_DEVICES: HostTest = HostTest::new("pci::names_devices", || {
+    assert_eq!(device_name(0x8086, 0x100E), Some("Intel 82540EM (e1000)"));
+    assert_eq!(device_name(0x1234, 0x11E8), Some("QEMU edu"));
+    assert_eq!(device_name(0x8086, 0xFFFF), None);
+
+    let bar = Bar::Memory {
+        address: 0xFEBC_0000,
+        size: 128 << 10,
+        prefetchable: false,
+    };
+    assert_eq!(alloc::format!("{}", bar), "memory at 0xfebc0000 (128 KiB)");
+    let bar = Bar::Io {
+        port: 0xC000,
+        size: 64,
+    };
+    assert_eq!(alloc::format!("{}", bar), "I/O at 0xc000 (64 B)");
+});
@@ -0,0 +1,209 @@
+// Message signalled interrupts. Instead of pulling a (shared) interrupt line,
+// the device writes a message to the local APIC, which raises the vector in the
+// message. Each device, or even each queue of a device, gets vectors of its
+// own from `interrupts::allocate_vector`, so handlers don't have to ask every
+// device on a line whether it was the one.
+//
+// There are two capabilities for it. MSI has room for a single message
+// (address and data) in the configuration space. MSI-X has a table of them,
+// one per vector, in the memory of one of the device's BARs. Both are set up
+// for the current CPU and fixed, edge triggered delivery.
+
+use super::{Bar, Device, CAPABILITY_MSI, CAPABILITY_MSIX};
+use crate::mmio::RegisterBlock;
+use crate::{apic, interrupts, memory};
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::PhysAddr;
+
+// MSI capability registers, from the start of the capability
+const MSI_CONTROL: u8 = 0x02;
+const MSI_ADDRESS_LOW: u8 = 0x04;
+const MSI_ADDRESS_HIGH: u8 = 0x08;
+// The data and mask registers come after the upper half of the address, if
+// the device has it
+const MSI_DATA_32: u8 = 0x08;
+const MSI_DATA_64: u8 = 0x0C;
+const MSI_MASK_32: u8 = 0x0C;
+const MSI_MASK_64: u8 = 0x10;
+
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+const MSI_CONTROL_MULTIPLE_MESSAGES: u16 = 0b111 << 4;
+const MSI_CONTROL_64_BIT: u16 = 1 << 7;
+const MSI_CONTROL_PER_VECTOR_MASKING: u16 = 1 << 8;
+
+// MSI-X capability registers
+const MSIX_CONTROL: u8 = 0x02;
+const MSIX_TABLE: u8 = 0x04;
+
+const MSIX_CONTROL_TABLE_SIZE: u16 = 0x7FF;
+const MSIX_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
+const MSIX_TABLE_BIR: u32 = 0b111;
+
+// An MSI-X table entry: the address, the data, and a mask bit
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_ENTRY_ADDRESS_LOW: usize = 0x0;
+const MSIX_ENTRY_ADDRESS_HIGH: usize = 0x4;
+const MSIX_ENTRY_DATA: usize = 0x8;
+const MSIX_ENTRY_VECTOR_CONTROL: usize = 0xC;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciError {
+    /// The device doesn't have the capability.
+    NoCapability,
+    /// All interrupt vectors are taken.
+    NoVectors,
+    /// More vectors were asked for than the device has.
+    TooManyVectors,
+    /// A BAR the device refers to isn't a memory BAR.
+    BadBar,
+}
+
+impl fmt::Display for PciError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            PciError::NoCapability => "capability not supported",
+            PciError::NoVectors => "out of interrupt vectors",
+            PciError::TooManyVectors => "too many vectors for the device",
+            PciError::BadBar => "bad BAR",
+        };
+        f.write_str(message)
+    }
+}
+
+/// The data of a message for `vector`: fixed delivery, edge triggered.
+fn message_data(vector: u8) -> u32 {
+    u32::from(vector)
+}
+
+/// Allocates a vector for each handler, or none at all.
+fn allocate_vectors(handlers: &[fn()]) -> Result<Vec<u8>, PciError> {
+    let mut vectors = Vec::with_capacity(handlers.len());
+    for &handler in handlers {
+        match interrupts::allocate_vector(handler) {
+            Some(vector) => vectors.push(vector),
+            None => {
+                vectors.into_iter().for_each(interrupts::free_vector);
+                return Err(PciError::NoVectors);
+            }
+        }
+    }
+    Ok(vectors)
+}
+
+impl Device {
+    /// Makes the device raise a vector of its own, which runs `handler`, with
+    /// MSI. Returns the vector.
+    ///
+    /// Turns off the legacy interrupt line, and turns on bus mastering, which
+    /// MSIs need.
+    pub fn enable_msi(&self, handler: fn()) -> Result<u8, PciError> {
+        let capability = self
+            .find_capability(CAPABILITY_MSI)
+            .ok_or(PciError::NoCapability)?;
+        let vector = interrupts::allocate_vector(handler).ok_or(PciError::NoVectors)?;
+        let address = self.address;
+
+        let control = address.read_u16(capability + MSI_CONTROL);
+        let is_64_bit = control & MSI_CONTROL_64_BIT != 0;
+        let (data, mask) = if is_64_bit {
+            (MSI_DATA_64, MSI_MASK_64)
+        } else {
+            (MSI_DATA_32, MSI_MASK_32)
+        };
+
+        let message_address = apic::msi_address();
+        address.write_u32(capability + MSI_ADDRESS_LOW, message_address as u32);
+        if is_64_bit {
+            address.write_u32(
+                capability + MSI_ADDRESS_HIGH,
+                (message_address >> 32) as u32,
+            );
+        }
+        address.write_u16(capability + data, message_data(vector) as u16);
+        if control & MSI_CONTROL_PER_VECTOR_MASKING != 0 {
+            address.write_u32(capability + mask, 0);
+        }
+
+        // A single message, even if the device could use more
+        let control = control & !MSI_CONTROL_MULTIPLE_MESSAGES | MSI_CONTROL_ENABLE;
+        address.write_u16(capability + MSI_CONTROL, control);
+
+        self.disable_intx();
+        self.enable_bus_mastering();
+        Ok(vector)
+    }
+
+    /// The number of vectors the device's MSI-X table has room for.
+    pub fn msix_vectors(&self) -> Option<usize> {
+        let capability = self.find_capability(CAPABILITY_MSIX)?;
+        let control = self.address.read_u16(capability + MSIX_CONTROL);
+        Some(usize::from(control & MSIX_CONTROL_TABLE_SIZE) + 1)
+    }
+
+    /// Gives each of the device's first MSI-X table entries a vector of its
+    /// own, running the corresponding handler, and returns the vectors. The
+    /// remaining entries stay masked.
+    ///
+    /// Turns off the legacy interrupt line, and turns on bus mastering.
+    pub fn enable_msix(&self, handlers: &[fn()]) -> Result<Vec<u8>, PciError> {
+        let capability = self
+            .find_capability(CAPABILITY_MSIX)
+            .ok_or(PciError::NoCapability)?;
+        let address = self.address;
+
+        let control = address.read_u16(capability + MSIX_CONTROL);
+        let entries = usize::from(control & MSIX_CONTROL_TABLE_SIZE) + 1;
+        if handlers.len() > entries {
+            return Err(PciError::TooManyVectors);
+        }
+
+        let table = address.read_u32(capability + MSIX_TABLE);
+        let bar = (table & MSIX_TABLE_BIR) as u8;
+        let table_offset = u64::from(table & !MSIX_TABLE_BIR);
+        let table_size = entries * MSIX_ENTRY_SIZE;
+        let table_address = match self.bar(bar) {
+            Some(Bar::Memory { address, size, .. }) if table_offset + table_size as u64 <= size => {
+                address + table_offset
+            }
+            _ => return Err(PciError::BadBar),
+        };
+
+        // The BAR has to be decoded to reach the table.
+        self.enable_bus_mastering();
+        let vectors = allocate_vectors(handlers)?;
+
+        // Keep the whole function masked while the entries change
+        address.write_u16(
+            capability + MSIX_CONTROL,
+            control | MSIX_CONTROL_ENABLE | MSIX_CONTROL_FUNCTION_MASK,
+        );
+
+        let table = unsafe {
+            let base = memory::phys_to_virt(PhysAddr::new(table_address));
+            RegisterBlock::new("msi-x table", base, table_size)
+        };
+        let message_address = apic::msi_address();
+        for index in 0..entries {
+            let entry = table.subblock(index * MSIX_ENTRY_SIZE, MSIX_ENTRY_SIZE);
+            match vectors.get(index) {
+                Some(&vector) => {
+                    entry.write(MSIX_ENTRY_ADDRESS_LOW, message_address as u32);
+                    entry.write(MSIX_ENTRY_ADDRESS_HIGH, (message_address >> 32) as u32);
+                    entry.write(MSIX_ENTRY_DATA, message_data(vector));
+                    entry.write(MSIX_ENTRY_VECTOR_CONTROL, 0u32);
+                }
+                None => entry.write(MSIX_ENTRY_VECTOR_CONTROL, MSIX_ENTRY_MASKED),
+            }
+        }
+
+        address.write_u16(
+            capability + MSIX_CONTROL,
+            control & !MSIX_CONTROL_FUNCTION_MASK | MSIX_CONTROL_ENABLE,
+        );
+        self.disable_intx();
+        Ok(vectors)
+    }
+}
@@ -0,0 +1,57 @@
+// Variables with one instance per CPU, declared with `per_cpu!`:
+//
+//     per_cpu! {
+//         static TICKS: AtomicU64 = AtomicU64::new(0);
+//     }
+//
+//     TICKS.get().fetch_add(1, Ordering::Relaxed);
+//
+// `get` is the current CPU's instance, `iter` all of them, e.g. to add up
+// statistics. The instances live in an array indexed by `cpu::index()`, which
+// the macro sizes and fills, so nobody has to work out where a CPU's copy is.
+// Another CPU can get at an instance through `for_cpu`, so the type has to be
+// `Sync` like any static: atomics, or a lock for anything bigger.
+
+use crate::cpu::{self, MAX_CPUS};
+
+pub struct PerCpu<T> {
+    instances: [T; MAX_CPUS],
+}
+
+impl<T> PerCpu<T> {
+    /// Use `per_cpu!` instead.
+    #[doc(hidden)]
+    pub const fn new(instances: [T; MAX_CPUS]) -> Self {
+        PerCpu { instances }
+    }
+
+    /// The current CPU's instance.
+    pub fn get(&self) -> &T {
+        &self.instances[cpu::index()]
+    }
+
+    /// The instance of the CPU with the given `cpu::index`.
+    pub fn for_cpu(&self, cpu: usize) -> Option<&T> {
+        self.instances.get(cpu)
+    }
+
+    /// The instances of all CPUs, by `cpu::index`.
+    pub fn iter(&self) -> core::slice::Iter<'_, T> {
+        self.instances.iter()
+    }
+}
+
+/// Declares statics with one instance per CPU, each starting out as the
+/// (constant) initializer. See `percpu.rs`.
+#[macro_export]
+macro_rules! per_cpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::percpu::PerCpu<$ty> = {
+                const INIT: $ty = $init;
+                $crate::percpu::PerCpu::new([INIT; $crate::cpu::MAX_CPUS])
+            };
+        )*
+    };
+}
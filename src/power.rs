@@ -0,0 +1,144 @@
+// Turning the machine off and restarting it.
+//
+// Either way, the devices are shut down first (see `device::shutdown_all`):
+// disks write out their caches, and network cards stop their DMA, so that
+// nothing is lost or half-written when the power goes.
+//
+// Shutting down enters the ACPI sleep state S5, "soft off": the S5 sleep type
+// from the DSDT and the SLP_EN bit go into the PM1 control registers that the
+// FADT names (see acpi.rs). If the firmware left the machine in legacy mode,
+// ACPI is switched on first. Without ACPI, QEMU's isa-debug-exit device still
+// ends QEMU, if it's attached; otherwise we halt.
+//
+// Rebooting writes the FADT's reset register if there is one, then pulses the
+// reset line of the keyboard controller, and as a last resort triple faults:
+// an exception without an IDT to handle it resets the CPU. `reset` also has
+// to work from the emergency console, where any lock may be held, so `init`
+// looks the reset register up ahead of time (reading the ACPI tables
+// allocates), and `reset` takes no locks at all.
+
+use crate::drivers::ps2;
+use crate::{acpi, device, println, shell};
+use core::sync::atomic::{AtomicU32, Ordering};
+use x86_64::instructions::port::{Port, PortReadOnly};
+use x86_64::instructions::{interrupts, tables};
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+
+// PM1 control register bits
+const SCI_ENABLE: u16 = 1 << 0;
+const SLEEP_TYPE_SHIFT: u16 = 10;
+const SLEEP_TYPE: u16 = 0b111 << SLEEP_TYPE_SHIFT;
+const SLEEP_ENABLE: u16 = 1 << 13;
+
+/// How many times to look at a status register before giving up on it, about
+/// a second's worth of port reads.
+const MAX_POLLS: usize = 1_000_000;
+
+/// The FADT's reset register, as `RESET_PRESENT | port << 8 | value`, or 0 if
+/// there is none.
+static RESET_REGISTER: AtomicU32 = AtomicU32::new(0);
+const RESET_PRESENT: u32 = 1 << 24;
+
+/// Remembers the FADT's reset register for `reset`. After `acpi::init`.
+pub fn init() {
+    if let Some((port, value)) = acpi::fadt().and_then(|fadt| fadt.reset) {
+        let register = RESET_PRESENT | u32::from(port) << 8 | u32::from(value);
+        RESET_REGISTER.store(register, Ordering::Relaxed);
+    }
+}
+
+/// Turns the machine off.
+pub fn shutdown() -> ! {
+    println!("shutting down");
+    shut_down_devices();
+    interrupts::disable();
+
+    if let (Some(fadt), Some((sleep_type_a, sleep_type_b))) = (acpi::fadt(), acpi::s5_sleep_types())
+    {
+        enable_acpi(&fadt);
+        unsafe {
+            enter_sleep_state(fadt.pm1a_control, sleep_type_a);
+            if fadt.pm1b_control != 0 {
+                enter_sleep_state(fadt.pm1b_control, sleep_type_b);
+            }
+        }
+    }
+
+    crate::exit_qemu(crate::QemuExitCode::Success);
+    println!("it's now safe to turn off your computer");
+    crate::hlt_loop();
+}
+
+/// Restarts the machine.
+pub fn reboot() -> ! {
+    println!("rebooting");
+    shut_down_devices();
+    reset();
+}
+
+/// Restarts the machine right away, without shutting the devices down, like
+/// the reset button. For when they can't be trusted to shut down, after a
+/// panic or a double fault. Takes no locks and doesn't allocate.
+pub fn reset() -> ! {
+    interrupts::disable();
+
+    let register = RESET_REGISTER.load(Ordering::Relaxed);
+    if register & RESET_PRESENT != 0 {
+        unsafe { Port::<u8>::new((register >> 8) as u16).write(register as u8) };
+    }
+
+    // Not through `ps2::COMMAND`, whose first access claims the ports, which
+    // takes the port registry's lock
+    let mut command = Port::<u8>::new(ps2::COMMAND.base());
+    unsafe {
+        for _ in 0..MAX_POLLS {
+            if command.read() & ps2::STATUS_INPUT_FULL == 0 {
+                break;
+            }
+        }
+        command.write(ps2::COMMAND_PULSE_RESET);
+    }
+
+    unsafe {
+        tables::lidt(&DescriptorTablePointer {
+            limit: 0,
+            base: VirtAddr::zero(),
+        });
+        x86_64::instructions::interrupts::int3();
+    }
+    crate::hlt_loop();
+}
+
+/// Shuts the devices down, while interrupts still work for them.
+fn shut_down_devices() {
+    if device::shutdown_all().is_err() {
+        println!("some devices didn't shut down cleanly, see the log");
+    }
+}
+
+/// Switches the firmware from legacy mode to ACPI, if it's in legacy mode.
+fn enable_acpi(fadt: &acpi::Fadt) {
+    let mut control = PortReadOnly::<u16>::new(fadt.pm1a_control);
+    if unsafe { control.read() } & SCI_ENABLE != 0 || fadt.smi_command == 0 {
+        return;
+    }
+
+    unsafe { Port::<u8>::new(fadt.smi_command).write(fadt.acpi_enable) };
+    for _ in 0..MAX_POLLS {
+        if unsafe { control.read() } & SCI_ENABLE != 0 {
+            return;
+        }
+    }
+}
+
+unsafe fn enter_sleep_state(pm1_control: u16, sleep_type: u16) {
+    let mut control = Port::<u16>::new(pm1_control);
+    let value = control.read() & !(SLEEP_TYPE | SLEEP_ENABLE);
+    control.write(value | (sleep_type << SLEEP_TYPE_SHIFT) & SLEEP_TYPE | SLEEP_ENABLE);
+}
+
+pub fn register_commands() {
+    shell::register("shutdown", "turn the machine off", |_args, _out| shutdown());
+    shell::register("reboot", "restart the machine", |_args, _out| reboot());
+}
@@ -0,0 +1,763 @@
+// User processes: programs loaded from ELF images that run in ring 3, each in
+// its own address space. A process also has a kernel stack, on which the CPU
+// handles interrupts that arrive while the process runs, and on which its kernel
+// context (the callee-saved registers of `switch`) is kept while it isn't running.
+//
+// The kernel itself, i.e. the executor running on the bootloader's stack, is
+// the process with PID 0. Switching between processes always happens in kernel
+// mode: `schedule` saves the current kernel context and resumes the next one.
+// A process that has never run yet resumes in `enter_user`, which drops to ring
+// 3 at the ELF entry point. Ring 3 code gets back into the kernel through
+// interrupts, and once a process has used up its time slice, the timer interrupt
+// hands the CPU to the next process in the run queue, so user code can't hang
+// the system. Kernel code is never preempted, since it may hold locks, so the
+// executor gives processes a turn with `yield_now`.
+//
+// A new process finds its arguments, environment and auxiliary vector on its
+// stack, laid out like the System V ABI says: the stack pointer points at argc,
+// followed by the argv pointers, a null, the envp pointers, a null, and the
+// auxv (type, value) pairs ending with AT_NULL. The strings come right after.
+//
+// A process that waits for something (a child to exit, a message to arrive)
+// blocks: it is taken off the run queue until `wake` puts it back. The kernel
+// never blocks, since it has to keep the executor going, so the run queue is
+// never empty while a process runs.
+//
+// A process that exits stays around as a zombie until its parent collects the
+// exit code with `wait`, which also frees the process's memory. The children of
+// an exited process are orphaned, and the kernel reaps them when they exit,
+// since nobody else can.
+
+use crate::elf::{Elf, ElfError, PF_W, PT_LOAD};
+use crate::memory::address_space::{self, AddressSpace, AddressSpaceError, USER_END};
+use crate::sync::Lazy;
+use crate::{file, gdt, idle, ipc, shm, syscall, tty};
+use alloc::{boxed::Box, collections::BTreeMap, collections::VecDeque, vec, vec::Vec};
+use core::arch::{asm, global_asm};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use kernel_stack::KernelStack;
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    registers::control::{Cr3, Cr3Flags},
+    structures::{
+        idt::InterruptStackFrame,
+        paging::{PageSize, PhysFrame, Size4KiB},
+    },
+    VirtAddr,
+};
+
+pub(crate) mod kernel_stack;
+
+pub const USER_STACK_SIZE: u64 = 64 * 1024;
+const USER_STACK_TOP: u64 = USER_END;
+
+/// How much of the user stack the arguments, environment and auxiliary vector
+/// may take up.
+pub const ARGS_MAX: u64 = 4096;
+
+// Auxiliary vector entry types
+const AT_NULL: u64 = 0;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+/// Interrupts enabled, plus the always-one reserved bit 1.
+const USER_RFLAGS: u64 = 0x202;
+
+/// How many timer ticks a process may run before it is preempted.
+pub const TIME_SLICE_TICKS: u64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(u64);
+
+impl Pid {
+    /// The kernel's own context.
+    pub const KERNEL: Pid = Pid(0);
+
+    pub fn from_u64(pid: u64) -> Pid {
+        Pid(pid)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Running or waiting for its turn.
+    Running,
+    /// Off the run queue until it is woken up, see `block`.
+    Blocked,
+    /// Called `exit` with the given code, and hasn't been waited for yet.
+    Zombie(i64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitError {
+    NoSuchProcess,
+    /// Only the parent of a process can wait for it.
+    NotAChild,
+}
+
+impl fmt::Display for WaitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WaitError::NoSuchProcess => f.write_str("no such process"),
+            WaitError::NotAChild => f.write_str("not a child process"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    Elf(ElfError),
+    Memory(AddressSpaceError),
+    /// The entry point isn't inside the user part of the address space.
+    BadEntryPoint,
+    NoKernelStack,
+    /// The arguments and environment take up more than `ARGS_MAX`.
+    ArgumentsTooLong,
+}
+
+impl From<ElfError> for SpawnError {
+    fn from(error: ElfError) -> Self {
+        SpawnError::Elf(error)
+    }
+}
+
+impl From<AddressSpaceError> for SpawnError {
+    fn from(error: AddressSpaceError) -> Self {
+        SpawnError::Memory(error)
+    }
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpawnError::Elf(error) => write!(f, "invalid executable: {}", error),
+            SpawnError::Memory(error) => write!(f, "can't map executable: {:?}", error),
+            SpawnError::BadEntryPoint => f.write_str("entry point outside of user space"),
+            SpawnError::NoKernelStack => f.write_str("no kernel stack available"),
+            SpawnError::ArgumentsTooLong => f.write_str("argument list too long"),
+        }
+    }
+}
+
+/// How a process has spent its time, in timer ticks.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Accounting {
+    /// Ticks that arrived while the process ran in ring 3.
+    pub user_ticks: u64,
+    /// Ticks that arrived while the process ran in the kernel, e.g. in a
+    /// system call. For the kernel itself, that's all of them.
+    pub kernel_ticks: u64,
+    /// How often the process was switched to.
+    pub dispatches: u64,
+    /// How often the process was switched away from because its time slice
+    /// was used up.
+    pub preemptions: u64,
+}
+
+pub struct Process {
+    pid: Pid,
+    // `None` for the kernel and orphans.
+    parent: Option<Pid>,
+    state: State,
+    // `None` for the kernel, which keeps using the bootloader's page table and stack.
+    address_space: Option<AddressSpace>,
+    kernel_stack: Option<KernelStack>,
+    // The kernel stack pointer saved by `switch` while the process isn't running.
+    saved_rsp: u64,
+    entry: VirtAddr,
+    user_stack_top: VirtAddr,
+    // Ticks left until the process is preempted, refilled when it is switched to.
+    slice_left: u64,
+    accounting: Accounting,
+}
+
+struct Scheduler {
+    // Boxed, so that `schedule` can hold on to `saved_rsp` after unlocking.
+    processes: BTreeMap<Pid, Box<Process>>,
+    run_queue: VecDeque<Pid>,
+    current: Pid,
+    next_pid: u64,
+    kernel_level_4_frame: PhysFrame,
+}
+
+static SCHEDULER: Lazy<Mutex<Scheduler>> = Lazy::new(|| {
+    let kernel = Process {
+        pid: Pid::KERNEL,
+        parent: None,
+        state: State::Running,
+        address_space: None,
+        kernel_stack: None,
+        saved_rsp: 0,
+        entry: VirtAddr::zero(),
+        user_stack_top: VirtAddr::zero(),
+        slice_left: TIME_SLICE_TICKS,
+        accounting: Accounting::default(),
+    };
+    let mut processes = BTreeMap::new();
+    processes.insert(Pid::KERNEL, Box::new(kernel));
+
+    Mutex::new(Scheduler {
+        processes,
+        run_queue: VecDeque::new(),
+        current: Pid::KERNEL,
+        next_pid: 1,
+        kernel_level_4_frame: Cr3::read().0,
+    })
+});
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+/// Counts the processes put back on the run queue, for MWAIT to watch while
+/// the kernel idles in `block`.
+static WAKEUPS: AtomicU64 = AtomicU64::new(0);
+
+/// Sets up process support. Needs the heap and `memory::init_global`.
+pub fn init() {
+    kernel_stack::init();
+    crate::usercopy::init();
+    crate::syscall::init();
+    Lazy::force(&SCHEDULER);
+    INITIALIZED.store(true, Ordering::Release);
+}
+
+impl Process {
+    /// Loads the given ELF executable into a new address space and queues it
+    /// to run, as a child of the current process. It gets no arguments, not
+    /// even its name.
+    pub fn spawn(elf: &[u8]) -> Result<Pid, SpawnError> {
+        Process::spawn_with_args(elf, &[], &[])
+    }
+
+    /// Like `spawn`, but passes the given arguments (by convention starting
+    /// with the program's name) and environment (`NAME=value` strings).
+    pub fn spawn_with_args(elf: &[u8], argv: &[&str], envp: &[&str]) -> Result<Pid, SpawnError> {
+        let (mut address_space, entry) = load(elf)?;
+        let user_stack_top = push_arguments(&mut address_space, entry, argv, envp)?;
+        let kernel_stack = KernelStack::allocate().ok_or(SpawnError::NoKernelStack)?;
+        let saved_rsp = unsafe { prepare_first_switch(kernel_stack.top()) };
+
+        interrupts::without_interrupts(|| {
+            let mut scheduler = SCHEDULER.lock();
+            let pid = Pid(scheduler.next_pid);
+            scheduler.next_pid += 1;
+
+            let process = Process {
+                pid,
+                parent: Some(scheduler.current),
+                state: State::Running,
+                address_space: Some(address_space),
+                kernel_stack: Some(kernel_stack),
+                saved_rsp,
+                entry,
+                user_stack_top,
+                slice_left: TIME_SLICE_TICKS,
+                accounting: Accounting::default(),
+            };
+            scheduler.processes.insert(pid, Box::new(process));
+            scheduler.run_queue.push_back(pid);
+
+            Ok(pid)
+        })
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    fn is_zombie(&self) -> bool {
+        matches!(self.state, State::Zombie(_))
+    }
+}
+
+/// Creates an address space with the executable's segments and a user stack,
+/// and returns it along with the entry point.
+fn load(bytes: &[u8]) -> Result<(AddressSpace, VirtAddr), SpawnError> {
+    let elf = Elf::parse(bytes)?;
+    let entry = VirtAddr::try_new(elf.entry()).map_err(|_| SpawnError::BadEntryPoint)?;
+    if !address_space::is_user_range(entry, 1) {
+        return Err(SpawnError::BadEntryPoint);
+    }
+
+    let mut address_space = AddressSpace::new()?;
+
+    for header in elf
+        .program_headers()
+        .filter(|header| header.kind == PT_LOAD)
+    {
+        if header.file_size > header.mem_size {
+            return Err(ElfError::BadProgramHeader.into());
+        }
+
+        let start =
+            VirtAddr::try_new(header.vaddr).map_err(|_| AddressSpaceError::OutsideUserSpace)?;
+        address_space.map_user(start, header.mem_size, header.flags & PF_W != 0)?;
+
+        // The rest of the segment (.bss) stays zeroed.
+        address_space.copy_to(start, elf.segment_data(&header)?)?;
+    }
+
+    let stack_bottom = VirtAddr::new(USER_STACK_TOP - USER_STACK_SIZE);
+    address_space.map_user(stack_bottom, USER_STACK_SIZE, true)?;
+
+    Ok((address_space, entry))
+}
+
+/// Writes the arguments, environment and auxiliary vector to the top of the
+/// user stack, and returns the initial stack pointer, which points at argc.
+fn push_arguments(
+    address_space: &mut AddressSpace,
+    entry: VirtAddr,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<VirtAddr, SpawnError> {
+    let auxv = [
+        (AT_PAGESZ, Size4KiB::SIZE),
+        (AT_ENTRY, entry.as_u64()),
+        (AT_NULL, 0),
+    ];
+    let words = 1 + argv.len() + 1 + envp.len() + 1 + 2 * auxv.len();
+    let strings_size: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+
+    // The stack pointer has to be 16-byte aligned at the entry point.
+    let size = x86_64::align_up((words * 8 + strings_size) as u64, 16);
+    if size > ARGS_MAX {
+        return Err(SpawnError::ArgumentsTooLong);
+    }
+    let stack_pointer = USER_STACK_TOP - size;
+    let strings_start = stack_pointer + words as u64 * 8;
+
+    let mut pointers = Vec::with_capacity(words);
+    let mut strings = Vec::with_capacity(strings_size);
+    pointers.push(argv.len() as u64);
+    for list in [argv, envp] {
+        for string in list {
+            pointers.push(strings_start + strings.len() as u64);
+            strings.extend_from_slice(string.as_bytes());
+            strings.push(0);
+        }
+        pointers.push(0);
+    }
+    for (kind, value) in auxv {
+        pointers.push(kind);
+        pointers.push(value);
+    }
+
+    let mut image = vec![0; size as usize];
+    for (chunk, pointer) in image.chunks_exact_mut(8).zip(&pointers) {
+        chunk.copy_from_slice(&pointer.to_le_bytes());
+    }
+    image[words * 8..words * 8 + strings.len()].copy_from_slice(&strings);
+
+    let stack_pointer = VirtAddr::new(stack_pointer);
+    address_space.copy_to(stack_pointer, &image)?;
+
+    Ok(stack_pointer)
+}
+
+// Saves the callee-saved registers on the current stack, stores the stack
+// pointer to `*old_rsp`, and resumes the context that saved `new_rsp` by popping
+// its registers and returning to wherever it called `process_switch` from. The
+// caller-saved registers are taken care of by the C calling convention.
+global_asm!(
+    ".global process_switch",
+    "process_switch:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+);
+
+extern "C" {
+    fn process_switch(old_rsp: *mut u64, new_rsp: u64);
+}
+
+/// Sets up a new kernel stack so that switching to it "returns" into `enter_user`.
+unsafe fn prepare_first_switch(stack_top: VirtAddr) -> u64 {
+    let frame: *mut u64 = stack_top.as_mut_ptr::<u64>().sub(8);
+
+    // The six registers popped by `process_switch`
+    for i in 0..6 {
+        frame.add(i).write(0);
+    }
+    frame.add(6).write(enter_user as usize as u64);
+    // A fake return address for `enter_user`, so that the stack pointer is
+    // aligned like it would be after a call.
+    frame.add(7).write(0);
+
+    frame as u64
+}
+
+/// The first thing a new process runs, in kernel mode on its own kernel stack.
+extern "C" fn enter_user() -> ! {
+    let (entry, user_stack_top) = {
+        let scheduler = SCHEDULER.lock();
+        let process = &scheduler.processes[&scheduler.current];
+        (process.entry, process.user_stack_top)
+    };
+    let selectors = gdt::selectors();
+
+    // Build the frame that `iretq` expects, as if an interrupt had arrived in
+    // ring 3 at the entry point.
+    unsafe {
+        asm!(
+            "push {ss}",
+            "push {stack}",
+            "push {rflags}",
+            "push {cs}",
+            "push {rip}",
+            "iretq",
+            ss = in(reg) u64::from(selectors.user_data_selector.0),
+            stack = in(reg) user_stack_top.as_u64(),
+            rflags = in(reg) USER_RFLAGS,
+            cs = in(reg) u64::from(selectors.user_code_selector.0),
+            rip = in(reg) entry.as_u64(),
+            options(noreturn)
+        );
+    }
+}
+
+/// Switches to the next process in the run queue, if there is one.
+///
+/// Must be called with interrupts disabled. Returns when the current process
+/// gets its next turn.
+fn schedule() {
+    let (old_rsp, new_rsp) = {
+        let mut scheduler = SCHEDULER.lock();
+        let next = match scheduler.run_queue.pop_front() {
+            Some(next) => next,
+            None => return,
+        };
+        let current = scheduler.current;
+        if scheduler.processes[&current].state == State::Running {
+            scheduler.run_queue.push_back(current);
+        }
+        scheduler.current = next;
+
+        let kernel_level_4_frame = scheduler.kernel_level_4_frame;
+        let old_rsp: *mut u64 = &mut scheduler.processes.get_mut(&current).unwrap().saved_rsp;
+        let process = scheduler.processes.get_mut(&next).unwrap();
+        process.slice_left = TIME_SLICE_TICKS;
+        process.accounting.dispatches += 1;
+
+        unsafe {
+            if let Some(stack) = &process.kernel_stack {
+                gdt::set_kernel_stack(stack.top());
+                syscall::set_kernel_stack(stack.top());
+            }
+            match &process.address_space {
+                Some(address_space) => address_space.activate(),
+                None => Cr3::write(kernel_level_4_frame, Cr3Flags::empty()),
+            }
+        }
+
+        (old_rsp, process.saved_rsp)
+    };
+
+    unsafe { process_switch(old_rsp, new_rsp) };
+}
+
+/// Lets the other processes run, until the current one gets its turn again.
+pub fn yield_now() {
+    if INITIALIZED.load(Ordering::Acquire) {
+        interrupts::without_interrupts(schedule);
+        reap_orphans();
+    }
+}
+
+/// Frees the orphans that have exited.
+fn reap_orphans() {
+    let orphans: Vec<Box<Process>> = interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let pids: Vec<Pid> = scheduler
+            .processes
+            .values()
+            .filter(|process| process.parent.is_none() && process.is_zombie())
+            .map(|process| process.pid)
+            .collect();
+
+        pids.iter()
+            .filter_map(|pid| scheduler.processes.remove(pid))
+            .collect()
+    });
+
+    // Dropping them frees their memory, which is better done without the lock.
+    drop(orphans);
+}
+
+/// Called by the timer interrupt handler, after the end of interrupt was sent.
+///
+/// Charges the tick to the current process, and switches to the next one if
+/// the current one has used up its time slice.
+pub(crate) fn timer_tick(stack_frame: &InterruptStackFrame) {
+    if !INITIALIZED.load(Ordering::Acquire) {
+        return;
+    }
+
+    let user_mode = stack_frame.code_segment & 3 == 3;
+
+    let preempt = {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        let others_waiting = !scheduler.run_queue.is_empty();
+        let process = scheduler.processes.get_mut(&current).unwrap();
+
+        if user_mode {
+            process.accounting.user_ticks += 1;
+        } else {
+            process.accounting.kernel_ticks += 1;
+        }
+        process.slice_left = process.slice_left.saturating_sub(1);
+
+        // Only preempt ring 3 code; the kernel might be holding locks. A
+        // process whose slice ran out during a system call is preempted on the
+        // next tick that catches it in ring 3.
+        let preempt = user_mode && process.slice_left == 0 && others_waiting;
+        if preempt {
+            process.accounting.preemptions += 1;
+        }
+        preempt
+    };
+
+    if preempt {
+        schedule();
+    }
+}
+
+/// Ends the current process with the given exit code.
+///
+/// Panics if called by the kernel.
+pub fn exit(code: i64) -> ! {
+    interrupts::disable();
+
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        assert!(current != Pid::KERNEL, "the kernel can't exit");
+
+        let process = scheduler.processes.get_mut(&current).unwrap();
+        process.state = State::Zombie(code);
+        let parent = process.parent;
+
+        for child in scheduler.processes.values_mut() {
+            if child.parent == Some(current) {
+                child.parent = None;
+            }
+        }
+
+        // The parent might be waiting for us
+        if let Some(parent) = parent {
+            scheduler.wake(parent);
+        }
+    }
+
+    file::release(current());
+    ipc::release(current());
+    shm::release(current());
+    tty::release(current());
+
+    // The kernel is always waiting in the run queue while a process runs, so
+    // this switches away for good.
+    schedule();
+    unreachable!("exited process was scheduled again");
+}
+
+/// Waits for the given child of the current process to exit, frees it, and
+/// returns its exit code.
+pub fn wait(pid: Pid) -> Result<i64, WaitError> {
+    loop {
+        let zombie = interrupts::without_interrupts(|| {
+            let mut scheduler = SCHEDULER.lock();
+            let current = scheduler.current;
+            let child = scheduler
+                .processes
+                .get(&pid)
+                .ok_or(WaitError::NoSuchProcess)?;
+
+            if child.parent != Some(current) {
+                return Err(WaitError::NotAChild);
+            }
+            if let State::Zombie(code) = child.state {
+                return Ok(Some((scheduler.processes.remove(&pid).unwrap(), code)));
+            }
+
+            // Woken up again by the child's `exit`
+            drop(scheduler);
+            block();
+
+            Ok(None)
+        })?;
+
+        // Dropping the process frees its memory, which is better done without
+        // the lock.
+        if let Some((process, code)) = zombie {
+            drop(process);
+            return Ok(code);
+        }
+    }
+}
+
+/// Gives up on waiting for the given child of the current process: it becomes
+/// an orphan, and the kernel frees it when it exits.
+pub fn detach(pid: Pid) -> Result<(), WaitError> {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        let child = scheduler
+            .processes
+            .get_mut(&pid)
+            .ok_or(WaitError::NoSuchProcess)?;
+
+        if child.parent != Some(current) {
+            return Err(WaitError::NotAChild);
+        }
+        child.parent = None;
+
+        Ok(())
+    })
+}
+
+/// Takes the current process off the run queue until `wake` is called for it,
+/// and lets the other processes run. The wakeup may be for something else than
+/// what the caller waits for, so it has to check again.
+///
+/// The kernel doesn't actually block: it lets the processes run, or waits for
+/// the next interrupt if there are none.
+///
+/// Must be called with interrupts disabled.
+pub(crate) fn block() {
+    let idle = {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        if current != Pid::KERNEL {
+            scheduler.processes.get_mut(&current).unwrap().state = State::Blocked;
+        }
+        scheduler.run_queue.is_empty()
+    };
+
+    if idle {
+        // Only the kernel can get here, see above.
+        // A process being woken wakes the CPU too, with MWAIT
+        idle::wait(&WAKEUPS as *const AtomicU64 as *const u8);
+        interrupts::disable();
+    } else {
+        schedule();
+    }
+}
+
+/// Puts a blocked process back on the run queue. Does nothing if the process
+/// isn't blocked.
+pub(crate) fn wake(pid: Pid) {
+    if INITIALIZED.load(Ordering::Acquire) {
+        interrupts::without_interrupts(|| SCHEDULER.lock().wake(pid));
+    }
+}
+
+impl Scheduler {
+    fn wake(&mut self, pid: Pid) {
+        if let Some(process) = self.processes.get_mut(&pid) {
+            if process.state == State::Blocked {
+                process.state = State::Running;
+                self.run_queue.push_back(pid);
+                WAKEUPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Returns the state of the given process, or `None` if there is no such process.
+pub fn state(pid: Pid) -> Option<State> {
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .processes
+            .get(&pid)
+            .map(|process| process.state)
+    })
+}
+
+/// Returns whether the current process may access `start..start + size`
+/// (for writing, if `write` is set). Always false for the kernel, which has
+/// no user memory.
+pub(crate) fn current_can_access(start: VirtAddr, size: u64, write: bool) -> bool {
+    with_current_address_space(|address_space| address_space.is_accessible(start, size, write))
+        .unwrap_or(false)
+}
+
+/// Runs `f` with the address space of the current process, or returns `None`
+/// for the kernel.
+pub(crate) fn with_current_address_space<R>(f: impl FnOnce(&mut AddressSpace) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+
+        scheduler
+            .processes
+            .get_mut(&current)?
+            .address_space
+            .as_mut()
+            .map(f)
+    })
+}
+
+/// Runs `f` with the address space of the process, or returns `None` if there
+/// is no such process, or it's the kernel.
+pub fn with_address_space<R>(pid: Pid, f: impl FnOnce(&mut AddressSpace) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .processes
+            .get_mut(&pid)?
+            .address_space
+            .as_mut()
+            .map(f)
+    })
+}
+
+/// Returns the PID of the running process.
+pub fn current() -> Pid {
+    if !INITIALIZED.load(Ordering::Acquire) {
+        return Pid::KERNEL;
+    }
+
+    interrupts::without_interrupts(|| SCHEDULER.lock().current)
+}
+
+/// Returns how many timer ticks the process has spent in ring 3, or `None` if
+/// there is no such process.
+pub fn user_ticks(pid: Pid) -> Option<u64> {
+    accounting(pid).map(|accounting| accounting.user_ticks)
+}
+
+/// Returns how the process has spent its time so far, or `None` if there is
+/// no such process.
+pub fn accounting(pid: Pid) -> Option<Accounting> {
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .processes
+            .get(&pid)
+            .map(|process| process.accounting)
+    })
+}
@@ -0,0 +1,105 @@
+// Every process has its own kernel stack, which the CPU switches to when an
+// interrupt arrives while the process runs in ring 3 (see `gdt::set_kernel_stack`),
+// and on which its kernel context is saved while other processes run.
+//
+// The stacks live in their own region of the kernel's address space, which
+// `memory::layout` places in level 4 entry 510. Each slot
+// has an unmapped guard page below the stack, so an overflow page faults instead
+// of silently corrupting the stack below it. The region's level 4 entry is
+// created by `init`, before any address space copies the kernel's level 4 table,
+// so that stacks mapped later are visible in every address space.
+
+use crate::memory::{self, layout};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{
+        page::PageRangeInclusive, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+const LEVEL_4_INDEX: usize = 510;
+
+const STACK_SIZE: u64 = 16 * 4096;
+const SLOT_SIZE: u64 = STACK_SIZE + 4096;
+const MAX_STACKS: usize = 256;
+
+/// How much room the kernel stacks take.
+pub const REGION_SIZE: u64 = MAX_STACKS as u64 * SLOT_SIZE;
+
+static USED_SLOTS: Mutex<[bool; MAX_STACKS]> = Mutex::new([false; MAX_STACKS]);
+
+pub fn init() {
+    memory::with_kernel_mapper(|mapper, frame_allocator| {
+        let entry = &mut mapper.level_4_table()[LEVEL_4_INDEX];
+        assert!(entry.is_unused(), "kernel stack region is already in use");
+
+        let frame = frame_allocator
+            .allocate_zeroed_frame()
+            .expect("no frame for the kernel stack region");
+        entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    });
+}
+
+pub struct KernelStack {
+    slot: usize,
+}
+
+impl KernelStack {
+    /// Maps a new kernel stack, or returns `None` if all slots are taken or
+    /// there is no memory left.
+    pub fn allocate() -> Option<KernelStack> {
+        let slot = interrupts::without_interrupts(|| {
+            let mut used = USED_SLOTS.lock();
+            let slot = used.iter().position(|used| !used)?;
+            used[slot] = true;
+            Some(slot)
+        })?;
+        // If mapping fails halfway, dropping the stack cleans up.
+        let stack = KernelStack { slot };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+        memory::with_kernel_mapper(|mapper, frame_allocator| {
+            for page in stack.pages() {
+                let frame = frame_allocator.allocate_zeroed_frame()?;
+                unsafe {
+                    mapper
+                        .map_to(page, frame, flags, frame_allocator)
+                        .ok()?
+                        .flush()
+                };
+            }
+            Some(())
+        })?;
+
+        Some(stack)
+    }
+
+    /// Returns the (exclusive) top of the stack, which is 16-byte aligned.
+    pub fn top(&self) -> VirtAddr {
+        VirtAddr::new(layout::kernel_stacks().start + (self.slot as u64 + 1) * SLOT_SIZE)
+    }
+
+    fn pages(&self) -> PageRangeInclusive<Size4KiB> {
+        let first = Page::containing_address(self.top() - STACK_SIZE);
+        let last = Page::containing_address(self.top() - 1u64);
+        Page::range_inclusive(first, last)
+    }
+}
+
+impl Drop for KernelStack {
+    /// Unmaps the stack and frees its memory. The stack must not be in use anymore.
+    fn drop(&mut self) {
+        memory::with_kernel_mapper(|mapper, frame_allocator| {
+            for page in self.pages() {
+                if let Ok((frame, flush)) = mapper.unmap(page) {
+                    flush.flush();
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+            }
+        });
+
+        interrupts::without_interrupts(|| USED_SLOTS.lock()[self.slot] = false);
+    }
+}
@@ -0,0 +1,127 @@
+// User programs that are built from user/ by build.rs and embedded into the
+// kernel image, so that there is something to run without a filesystem. The
+// shell can start them with `spawn`, which also starts the kernel's built-in
+// tasks (see task/executor.rs).
+//
+// `run` starts a program in the foreground instead: it gets the console, and
+// the shell waits for it to exit. It also finds the programs in the initrd's
+// /bin, and takes a path to any other executable.
+
+use crate::fs::{self, FsError};
+use crate::process::{self, Process, State};
+use crate::task::executor;
+use crate::{initrd, shell, time, tty};
+use alloc::{borrow::Cow, boxed::Box, format};
+use core::fmt::{self, Write};
+
+/// Prints a greeting and exits with 0.
+pub static HELLO: &[u8] = include_bytes!(concat!(env!("USER_PROGRAMS_DIR"), "/hello"));
+
+/// Exercises the system calls, including with bad arguments, and prints
+/// "stress: ok" if they all behave.
+pub static STRESS: &[u8] = include_bytes!(concat!(env!("USER_PROGRAMS_DIR"), "/stress"));
+
+/// Prints its arguments, like echo(1).
+pub static ECHO: &[u8] = include_bytes!(concat!(env!("USER_PROGRAMS_DIR"), "/echo"));
+
+/// Prints files, or its standard input, like cat(1).
+pub static CAT: &[u8] = include_bytes!(concat!(env!("USER_PROGRAMS_DIR"), "/cat"));
+
+/// All embedded programs, by name.
+pub static PROGRAMS: &[(&str, &[u8])] = &[
+    ("hello", HELLO),
+    ("stress", STRESS),
+    ("echo", ECHO),
+    ("cat", CAT),
+];
+
+/// Returns the executable of the embedded program with the given name.
+pub fn find(name: &str) -> Option<&'static [u8]> {
+    PROGRAMS
+        .iter()
+        .find(|(program, _)| *program == name)
+        .map(|(_, elf)| *elf)
+}
+
+/// Finds a program for `run`: the embedded one with that name, or else the
+/// one in the initrd's /bin. A name with a slash in it is a path.
+fn load(name: &str) -> Result<Cow<'static, [u8]>, FsError> {
+    if name.contains('/') {
+        return fs::read_file(name).map(Cow::Owned);
+    }
+    match find(name) {
+        Some(elf) => Ok(Cow::Borrowed(elf)),
+        None => fs::read_file(&format!("{}/bin/{}", initrd::MOUNT_PATH, name)).map(Cow::Owned),
+    }
+}
+
+pub fn register_commands() {
+    shell::register(
+        "spawn",
+        "start an embedded program or a built-in task in the background: spawn <name> [args...]",
+        |args, out| {
+            let name = match args.get(1) {
+                Some(name) => *name,
+                None => {
+                    write!(out, "usage: spawn <name> [args...]\nprograms:")?;
+                    for (name, _) in PROGRAMS {
+                        write!(out, " {}", name)?;
+                    }
+                    write!(out, "\ntasks:")?;
+                    for (name, _) in executor::BUILTINS {
+                        write!(out, " {}", name)?;
+                    }
+                    return writeln!(out);
+                }
+            };
+            if let Some((_, start)) = executor::BUILTINS.iter().find(|(task, _)| *task == name) {
+                return writeln!(out, "spawned {} as task {}", name, start());
+            }
+            let elf = match find(name) {
+                Some(elf) => elf,
+                None => return writeln!(out, "spawn: no such program: {}", name),
+            };
+
+            match Process::spawn_with_args(elf, &args[1..], &[]) {
+                Ok(pid) => {
+                    // Nobody waits for it, so the kernel frees it when it exits.
+                    let _ = process::detach(pid);
+                    writeln!(out, "spawned {} as process {}", name, pid)
+                }
+                Err(error) => writeln!(out, "spawn: {}", error),
+            }
+        },
+    );
+    shell::register_async(
+        "run",
+        "run a program in the foreground and wait for it to exit: run <name|path> [args...]",
+        |args, out| Box::pin(run(args, out)),
+    );
+}
+
+async fn run(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    let name = match args.get(1) {
+        Some(name) => *name,
+        None => return writeln!(out, "usage: run <name|path> [args...]"),
+    };
+    let pid = match load(name) {
+        Ok(elf) => match Process::spawn_with_args(&elf, &args[1..], &[]) {
+            Ok(pid) => pid,
+            Err(error) => return writeln!(out, "run: {}: {}", name, error),
+        },
+        Err(error) => return writeln!(out, "run: {}: {}", name, error),
+    };
+
+    // The console goes back to the shell when the process exits.
+    tty::set_foreground(Some(pid));
+    // `wait` would block the kernel, including the tasks that pass the console
+    // input on to the process, so the shell polls instead.
+    while !matches!(process::state(pid), Some(State::Zombie(_)) | None) {
+        time::sleep(1).await;
+    }
+
+    match process::wait(pid) {
+        Ok(code) => writeln!(out, "{} exited with {}", name, code),
+        Err(error) => writeln!(out, "run: {}: {}", name, error),
+    }
+}
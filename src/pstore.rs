@@ -0,0 +1,234 @@
+// A copy of the log that survives a reboot, like Linux's pstore. The log
+// ring buffer is gone once the machine resets, and with it everything about
+// why it did, since a triple fault doesn't leave the kernel a chance to write
+// a crash dump. So the logger mirrors every record into a piece of physical
+// memory that the frame allocator keeps out of use, the top `SIZE` bytes of
+// usable memory, which is in the same place on every boot of the same
+// machine. Memory keeps its contents over a reset (QEMU's does, anyway), and
+// neither bootloader touches the top of it.
+//
+// The region starts with a `Header`, followed by the log as a ring of bytes:
+//
+//     magic     "PSTORE01", for a region that holds a log at all
+//     capacity  how many bytes the ring holds
+//     boot      which boot wrote it, counting up from 1
+//     written   how many bytes it has written to the ring
+//
+// `init` looks for a header from the previous boot, keeps its log for the
+// `pstore` command and prints its last lines, and then starts the ring over
+// for this boot with what's been logged so far.
+
+use crate::memory::{self, BootInfoFrameAllocator};
+#[cfg(test)]
+use crate::HostTest;
+use crate::{info, logger, serial, shell, warn};
+use alloc::{string::String, vec::Vec};
+use core::fmt::{self, Write};
+use core::ptr;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// The size of the region, header and all.
+const SIZE: u64 = 64 * 1024;
+const MAGIC: u64 = u64::from_le_bytes(*b"PSTORE01");
+/// How many lines of the previous boot's log `init` prints.
+const TAIL_LINES: usize = 16;
+
+#[repr(C)]
+struct Header {
+    magic: u64,
+    capacity: u64,
+    boot: u64,
+    written: u64,
+}
+
+const HEADER_SIZE: usize = core::mem::size_of::<Header>();
+
+/// The region, through raw pointers with volatile accesses: the memory
+/// outlives us, so the compiler mustn't assume it knows what's in there.
+struct Region {
+    header: *mut Header,
+    ring: *mut u8,
+    capacity: usize,
+}
+
+// Only ever used under `STORE`'s lock.
+unsafe impl Send for Region {}
+
+impl Region {
+    /// # Safety
+    ///
+    /// `start` must point to `size` bytes that nothing else uses, aligned for
+    /// a `Header`.
+    unsafe fn new(start: *mut u8, size: usize) -> Region {
+        Region {
+            header: start as *mut Header,
+            ring: start.add(HEADER_SIZE),
+            capacity: size - HEADER_SIZE,
+        }
+    }
+
+    fn read_header(&self) -> Header {
+        unsafe { ptr::read_volatile(self.header) }
+    }
+
+    /// The previous boot's number and log, oldest first, if the region has
+    /// one.
+    fn previous(&self) -> Option<(u64, Vec<u8>)> {
+        let header = self.read_header();
+        if header.magic != MAGIC || header.capacity != self.capacity as u64 {
+            return None;
+        }
+
+        let written = header.written as usize;
+        let (start, len) = match written.checked_sub(self.capacity) {
+            Some(_) => (written % self.capacity, self.capacity),
+            None => (0, written),
+        };
+        let mut log: Vec<u8> = (0..len)
+            .map(|i| unsafe { ptr::read_volatile(self.ring.add((start + i) % self.capacity)) })
+            .collect();
+        // The oldest line was most likely cut in half by the wrap around.
+        if written > self.capacity {
+            let cut = log.iter().position(|&b| b == b'\n').map_or(0, |i| i + 1);
+            log.drain(..cut);
+        }
+        Some((header.boot, log))
+    }
+
+    /// Starts an empty log for boot number `boot`.
+    fn reset(&mut self, boot: u64) {
+        let header = Header {
+            magic: MAGIC,
+            capacity: self.capacity as u64,
+            boot,
+            written: 0,
+        };
+        unsafe { ptr::write_volatile(self.header, header) };
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        let header = self.read_header();
+        let mut written = header.written as usize;
+        for &byte in bytes {
+            unsafe { ptr::write_volatile(self.ring.add(written % self.capacity), byte) };
+            written += 1;
+        }
+        // After the bytes, so that a reset in between loses them but doesn't
+        // show garbage.
+        unsafe { ptr::write_volatile(ptr::addr_of_mut!((*self.header).written), written as u64) };
+    }
+}
+
+impl Write for Region {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+static STORE: Mutex<Option<Region>> = Mutex::new(None);
+/// The previous boot's number and log, for `pstore`.
+static PREVIOUS: Mutex<Option<(u64, Vec<u8>)>> = Mutex::new(None);
+
+/// Reserves the region and picks up the previous boot's log. Before
+/// `memory::init_global`, so that the frame allocator hasn't handed the
+/// region out, and once the heap is there.
+pub fn init(frame_allocator: &mut BootInfoFrameAllocator) {
+    let start = match frame_allocator.reserve_top(SIZE) {
+        Some(start) => start,
+        None => return warn!("pstore: no room for the persistent log"),
+    };
+    let mut region =
+        unsafe { Region::new(memory::phys_to_virt(start).as_mut_ptr(), SIZE as usize) };
+
+    let boot = match region.previous() {
+        Some((boot, log)) => {
+            print_tail(boot, &log);
+            *PREVIOUS.lock() = Some((boot, log));
+            boot + 1
+        }
+        None => 1,
+    };
+    region.reset(boot);
+
+    // What's been logged before now is in the ring buffer
+    interrupts::without_interrupts(|| {
+        let _ = logger::dmesg(&mut region);
+        *STORE.lock() = Some(region);
+    });
+    info!("pstore: boot {}, log at {:#x}", boot, start.as_u64());
+}
+
+/// Writes the previous boot's last lines to the serial log, around the
+/// logger, so that they don't end up in this boot's log as well.
+fn print_tail(boot: u64, log: &[u8]) {
+    let lines = log.iter().filter(|&&b| b == b'\n').count();
+    let skip = lines.saturating_sub(TAIL_LINES);
+    let start = match skip {
+        0 => 0,
+        skip => log
+            .iter()
+            .enumerate()
+            .filter(|(_, &b)| b == b'\n')
+            .nth(skip - 1)
+            .map_or(0, |(i, _)| i + 1),
+    };
+
+    let _ = serial::with_channel(serial::Channel::Log, |out| -> fmt::Result {
+        writeln!(out, "pstore: the last lines of boot {}:", boot)?;
+        for line in String::from_utf8_lossy(&log[start..]).lines() {
+            writeln!(out, "  {}", line)?;
+        }
+        Ok(())
+    });
+}
+
+/// Mirrors a log record, from the logger. Records are dropped while the
+/// region is locked, so that a crash in the middle of one doesn't hang the
+/// ones after it.
+pub(crate) fn write_record(record: &dyn fmt::Display) {
+    if let Some(mut store) = STORE.try_lock() {
+        if let Some(region) = store.as_mut() {
+            let _ = write!(region, "{}", record);
+        }
+    }
+}
+
+pub fn register_commands() {
+    shell::register(
+        "pstore",
+        "print the log of the previous boot",
+        |_args, out| match &*PREVIOUS.lock() {
+            Some((boot, log)) => {
+                writeln!(out, "boot {}:", boot)?;
+                out.write_str(&String::from_utf8_lossy(log))
+            }
+            None => writeln!(out, "pstore: no log from a previous boot"),
+        },
+    );
+}
+
+#[test_case]
+static KEEPS_LOG_OVER_REBOOT: HostTest = HostTest::new("pstore::keeps_log_over_reboot", || {
+    let memory = alloc::boxed::Box::leak(alloc::vec![0u64; 8].into_boxed_slice());
+    let start = memory.as_mut_ptr() as *mut u8;
+    let new_region = || unsafe { Region::new(start, 64) };
+
+    let mut region = new_region();
+    assert!(region.previous().is_none());
+    region.reset(1);
+    write!(region, "one\ntwo\n").unwrap();
+
+    // The next boot finds it, and wraps around
+    let mut region = new_region();
+    assert_eq!(region.previous(), Some((1, b"one\ntwo\n".to_vec())));
+    region.reset(2);
+    for line in ["first line\n", "second line\n", "third line\n"] {
+        write!(region, "{}", line).unwrap();
+    }
+    assert_eq!(
+        new_region().previous(),
+        Some((2, b"second line\nthird line\n".to_vec()))
+    );
+});
@@ -0,0 +1,190 @@
+// Random numbers for the kernel: ephemeral ports, TCP sequence numbers and
+// where `memory::layout` puts things for now, stack canaries later.
+//
+// Entropy comes from RDSEED and RDRAND when CPUID says the CPU has them, from
+// the jitter in how long CPUID itself takes (it's slow and, under a
+// hypervisor, trapped), and from when keyboard interrupts arrive. It's mixed
+// into the key of a ChaCha20 generator (RFC 8439), whose keystream is the
+// output. Every `fill` first takes a new key from the keystream, so that what
+// was handed out before can't be worked out from the generator's state later
+// ("fast key erasure").
+//
+// The generator is seeded on first use and takes in more entropy on every
+// `fill`. Nothing estimates how much entropy there is, so nothing ever waits
+// for more: early output on a CPU without RDRAND is only as good as the TSC
+// jitter.
+
+use crate::sync::Lazy;
+use crate::time;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::random::RdRand;
+
+/// "expand 32-byte k"
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+/// The nonce for mixing entropy in, which keeps it apart from the output's.
+const MIX_NONCE: u32 = 1;
+
+/// How many TSC jitter samples go into the first seed.
+const JITTER_SAMPLES: usize = 64;
+
+/// How many values RDSEED and RDRAND each add to the first seed.
+const SEED_WORDS: usize = 8;
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// The ChaCha20 block function: the 64 bytes of keystream for `key`, the
+/// block `counter` and `nonce`, as 16 little endian words.
+pub fn chacha20_block(key: &[u32; 8], counter: u32, nonce: &[u32; 3]) -> [u32; 16] {
+    let mut state = [0; 16];
+    state[0..4].copy_from_slice(&CONSTANTS);
+    state[4..12].copy_from_slice(key);
+    state[12] = counter;
+    state[13..16].copy_from_slice(nonce);
+
+    let mut block = state;
+    for _ in 0..10 {
+        quarter_round(&mut block, 0, 4, 8, 12);
+        quarter_round(&mut block, 1, 5, 9, 13);
+        quarter_round(&mut block, 2, 6, 10, 14);
+        quarter_round(&mut block, 3, 7, 11, 15);
+        quarter_round(&mut block, 0, 5, 10, 15);
+        quarter_round(&mut block, 1, 6, 11, 12);
+        quarter_round(&mut block, 2, 7, 8, 13);
+        quarter_round(&mut block, 3, 4, 9, 14);
+    }
+    for (word, initial) in block.iter_mut().zip(state.iter()) {
+        *word = word.wrapping_add(*initial);
+    }
+    block
+}
+
+struct Generator {
+    key: [u32; 8],
+}
+
+impl Generator {
+    fn seeded() -> Generator {
+        let mut generator = Generator { key: [0; 8] };
+        if has_rdseed() {
+            for _ in 0..SEED_WORDS {
+                if let Some(seed) = unsafe { rdseed() } {
+                    generator.mix(seed);
+                }
+            }
+        }
+        if let Some(rdrand) = RdRand::new() {
+            for _ in 0..SEED_WORDS {
+                if let Some(random) = rdrand.get_u64() {
+                    generator.mix(random);
+                }
+            }
+        }
+        for _ in 0..JITTER_SAMPLES {
+            generator.mix(tsc_jitter());
+        }
+        generator
+    }
+
+    /// Mixes `entropy` into the key.
+    fn mix(&mut self, entropy: u64) {
+        let nonce = [entropy as u32, (entropy >> 32) as u32, MIX_NONCE];
+        let block = chacha20_block(&self.key, 0, &nonce);
+        self.key.copy_from_slice(&block[0..8]);
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        let block = chacha20_block(&self.key, 0, &[0; 3]);
+        let mut next_key = [0; 8];
+        next_key.copy_from_slice(&block[0..8]);
+
+        for (counter, chunk) in (1..).zip(buf.chunks_mut(64)) {
+            let block = chacha20_block(&self.key, counter, &[0; 3]);
+            for (bytes, word) in chunk.chunks_mut(4).zip(block.iter()) {
+                bytes.copy_from_slice(&word.to_le_bytes()[..bytes.len()]);
+            }
+        }
+        self.key = next_key;
+    }
+}
+
+static GENERATOR: Lazy<Mutex<Generator>> = Lazy::new(|| Mutex::new(Generator::seeded()));
+
+/// The TSC values of interrupts, folded into one, until the next `fill`
+/// takes them.
+static INTERRUPT_TIMINGS: AtomicU64 = AtomicU64::new(0);
+
+fn has_rdseed() -> bool {
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    max_leaf >= 7 && unsafe { __cpuid(7) }.ebx & (1 << 18) != 0
+}
+
+/// Unsafe because the CPU must have RDSEED, see `has_rdseed`.
+#[target_feature(enable = "rdseed")]
+unsafe fn rdseed() -> Option<u64> {
+    let mut seed = 0;
+    // RDSEED fails while the CPU's entropy source catches up
+    for _ in 0..10 {
+        if core::arch::x86_64::_rdseed64_step(&mut seed) == 1 {
+            return Some(seed);
+        }
+    }
+    None
+}
+
+/// How long a CPUID takes, with the time it was taken at.
+fn tsc_jitter() -> u64 {
+    let start = time::tsc();
+    unsafe { __cpuid(0) };
+    let end = time::tsc();
+    (end - start).rotate_left(32) ^ start
+}
+
+/// Adds the time of an interrupt to the entropy. Called by interrupt
+/// handlers of devices that are driven from outside, like the keyboard.
+pub fn add_interrupt_timing() {
+    let now = time::tsc();
+    let _ = INTERRUPT_TIMINGS.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |timings| {
+        Some(timings.rotate_left(7) ^ now)
+    });
+}
+
+/// Fills `buf` with random bytes.
+pub fn fill(buf: &mut [u8]) {
+    let random = RdRand::new().and_then(|rdrand| rdrand.get_u64());
+    interrupts::without_interrupts(|| {
+        let mut generator = GENERATOR.lock();
+        let timings = INTERRUPT_TIMINGS.swap(0, Ordering::Relaxed);
+        if timings != 0 {
+            generator.mix(timings);
+        }
+        if let Some(random) = random {
+            generator.mix(random);
+        }
+        generator.fill(buf);
+    });
+}
+
+pub fn next_u32() -> u32 {
+    let mut bytes = [0; 4];
+    fill(&mut bytes);
+    u32::from_le_bytes(bytes)
+}
+
+pub fn next_u64() -> u64 {
+    let mut bytes = [0; 8];
+    fill(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
@@ -0,0 +1,278 @@
+// The real-time clock in the CMOS, the only wall clock a PC has.
+//
+// Its registers are read through an index port and a data port. The clock
+// updates them once a second, and reading while it does can mix up the old
+// time and the new one, so we wait for an update to be out of the way and read
+// until two reads in a row agree. Depending on status register B, the values
+// are BCD or binary, and the hour is 12- or 24-hour with the top bit for PM.
+//
+// The clock only has the last two digits of the year. The FADT can name a
+// century register, but QEMU's is always 20, so we take that for granted.
+//
+// Nothing here knows about time zones: the clock is taken to be in UTC, which
+// is what QEMU sets it to.
+//
+// The clock is only read to start `time::wall_clock` off, which `date` prints,
+// and which `time::sntp` keeps right from then on.
+
+use crate::io::PortRange;
+#[cfg(test)]
+use crate::HostTest;
+use crate::{shell, time};
+use core::fmt;
+use x86_64::instructions::interrupts;
+
+static CMOS: PortRange = PortRange::new("cmos", 0x70, 2);
+const CMOS_INDEX: u16 = 0;
+const CMOS_DATA: u16 = 1;
+// Keeps NMIs off while a register is selected. Nothing else masks them there,
+// so `read_register` turns them back on once it's done.
+const NMI_DISABLE: u8 = 0x80;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+
+// Status register bits
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const HOURS_24: u8 = 1 << 1;
+const BINARY: u8 = 1 << 2;
+const PM: u8 = 1 << 7;
+
+const CENTURY: u16 = 2000;
+
+/// A date and time of day, in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+impl DateTime {
+    /// The date and time `seconds` after the Unix epoch, 1970-01-01 00:00:00.
+    pub fn from_unix(seconds: u64) -> DateTime {
+        // From Howard Hinnant's `civil_from_days`, with years that start on
+        // March 1st so that the leap day comes last.
+        let days = seconds / 86400 + 719_468;
+        let era = days / 146_097;
+        let day_of_era = days % 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = era * 400 + year_of_era + u64::from(month <= 2);
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600 % 24) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+
+    /// The seconds since the Unix epoch. Dates before it are taken to be it.
+    pub fn to_unix(self) -> u64 {
+        // The reverse of `from_unix`
+        let (year, month) = match self.month {
+            1 | 2 => (u64::from(self.year) - 1, u64::from(self.month) + 9),
+            _ => (u64::from(self.year), u64::from(self.month) - 3),
+        };
+        let era = year / 400;
+        let year_of_era = year % 400;
+        let day_of_year = (153 * month + 2) / 5 + u64::from(self.day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = (era * 146_097 + day_of_era).saturating_sub(719_468);
+        days * 86400
+            + u64::from(self.hour) * 3600
+            + u64::from(self.minute) * 60
+            + u64::from(self.second)
+    }
+}
+
+/// The registers as they were read, before decoding.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Raw {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+}
+
+impl Raw {
+    fn decode(self, status_b: u8) -> DateTime {
+        let number = |value: u8| match status_b & BINARY {
+            0 => from_bcd(value),
+            _ => value,
+        };
+        let hour = match status_b & HOURS_24 {
+            0 => {
+                // 12 AM is midnight and 12 PM is noon
+                let hour = number(self.hour & !PM) % 12;
+                match self.hour & PM {
+                    0 => hour,
+                    _ => hour + 12,
+                }
+            }
+            _ => number(self.hour),
+        };
+        DateTime {
+            year: CENTURY + u16::from(number(self.year)),
+            month: number(self.month),
+            day: number(self.day),
+            hour,
+            minute: number(self.minute),
+            second: number(self.second),
+        }
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+unsafe fn read_register(register: u8) -> u8 {
+    CMOS.write(CMOS_INDEX, NMI_DISABLE | register);
+    let value = CMOS.read(CMOS_DATA);
+    CMOS.write(CMOS_INDEX, register);
+    value
+}
+
+unsafe fn read_raw() -> Raw {
+    while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    Raw {
+        second: read_register(SECONDS),
+        minute: read_register(MINUTES),
+        hour: read_register(HOURS),
+        day: read_register(DAY),
+        month: read_register(MONTH),
+        year: read_register(YEAR),
+    }
+}
+
+/// Reads the current date and time from the clock.
+pub fn now() -> DateTime {
+    interrupts::without_interrupts(|| unsafe {
+        let mut raw = read_raw();
+        loop {
+            let again = read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        raw.decode(read_register(STATUS_B))
+    })
+}
+
+pub fn register_commands() {
+    shell::register("date", "print the date and time", |args, out| {
+        match args.get(1) {
+            Some(&"-r") => writeln!(out, "{}", now()),
+            Some(_) => writeln!(out, "usage: date [-r], -r reads the RTC"),
+            None => writeln!(out, "{}", DateTime::from_unix(time::wall_clock().as_secs())),
+        }
+    });
+}
+
+#[test_case]
+static DECODES_REGISTERS: HostTest = HostTest::new("rtc::decodes_registers", || {
+    let raw = Raw {
+        second: 0x59,
+        minute: 0x30,
+        hour: 0x23,
+        day: 0x31,
+        month: 0x12,
+        year: 0x24,
+    };
+    let expected = DateTime {
+        year: 2024,
+        month: 12,
+        day: 31,
+        hour: 23,
+        minute: 30,
+        second: 59,
+    };
+    assert_eq!(raw.decode(HOURS_24), expected);
+
+    let binary = Raw {
+        second: 59,
+        minute: 30,
+        hour: 23,
+        day: 31,
+        month: 12,
+        year: 24,
+    };
+    assert_eq!(binary.decode(HOURS_24 | BINARY), expected);
+    assert_eq!(alloc::format!("{}", expected), "2024-12-31 23:30:59 UTC");
+});
+
+#[test_case]
+static DECODES_12_HOUR_CLOCK: HostTest = HostTest::new("rtc::decodes_12_hour_clock", || {
+    let hour = |hour: u8| {
+        let raw = Raw {
+            second: 0,
+            minute: 0,
+            hour,
+            day: 1,
+            month: 1,
+            year: 0,
+        };
+        raw.decode(0).hour
+    };
+    assert_eq!(hour(0x12), 0);
+    assert_eq!(hour(0x01), 1);
+    assert_eq!(hour(0x12 | PM), 12);
+    assert_eq!(hour(0x11 | PM), 23);
+});
+
+#[test_case]
+static CONVERTS_UNIX_TIME: HostTest = HostTest::new("rtc::converts_unix_time", || {
+    let date = |year, month, day| DateTime {
+        year,
+        month,
+        day,
+        hour: 0,
+        minute: 0,
+        second: 0,
+    };
+    assert_eq!(DateTime::from_unix(0), date(1970, 1, 1));
+    assert_eq!(date(2000, 3, 1).to_unix(), 951_868_800);
+    assert_eq!(DateTime::from_unix(951_868_799).day, 29);
+
+    let expected = DateTime {
+        year: 2024,
+        month: 12,
+        day: 31,
+        hour: 23,
+        minute: 30,
+        second: 59,
+    };
+    assert_eq!(expected.to_unix(), 1_735_687_859);
+    assert_eq!(DateTime::from_unix(1_735_687_859), expected);
+});
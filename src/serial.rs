@@ -1,29 +1,269 @@
-use lazy_static::lazy_static;
+use crate::sync::{spsc, Lazy, SpinLock};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::task::AtomicWaker;
+use mux::{Deframer, Framing};
 use spin::Mutex;
-use uart_16550::SerialPort;
-
-// Like with the VGA text buffer, we use lazy_static and a spinlock to create a
-// static writer instance. By using lazy_static we can ensure that the init
-// method is called exactly once on its first use.
-lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-        serial_port.init();
-        Mutex::new(serial_port)
+use uart::Uart;
+
+pub mod mux;
+pub mod uart;
+
+pub use mux::Channel;
+pub use uart::{DataBits, Parity, SerialPortId, StopBits, UartConfig, UartError};
+
+const INPUT_QUEUE_CAPACITY: usize = 100;
+
+// Like with the VGA text buffer, we use a Lazy static and a spinlock to create
+// a static writer instance. The Lazy makes sure that the UART is initialized
+// exactly once, on first use, and the lock keeps interrupts disabled while it's
+// held, so the serial interrupt handler can't deadlock against it. We prefer
+// COM1, but on real hardware it may be missing, so we fall back to the first
+// port that answers the probe. `configure` can switch to a different port
+// later on.
+pub static SERIAL: Lazy<SpinLock<Uart>> = Lazy::new(|| {
+    let id = SerialPortId::ALL
+        .iter()
+        .copied()
+        .find(|&id| uart::probe(id))
+        .unwrap_or(SerialPortId::Com1);
+    let uart = Uart::init(id, UartConfig::DEFAULT).expect("default UART config is valid");
+    set_active_port(id);
+
+    SpinLock::new(uart)
+});
+
+// The active port's index in `SerialPortId::ALL`, for `steal`, which can't
+// take the lock.
+static ACTIVE_PORT: AtomicU8 = AtomicU8::new(0);
+
+fn set_active_port(id: SerialPortId) {
+    let index = SerialPortId::ALL.iter().position(|&port| port == id);
+    ACTIVE_PORT.store(index.unwrap_or(0) as u8, Ordering::Relaxed);
+}
+
+/// Unmasks the IRQ line of the serial port used for kernel I/O, so that
+/// incoming bytes end up in the input stream.
+///
+/// Must be called after the PICs have been initialized.
+pub fn init() {
+    crate::interrupts::unmask_irq(active_port().irq());
+}
+
+/// Returns the port that is currently used for kernel I/O.
+pub fn active_port() -> SerialPortId {
+    SERIAL.lock().id()
+}
+
+/// Reprograms the given port with the given line settings and switches kernel
+/// serial I/O over to it.
+pub fn configure(
+    port: SerialPortId,
+    baud: u32,
+    data_bits: DataBits,
+    parity: Parity,
+    stop_bits: StopBits,
+) -> Result<(), UartError> {
+    let config = UartConfig {
+        baud,
+        data_bits,
+        parity,
+        stop_bits,
     };
+
+    {
+        let mut serial = SERIAL.lock();
+
+        if serial.id() != port && !uart::probe(port) {
+            return Err(UartError::NotPresent(port));
+        }
+
+        *serial = Uart::init(port, config)?;
+        set_active_port(port);
+    }
+
+    crate::interrupts::unmask_irq(port.irq());
+
+    Ok(())
+}
+
+/// Returns the COM ports that have a UART behind them.
+///
+/// The active port is reported without probing it, since the loopback probe
+/// would clobber output that is still in flight.
+pub fn detect_ports() -> impl Iterator<Item = SerialPortId> {
+    let active = active_port();
+
+    SerialPortId::ALL
+        .iter()
+        .copied()
+        .filter(move |&id| id == active || uart::probe(id))
+}
+
+// Same idea as the scancode queue in task/keyboard.rs, with one queue for
+// each channel (see mux.rs). Bytes are only queued once someone has asked for
+// the channel's input stream. The interrupt handler pushes with SERIAL locked,
+// so there's only ever one producer.
+struct Input {
+    queue: spsc::Queue<u8, INPUT_QUEUE_CAPACITY>,
+    taken: AtomicBool,
+    waker: AtomicWaker,
+}
+
+const NO_INPUT: Input = Input {
+    queue: spsc::Queue::new(),
+    taken: AtomicBool::new(false),
+    waker: AtomicWaker::new(),
+};
+
+static INPUTS: [Input; Channel::ALL.len()] = [NO_INPUT; Channel::ALL.len()];
+
+// Only the interrupt handler takes it, with SERIAL locked.
+static DEFRAMER: Mutex<Deframer> = Mutex::new(Deframer::new());
+
+/// Called by the serial interrupt handlers
+///
+/// Drains the receive buffer of the active UART. Must not block or allocate!
+pub(crate) fn handle_interrupt() {
+    // SERIAL keeps interrupts disabled while it's held, so we can't deadlock
+    // against ourselves here.
+    let mut serial = SERIAL.lock();
+
+    while let Some(byte) = serial.try_receive() {
+        match mux::framing() {
+            Framing::Plain => add_byte(Channel::Console, byte),
+            Framing::Framed => {
+                if let Some((channel, byte)) = DEFRAMER.lock().feed(byte) {
+                    add_byte(channel, byte);
+                }
+            }
+        }
+    }
+}
+
+fn add_byte(channel: Channel, byte: u8) {
+    let input = &INPUTS[channel as usize];
+    if input.taken.load(Ordering::Relaxed) && input.queue.push(byte).is_ok() {
+        input.waker.wake();
+    }
+    // We deliberately don't warn on a full queue here: the warning would be
+    // printed to the same serial line that is flooding us.
+}
+
+pub struct SerialInputStream {
+    input: &'static Input,
+}
+
+impl SerialInputStream {
+    pub fn new(channel: Channel) -> Self {
+        let input = &INPUTS[channel as usize];
+        assert!(
+            !input.taken.swap(true, Ordering::Relaxed),
+            "SerialInputStream::new should only be called once per channel"
+        );
+
+        SerialInputStream { input }
+    }
+}
+
+impl Stream for SerialInputStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let input = self.input;
+
+        // Fast path
+        if let Some(byte) = input.queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+
+        input.waker.register(cx.waker());
+
+        match input.queue.pop() {
+            Some(byte) => {
+                input.waker.take();
+                Poll::Ready(Some(byte))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Returns the stream of bytes received for the console on the active serial
+/// port.
+///
+/// Like `ScancodeStream::new`, this may only be called once.
+pub fn input_stream() -> SerialInputStream {
+    SerialInputStream::new(Channel::Console)
+}
+
+/// Returns the stream of bytes received for `channel`, which only framed
+/// framing has anything but the console in. May only be called once per
+/// channel.
+pub fn channel_input(channel: Channel) -> SerialInputStream {
+    SerialInputStream::new(channel)
+}
+
+/// Echoes everything typed into the host's serial console to the screen
+/// (and back to the host, since terminals don't echo for us).
+pub async fn echo_input() {
+    let mut input = input_stream();
+
+    while let Some(byte) = input.next().await {
+        let character = match byte {
+            b'\r' => '\n',
+            byte => char::from(byte),
+        };
+
+        crate::print!("{}", character);
+        crate::serial_print!("{}", character);
+    }
+}
+
+/// Releases the serial port lock if it is held.
+///
+/// # Safety
+///
+/// Only for crash paths: whoever holds the lock must never run again, or
+/// their output gets interleaved with ours.
+pub unsafe fn force_unlock() {
+    if SERIAL.try_lock().is_none() {
+        SERIAL.force_unlock();
+    }
+}
+
+/// The active port, without taking the lock, for talking to it polled.
+///
+/// # Safety
+///
+/// Only for crash paths: whoever else uses the port must never run again.
+pub unsafe fn steal() -> Uart {
+    let id = SerialPortId::ALL[usize::from(ACTIVE_PORT.load(Ordering::Relaxed))];
+    Uart::steal(id, UartConfig::DEFAULT)
+}
+
+/// Runs `f` with a writer to `channel` on the active port, which it holds
+/// until `f` returns, so that nothing else comes out in between.
+pub fn with_channel<R>(channel: Channel, f: impl FnOnce(&mut dyn fmt::Write) -> R) -> R {
+    let mut serial = SERIAL.lock();
+    let mut send = |byte| serial.send(byte);
+    let mut writer = mux::Writer::new(&mut send, channel, mux::framing());
+    f(&mut writer)
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    _print_to(Channel::Console, args);
 }
 
 #[doc(hidden)]
-pub fn _print(args: core::fmt::Arguments) {
-    use core::fmt::Write;
-    use x86_64::instructions::interrupts;
-
-    interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("printing to serial failed");
-    })
+pub fn _print_to(channel: Channel, args: fmt::Arguments) {
+    with_channel(channel, |out| out.write_fmt(args)).expect("printing to serial failed");
 }
 
 /// Prints to the host through the serial interface.
@@ -0,0 +1,231 @@
+// Sharing the serial port between the console, the log and a GDB stub.
+//
+// There's only the one line to the host, so everything written to it comes
+// out interleaved, and everything the host sends goes to the console. That's
+// what's wanted when a person reads it in a terminal, so it's the default,
+// "plain" framing. With `serial=framed` on the command line, every write goes
+// out in frames instead, and the host has to send frames too:
+//
+//     +---------+--------+-----------------+
+//     | channel | length | length bytes    |
+//     +---------+--------+-----------------+
+//
+// The channel is a `Channel`, and the length one byte, so a frame carries at
+// most `MAX_FRAME` bytes. Zero-length frames are allowed, and frames for a
+// channel the other side doesn't know are skipped. There is no resync: both
+// ends have to start at a frame boundary, which the kernel does from its
+// first byte on, so the host should connect before booting it.
+// tools/serial_mux.py is the host's end.
+//
+// In plain framing, the GDB channel's output is dropped, since a debugger and
+// a terminal can't share the line without frames.
+
+use crate::cmdline;
+use crate::sync::Lazy;
+#[cfg(test)]
+use crate::HostTest;
+use core::fmt;
+
+/// The most a frame carries.
+pub const MAX_FRAME: usize = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Channel {
+    /// The console: the shell, and processes' standard input and output.
+    Console = 0,
+    /// The log, and crash dumps.
+    Log = 1,
+    Gdb = 2,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 3] = [Channel::Console, Channel::Log, Channel::Gdb];
+
+    pub fn from_u8(channel: u8) -> Option<Channel> {
+        Channel::ALL.get(usize::from(channel)).copied()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    Plain,
+    Framed,
+}
+
+static FRAMING: Lazy<Framing> = Lazy::new(|| match cmdline::value("serial") {
+    Some("framed") => Framing::Framed,
+    _ => Framing::Plain,
+});
+
+/// How the serial port is shared, from the command line.
+pub fn framing() -> Framing {
+    *FRAMING
+}
+
+/// Sends what's written to it to one channel, through `send`. In framed
+/// framing, it collects up to a frame before sending, and sends the rest when
+/// it's dropped.
+pub struct Writer<'a> {
+    send: &'a mut dyn FnMut(u8),
+    channel: Channel,
+    framing: Framing,
+    buf: [u8; MAX_FRAME],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(send: &'a mut dyn FnMut(u8), channel: Channel, framing: Framing) -> Writer<'a> {
+        Writer {
+            send,
+            channel,
+            framing,
+            buf: [0; MAX_FRAME],
+            len: 0,
+        }
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        match (self.framing, self.channel) {
+            (Framing::Framed, _) => {
+                for &byte in bytes {
+                    if self.len == MAX_FRAME {
+                        self.flush();
+                    }
+                    self.buf[self.len] = byte;
+                    self.len += 1;
+                }
+            }
+            (Framing::Plain, Channel::Gdb) => {}
+            (Framing::Plain, _) => bytes.iter().for_each(|&byte| (self.send)(byte)),
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+        (self.send)(self.channel as u8);
+        (self.send)(self.len as u8);
+        for &byte in &self.buf[..self.len] {
+            (self.send)(byte);
+        }
+        self.len = 0;
+    }
+}
+
+impl fmt::Write for Writer<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl Drop for Writer<'_> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Takes the frames the host sends apart, a byte at a time.
+#[derive(Debug, Default)]
+pub struct Deframer {
+    state: State,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+enum State {
+    #[default]
+    Channel,
+    Length(u8),
+    Data {
+        channel: u8,
+        left: u8,
+    },
+}
+
+impl Deframer {
+    pub const fn new() -> Deframer {
+        Deframer {
+            state: State::Channel,
+        }
+    }
+
+    /// Takes the next byte from the host, and returns it with its channel if
+    /// it's data for a channel we know.
+    pub fn feed(&mut self, byte: u8) -> Option<(Channel, u8)> {
+        let (state, data) = match self.state {
+            State::Channel => (State::Length(byte), None),
+            State::Length(_) if byte == 0 => (State::Channel, None),
+            State::Length(channel) => (
+                State::Data {
+                    channel,
+                    left: byte,
+                },
+                None,
+            ),
+            State::Data { channel, left } => {
+                let state = match left - 1 {
+                    0 => State::Channel,
+                    left => State::Data { channel, left },
+                };
+                (
+                    state,
+                    Channel::from_u8(channel).map(|channel| (channel, byte)),
+                )
+            }
+        };
+        self.state = state;
+        data
+    }
+}
+
+#[test_case]
+static FRAMES_OUTPUT: HostTest = HostTest::new("serial::mux::frames_output", || {
+    use alloc::vec::Vec;
+    use core::fmt::Write;
+
+    let mut sent = Vec::new();
+    let mut send = |byte| sent.push(byte);
+    let mut writer = Writer::new(&mut send, Channel::Log, Framing::Framed);
+    write!(writer, "hi").unwrap();
+    writer.write_bytes(&[b'x'; MAX_FRAME]);
+    drop(writer);
+    assert_eq!(sent.len(), 2 + MAX_FRAME + 2 + 2);
+    assert_eq!(&sent[..4], &[1, MAX_FRAME as u8, b'h', b'i']);
+    assert_eq!(&sent[2 + MAX_FRAME..], &[1, 2, b'x', b'x']);
+
+    let mut sent = Vec::new();
+    let mut send = |byte| sent.push(byte);
+    write!(
+        Writer::new(&mut send, Channel::Console, Framing::Plain),
+        "hi"
+    )
+    .unwrap();
+    write!(
+        Writer::new(&mut send, Channel::Gdb, Framing::Plain),
+        "$g#67"
+    )
+    .unwrap();
+    assert_eq!(sent, b"hi");
+});
+
+#[test_case]
+static DEFRAMES_INPUT: HostTest = HostTest::new("serial::mux::deframes_input", || {
+    use alloc::vec::Vec;
+
+    let input = [0, 2, b'l', b's', 7, 1, b'?', 2, 0, 2, 1, b'$'];
+    let mut deframer = Deframer::new();
+    let data: Vec<_> = input
+        .iter()
+        .filter_map(|&byte| deframer.feed(byte))
+        .collect();
+    assert_eq!(
+        data,
+        [
+            (Channel::Console, b'l'),
+            (Channel::Console, b's'),
+            (Channel::Gdb, b'$')
+        ]
+    );
+});
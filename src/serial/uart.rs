@@ -0,0 +1,294 @@
+// A minimal driver for the 16550 UART found behind the legacy COM ports. Each
+// port is a block of eight I/O registers starting at its base address. Some of
+// the registers are overloaded: when the "divisor latch access bit" (DLAB) in the
+// line control register is set, the first two registers hold the low and high
+// byte of the baud rate divisor instead of the data and interrupt enable registers.
+
+use crate::io::PortRange;
+use core::fmt;
+
+/// The UART's internal clock runs at 115200 Hz, so the baud rate is programmed
+/// as a divisor of it.
+const UART_CLOCK_HZ: u32 = 115_200;
+
+const DATA: u16 = 0; // DLAB = 0: receive/transmit buffer, DLAB = 1: divisor low byte
+const INT_ENABLE: u16 = 1; // DLAB = 0: interrupt enable, DLAB = 1: divisor high byte
+const FIFO_CTRL: u16 = 2;
+const LINE_CTRL: u16 = 3;
+const MODEM_CTRL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const SCRATCH: u16 = 7;
+
+const LINE_CTRL_DLAB: u8 = 1 << 7;
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
+const LINE_STATUS_THR_EMPTY: u8 = 1 << 5;
+const INT_ENABLE_RECEIVED_DATA: u8 = 1 << 0;
+const MODEM_CTRL_NORMAL: u8 = 0x0B; // DTR, RTS and OUT2 (which gates the IRQ line)
+const MODEM_CTRL_LOOPBACK: u8 = 0x1E; // RTS, OUT1, OUT2 and loopback mode
+
+/// The four legacy COM ports. Their base addresses and IRQ lines are fixed by
+/// convention, although the BIOS data area may tell a different story on odd
+/// hardware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialPortId {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+static COM1: PortRange = PortRange::new("COM1", 0x3F8, 8);
+static COM2: PortRange = PortRange::new("COM2", 0x2F8, 8);
+static COM3: PortRange = PortRange::new("COM3", 0x3E8, 8);
+static COM4: PortRange = PortRange::new("COM4", 0x2E8, 8);
+
+impl SerialPortId {
+    pub const ALL: [SerialPortId; 4] = [
+        SerialPortId::Com1,
+        SerialPortId::Com2,
+        SerialPortId::Com3,
+        SerialPortId::Com4,
+    ];
+
+    pub fn base(self) -> u16 {
+        self.ports().base()
+    }
+
+    fn ports(self) -> &'static PortRange {
+        match self {
+            SerialPortId::Com1 => &COM1,
+            SerialPortId::Com2 => &COM2,
+            SerialPortId::Com3 => &COM3,
+            SerialPortId::Com4 => &COM4,
+        }
+    }
+
+    /// COM1/COM3 share IRQ4 and COM2/COM4 share IRQ3.
+    pub fn irq(self) -> u8 {
+        match self {
+            SerialPortId::Com1 | SerialPortId::Com3 => 4,
+            SerialPortId::Com2 | SerialPortId::Com4 => 3,
+        }
+    }
+}
+
+impl fmt::Display for SerialPortId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            SerialPortId::Com1 => "COM1",
+            SerialPortId::Com2 => "COM2",
+            SerialPortId::Com3 => "COM3",
+            SerialPortId::Com4 => "COM4",
+        };
+
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    Mark,
+    Space,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UartConfig {
+    pub baud: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl UartConfig {
+    /// 38400 baud, 8N1. This is what the uart_16550 crate used to program.
+    pub const DEFAULT: UartConfig = UartConfig {
+        baud: 38_400,
+        data_bits: DataBits::Eight,
+        parity: Parity::None,
+        stop_bits: StopBits::One,
+    };
+
+    /// Returns the divisor for the configured baud rate, or `None` if the
+    /// rate can't be derived from the UART clock exactly.
+    fn divisor(&self) -> Option<u16> {
+        if self.baud == 0 || UART_CLOCK_HZ % self.baud != 0 {
+            return None;
+        }
+
+        Some((UART_CLOCK_HZ / self.baud) as u16)
+    }
+
+    fn line_ctrl(&self) -> u8 {
+        let data_bits = match self.data_bits {
+            DataBits::Five => 0b00,
+            DataBits::Six => 0b01,
+            DataBits::Seven => 0b10,
+            DataBits::Eight => 0b11,
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => 1 << 2,
+        };
+        let parity = match self.parity {
+            Parity::None => 0b000 << 3,
+            Parity::Odd => 0b001 << 3,
+            Parity::Even => 0b011 << 3,
+            Parity::Mark => 0b101 << 3,
+            Parity::Space => 0b111 << 3,
+        };
+
+        data_bits | stop_bits | parity
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UartError {
+    /// The baud rate is zero or doesn't divide the 115200 Hz UART clock.
+    InvalidBaudRate(u32),
+    /// The port didn't pass the loopback probe.
+    NotPresent(SerialPortId),
+}
+
+pub struct Uart {
+    id: SerialPortId,
+    config: UartConfig,
+}
+
+impl Uart {
+    /// Programs the given port with the given line settings and enables the
+    /// "received data available" interrupt.
+    ///
+    /// The port is not probed; writes to an absent port simply go nowhere.
+    pub fn init(id: SerialPortId, config: UartConfig) -> Result<Uart, UartError> {
+        let divisor = config
+            .divisor()
+            .ok_or(UartError::InvalidBaudRate(config.baud))?;
+        let uart = Uart { id, config };
+
+        unsafe {
+            uart.write_reg(INT_ENABLE, 0x00); // Disable interrupts while we reprogram
+            uart.write_reg(LINE_CTRL, LINE_CTRL_DLAB);
+            uart.write_reg(DATA, divisor as u8);
+            uart.write_reg(INT_ENABLE, (divisor >> 8) as u8);
+            uart.write_reg(LINE_CTRL, config.line_ctrl()); // Also clears DLAB again
+            uart.write_reg(FIFO_CTRL, 0xC7); // Enable + clear FIFOs, 14-byte threshold
+            uart.write_reg(MODEM_CTRL, MODEM_CTRL_NORMAL);
+            uart.write_reg(INT_ENABLE, INT_ENABLE_RECEIVED_DATA);
+        }
+
+        Ok(uart)
+    }
+
+    /// The port `id`, the way someone else set it up, without reprogramming
+    /// it. `config` is only what `config` returns.
+    ///
+    /// # Safety
+    ///
+    /// Only for crash paths: whoever else uses the port must never run again.
+    pub unsafe fn steal(id: SerialPortId, config: UartConfig) -> Uart {
+        Uart { id, config }
+    }
+
+    pub fn id(&self) -> SerialPortId {
+        self.id
+    }
+
+    pub fn config(&self) -> UartConfig {
+        self.config
+    }
+
+    /// Sends a single byte, spinning until the transmit holding register is free.
+    pub fn send(&mut self, byte: u8) {
+        unsafe {
+            while self.read_reg(LINE_STATUS) & LINE_STATUS_THR_EMPTY == 0 {
+                core::hint::spin_loop();
+            }
+
+            self.write_reg(DATA, byte);
+        }
+    }
+
+    /// Returns the next received byte, if there is one.
+    pub fn try_receive(&mut self) -> Option<u8> {
+        unsafe {
+            if self.read_reg(LINE_STATUS) & LINE_STATUS_DATA_READY != 0 {
+                Some(self.read_reg(DATA))
+            } else {
+                None
+            }
+        }
+    }
+
+    unsafe fn read_reg(&self, offset: u16) -> u8 {
+        self.id.ports().read(offset)
+    }
+
+    unsafe fn write_reg(&self, offset: u16, value: u8) {
+        self.id.ports().write(offset, value)
+    }
+}
+
+impl fmt::Write for Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+/// Checks whether a UART is actually present at the given port.
+///
+/// Reading an unpopulated I/O port returns 0xFF, so we first check that the
+/// scratch register holds what we write into it and then send a byte through
+/// the UART's internal loopback path. The modem control register is restored
+/// afterwards, but the probe still clobbers any byte in flight, so don't probe
+/// the port that is currently used for output.
+pub fn probe(id: SerialPortId) -> bool {
+    let uart = Uart {
+        id,
+        config: UartConfig::DEFAULT,
+    };
+
+    unsafe {
+        uart.write_reg(SCRATCH, 0xAE);
+        if uart.read_reg(SCRATCH) != 0xAE {
+            return false;
+        }
+
+        let modem_ctrl = uart.read_reg(MODEM_CTRL);
+        uart.write_reg(MODEM_CTRL, MODEM_CTRL_LOOPBACK);
+        uart.write_reg(DATA, 0xAE);
+
+        // The byte comes back almost immediately, but give the UART a little
+        // time before declaring it broken.
+        let mut present = false;
+        for _ in 0..1000 {
+            if uart.read_reg(LINE_STATUS) & LINE_STATUS_DATA_READY != 0 {
+                present = uart.read_reg(DATA) == 0xAE;
+                break;
+            }
+        }
+
+        uart.write_reg(MODEM_CTRL, modem_ctrl);
+
+        present
+    }
+}
@@ -0,0 +1,150 @@
+// A small command shell that reads lines from the console TTY, i.e. from both
+// the keyboard and the serial port. The shell itself knows no commands except
+// `help` and `exit`: every subsystem adds its own with `register`, usually from
+// a `register_commands` function that is called once the heap is up (the
+// command table is a BTreeMap).
+//
+// Command handlers get the whitespace-separated arguments (with the command
+// name as `args[0]`, like argv) and a writer for their output, which goes to the
+// screen and the serial port when run from the shell.
+//
+// Commands that wait for something, like `ping` for its replies, are
+// registered with `register_async` instead. The shell awaits them, so that the
+// other tasks (including the ones they wait for) keep running meanwhile.
+
+use crate::sync::Lazy;
+use crate::tty::{self, Console};
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::fmt::{self, Write};
+use core::{future::Future, pin::Pin};
+use spin::Mutex;
+
+pub type Handler = fn(args: &[&str], out: &mut dyn Write) -> fmt::Result;
+
+/// A running async command.
+pub type CommandFuture<'a> = Pin<Box<dyn Future<Output = fmt::Result> + 'a>>;
+
+pub type AsyncHandler =
+    for<'a> fn(args: &'a [&'a str], out: &'a mut dyn Write) -> CommandFuture<'a>;
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Sync(Handler),
+    Async(AsyncHandler),
+}
+
+#[derive(Clone, Copy)]
+struct Command {
+    help: &'static str,
+    kind: Kind,
+}
+
+static COMMANDS: Lazy<Mutex<BTreeMap<&'static str, Command>>> = Lazy::new(|| {
+    let mut commands = BTreeMap::new();
+    commands.insert(
+        "help",
+        Command {
+            help: "list the available commands",
+            kind: Kind::Sync(help),
+        },
+    );
+    // `run` stops at `exit` before running it
+    commands.insert(
+        "exit",
+        Command {
+            help: "leave the shell",
+            kind: Kind::Sync(|_args, _out| Ok(())),
+        },
+    );
+    Mutex::new(commands)
+});
+
+const PROMPT: &str = "> ";
+
+/// Adds a command to the shell.
+///
+/// Panics if a command with that name already exists, since two subsystems
+/// silently fighting over a name would be confusing.
+pub fn register(name: &'static str, help: &'static str, handler: Handler) {
+    add(name, help, Kind::Sync(handler));
+}
+
+/// Adds an async command to the shell, see `register`.
+pub fn register_async(name: &'static str, help: &'static str, handler: AsyncHandler) {
+    add(name, help, Kind::Async(handler));
+}
+
+fn add(name: &'static str, help: &'static str, kind: Kind) {
+    let previous = COMMANDS.lock().insert(name, Command { help, kind });
+    assert!(
+        previous.is_none(),
+        "shell command {} registered twice",
+        name
+    );
+}
+
+/// Runs a single command line, writing the output to `out`.
+///
+/// Async commands can only be run from a task, with `execute_async`.
+pub fn execute(line: &str, out: &mut dyn Write) -> fmt::Result {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let name = match args.first() {
+        Some(name) => *name,
+        None => return Ok(()),
+    };
+
+    match lookup(name) {
+        Some(Kind::Sync(handler)) => handler(&args, out),
+        Some(Kind::Async(_)) => writeln!(out, "{}: can only be run from the shell", name),
+        None => writeln!(out, "unknown command: {} (try `help`)", name),
+    }
+}
+
+/// Runs a single command line, which may be an async command, writing the
+/// output to `out`.
+pub async fn execute_async(line: &str, out: &mut dyn Write) -> fmt::Result {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let name = match args.first() {
+        Some(name) => *name,
+        None => return Ok(()),
+    };
+
+    match lookup(name) {
+        Some(Kind::Sync(handler)) => handler(&args, out),
+        Some(Kind::Async(handler)) => handler(&args, out).await,
+        None => writeln!(out, "unknown command: {} (try `help`)", name),
+    }
+}
+
+fn lookup(name: &str) -> Option<Kind> {
+    // Don't hold the lock while the command runs, it may want to look at the
+    // command table itself (like `help` does).
+    COMMANDS.lock().get(name).map(|command| command.kind)
+}
+
+fn help(_args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    let commands: Vec<(&str, &str)> = COMMANDS
+        .lock()
+        .iter()
+        .map(|(name, command)| (*name, command.help))
+        .collect();
+
+    for (name, help) in commands {
+        writeln!(out, "  {:<12} {}", name, help)?;
+    }
+
+    Ok(())
+}
+
+/// Reads command lines from the console and runs them, until `exit`.
+pub async fn run() {
+    loop {
+        let _ = Console.write_str(PROMPT);
+
+        let line = tty::read_line().await;
+        if line.split_whitespace().next() == Some("exit") {
+            return;
+        }
+        let _ = execute_async(&line, &mut Console).await;
+    }
+}
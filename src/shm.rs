@@ -0,0 +1,130 @@
+// Shared memory between processes. `create` allocates a region and returns a
+// handle for it, which is passed on like a port ID, and any process that knows
+// the handle can map the region into its address space with `map`.
+//
+// The handle belongs to the process that created it and goes away when that
+// process exits, but the memory stays alive for as long as it is mapped
+// anywhere, see `SharedMemory`.
+
+use crate::memory::{address_space::AddressSpaceError, SharedMemory};
+use crate::process::{self, Pid};
+use alloc::{collections::BTreeMap, sync::Arc};
+use core::fmt;
+use spin::Mutex;
+use x86_64::{instructions::interrupts, VirtAddr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ShmId(u64);
+
+impl ShmId {
+    pub fn from_u64(id: u64) -> ShmId {
+        ShmId(id)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ShmId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShmError {
+    /// Zero, or larger than `memory::shared::MAX_SIZE`.
+    InvalidSize,
+    NoSuchRegion,
+    /// Only the process that created a region can destroy its handle.
+    NotOwner,
+    /// The kernel has no user address space to map anything into.
+    NoAddressSpace,
+    Memory(AddressSpaceError),
+}
+
+struct Region {
+    owner: Pid,
+    memory: Arc<SharedMemory>,
+}
+
+struct Regions {
+    regions: BTreeMap<ShmId, Region>,
+    next_id: u64,
+}
+
+static REGIONS: Mutex<Regions> = Mutex::new(Regions {
+    regions: BTreeMap::new(),
+    next_id: 1,
+});
+
+/// Allocates a zeroed shared memory region of at least `size` bytes, owned by
+/// the current process.
+pub fn create(size: u64) -> Result<ShmId, ShmError> {
+    if size == 0 || size > crate::memory::shared::MAX_SIZE {
+        return Err(ShmError::InvalidSize);
+    }
+
+    let memory =
+        SharedMemory::new(size).map_err(|_| ShmError::Memory(AddressSpaceError::OutOfMemory))?;
+    let owner = process::current();
+
+    Ok(interrupts::without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        let id = ShmId(regions.next_id);
+        regions.next_id += 1;
+        regions.regions.insert(id, Region { owner, memory });
+
+        id
+    }))
+}
+
+/// Returns the memory behind a handle, e.g. for the kernel to read or write it.
+pub fn get(id: ShmId) -> Option<Arc<SharedMemory>> {
+    interrupts::without_interrupts(|| {
+        REGIONS
+            .lock()
+            .regions
+            .get(&id)
+            .map(|region| region.memory.clone())
+    })
+}
+
+/// Maps the region into the current process's address space and returns its
+/// address there.
+pub fn map(id: ShmId) -> Result<VirtAddr, ShmError> {
+    let memory = get(id).ok_or(ShmError::NoSuchRegion)?;
+
+    process::with_current_address_space(|address_space| address_space.map_shared(memory))
+        .ok_or(ShmError::NoAddressSpace)?
+        .map_err(ShmError::Memory)
+}
+
+/// Removes the handle. Existing mappings stay valid.
+pub fn destroy(id: ShmId) -> Result<(), ShmError> {
+    let current = process::current();
+
+    let region = interrupts::without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        match regions.regions.get(&id) {
+            Some(region) if region.owner != current => Err(ShmError::NotOwner),
+            Some(_) => Ok(regions.regions.remove(&id)),
+            None => Err(ShmError::NoSuchRegion),
+        }
+    })?;
+
+    // Might free the memory, which is better done without the lock.
+    drop(region);
+    Ok(())
+}
+
+/// Removes the handles owned by the given process. Called when it exits.
+pub(crate) fn release(owner: Pid) {
+    interrupts::without_interrupts(|| {
+        REGIONS
+            .lock()
+            .regions
+            .retain(|_, region| region.owner != owner)
+    });
+}
@@ -0,0 +1,159 @@
+// The addresses of kernel functions are only known after linking, so the symbol
+// table can't be generated by the compiler. Instead we reserve a fixed-size,
+// zeroed section (`.ksymtab`) in the kernel image and fill it in afterwards:
+// `tools/embed_symbols.py` runs `nm` on the linked kernel and patches the section
+// contents with `objcopy --update-section`. Since the section size doesn't
+// change, no address in the kernel moves. The cargo runner (tools/runner.sh)
+// does this automatically before handing the kernel to bootimage.
+//
+// The section layout (all integers little endian):
+//
+//   header:  magic "KSYMTAB1" | count: u64 | strings_offset: u64
+//   entries: count * (address: u64 | size: u64 | name_offset: u32 | name_len: u32),
+//            sorted by address
+//   strings: the names, relative to strings_offset, not NUL-terminated
+//
+// If the kernel was started without going through the runner, the section is
+// still all zeros and every lookup simply fails.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+
+const SYMTAB_SIZE: usize = 512 * 1024;
+const MAGIC: &[u8; 8] = b"KSYMTAB1";
+const HEADER_SIZE: usize = 24;
+const ENTRY_SIZE: usize = 24;
+
+// The UnsafeCell keeps the compiler from treating the (all zero) initializer as
+// the section's actual contents and constant-folding our reads.
+#[repr(C, align(8))]
+struct SymbolTableSection(UnsafeCell<[u8; SYMTAB_SIZE]>);
+
+unsafe impl Sync for SymbolTableSection {}
+
+#[used]
+#[link_section = ".ksymtab"]
+static SYMTAB: SymbolTableSection = SymbolTableSection(UnsafeCell::new([0; SYMTAB_SIZE]));
+
+/// A resolved address: the function containing it and the offset into it.
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub address: u64,
+    pub offset: u64,
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}+{:#x}", self.name, self.offset)
+    }
+}
+
+struct Entry {
+    address: u64,
+    size: u64,
+    name_offset: usize,
+    name_len: usize,
+}
+
+fn table() -> &'static [u8] {
+    // Nothing writes to the section at runtime, the cell is only there to
+    // hide the initializer from the optimizer.
+    unsafe { &*SYMTAB.0.get() }
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(buf)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(buf)
+}
+
+/// Returns the number of entries, or `None` if no valid table was embedded.
+fn count() -> Option<usize> {
+    let table = table();
+
+    if &table[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+
+    let count = read_u64(table, 8) as usize;
+    if HEADER_SIZE + count * ENTRY_SIZE > SYMTAB_SIZE {
+        return None;
+    }
+
+    Some(count)
+}
+
+fn entry(index: usize) -> Entry {
+    let table = table();
+    let offset = HEADER_SIZE + index * ENTRY_SIZE;
+
+    Entry {
+        address: read_u64(table, offset),
+        size: read_u64(table, offset + 8),
+        name_offset: read_u32(table, offset + 16) as usize,
+        name_len: read_u32(table, offset + 20) as usize,
+    }
+}
+
+fn name(entry: &Entry) -> Option<&'static str> {
+    let table = table();
+    let start = read_u64(table, 16) as usize + entry.name_offset;
+    let bytes = table.get(start..start + entry.name_len)?;
+
+    core::str::from_utf8(bytes).ok()
+}
+
+/// Returns whether a symbol table was embedded into this kernel image.
+pub fn available() -> bool {
+    count().is_some()
+}
+
+/// Returns the number of embedded symbols.
+pub fn len() -> usize {
+    count().unwrap_or(0)
+}
+
+/// Looks up the function containing the given address.
+pub fn resolve(addr: u64) -> Option<Symbol> {
+    let count = count()?;
+
+    // Binary search for the last symbol starting at or before addr
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if entry(mid).address <= addr {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+
+    let entry = entry(low.checked_sub(1)?);
+    let offset = addr - entry.address;
+
+    // Symbols without a size (e.g. from assembly) match up to the next symbol.
+    if entry.size != 0 && offset >= entry.size {
+        return None;
+    }
+
+    Some(Symbol {
+        name: name(&entry)?,
+        address: entry.address,
+        offset,
+    })
+}
+
+/// Looks up a symbol by name and returns its address.
+pub fn lookup(name_to_find: &str) -> Option<u64> {
+    (0..count()?)
+        .map(entry)
+        .find(|entry| name(entry) == Some(name_to_find))
+        .map(|entry| entry.address)
+}
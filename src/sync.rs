@@ -0,0 +1,309 @@
+// Synchronization primitives shared by the rest of the kernel.
+//
+// `SpinLock` keeps interrupts disabled for as long as it's held. Otherwise an
+// interrupt handler that takes a lock the interrupted code holds (printing or
+// allocating from an interrupt, say) would spin forever, so every user had to
+// remember to wrap the lock in `without_interrupts`. The guard saves whether
+// interrupts were enabled in RFLAGS before it disabled them and restores that
+// when it's dropped, so it nests: only the outermost guard turns interrupts
+// back on.
+//
+// `RwSpinLock` is for data that's read far more often than it's changed, like
+// the block device registry and the mount table: any number of readers can
+// hold it at once, a writer holds it alone. It only disables interrupts if
+// it's made with `new_irq_safe`, for data that interrupt handlers get at too
+// or whose users might be preempted while holding it.
+
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use x86_64::instructions::interrupts;
+use x86_64::registers::rflags::{self, RFlags};
+
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
+pub mod mpsc;
+pub mod once;
+pub mod semaphore;
+pub mod seqlock;
+pub mod spsc;
+
+pub use once::{Lazy, Once};
+pub use semaphore::{Semaphore, SemaphorePermit};
+pub use seqlock::SeqLock;
+
+/// A spinlock that disables interrupts while it's held. It's also a wrapper
+/// around spin::Mutex to permit trait implementations, like `GlobalAlloc`.
+///
+/// With the `lockdep` feature enabled, the lock also remembers who holds it
+/// and panics with that information instead of spinning forever.
+pub struct SpinLock<T> {
+    inner: spin::Mutex<T>,
+    #[cfg(feature = "lockdep")]
+    owner: lockdep::Owner,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(inner: T) -> Self {
+        SpinLock {
+            inner: spin::Mutex::new(inner),
+            #[cfg(feature = "lockdep")]
+            owner: lockdep::Owner::new(),
+        }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        if let Some(guard) = self.try_lock() {
+            return guard;
+        }
+
+        crate::trace_event!("lock", "contended {:p}", self);
+
+        let flags = disable_interrupts();
+
+        #[cfg(feature = "lockdep")]
+        let guard = lockdep::acquire(
+            &self.inner,
+            &self.owner,
+            self as *const _ as *const (),
+            core::panic::Location::caller(),
+        );
+        #[cfg(not(feature = "lockdep"))]
+        let guard = self.inner.lock();
+
+        SpinLockGuard {
+            guard: ManuallyDrop::new(guard),
+            flags,
+            #[cfg(feature = "lockdep")]
+            owner: &self.owner,
+        }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        let flags = disable_interrupts();
+
+        let guard = match self.inner.try_lock() {
+            Some(guard) => guard,
+            None => {
+                restore_interrupts(flags);
+                return None;
+            }
+        };
+
+        #[cfg(feature = "lockdep")]
+        self.owner.set(core::panic::Location::caller());
+
+        Some(SpinLockGuard {
+            guard: ManuallyDrop::new(guard),
+            flags,
+            #[cfg(feature = "lockdep")]
+            owner: &self.owner,
+        })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.try_lock().is_none()
+    }
+
+    /// Releases the lock, whoever holds it.
+    ///
+    /// # Safety
+    ///
+    /// Only for crash paths: whoever holds the lock must never touch the data
+    /// again. Their guard won't restore the interrupt flag either.
+    pub unsafe fn force_unlock(&self) {
+        #[cfg(feature = "lockdep")]
+        self.owner.clear();
+
+        self.inner.force_unlock();
+    }
+
+    /// Returns who currently holds the lock, if anyone.
+    #[cfg(feature = "lockdep")]
+    pub fn owner(&self) -> Option<lockdep::OwnerInfo> {
+        self.owner.get()
+    }
+}
+
+/// Disables interrupts, returning RFLAGS from before.
+fn disable_interrupts() -> RFlags {
+    let flags = rflags::read();
+    interrupts::disable();
+    flags
+}
+
+/// Enables interrupts again if they were enabled in `flags`.
+fn restore_interrupts(flags: RFlags) {
+    if flags.contains(RFlags::INTERRUPT_FLAG) {
+        interrupts::enable();
+    }
+}
+
+pub struct SpinLockGuard<'a, T> {
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    /// RFLAGS from before the lock was taken.
+    flags: RFlags,
+    #[cfg(feature = "lockdep")]
+    owner: &'a lockdep::Owner,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "lockdep")]
+        self.owner.clear();
+
+        // The lock has to be free before an interrupt can come in and want it.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        restore_interrupts(self.flags);
+    }
+}
+
+/// A reader-writer spinlock: many readers or one writer.
+///
+/// Made with `new_irq_safe`, it disables interrupts while it's held, like
+/// `SpinLock`. Lockdep doesn't track it, since it can have many owners.
+pub struct RwSpinLock<T> {
+    inner: spin::RwLock<T>,
+    irq_safe: bool,
+}
+
+impl<T> RwSpinLock<T> {
+    /// A lock that leaves interrupts alone. Interrupt handlers must never
+    /// take it.
+    pub const fn new(inner: T) -> Self {
+        RwSpinLock {
+            inner: spin::RwLock::new(inner),
+            irq_safe: false,
+        }
+    }
+
+    /// A lock that disables interrupts while it's held.
+    pub const fn new_irq_safe(inner: T) -> Self {
+        RwSpinLock {
+            inner: spin::RwLock::new(inner),
+            irq_safe: true,
+        }
+    }
+
+    pub fn read(&self) -> RwSpinLockReadGuard<T> {
+        let flags = self.disable_interrupts();
+        RwSpinLockReadGuard {
+            guard: ManuallyDrop::new(self.inner.read()),
+            flags,
+        }
+    }
+
+    pub fn write(&self) -> RwSpinLockWriteGuard<T> {
+        let flags = self.disable_interrupts();
+        RwSpinLockWriteGuard {
+            guard: ManuallyDrop::new(self.inner.write()),
+            flags,
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwSpinLockReadGuard<T>> {
+        let flags = self.disable_interrupts();
+        match self.inner.try_read() {
+            Some(guard) => Some(RwSpinLockReadGuard {
+                guard: ManuallyDrop::new(guard),
+                flags,
+            }),
+            None => {
+                if let Some(flags) = flags {
+                    restore_interrupts(flags);
+                }
+                None
+            }
+        }
+    }
+
+    pub fn try_write(&self) -> Option<RwSpinLockWriteGuard<T>> {
+        let flags = self.disable_interrupts();
+        match self.inner.try_write() {
+            Some(guard) => Some(RwSpinLockWriteGuard {
+                guard: ManuallyDrop::new(guard),
+                flags,
+            }),
+            None => {
+                if let Some(flags) = flags {
+                    restore_interrupts(flags);
+                }
+                None
+            }
+        }
+    }
+
+    /// RFLAGS from before, if this lock disables interrupts.
+    fn disable_interrupts(&self) -> Option<RFlags> {
+        if self.irq_safe {
+            Some(disable_interrupts())
+        } else {
+            None
+        }
+    }
+}
+
+pub struct RwSpinLockReadGuard<'a, T> {
+    guard: ManuallyDrop<spin::RwLockReadGuard<'a, T>>,
+    flags: Option<RFlags>,
+}
+
+impl<T> Deref for RwSpinLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> Drop for RwSpinLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if let Some(flags) = self.flags {
+            restore_interrupts(flags);
+        }
+    }
+}
+
+pub struct RwSpinLockWriteGuard<'a, T> {
+    guard: ManuallyDrop<spin::RwLockWriteGuard<'a, T>>,
+    flags: Option<RFlags>,
+}
+
+impl<T> Deref for RwSpinLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for RwSpinLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for RwSpinLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if let Some(flags) = self.flags {
+            restore_interrupts(flags);
+        }
+    }
+}
@@ -0,0 +1,104 @@
+// Owner tracking for spinlocks, enabled by the `lockdep` cargo feature.
+//
+// A spinlock that is never released just makes the CPU spin silently. The most
+// common way to get there in this kernel is an interrupt handler taking a lock
+// that the code it interrupted already holds (e.g. printing or allocating from
+// an interrupt). With lockdep, every lock records the CPU and the source location
+// that acquired it, and a CPU that finds a lock held by itself, or that spins
+// for too long, panics with that information.
+
+use crate::{cpu, time};
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+/// How long we spin on a lock before declaring a deadlock. The TSC frequency
+/// isn't known here, so this is roughly one to a few seconds on current CPUs.
+const TIMEOUT_CYCLES: u64 = 1 << 32;
+
+const NO_OWNER: usize = usize::MAX;
+
+/// The owner information embedded in each tracked lock.
+pub struct Owner {
+    cpu: AtomicUsize,
+    location: AtomicPtr<Location<'static>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OwnerInfo {
+    pub cpu: usize,
+    pub location: &'static Location<'static>,
+}
+
+impl Owner {
+    pub const fn new() -> Self {
+        Owner {
+            cpu: AtomicUsize::new(NO_OWNER),
+            location: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    pub fn set(&self, location: &'static Location<'static>) {
+        self.location
+            .store(location as *const _ as *mut _, Ordering::Relaxed);
+        self.cpu.store(cpu::index(), Ordering::Release);
+    }
+
+    pub fn clear(&self) {
+        self.cpu.store(NO_OWNER, Ordering::Release);
+    }
+
+    pub fn get(&self) -> Option<OwnerInfo> {
+        let cpu = self.cpu.load(Ordering::Acquire);
+        let location = self.location.load(Ordering::Relaxed);
+
+        if cpu == NO_OWNER || location.is_null() {
+            return None;
+        }
+
+        Some(OwnerInfo {
+            cpu,
+            location: unsafe { &*location },
+        })
+    }
+}
+
+/// Spins until the mutex can be locked, recording `location` as the new owner.
+///
+/// Panics if the current CPU already holds the lock, since nothing could ever
+/// release it, or if the lock stays held for longer than `TIMEOUT_CYCLES`.
+pub fn acquire<'a, T>(
+    mutex: &'a spin::Mutex<T>,
+    owner: &Owner,
+    lock: *const (),
+    location: &'static Location<'static>,
+) -> spin::MutexGuard<'a, T> {
+    let start = time::tsc();
+
+    loop {
+        if let Some(guard) = mutex.try_lock() {
+            owner.set(location);
+            return guard;
+        }
+
+        // The owner is set right after the lock is taken, so it may briefly be
+        // missing. We'll catch it on the next iteration.
+        if let Some(holder) = owner.get() {
+            if holder.cpu == cpu::index() {
+                panic!(
+                    "lockdep: deadlock on lock {:p} at {}: already held by this CPU, acquired at {}",
+                    lock, location, holder.location
+                );
+            }
+
+            if time::tsc() - start > TIMEOUT_CYCLES {
+                panic!(
+                    "lockdep: timed out on lock {:p} at {}: held by CPU {}, acquired at {}",
+                    lock, location, holder.cpu, holder.location
+                );
+            }
+        }
+
+        core::hint::spin_loop();
+    }
+}
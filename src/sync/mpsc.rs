@@ -0,0 +1,144 @@
+// A bounded multi-producer, single-consumer queue with its storage inline,
+// for when several interrupt handlers or CPUs hand work to one task, like
+// wakers queueing tasks for the executor.
+//
+// It's Dmitry Vyukov's bounded queue, with one consumer. Every slot has a
+// stamp that says whose turn it is: for the position `p` that lands in it,
+// in lap `p / N` around the buffer, the stamp is `2 * lap` while the slot is
+// free for the producer of `p`, and `2 * lap + 1` once the value is in it and
+// the consumer can take it. Producers claim a position by moving `tail` on
+// with a compare-and-swap, write the value, and then flip the stamp, so the
+// consumer never sees a half-written value even though `tail` is already past
+// it.
+//
+// Only one pop may run at a time, like with `spsc::Queue`.
+
+use super::spsc::End;
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+struct Slot<T> {
+    stamp: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+impl<T> Slot<T> {
+    const FREE: Slot<T> = Slot {
+        stamp: AtomicUsize::new(0),
+        value: UnsafeCell::new(MaybeUninit::uninit()),
+    };
+}
+
+pub struct Queue<T, const N: usize> {
+    slots: [Slot<T>; N],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    popping: AtomicBool,
+}
+
+unsafe impl<T: Send, const N: usize> Sync for Queue<T, N> {}
+unsafe impl<T: Send, const N: usize> Send for Queue<T, N> {}
+
+impl<T, const N: usize> Queue<T, N> {
+    pub const fn new() -> Self {
+        Queue {
+            slots: [Slot::FREE; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            popping: AtomicBool::new(false),
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// The number of values in the queue. With producers running, it may be
+    /// out of date by the time it's returned.
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        self.tail.load(Ordering::Acquire).wrapping_sub(head).min(N)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The address of the index that every push moves on, for MONITOR to
+    /// watch.
+    pub fn tail_address(&self) -> *const u8 {
+        &self.tail as *const AtomicUsize as *const u8
+    }
+
+    fn stamps(position: usize) -> (usize, usize) {
+        let lap = position / N;
+        (lap.wrapping_mul(2), lap.wrapping_mul(2).wrapping_add(1))
+    }
+
+    /// Adds `value` at the end, or hands it back if the queue is full. Can be
+    /// called from anywhere, at the same time as other pushes.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut tail = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[tail % N];
+            let (free, full) = Queue::<T, N>::stamps(tail);
+            let stamp = slot.stamp.load(Ordering::Acquire);
+
+            if stamp == free {
+                match self.tail.compare_exchange_weak(
+                    tail,
+                    tail.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).as_mut_ptr().write(value) };
+                        slot.stamp.store(full, Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => tail = current,
+                }
+            } else if stamp.wrapping_add(1) == free {
+                // The value from the last lap is still there.
+                return Err(value);
+            } else {
+                // Another producer took this position.
+                core::hint::spin_loop();
+                tail = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Takes the value at the front, if there is one.
+    ///
+    /// Panics if another pop is running, since there may only be one
+    /// consumer.
+    pub fn pop(&self) -> Option<T> {
+        let _end = End::enter(&self.popping, "consumer");
+
+        let head = self.head.load(Ordering::Relaxed);
+        let slot = &self.slots[head % N];
+        let (_, full) = Queue::<T, N>::stamps(head);
+        if slot.stamp.load(Ordering::Acquire) != full {
+            return None;
+        }
+
+        let value = unsafe { (*slot.value.get()).as_ptr().read() };
+        slot.stamp.store(full.wrapping_add(1), Ordering::Release);
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+}
+
+impl<T, const N: usize> Default for Queue<T, N> {
+    fn default() -> Self {
+        Queue::new()
+    }
+}
+
+impl<T, const N: usize> Drop for Queue<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
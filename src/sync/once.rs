@@ -0,0 +1,167 @@
+// One-time initialization, in place of the lazy_static crate.
+//
+// `Once` runs its initializer the first time it's asked for the value, and
+// everyone else waits for that. The initializer runs with interrupts disabled,
+// so an interrupt handler can't come in halfway through and wait for it
+// forever. What's left is the same CPU asking again while it's initializing,
+// from the initializer itself or from an exception handler, which would spin
+// forever as well: that panics instead, with where the initialization started,
+// which is usually enough to untangle an init-order problem.
+//
+// `Lazy` is a `Once` that knows its initializer, for statics:
+//
+//     static TABLE: Lazy<Table> = Lazy::new(|| Table::new());
+
+use crate::cpu;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU8, AtomicUsize, Ordering};
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+const NO_CPU: usize = usize::MAX;
+
+pub struct Once<T> {
+    state: AtomicU8,
+    /// The CPU running the initializer, while it runs.
+    cpu: AtomicUsize,
+    /// Where the initialization was started.
+    location: AtomicPtr<Location<'static>>,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Once {
+            state: AtomicU8::new(INCOMPLETE),
+            cpu: AtomicUsize::new(NO_CPU),
+            location: AtomicPtr::new(ptr::null_mut()),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// The value, if it's been initialized.
+    pub fn get(&self) -> Option<&T> {
+        if self.is_completed() {
+            Some(unsafe { &*(*self.value.get()).as_ptr() })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+
+    /// The value, initialized with `init` if this is the first call. Waits if
+    /// another CPU is initializing it.
+    ///
+    /// Panics if this CPU is already initializing it.
+    #[track_caller]
+    pub fn call_once(&self, init: impl FnOnce() -> T) -> &T {
+        if let Some(value) = self.get() {
+            return value;
+        }
+
+        let location = Location::caller();
+        match self
+            .state
+            .compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {
+                self.location
+                    .store(location as *const _ as *mut _, Ordering::Relaxed);
+                self.cpu.store(cpu::index(), Ordering::Release);
+
+                let flags = super::disable_interrupts();
+                let value = init();
+                unsafe { (*self.value.get()).as_mut_ptr().write(value) };
+                self.cpu.store(NO_CPU, Ordering::Relaxed);
+                self.state.store(COMPLETE, Ordering::Release);
+                super::restore_interrupts(flags);
+            }
+            Err(_) => self.wait(location),
+        }
+
+        self.get().unwrap()
+    }
+
+    fn wait(&self, location: &'static Location<'static>) {
+        while self.state.load(Ordering::Acquire) != COMPLETE {
+            if self.cpu.load(Ordering::Acquire) == cpu::index() {
+                let started = self.location.load(Ordering::Relaxed);
+                panic!(
+                    "Once re-entered at {} while initializing it, started at {}",
+                    location,
+                    unsafe { &*started }
+                );
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl<T> Default for Once<T> {
+    fn default() -> Self {
+        Once::new()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Once<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.get() {
+            Some(value) => f.debug_tuple("Once").field(value).finish(),
+            None => f.write_str("Once(<uninitialized>)"),
+        }
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == COMPLETE {
+            unsafe { ptr::drop_in_place((*self.value.get()).as_mut_ptr()) };
+        }
+    }
+}
+
+/// A value that's initialized on first use.
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once<T>,
+    init: F,
+}
+
+impl<T, F> Lazy<T, F> {
+    pub const fn new(init: F) -> Self {
+        Lazy {
+            once: Once::new(),
+            init,
+        }
+    }
+}
+
+impl<T, F: Fn() -> T> Lazy<T, F> {
+    /// Initializes the value now, if it isn't yet. For when it should
+    /// happen at a known point, e.g. before interrupts can get to it.
+    #[track_caller]
+    pub fn force(this: &Self) -> &T {
+        this.once.call_once(&this.init)
+    }
+}
+
+impl<T, F: Fn() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    #[track_caller]
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
@@ -0,0 +1,119 @@
+// A counting semaphore, for bounding how many of something can be going on at
+// once, like the frames a network card has queued for sending.
+//
+// Tasks wait for a permit with `acquire_async`, which registers their waker
+// and lets the executor run something else. Code outside of tasks spins in
+// `acquire`. A permit goes back when its `SemaphorePermit` is dropped, which
+// wakes every waiting task; the ones that lose the race for it wait again.
+// Releasing doesn't block or allocate, so interrupt handlers can do it.
+
+use super::SpinLock;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: SpinLock<Vec<Waker>>,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            permits: AtomicUsize::new(permits),
+            waiters: SpinLock::new(Vec::new()),
+        }
+    }
+
+    /// The number of permits that are free right now.
+    pub fn available_permits(&self) -> usize {
+        self.permits.load(Ordering::Acquire)
+    }
+
+    /// Takes a permit if one is free.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| {
+                permits.checked_sub(1)
+            })
+            .ok()
+            .map(|_| SemaphorePermit { semaphore: self })
+    }
+
+    /// Spins until a permit is free, and takes it. For code that doesn't run
+    /// in a task; tasks should use `acquire_async`.
+    pub fn acquire(&self) -> SemaphorePermit<'_> {
+        loop {
+            if let Some(permit) = self.try_acquire() {
+                return permit;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Waits until a permit is free, letting other tasks run meanwhile, and
+    /// takes it.
+    pub fn acquire_async(&self) -> Acquire<'_> {
+        Acquire { semaphore: self }
+    }
+
+    /// Adds `count` permits, e.g. once a device is ready for more work.
+    pub fn add_permits(&self, count: usize) {
+        self.permits.fetch_add(count, Ordering::Release);
+        for waker in self.waiters.lock().drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// A permit taken from a semaphore. It's given back when dropped.
+#[must_use = "the permit is given back right away if it isn't kept"]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl SemaphorePermit<'_> {
+    /// Keeps the permit from going back, e.g. when the resource it stands
+    /// for is gone.
+    pub fn forget(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(1);
+    }
+}
+
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<SemaphorePermit<'a>> {
+        let semaphore = self.semaphore;
+
+        // Fast path
+        if let Some(permit) = semaphore.try_acquire() {
+            return Poll::Ready(permit);
+        }
+
+        {
+            let mut waiters = semaphore.waiters.lock();
+            if !waiters.iter().any(|waker| waker.will_wake(cx.waker())) {
+                waiters.push(cx.waker().clone());
+            }
+        }
+
+        // A permit that came back before we were on the list didn't wake us.
+        match semaphore.try_acquire() {
+            Some(permit) => Poll::Ready(permit),
+            None => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,73 @@
+// A sequence lock, for small data that's written rarely and read often, where
+// readers mustn't wait for each other or hold up the writer: the time the
+// timer interrupt last ticked, say.
+//
+// The writer makes the sequence number odd, changes the data, and makes it
+// even again. A reader copies the data out between two reads of the sequence
+// number, and tries again if they differ or were odd, since the writer was
+// busy in between. Readers don't write anything, so they don't bounce a cache
+// line between CPUs either.
+//
+// Writers take a `SpinLock` among themselves, which also keeps interrupts off
+// while they write: a reader in an interrupt handler would spin forever on
+// the data the interrupted writer left half-written. The data is `Copy`, since
+// a reader may copy it out while it's being written and throw the copy away.
+
+use super::SpinLock;
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{self, AtomicUsize, Ordering};
+
+pub struct SeqLock<T: Copy> {
+    sequence: AtomicUsize,
+    data: UnsafeCell<T>,
+    writer: SpinLock<()>,
+}
+
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+impl<T: Copy> SeqLock<T> {
+    pub const fn new(data: T) -> Self {
+        SeqLock {
+            sequence: AtomicUsize::new(0),
+            data: UnsafeCell::new(data),
+            writer: SpinLock::new(()),
+        }
+    }
+
+    /// A copy of the data, as some writer left it.
+    pub fn read(&self) -> T {
+        loop {
+            let before = self.sequence.load(Ordering::Acquire);
+            if before % 2 == 1 {
+                core::hint::spin_loop();
+                continue;
+            }
+
+            let data = unsafe { ptr::read_volatile(self.data.get()) };
+            atomic::fence(Ordering::Acquire);
+
+            if self.sequence.load(Ordering::Relaxed) == before {
+                return data;
+            }
+        }
+    }
+
+    /// Changes the data with `f`. Readers see all of the change or none of
+    /// it.
+    pub fn write(&self, f: impl FnOnce(&mut T)) {
+        let _writer = self.writer.lock();
+
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence
+            .store(sequence.wrapping_add(1), Ordering::Relaxed);
+        atomic::fence(Ordering::Release);
+
+        let mut data = unsafe { ptr::read_volatile(self.data.get()) };
+        f(&mut data);
+        unsafe { ptr::write_volatile(self.data.get(), data) };
+
+        self.sequence
+            .store(sequence.wrapping_add(2), Ordering::Release);
+    }
+}
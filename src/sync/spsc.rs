@@ -0,0 +1,337 @@
+// A bounded single-producer, single-consumer queue with its storage inline,
+// for handing bytes and events from an interrupt handler to a task. Nothing
+// allocates, so it can be a plain static:
+//
+//     static SCANCODES: Queue<u8, 100> = Queue::new();
+//
+// `head` and `tail` count the values popped and pushed so far; they only ever
+// go up, and their difference is the length. The producer only writes `tail`
+// and the consumer only writes `head`, so neither needs a lock, and a value's
+// slot is written before `tail` moves past it (and read before `head` does).
+//
+// Only one push and one pop may run at a time. Each end has a flag that's set
+// while it's in use, so breaking that panics instead of corrupting the queue.
+//
+// `GrowableQueue` is the same, but it keeps count of the values it had to
+// turn away and of the most it ever held, and the consumer can move it to
+// bigger storage on the heap when that's not enough. Only a consumer in task
+// context can, since it allocates, and the producer must not be able to run
+// meanwhile: when it's an interrupt handler, `grow` has to be called with
+// interrupts off.
+
+#[cfg(test)]
+use crate::HostTest;
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+pub struct Queue<T, const N: usize> {
+    buffer: UnsafeCell<MaybeUninit<[T; N]>>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    pushing: AtomicBool,
+    popping: AtomicBool,
+}
+
+unsafe impl<T: Send, const N: usize> Sync for Queue<T, N> {}
+unsafe impl<T: Send, const N: usize> Send for Queue<T, N> {}
+
+impl<T, const N: usize> Queue<T, N> {
+    pub const fn new() -> Self {
+        Queue {
+            buffer: UnsafeCell::new(MaybeUninit::uninit()),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            pushing: AtomicBool::new(false),
+            popping: AtomicBool::new(false),
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        tail.wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= N
+    }
+
+    fn slot(&self, position: usize) -> *mut T {
+        unsafe { (self.buffer.get() as *mut T).add(position % N) }
+    }
+
+    /// Adds `value` at the end, or hands it back if the queue is full.
+    ///
+    /// Panics if another push is running, since there may only be one
+    /// producer.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let _end = End::enter(&self.pushing, "producer");
+
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) >= N {
+            return Err(value);
+        }
+        unsafe { self.slot(tail).write(value) };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Takes the value at the front, if there is one.
+    ///
+    /// Panics if another pop is running, since there may only be one
+    /// consumer.
+    pub fn pop(&self) -> Option<T> {
+        let _end = End::enter(&self.popping, "consumer");
+
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let value = unsafe { self.slot(head).read() };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+}
+
+impl<T, const N: usize> Default for Queue<T, N> {
+    fn default() -> Self {
+        Queue::new()
+    }
+}
+
+impl<T, const N: usize> Drop for Queue<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+pub struct GrowableQueue<T: Copy, const N: usize> {
+    inline: UnsafeCell<MaybeUninit<[T; N]>>,
+    /// The storage on the heap, once it has grown, or null.
+    heap: AtomicPtr<T>,
+    capacity: AtomicUsize,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    pushing: AtomicBool,
+    popping: AtomicBool,
+    dropped: AtomicU64,
+    high_water: AtomicUsize,
+}
+
+unsafe impl<T: Copy + Send, const N: usize> Sync for GrowableQueue<T, N> {}
+unsafe impl<T: Copy + Send, const N: usize> Send for GrowableQueue<T, N> {}
+
+impl<T: Copy, const N: usize> GrowableQueue<T, N> {
+    pub const fn new() -> Self {
+        GrowableQueue {
+            inline: UnsafeCell::new(MaybeUninit::uninit()),
+            heap: AtomicPtr::new(ptr::null_mut()),
+            capacity: AtomicUsize::new(N),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            pushing: AtomicBool::new(false),
+            popping: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+            high_water: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity.load(Ordering::Relaxed)
+    }
+
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        tail.wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How many values `push` turned away, since the queue was made.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// The most values the queue ever held at once.
+    pub fn high_water(&self) -> usize {
+        self.high_water.load(Ordering::Relaxed)
+    }
+
+    fn slot(&self, position: usize) -> *mut T {
+        let heap = self.heap.load(Ordering::Relaxed);
+        let buffer = if heap.is_null() {
+            self.inline.get() as *mut T
+        } else {
+            heap
+        };
+        unsafe { buffer.add(position % self.capacity()) }
+    }
+
+    /// Adds `value` at the end, or counts it as dropped and hands it back if
+    /// the queue is full.
+    ///
+    /// Panics if another push is running, since there may only be one
+    /// producer.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let _end = End::enter(&self.pushing, "producer");
+
+        let tail = self.tail.load(Ordering::Relaxed);
+        let len = tail.wrapping_sub(self.head.load(Ordering::Acquire));
+        if len >= self.capacity() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return Err(value);
+        }
+        unsafe { self.slot(tail).write(value) };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        self.high_water.fetch_max(len + 1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Takes the value at the front, if there is one.
+    ///
+    /// Panics if another pop is running, since there may only be one
+    /// consumer.
+    pub fn pop(&self) -> Option<T> {
+        let _end = End::enter(&self.popping, "consumer");
+
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let value = unsafe { self.slot(head).read() };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    /// Moves the queue to storage for `capacity` values, keeping the ones it
+    /// has. Only for the consumer, and the producer mustn't be able to run
+    /// meanwhile, see above. Returns `false` if the queue is that big
+    /// already, or a push is running after all.
+    pub fn grow(&self, capacity: usize) -> bool {
+        let _consumer = End::enter(&self.popping, "consumer");
+        if capacity <= self.capacity() {
+            return false;
+        }
+        if self
+            .pushing
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return false;
+        }
+
+        let mut storage: Box<[MaybeUninit<T>]> =
+            (0..capacity).map(|_| MaybeUninit::uninit()).collect();
+        let head = self.head.load(Ordering::Relaxed);
+        let len = self.tail.load(Ordering::Relaxed).wrapping_sub(head);
+        for (i, slot) in storage.iter_mut().take(len).enumerate() {
+            *slot = MaybeUninit::new(unsafe { self.slot(head.wrapping_add(i)).read() });
+        }
+
+        let old_capacity = self.capacity();
+        let old = self
+            .heap
+            .swap(Box::into_raw(storage) as *mut T, Ordering::Relaxed);
+        self.capacity.store(capacity, Ordering::Relaxed);
+        self.head.store(0, Ordering::Relaxed);
+        self.tail.store(len, Ordering::Relaxed);
+        self.pushing.store(false, Ordering::Release);
+
+        unsafe { free(old, old_capacity) };
+        true
+    }
+}
+
+/// Frees heap storage of a `GrowableQueue`, if it's that and not null.
+unsafe fn free<T>(storage: *mut T, capacity: usize) {
+    if !storage.is_null() {
+        let storage = ptr::slice_from_raw_parts_mut(storage as *mut MaybeUninit<T>, capacity);
+        drop(Box::from_raw(storage));
+    }
+}
+
+impl<T: Copy, const N: usize> Default for GrowableQueue<T, N> {
+    fn default() -> Self {
+        GrowableQueue::new()
+    }
+}
+
+impl<T: Copy, const N: usize> Drop for GrowableQueue<T, N> {
+    fn drop(&mut self) {
+        unsafe { free(*self.heap.get_mut(), *self.capacity.get_mut()) };
+    }
+}
+
+/// Marks one end of a queue as in use until it's dropped.
+pub(super) struct End<'a>(&'a AtomicBool);
+
+impl<'a> End<'a> {
+    pub(super) fn enter(flag: &'a AtomicBool, role: &str) -> End<'a> {
+        if flag.swap(true, Ordering::Acquire) {
+            panic!("queue: more than one {} at a time", role);
+        }
+        End(flag)
+    }
+}
+
+impl Drop for End<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+#[test_case]
+static WRAPS_AROUND: HostTest = HostTest::new("sync::spsc::wraps_around", || {
+    let queue: Queue<usize, 4> = Queue::new();
+    for round in 0..3 {
+        for i in 0..4 {
+            assert_eq!(queue.push(round * 4 + i), Ok(()));
+        }
+        assert_eq!(queue.push(99), Err(99));
+        assert!(queue.is_full());
+
+        for i in 0..4 {
+            assert_eq!(queue.pop(), Some(round * 4 + i));
+        }
+        assert_eq!(queue.pop(), None);
+    }
+});
+
+#[test_case]
+static GROWS: HostTest = HostTest::new("sync::spsc::grows", || {
+    let queue: GrowableQueue<usize, 4> = GrowableQueue::new();
+    // Wrapped around once, so that growing has to straighten it out
+    for i in 0..6 {
+        assert_eq!(queue.push(i), Ok(()));
+        if i < 2 {
+            assert_eq!(queue.pop(), Some(i));
+        }
+    }
+    assert_eq!(queue.push(6), Err(6));
+    assert_eq!((queue.dropped(), queue.high_water()), (1, 4));
+
+    assert!(!queue.grow(4));
+    assert!(queue.grow(8));
+    assert_eq!((queue.capacity(), queue.len()), (8, 4));
+    for i in 6..10 {
+        assert_eq!(queue.push(i), Ok(()));
+    }
+    assert!(queue.grow(16));
+    for i in 2..10 {
+        assert_eq!(queue.pop(), Some(i));
+    }
+    assert_eq!(queue.pop(), None);
+    assert_eq!((queue.dropped(), queue.high_water()), (1, 8));
+});
@@ -0,0 +1,413 @@
+// The system call interface for user processes. A process issues the `syscall`
+// instruction with the call number in rax and up to five arguments in rdi, rsi,
+// rdx, r10 and r8 (rcx is taken by `syscall` itself, like on Linux). The result
+// comes back in rax: a non-negative value on success, or a negative `errno`
+// value. All other registers except rcx and r11 are preserved.
+//
+// `syscall` doesn't switch stacks, so the entry stub does that itself: the
+// user stack pointer is parked in a scratch variable, and we switch to the
+// current process's kernel stack, which `process::schedule` keeps up to date.
+// That only works with a single CPU; with SMP both would have to be per-CPU.
+//
+// Pointers from user space are never dereferenced here: everything goes
+// through `usercopy`, which checks them against the process's mapped memory,
+// and through bounce buffers on the kernel stack.
+
+use crate::file::{self, FileError, Whence};
+use crate::fs::FsError;
+use crate::ipc::{self, IpcError, PortId};
+use crate::memory::address_space::AddressSpaceError;
+use crate::process::{self, Pid, Process, SpawnError};
+use crate::shm::{self, ShmError, ShmId};
+use crate::tty::{self, Mode};
+use crate::usercopy::{self, Fault};
+use crate::{gdt, time};
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use x86_64::{
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    VirtAddr,
+};
+
+/// System call numbers.
+pub mod number {
+    /// `read(fd, buf, len) -> bytes read`, at most `CHUNK_SIZE` at a time
+    pub const READ: u64 = 0;
+    /// `write(fd, buf, len) -> bytes written`
+    pub const WRITE: u64 = 1;
+    /// `exit(code)`, doesn't return
+    pub const EXIT: u64 = 2;
+    /// `yield()`
+    pub const YIELD: u64 = 3;
+    /// `sleep(milliseconds)`
+    pub const SLEEP: u64 = 4;
+    /// `spawn(elf, len) -> pid`
+    pub const SPAWN: u64 = 5;
+    /// `wait(pid) -> exit code`, for a child of the calling process
+    pub const WAIT: u64 = 6;
+    /// `create_port() -> port`
+    pub const CREATE_PORT: u64 = 7;
+    /// `send(port, buf, len)`, fails with EAGAIN if the port's queue is full
+    pub const SEND: u64 = 8;
+    /// `recv(port, buf, len) -> message length`, blocks until a message arrives.
+    /// Messages longer than the buffer are truncated.
+    pub const RECV: u64 = 9;
+    /// `shm_create(size) -> handle`, for zeroed memory of at least `size` bytes
+    pub const SHM_CREATE: u64 = 10;
+    /// `shm_map(handle) -> address`
+    pub const SHM_MAP: u64 = 11;
+    /// `tty_mode(raw)`, switches the console to raw mode if `raw` is 1, and back
+    /// to canonical mode if it is 0. Only for the foreground process.
+    pub const TTY_MODE: u64 = 12;
+    /// `open(path, len, flags) -> fd`, with the flags from `file::flags`. The
+    /// path ends after `len` bytes, or at a NUL before that.
+    pub const OPEN: u64 = 13;
+    /// `close(fd)`
+    pub const CLOSE: u64 = 14;
+    /// `seek(fd, offset, whence) -> position`, with `whence` 0 (from the
+    /// start), 1 (from the current position) or 2 (from the end)
+    pub const SEEK: u64 = 15;
+}
+
+/// Error numbers, returned negated. The values match Linux.
+pub mod errno {
+    pub const ENOENT: i64 = 2;
+    pub const EIO: i64 = 5;
+    pub const EBADF: i64 = 9;
+    pub const ECHILD: i64 = 10;
+    pub const EAGAIN: i64 = 11;
+    pub const ENOMEM: i64 = 12;
+    pub const EFAULT: i64 = 14;
+    pub const EEXIST: i64 = 17;
+    pub const ENOTDIR: i64 = 20;
+    pub const EISDIR: i64 = 21;
+    pub const EINVAL: i64 = 22;
+    pub const EMFILE: i64 = 24;
+    pub const EFBIG: i64 = 27;
+    pub const ENOSPC: i64 = 28;
+    pub const ESPIPE: i64 = 29;
+    pub const EROFS: i64 = 30;
+    pub const ENAMETOOLONG: i64 = 36;
+    pub const ENOSYS: i64 = 38;
+    pub const ENOTEMPTY: i64 = 39;
+    pub const EMSGSIZE: i64 = 90;
+}
+
+type SyscallResult = Result<u64, i64>;
+
+/// The most `read` reads, and the most `write` copies to the kernel at a time.
+pub const CHUNK_SIZE: usize = 1024;
+/// The longest path `open` takes.
+pub const PATH_MAX: usize = 256;
+
+// Written by `set_kernel_stack` and read by the entry stub.
+#[no_mangle]
+static mut SYSCALL_KERNEL_RSP: u64 = 0;
+#[no_mangle]
+static mut SYSCALL_USER_RSP: u64 = 0;
+
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    // Interrupts are off (see SFMask in `init`) until we are on the kernel stack.
+    "mov [rip + SYSCALL_USER_RSP], rsp",
+    "mov rsp, [rip + SYSCALL_KERNEL_RSP]",
+    "push qword ptr [rip + SYSCALL_USER_RSP]",
+    "push rcx", // user rip
+    "push r11", // user rflags
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "push r10",
+    "push r8",
+    "push r9",
+    // Nine pushes from an aligned stack top; realign for the call.
+    "sub rsp, 8",
+    // Shuffle the registers into the C calling convention.
+    "mov r9, r8",
+    "mov r8, r10",
+    "mov rcx, rdx",
+    "mov rdx, rsi",
+    "mov rsi, rdi",
+    "mov rdi, rax",
+    "call syscall_dispatch",
+    // No interrupts once we're back on the user stack, in kernel mode.
+    "cli",
+    "add rsp, 8",
+    "pop r9",
+    "pop r8",
+    "pop r10",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    "pop r11",
+    "pop rcx",
+    "pop rsp",
+    "sysretq",
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+/// Enables the `syscall` instruction and points it at our entry stub.
+pub fn init() {
+    let selectors = gdt::selectors();
+
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.code_selector,
+        selectors.data_selector,
+    )
+    .expect("GDT layout doesn't work with SYSCALL/SYSRET");
+    LStar::write(VirtAddr::new(syscall_entry as usize as u64));
+    // Clearing AC keeps user space from turning off SMAP for the kernel.
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::ALIGNMENT_CHECK);
+
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+/// Sets the stack system calls of the current process run on.
+///
+/// # Safety
+///
+/// Must be called with interrupts disabled, with the top of the kernel stack
+/// of the process that is about to run.
+pub(crate) unsafe fn set_kernel_stack(stack_top: VirtAddr) {
+    SYSCALL_KERNEL_RSP = stack_top.as_u64();
+}
+
+#[no_mangle]
+extern "C" fn syscall_dispatch(
+    number: u64,
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    _arg4: u64,
+    _arg5: u64,
+) -> i64 {
+    // We're on the process's kernel stack now, so interrupts are fine again.
+    x86_64::instructions::interrupts::enable();
+
+    let result = match number {
+        number::READ => read(arg1, arg2, arg3),
+        number::WRITE => write(arg1, arg2, arg3),
+        number::EXIT => process::exit(arg1 as i64),
+        number::YIELD => {
+            process::yield_now();
+            Ok(0)
+        }
+        number::SLEEP => sleep(arg1),
+        number::SPAWN => spawn(arg1, arg2),
+        number::WAIT => wait(arg1),
+        number::CREATE_PORT => Ok(ipc::create_port().as_u64()),
+        number::SEND => send(arg1, arg2, arg3),
+        number::RECV => recv(arg1, arg2, arg3),
+        number::SHM_CREATE => shm_create(arg1),
+        number::SHM_MAP => shm_map(arg1),
+        number::TTY_MODE => tty_mode(arg1),
+        number::OPEN => open(arg1, arg2, arg3),
+        number::CLOSE => close(arg1),
+        number::SEEK => seek(arg1, arg2, arg3),
+        _ => Err(errno::ENOSYS),
+    };
+
+    match result {
+        Ok(value) => value as i64,
+        Err(errno) => -errno,
+    }
+}
+
+impl From<Fault> for i64 {
+    fn from(_: Fault) -> i64 {
+        errno::EFAULT
+    }
+}
+
+impl From<FsError> for i64 {
+    fn from(error: FsError) -> i64 {
+        match error {
+            FsError::NotFound => errno::ENOENT,
+            FsError::NotADirectory => errno::ENOTDIR,
+            FsError::IsADirectory => errno::EISDIR,
+            FsError::AlreadyExists => errno::EEXIST,
+            FsError::NotEmpty => errno::ENOTEMPTY,
+            FsError::InvalidPath => errno::EINVAL,
+            FsError::ReadOnly => errno::EROFS,
+            FsError::NoMemory => errno::ENOMEM,
+            FsError::NoSpace => errno::ENOSPC,
+            FsError::FileTooLarge => errno::EFBIG,
+            FsError::Corrupt | FsError::Unsupported | FsError::Block(_) => errno::EIO,
+        }
+    }
+}
+
+impl From<FileError> for i64 {
+    fn from(error: FileError) -> i64 {
+        match error {
+            FileError::BadFd => errno::EBADF,
+            FileError::TooManyFiles => errno::EMFILE,
+            FileError::InvalidArgument => errno::EINVAL,
+            FileError::NotSeekable => errno::ESPIPE,
+            FileError::Fs(error) => error.into(),
+        }
+    }
+}
+
+fn read(fd: u64, buf: u64, len: u64) -> SyscallResult {
+    usercopy::check(buf, len, true)?;
+    let mut chunk = [0; CHUNK_SIZE];
+    let chunk = &mut chunk[..(len as usize).min(CHUNK_SIZE)];
+
+    let read = file::read(fd as usize, chunk)?;
+    usercopy::copy_to_user(buf, &chunk[..read])?;
+    Ok(read as u64)
+}
+
+fn write(fd: u64, buf: u64, len: u64) -> SyscallResult {
+    usercopy::check(buf, len, false)?;
+    let mut chunk = [0; CHUNK_SIZE];
+    let mut written = 0;
+
+    while written < len {
+        let chunk = &mut chunk[..((len - written) as usize).min(CHUNK_SIZE)];
+        usercopy::copy_from_user(chunk, buf + written)?;
+        let done = match file::write(fd as usize, chunk) {
+            Ok(done) => done,
+            // What's written is written
+            Err(_) if written > 0 => break,
+            Err(error) => return Err(error.into()),
+        };
+        written += done as u64;
+        if done < chunk.len() {
+            break;
+        }
+    }
+    Ok(written)
+}
+
+fn open(path_addr: u64, len: u64, flags: u64) -> SyscallResult {
+    if len > PATH_MAX as u64 {
+        return Err(errno::ENAMETOOLONG);
+    }
+    let mut buf = [0; PATH_MAX];
+    let len = usercopy::strncpy_from_user(&mut buf[..len as usize], path_addr)?;
+    let path = core::str::from_utf8(&buf[..len]).map_err(|_| errno::EINVAL)?;
+
+    Ok(file::open(path, flags)? as u64)
+}
+
+fn close(fd: u64) -> SyscallResult {
+    file::close(fd as usize)?;
+    Ok(0)
+}
+
+fn seek(fd: u64, offset: u64, whence: u64) -> SyscallResult {
+    let whence = Whence::from_u64(whence).ok_or(errno::EINVAL)?;
+    Ok(file::seek(fd as usize, offset as i64, whence)?)
+}
+
+fn sleep(milliseconds: u64) -> SyscallResult {
+    let ticks = milliseconds
+        .checked_mul(time::TIMER_HZ)
+        .ok_or(errno::EINVAL)?
+        / 1000;
+    let deadline = time::ticks() + ticks;
+
+    while time::ticks() < deadline {
+        process::yield_now();
+    }
+
+    Ok(0)
+}
+
+fn spawn(elf_addr: u64, len: u64) -> SyscallResult {
+    usercopy::check(elf_addr, len, false)?;
+    let mut elf = Vec::new();
+    elf.try_reserve_exact(len as usize)
+        .map_err(|_| errno::ENOMEM)?;
+    elf.resize(len as usize, 0);
+    usercopy::copy_from_user(&mut elf, elf_addr)?;
+
+    match Process::spawn(&elf) {
+        Ok(pid) => Ok(pid.as_u64()),
+        Err(SpawnError::Memory(AddressSpaceError::OutOfMemory))
+        | Err(SpawnError::NoKernelStack) => Err(errno::ENOMEM),
+        Err(_) => Err(errno::EINVAL),
+    }
+}
+
+fn wait(pid: u64) -> SyscallResult {
+    // Exit codes are 64 bits wide, so a negative one is indistinguishable from
+    // an error. Processes that want to tell should stick to small positive codes.
+    match process::wait(Pid::from_u64(pid)) {
+        Ok(code) => Ok(code as u64),
+        Err(_) => Err(errno::ECHILD),
+    }
+}
+
+impl From<IpcError> for i64 {
+    fn from(error: IpcError) -> i64 {
+        match error {
+            IpcError::NoSuchPort | IpcError::NotOwner => errno::EBADF,
+            IpcError::TooLong => errno::EMSGSIZE,
+            IpcError::QueueFull => errno::EAGAIN,
+        }
+    }
+}
+
+impl From<ShmError> for i64 {
+    fn from(error: ShmError) -> i64 {
+        match error {
+            ShmError::InvalidSize | ShmError::NoSuchRegion | ShmError::NotOwner => errno::EINVAL,
+            ShmError::NoAddressSpace | ShmError::Memory(_) => errno::ENOMEM,
+        }
+    }
+}
+
+fn send(port: u64, buf: u64, len: u64) -> SyscallResult {
+    if len > ipc::MESSAGE_SIZE as u64 {
+        return Err(errno::EMSGSIZE);
+    }
+    let mut message = [0; ipc::MESSAGE_SIZE];
+    let message = &mut message[..len as usize];
+    usercopy::copy_from_user(message, buf)?;
+    ipc::send(PortId::from_u64(port), message)?;
+
+    Ok(0)
+}
+
+fn recv(port: u64, buf: u64, len: u64) -> SyscallResult {
+    // Before a message is taken off the queue
+    usercopy::check(buf, len, true)?;
+    let message = ipc::recv(PortId::from_u64(port))?;
+
+    let data = message.data();
+    let copied = data.len().min(len as usize);
+    usercopy::copy_to_user(buf, &data[..copied])?;
+
+    Ok(data.len() as u64)
+}
+
+fn shm_create(size: u64) -> SyscallResult {
+    Ok(shm::create(size)?.as_u64())
+}
+
+fn shm_map(handle: u64) -> SyscallResult {
+    Ok(shm::map(ShmId::from_u64(handle))?.as_u64())
+}
+
+fn tty_mode(raw: u64) -> SyscallResult {
+    let mode = match raw {
+        0 => Mode::Canonical,
+        1 => Mode::Raw,
+        _ => return Err(errno::EINVAL),
+    };
+
+    tty::set_mode(mode).map_err(|_| errno::EIO)?;
+    Ok(0)
+}
@@ -1,47 +1,262 @@
 use super::{Task, TaskId};
+use crate::allocator::accounting::{self, Subsystem};
+use crate::sync::{mpsc, SpinLock};
+use crate::{config, idle, kassert, println, shell, time, trace_event, warn};
 use alloc::task::Wake;
-use alloc::{collections::BTreeMap, sync::Arc};
-use core::task::{Context, Poll, Waker};
-use crossbeam_queue::ArrayQueue;
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::fmt::{self, Write};
+use core::future::Future;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Waker};
+use core::time::Duration;
 
-static TASK_QUEUE_CAPACITY: usize = 100;
+type TaskQueue = mpsc::Queue<TaskId, { config::TASK_QUEUE_SIZE }>;
+
+// A few numbers about the executor that can be read from interrupt context (by
+// the watchdog), which can't get at the Executor itself.
+const NO_TASK: u64 = u64::MAX;
+static CURRENT_TASK: AtomicU64 = AtomicU64::new(NO_TASK);
+static CURRENT_TASK_SINCE: AtomicU64 = AtomicU64::new(0);
+static TASK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the ID of the task that is currently being polled and the tick at
+/// which its poll started, or `None` if the executor is between polls.
+pub fn current_task() -> Option<(u64, u64)> {
+    match CURRENT_TASK.load(Ordering::Relaxed) {
+        NO_TASK => None,
+        id => Some((id, CURRENT_TASK_SINCE.load(Ordering::Relaxed))),
+    }
+}
+
+/// How long a task may run in one poll. Tasks can't be interrupted, so one
+/// that takes longer only gets reported, as the one holding up the others.
+pub const POLL_BUDGET: Duration = Duration::from_millis(20);
+
+static OVERRUNS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns how many polls have gone over `POLL_BUDGET` since boot.
+pub fn overruns() -> u64 {
+    OVERRUNS.load(Ordering::Relaxed)
+}
+
+/// Returns the number of tasks that have been spawned and are not done yet.
+pub fn task_count() -> usize {
+    TASK_COUNT.load(Ordering::Relaxed)
+}
+
+// Shell commands run in a task, so they can't get at the executor to list,
+// kill or spawn tasks. The executor keeps a list of its tasks in `TASKS` for
+// them, and takes requests to kill or spawn from queues that it empties
+// between polls. The kernel runs a single executor, which is the one that
+// picks the requests up.
+
+/// A task, as `tasks` lists it.
+#[derive(Debug, Clone, Copy)]
+pub struct TaskInfo {
+    pub id: u64,
+    pub name: &'static str,
+    /// The tick it was spawned at.
+    pub spawned: u64,
+}
+
+static TASKS: SpinLock<BTreeMap<TaskId, TaskInfo>> = SpinLock::new(BTreeMap::new());
+
+/// A task whose future is `Send`, so that it can wait in `SPAWN_REQUESTS`.
+struct SendTask(Task);
+
+// `spawn` only makes these from futures that are `Send`.
+unsafe impl Send for SendTask {}
+
+static SPAWN_REQUESTS: SpinLock<Vec<SendTask>> = SpinLock::new(Vec::new());
+static KILL_REQUESTS: SpinLock<Vec<TaskId>> = SpinLock::new(Vec::new());
+static REQUESTS_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Returns the tasks of the executor, in the order they were spawned.
+pub fn tasks() -> Vec<TaskInfo> {
+    TASKS.lock().values().copied().collect()
+}
+
+/// Calls `f` with each task, like `tasks` but without allocating, for
+/// interrupt context.
+pub fn for_each_task(f: impl FnMut(&TaskInfo)) {
+    TASKS.lock().values().for_each(f);
+}
+
+/// Has the executor run `future` as a new task, from anywhere, and returns
+/// the task's ID.
+pub fn spawn(future: impl Future<Output = ()> + Send + 'static) -> u64 {
+    let task = Task::new(future);
+    let id = task.id.0;
+    SPAWN_REQUESTS.lock().push(SendTask(task));
+    REQUESTS_PENDING.store(true, Ordering::Release);
+    id
+}
+
+/// Has the executor drop the task with the given ID, which cancels it at the
+/// point it last waited. Returns `false` if there's no such task.
+pub fn kill(id: u64) -> bool {
+    let id = TaskId(id);
+    if !TASKS.lock().contains_key(&id) {
+        return false;
+    }
+    KILL_REQUESTS.lock().push(id);
+    REQUESTS_PENDING.store(true, Ordering::Release);
+    true
+}
 
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    task_queue: Arc<TaskQueue>,
+    waker_cache: BTreeMap<TaskId, Arc<TaskWaker>>,
+}
+
+/// A task taken out of an executor with `Executor::detach`, on its way to
+/// another one. It keeps its waker, so that whoever is holding on to the
+/// waker, like a timer or an interrupt handler, wakes the task in the
+/// executor it's attached to next.
+///
+/// Its future isn't necessarily `Send`, so this can't cross to another CPU's
+/// executor by itself; the executors have to agree on what they hand over.
+///
+/// Dropping it instead of attaching it drops the task, like `kill` would.
+pub struct DetachedTask {
+    id: TaskId,
+    name: &'static str,
+    // Until `attach` takes it
+    task: Option<Task>,
+    waker: Option<Arc<TaskWaker>>,
+}
+
+impl DetachedTask {
+    pub fn id(&self) -> u64 {
+        self.id.0
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl Drop for DetachedTask {
+    fn drop(&mut self) {
+        if self.task.take().is_some() {
+            trace_event!("executor", "drop detached task {}", self.id.0);
+            unlist(self.id);
+        }
+    }
+}
+
+/// Takes a task that's gone for good off the list.
+fn unlist(task_id: TaskId) {
+    let listed = TASKS.lock().remove(&task_id).is_some();
+    kassert!("executor", listed, "task {} wasn't listed", task_id.0);
+    TASK_COUNT.fetch_sub(1, Ordering::Relaxed);
 }
 
 impl Executor {
     pub fn new() -> Self {
         Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(TASK_QUEUE_CAPACITY)),
+            task_queue: Arc::new(TaskQueue::new()),
             waker_cache: BTreeMap::new(),
         }
     }
 
     pub fn spawn(&mut self, task: Task) {
+        let _charge = accounting::charge_to(Subsystem::Executor);
+        let task_id = task.id;
+        let info = TaskInfo {
+            id: task_id.0,
+            name: task.name,
+            spawned: time::ticks(),
+        };
+        if self.tasks.insert(task_id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+        self.task_queue.push(task_id).expect("queue full");
+        TASKS.lock().insert(task_id, info);
+        TASK_COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Takes the task with the given ID out of the executor without dropping
+    /// it, to `attach` it to another executor, e.g. before parking this one's
+    /// CPU. Wakes that come in until then are lost, which `attach` makes up
+    /// for by polling the task once.
+    pub fn detach(&mut self, id: u64) -> Option<DetachedTask> {
+        let task_id = TaskId(id);
+        let task = self.tasks.remove(&task_id)?;
+        trace_event!("executor", "detach task {}", id);
+        Some(DetachedTask {
+            id: task_id,
+            name: task.name,
+            task: Some(task),
+            waker: self.waker_cache.remove(&task_id),
+        })
+    }
+
+    /// Takes all of its tasks out of the executor, like `detach`.
+    pub fn detach_all(&mut self) -> Vec<DetachedTask> {
+        let ids: Vec<u64> = self.tasks.keys().map(|task_id| task_id.0).collect();
+        ids.into_iter().filter_map(|id| self.detach(id)).collect()
+    }
+
+    /// Takes over a task that another executor detached. Its waker now wakes
+    /// it here, and it gets polled once in case it was woken in between.
+    pub fn attach(&mut self, mut detached: DetachedTask) {
+        let _charge = accounting::charge_to(Subsystem::Executor);
+        let task = detached.task.take().expect("detached task without a task");
         let task_id = task.id;
         if self.tasks.insert(task_id, task).is_some() {
             panic!("task with same ID already in tasks");
         }
+        if let Some(waker) = detached.waker.take() {
+            *waker.task_queue.lock() = self.task_queue.clone();
+            self.waker_cache.insert(task_id, waker);
+        }
+        trace_event!("executor", "attach task {}", task_id.0);
         self.task_queue.push(task_id).expect("queue full");
     }
 
+    /// Drops a task that's done or killed.
+    fn remove(&mut self, task_id: TaskId) {
+        if self.tasks.remove(&task_id).is_some() {
+            self.waker_cache.remove(&task_id);
+            unlist(task_id);
+        }
+    }
+
+    /// Spawns and kills the tasks that `spawn` and `kill` asked for.
+    fn handle_requests(&mut self) {
+        if !REQUESTS_PENDING.swap(false, Ordering::Acquire) {
+            return;
+        }
+        let spawned = core::mem::take(&mut *SPAWN_REQUESTS.lock());
+        for SendTask(task) in spawned {
+            self.spawn(task);
+        }
+        let killed = core::mem::take(&mut *KILL_REQUESTS.lock());
+        for task_id in killed {
+            trace_event!("executor", "kill task {}", task_id.0);
+            self.remove(task_id);
+        }
+    }
+
     // The basic idea of this function is similar to the one in our SimpleExecutor: Loop over
     // all tasks in the task_queue, create a waker for each task, and then poll them. However,
     // instead of adding pending tasks back to the end of the task_queue, we let our TaskWaker
     // implementation take care of adding woken tasks back to the queue.
     fn run_ready_tasks(&mut self) {
-        // Destructure Self to avoid borrow checker errors
-        let Self {
-            tasks,
-            task_queue,
-            waker_cache,
-        } = self;
-
-        while let Ok(task_id) = task_queue.pop() {
+        let _charge = accounting::charge_to(Subsystem::Executor);
+        self.handle_requests();
+
+        while let Some(task_id) = self.task_queue.pop() {
+            // Destructure Self to avoid borrow checker errors
+            let Self {
+                tasks,
+                task_queue,
+                waker_cache,
+            } = self;
+
             let task = match tasks.get_mut(&task_id) {
                 Some(task) => task,
                 None => continue, // Task no longer exists
@@ -49,53 +264,101 @@ impl Executor {
             let waker = waker_cache
                 .entry(task_id)
                 .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    // Task done -> remove it and its cached waker
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
-                }
-                Poll::Pending => {}
+            let waker = Waker::from(waker.clone());
+            let mut context = Context::from_waker(&waker);
+
+            trace_event!("executor", "poll task {}", task_id.0);
+            let started = time::now();
+            CURRENT_TASK_SINCE.store(crate::time::ticks(), Ordering::Relaxed);
+            CURRENT_TASK.store(task_id.0, Ordering::Relaxed);
+            let charge = accounting::charge_to(task.subsystem);
+            let poll = task.poll(&mut context);
+            drop(charge);
+            CURRENT_TASK.store(NO_TASK, Ordering::Relaxed);
+
+            let elapsed = time::now().saturating_sub(started);
+            if elapsed > POLL_BUDGET {
+                OVERRUNS.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    target: "executor",
+                    "task {} ({}) ran for {} ms without yielding",
+                    task_id.0,
+                    task.name,
+                    elapsed.as_millis()
+                );
             }
+
+            if poll.is_ready() {
+                // Task done -> remove it and its cached waker
+                trace_event!("executor", "task {} done", task_id.0);
+                self.remove(task_id);
+            }
+            self.handle_requests();
         }
     }
 
+    /// Polls tasks until none is ready, and returns instead of waiting for
+    /// more to wake up. For tests and benchmarks.
+    pub fn run_until_idle(&mut self) {
+        self.run_ready_tasks();
+    }
+
     pub fn run(&mut self) -> ! {
         loop {
+            crate::watchdog::pet();
             self.run_ready_tasks();
+            // Give user processes a turn; we get the CPU back on their next timer tick.
+            crate::process::yield_now();
             self.sleep_if_idle();
         }
     }
 
     fn sleep_if_idle(&self) {
-        use x86_64::instructions::interrupts::{self, enable_and_hlt};
+        use x86_64::instructions::interrupts;
 
         interrupts::disable();
         if self.task_queue.is_empty() {
-            enable_and_hlt();
+            // Wakers push to the queue, which moves its tail on and wakes the
+            // CPU by itself with MWAIT
+            idle::wait(self.task_queue.tail_address());
         } else {
             interrupts::enable();
         }
     }
 }
 
+impl Drop for Executor {
+    fn drop(&mut self) {
+        let mut tasks = TASKS.lock();
+        for task_id in self.tasks.keys() {
+            tasks.remove(task_id);
+        }
+        TASK_COUNT.fetch_sub(self.tasks.len(), Ordering::Relaxed);
+    }
+}
+
 struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    /// The queue of the executor the task is in, which `attach` changes.
+    task_queue: SpinLock<Arc<TaskQueue>>,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
+    fn new(task_id: TaskId, task_queue: Arc<TaskQueue>) -> Arc<TaskWaker> {
+        Arc::new(TaskWaker {
             task_id,
-            task_queue,
-        }))
+            task_queue: SpinLock::new(task_queue),
+        })
     }
 
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        trace_event!("executor", "wake task {}", self.task_id.0);
+        // Pushed under the lock, so that it can't go to the old queue once
+        // `attach` has returned
+        self.task_queue
+            .lock()
+            .push(self.task_id)
+            .expect("task_queue full");
     }
 }
 
@@ -108,3 +371,66 @@ impl Wake for TaskWaker {
         self.wake_task();
     }
 }
+
+/// Starts a built-in task and returns its ID.
+pub type StartTask = fn() -> u64;
+
+/// Tasks that `spawn` can start, by name.
+pub static BUILTINS: &[(&str, StartTask)] = &[("ticker", || spawn(ticker()))];
+
+/// Prints the uptime every few seconds, until it's killed.
+async fn ticker() {
+    loop {
+        time::sleep(5 * time::TIMER_HZ).await;
+        println!("ticker: up {}", time::Uptime(time::now()));
+    }
+}
+
+pub fn register_commands() {
+    shell::register("ps", "list the tasks", |_args, out| {
+        writeln!(out, "  ID       AGE  NAME")?;
+        let now = time::ticks();
+        for task in tasks() {
+            let age = (now - task.spawned) / time::TIMER_HZ;
+            writeln!(out, "{:>4} {:>8}s  {}", task.id, age, task.name)?;
+        }
+        Ok(())
+    });
+    shell::register("kill", "stop a task, `kill <id>`", kill_command);
+    shell::register(
+        "burn",
+        "busy-loop without yielding, `burn <ms>`, to try out the poll budget and the watchdog",
+        burn,
+    );
+}
+
+/// The longest `burn` there is, so that a typo doesn't hang the kernel for
+/// good.
+const MAX_BURN: Duration = Duration::from_secs(60);
+
+fn burn(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    let duration = match args.get(1).map(|ms| ms.parse()) {
+        Some(Ok(ms)) => Duration::from_millis(ms),
+        _ => return writeln!(out, "usage: burn <ms>"),
+    };
+    if duration > MAX_BURN {
+        return writeln!(out, "burn: at most {} ms", MAX_BURN.as_millis());
+    }
+
+    let deadline = time::now() + duration;
+    while time::now() < deadline {
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+fn kill_command(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    match args {
+        [_, id] => match id.parse() {
+            Ok(id) if kill(id) => Ok(()),
+            Ok(id) => writeln!(out, "kill: no task {}", id),
+            Err(_) => writeln!(out, "usage: kill <id>"),
+        },
+        _ => writeln!(out, "usage: kill <id>"),
+    }
+}
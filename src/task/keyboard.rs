@@ -1,54 +1,124 @@
+use crate::config;
+use crate::debugflags;
+use crate::logger::Level;
 use crate::print;
-use crate::println;
-use conquer_once::spin::OnceCell;
+use crate::sync::spsc;
+#[cfg(test)]
+use crate::HostTest;
+use crate::{info, log_rate_limited, shell, time};
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::{
     pin::Pin,
     task::{Context, Poll},
 };
-use crossbeam_queue::ArrayQueue;
+use futures_util::future;
 use futures_util::stream::{Stream, StreamExt};
 use futures_util::task::AtomicWaker;
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1};
+use x86_64::instructions::interrupts;
 
-// Since ArrayQueue::new performs a heap allocation, which is not possible at compile
-// time (yet), we can’t initialize the static variable directly. Instead, we use the
-// OnceCell type of the conquer_once crate, which makes it possible to perform a safe
-// one-time initialization of static values. Instead of the OnceCell primitive, we could
-// also use the lazy_static macro here. However, the OnceCell type has the advantage
-// that we can ensure that the initialization does not happen in the interrupt handler,
-// thus preventing the interrupt handler from performing a heap allocation.
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+// The queue starts out with its storage inline, so it can be initialized at
+// compile time and the interrupt handler never has to allocate. The interrupt handler is its
+// only producer, and the one ScancodeStream its only consumer.
+//
+// When the queue is full, the interrupt handler drops the scancode, and that's
+// all: the queue counts it, and the ScancodeStream, in task context, warns
+// about it, at most every `WARN_INTERVAL_MS`. If the queue keeps
+// overflowing, the stream doubles it, up to `config::SCANCODE_QUEUE_MAX_SIZE`.
+static SCANCODE_QUEUE: spsc::GrowableQueue<u8, { config::SCANCODE_QUEUE_SIZE }> =
+    spsc::GrowableQueue::new();
+
+/// An overflow this soon after the last one means the queue is too small.
+const SUSTAINED_MS: u64 = 1000;
+/// How often the queue's troubles are warned about, at most.
+const WARN_INTERVAL_MS: u64 = 10_000;
+
+static STREAM_TAKEN: AtomicBool = AtomicBool::new(false);
 
 static WAKER: AtomicWaker = AtomicWaker::new();
 
-/// Called by the keyboard interrupt handler
+/// Called by the keyboard interrupt handler, and by tests to type without a
+/// keyboard.
 ///
 /// Must not block or allocate!
-pub(crate) fn add_scancode(scancode: u8) {
-    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if let Err(_) = queue.push(scancode) {
-            println!("WARNING: scancode queue full; dropping keyboard input");
-        } else {
-            WAKER.wake();
+pub fn add_scancode(scancode: u8) {
+    if !STREAM_TAKEN.load(Ordering::Relaxed) {
+        log_rate_limited!(
+            WARN_INTERVAL_MS,
+            Level::Warn,
+            "scancode queue uninitialized"
+        );
+    } else if SCANCODE_QUEUE.push(scancode).is_ok() {
+        WAKER.wake();
+    }
+}
+
+/// What the ScancodeStream knows about the queue's overflows.
+#[derive(Default)]
+struct Overflows {
+    /// `dropped()` when it last looked.
+    seen: u64,
+    /// The tick it last found new drops at.
+    last: Option<u64>,
+}
+
+impl Overflows {
+    /// Takes the queue's drop count at `tick`, and returns whether there were
+    /// new drops, and whether the queue should grow.
+    fn check(&mut self, dropped: u64, tick: u64) -> (bool, bool) {
+        let new = dropped > self.seen;
+        self.seen = dropped;
+
+        let mut grow = false;
+        if new {
+            grow = self
+                .last
+                .is_some_and(|last| tick - last <= time::ms_to_ticks(SUSTAINED_MS));
+            self.last = Some(tick);
         }
-    } else {
-        println!("WARNING: scancode queue uninitialized");
+        (new, grow)
     }
 }
 
 pub struct ScancodeStream {
-    // The purpose of the _private field is to prevent construction of the struct from outside
-    // of the module. This makes the new function the only way to construct the type
-    _private: (),
+    // Private, which also keeps the struct from being made outside of the
+    // module, so `new` is the only way.
+    overflows: Overflows,
 }
 
 impl ScancodeStream {
     pub fn new() -> Self {
-        SCANCODE_QUEUE
-            .try_init_once(|| ArrayQueue::new(100))
-            .expect("ScancodeStream::new should only be called once");
+        assert!(
+            !STREAM_TAKEN.swap(true, Ordering::Relaxed),
+            "ScancodeStream::new should only be called once"
+        );
 
-        ScancodeStream { _private: () }
+        ScancodeStream {
+            overflows: Overflows::default(),
+        }
+    }
+
+    fn check_overflows(&mut self) {
+        let dropped = SCANCODE_QUEUE.dropped();
+        let (new, grow) = self.overflows.check(dropped, time::ticks());
+        let capacity = SCANCODE_QUEUE.capacity();
+        if new {
+            log_rate_limited!(
+                WARN_INTERVAL_MS,
+                Level::Warn,
+                "keyboard: dropped {} scancodes so far, the queue holds {}",
+                dropped,
+                capacity
+            );
+        }
+
+        let bigger = (capacity * 2).min(config::SCANCODE_QUEUE_MAX_SIZE);
+        if grow
+            && bigger > capacity
+            && interrupts::without_interrupts(|| SCANCODE_QUEUE.grow(bigger))
+        {
+            info!("keyboard: the scancode queue holds {} now", bigger);
+        }
     }
 }
 
@@ -56,23 +126,21 @@ impl Stream for ScancodeStream {
     type Item = u8;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
-        let queue = SCANCODE_QUEUE
-            .try_get()
-            .expect("scancode queue not initialized");
+        self.get_mut().check_overflows();
 
         // Fast path
-        if let Ok(scancode) = queue.pop() {
+        if let Some(scancode) = SCANCODE_QUEUE.pop() {
             return Poll::Ready(Some(scancode));
         }
 
         WAKER.register(cx.waker());
 
-        match queue.pop() {
-            Ok(scancode) => {
+        match SCANCODE_QUEUE.pop() {
+            Some(scancode) => {
                 WAKER.take();
                 Poll::Ready(Some(scancode))
             }
-            Err(crossbeam_queue::PopError) => Poll::Pending,
+            None => Poll::Pending,
         }
     }
 }
@@ -82,13 +150,101 @@ pub async fn print_keypresses() {
     let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
 
     while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => print!("{}", character),
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
-                }
+        if let Some(key) = process_scancode(&mut keyboard, scancode) {
+            match key {
+                DecodedKey::Unicode(character) => print!("{}", character),
+                DecodedKey::RawKey(key) => print!("{:?}", key),
             }
         }
     }
 }
+
+/// Returns the stream of characters typed on the keyboard. Keys that don't
+/// produce a character are dropped.
+///
+/// Like `ScancodeStream::new`, this may only be called once.
+pub fn characters() -> impl Stream<Item = char> {
+    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
+
+    ScancodeStream::new().filter_map(move |scancode| {
+        let character = match process_scancode(&mut keyboard, scancode) {
+            Some(DecodedKey::Unicode(character)) => Some(character),
+            _ => None,
+        };
+        future::ready(character)
+    })
+}
+
+/// Feeds a scancode to the decoder and returns the key it completes, if any.
+///
+/// Function keys are hotkeys for the debug flags and are handled here.
+fn process_scancode(
+    keyboard: &mut Keyboard<layouts::Us104Key, ScancodeSet1>,
+    scancode: u8,
+) -> Option<DecodedKey> {
+    if debugflags::ECHO_SCANCODES.get() {
+        print!("[{:#04x}]", scancode);
+    }
+
+    let key_event = keyboard.add_byte(scancode).ok()??;
+    let key = keyboard.process_keyevent(key_event)?;
+
+    if let DecodedKey::RawKey(code) = key {
+        if let Some(n) = function_key(code) {
+            debugflags::hotkey(n);
+            return None;
+        }
+    }
+
+    Some(key)
+}
+
+/// Returns `n` for the function key F`n`.
+fn function_key(key: KeyCode) -> Option<usize> {
+    let n = match key {
+        KeyCode::F1 => 1,
+        KeyCode::F2 => 2,
+        KeyCode::F3 => 3,
+        KeyCode::F4 => 4,
+        KeyCode::F5 => 5,
+        KeyCode::F6 => 6,
+        KeyCode::F7 => 7,
+        KeyCode::F8 => 8,
+        KeyCode::F9 => 9,
+        KeyCode::F10 => 10,
+        KeyCode::F11 => 11,
+        KeyCode::F12 => 12,
+        _ => return None,
+    };
+
+    Some(n)
+}
+
+pub fn register_commands() {
+    shell::register(
+        "kbdstat",
+        "show how full the scancode queue gets",
+        |_args, out| {
+            writeln!(
+                out,
+                "scancode queue: {} of {}, at most {}, {} dropped",
+                SCANCODE_QUEUE.len(),
+                SCANCODE_QUEUE.capacity(),
+                SCANCODE_QUEUE.high_water(),
+                SCANCODE_QUEUE.dropped()
+            )
+        },
+    );
+}
+
+#[test_case]
+static HANDLES_OVERFLOWS: HostTest = HostTest::new("task::keyboard::handles_overflows", || {
+    let second = time::ms_to_ticks(1000);
+    let mut overflows = Overflows::default();
+    assert_eq!(overflows.check(0, 0), (false, false));
+    assert_eq!(overflows.check(3, 10), (true, false));
+    // Within a second of the last one
+    assert_eq!(overflows.check(5, 10 + second), (true, true));
+    assert_eq!(overflows.check(5, 10 + 3 * second), (false, false));
+    assert_eq!(overflows.check(6, 10 + 10 * second), (true, false));
+});
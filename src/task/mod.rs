@@ -1,7 +1,10 @@
+use crate::allocator::accounting::{self, Subsystem};
+#[cfg(test)]
+use crate::HostTest;
 use alloc::boxed::Box;
 use core::sync::atomic::{AtomicU64, Ordering};
 use core::task::{Context, Poll};
-use core::{future::Future, pin::Pin};
+use core::{future, future::Future, pin::Pin};
 
 pub mod executor;
 pub mod keyboard;
@@ -20,18 +23,94 @@ impl TaskId {
 
 pub struct Task {
     id: TaskId,
+    name: &'static str,
+    /// What its polls are charged to, see allocator/accounting.rs.
+    subsystem: Subsystem,
     future: Pin<Box<dyn Future<Output = ()>>>,
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+    /// A task named after the function its future comes from, like
+    /// `shell::run`.
+    pub fn new<F: Future<Output = ()> + 'static>(future: F) -> Task {
+        Task::named(short_name(core::any::type_name::<F>()), future)
+    }
+
+    /// A task with the given name, for `ps`.
+    pub fn named(name: &'static str, future: impl Future<Output = ()> + 'static) -> Task {
+        // The future is the executor's to keep, what it allocates is not
+        let _charge = accounting::charge_to(Subsystem::Executor);
         Task {
             id: TaskId::new(),
+            name,
+            subsystem: Subsystem::Other,
             future: Box::pin(future),
         }
     }
 
+    /// Charges what the task allocates to `subsystem`, instead of `Other`.
+    pub fn charged_to(mut self, subsystem: Subsystem) -> Task {
+        self.subsystem = subsystem;
+        self
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
 }
+
+/// Lets the other tasks run before carrying on, for tasks that have a lot of
+/// work to do.
+pub async fn yield_now() {
+    let mut yielded = false;
+    future::poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+    .await
+}
+
+/// Shortens the type name of a future to the function it comes from and its
+/// module, e.g. `{async fn body of rust_os_playground::shell::run()}` (or
+/// `rust_os_playground::shell::run::{{closure}}`, depending on the compiler)
+/// to `shell::run`.
+fn short_name(type_name: &'static str) -> &'static str {
+    let mut name = type_name
+        .strip_prefix("{async fn body of ")
+        .and_then(|name| name.strip_suffix("()}"))
+        .unwrap_or(type_name);
+    name = &name[..name.find('<').unwrap_or(name.len())];
+    while let Some(outer) = name.strip_suffix("::{{closure}}") {
+        name = outer;
+    }
+
+    match name.rmatch_indices("::").nth(1) {
+        Some((i, _)) => &name[i + 2..],
+        None => name,
+    }
+}
+
+#[test_case]
+static SHORTENS_NAMES: HostTest = HostTest::new("task::shortens_names", || {
+    assert_eq!(
+        short_name("{async fn body of rust_os_playground::shell::run()}"),
+        "shell::run"
+    );
+    assert_eq!(
+        short_name("rust_os_playground::shell::run::{{closure}}"),
+        "shell::run"
+    );
+    assert_eq!(
+        short_name("main::start::{{closure}}::{{closure}}"),
+        "main::start"
+    );
+    assert_eq!(
+        short_name("futures_util::future::Map<Fut, F>"),
+        "future::Map"
+    );
+    assert_eq!(short_name("ticker"), "ticker");
+});
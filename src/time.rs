@@ -0,0 +1,610 @@
+use crate::error::KernelError;
+use crate::io::PortRange;
+use crate::sync::SeqLock;
+#[cfg(test)]
+use crate::HostTest;
+use crate::{apic, cmdline, hpet, info, rtc, shell, warn};
+use alloc::{boxed::Box, vec::Vec};
+use core::fmt::{self, Write};
+use core::future::Future;
+use core::pin::Pin;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use futures_util::future::{self, Either};
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+
+pub mod sntp;
+
+// The programmable interval timer (PIT) is the oldest timer on the PC and it's
+// the one that is wired to IRQ0 of the primary PIC. Its oscillator runs at
+// roughly 1.193182 MHz, and channel 0 divides that by a 16-bit reload value to
+// produce the timer interrupt. The BIOS leaves the reload value at 0 (= 65536),
+// which gives the odd default rate of ~18.2 Hz, so we program our own.
+
+/// The frequency of the timer interrupt after `init`.
+pub const TIMER_HZ: u64 = 100;
+
+const PIT_FREQUENCY_HZ: u64 = 1_193_182;
+static PIT: PortRange = PortRange::new("pit", 0x40, 4);
+const PIT_CHANNEL_0: u16 = 0;
+const PIT_CHANNEL_2: u16 = 2;
+const PIT_COMMAND: u16 = 3;
+
+// Bit 0 of this port gates PIT channel 2, bit 1 connects it to the PC speaker
+// and bit 5 reflects the channel's output.
+static SPEAKER_CONTROL: PortRange = PortRange::new("pc speaker", 0x61, 1);
+
+/// How long we measure the TSC against the PIT for.
+const CALIBRATION_MS: u64 = 10;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// Calibrates the TSC and programs PIT channel 0 to fire the timer interrupt
+/// `TIMER_HZ` times a second.
+pub fn init() {
+    TSC_HZ.store(calibrate_tsc(), Ordering::Relaxed);
+
+    let divisor = (PIT_FREQUENCY_HZ / TIMER_HZ) as u16;
+
+    unsafe {
+        // Channel 0, access mode lobyte/hibyte, mode 3 (square wave generator)
+        PIT.write(PIT_COMMAND, 0x36u8);
+        PIT.write(PIT_CHANNEL_0, divisor as u8);
+        PIT.write(PIT_CHANNEL_0, (divisor >> 8) as u8);
+    }
+}
+
+/// Measures the TSC frequency by counting cycles during a one-shot countdown of
+/// PIT channel 2, which (unlike channel 0) can be polled without interrupts.
+fn calibrate_tsc() -> u64 {
+    let count = PIT_FREQUENCY_HZ * CALIBRATION_MS / 1000;
+
+    unsafe {
+        // Enable the gate, but keep the speaker quiet
+        let control: u8 = SPEAKER_CONTROL.read(0);
+        SPEAKER_CONTROL.write(0, (control & !0x02) | 0x01);
+
+        // Channel 2, access mode lobyte/hibyte, mode 0 (interrupt on terminal count).
+        // The countdown starts as soon as the count is written.
+        PIT.write(PIT_COMMAND, 0xB0u8);
+        PIT.write(PIT_CHANNEL_2, count as u8);
+        PIT.write(PIT_CHANNEL_2, (count >> 8) as u8);
+
+        let start = tsc();
+        while SPEAKER_CONTROL.read::<u8>(0) & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        let end = tsc();
+
+        SPEAKER_CONTROL.write(0, control);
+
+        (end - start) * 1000 / CALIBRATION_MS
+    }
+}
+
+// The PIT is all there is at boot, but once ACPI is up, `select_tick_source`
+// may move the timer interrupt to something better: the local APIC timer in
+// TSC-deadline mode, which is programmed with a single MSR write rather than
+// port I/O, or the HPET (see hpet.rs). It picks the best one that's there,
+// unless `clocksource=` on the command line asks for one. Whichever it is,
+// the interrupt still comes `TIMER_HZ` times a second, so nothing that counts
+// ticks notices the change.
+
+/// What raises the timer interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickSource {
+    Pit,
+    Hpet,
+    TscDeadline,
+}
+
+impl TickSource {
+    /// Best first.
+    const ALL: [TickSource; 3] = [TickSource::TscDeadline, TickSource::Hpet, TickSource::Pit];
+
+    fn name(self) -> &'static str {
+        match self {
+            TickSource::Pit => "pit",
+            TickSource::Hpet => "hpet",
+            TickSource::TscDeadline => "tsc-deadline",
+        }
+    }
+
+    fn available(self) -> bool {
+        match self {
+            TickSource::Pit => true,
+            TickSource::Hpet => hpet::get().is_some_and(|hpet| hpet.can_tick()),
+            TickSource::TscDeadline => apic::tsc_deadline_supported() && tsc_hz().is_some(),
+        }
+    }
+}
+
+impl fmt::Display for TickSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for TickSource {
+    type Err = KernelError;
+
+    fn from_str(s: &str) -> Result<TickSource, KernelError> {
+        TickSource::ALL
+            .iter()
+            .copied()
+            .find(|source| source.name() == s)
+            .ok_or(KernelError::InvalidArgument)
+    }
+}
+
+static TICK_SOURCE: Once<TickSource> = Once::new();
+/// The TSC value that the local APIC timer fires at next, in TSC-deadline mode.
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(0);
+
+/// Moves the timer interrupt to the best source there is, or the one the
+/// command line asks for. Called once, after `hpet::init`, and once the TSC
+/// frequency is known as well as it will be.
+pub fn select_tick_source() {
+    let best = || {
+        TickSource::ALL
+            .iter()
+            .copied()
+            .find(|source| source.available())
+            .unwrap_or(TickSource::Pit)
+    };
+    let source = match cmdline::value("clocksource") {
+        None => best(),
+        Some(name) => match name.parse::<TickSource>() {
+            Ok(source) if source.available() => source,
+            _ => {
+                warn!("clocksource={}: not available", name);
+                best()
+            }
+        },
+    };
+
+    if *TICK_SOURCE.call_once(|| source) != source {
+        return;
+    }
+    match source {
+        TickSource::Pit => {}
+        TickSource::Hpet => hpet::get().unwrap().start_ticking(TIMER_HZ),
+        TickSource::TscDeadline => {
+            crate::interrupts::mask_irq(0);
+            apic::start_tsc_deadline_timer();
+            NEXT_DEADLINE.store(tsc(), Ordering::Relaxed);
+            arm_next_deadline();
+        }
+    }
+    info!("timer interrupt from the {}", source);
+}
+
+/// What raises the timer interrupt.
+pub fn tick_source() -> TickSource {
+    TICK_SOURCE.r#try().copied().unwrap_or(TickSource::Pit)
+}
+
+/// Sets the local APIC timer to fire a `TIMER_HZ`th of a second after it
+/// last did. Called by its interrupt handler, in TSC-deadline mode.
+pub(crate) fn arm_next_deadline() {
+    let period = tsc_hz().unwrap_or(0) / TIMER_HZ;
+    let now = tsc();
+    let mut next = NEXT_DEADLINE.load(Ordering::Relaxed) + period;
+    // After falling behind, like when stopped in a debugger, the missed ticks
+    // aren't made up for all at once.
+    if next <= now {
+        next = now + period;
+    }
+    NEXT_DEADLINE.store(next, Ordering::Relaxed);
+    apic::set_tsc_deadline(next);
+}
+
+pub fn register_commands() {
+    shell::register(
+        "clocksource",
+        "show what raises the timer interrupt",
+        |_args, out| {
+            writeln!(out, "timer interrupt  {} ({} Hz)", tick_source(), TIMER_HZ)?;
+            write!(out, "available       ")?;
+            for source in TickSource::ALL.iter().filter(|source| source.available()) {
+                write!(out, " {}", source)?;
+            }
+            writeln!(out)?;
+            if let Some(hz) = tsc_hz() {
+                writeln!(out, "tsc              {} MHz", hz / 1_000_000)?;
+            }
+            if let Some(hpet) = hpet::get() {
+                writeln!(
+                    out,
+                    "hpet             {} MHz",
+                    hpet.frequency_hz() / 1_000_000
+                )?;
+            }
+            Ok(())
+        },
+    );
+    shell::register(
+        "boottime",
+        "print how long each boot phase took",
+        |_args, out| write!(out, "{}", boot_report()),
+    );
+    shell::register(
+        "uptime",
+        "print how long the system has been up",
+        |_args, out| writeln!(out, "up {}", Uptime(now())),
+    );
+    shell::register_async("sleep", "wait, `sleep <ms>`", |args, out| {
+        Box::pin(sleep_command(args, out))
+    });
+    sntp::register_commands();
+}
+
+async fn sleep_command(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    let ms: u64 = match args.get(1).map(|ms| ms.parse()) {
+        Some(Ok(ms)) => ms,
+        _ => return writeln!(out, "usage: sleep <ms>"),
+    };
+    sleep(ms_to_ticks(ms)).await;
+    Ok(())
+}
+
+/// Converts milliseconds to timer ticks, rounding up.
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    ms.saturating_mul(TIMER_HZ).div_ceil(1000)
+}
+
+/// What the clock looked like at the last tick: `now` interpolates from here
+/// with the TSC, so it needn't read the PIT.
+#[derive(Clone, Copy)]
+struct Clock {
+    ticks: u64,
+    tsc: u64,
+}
+
+static CLOCK: SeqLock<Clock> = SeqLock::new(Clock { ticks: 0, tsc: 0 });
+
+/// Called by the timer interrupt handler
+pub(crate) fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    let tsc = tsc();
+    CLOCK.write(|clock| *clock = Clock { ticks: now, tsc });
+    wake_sleepers(now);
+}
+
+/// Returns the number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the time since boot in milliseconds, with tick granularity.
+pub fn uptime_ms() -> u64 {
+    ticks() * 1000 / TIMER_HZ
+}
+
+/// Returns the time since boot, interpolated between ticks with the TSC once
+/// it's calibrated. It never goes backwards: the interpolation stops short of
+/// the next tick, even if that tick is late.
+pub fn now() -> Duration {
+    const TICK_NS: u64 = 1_000_000_000 / TIMER_HZ;
+
+    let clock = CLOCK.read();
+    let since_tick = match tsc_hz() {
+        Some(hz) => {
+            let cycles = tsc().saturating_sub(clock.tsc);
+            ((cycles as u128 * 1_000_000_000 / hz as u128) as u64).min(TICK_NS - 1)
+        }
+        None => 0,
+    };
+    Duration::from_nanos(clock.ticks * TICK_NS + since_tick)
+}
+
+// The wall clock is `now` plus the time since the Unix epoch at some point
+// since boot. It starts out from the RTC, which only has whole seconds, and
+// once the network is up, `sntp` keeps it right, including for how much
+// faster or slower than real time `now` runs.
+
+/// Maps `now` to the wall clock: at `since_boot`, it was `unix`, and it has
+/// run `ppm` parts per million faster than `now` since.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct WallClock {
+    pub since_boot: Duration,
+    pub unix: Duration,
+    pub ppm: i64,
+}
+
+impl WallClock {
+    /// The wall clock when `now()` is `since_boot`.
+    pub fn at(&self, since_boot: Duration) -> Duration {
+        let elapsed = since_boot.saturating_sub(self.since_boot);
+        let drift = elapsed.as_nanos() as i128 * i128::from(self.ppm) / 1_000_000;
+        let unix = (self.unix + elapsed).as_nanos() as i128 + drift;
+        Duration::from_nanos(unix.max(0) as u64)
+    }
+}
+
+static WALL_CLOCK: SeqLock<Option<WallClock>> = SeqLock::new(None);
+
+/// The time since the Unix epoch, in UTC.
+pub fn wall_clock() -> Duration {
+    let since_boot = now();
+    wall_clock_state(since_boot).at(since_boot)
+}
+
+/// How to get to the wall clock from `now`, reading the RTC the first time.
+pub(crate) fn wall_clock_state(since_boot: Duration) -> WallClock {
+    if let Some(clock) = WALL_CLOCK.read() {
+        return clock;
+    }
+    let clock = WallClock {
+        since_boot,
+        unix: Duration::from_secs(rtc::now().to_unix()),
+        ppm: 0,
+    };
+    set_wall_clock(clock);
+    clock
+}
+
+pub(crate) fn set_wall_clock(clock: WallClock) {
+    WALL_CLOCK.write(|wall_clock| *wall_clock = Some(clock));
+}
+
+/// Formats a time since boot like `2 days, 03:04:05.678`.
+pub struct Uptime(pub Duration);
+
+impl fmt::Display for Uptime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let seconds = self.0.as_secs();
+        match seconds / 86400 {
+            0 => {}
+            1 => write!(f, "1 day, ")?,
+            days => write!(f, "{} days, ", days)?,
+        }
+        write!(
+            f,
+            "{:02}:{:02}:{:02}.{:03}",
+            seconds / 3600 % 24,
+            seconds / 60 % 60,
+            seconds % 60,
+            self.0.subsec_millis()
+        )
+    }
+}
+
+// Tasks sleep by leaving a waker and a deadline in `SLEEPERS`, which the timer
+// interrupt wakes once the deadline has passed. The interrupt handler only
+// wakes them by reference and marks them as woken: dropping a waker could free
+// memory, which interrupt handlers mustn't do, so the sleeping future removes
+// its entry itself.
+
+struct Sleeper {
+    id: u64,
+    deadline: u64,
+    waker: Waker,
+    woken: bool,
+}
+
+static SLEEPERS: Mutex<Vec<Sleeper>> = Mutex::new(Vec::new());
+
+fn wake_sleepers(now: u64) {
+    // Tasks only take the lock with interrupts disabled, so it's free unless
+    // a process was interrupted holding it.
+    if let Some(mut sleepers) = SLEEPERS.try_lock() {
+        for sleeper in sleepers.iter_mut() {
+            if !sleeper.woken && sleeper.deadline <= now {
+                sleeper.woken = true;
+                sleeper.waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+/// A future that finishes at a tick, see `sleep_until`.
+pub struct Sleep {
+    id: u64,
+    deadline: u64,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let (id, deadline) = (self.id, self.deadline);
+        if ticks() >= deadline {
+            return Poll::Ready(());
+        }
+
+        // The waker can change between polls, so it's replaced every time.
+        interrupts::without_interrupts(|| {
+            let mut sleepers = SLEEPERS.lock();
+            sleepers.retain(|sleeper| sleeper.id != id);
+            sleepers.push(Sleeper {
+                id,
+                deadline,
+                waker: cx.waker().clone(),
+                woken: false,
+            });
+        });
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        let id = self.id;
+        interrupts::without_interrupts(|| SLEEPERS.lock().retain(|sleeper| sleeper.id != id));
+    }
+}
+
+/// Waits until `ticks()` reaches `deadline`.
+pub fn sleep_until(deadline: u64) -> Sleep {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    Sleep {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        deadline,
+    }
+}
+
+/// Waits for `ticks` timer ticks.
+pub fn sleep(ticks: u64) -> Sleep {
+    sleep_until(self::ticks() + ticks)
+}
+
+/// Runs `future` for at most `ticks` timer ticks. Returns `None` if it didn't
+/// finish in time.
+pub async fn timeout<F: Future>(ticks: u64, future: F) -> Option<F::Output> {
+    match future::select(Box::pin(future), sleep(ticks)).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right(_) => None,
+    }
+}
+
+/// Reads the CPU's time stamp counter.
+///
+/// The TSC counts at a constant rate on anything remotely modern, but that
+/// rate is unknown until it has been calibrated against another timer.
+pub fn tsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Returns the TSC frequency measured by `init`.
+pub fn tsc_hz() -> Option<u64> {
+    match TSC_HZ.load(Ordering::Relaxed) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+/// Replaces the TSC frequency measured by `init` with a better one, like the
+/// one the hypervisor knows.
+pub(crate) fn set_tsc_hz(hz: u64) {
+    TSC_HZ.store(hz, Ordering::Relaxed);
+}
+
+/// Converts a number of TSC cycles to microseconds, if the TSC is calibrated.
+pub fn tsc_to_us(cycles: u64) -> Option<u64> {
+    tsc_hz().map(|hz| (cycles as u128 * 1_000_000 / hz as u128) as u64)
+}
+
+// The boot sequence marks the end of each of its phases with `boot_phase`, so
+// that we can tell where boot time goes, and notice when a new subsystem makes
+// booting noticeably slower.
+
+const MAX_BOOT_PHASES: usize = 16;
+
+static BOOT_START: AtomicU64 = AtomicU64::new(0);
+static BOOT_PHASES: Mutex<[Option<(&'static str, u64)>; MAX_BOOT_PHASES]> =
+    Mutex::new([None; MAX_BOOT_PHASES]);
+
+/// Marks the start of the boot sequence. Should be the first thing the kernel does.
+pub fn boot_begin() {
+    BOOT_START.store(tsc(), Ordering::Relaxed);
+}
+
+/// Marks the end of the given boot phase, which started when the previous one ended.
+///
+/// Phases beyond `MAX_BOOT_PHASES` are ignored.
+pub fn boot_phase(name: &'static str) {
+    let now = tsc();
+
+    if let Some(slot) = BOOT_PHASES.lock().iter_mut().find(|slot| slot.is_none()) {
+        *slot = Some((name, now));
+    }
+}
+
+/// Returns the boot timing report, which is printed with `{}`.
+pub fn boot_report() -> BootReport {
+    BootReport {
+        start: BOOT_START.load(Ordering::Relaxed),
+        phases: *BOOT_PHASES.lock(),
+    }
+}
+
+pub struct BootReport {
+    start: u64,
+    phases: [Option<(&'static str, u64)>; MAX_BOOT_PHASES],
+}
+
+impl BootReport {
+    fn write_duration(f: &mut fmt::Formatter, name: &str, cycles: u64) -> fmt::Result {
+        match tsc_to_us(cycles) {
+            Some(us) => writeln!(f, "  {:<12} {:>8} us", name, us),
+            None => writeln!(f, "  {:<12} {:>8} cycles", name, cycles),
+        }
+    }
+}
+
+impl fmt::Display for BootReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match tsc_hz() {
+            Some(hz) => writeln!(f, "boot timing (TSC at {} MHz):", hz / 1_000_000)?,
+            None => writeln!(f, "boot timing (TSC not calibrated):")?,
+        }
+
+        // Without `boot_begin`, the first phase has no start and is skipped.
+        let mut previous = match self.start {
+            0 => None,
+            start => Some(start),
+        };
+
+        for &(name, end) in self.phases.iter().flatten() {
+            if let Some(start) = previous {
+                Self::write_duration(f, name, end - start)?;
+            }
+            previous = Some(end);
+        }
+
+        let last = self.phases.iter().flatten().last();
+        if let (true, Some(&(_, end))) = (self.start != 0, last) {
+            Self::write_duration(f, "total", end - self.start)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[test_case]
+static CONVERTS_MS_TO_TICKS: HostTest = HostTest::new("time::converts_ms_to_ticks", || {
+    assert_eq!(ms_to_ticks(0), 0);
+    assert_eq!(ms_to_ticks(1), 1);
+    assert_eq!(ms_to_ticks(10), 1);
+    assert_eq!(ms_to_ticks(11), 2);
+    assert_eq!(ms_to_ticks(1000), TIMER_HZ);
+});
+
+#[test_case]
+static FORMATS_UPTIME: HostTest = HostTest::new("time::formats_uptime", || {
+    let uptime = |ms| alloc::format!("{}", Uptime(Duration::from_millis(ms)));
+    assert_eq!(uptime(0), "00:00:00.000");
+    assert_eq!(uptime(3_723_004), "01:02:03.004");
+    assert_eq!(uptime(86_400_000), "1 day, 00:00:00.000");
+    assert_eq!(uptime(3 * 86_400_000 + 86_399_999), "3 days, 23:59:59.999");
+});
+
+#[test_case]
+static PARSES_TICK_SOURCES: HostTest = HostTest::new("time::parses_tick_sources", || {
+    assert_eq!("pit".parse(), Ok(TickSource::Pit));
+    assert_eq!("tsc-deadline".parse(), Ok(TickSource::TscDeadline));
+    assert_eq!(
+        "lapic".parse::<TickSource>(),
+        Err(KernelError::InvalidArgument)
+    );
+    assert_eq!(alloc::format!("{}", TickSource::Hpet), "hpet");
+});
+
+#[test_case]
+static RUNS_THE_WALL_CLOCK: HostTest = HostTest::new("time::runs_the_wall_clock", || {
+    let clock = WallClock {
+        since_boot: Duration::from_secs(10),
+        unix: Duration::from_secs(1_700_000_000),
+        ppm: -100,
+    };
+    assert_eq!(clock.at(Duration::from_secs(5)), clock.unix);
+    assert_eq!(
+        clock.at(Duration::from_secs(110)),
+        Duration::from_secs(1_700_000_100) - Duration::from_millis(10)
+    );
+});
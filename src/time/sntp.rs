@@ -0,0 +1,359 @@
+// A Simple Network Time Protocol client (RFC 4330). `run` asks an NTP server
+// for the time every `POLL_INTERVAL_S` seconds, and corrects the wall clock
+// (see `time::wall_clock`) with the answer.
+//
+// Each query has four timestamps: when we sent it (t1), when the server
+// received it (t2) and answered (t3), and when the answer came back (t4). The
+// wall clock is off by ((t2 - t1) + (t3 - t4)) / 2, which leaves out the
+// network delay as long as it's the same both ways.
+//
+// An offset over `STEP_THRESHOLD_MS`, like the RTC's up to a second at boot,
+// is corrected by setting the clock. A smaller one is what the clock drifted
+// since the last query: it's corrected too, and it adjusts how fast the wall
+// clock runs against `now`, which is only as good as the TSC's calibration,
+// so that it drifts less until the next query.
+//
+// The server is the one that `ntp=<address>` on the command line names. There
+// is no DNS client, so it's an IPv4 address.
+
+use super::WallClock;
+use crate::net::udp::{Endpoint, UdpSocket};
+use crate::net::{Ipv4Address, NetError};
+#[cfg(test)]
+use crate::HostTest;
+use crate::{cmdline, info, shell, time, warn};
+use alloc::boxed::Box;
+use core::convert::TryInto;
+use core::fmt::{self, Write};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const PORT: u16 = 123;
+
+/// How often `run` asks. RFC 4330 asks clients not to ask more often than
+/// every 15 seconds, and servers can't tell a clock more than that anyway.
+const POLL_INTERVAL_S: u64 = 64;
+/// How long to wait for an answer.
+const TIMEOUT_MS: u64 = 2000;
+/// Offsets larger than this are corrected by setting the clock, without
+/// touching its rate.
+const STEP_THRESHOLD_MS: i64 = 128;
+/// How far the rate can be off. Anything more is a bad answer rather than a
+/// bad TSC calibration.
+const MAX_PPM: i64 = 500;
+
+const PACKET_LEN: usize = 48;
+const VERSION: u8 = 4;
+const MODE_CLIENT: u8 = 3;
+const MODE_SERVER: u8 = 4;
+const LEAP_UNSYNCHRONIZED: u8 = 3;
+const ORIGINATE: usize = 24;
+const RECEIVE: usize = 32;
+const TRANSMIT: usize = 40;
+
+/// NTP counts seconds from 1900, Unix from 1970.
+const NTP_TO_UNIX_S: u64 = 2_208_988_800;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SntpError {
+    Net(NetError),
+    Timeout,
+    /// The answer wasn't one, or it was from a server that doesn't know the
+    /// time itself.
+    BadAnswer,
+}
+
+impl fmt::Display for SntpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SntpError::Net(error) => write!(f, "{}", error),
+            SntpError::Timeout => f.write_str("no answer"),
+            SntpError::BadAnswer => f.write_str("bad answer"),
+        }
+    }
+}
+
+impl From<NetError> for SntpError {
+    fn from(error: NetError) -> Self {
+        SntpError::Net(error)
+    }
+}
+
+/// What a query found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    /// When the answer came, on `time::now`.
+    pub at: Duration,
+    /// How far the wall clock is behind the server's, in nanoseconds.
+    pub offset_ns: i64,
+    /// The round trip, less the time the server took to answer.
+    pub delay: Duration,
+}
+
+/// The last sample that `run` or `sntp` took, and who from.
+static LAST_SYNC: Mutex<Option<(Ipv4Address, Sample)>> = Mutex::new(None);
+
+/// Keeps the wall clock in sync with the server that the command line names,
+/// if any.
+pub async fn run() {
+    let server = match cmdline::value("ntp") {
+        Some(address) => match address.parse() {
+            Ok(address) => Endpoint::new(address, PORT),
+            Err(_) => return warn!("ntp={}: not an IPv4 address", address),
+        },
+        None => return,
+    };
+
+    loop {
+        match sync(server).await {
+            Ok(sample) => info!(
+                "sntp: {}: offset {} us, delay {} us",
+                server,
+                sample.offset_ns / 1000,
+                sample.delay.as_micros()
+            ),
+            Err(error) => warn!("sntp: {}: {}", server, error),
+        }
+        time::sleep(POLL_INTERVAL_S * time::TIMER_HZ).await;
+    }
+}
+
+/// Asks `server` for the time, and corrects the wall clock with the answer.
+pub async fn sync(server: Endpoint) -> Result<Sample, SntpError> {
+    let clock = time::wall_clock_state(time::now());
+    let sample = query(server, &clock).await?;
+
+    let last =
+        interrupts::without_interrupts(|| LAST_SYNC.lock().replace((server.address, sample)));
+    let since_last = last.map(|(_, last)| sample.at.saturating_sub(last.at));
+    time::set_wall_clock(discipline(clock, sample, since_last));
+    Ok(sample)
+}
+
+/// Sends a query to `server` and waits for the answer. `clock` is the wall
+/// clock that the offset is measured from.
+async fn query(server: Endpoint, clock: &WallClock) -> Result<Sample, SntpError> {
+    let socket = UdpSocket::bind(0)?;
+    let mut request = [0u8; PACKET_LEN];
+    request[0] = VERSION << 3 | MODE_CLIENT;
+    let sent = time::now();
+    let t1 = clock.at(sent);
+    request[TRANSMIT..].copy_from_slice(&to_ntp(t1).to_be_bytes());
+    socket.send_to(&request, server).await?;
+
+    let mut answer = [0u8; PACKET_LEN];
+    let len = time::timeout(time::ms_to_ticks(TIMEOUT_MS), async {
+        loop {
+            let (len, source) = socket.recv_from(&mut answer).await;
+            if source == server {
+                return len;
+            }
+        }
+    })
+    .await
+    .ok_or(SntpError::Timeout)?;
+    let at = time::now();
+
+    let (t2, t3) = parse_answer(&answer[..len], &request).ok_or(SntpError::BadAnswer)?;
+    Ok(sample(t1, t2, t3, clock.at(at), at))
+}
+
+/// The server's receive and transmit timestamps, if `answer` answers
+/// `request`.
+fn parse_answer(answer: &[u8], request: &[u8; PACKET_LEN]) -> Option<(Duration, Duration)> {
+    if answer.len() < PACKET_LEN {
+        return None;
+    }
+    let timestamp =
+        |offset: usize| u64::from_be_bytes(answer[offset..offset + 8].try_into().unwrap());
+    let leap = answer[0] >> 6;
+    let mode = answer[0] & 0b111;
+    // Stratum 0 is a "kiss of death", which tells us to go away.
+    let stratum = answer[1];
+    if mode != MODE_SERVER
+        || leap == LEAP_UNSYNCHRONIZED
+        || !(1..=15).contains(&stratum)
+        || answer[ORIGINATE..ORIGINATE + 8] != request[TRANSMIT..]
+        || timestamp(TRANSMIT) == 0
+    {
+        return None;
+    }
+    Some((
+        from_ntp(timestamp(RECEIVE))?,
+        from_ntp(timestamp(TRANSMIT))?,
+    ))
+}
+
+fn sample(t1: Duration, t2: Duration, t3: Duration, t4: Duration, at: Duration) -> Sample {
+    let ns = |time: Duration| time.as_nanos() as i128;
+    let offset = ((ns(t2) - ns(t1)) + (ns(t3) - ns(t4))) / 2;
+    let delay = (ns(t4) - ns(t1)) - (ns(t3) - ns(t2));
+    Sample {
+        at,
+        offset_ns: offset as i64,
+        delay: Duration::from_nanos(delay.max(0) as u64),
+    }
+}
+
+/// The wall clock, corrected by `sample`. `since_last` is how long it's been
+/// since the previous sample, if there was one.
+fn discipline(clock: WallClock, sample: Sample, since_last: Option<Duration>) -> WallClock {
+    let unix = clock.at(sample.at).as_nanos() as i128 + i128::from(sample.offset_ns);
+    let mut ppm = clock.ppm;
+    if let Some(since_last) = since_last.filter(|since_last| !since_last.is_zero()) {
+        if sample.offset_ns.abs() <= STEP_THRESHOLD_MS * 1_000_000 {
+            // Only half of it, so that one answer that took longer one way
+            // than the other doesn't throw the rate off.
+            let error = i128::from(sample.offset_ns) * 1_000_000 / since_last.as_nanos() as i128;
+            ppm = (ppm + error as i64 / 2).clamp(-MAX_PPM, MAX_PPM);
+        }
+    }
+    WallClock {
+        since_boot: sample.at,
+        unix: Duration::from_nanos(unix.max(0) as u64),
+        ppm,
+    }
+}
+
+/// An NTP timestamp: 32 bits of seconds since 1900, and 32 bits of fraction.
+fn to_ntp(unix: Duration) -> u64 {
+    let seconds = (unix.as_secs() + NTP_TO_UNIX_S) as u32;
+    let fraction = (u64::from(unix.subsec_nanos()) << 32) / 1_000_000_000;
+    u64::from(seconds) << 32 | fraction
+}
+
+/// The Unix time of an NTP timestamp, unless it's from before 1970.
+fn from_ntp(timestamp: u64) -> Option<Duration> {
+    let mut seconds = timestamp >> 32;
+    // The seconds wrap around in 2036. RFC 4330 takes timestamps without the
+    // top bit set to be from after that, since none of them are from before
+    // 1968.
+    if seconds & 0x8000_0000 == 0 {
+        seconds += 1 << 32;
+    }
+    let nanos = ((timestamp & 0xFFFF_FFFF) * 1_000_000_000) >> 32;
+    Some(Duration::new(
+        seconds.checked_sub(NTP_TO_UNIX_S)?,
+        nanos as u32,
+    ))
+}
+
+pub fn register_commands() {
+    shell::register_async(
+        "sntp",
+        "set the clock from an NTP server: sntp [ip]",
+        |args, out| Box::pin(sntp_command(args, out)),
+    );
+}
+
+async fn sntp_command(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    let address: Ipv4Address = match args.get(1).map(|address| address.parse()) {
+        Some(Ok(address)) => address,
+        Some(Err(_)) => return writeln!(out, "usage: sntp [ip]"),
+        None => {
+            return match interrupts::without_interrupts(|| *LAST_SYNC.lock()) {
+                Some((server, sample)) => writeln!(
+                    out,
+                    "synced with {} {} ago, offset {} us, delay {} us, rate {} ppm",
+                    server,
+                    time::Uptime(time::now().saturating_sub(sample.at)),
+                    sample.offset_ns / 1000,
+                    sample.delay.as_micros(),
+                    time::wall_clock_state(time::now()).ppm
+                ),
+                None => writeln!(out, "not synced"),
+            }
+        }
+    };
+    match sync(Endpoint::new(address, PORT)).await {
+        Ok(sample) => writeln!(
+            out,
+            "offset {} us, delay {} us",
+            sample.offset_ns / 1000,
+            sample.delay.as_micros()
+        ),
+        Err(error) => writeln!(out, "sntp: {}: {}", address, error),
+    }
+}
+
+#[test_case]
+static CONVERTS_TIMESTAMPS: HostTest = HostTest::new("time::sntp::converts_timestamps", || {
+    let unix = Duration::new(1_700_000_000, 500_000_000);
+    assert_eq!(
+        to_ntp(unix),
+        (1_700_000_000 + NTP_TO_UNIX_S) << 32 | 1 << 31
+    );
+    assert_eq!(from_ntp(to_ntp(unix)), Some(unix));
+    // 2036-02-07 06:28:16, when the seconds wrap
+    assert_eq!(from_ntp(0), Some(Duration::from_secs(2_085_978_496)));
+    // 1968 to 1970
+    assert_eq!(from_ntp(0x8000_0000 << 32), None);
+});
+
+#[test_case]
+static CHECKS_ANSWERS: HostTest = HostTest::new("time::sntp::checks_answers", || {
+    let mut request = [0u8; PACKET_LEN];
+    request[0] = VERSION << 3 | MODE_CLIENT;
+    request[TRANSMIT..].copy_from_slice(&to_ntp(Duration::from_secs(1)).to_be_bytes());
+
+    let mut answer = [0u8; PACKET_LEN];
+    answer[0] = VERSION << 3 | MODE_SERVER;
+    answer[1] = 2;
+    answer[ORIGINATE..ORIGINATE + 8].copy_from_slice(&request[TRANSMIT..]);
+    let t2 = Duration::from_secs(1_700_000_000);
+    let t3 = t2 + Duration::from_millis(500);
+    answer[RECEIVE..RECEIVE + 8].copy_from_slice(&to_ntp(t2).to_be_bytes());
+    answer[TRANSMIT..].copy_from_slice(&to_ntp(t3).to_be_bytes());
+    assert_eq!(parse_answer(&answer, &request), Some((t2, t3)));
+
+    let mut kiss_of_death = answer;
+    kiss_of_death[1] = 0;
+    assert_eq!(parse_answer(&kiss_of_death, &request), None);
+    let mut not_ours = answer;
+    not_ours[ORIGINATE] ^= 1;
+    assert_eq!(parse_answer(&not_ours, &request), None);
+    assert_eq!(parse_answer(&answer[..40], &request), None);
+    let mut before_1970 = answer;
+    before_1970[RECEIVE..RECEIVE + 8].copy_from_slice(&(0x8000_0000u64 << 32).to_be_bytes());
+    assert_eq!(parse_answer(&before_1970, &request), None);
+});
+
+#[test_case]
+static DISCIPLINES_THE_CLOCK: HostTest = HostTest::new("time::sntp::disciplines_the_clock", || {
+    let secs = Duration::from_secs;
+    let ms = Duration::from_millis;
+
+    // The server is 10 s ahead, and the network takes 20 ms each way.
+    let sample = sample(
+        secs(100),
+        secs(110) + ms(20),
+        secs(110) + ms(21),
+        secs(100) + ms(41),
+        secs(5),
+    );
+    assert_eq!(sample.offset_ns, 10_000_000_000);
+    assert_eq!(sample.delay, ms(40));
+
+    let clock = WallClock {
+        since_boot: Duration::ZERO,
+        unix: secs(95),
+        ppm: 0,
+    };
+    let stepped = discipline(clock, sample, Some(secs(64)));
+    assert_eq!(stepped.at(secs(5)), secs(110));
+    assert_eq!(stepped.ppm, 0);
+
+    // 6.4 ms behind after 64 s is 100 ppm slow, and half of that is made up.
+    let drifted = Sample {
+        at: secs(69),
+        offset_ns: 6_400_000,
+        delay: ms(40),
+    };
+    let disciplined = discipline(stepped, drifted, Some(secs(64)));
+    assert_eq!(
+        disciplined.at(secs(69)),
+        secs(174) + ms(6) + Duration::from_micros(400)
+    );
+    assert_eq!(disciplined.ppm, 50);
+});
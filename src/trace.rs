@@ -0,0 +1,213 @@
+// Lightweight tracepoints. `trace_event!` formats a short message into a fixed
+// size record, stamps it with the TSC and stores it in the ring buffer of the
+// current CPU. Older events get overwritten, so tracing can stay enabled all the
+// time and the buffers hold the last few hundred events when something goes
+// wrong. Nothing here allocates, so tracepoints are fine in interrupt handlers
+// and inside the allocator.
+//
+// `dump` writes the buffers as plain text lines framed by markers:
+//
+//   TRACE BEGIN
+//   <cpu> <tsc> <category> <message>
+//   ...
+//   TRACE END
+//
+// `tools/trace_to_chrome.py` turns such a block from a serial log into the
+// Chrome trace event JSON format, which chrome://tracing and Perfetto can load.
+
+use crate::time;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const EVENTS_PER_CPU: usize = 256;
+const MESSAGE_LEN: usize = 48;
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+crate::per_cpu! {
+    static RINGS: Mutex<TraceRing> = Mutex::new(TraceRing::new());
+}
+
+#[derive(Clone, Copy)]
+struct Event {
+    tsc: u64,
+    category: &'static str,
+    message: [u8; MESSAGE_LEN],
+    len: u8,
+}
+
+impl Event {
+    const EMPTY: Event = Event {
+        tsc: 0,
+        category: "",
+        message: [0; MESSAGE_LEN],
+        len: 0,
+    };
+
+    fn message(&self) -> &str {
+        // The writer only ever cuts messages at char boundaries.
+        core::str::from_utf8(&self.message[..usize::from(self.len)]).unwrap_or("<invalid>")
+    }
+}
+
+// Messages longer than MESSAGE_LEN are silently truncated.
+impl Write for Event {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let len = usize::from(self.len);
+            if len + c.len_utf8() > MESSAGE_LEN {
+                break;
+            }
+            c.encode_utf8(&mut self.message[len..]);
+            self.len += c.len_utf8() as u8;
+        }
+        Ok(())
+    }
+}
+
+struct TraceRing {
+    events: [Event; EVENTS_PER_CPU],
+    // Total number of events ever recorded, the next slot is `recorded % EVENTS_PER_CPU`.
+    recorded: usize,
+}
+
+impl TraceRing {
+    const fn new() -> Self {
+        TraceRing {
+            events: [Event::EMPTY; EVENTS_PER_CPU],
+            recorded: 0,
+        }
+    }
+
+    /// Iterates over the stored events, oldest first.
+    fn iter(&self) -> impl DoubleEndedIterator<Item = &Event> {
+        let stored = self.recorded.min(EVENTS_PER_CPU);
+        let start = self.recorded - stored;
+
+        (start..self.recorded).map(move |i| &self.events[i % EVENTS_PER_CPU])
+    }
+}
+
+/// Turns recording of new events on or off.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+#[doc(hidden)]
+pub fn _record(category: &'static str, args: fmt::Arguments) {
+    let mut event = Event {
+        tsc: time::tsc(),
+        category,
+        ..Event::EMPTY
+    };
+    let _ = event.write_fmt(args);
+
+    interrupts::without_interrupts(|| {
+        let mut ring = RINGS.get().lock();
+        let slot = ring.recorded % EVENTS_PER_CPU;
+
+        ring.events[slot] = event;
+        ring.recorded += 1;
+    });
+}
+
+/// Writes all recorded events of all CPUs in the text format described at the
+/// top of this module, each CPU's events oldest first.
+pub fn dump(out: &mut dyn Write) -> fmt::Result {
+    writeln!(out, "TRACE BEGIN")?;
+
+    for (cpu, ring) in RINGS.iter().enumerate() {
+        interrupts::without_interrupts(|| -> fmt::Result {
+            for event in ring.lock().iter() {
+                writeln!(
+                    out,
+                    "{} {} {} {}",
+                    cpu,
+                    event.tsc,
+                    event.category,
+                    event.message()
+                )?;
+            }
+            Ok(())
+        })?;
+    }
+
+    writeln!(out, "TRACE END")
+}
+
+/// Calls `f` with the TSC and message of the current CPU's last `N` events in
+/// `category`, oldest first.
+pub fn for_each_recent<const N: usize>(category: &str, mut f: impl FnMut(u64, &str)) {
+    // Copied out, so that `f` can record events of its own.
+    let mut recent = [Event::EMPTY; N];
+    let mut found = 0;
+    interrupts::without_interrupts(|| {
+        let ring = RINGS.get().lock();
+        for event in ring.iter().rev().filter(|event| event.category == category) {
+            if found == N {
+                break;
+            }
+            recent[found] = *event;
+            found += 1;
+        }
+    });
+    for event in recent[..found].iter().rev() {
+        f(event.tsc, event.message());
+    }
+}
+
+/// Drops all recorded events.
+pub fn clear() {
+    for ring in RINGS.iter() {
+        interrupts::without_interrupts(|| ring.lock().recorded = 0);
+    }
+}
+
+/// Records a trace event in the current CPU's ring buffer.
+///
+/// ```ignore
+/// trace_event!("executor", "wake task {}", id);
+/// ```
+///
+/// The category should be a short word without spaces, it ends up as a column
+/// in the dump.
+#[macro_export]
+macro_rules! trace_event {
+    ($category:expr, $($arg:tt)+) => {
+        if $crate::trace::enabled() {
+            $crate::trace::_record($category, format_args!($($arg)+));
+        }
+    };
+}
+
+#[test_case]
+fn test_message_truncation() {
+    let mut event = Event::EMPTY;
+    for _ in 0..MESSAGE_LEN + 10 {
+        event.write_str("x").unwrap();
+    }
+
+    assert_eq!(event.message().len(), MESSAGE_LEN);
+}
+
+#[test_case]
+fn test_recent_events() {
+    for i in 0..4 {
+        _record("test_recent", format_args!("{}", i));
+        _record("test_other", format_args!("x"));
+    }
+
+    let mut seen = [0u8; 4];
+    let mut count = 0;
+    for_each_recent::<3>("test_recent", |_tsc, message| {
+        seen[count] = message.as_bytes()[0];
+        count += 1;
+    });
+    assert_eq!(&seen[..count], b"123");
+}
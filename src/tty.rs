@@ -0,0 +1,349 @@
+// The console TTY, which connects the keyboard and the serial port (input) and
+// the screen and the serial port (output) to whoever is using the console.
+//
+// Input is fed in by the `run` task. In canonical mode, it is edited a line at a
+// time, with echo and backspace, and readers only see complete lines. In raw
+// mode, readers get every character as it arrives, and nothing is echoed.
+//
+// There are two kinds of readers: the shell (a kernel task, through `read_line`)
+// and processes (through the read system call). The input goes to the
+// foreground process if there is one, and to the shell otherwise. Processes
+// that aren't in the foreground find their standard input empty. When the
+// foreground process exits, the console goes back to the shell, in canonical
+// mode.
+
+use crate::process::{self, Pid};
+use crate::{print, serial, serial_print, task::keyboard};
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use core::fmt::{self, Write};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use futures_util::stream::{self, StreamExt};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// How much input is kept for readers before more is dropped.
+const INPUT_CAPACITY: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Line editing and echo.
+    Canonical,
+    /// Every character is passed on as is.
+    Raw,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtyError {
+    /// Only the foreground process can change the console's mode.
+    NotForeground,
+}
+
+struct Tty {
+    mode: Mode,
+    // The line being edited in canonical mode.
+    line: String,
+    // Input that readers can take.
+    ready: VecDeque<u8>,
+    foreground: Option<Pid>,
+    // The shell, waiting in `read_line`. A waiting process is woken through the
+    // scheduler instead.
+    waker: Option<Waker>,
+    // Process output, while it is being captured.
+    captured: Option<String>,
+}
+
+static TTY: Mutex<Tty> = Mutex::new(Tty {
+    mode: Mode::Canonical,
+    line: String::new(),
+    ready: VecDeque::new(),
+    foreground: None,
+    waker: None,
+    captured: None,
+});
+
+/// Writes to both the screen and the serial port.
+pub struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        print!("{}", s);
+        serial_print!("{}", s);
+        Ok(())
+    }
+}
+
+/// Feeds the input from the keyboard and the serial port into the TTY.
+pub async fn run() {
+    let serial = serial::input_stream().map(char::from);
+    let mut input = stream::select(keyboard::characters(), serial);
+
+    while let Some(character) = input.next().await {
+        self::input(character);
+    }
+}
+
+/// Handles one character of input, as if it was typed.
+pub fn input(character: char) {
+    interrupts::without_interrupts(|| {
+        let mut tty = TTY.lock();
+
+        match tty.mode {
+            Mode::Raw => {
+                let mut bytes = [0; 4];
+                tty.push_ready(character.encode_utf8(&mut bytes).as_bytes());
+            }
+            Mode::Canonical => tty.edit(character),
+        }
+    });
+}
+
+impl Tty {
+    fn edit(&mut self, character: char) {
+        match character {
+            // Terminals send a carriage return for the enter key.
+            '\n' | '\r' => {
+                print!("\n");
+                serial_print!("\r\n");
+
+                let mut line = core::mem::take(&mut self.line);
+                line.push('\n');
+                self.push_ready(line.as_bytes());
+            }
+            // Backspace from the keyboard, DEL from most terminals.
+            '\x08' | '\x7f' => {
+                if self.line.pop().is_some() {
+                    print!("\x08");
+                    serial_print!("\x08 \x08");
+                }
+            }
+            character if !character.is_control() => {
+                self.line.push(character);
+                print!("{}", character);
+                serial_print!("{}", character);
+            }
+            _ => {}
+        }
+    }
+
+    fn push_ready(&mut self, bytes: &[u8]) {
+        let room = INPUT_CAPACITY - self.ready.len();
+        self.ready.extend(bytes.iter().take(room));
+
+        // Whoever is waiting for input checks for it again.
+        match self.foreground {
+            Some(pid) => process::wake(pid),
+            None => {
+                if let Some(waker) = self.waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+
+    /// Takes input for a reader: up to one line in canonical mode, anything
+    /// that's there in raw mode.
+    fn take(&mut self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+
+        while len < buf.len() {
+            let byte = match self.ready.pop_front() {
+                Some(byte) => byte,
+                None => break,
+            };
+            buf[len] = byte;
+            len += 1;
+
+            if byte == b'\n' && self.mode == Mode::Canonical {
+                break;
+            }
+        }
+
+        len
+    }
+}
+
+/// Reads input for the current process into `buf`, blocking until there is
+/// some, and returns how much was read.
+///
+/// Returns 0 (end of input) for processes that aren't in the foreground, and
+/// for an empty buffer.
+pub fn read(buf: &mut [u8]) -> usize {
+    let current = process::current();
+
+    // With interrupts disabled, no input can arrive between our check and `block`.
+    interrupts::without_interrupts(|| loop {
+        {
+            let mut tty = TTY.lock();
+            if tty.foreground != Some(current) || buf.is_empty() {
+                return 0;
+            }
+            if !tty.ready.is_empty() {
+                return tty.take(buf);
+            }
+        }
+
+        process::block();
+    })
+}
+
+/// Returns the next line of input for the shell, without the newline, if there
+/// is one and no process is in the foreground.
+pub fn try_read_line() -> Option<String> {
+    interrupts::without_interrupts(|| {
+        let mut tty = TTY.lock();
+        if tty.foreground.is_some() {
+            return None;
+        }
+
+        let end = tty.ready.iter().position(|&byte| byte == b'\n')?;
+        let line: Vec<u8> = tty.ready.drain(..=end).take(end).collect();
+
+        Some(String::from_utf8_lossy(&line).into_owned())
+    })
+}
+
+/// Returns the next byte of input for the shell, if there is one and no
+/// process is in the foreground. For reading keys one by one in raw mode.
+pub fn try_read_byte() -> Option<u8> {
+    interrupts::without_interrupts(|| {
+        let mut tty = TTY.lock();
+        if tty.foreground.is_some() {
+            return None;
+        }
+        tty.ready.pop_front()
+    })
+}
+
+/// Waits for the next line of input for the shell, see `try_read_line`.
+pub fn read_line() -> impl Future<Output = String> {
+    Read(try_read_line)
+}
+
+/// Waits for the next byte of input for the shell, see `try_read_byte`.
+pub fn read_byte() -> impl Future<Output = u8> {
+    Read(try_read_byte)
+}
+
+/// Waits until its function finds some input.
+struct Read<T>(fn() -> Option<T>);
+
+impl<T> Future for Read<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        // Register first, so that input arriving right after the check isn't missed.
+        interrupts::without_interrupts(|| TTY.lock().waker = Some(cx.waker().clone()));
+
+        match (self.0)() {
+            Some(input) => Poll::Ready(input),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Writes output from a process to the console.
+pub fn write(bytes: &[u8]) {
+    let text = String::from_utf8_lossy(bytes);
+
+    interrupts::without_interrupts(|| {
+        if let Some(captured) = &mut TTY.lock().captured {
+            captured.push_str(&text);
+        }
+    });
+
+    let _ = Console.write_str(&text);
+}
+
+/// Starts or stops recording the output of processes, so that tests can
+/// check what a program printed. Stopping discards what was recorded.
+pub fn capture_output(enable: bool) {
+    interrupts::without_interrupts(|| {
+        TTY.lock().captured = if enable { Some(String::new()) } else { None };
+    });
+}
+
+/// Returns the output recorded since `capture_output` or the last call, and
+/// keeps recording.
+pub fn take_captured_output() -> String {
+    interrupts::without_interrupts(|| {
+        TTY.lock()
+            .captured
+            .as_mut()
+            .map(core::mem::take)
+            .unwrap_or_default()
+    })
+}
+
+/// Gives the console to the given process, or back to the shell with `None`.
+pub fn set_foreground(pid: Option<Pid>) {
+    interrupts::without_interrupts(|| {
+        let mut tty = TTY.lock();
+        tty.foreground = pid;
+
+        if pid.is_none() {
+            tty.mode = Mode::Canonical;
+            if let Some(waker) = tty.waker.take() {
+                waker.wake();
+            }
+        }
+    });
+}
+
+pub fn foreground() -> Option<Pid> {
+    interrupts::without_interrupts(|| TTY.lock().foreground)
+}
+
+pub fn mode() -> Mode {
+    interrupts::without_interrupts(|| TTY.lock().mode)
+}
+
+/// Switches between canonical and raw mode, on behalf of the current process.
+/// The kernel may always do that.
+pub fn set_mode(mode: Mode) -> Result<(), TtyError> {
+    let current = process::current();
+
+    interrupts::without_interrupts(|| {
+        let mut tty = TTY.lock();
+        if current != Pid::KERNEL && tty.foreground != Some(current) {
+            return Err(TtyError::NotForeground);
+        }
+
+        // A half-edited line is passed on as is.
+        if mode == Mode::Raw && !tty.line.is_empty() {
+            let line = core::mem::take(&mut tty.line);
+            tty.push_ready(line.as_bytes());
+        }
+        tty.mode = mode;
+
+        Ok(())
+    })
+}
+
+/// Keeps the console in raw mode until it's dropped, for a shell command that
+/// reads keys one by one. Dropping it also restores the mode when the command
+/// is cancelled.
+pub struct RawMode;
+
+impl RawMode {
+    pub fn enter() -> RawMode {
+        // The kernel may always change the mode
+        let _ = set_mode(Mode::Raw);
+        RawMode
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = set_mode(Mode::Canonical);
+    }
+}
+
+/// Gives the console back to the shell if the given process has it. Called
+/// when a process exits.
+pub(crate) fn release(pid: Pid) {
+    if foreground() == Some(pid) {
+        set_foreground(None);
+    }
+}
@@ -0,0 +1,169 @@
+// Since we compile with frame pointers forced on (see .cargo/config.toml), every
+// function starts with `push rbp; mov rbp, rsp`. This turns the stack into a
+// linked list of frames: rbp points to the saved rbp of the caller, and right
+// above it sits the return address into the caller. Walking that chain gives us
+// a backtrace without any unwind tables.
+//
+//      higher addresses
+//     +----------------+
+//     | return address |  <- rbp + 8
+//     | caller's rbp   |  <- rbp
+//     | locals ...     |
+//      lower addresses
+//
+// We are usually walking the stack because something already went wrong, so we
+// check every frame pointer against the page tables before dereferencing it
+// and stop at the first thing that doesn't look like a frame.
+
+use crate::{memory, symbols};
+use core::fmt;
+use x86_64::VirtAddr;
+
+const MAX_FRAMES: usize = 32;
+
+/// Frames of a sane kernel function are much smaller than this. A larger jump
+/// between two frame pointers means we are following garbage.
+const MAX_FRAME_SIZE: u64 = 1024 * 1024;
+
+/// A captured list of return addresses, innermost frame first.
+#[derive(Clone)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    fn empty() -> Self {
+        Backtrace {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, address: u64) -> bool {
+        if self.len == MAX_FRAMES {
+            return false;
+        }
+
+        self.frames[self.len] = address;
+        self.len += 1;
+
+        true
+    }
+
+    /// Returns the captured return addresses, innermost frame first.
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+
+    /// Follows the frame pointer chain starting at `rbp`.
+    fn walk(&mut self, mut rbp: u64) {
+        while is_readable_frame(rbp) {
+            let (next_rbp, return_address) = unsafe {
+                let frame = rbp as *const u64;
+                (frame.read(), frame.add(1).read())
+            };
+
+            if return_address == 0 || !self.push(return_address) {
+                break;
+            }
+
+            // The stack grows down, so callers always live at higher addresses.
+            if next_rbp <= rbp || next_rbp - rbp > MAX_FRAME_SIZE {
+                break;
+            }
+
+            rbp = next_rbp;
+        }
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "backtrace:")?;
+
+        if self.len == 0 {
+            return writeln!(f, "  <unavailable>");
+        }
+
+        for (i, &address) in self.frames().iter().enumerate() {
+            match symbols::resolve(address) {
+                Some(symbol) => writeln!(f, "  #{:<2} {:#018x} {}", i, address, symbol)?,
+                None => writeln!(f, "  #{:<2} {:#018x}", i, address)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the current value of the frame pointer register.
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+
+    unsafe {
+        core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+
+    rbp
+}
+
+/// Captures a backtrace of the calling function's stack.
+#[inline(always)]
+pub fn backtrace() -> Backtrace {
+    let mut backtrace = Backtrace::empty();
+    backtrace.walk(frame_pointer());
+
+    backtrace
+}
+
+/// Captures a backtrace of an interrupted context, e.g. from an exception
+/// handler, given the interrupted instruction pointer and frame pointer.
+pub fn backtrace_from(instruction_pointer: u64, rbp: u64) -> Backtrace {
+    let mut backtrace = Backtrace::empty();
+    backtrace.push(instruction_pointer);
+    backtrace.walk(rbp);
+
+    backtrace
+}
+
+/// Returns the frame pointer of the code that was interrupted when an
+/// interrupt handler was entered.
+///
+/// The CPU doesn't touch rbp when it delivers an interrupt, so the first thing
+/// an `extern "x86-interrupt"` function saves in its own frame is the rbp of
+/// whatever it interrupted. Must be called directly from the handler.
+#[inline(always)]
+pub fn interrupted_frame_pointer() -> u64 {
+    let rbp = frame_pointer();
+
+    if is_readable_frame(rbp) {
+        unsafe { (rbp as *const u64).read() }
+    } else {
+        0
+    }
+}
+
+/// Checks that both words of the frame at `rbp` are mapped, and in the
+/// kernel: user stacks are only for `usercopy` to touch.
+fn is_readable_frame(rbp: u64) -> bool {
+    if rbp == 0 || rbp % 8 != 0 {
+        return false;
+    }
+    if memory::address_space::is_user_range(VirtAddr::new_truncate(rbp), 16) {
+        return false;
+    }
+
+    let is_mapped = |addr: u64| match VirtAddr::try_new(addr) {
+        Ok(addr) => memory::translate_addr(addr).is_some(),
+        Err(_) => false,
+    };
+
+    // The two words can straddle a page boundary only if rbp sits in the last
+    // eight bytes of a page, so checking both ends covers it.
+    match rbp.checked_add(15) {
+        Some(end) => is_mapped(rbp) && is_mapped(end),
+        None => false,
+    }
+}
@@ -0,0 +1,196 @@
+// Copying between the kernel and the current process's memory. System calls
+// get addresses from user space, which can point anywhere: into the kernel,
+// into nothing, or into memory the process may read but not write. Nothing
+// but these functions should ever dereference one.
+//
+// An address range is first checked against the process's VMAs, the ranges
+// it has mapped. That catches the mistakes, but the copy can still fault if
+// the page tables don't agree with the VMAs, so the copying is done by a few
+// instructions in assembly, which are listed in a fixup table, the
+// `kusercopy_fixups` linker section. When one of them faults, the page fault
+// handler doesn't give up but carries on at its fixup, which makes the copy
+// fail with `Fault`.
+//
+// With SMAP (supervisor mode access prevention), which `init` turns on if the
+// CPU has it, the kernel can't touch user pages at all unless it sets the AC
+// flag first, so even a stray pointer from user space can't be followed by
+// accident. The copies set it for as long as they run.
+
+use crate::info;
+use crate::process;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::arch::{asm, global_asm};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::VirtAddr;
+
+/// The CPU has SMAP (CPUID leaf 7, EBX).
+const CPUID_SMAP: u32 = 1 << 20;
+
+static SMAP: AtomicBool = AtomicBool::new(false);
+
+/// The user memory isn't there, or can't be written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault;
+
+global_asm!(
+    // usercopy_copy(dst, src, len) -> how many bytes it didn't copy
+    ".global usercopy_copy",
+    "usercopy_copy:",
+    "mov rcx, rdx",
+    "usercopy_copy_access:",
+    "rep movsb",
+    "xor eax, eax",
+    "ret",
+    "usercopy_copy_fixup:",
+    // rcx counts down as `rep movsb` goes
+    "mov rax, rcx",
+    "ret",
+    // usercopy_strncpy(dst, src, len) -> the string's length, len if it's
+    // longer, or -1 if it faulted. Copies the terminating NUL too.
+    ".global usercopy_strncpy",
+    "usercopy_strncpy:",
+    "xor eax, eax",
+    ".Lstrncpy_next:",
+    "cmp rax, rdx",
+    "je .Lstrncpy_done",
+    "usercopy_strncpy_access:",
+    "movzx ecx, byte ptr [rsi + rax]",
+    "mov [rdi + rax], cl",
+    "test cl, cl",
+    "jz .Lstrncpy_done",
+    "inc rax",
+    "jmp .Lstrncpy_next",
+    ".Lstrncpy_done:",
+    "ret",
+    "usercopy_strncpy_fixup:",
+    "mov rax, -1",
+    "ret",
+    // Each instruction that touches user memory, and where to go when it
+    // faults
+    ".pushsection kusercopy_fixups, \"awR\"",
+    ".quad usercopy_copy_access, usercopy_copy_fixup",
+    ".quad usercopy_strncpy_access, usercopy_strncpy_fixup",
+    ".popsection",
+);
+
+extern "C" {
+    fn usercopy_copy(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn usercopy_strncpy(dst: *mut u8, src: *const u8, len: usize) -> isize;
+
+    // Only their addresses mean anything
+    static __start_kusercopy_fixups: u8;
+    static __stop_kusercopy_fixups: u8;
+}
+
+#[repr(C)]
+struct Fixup {
+    access: u64,
+    fixup: u64,
+}
+
+fn fixups() -> &'static [Fixup] {
+    // Safe since the linker puts the two symbols around the section, which
+    // holds nothing but fixups.
+    unsafe {
+        let start = &__start_kusercopy_fixups as *const u8 as *const Fixup;
+        let stop = &__stop_kusercopy_fixups as *const u8 as *const Fixup;
+        core::slice::from_raw_parts(start, stop.offset_from(start) as usize)
+    }
+}
+
+/// Turns on SMAP, if the CPU has it. Before the first process runs, since
+/// from then on the kernel only reaches user memory through here.
+pub fn init() {
+    let has_smap =
+        unsafe { __cpuid(0) }.eax >= 7 && unsafe { __cpuid_count(7, 0) }.ebx & CPUID_SMAP != 0;
+    if !has_smap {
+        return;
+    }
+
+    unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION)) };
+    SMAP.store(true, Ordering::Relaxed);
+    info!("usercopy: SMAP is on");
+}
+
+/// Where the page fault handler should carry on after a fault at
+/// `instruction_pointer`, if that's one of the copies in here.
+pub(crate) fn fixup(instruction_pointer: VirtAddr) -> Option<VirtAddr> {
+    fixups()
+        .iter()
+        .find(|fixup| fixup.access == instruction_pointer.as_u64())
+        .map(|fixup| VirtAddr::new(fixup.fixup))
+}
+
+/// Checks that the current process may access `addr..addr + len`, and may
+/// write to it if `write` is set. The copies check by themselves, this is for
+/// system calls that want to fail before they start.
+pub fn check(addr: u64, len: u64, write: bool) -> Result<VirtAddr, Fault> {
+    let start = VirtAddr::try_new(addr).map_err(|_| Fault)?;
+    if !process::current_can_access(start, len, write) {
+        return Err(Fault);
+    }
+    Ok(start)
+}
+
+/// Runs `f` with the AC flag set, which lets it touch user pages with SMAP.
+fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    let smap = SMAP.load(Ordering::Relaxed);
+    // Not `nomem`: memory accesses must not move out from between the two.
+    if smap {
+        unsafe { asm!("stac", options(nostack)) };
+    }
+    let result = f();
+    if smap {
+        unsafe { asm!("clac", options(nostack)) };
+    }
+    result
+}
+
+/// Fills `dst` from the current process's memory at `src`.
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), Fault> {
+    let src = check(src, dst.len() as u64, false)?;
+    let left =
+        with_user_access(|| unsafe { usercopy_copy(dst.as_mut_ptr(), src.as_ptr(), dst.len()) });
+    match left {
+        0 => Ok(()),
+        _ => Err(Fault),
+    }
+}
+
+/// Copies `src` to the current process's memory at `dst`.
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), Fault> {
+    let dst = check(dst, src.len() as u64, true)?;
+    let left =
+        with_user_access(|| unsafe { usercopy_copy(dst.as_mut_ptr(), src.as_ptr(), src.len()) });
+    match left {
+        0 => Ok(()),
+        _ => Err(Fault),
+    }
+}
+
+/// Copies the NUL-terminated string at `src` in the current process's memory
+/// to `dst`, NUL included, and returns its length without the NUL. Like
+/// Linux's, it returns `dst.len()` if the string doesn't fit, and then `dst`
+/// isn't terminated.
+pub fn strncpy_from_user(dst: &mut [u8], src: u64) -> Result<usize, Fault> {
+    // The string's end isn't known up front, so the range is checked a page
+    // at a time, as far as the string goes.
+    let mut copied = 0;
+    while copied < dst.len() {
+        let addr = src.checked_add(copied as u64).ok_or(Fault)?;
+        let page_left = 4096 - (addr % 4096) as usize;
+        let len = (dst.len() - copied).min(page_left);
+        let addr = check(addr, len as u64, false)?;
+
+        let dst = &mut dst[copied..copied + len];
+        let found =
+            with_user_access(|| unsafe { usercopy_strncpy(dst.as_mut_ptr(), addr.as_ptr(), len) });
+        match found {
+            -1 => return Err(Fault),
+            found if (found as usize) < len => return Ok(copied + found as usize),
+            _ => copied += len,
+        }
+    }
+    Ok(copied)
+}
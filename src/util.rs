@@ -0,0 +1,364 @@
+// Small helpers for poking at the kernel that don't belong to any subsystem.
+//
+// `hexdump` formats memory the way `hexdump -C` does, sixteen bytes a line:
+//
+//     ffff800000001000  7f 45 4c 46 02 01 01 00  00 00 00 00 00 00 00 00  |.ELF............|
+//
+// Every byte is only read after `memory::translate_addr` has found its page in
+// the page tables, so dumping an address that isn't mapped prints `??` rather
+// than faulting. That makes it safe to point at anything from the shell.
+//
+// `peek` and `poke` read and write a single value, of 1, 2, 4 or 8 bytes, at a
+// virtual address or (with `-p`) a physical one, which is reached through the
+// mapping of all physical memory. That's mostly for device registers during
+// driver bring-up, so the access is volatile and has exactly the width asked
+// for. They check the address is mapped first, and `poke` won't write a page
+// that isn't writable or the kernel's code, even through its physical address:
+// that's found by walking the page tables from one of our own functions for as
+// long as the pages are executable and read-only.
+
+#[cfg(test)]
+use crate::HostTest;
+use crate::{memory, shell};
+use core::fmt::{self, Write};
+use core::ptr;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+const BYTES_PER_LINE: u64 = 16;
+const PAGE_SIZE: u64 = 4096;
+
+/// The most the `hexdump` command prints at once.
+const MAX_DUMP_LEN: u64 = 64 * 1024;
+
+/// How many bytes `peek` and `poke` access if not told: most device registers
+/// are 32 bits.
+const DEFAULT_WIDTH: u64 = 4;
+
+/// How far to look for the kernel's code on either side of our own function.
+const MAX_TEXT_PAGES: u64 = 4096;
+
+/// Formats `len` bytes of memory starting at `addr` with `{}`, see above.
+pub fn hexdump(addr: VirtAddr, len: usize) -> HexDump {
+    HexDump {
+        addr: addr.as_u64(),
+        len: len as u64,
+        read: read_mapped,
+    }
+}
+
+pub struct HexDump {
+    addr: u64,
+    len: u64,
+    read: fn(u64) -> Option<u8>,
+}
+
+/// Reads the byte at `addr` if its page is mapped.
+fn read_mapped(addr: u64) -> Option<u8> {
+    let page = VirtAddr::try_new(addr & !(PAGE_SIZE - 1)).ok()?;
+    memory::translate_addr(page)?;
+    Some(unsafe { (addr as *const u8).read_volatile() })
+}
+
+impl fmt::Display for HexDump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let end = self.addr.saturating_add(self.len);
+        let mut line = self.addr;
+        while line < end {
+            let mut bytes = [None; BYTES_PER_LINE as usize];
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                let addr = line + i as u64;
+                if addr < end {
+                    *byte = Some((self.read)(addr));
+                }
+            }
+            write_line(f, line, &bytes)?;
+            line = match line.checked_add(BYTES_PER_LINE) {
+                Some(next) => next,
+                None => break,
+            };
+        }
+        Ok(())
+    }
+}
+
+/// Writes one line of the dump. `None` is past the end, `Some(None)` is a
+/// byte that isn't mapped.
+fn write_line(
+    f: &mut fmt::Formatter,
+    addr: u64,
+    bytes: &[Option<Option<u8>>; BYTES_PER_LINE as usize],
+) -> fmt::Result {
+    write!(f, "{:016x} ", addr)?;
+    for (i, byte) in bytes.iter().enumerate() {
+        if i % 8 == 0 {
+            f.write_char(' ')?;
+        }
+        match byte {
+            Some(Some(byte)) => write!(f, "{:02x} ", byte)?,
+            Some(None) => f.write_str("?? ")?,
+            None => f.write_str("   ")?,
+        }
+    }
+
+    f.write_str(" |")?;
+    for byte in bytes.iter().flatten() {
+        match byte {
+            Some(byte) if byte.is_ascii_graphic() || *byte == b' ' => {
+                f.write_char(*byte as char)?
+            }
+            _ => f.write_char('.')?,
+        }
+    }
+    f.write_str("|\n")
+}
+
+/// Parses a number in decimal, or in hex with a `0x` prefix.
+pub(crate) fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+pub fn register_commands() {
+    shell::register(
+        "hexdump",
+        "print memory, `hexdump <addr> <len>`",
+        hexdump_command,
+    );
+    shell::register(
+        "peek",
+        "read memory, `peek [-p] <addr> [1|2|4|8]`",
+        peek_command,
+    );
+    shell::register(
+        "poke",
+        "write memory, `poke [-p] <addr> <value> [1|2|4|8]`",
+        poke_command,
+    );
+}
+
+fn hexdump_command(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    let (addr, len) = match args {
+        [_, addr, len] => match (parse_number(addr), parse_number(len)) {
+            (Some(addr), Some(len)) => (addr, len),
+            _ => return writeln!(out, "usage: hexdump <addr> <len>"),
+        },
+        _ => return writeln!(out, "usage: hexdump <addr> <len>"),
+    };
+
+    let addr = match VirtAddr::try_new(addr) {
+        Ok(addr) => addr,
+        Err(_) => return writeln!(out, "hexdump: {:#x} isn't a canonical address", addr),
+    };
+    if len > MAX_DUMP_LEN {
+        return writeln!(out, "hexdump: at most {} bytes at a time", MAX_DUMP_LEN);
+    }
+    write!(out, "{}", hexdump(addr, len as usize))
+}
+
+/// Why `peek` or `poke` can't go somewhere.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AccessError {
+    BadAddress,
+    Unaligned,
+    NotMapped,
+    ReadOnly,
+    KernelText,
+}
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            AccessError::BadAddress => "not a valid address",
+            AccessError::Unaligned => "not aligned to the width",
+            AccessError::NotMapped => "not mapped",
+            AccessError::ReadOnly => "read-only",
+            AccessError::KernelText => "the kernel's code",
+        })
+    }
+}
+
+/// Finds where `peek` or `poke` can reach `addr`, physical if `physical`, and
+/// checks that it may access `width` bytes there.
+fn check_access(
+    addr: u64,
+    physical: bool,
+    width: u64,
+    write: bool,
+) -> Result<VirtAddr, AccessError> {
+    let virt = if physical {
+        let phys = PhysAddr::try_new(addr).map_err(|_| AccessError::BadAddress)?;
+        memory::try_phys_to_virt(phys).ok_or(AccessError::BadAddress)?
+    } else {
+        VirtAddr::try_new(addr).map_err(|_| AccessError::BadAddress)?
+    };
+    if addr % width != 0 {
+        return Err(AccessError::Unaligned);
+    }
+
+    let (phys, flags) = memory::translate_with_flags(virt).ok_or(AccessError::NotMapped)?;
+    if write {
+        if is_kernel_text(phys) {
+            return Err(AccessError::KernelText);
+        }
+        if !flags.contains(PageTableFlags::WRITABLE) {
+            return Err(AccessError::ReadOnly);
+        }
+    }
+    Ok(virt)
+}
+
+/// Whether the frame at `phys` holds some of the kernel's code.
+fn is_kernel_text(phys: PhysAddr) -> bool {
+    let frame = phys.align_down(PAGE_SIZE);
+    let code = VirtAddr::new(is_kernel_text as usize as u64).align_down(PAGE_SIZE);
+
+    // The frame of `page`, if it's code.
+    let text_frame = |page: VirtAddr| match memory::translate_with_flags(page) {
+        Some((phys, flags))
+            if !flags.intersects(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE) =>
+        {
+            Some(phys.align_down(PAGE_SIZE))
+        }
+        _ => None,
+    };
+    let mut pages_below = (0..MAX_TEXT_PAGES).map_while(|i| {
+        let page = code.as_u64().checked_sub(i * PAGE_SIZE)?;
+        text_frame(VirtAddr::try_new(page).ok()?)
+    });
+    let mut pages_above = (1..MAX_TEXT_PAGES).map_while(|i| {
+        let page = code.as_u64().checked_add(i * PAGE_SIZE)?;
+        text_frame(VirtAddr::try_new(page).ok()?)
+    });
+    pages_below.any(|text| text == frame) || pages_above.any(|text| text == frame)
+}
+
+/// Splits off the `-p` in front of the arguments, which makes the address a
+/// physical one.
+fn physical_flag<'a>(args: &'a [&'a str]) -> (bool, &'a [&'a str]) {
+    match args {
+        ["-p", rest @ ..] => (true, rest),
+        _ => (false, args),
+    }
+}
+
+fn parse_width(width: Option<&&str>) -> Option<u64> {
+    match width {
+        None => Some(DEFAULT_WIDTH),
+        Some(width) => parse_number(width).filter(|width| [1, 2, 4, 8].contains(width)),
+    }
+}
+
+fn peek_command(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    const USAGE: &str = "usage: peek [-p] <addr> [1|2|4|8]";
+
+    let (physical, args) = physical_flag(&args[1..]);
+    let (addr, width) = match args {
+        [addr] | [addr, _] => match (parse_number(addr), parse_width(args.get(1))) {
+            (Some(addr), Some(width)) => (addr, width),
+            _ => return writeln!(out, "{}", USAGE),
+        },
+        _ => return writeln!(out, "{}", USAGE),
+    };
+
+    let virt = match check_access(addr, physical, width, false) {
+        Ok(virt) => virt,
+        Err(error) => return writeln!(out, "peek: {:#x} is {}", addr, error),
+    };
+    let value = unsafe {
+        match width {
+            1 => u64::from(ptr::read_volatile(virt.as_ptr::<u8>())),
+            2 => u64::from(ptr::read_volatile(virt.as_ptr::<u16>())),
+            4 => u64::from(ptr::read_volatile(virt.as_ptr::<u32>())),
+            _ => ptr::read_volatile(virt.as_ptr::<u64>()),
+        }
+    };
+    writeln!(
+        out,
+        "{:#x}: {:#0digits$x}",
+        addr,
+        value,
+        digits = 2 + 2 * width as usize
+    )
+}
+
+fn poke_command(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    const USAGE: &str = "usage: poke [-p] <addr> <value> [1|2|4|8]";
+
+    let (physical, args) = physical_flag(&args[1..]);
+    let (addr, value, width) = match args {
+        [addr, value] | [addr, value, _] => {
+            match (
+                parse_number(addr),
+                parse_number(value),
+                parse_width(args.get(2)),
+            ) {
+                (Some(addr), Some(value), Some(width)) => (addr, value, width),
+                _ => return writeln!(out, "{}", USAGE),
+            }
+        }
+        _ => return writeln!(out, "{}", USAGE),
+    };
+    if width < 8 && value >> (8 * width) != 0 {
+        return writeln!(out, "poke: {:#x} doesn't fit in {} bytes", value, width);
+    }
+
+    let virt = match check_access(addr, physical, width, true) {
+        Ok(virt) => virt,
+        Err(error) => return writeln!(out, "poke: {:#x} is {}", addr, error),
+    };
+    unsafe {
+        match width {
+            1 => ptr::write_volatile(virt.as_mut_ptr::<u8>(), value as u8),
+            2 => ptr::write_volatile(virt.as_mut_ptr::<u16>(), value as u16),
+            4 => ptr::write_volatile(virt.as_mut_ptr::<u32>(), value as u32),
+            _ => ptr::write_volatile(virt.as_mut_ptr::<u64>(), value),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+static TEST_MEMORY: &[u8] = b"Hello, world!\n\x00\x7f\xff";
+
+#[cfg(test)]
+fn read_test_memory(addr: u64) -> Option<u8> {
+    TEST_MEMORY.get(addr.checked_sub(0x1000)? as usize).copied()
+}
+
+#[test_case]
+static FORMATS_HEXDUMP: HostTest = HostTest::new("util::formats_hexdump", || {
+    let dump = |addr, len| {
+        let dump = HexDump {
+            addr,
+            len,
+            read: read_test_memory,
+        };
+        alloc::format!("{}", dump)
+    };
+
+    assert_eq!(
+        dump(0x1000, 20),
+        "0000000000001000  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0a 00 7f  |Hello, world!...|\n\
+         0000000000001010  ff ?? ?? ??                                       |....|\n"
+    );
+    assert_eq!(
+        dump(0x1004, 3),
+        "0000000000001004  6f 2c 20                                          |o, |\n"
+    );
+    assert_eq!(dump(0x1000, 0), "");
+});
+
+#[test_case]
+static PARSES_NUMBERS: HostTest = HostTest::new("util::parses_numbers", || {
+    assert_eq!(parse_number("4096"), Some(4096));
+    assert_eq!(parse_number("0x1000"), Some(4096));
+    assert_eq!(
+        parse_number("0xffff800000000000"),
+        Some(0xffff_8000_0000_0000)
+    );
+    assert_eq!(parse_number("0x"), None);
+    assert_eq!(parse_number("12ab"), None);
+});
@@ -1,8 +1,6 @@
+use crate::sync::{Lazy, SpinLock};
 use core::fmt::Write;
-use lazy_static::lazy_static;
-use spin::Mutex;
 use volatile::Volatile;
-use x86_64::instructions::interrupts;
 
 // We use a C-like enum here to explicitly specify the number for each color.
 // Because of the repr(u8) attribute, each enum variant is stored as a u8.
@@ -48,8 +46,9 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+/// The size of the screen, in characters.
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
 
 // repr[transparent] here to ensure that it has the same
 // memory layout as its single field.
@@ -69,6 +68,7 @@ impl Writer {
     pub fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
+            0x08 => self.backspace(),
             byte => {
                 if self.column_position >= BUFFER_WIDTH {
                     self.new_line();
@@ -92,8 +92,8 @@ impl Writer {
     pub fn write_string(&mut self, s: &str) {
         for byte in s.bytes() {
             match byte {
-                // Printable ASCII byte or newline:
-                0x20..=0x7E | b'\n' => self.write_byte(byte),
+                // Printable ASCII byte, newline or backspace:
+                0x20..=0x7E | b'\n' | 0x08 => self.write_byte(byte),
                 // Not part of printable ASCII range.
                 _ => self.write_byte(0xfe),
             }
@@ -113,6 +113,39 @@ impl Writer {
         self.column_position = 0;
     }
 
+    // Moves the cursor back by one and erases the character there. Doesn't
+    // go back to the previous line, since that has already scrolled up.
+    fn backspace(&mut self) {
+        if self.column_position > 0 {
+            self.column_position -= 1;
+            self.buffer.chars[BUFFER_HEIGHT - 1][self.column_position].write(ScreenChar {
+                ascii_char: b' ',
+                color_code: self.color_code,
+            });
+        }
+    }
+
+    /// Writes `byte` at the given position, in the given colors, for drawing
+    /// on the screen rather than printing. Doesn't move the cursor, and does
+    /// nothing outside of the screen.
+    pub fn write_at(&mut self, row: usize, col: usize, byte: u8, colors: (Color, Color)) {
+        if row < BUFFER_HEIGHT && col < BUFFER_WIDTH {
+            self.buffer.chars[row][col].write(ScreenChar {
+                ascii_char: byte,
+                color_code: ColorCode::new(colors.0, colors.1),
+            });
+        }
+    }
+
+    /// Clears the whole screen, and puts the cursor back at the start of the
+    /// bottom row.
+    pub fn clear(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.column_position = 0;
+    }
+
     // Clears a row by overwriting all of its characters with a space character.
     fn clear_row(&mut self, row: usize) {
         let blank = ScreenChar {
@@ -135,19 +168,39 @@ impl core::fmt::Write for Writer {
 }
 
 // The one-time initialization of statics with non-const functions is a common
-// problem in Rust. Fortunately, there already exists a good solution in a crate
-// named lazy_static. This crate provides a lazy_static! macro that defines a
-// lazily initialized static. Instead of computing its value at compile time,
-// the static lazily initializes itself when accessed for the first time. Thus,
-// the initialization happens at runtime, so arbitrarily complex initialization
-// code is possible. we can use the spinning mutex to add safe interior mutability
-// to our static WRITER:
-lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+// problem in Rust. We solve it with `sync::Lazy`, a lazily initialized static.
+// Instead of computing its value at compile time, the static initializes itself
+// when accessed for the first time. Thus, the initialization happens at
+// runtime, so arbitrarily complex initialization code is possible. we can use a spinlock to add safe interior mutability to our
+// static WRITER. It keeps interrupts disabled while it's held, so an interrupt
+// handler that prints can't deadlock against the code it interrupted:
+pub static WRITER: Lazy<SpinLock<Writer>> = Lazy::new(|| {
+    SpinLock::new(Writer {
         column_position: 0,
         color_code: ColorCode::new(Color::Green, Color::Black),
         buffer: unsafe { &mut *(0xB8000 as *mut Buffer) },
-    });
+    })
+});
+
+/// Releases the writer's lock if it is held.
+///
+/// # Safety
+///
+/// Only for crash paths: whoever holds the lock must never run again.
+pub unsafe fn force_unlock() {
+    if WRITER.is_locked() {
+        WRITER.force_unlock();
+    }
+}
+
+// Only machines booted through the BIOS have the VGA text buffer. With the
+// "uefi" feature, the kernel is booted by version 0.11 of the bootloader, which
+// sets up a graphics mode on BIOS machines too and doesn't even map 0xB8000, so
+// printing goes to the framebuffer console instead (see framebuffer.rs).
+
+/// Whether there is a VGA text buffer that `print!` writes to.
+pub fn present() -> bool {
+    !cfg!(feature = "uefi")
 }
 
 #[macro_export]
@@ -166,12 +219,16 @@ macro_rules! println {
 // implementation detail, we add the doc(hidden) attribute to hide it from the
 // generated documentation.
 /// Prints the given formatted string to the VGA text buffer
-/// through the global `WRITER` instance.
+/// through the global `WRITER` instance, or to the framebuffer console if
+/// there is no VGA text buffer.
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    })
+    if !present() {
+        #[cfg(feature = "uefi")]
+        crate::framebuffer::_print(args);
+        return;
+    }
+    WRITER.lock().write_fmt(args).unwrap();
 }
 
 #[test_case]
@@ -192,13 +249,12 @@ fn test_println_many() {
 fn test_println_output() {
     let s = "some test string that fits on a single line";
 
-    interrupts::without_interrupts(|| {
-        let mut writer = WRITER.lock();
-        writeln!(writer, "\n{}", s).expect("writeln failed");
+    // Holding the lock keeps the timer interrupt from printing in between.
+    let mut writer = WRITER.lock();
+    writeln!(writer, "\n{}", s).expect("writeln failed");
 
-        for (i, c) in s.chars().enumerate() {
-            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
-            assert_eq!(char::from(screen_char.ascii_char), c);
-        }
-    })
+    for (i, c) in s.chars().enumerate() {
+        let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
+        assert_eq!(char::from(screen_char.ascii_char), c);
+    }
 }
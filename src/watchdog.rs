@@ -0,0 +1,88 @@
+// With cooperative multitasking, a single future that never returns from `poll`
+// hangs the whole kernel, and all we see is a frozen screen. The watchdog makes
+// such hangs visible: the executor pets it on every round of its run loop (which
+// happens at least once per timer tick, since the idle `hlt` is woken up by the
+// timer), and the timer interrupt checks how long ago that was. If the executor
+// hasn't come around for `TIMEOUT_SECS`, we dump what we know over serial.
+// `burn` in the shell hogs the executor on purpose, to see it fire.
+
+use crate::{allocator, interrupts, serial, serial_println, task::executor, time, trace};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// How long the executor may go without petting the watchdog.
+pub const TIMEOUT_SECS: u64 = 5;
+
+static LAST_PET: AtomicU64 = AtomicU64::new(0);
+static FIRED: AtomicBool = AtomicBool::new(false);
+
+/// Tells the watchdog that the executor is still making progress.
+pub fn pet() {
+    LAST_PET.store(time::ticks(), Ordering::Relaxed);
+
+    if FIRED.swap(false, Ordering::Relaxed) {
+        serial_println!("watchdog: executor made progress again");
+    }
+}
+
+/// Called by the timer interrupt handler
+pub(crate) fn check() {
+    let last_pet = LAST_PET.load(Ordering::Relaxed);
+
+    // Not armed until the executor runs for the first time.
+    if last_pet == 0 || time::ticks() - last_pet < TIMEOUT_SECS * time::TIMER_HZ {
+        return;
+    }
+
+    // Only report each hang once
+    if !FIRED.swap(true, Ordering::Relaxed) {
+        report(last_pet);
+    }
+}
+
+// Runs in interrupt context. Every lock we take here is only ever held with
+// interrupts disabled, so none of them can be held by the code we interrupted.
+fn report(last_pet: u64) {
+    let stalled_ms = (time::ticks() - last_pet) * 1000 / time::TIMER_HZ;
+
+    serial_println!("watchdog: executor stalled for {} ms", stalled_ms);
+
+    serial_println!("tasks: {} alive", executor::task_count());
+    match executor::current_task() {
+        Some((id, since)) => {
+            let polling_ms = (time::ticks() - since) * 1000 / time::TIMER_HZ;
+            serial_println!("  task {} has been in poll() for {} ms", id, polling_ms);
+        }
+        None => {
+            serial_println!("  no task is being polled");
+        }
+    }
+
+    serial_println!("interrupts:");
+    for irq in 0..16 {
+        let count = interrupts::irq_count(irq);
+        if count != 0 {
+            serial_println!("  irq {:>2}: {}", irq, count);
+        }
+    }
+
+    serial_println!("locks:");
+    serial_println!("  allocator: {}", lock_state(allocator::is_locked()));
+    #[cfg(feature = "lockdep")]
+    if let Some(owner) = allocator::lock_owner() {
+        serial_println!(
+            "    held by CPU {}, acquired at {}",
+            owner.cpu,
+            owner.location
+        );
+    }
+
+    let _ = serial::with_channel(serial::Channel::Log, trace::dump);
+}
+
+fn lock_state(locked: bool) -> &'static str {
+    if locked {
+        "held"
+    } else {
+        "free"
+    }
+}
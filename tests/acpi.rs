@@ -0,0 +1,54 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use rust_os_playground::acpi;
+
+rust_os_playground::kernel_test_main!(heap, acpi);
+
+#[test_case]
+fn finds_the_tables() {
+    assert!(acpi::present());
+    let tables = acpi::tables();
+    for signature in ["FACP", "APIC"].iter() {
+        assert!(tables.iter().any(|table| table.signature() == *signature));
+    }
+
+    let madt = acpi::find("APIC").unwrap();
+    assert_eq!(madt.signature(), "APIC");
+    assert!(madt.data().len() > acpi::HEADER_LEN);
+    assert!(acpi::find("NOPE").is_none());
+}
+
+#[test_case]
+fn reads_the_fadt() {
+    let fadt = acpi::fadt().unwrap();
+    assert_ne!(fadt.pm1a_control, 0);
+    assert_ne!(fadt.dsdt.as_u64(), 0);
+    // QEMU's DSDT defines \_S5
+    assert!(acpi::s5_sleep_types().is_some());
+}
+
+#[test_case]
+fn parses_s5() {
+    // Name (_S5, Package (0x04) { 0x05, One, Zero, Zero })
+    let aml = [
+        0x10, 0x08, 0x08, b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04, 0x0A, 0x05, 0x01, 0x00, 0x00,
+    ];
+    assert_eq!(acpi::parse_s5(&aml), Some((5, 1)));
+
+    // With a root prefix, and a two byte package length
+    let aml = [
+        0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x40, 0x00, 0x02, 0x00, 0x0A, 0x07,
+    ];
+    assert_eq!(acpi::parse_s5(&aml), Some((0, 7)));
+
+    // A method called _S5_ isn't it
+    let aml = [0x14, b'_', b'S', b'5', b'_', 0x12, 0x04, 0x02, 0x00, 0x00];
+    assert_eq!(acpi::parse_s5(&aml), None);
+    assert_eq!(acpi::parse_s5(b"no sleep states"), None);
+}
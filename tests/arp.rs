@@ -0,0 +1,195 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::vec::Vec;
+use common::net::{wire, Wire, OUR_IP, OUR_MAC, PEER_IP, PEER_MAC};
+use common::run;
+use rust_os_playground::net::arp::{self, Packet, OPERATION_REPLY, OPERATION_REQUEST};
+use rust_os_playground::net::eth::{self, Header, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use rust_os_playground::net::{Ipv4Address, MacAddress, NetError, PacketBuf};
+
+rust_os_playground::kernel_test_main!(heap);
+
+/// Takes the frames sent so far, and their Ethernet headers.
+fn take_sent(wire: &Wire) -> Vec<(Header, PacketBuf)> {
+    let frames = core::mem::take(&mut *wire.sent.lock());
+    frames
+        .into_iter()
+        .map(|mut frame| (Header::pull(&mut frame).unwrap(), frame))
+        .collect()
+}
+
+fn payload(data: &[u8]) -> PacketBuf {
+    let mut packet = PacketBuf::new();
+    packet.extend_from_slice(data);
+    packet
+}
+
+fn arp_frame(destination: MacAddress, packet: Packet) -> PacketBuf {
+    let mut frame = PacketBuf::new();
+    packet.write(frame.push(arp::PACKET_LEN));
+    let header = Header {
+        destination,
+        source: packet.sender_mac,
+        ethertype: ETHERTYPE_ARP,
+    };
+    header.push(&mut frame);
+    frame
+}
+
+fn request_from_peer(target_ip: Ipv4Address) -> Packet {
+    Packet {
+        operation: OPERATION_REQUEST,
+        sender_mac: PEER_MAC,
+        sender_ip: PEER_IP,
+        target_mac: MacAddress::default(),
+        target_ip,
+    }
+}
+
+fn reply_from_peer() -> Packet {
+    Packet {
+        operation: OPERATION_REPLY,
+        sender_mac: PEER_MAC,
+        sender_ip: PEER_IP,
+        target_mac: OUR_MAC,
+        target_ip: OUR_IP,
+    }
+}
+
+#[test_case]
+fn packets_round_trip() {
+    let packet = request_from_peer(OUR_IP);
+    let mut data = [0; arp::PACKET_LEN + 4];
+    packet.write(&mut data);
+    assert_eq!(&data[..8], [0, 1, 0x08, 0x00, 6, 4, 0, 1]);
+    assert_eq!(Packet::parse(&data), Some(packet));
+
+    assert_eq!(Packet::parse(&data[..arp::PACKET_LEN - 1]), None);
+    data[1] = 6; // Token ring
+    assert_eq!(Packet::parse(&data), None);
+}
+
+#[test_case]
+fn answers_requests_for_our_address() {
+    let (wire, interface) = wire();
+
+    let request = arp_frame(MacAddress::BROADCAST, request_from_peer(OUR_IP));
+    run(eth::handle(&interface, request));
+
+    let sent = take_sent(&wire);
+    assert_eq!(sent.len(), 1);
+    let (header, reply) = &sent[0];
+    assert_eq!(header.destination, PEER_MAC);
+    assert_eq!(header.source, OUR_MAC);
+    assert_eq!(header.ethertype, ETHERTYPE_ARP);
+    let reply = Packet::parse(reply).unwrap();
+    assert_eq!(reply.operation, OPERATION_REPLY);
+    assert_eq!((reply.sender_mac, reply.sender_ip), (OUR_MAC, OUR_IP));
+    assert_eq!((reply.target_mac, reply.target_ip), (PEER_MAC, PEER_IP));
+
+    // The sender was talking to us, so we know its address now
+    assert_eq!(arp::lookup(&interface, PEER_IP), Some(PEER_MAC));
+}
+
+#[test_case]
+fn ignores_requests_for_others() {
+    let (wire, interface) = wire();
+
+    let other = Ipv4Address::new(192, 168, 7, 3);
+    let request = arp_frame(MacAddress::BROADCAST, request_from_peer(other));
+    run(eth::handle(&interface, request));
+    assert!(take_sent(&wire).is_empty());
+    assert_eq!(arp::lookup(&interface, PEER_IP), None);
+
+    // Nor frames for other cards
+    let other_mac = MacAddress([2, 0, 0, 0, 0, 3]);
+    let request = arp_frame(other_mac, request_from_peer(OUR_IP));
+    run(eth::handle(&interface, request));
+    assert!(take_sent(&wire).is_empty());
+}
+
+#[test_case]
+fn queues_packets_until_resolved() {
+    let (wire, interface) = wire();
+
+    for data in [&b"one"[..], b"two"].iter() {
+        let send = arp::send(&interface, PEER_IP, ETHERTYPE_IPV4, payload(data));
+        assert_eq!(run(send), Ok(()));
+    }
+
+    // One request, broadcast, for both
+    let sent = take_sent(&wire);
+    assert_eq!(sent.len(), 1);
+    let (header, request) = &sent[0];
+    assert_eq!(header.destination, MacAddress::BROADCAST);
+    let request = Packet::parse(request).unwrap();
+    assert_eq!(request.operation, OPERATION_REQUEST);
+    assert_eq!((request.sender_mac, request.sender_ip), (OUR_MAC, OUR_IP));
+    assert_eq!(request.target_ip, PEER_IP);
+
+    // The reply sends them, in order
+    run(eth::handle(
+        &interface,
+        arp_frame(OUR_MAC, reply_from_peer()),
+    ));
+    let sent = take_sent(&wire);
+    let payloads: Vec<&[u8]> = sent.iter().map(|(_, packet)| &packet[..]).collect();
+    assert_eq!(payloads, [b"one", b"two"]);
+    for (header, _) in &sent {
+        assert_eq!(header.destination, PEER_MAC);
+        assert_eq!(header.ethertype, ETHERTYPE_IPV4);
+    }
+
+    // And later packets go straight out
+    let send = arp::send(&interface, PEER_IP, ETHERTYPE_IPV4, payload(b"three"));
+    assert_eq!(run(send), Ok(()));
+    let sent = take_sent(&wire);
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].0.destination, PEER_MAC);
+}
+
+#[test_case]
+fn bounds_the_queue() {
+    let (wire, interface) = wire();
+
+    for _ in 0..arp::MAX_PENDING {
+        let send = arp::send(&interface, PEER_IP, ETHERTYPE_IPV4, payload(b"queued"));
+        assert_eq!(run(send), Ok(()));
+    }
+    let send = arp::send(&interface, PEER_IP, ETHERTYPE_IPV4, payload(b"dropped"));
+    assert_eq!(run(send), Err(NetError::Unresolved));
+
+    take_sent(&wire);
+    run(eth::handle(
+        &interface,
+        arp_frame(OUR_MAC, reply_from_peer()),
+    ));
+    assert_eq!(take_sent(&wire).len(), arp::MAX_PENDING);
+}
+
+#[test_case]
+fn needs_an_address() {
+    let (wire, interface) = wire();
+    interface.set_ipv4_config(None);
+
+    let send = arp::send(&interface, PEER_IP, ETHERTYPE_IPV4, payload(b"lost"));
+    assert_eq!(run(send), Err(NetError::NoAddress));
+
+    // Broadcasts don't need resolving
+    let send = arp::send(
+        &interface,
+        Ipv4Address::BROADCAST,
+        ETHERTYPE_IPV4,
+        payload(b"everyone"),
+    );
+    assert_eq!(run(send), Ok(()));
+    assert_eq!(take_sent(&wire)[0].0.destination, MacAddress::BROADCAST);
+}
@@ -0,0 +1,53 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use core::hint::black_box;
+use rust_os_playground::bench::Bench;
+use rust_os_playground::println;
+use rust_os_playground::sync::SpinLock;
+use rust_os_playground::task::{executor::Executor, Task};
+
+rust_os_playground::kernel_test_main!(heap);
+
+#[test_case]
+static BOX_SMALL: Bench = Bench::new("allocator::box_small", || {
+    black_box(Box::new(black_box(1u64)));
+});
+
+#[test_case]
+static VEC_4K: Bench = Bench::new("allocator::vec_4k", || {
+    black_box(Vec::<u8>::with_capacity(black_box(4096)));
+});
+
+#[test_case]
+static VGA_SCROLL: Bench = Bench::new("vga_buffer::scroll", || {
+    println!();
+})
+.iterations(100);
+
+#[test_case]
+static EXECUTOR_SPAWN: Bench = Bench::new("executor::spawn_and_run", || {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {}));
+    executor.run_until_idle();
+});
+
+static LOCK: SpinLock<u64> = SpinLock::new(0);
+
+#[test_case]
+static SPINLOCK_UNCONTENDED: Bench = Bench::new("sync::spinlock_uncontended", || {
+    *LOCK.lock() += 1;
+});
+
+#[test_case]
+fn measures_every_iteration() {
+    let result = Bench::new("nothing", || {}).iterations(10).measure();
+    assert_eq!(result.iterations, 10);
+    assert!(result.min <= result.median);
+}
@@ -0,0 +1,550 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, sync::Arc, task::Wake, vec, vec::Vec};
+use core::future::Future;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use rust_os_playground::block::partitions::{self, PartitionKind};
+use rust_os_playground::block::{
+    self, BlockCache, BlockDevice, BlockError, BlockFuture, Completion, RamDisk,
+};
+use rust_os_playground::{device, time};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+rust_os_playground::kernel_test_main!(heap);
+
+#[test_case]
+fn ramdisk_reads_back_writes() {
+    let disk = RamDisk::new(512, 8);
+    assert_eq!(disk.size(), 4096);
+
+    disk.write_blocks(2, &[0xAB; 1024]).unwrap();
+
+    let mut buf = [0; 1536];
+    disk.read_blocks(1, &mut buf).unwrap();
+    assert!(buf[..512].iter().all(|&byte| byte == 0));
+    assert!(buf[512..].iter().all(|&byte| byte == 0xAB));
+}
+
+#[test_case]
+fn ramdisk_checks_requests() {
+    let disk = RamDisk::from_bytes(512, vec![1; 1000]);
+    assert_eq!(disk.num_blocks(), 2);
+
+    let mut buf = [0; 512];
+    assert_eq!(disk.read_blocks(2, &mut buf), Err(BlockError::OutOfRange));
+    assert_eq!(
+        disk.read_blocks(u64::MAX, &mut buf),
+        Err(BlockError::OutOfRange)
+    );
+    assert_eq!(
+        disk.read_blocks(0, &mut buf[..100]),
+        Err(BlockError::BadBufferSize)
+    );
+
+    // The padding is zeroed
+    disk.read_blocks(1, &mut buf).unwrap();
+    assert_eq!(buf[487], 1);
+    assert_eq!(buf[488], 0);
+
+    let disk = disk.read_only();
+    assert_eq!(disk.write_blocks(0, &buf), Err(BlockError::ReadOnly));
+}
+
+#[test_case]
+fn registry_names_devices() {
+    let first = block::register("ram", Arc::new(RamDisk::new(512, 1)));
+    let second = block::register("ram", Arc::new(RamDisk::new(512, 2)));
+    assert_eq!(first, "ram0");
+    assert_eq!(second, "ram1");
+
+    assert_eq!(block::get("ram1").unwrap().num_blocks(), 2);
+    assert!(block::get("ram2").is_none());
+    assert_eq!(block::devices().len(), 2);
+}
+
+fn put(image: &mut [u8], offset: usize, bytes: &[u8]) {
+    image[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+fn mbr_entry(image: &mut [u8], index: usize, kind: u8, start: u32, num_blocks: u32) {
+    let entry = 446 + index * 16;
+    image[entry + 4] = kind;
+    put(image, entry + 8, &start.to_le_bytes());
+    put(image, entry + 12, &num_blocks.to_le_bytes());
+}
+
+#[test_case]
+fn mbr_partitions() {
+    let mut image = vec![0; 64 * 512];
+    mbr_entry(&mut image, 0, 0x0C, 8, 16);
+    mbr_entry(&mut image, 2, 0x83, 24, 40);
+    // Doesn't fit on the disk
+    mbr_entry(&mut image, 3, 0x83, 60, 8);
+    put(&mut image, 510, &[0x55, 0xAA]);
+    put(&mut image, 24 * 512, b"third");
+
+    let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::from_bytes(512, image));
+    let table = partitions::read_table(&*disk).unwrap();
+    assert_eq!(table.len(), 3);
+    assert_eq!(table[0].number, 1);
+    assert_eq!(table[0].kind, PartitionKind::Mbr(0x0C));
+    assert_eq!((table[1].start, table[1].num_blocks), (24, 40));
+
+    let name = block::register("mbr", disk.clone());
+    let names = partitions::scan(&name, &disk).unwrap();
+    assert_eq!(names, ["mbr0p1", "mbr0p3"]);
+
+    let partition = block::get("mbr0p3").unwrap();
+    assert_eq!(partition.num_blocks(), 40);
+    let mut buf = [0; 512];
+    partition.read_blocks(0, &mut buf).unwrap();
+    assert_eq!(&buf[..5], b"third");
+    assert_eq!(
+        partition.read_blocks(40, &mut buf),
+        Err(BlockError::OutOfRange)
+    );
+
+    // Partitions are block devices of their own, but aren't scanned themselves.
+    partitions::scan_all();
+    assert!(block::get("mbr0p3p1").is_none());
+}
+
+/// A disk of 64 blocks with a GPT of 4 entries of 128 bytes, in block 2.
+fn gpt_image() -> Vec<u8> {
+    let mut image = vec![0; 64 * 512];
+    mbr_entry(&mut image, 0, 0xEE, 1, 63);
+    put(&mut image, 510, &[0x55, 0xAA]);
+
+    put(&mut image, 512, b"EFI PART");
+    put(&mut image, 512 + 72, &2u64.to_le_bytes());
+    put(&mut image, 512 + 80, &4u32.to_le_bytes());
+    put(&mut image, 512 + 84, &128u32.to_le_bytes());
+    image
+}
+
+#[test_case]
+fn gpt_partitions() {
+    let mut image = gpt_image();
+    let entry = 2 * 512 + 128;
+    let type_guid = [
+        0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D,
+        0xE4,
+    ];
+    put(&mut image, entry, &type_guid);
+    put(&mut image, entry + 32, &10u64.to_le_bytes());
+    put(&mut image, entry + 40, &19u64.to_le_bytes());
+    let name: Vec<u8> = "root".encode_utf16().flat_map(u16::to_le_bytes).collect();
+    put(&mut image, entry + 56, &name);
+
+    let table = partitions::read_table(&RamDisk::from_bytes(512, image)).unwrap();
+    assert_eq!(table.len(), 1);
+    assert_eq!(table[0].number, 2);
+    assert_eq!((table[0].start, table[0].num_blocks), (10, 10));
+    match &table[0].kind {
+        PartitionKind::Gpt { type_guid, name } => {
+            assert_eq!(name, "root");
+            assert_eq!(
+                alloc::format!("{}", type_guid),
+                "0fc63daf-8483-4772-8e79-3d69d8477de4"
+            );
+        }
+        kind => panic!("not a GPT partition: {:?}", kind),
+    }
+}
+
+#[test_case]
+fn hostile_gpt_entries() {
+    let mut image = gpt_image();
+    for (index, start, last) in [(0, 0, u64::MAX), (1, u64::MAX - 1, u64::MAX)] {
+        let entry = 2 * 512 + index * 128;
+        put(&mut image, entry, &[1; 16]);
+        put(&mut image, entry + 32, &start.to_le_bytes());
+        put(&mut image, entry + 40, &last.to_le_bytes());
+    }
+
+    // The first one has 2^64 blocks, the second one ends past 2^64
+    let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::from_bytes(512, image));
+    let table = partitions::read_table(&*disk).unwrap();
+    assert_eq!(table.len(), 1);
+    assert_eq!(
+        alloc::format!("{}", table[0]),
+        alloc::format!(
+            "#2 2 blocks at {} type 01010101-0101-0101-0101-010101010101 \"\"",
+            u64::MAX - 1
+        )
+    );
+
+    let name = block::register("gpt", disk.clone());
+    assert!(partitions::scan(&name, &disk).unwrap().is_empty());
+}
+
+#[test_case]
+fn unpartitioned_disk() {
+    let disk = RamDisk::new(512, 4);
+    assert!(partitions::read_table(&disk).unwrap().is_empty());
+}
+
+/// A RAM disk that counts the requests that reach it.
+struct CountingDisk {
+    disk: RamDisk,
+    reads: AtomicUsize,
+    writes: AtomicUsize,
+}
+
+impl CountingDisk {
+    fn new(num_blocks: u64) -> Arc<CountingDisk> {
+        Arc::new(CountingDisk {
+            disk: RamDisk::new(512, num_blocks),
+            reads: AtomicUsize::new(0),
+            writes: AtomicUsize::new(0),
+        })
+    }
+
+    fn reads(&self) -> usize {
+        self.reads.load(Ordering::Relaxed)
+    }
+
+    fn writes(&self) -> usize {
+        self.writes.load(Ordering::Relaxed)
+    }
+}
+
+impl BlockDevice for CountingDisk {
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.disk.num_blocks()
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.disk.read_blocks(start, buf)
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.disk.write_blocks(start, buf)
+    }
+}
+
+#[test_case]
+fn cache_serves_reads_from_memory() {
+    let disk = CountingDisk::new(8);
+    disk.disk.write_blocks(3, &[7; 512]).unwrap();
+    let cache = BlockCache::new(disk.clone(), 8);
+
+    let mut buf = [0; 2048];
+    cache.read_blocks(0, &mut buf).unwrap();
+    assert_eq!(disk.reads(), 1);
+    assert!(buf[1536..].iter().all(|&byte| byte == 7));
+
+    cache.read_blocks(3, &mut buf[..512]).unwrap();
+    assert_eq!(disk.reads(), 1);
+    assert!(buf[..512].iter().all(|&byte| byte == 7));
+
+    // Only blocks 4 and 5 are missing, and read at once
+    cache.read_blocks(2, &mut buf).unwrap();
+    assert_eq!(disk.reads(), 2);
+
+    assert_eq!(
+        cache.read_blocks(7, &mut buf[..1024]),
+        Err(BlockError::OutOfRange)
+    );
+}
+
+#[test_case]
+fn cache_writes_back_on_sync() {
+    let disk = CountingDisk::new(8);
+    let cache = BlockCache::new(disk.clone(), 8);
+
+    cache.write_blocks(1, &[1; 1024]).unwrap();
+    cache.write_blocks(5, &[5; 512]).unwrap();
+    assert_eq!(disk.writes(), 0);
+    assert_eq!(cache.dirty_blocks(), 3);
+
+    // Reads see the writes before they reach the disk
+    let mut buf = [0; 512];
+    cache.read_blocks(2, &mut buf).unwrap();
+    assert!(buf.iter().all(|&byte| byte == 1));
+    disk.disk.read_blocks(2, &mut buf).unwrap();
+    assert!(buf.iter().all(|&byte| byte == 0));
+
+    // Blocks 1 and 2 are written together
+    cache.sync().unwrap();
+    assert_eq!(disk.writes(), 2);
+    assert_eq!(cache.dirty_blocks(), 0);
+    disk.disk.read_blocks(5, &mut buf).unwrap();
+    assert!(buf.iter().all(|&byte| byte == 5));
+
+    cache.sync().unwrap();
+    assert_eq!(disk.writes(), 2);
+}
+
+#[test_case]
+fn cache_evicts_least_recently_used() {
+    let disk = CountingDisk::new(8);
+    let cache = BlockCache::new(disk.clone(), 2);
+
+    let mut buf = [0; 512];
+    cache.write_blocks(0, &[1; 512]).unwrap();
+    cache.write_blocks(1, &[2; 512]).unwrap();
+    cache.read_blocks(0, &mut buf).unwrap();
+
+    // Block 1 makes room, and is written back as it goes
+    cache.write_blocks(2, &[3; 512]).unwrap();
+    assert_eq!(disk.writes(), 1);
+    disk.disk.read_blocks(1, &mut buf).unwrap();
+    assert!(buf.iter().all(|&byte| byte == 2));
+
+    cache.read_blocks(1, &mut buf).unwrap();
+    assert_eq!(disk.reads(), 1);
+    assert!(buf.iter().all(|&byte| byte == 2));
+}
+
+/// A RAM disk that acts like a DMA device: requests only finish when
+/// `interrupt` is called.
+struct IrqDisk {
+    disk: RamDisk,
+    pending: Mutex<Vec<Arc<Completion>>>,
+}
+
+impl IrqDisk {
+    fn new(num_blocks: u64) -> Arc<IrqDisk> {
+        Arc::new(IrqDisk {
+            disk: RamDisk::new(512, num_blocks),
+            pending: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Finishes all requests, like the interrupt handler of a driver would.
+    fn interrupt(&self, result: Result<(), BlockError>) {
+        for completion in self.pending.lock().drain(..) {
+            completion.complete(result);
+        }
+    }
+
+    fn start(&self) -> Arc<Completion> {
+        let completion = Arc::new(Completion::new());
+        self.pending.lock().push(completion.clone());
+        completion
+    }
+}
+
+impl BlockDevice for IrqDisk {
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.disk.num_blocks()
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.disk.read_blocks(start, buf)
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.disk.write_blocks(start, buf)
+    }
+
+    fn read_blocks_async<'a>(&'a self, start: u64, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        let completion = self.start();
+        Box::pin(async move {
+            completion.wait().await?;
+            // The data would be in `buf` already, if there was a real device
+            self.disk.read_blocks(start, buf)
+        })
+    }
+}
+
+/// Counts how often it was woken.
+struct CountingWaker(AtomicUsize);
+
+impl Wake for CountingWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn counting_waker() -> (Arc<CountingWaker>, Waker) {
+    let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+    (counter.clone(), Waker::from(counter))
+}
+
+#[test_case]
+fn async_reads_wait_for_the_interrupt() {
+    let disk = IrqDisk::new(8);
+    disk.disk.write_blocks(1, &[9; 512]).unwrap();
+    let (counter, waker) = counting_waker();
+    let mut context = Context::from_waker(&waker);
+
+    let mut buf = [0; 512];
+    let mut read = disk.read_blocks_async(1, &mut buf);
+    assert!(read.as_mut().poll(&mut context).is_pending());
+    assert_eq!(counter.0.load(Ordering::Relaxed), 0);
+
+    disk.interrupt(Ok(()));
+    assert_eq!(counter.0.load(Ordering::Relaxed), 1);
+    assert_eq!(read.as_mut().poll(&mut context), Poll::Ready(Ok(())));
+    drop(read);
+    assert!(buf.iter().all(|&byte| byte == 9));
+
+    let mut read = disk.read_blocks_async(1, &mut buf);
+    assert!(read.as_mut().poll(&mut context).is_pending());
+    disk.interrupt(Err(BlockError::Io));
+    assert_eq!(
+        read.as_mut().poll(&mut context),
+        Poll::Ready(Err(BlockError::Io))
+    );
+}
+
+#[test_case]
+fn async_reads_through_the_cache() {
+    let disk = IrqDisk::new(8);
+    let cache = BlockCache::new(disk.clone(), 8);
+    let (_counter, waker) = counting_waker();
+    let mut context = Context::from_waker(&waker);
+
+    let mut buf = [0; 1024];
+    let mut read = cache.read_blocks_async(2, &mut buf);
+    assert!(read.as_mut().poll(&mut context).is_pending());
+    disk.interrupt(Ok(()));
+    assert_eq!(read.as_mut().poll(&mut context), Poll::Ready(Ok(())));
+    drop(read);
+
+    // Cached blocks don't go to the device at all
+    let mut read = cache.read_blocks_async(3, &mut buf[..512]);
+    assert_eq!(read.as_mut().poll(&mut context), Poll::Ready(Ok(())));
+}
+
+/// Finishes a request when it's woken, which `TickDisk` has the timer
+/// interrupt do.
+struct CompleteOnWake(Arc<Completion>);
+
+impl Wake for CompleteOnWake {
+    fn wake(self: Arc<Self>) {
+        self.0.complete(Ok(()));
+    }
+}
+
+/// A RAM disk whose synchronous requests wait for an interrupt, the next
+/// timer tick, to finish them, like those of a DMA device.
+struct TickDisk {
+    disk: RamDisk,
+}
+
+impl TickDisk {
+    fn wait_for_interrupt(&self) -> Result<(), BlockError> {
+        // It would never come
+        assert!(
+            interrupts::are_enabled(),
+            "waiting for an interrupt with interrupts disabled"
+        );
+
+        let completion = Arc::new(Completion::new());
+        let waker = Waker::from(Arc::new(CompleteOnWake(completion.clone())));
+        let mut tick = Box::pin(time::sleep(1));
+        if tick
+            .as_mut()
+            .poll(&mut Context::from_waker(&waker))
+            .is_ready()
+        {
+            completion.complete(Ok(()));
+        }
+        completion.wait_blocking()
+    }
+}
+
+impl BlockDevice for TickDisk {
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.disk.num_blocks()
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.wait_for_interrupt()?;
+        self.disk.read_blocks(start, buf)
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.wait_for_interrupt()?;
+        self.disk.write_blocks(start, buf)
+    }
+}
+
+#[test_case]
+fn cache_lets_the_device_interrupt() {
+    let disk = Arc::new(TickDisk {
+        disk: RamDisk::new(512, 8),
+    });
+    disk.disk.write_blocks(3, &[7; 512]).unwrap();
+    let cache = BlockCache::new(disk.clone(), 2);
+
+    let mut buf = [0; 512];
+    cache.read_blocks(3, &mut buf).unwrap();
+    assert!(buf.iter().all(|&byte| byte == 7));
+
+    // Writing block 2 evicts block 0, which is written back, and `sync`
+    // writes back the others
+    for block in 0..3 {
+        cache.write_blocks(block, &[block as u8 + 1; 512]).unwrap();
+    }
+    disk.disk.read_blocks(0, &mut buf).unwrap();
+    assert!(buf.iter().all(|&byte| byte == 1));
+    cache.sync().unwrap();
+    disk.disk.read_blocks(2, &mut buf).unwrap();
+    assert!(buf.iter().all(|&byte| byte == 3));
+}
+
+#[test_case]
+fn sync_devices_are_ready_right_away() {
+    let disk = RamDisk::new(512, 4);
+    let (_counter, waker) = counting_waker();
+    let mut context = Context::from_waker(&waker);
+
+    let mut write = disk.write_blocks_async(0, &[3; 512]);
+    assert_eq!(write.as_mut().poll(&mut context), Poll::Ready(Ok(())));
+
+    let mut buf = [0; 512];
+    let mut read = disk.read_blocks_async(4, &mut buf);
+    assert_eq!(
+        read.as_mut().poll(&mut context),
+        Poll::Ready(Err(BlockError::OutOfRange))
+    );
+}
+
+#[test_case]
+fn completions_can_be_waited_for_outside_tasks() {
+    let completion = Completion::new();
+    assert_eq!(completion.result(), None);
+
+    completion.complete(Err(BlockError::ReadOnly));
+    assert_eq!(completion.wait_blocking(), Err(BlockError::ReadOnly));
+}
+
+#[test_case]
+fn shutting_down_writes_back_caches() {
+    let disk = CountingDisk::new(8);
+    let cache = Arc::new(BlockCache::new(disk.clone(), 8));
+    block::register("cached", cache.clone());
+
+    cache.write_blocks(3, &[3; 512]).unwrap();
+    assert_eq!(cache.dirty_blocks(), 1);
+
+    device::shutdown_all().unwrap();
+    assert_eq!(cache.dirty_blocks(), 0);
+    assert_eq!(disk.writes(), 1);
+}
@@ -0,0 +1,90 @@
+// Boots the way the kernel does, in the same order, and then does a bit of
+// everything at once: several tasks on one executor, one reading typed input
+// and one sleeping on the timer. The other tests set up just what they need,
+// so this is the one that notices when a subsystem starts depending on one
+// that's initialized after it.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{rc::Rc, string::String, vec::Vec};
+use core::cell::RefCell;
+use futures_util::stream::StreamExt;
+use rust_os_playground::task::{executor::Executor, keyboard, Task};
+use rust_os_playground::{allocator, initrd, time};
+
+rust_os_playground::kernel_test_main!(memory, acpi, hypervisor, process, pci, drivers, fs => {
+    initrd::init().expect("can't mount the initrd");
+});
+
+/// How long the tasks get to finish.
+const DEADLINE_TICKS: u64 = 5 * time::TIMER_HZ;
+
+// "ok" and enter, in scancode set 1
+const TYPED: [u8; 6] = [0x18, 0x98, 0x25, 0xA5, 0x1C, 0x9C];
+
+#[test_case]
+fn tasks_sleep_and_read_input() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let line = Rc::new(RefCell::new(String::new()));
+    let mut executor = Executor::new();
+
+    let typed = line.clone();
+    let reader_log = log.clone();
+    executor.spawn(Task::new(async move {
+        let mut characters = keyboard::characters();
+        while let Some(character) = characters.next().await {
+            if character == '\n' {
+                break;
+            }
+            typed.borrow_mut().push(character);
+        }
+        reader_log.borrow_mut().push("read");
+    }));
+
+    let sleeper_log = log.clone();
+    executor.spawn(Task::new(async move {
+        let start = time::ticks();
+        time::sleep(2).await;
+        assert!(time::ticks() >= start + 2, "woke up early");
+        sleeper_log.borrow_mut().push("slept");
+    }));
+
+    for name in ["first", "second", "third"] {
+        let log = log.clone();
+        executor.spawn(Task::new(async move {
+            let numbers: Vec<u64> = (1..=10).collect();
+            assert_eq!(numbers.iter().sum::<u64>(), 55);
+            log.borrow_mut().push(name);
+        }));
+    }
+
+    executor.run_until_idle();
+    assert_eq!(*log.borrow(), ["first", "second", "third"]);
+
+    for &scancode in TYPED.iter() {
+        keyboard::add_scancode(scancode);
+    }
+
+    let deadline = time::ticks() + DEADLINE_TICKS;
+    while log.borrow().len() < 5 {
+        assert!(
+            time::ticks() < deadline,
+            "tasks didn't finish: {:?}",
+            log.borrow()
+        );
+        executor.run_until_idle();
+        x86_64::instructions::hlt();
+    }
+
+    let mut finished = log.borrow().clone();
+    finished.sort_unstable();
+    assert_eq!(finished, ["first", "read", "second", "slept", "third"]);
+    assert_eq!(*line.borrow(), "ok");
+    assert_eq!(allocator::check(), Ok(()));
+}
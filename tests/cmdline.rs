@@ -0,0 +1,47 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use rust_os_playground::cmdline;
+
+rust_os_playground::kernel_test_main!();
+
+#[test_case]
+fn splits_options() {
+    cmdline::set("  log=debug quiet  title=\"a b\" heap=4M log=trace");
+    let mut options = cmdline::options();
+    assert_eq!(options.next(), Some(("log", Some("debug"))));
+    assert_eq!(options.next(), Some(("quiet", None)));
+    assert_eq!(options.next(), Some(("title", Some("a b"))));
+    assert_eq!(options.next(), Some(("heap", Some("4M"))));
+    assert_eq!(options.next(), Some(("log", Some("trace"))));
+    assert_eq!(options.next(), None);
+    cmdline::set(cmdline::BUILT_IN);
+}
+
+#[test_case]
+fn looks_up_options() {
+    cmdline::set("log=debug quiet verbose=off heap=64K log=trace retries=3");
+    assert_eq!(cmdline::value("log"), Some("trace"));
+    assert_eq!(cmdline::value("quiet"), None);
+    assert_eq!(cmdline::value("missing"), None);
+    assert!(cmdline::flag("quiet"));
+    assert!(!cmdline::flag("verbose"));
+    assert!(!cmdline::flag("missing"));
+    assert_eq!(cmdline::parse::<u32>("retries"), Some(3));
+    assert_eq!(cmdline::parse::<u32>("log"), None);
+    assert_eq!(cmdline::size("heap"), Some(64 * 1024));
+    cmdline::set(cmdline::BUILT_IN);
+}
+
+#[test_case]
+fn parses_sizes() {
+    assert_eq!(cmdline::parse_size("4096"), Some(4096));
+    assert_eq!(cmdline::parse_size("2k"), Some(2048));
+    assert_eq!(cmdline::parse_size("3M"), Some(3 << 20));
+    assert_eq!(cmdline::parse_size("1G"), Some(1 << 30));
+    assert_eq!(cmdline::parse_size("M"), None);
+    assert_eq!(cmdline::parse_size(""), None);
+}
@@ -0,0 +1,189 @@
+// Helpers for the integration tests that run user processes or futures, and
+// the fake network in `net`. Each test binary only uses some of them.
+#![allow(dead_code)]
+
+pub mod net;
+
+use alloc::{boxed::Box, vec::Vec};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::task;
+use rust_os_playground::elf::{PF_R, PF_X, PT_LOAD};
+use rust_os_playground::process::{self, Pid};
+use rust_os_playground::time::{self, TIMER_HZ};
+
+const HEADERS_SIZE: usize = 64 + 56;
+
+/// Where `executable` puts the data, relative to the load address.
+pub const DATA_OFFSET: u64 = 0x400;
+
+/// Builds an executable with a single read/execute segment at `vaddr`. The
+/// segment holds the headers followed by `code`, which is also the entry point,
+/// and `data` at `vaddr + DATA_OFFSET`.
+pub fn executable(vaddr: u64, code: &[u8], data: &[u8]) -> Vec<u8> {
+    assert!(
+        HEADERS_SIZE + code.len() <= DATA_OFFSET as usize,
+        "code too long"
+    );
+
+    let size = DATA_OFFSET + data.len() as u64;
+    let mut elf = Vec::new();
+
+    // File header
+    elf.extend_from_slice(b"\x7fELF\x02\x01\x01\x00");
+    elf.extend_from_slice(&[0; 8]);
+    elf.extend_from_slice(&2u16.to_le_bytes()); // executable
+    elf.extend_from_slice(&0x3Eu16.to_le_bytes()); // x86_64
+    elf.extend_from_slice(&1u32.to_le_bytes());
+    elf.extend_from_slice(&(vaddr + HEADERS_SIZE as u64).to_le_bytes()); // entry
+    elf.extend_from_slice(&64u64.to_le_bytes()); // program headers
+    elf.extend_from_slice(&0u64.to_le_bytes()); // section headers
+    elf.extend_from_slice(&0u32.to_le_bytes());
+    elf.extend_from_slice(&64u16.to_le_bytes());
+    elf.extend_from_slice(&56u16.to_le_bytes());
+    elf.extend_from_slice(&1u16.to_le_bytes());
+    elf.extend_from_slice(&[0; 6]);
+
+    // Program header
+    elf.extend_from_slice(&PT_LOAD.to_le_bytes());
+    elf.extend_from_slice(&(PF_R | PF_X).to_le_bytes());
+    elf.extend_from_slice(&0u64.to_le_bytes()); // offset
+    elf.extend_from_slice(&vaddr.to_le_bytes());
+    elf.extend_from_slice(&vaddr.to_le_bytes());
+    elf.extend_from_slice(&size.to_le_bytes());
+    elf.extend_from_slice(&size.to_le_bytes());
+    elf.extend_from_slice(&4096u64.to_le_bytes());
+
+    elf.extend_from_slice(code);
+    elf.resize(DATA_OFFSET as usize, 0);
+    elf.extend_from_slice(data);
+    elf
+}
+
+#[derive(Clone, Copy)]
+pub enum Reg {
+    Rax,
+    Rdx,
+    Rsi,
+    Rdi,
+    R8,
+    R10,
+}
+
+/// Just enough of an x86_64 assembler to write test programs that make system calls.
+pub struct Asm {
+    code: Vec<u8>,
+}
+
+impl Asm {
+    pub fn new() -> Self {
+        Asm { code: Vec::new() }
+    }
+
+    /// `mov reg, imm64`
+    pub fn mov(mut self, reg: Reg, value: u64) -> Self {
+        let (rex, number) = match reg {
+            Reg::Rax => (0x48, 0),
+            Reg::Rdx => (0x48, 2),
+            Reg::Rsi => (0x48, 6),
+            Reg::Rdi => (0x48, 7),
+            Reg::R8 => (0x49, 0),
+            Reg::R10 => (0x49, 2),
+        };
+        self.code.extend_from_slice(&[rex, 0xB8 + number]);
+        self.code.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    /// `mov rdi, rax`
+    pub fn mov_rdi_rax(mut self) -> Self {
+        self.code.extend_from_slice(&[0x48, 0x89, 0xC7]);
+        self
+    }
+
+    /// `push rax`
+    pub fn push_rax(mut self) -> Self {
+        self.code.push(0x50);
+        self
+    }
+
+    /// `pop rdi`
+    pub fn pop_rdi(mut self) -> Self {
+        self.code.push(0x5F);
+        self
+    }
+
+    /// `mov rsi, rsp`
+    pub fn mov_rsi_rsp(mut self) -> Self {
+        self.code.extend_from_slice(&[0x48, 0x89, 0xE6]);
+        self
+    }
+
+    /// `movzx rdi, byte ptr [rax + offset]`
+    pub fn load_byte_rdi_from_rax(mut self, offset: u32) -> Self {
+        self.code.extend_from_slice(&[0x48, 0x0F, 0xB6, 0xB8]);
+        self.code.extend_from_slice(&offset.to_le_bytes());
+        self
+    }
+
+    /// `mov byte ptr [rax + offset], value`
+    pub fn store_byte_to_rax(mut self, offset: u32, value: u8) -> Self {
+        self.code.extend_from_slice(&[0xC6, 0x80]);
+        self.code.extend_from_slice(&offset.to_le_bytes());
+        self.code.push(value);
+        self
+    }
+
+    pub fn syscall(mut self) -> Self {
+        self.code.extend_from_slice(&[0x0F, 0x05]);
+        self
+    }
+
+    /// `jmp $`
+    pub fn spin(mut self) -> Vec<u8> {
+        self.code.extend_from_slice(&[0xEB, 0xFE]);
+        self.code
+    }
+}
+
+/// Lets the process run until it exits and returns its exit code.
+pub fn run_to_exit(pid: Pid) -> i64 {
+    process::wait(pid).expect("can't wait for process")
+}
+
+/// Runs a future that doesn't wait for anything.
+pub fn run<T>(future: impl Future<Output = T>) -> T {
+    let mut context = Context::from_waker(task::noop_waker_ref());
+    match Box::pin(future).as_mut().poll(&mut context) {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("future is waiting"),
+    }
+}
+
+pub type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+/// Runs `future`, and `tasks` meanwhile, sleeping until the next interrupt
+/// between polls. Panics if it takes longer than a few seconds.
+pub fn drive<T>(tasks: &mut [Task], future: impl Future<Output = T>) -> T {
+    // Nothing needs waking, since everything is polled after every interrupt
+    // anyway.
+    let mut context = Context::from_waker(task::noop_waker_ref());
+    let mut future = Box::pin(future);
+    let deadline = time::ticks() + 5 * TIMER_HZ;
+    loop {
+        for task in tasks.iter_mut() {
+            let _ = task.as_mut().poll(&mut context);
+        }
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        assert!(time::ticks() < deadline, "timed out");
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Runs a future that waits for interrupts, see `drive`.
+pub fn block_on<T>(future: impl Future<Output = T>) -> T {
+    drive(&mut [], future)
+}
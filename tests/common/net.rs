@@ -0,0 +1,106 @@
+// A fake network for the networking tests: a `Wire` device that keeps what's
+// sent through it, and frames from a peer on the other end.
+
+use super::run;
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use futures_util::future;
+use rust_os_playground::net::arp::{self, Packet, OPERATION_REQUEST};
+use rust_os_playground::net::eth::{self, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use rust_os_playground::net::ipv4::{self, Config};
+use rust_os_playground::net::{
+    self, Interface, Ipv4Address, MacAddress, NetDevice, NetError, NetFuture, PacketBuf,
+};
+use spin::Mutex;
+
+pub const OUR_MAC: MacAddress = MacAddress([2, 0, 0, 0, 0, 1]);
+pub const OUR_IP: Ipv4Address = Ipv4Address::new(192, 168, 7, 15);
+pub const PEER_MAC: MacAddress = MacAddress([2, 0, 0, 0, 0, 2]);
+pub const PEER_IP: Ipv4Address = Ipv4Address::new(192, 168, 7, 2);
+
+/// A device that keeps the frames sent through it.
+pub struct Wire {
+    pub sent: Mutex<Vec<PacketBuf>>,
+}
+
+impl NetDevice for Wire {
+    fn mac_address(&self) -> MacAddress {
+        OUR_MAC
+    }
+
+    fn mtu(&self) -> usize {
+        1500
+    }
+
+    fn send(&self, frame: PacketBuf) -> NetFuture<'_, Result<(), NetError>> {
+        self.sent.lock().push(frame);
+        Box::pin(future::ready(Ok(())))
+    }
+
+    fn receive(&self) -> NetFuture<'_, PacketBuf> {
+        Box::pin(future::pending())
+    }
+}
+
+/// Registers a new wire on 192.168.7.0/24. It's the only configured
+/// interface, so that packets are routed through it.
+pub fn wire() -> (Arc<Wire>, Arc<Interface>) {
+    for interface in net::interfaces() {
+        interface.set_ipv4_config(None);
+    }
+
+    let wire = Arc::new(Wire {
+        sent: Mutex::new(Vec::new()),
+    });
+    let name = net::register("wire", wire.clone());
+    let interface = net::interface(&name).unwrap();
+    interface.set_ipv4_config(Some(Config {
+        address: OUR_IP,
+        prefix_len: 24,
+        gateway: None,
+    }));
+    (wire, interface)
+}
+
+/// Like `wire`, but the interface knows the peer's MAC address already, from
+/// an ARP request the peer sent.
+pub fn wire_to_peer() -> (Arc<Wire>, Arc<Interface>) {
+    let (wire, interface) = wire();
+
+    let request = Packet {
+        operation: OPERATION_REQUEST,
+        sender_mac: PEER_MAC,
+        sender_ip: PEER_IP,
+        target_mac: MacAddress::default(),
+        target_ip: OUR_IP,
+    };
+    let mut frame = PacketBuf::new();
+    request.write(frame.push(arp::PACKET_LEN));
+    run(eth::handle(&interface, ethernet(frame, ETHERTYPE_ARP)));
+    wire.sent.lock().clear();
+
+    (wire, interface)
+}
+
+/// Puts an Ethernet header from the peer to us in front of `packet`.
+pub fn ethernet(mut packet: PacketBuf, ethertype: u16) -> PacketBuf {
+    let header = eth::Header {
+        destination: OUR_MAC,
+        source: PEER_MAC,
+        ethertype,
+    };
+    header.push(&mut packet);
+    packet
+}
+
+/// An IPv4 packet from the peer to us.
+pub fn from_peer(protocol: u8, mut packet: PacketBuf) -> PacketBuf {
+    let header = ipv4::Header {
+        source: PEER_IP,
+        destination: OUR_IP,
+        protocol,
+        ttl: 64,
+        identification: 1,
+    };
+    header.push(&mut packet);
+    ethernet(packet, ETHERTYPE_IPV4)
+}
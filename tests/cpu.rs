@@ -0,0 +1,59 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use rust_os_playground::acpi;
+use rust_os_playground::cpu::{self, IdLayout};
+
+rust_os_playground::kernel_test_main!(heap, acpi);
+
+#[test_case]
+fn finds_the_boot_cpu() {
+    let topology = cpu::topology();
+    let boot: vec::Vec<_> = topology.cpus.iter().filter(|cpu| cpu.bootstrap).collect();
+    assert_eq!(boot.len(), 1);
+    assert_eq!(boot[0].apic_id, u32::from(cpu::apic_id()));
+    assert!(boot[0].enabled);
+    assert!(topology.packages() >= 1);
+    assert!(topology.cores() >= topology.packages());
+    assert!(topology.threads() >= topology.cores());
+}
+
+#[test_case]
+fn boot_cpu_is_index_0() {
+    assert_eq!(cpu::index(), 0);
+}
+
+#[test_case]
+fn splits_apic_ids() {
+    // Two threads per core, eight cores per package
+    let layout = IdLayout {
+        core_shift: 1,
+        package_shift: 4,
+    };
+    assert_eq!(layout.split(0), (0, 0, 0));
+    assert_eq!(layout.split(0b1_011_1), (1, 3, 1));
+    assert_eq!(layout.split(0b10_000_0), (2, 0, 0));
+}
+
+#[test_case]
+fn reads_the_madt() {
+    let mut madt = vec![0; acpi::HEADER_LEN + 8];
+    // A local APIC (ID 0, enabled), one that's online capable (ID 1), one
+    // that's unusable (ID 2), something else, and an x2APIC (ID 300)
+    madt.extend_from_slice(&[0, 8, 0, 0, 1, 0, 0, 0]);
+    madt.extend_from_slice(&[0, 8, 1, 1, 2, 0, 0, 0]);
+    madt.extend_from_slice(&[0, 8, 2, 2, 0, 0, 0, 0]);
+    madt.extend_from_slice(&[1, 12, 0, 0, 0, 0, 0xC0, 0xFE, 0, 0, 0, 0]);
+    madt.extend_from_slice(&[9, 16, 0, 0, 44, 1, 0, 0, 1, 0, 0, 0, 3, 0, 0, 0]);
+
+    assert_eq!(
+        cpu::madt_apic_ids(&madt),
+        vec![(0, true), (1, false), (300, true)]
+    );
+}
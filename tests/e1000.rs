@@ -0,0 +1,105 @@
+// Tests the e1000 driver against the card QEMU emulates, which Cargo.toml
+// connects to QEMU's user mode network. That network has a virtual gateway at
+// 10.0.2.2 that answers ARP requests, so frames can make a round trip without
+// any network stack.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::vec::Vec;
+use common::block_on;
+use rust_os_playground::drivers::e1000::{self, SendError, MAX_FRAME_SIZE};
+use rust_os_playground::net::{self, MacAddress, NetDevice, PacketBuf};
+
+// The addresses QEMU's user mode network gives to the guest and the gateway
+const GUEST_IP: [u8; 4] = [10, 0, 2, 15];
+const GATEWAY_IP: [u8; 4] = [10, 0, 2, 2];
+const ETHERTYPE_ARP: [u8; 2] = [0x08, 0x06];
+
+rust_os_playground::kernel_test_main!(memory, pci, drivers);
+
+fn arp_request(mac: MacAddress) -> Vec<u8> {
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&MacAddress::BROADCAST.0);
+    frame.extend_from_slice(&mac.0);
+    frame.extend_from_slice(&ETHERTYPE_ARP);
+
+    frame.extend_from_slice(&[0, 1, 0x08, 0x00, 6, 4, 0, 1]); // Ethernet/IPv4, request
+    frame.extend_from_slice(&mac.0);
+    frame.extend_from_slice(&GUEST_IP);
+    frame.extend_from_slice(&[0; 6]);
+    frame.extend_from_slice(&GATEWAY_IP);
+    frame
+}
+
+#[test_case]
+fn finds_the_card() {
+    let card = e1000::device().expect("no e1000");
+
+    // QEMU's default MAC address
+    let mac = MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+    assert_eq!(card.mac_address(), mac);
+    assert!(card.link_up());
+    assert_eq!(card.mtu(), 1500);
+
+    let eth0 = net::get("eth0").expect("e1000 not registered");
+    assert_eq!(eth0.mac_address(), mac);
+}
+
+#[test_case]
+fn rejects_oversized_frames() {
+    let card = e1000::device().unwrap();
+    let frame = [0; MAX_FRAME_SIZE + 1];
+    assert_eq!(card.transmit(&frame), Err(SendError::TooLarge));
+}
+
+#[test_case]
+fn transmits_frames() {
+    let card = e1000::device().unwrap();
+    let frame = arp_request(card.mac_address());
+
+    let first = card.transmit(&frame).unwrap();
+    let second = card.transmit(&frame).unwrap();
+    assert_eq!(second, first + 1);
+
+    block_on(card.transmitted(second));
+    block_on(card.transmitted(first));
+    let result = block_on(card.send_frame(&frame));
+    assert_eq!(result, Ok(()));
+    assert!(card.interrupts() > 0);
+}
+
+#[test_case]
+fn receives_the_gateways_arp_reply() {
+    let card = e1000::device().unwrap();
+    let mac = card.mac_address();
+
+    // Drop whatever earlier tests got back
+    while card.try_receive().is_some() {}
+
+    // Through the network device interface this time
+    let request = PacketBuf::from_slice(&arp_request(mac));
+    let result = block_on(card.send(request));
+    assert_eq!(result, Ok(()));
+
+    let reply = block_on(async {
+        loop {
+            let frame = card.receive().await;
+            if frame.len() >= 42 && frame[12..14] == ETHERTYPE_ARP && frame[28..32] == GATEWAY_IP {
+                return frame;
+            }
+        }
+    });
+
+    assert_eq!(reply[..6], mac.0);
+    assert_eq!(reply[20..22], [0, 2]); // reply
+    assert_eq!(reply[32..38], mac.0);
+    assert_eq!(reply[38..42], GUEST_IP);
+}
@@ -0,0 +1,89 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::rc::Rc;
+use core::cell::{Cell, RefCell};
+use core::future;
+use core::task::{Poll, Waker};
+use rust_os_playground::task::{executor, executor::Executor, Task};
+
+rust_os_playground::kernel_test_main!(heap);
+
+/// A task that waits until `go` is set, leaving its waker in `waker`, and
+/// counts its polls.
+fn waiting_task(
+    go: Rc<Cell<bool>>,
+    waker: Rc<RefCell<Option<Waker>>>,
+    polls: Rc<Cell<u32>>,
+) -> Task {
+    Task::new(future::poll_fn(move |cx| {
+        polls.set(polls.get() + 1);
+        if go.get() {
+            return Poll::Ready(());
+        }
+        *waker.borrow_mut() = Some(cx.waker().clone());
+        Poll::Pending
+    }))
+}
+
+#[test_case]
+fn moved_task_is_woken_in_its_new_executor() {
+    let go = Rc::new(Cell::new(false));
+    let waker = Rc::new(RefCell::new(None));
+    let polls = Rc::new(Cell::new(0));
+
+    let mut old = Executor::new();
+    let task = waiting_task(go.clone(), waker.clone(), polls.clone());
+    old.spawn(task);
+    old.run_until_idle();
+    assert_eq!(polls.get(), 1);
+    let stale = waker.borrow_mut().take().unwrap();
+
+    let detached = old.detach_all();
+    assert_eq!(detached.len(), 1);
+    let mut new = Executor::new();
+    for task in detached {
+        new.attach(task);
+    }
+
+    // Attaching polls it once, in case a wake got lost on the way
+    new.run_until_idle();
+    assert_eq!(polls.get(), 2);
+
+    // The waker it handed out before the move wakes it in the new executor
+    go.set(true);
+    stale.wake();
+    old.run_until_idle();
+    assert_eq!(polls.get(), 2);
+    new.run_until_idle();
+    assert_eq!(polls.get(), 3);
+}
+
+#[test_case]
+fn detaching_unknown_task_fails() {
+    let mut executor = Executor::new();
+    assert!(executor.detach(u64::MAX).is_none());
+    assert!(executor.detach_all().is_empty());
+}
+
+#[test_case]
+fn dropped_detached_task_is_gone() {
+    let mut executor = Executor::new();
+    let count = executor::task_count();
+    executor.spawn(Task::new(future::pending()));
+    assert_eq!(executor::task_count(), count + 1);
+
+    let detached = executor.detach_all().pop().unwrap();
+    let id = detached.id();
+    assert!(executor::tasks().iter().any(|task| task.id == id));
+    drop(detached);
+
+    assert!(!executor::tasks().iter().any(|task| task.id == id));
+    assert_eq!(executor::task_count(), count);
+    assert!(!executor::kill(id));
+}
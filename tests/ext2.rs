@@ -0,0 +1,270 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use rust_os_playground::block::{BlockDevice, RamDisk};
+use rust_os_playground::fs::{self, ext2::Ext2Fs, FileSystem, FileType, FsError};
+
+rust_os_playground::kernel_test_main!(heap);
+
+// A small ext2 volume, laid out like mke2fs would: 1 KiB blocks, a single
+// group, the group descriptors in block 2 and 16 inodes of 128 bytes in blocks
+// 5 and 6. Blocks 3 and 4 would be the bitmaps, which we don't read.
+const BLOCK: usize = 1024;
+const NUM_BLOCKS: usize = 64;
+const NUM_INODES: u32 = 16;
+const INODE_TABLE: usize = 5;
+const INODE_SIZE: usize = 128;
+
+const ROOT: u32 = 2;
+const HELLO: u32 = 12;
+const DOCS: u32 = 13;
+const BIG: u32 = 14;
+const SPARSE: u32 = 15;
+const LINK: u32 = 16;
+
+const MODE_DIRECTORY: u16 = 0x41ED;
+const MODE_FILE: u16 = 0x81A4;
+const MODE_SYMLINK: u16 = 0xA1FF;
+
+const TYPE_FILE: u8 = 1;
+const TYPE_DIRECTORY: u8 = 2;
+const TYPE_SYMLINK: u8 = 7;
+
+struct Image(Vec<u8>);
+
+impl Image {
+    fn new() -> Image {
+        let mut image = Image(vec![0; NUM_BLOCKS * BLOCK]);
+
+        let superblock = 1024;
+        image.put(superblock, &NUM_INODES.to_le_bytes());
+        image.put(superblock + 4, &(NUM_BLOCKS as u32).to_le_bytes());
+        image.put(superblock + 20, &1u32.to_le_bytes());
+        image.put(superblock + 32, &8192u32.to_le_bytes());
+        image.put(superblock + 40, &NUM_INODES.to_le_bytes());
+        image.put(superblock + 56, &0xEF53u16.to_le_bytes());
+        image.put(superblock + 76, &1u32.to_le_bytes());
+        image.put(superblock + 88, &(INODE_SIZE as u16).to_le_bytes());
+        // The file type in directory entries
+        image.put(superblock + 96, &2u32.to_le_bytes());
+
+        image.put(2 * BLOCK + 8, &(INODE_TABLE as u32).to_le_bytes());
+        image
+    }
+
+    fn put(&mut self, offset: usize, bytes: &[u8]) {
+        self.0[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn inode(&mut self, number: u32, mode: u16, size: u32, blocks: &[(usize, u32)]) {
+        let offset = INODE_TABLE * BLOCK + (number as usize - 1) * INODE_SIZE;
+        self.put(offset, &mode.to_le_bytes());
+        self.put(offset + 4, &size.to_le_bytes());
+        for &(index, block) in blocks {
+            self.put(offset + 40 + index * 4, &block.to_le_bytes());
+        }
+    }
+
+    /// Fills a block with directory entries, the last of which takes up the
+    /// rest of the block.
+    fn directory(&mut self, block: usize, entries: &[(u32, &str, u8)]) {
+        let mut offset = block * BLOCK;
+        for (i, &(inode, name, file_type)) in entries.iter().enumerate() {
+            let len = if i + 1 == entries.len() {
+                (block + 1) * BLOCK - offset
+            } else {
+                x86_64::align_up((8 + name.len()) as u64, 4) as usize
+            };
+
+            self.put(offset, &inode.to_le_bytes());
+            self.put(offset + 4, &(len as u16).to_le_bytes());
+            self.0[offset + 6] = name.len() as u8;
+            self.0[offset + 7] = file_type;
+            self.put(offset + 8, name.as_bytes());
+            offset += len;
+        }
+    }
+}
+
+const BIG_SIZE: usize = 14 * BLOCK + 100;
+/// The block of big.bin that is a hole.
+const BIG_HOLE: usize = 5;
+/// Past the direct and indirect blocks, in the doubly indirect ones
+const SPARSE_BLOCK: usize = 12 + 256 + 1;
+
+fn big_contents() -> Vec<u8> {
+    (0..BIG_SIZE)
+        .map(|i| match i / BLOCK {
+            BIG_HOLE => 0,
+            _ => (i % 251) as u8,
+        })
+        .collect()
+}
+
+/// / holds hello.txt, a symbolic link to it, sparse, and docs/, which holds
+/// big.bin. big.bin has a hole and needs an indirect block, and sparse is
+/// empty but for a block that is doubly indirect.
+fn image() -> Image {
+    let mut image = Image::new();
+
+    image.inode(ROOT, MODE_DIRECTORY, BLOCK as u32, &[(0, 10)]);
+    image.directory(
+        10,
+        &[
+            (ROOT, ".", TYPE_DIRECTORY),
+            (ROOT, "..", TYPE_DIRECTORY),
+            (HELLO, "hello.txt", TYPE_FILE),
+            // A deleted entry
+            (0, "gone", TYPE_FILE),
+            (LINK, "link", TYPE_SYMLINK),
+            (SPARSE, "sparse", TYPE_FILE),
+            (DOCS, "docs", TYPE_DIRECTORY),
+        ],
+    );
+
+    image.inode(HELLO, MODE_FILE, 13, &[(0, 11)]);
+    image.put(11 * BLOCK, b"Hello, ext2!\n");
+    image.inode(LINK, MODE_SYMLINK, 9, &[]);
+
+    image.inode(DOCS, MODE_DIRECTORY, BLOCK as u32, &[(0, 12)]);
+    image.directory(
+        12,
+        &[
+            (DOCS, ".", TYPE_DIRECTORY),
+            (ROOT, "..", TYPE_DIRECTORY),
+            (BIG, "big.bin", TYPE_FILE),
+        ],
+    );
+
+    // Blocks 20 to 31 directly, then 33 to 35 through block 32
+    let contents = big_contents();
+    let mut blocks = Vec::new();
+    for (index, chunk) in contents.chunks(BLOCK).enumerate() {
+        let block = if index < 12 { 20 + index } else { 21 + index };
+        if index == BIG_HOLE {
+            continue;
+        }
+        image.put(block * BLOCK, chunk);
+        if index < 12 {
+            blocks.push((index, block as u32));
+        } else {
+            image.put(32 * BLOCK + (index - 12) * 4, &(block as u32).to_le_bytes());
+        }
+    }
+    blocks.push((12, 32));
+    image.inode(BIG, MODE_FILE, BIG_SIZE as u32, &blocks);
+
+    // Block 40 points to 41, whose second entry points to 42
+    let size = (SPARSE_BLOCK + 1) * BLOCK;
+    image.inode(SPARSE, MODE_FILE, size as u32, &[(13, 40)]);
+    image.put(40 * BLOCK, &41u32.to_le_bytes());
+    image.put(41 * BLOCK + 4, &42u32.to_le_bytes());
+    image.put(42 * BLOCK, b"tail");
+
+    image
+}
+
+fn volume(image: Image) -> Result<Ext2Fs, FsError> {
+    let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::from_bytes(512, image.0));
+    Ext2Fs::new(disk)
+}
+
+#[test_case]
+fn reads_root_directory() {
+    let fs = volume(image()).unwrap();
+    let entries = fs.read_dir("/").unwrap();
+
+    // ".", "..", the deleted entry and the symbolic link are skipped.
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0].name, "hello.txt");
+    assert_eq!(entries[0].metadata.size, 13);
+    assert_eq!(entries[1].name, "sparse");
+    assert_eq!(entries[2].name, "docs");
+    assert_eq!(entries[2].metadata.file_type, FileType::Directory);
+}
+
+#[test_case]
+fn reads_files() {
+    let fs = volume(image()).unwrap();
+
+    assert_eq!(fs.read_file("/hello.txt").unwrap(), b"Hello, ext2!\n");
+    assert_eq!(fs.read_file("/docs/big.bin").unwrap(), big_contents());
+    assert_eq!(
+        fs.read_file("/docs/../hello.txt").unwrap(),
+        b"Hello, ext2!\n"
+    );
+
+    // The end of big.bin comes through the indirect block
+    let mut buf = [0; 8];
+    assert_eq!(
+        fs.read("/docs/big.bin", BIG_SIZE as u64 - 4, &mut buf),
+        Ok(4)
+    );
+    assert_eq!(buf[..4], big_contents()[BIG_SIZE - 4..]);
+
+    let mut buf = [0xFF; 4];
+    let offset = (SPARSE_BLOCK * BLOCK) as u64;
+    assert_eq!(fs.read("/sparse", offset, &mut buf), Ok(4));
+    assert_eq!(&buf, b"tail");
+    assert_eq!(fs.read("/sparse", offset - 4, &mut buf), Ok(4));
+    assert_eq!(buf, [0; 4]);
+}
+
+#[test_case]
+fn mounts_in_the_vfs() {
+    fs::mount("/mnt", Arc::new(volume(image()).unwrap())).unwrap();
+
+    assert_eq!(fs::read_file("/mnt/hello.txt").unwrap(), b"Hello, ext2!\n");
+    assert_eq!(fs::write("/mnt/hello.txt", 0, b"x"), Err(FsError::ReadOnly));
+
+    fs::unmount("/mnt").unwrap();
+}
+
+#[test_case]
+fn reports_errors() {
+    let fs = volume(image()).unwrap();
+
+    assert_eq!(fs.metadata("/missing").err(), Some(FsError::NotFound));
+    // Names are case-sensitive
+    assert_eq!(fs.metadata("/Hello.txt").err(), Some(FsError::NotFound));
+    assert_eq!(
+        fs.metadata("/hello.txt/x").err(),
+        Some(FsError::NotADirectory)
+    );
+    assert_eq!(fs.read_file("/docs").err(), Some(FsError::IsADirectory));
+    assert_eq!(fs.metadata("/link").err(), Some(FsError::Unsupported));
+
+    let mut blank = image();
+    blank.put(1024 + 56, &[0, 0]);
+    assert_eq!(volume(blank).err(), Some(FsError::Corrupt));
+
+    // Extents, from ext4
+    let mut ext4 = image();
+    ext4.put(1024 + 96, &0x42u32.to_le_bytes());
+    assert_eq!(volume(ext4).err(), Some(FsError::Unsupported));
+
+    // Groups bigger than a block's bitmap covers, and groups so small
+    // that their descriptors would fill the heap
+    for blocks_per_group in [8 * BLOCK as u32 + 8, 1] {
+        let mut groups = image();
+        groups.put(1024 + 32, &blocks_per_group.to_le_bytes());
+        assert_eq!(volume(groups).err(), Some(FsError::Corrupt));
+    }
+
+    // An inode size that would let inodes cross a block
+    let mut odd_inodes = image();
+    odd_inodes.put(1024 + 88, &200u16.to_le_bytes());
+    assert_eq!(volume(odd_inodes).err(), Some(FsError::Corrupt));
+
+    // A directory that says it's bigger than the disk
+    let mut huge_directory = image();
+    huge_directory.inode(DOCS, MODE_DIRECTORY, u32::MAX, &[(0, 12)]);
+    let fs = volume(huge_directory).unwrap();
+    assert_eq!(fs.read_dir("/docs").err(), Some(FsError::Corrupt));
+}
@@ -0,0 +1,240 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use rust_os_playground::block::{BlockDevice, RamDisk};
+use rust_os_playground::fs::{fat::FatFs, FileSystem, FileType, FsError};
+
+rust_os_playground::kernel_test_main!(heap);
+
+// A small FAT32 volume, laid out like mkfs.fat would: 512-byte sectors, one
+// sector per cluster, 32 reserved sectors and two FATs of 2 sectors each.
+const SECTOR: usize = 512;
+const TOTAL_SECTORS: usize = 256;
+const RESERVED: usize = 32;
+const FAT_SECTORS: usize = 2;
+const DATA_START: usize = RESERVED + 2 * FAT_SECTORS;
+const END_OF_CHAIN: u32 = 0x0FFF_FFFF;
+
+const ROOT: u32 = 2;
+const HELLO: u32 = 3;
+const DOCS: u32 = 4;
+
+struct Image(Vec<u8>);
+
+impl Image {
+    fn new() -> Image {
+        let mut image = Image(vec![0; TOTAL_SECTORS * SECTOR]);
+
+        image.put(0, &[0xEB, 0x58, 0x90]);
+        image.put(3, b"MSWIN4.1");
+        image.put(11, &(SECTOR as u16).to_le_bytes());
+        image.0[13] = 1;
+        image.put(14, &(RESERVED as u16).to_le_bytes());
+        image.0[16] = 2;
+        image.put(32, &(TOTAL_SECTORS as u32).to_le_bytes());
+        image.put(36, &(FAT_SECTORS as u32).to_le_bytes());
+        image.put(44, &ROOT.to_le_bytes());
+        image.put(510, &[0x55, 0xAA]);
+
+        image.set_fat(0, 0x0FFF_FFF8);
+        image.set_fat(1, END_OF_CHAIN);
+        image
+    }
+
+    fn put(&mut self, offset: usize, bytes: &[u8]) {
+        self.0[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Sets the entry in both FATs.
+    fn set_fat(&mut self, cluster: u32, next: u32) {
+        for fat in 0..2 {
+            let offset = (RESERVED + fat * FAT_SECTORS) * SECTOR + cluster as usize * 4;
+            self.put(offset, &next.to_le_bytes());
+        }
+    }
+
+    fn cluster_offset(cluster: u32) -> usize {
+        (DATA_START + cluster as usize - 2) * SECTOR
+    }
+
+    /// Writes a file into the given clusters, which don't have to be in order.
+    fn write_file(&mut self, clusters: &[u32], data: &[u8]) {
+        for (i, (&cluster, chunk)) in clusters.iter().zip(data.chunks(SECTOR)).enumerate() {
+            self.put(Self::cluster_offset(cluster), chunk);
+            let next = clusters.get(i + 1).copied().unwrap_or(END_OF_CHAIN);
+            self.set_fat(cluster, next);
+        }
+    }
+
+    /// Writes the 32-byte directory entry at `index` in the directory at
+    /// `cluster`.
+    fn dir_entry(&mut self, cluster: u32, index: usize, entry: &[u8; 32]) {
+        self.put(Self::cluster_offset(cluster) + index * 32, entry);
+    }
+}
+
+fn short_entry(name: &[u8; 11], attributes: u8, cluster: u32, size: u32) -> [u8; 32] {
+    let mut entry = [0; 32];
+    entry[..11].copy_from_slice(name);
+    entry[11] = attributes;
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+    entry
+}
+
+/// The LFN entries for `name`, in the order they go on disk.
+fn long_entries(name: &str, short_name: &[u8; 11]) -> Vec<[u8; 32]> {
+    let checksum = short_name
+        .iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte));
+
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    if units.len() % 13 != 0 {
+        units.push(0);
+    }
+    while units.len() % 13 != 0 {
+        units.push(0xFFFF);
+    }
+
+    let parts = units.len() / 13;
+    (0..parts)
+        .rev()
+        .map(|i| {
+            let mut entry = [0; 32];
+            entry[0] = (i + 1) as u8 | if i + 1 == parts { 0x40 } else { 0 };
+            entry[11] = 0x0F;
+            entry[13] = checksum;
+
+            let offsets = (1..11)
+                .step_by(2)
+                .chain((14..26).step_by(2))
+                .chain((28..32).step_by(2));
+            for (offset, unit) in offsets.zip(&units[i * 13..(i + 1) * 13]) {
+                entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            entry
+        })
+        .collect()
+}
+
+const LONG_NAME: &str = "A rather long file name.txt";
+
+fn long_file_contents() -> Vec<u8> {
+    (0..1300u32).map(|i| (i % 251) as u8).collect()
+}
+
+/// / holds HELLO.TXT and docs/, which holds a file with a long name that
+/// spans three clusters, which are out of order.
+fn volume() -> FatFs {
+    let mut image = Image::new();
+
+    image.set_fat(ROOT, END_OF_CHAIN);
+    image.dir_entry(ROOT, 0, &short_entry(b"TESTVOL    ", 0x08, 0, 0));
+    image.dir_entry(ROOT, 1, &short_entry(b"HELLO   TXT", 0x20, HELLO, 12));
+    image.dir_entry(ROOT, 2, &[0xE5; 32]);
+    let mut docs = short_entry(b"DOCS       ", 0x10, DOCS, 0);
+    docs[12] = 0x08;
+    image.dir_entry(ROOT, 3, &docs);
+
+    image.write_file(&[HELLO], b"Hello, FAT!\n");
+
+    image.set_fat(DOCS, END_OF_CHAIN);
+    image.dir_entry(DOCS, 0, &short_entry(b".          ", 0x10, DOCS, 0));
+    image.dir_entry(DOCS, 1, &short_entry(b"..         ", 0x10, 0, 0));
+    let short_name = b"ARATHE~1TXT";
+    let mut index = 2;
+    for entry in long_entries(LONG_NAME, short_name) {
+        image.dir_entry(DOCS, index, &entry);
+        index += 1;
+    }
+    image.dir_entry(DOCS, index, &short_entry(short_name, 0x20, 9, 1300));
+    image.write_file(&[9, 7, 8], &long_file_contents());
+
+    let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::from_bytes(SECTOR, image.0));
+    FatFs::new(disk).unwrap()
+}
+
+#[test_case]
+fn reads_root_directory() {
+    let fs = volume();
+    let entries = fs.read_dir("/").unwrap();
+
+    // The volume label and the deleted entry are skipped.
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].name, "HELLO.TXT");
+    assert_eq!(entries[0].metadata.file_type, FileType::File);
+    assert_eq!(entries[0].metadata.size, 12);
+    assert_eq!(entries[1].name, "docs");
+    assert_eq!(entries[1].metadata.file_type, FileType::Directory);
+}
+
+#[test_case]
+fn reads_known_file() {
+    let fs = volume();
+
+    assert_eq!(fs.read_file("/HELLO.TXT").unwrap(), b"Hello, FAT!\n");
+    // Names are case-insensitive
+    assert_eq!(fs.read_file("hello.txt").unwrap(), b"Hello, FAT!\n");
+
+    let mut buf = [0; 16];
+    assert_eq!(fs.read("/HELLO.TXT", 7, &mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"FAT!\n");
+    assert_eq!(fs.read("/HELLO.TXT", 12, &mut buf).unwrap(), 0);
+}
+
+#[test_case]
+fn reads_long_names_and_cluster_chains() {
+    let fs = volume();
+
+    let entries = fs.read_dir("/docs").unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].name, LONG_NAME);
+
+    let path = "/docs/A rather long file name.txt";
+    assert_eq!(fs.read_file(path).unwrap(), long_file_contents());
+
+    // Across the boundary between the first and second cluster
+    let mut buf = [0; 4];
+    fs.read(path, 510, &mut buf).unwrap();
+    assert_eq!(buf, [510, 511, 512, 513].map(|i: u32| (i % 251) as u8));
+
+    assert_eq!(
+        fs.read_file("/docs/../HELLO.TXT").unwrap(),
+        b"Hello, FAT!\n"
+    );
+}
+
+#[test_case]
+fn reports_errors() {
+    let fs = volume();
+
+    assert_eq!(fs.metadata("/missing").err(), Some(FsError::NotFound));
+    assert_eq!(
+        fs.read_dir("/HELLO.TXT").err(),
+        Some(FsError::NotADirectory)
+    );
+    assert_eq!(fs.read_file("/docs").err(), Some(FsError::IsADirectory));
+    assert_eq!(
+        fs.metadata("/HELLO.TXT/x").err(),
+        Some(FsError::NotADirectory)
+    );
+
+    let blank: Arc<dyn BlockDevice> = Arc::new(RamDisk::new(SECTOR, 16));
+    assert_eq!(FatFs::new(blank).err(), Some(FsError::Corrupt));
+
+    // Sectors or clusters too big to read into the heap
+    for (sector_size, sectors_per_cluster) in [(8192, 1), (4096, 32), (512, 255)] {
+        let mut image = Image::new();
+        image.put(11, &(sector_size as u16).to_le_bytes());
+        image.0[13] = sectors_per_cluster;
+        let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::from_bytes(SECTOR, image.0));
+        assert_eq!(FatFs::new(disk).err(), Some(FsError::Corrupt));
+    }
+}
@@ -0,0 +1,136 @@
+// Mounts the FAT32 image that build.rs makes from tests/fixtures/disk, and
+// checks what the VFS finds in it against the fixtures themselves.
+//
+// There is no disk driver yet, so the image is embedded into the test kernel
+// and put on a RAM disk, behind a block cache like a real disk would be.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use rust_os_playground::block::{self, cache, BlockCache, RamDisk};
+use rust_os_playground::fs::{self, fat::FatFs, FileType, FsError};
+
+static IMAGE: &[u8] = include_bytes!(env!("DISK_IMAGE"));
+
+const MOUNT_PATH: &str = "/disk";
+// The name `block::register` gives the first "disk" device
+const DEVICE: &str = "disk0";
+
+rust_os_playground::kernel_test_main!(heap => {
+    let disk = RamDisk::from_bytes(512, IMAGE.to_vec());
+    let cache = BlockCache::new(Arc::new(disk), cache::DEFAULT_CAPACITY);
+    assert_eq!(block::register("disk", Arc::new(cache)), DEVICE);
+    mount();
+});
+
+fn mount() {
+    let disk = block::get(DEVICE).unwrap();
+    let fat = FatFs::new(disk).expect("can't read the disk image");
+    fs::mount(MOUNT_PATH, Arc::new(fat)).unwrap();
+}
+
+fn names(path: &str) -> Vec<String> {
+    fs::read_dir(path)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect()
+}
+
+#[test_case]
+fn lists_directories() {
+    assert_eq!(names("/disk"), ["README.TXT", "docs", "hello.txt"]);
+    assert_eq!(names("/disk/docs"), ["empty.txt", "notes.txt"]);
+    assert_eq!(
+        fs::metadata("/disk/docs").unwrap().file_type,
+        FileType::Directory
+    );
+    assert!(names("/").contains(&String::from("disk")));
+}
+
+#[test_case]
+fn reads_fixtures() {
+    let fixtures: [(&str, &[u8]); 4] = [
+        (
+            "/disk/README.TXT",
+            include_bytes!("fixtures/disk/README.TXT"),
+        ),
+        ("/disk/hello.txt", include_bytes!("fixtures/disk/hello.txt")),
+        (
+            "/disk/docs/notes.txt",
+            include_bytes!("fixtures/disk/docs/notes.txt"),
+        ),
+        (
+            "/disk/docs/empty.txt",
+            include_bytes!("fixtures/disk/docs/empty.txt"),
+        ),
+    ];
+
+    for (path, contents) in fixtures {
+        assert_eq!(fs::read_file(path).unwrap(), contents, "{}", path);
+        assert_eq!(fs::metadata(path).unwrap().size, contents.len() as u64);
+    }
+
+    // notes.txt spans several clusters
+    let notes = include_bytes!("fixtures/disk/docs/notes.txt");
+    let mut buf = [0; 100];
+    assert_eq!(fs::read("/disk/docs/notes.txt", 500, &mut buf), Ok(100));
+    assert_eq!(buf[..], notes[500..600]);
+}
+
+#[test_case]
+fn survives_a_remount() {
+    let hello = include_bytes!("fixtures/disk/hello.txt");
+
+    // The FAT driver is read-only, so writes must fail without changing
+    // anything, in the cache or on the disk.
+    assert_eq!(
+        fs::write("/disk/hello.txt", 0, b"Goodbye"),
+        Err(FsError::ReadOnly)
+    );
+    assert_eq!(fs::create_file("/disk/new.txt"), Err(FsError::ReadOnly));
+
+    // So change hello.txt's contents on the disk instead, under the driver
+    let disk = block::get(DEVICE).unwrap();
+    let sector = IMAGE
+        .chunks(512)
+        .position(|sector| sector.starts_with(hello))
+        .unwrap() as u64;
+    let mut data = [0; 512];
+    disk.read_blocks(sector, &mut data).unwrap();
+    let original = data;
+    data[..hello.len()].make_ascii_uppercase();
+    disk.write_blocks(sector, &data).unwrap();
+    block::sync().unwrap();
+
+    fs::unmount(MOUNT_PATH).unwrap();
+    assert_eq!(fs::read_file("/disk/hello.txt"), Err(FsError::NotFound));
+
+    mount();
+    assert_eq!(
+        fs::read_file("/disk/hello.txt").unwrap(),
+        hello.to_ascii_uppercase()
+    );
+    assert_eq!(names("/disk"), ["README.TXT", "docs", "hello.txt"]);
+
+    disk.write_blocks(sector, &original).unwrap();
+    block::sync().unwrap();
+    assert_eq!(fs::read_file("/disk/hello.txt").unwrap(), hello);
+}
+
+#[test_case]
+fn image_is_a_fat32_volume() {
+    let disk = block::get(DEVICE).unwrap();
+    assert_eq!(disk.size(), IMAGE.len() as u64);
+
+    let mut boot = [0; 512];
+    disk.read_blocks(0, &mut boot).unwrap();
+    assert_eq!(boot[510..], [0x55, 0xAA]);
+    assert_eq!(&boot[82..90], b"FAT32   ");
+}
@@ -0,0 +1,32 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+// Transcripts for tools/golden.py to compare with tests/golden/ (see
+// src/golden.rs).
+
+extern crate alloc;
+
+use core::fmt::{self, Write};
+use rust_os_playground::{golden, shell};
+
+rust_os_playground::kernel_test_main!(heap);
+
+fn echo(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    writeln!(out, "{}", args[1..].join(" "))
+}
+
+#[test_case]
+fn shell_transcript() {
+    shell::register("echo", "print the arguments", echo);
+
+    golden::transcript("shell", |out| {
+        for line in ["echo hello   world", "help", "no_such_command"] {
+            writeln!(out, "> {}", line)?;
+            shell::execute(line, out)?;
+        }
+        Ok(())
+    });
+}
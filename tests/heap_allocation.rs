@@ -7,34 +7,15 @@
 extern crate alloc;
 
 use alloc::boxed::Box;
+use alloc::string::String;
 use alloc::vec::Vec;
-use bootloader::{entry_point, BootInfo};
-use core::panic::PanicInfo;
-use rust_os_playground::allocator::{self, HEAP_SIZE};
+use rust_os_playground::allocator;
+use rust_os_playground::config::HEAP_SIZE;
+use rust_os_playground::shell;
 
-entry_point!(main);
-fn main(boot_info: &'static BootInfo) -> ! {
-    use rust_os_playground::memory::{self, BootInfoFrameAllocator};
-    use x86_64::VirtAddr;
-
-    rust_os_playground::init();
-
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-
-    allocator::init_heap(&mut mapper, &mut frame_allocator)
-        .expect("test heap initialization failed");
-
-    test_main();
-
-    loop {}
-}
-
-#[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
-    rust_os_playground::test_panic_handler(info)
-}
+rust_os_playground::kernel_test_main!(heap => {
+    allocator::register_commands();
+});
 
 // Most importantly, this test verifies that no allocation error occurs
 #[test_case]
@@ -77,3 +58,14 @@ fn many_boxes_long_lived() {
     }
     assert_eq!(*long_lived, 1);
 }
+
+#[test_case]
+fn heap_command_self_test() {
+    let mut out = String::new();
+    shell::execute("heap test", &mut out).unwrap();
+    assert!(out.ends_with("heap test passed\n"), "{}", out);
+
+    let mut out = String::new();
+    shell::execute("heap", &mut out).unwrap();
+    assert!(out.contains(allocator::BACKEND));
+}
@@ -0,0 +1,135 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+// Random allocations, frees and reallocations of all sizes and alignments,
+// checking that nothing an allocation holds changes under it. The operations
+// come from a generator with a seed that's printed first, so a failure can be
+// reproduced by building the test with the same seed:
+//
+//     KERNEL_CMDLINE="seed=<seed>" cargo test --test heap_stress
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, realloc, Layout};
+use rust_os_playground::{allocator, cmdline, rand, serial_println};
+
+rust_os_playground::kernel_test_main!(heap);
+
+const OPERATIONS: usize = 5000;
+const SLOTS: usize = 64;
+const CHECK_EVERY: usize = 250;
+
+/// SplitMix64, which is plenty for picking operations, and the same on every
+/// machine.
+struct Generator(u64);
+
+impl Generator {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, bound: u64) -> usize {
+        (self.next() % bound) as usize
+    }
+
+    /// Mostly small sizes, like a kernel allocates, and now and then a block
+    /// too large for the fixed-size lists.
+    fn layout(&mut self) -> Layout {
+        let size = match self.below(8) {
+            0 => 1 + self.below(4096),
+            _ => 1 + self.below(128),
+        };
+        let align = 1 << self.below(7);
+        Layout::from_size_align(size, align).unwrap()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Allocation {
+    ptr: *mut u8,
+    layout: Layout,
+    fill: u8,
+}
+
+impl Allocation {
+    fn verify(&self, len: usize) {
+        let bytes = unsafe { core::slice::from_raw_parts(self.ptr, len) };
+        if let Some(offset) = bytes.iter().position(|&byte| byte != self.fill) {
+            panic!(
+                "byte {} of {:p} ({:?}) is {:#04x} instead of {:#04x}",
+                offset, self.ptr, self.layout, bytes[offset], self.fill
+            );
+        }
+    }
+
+    fn refill(&mut self, fill: u8) {
+        self.fill = fill;
+        unsafe { self.ptr.write_bytes(fill, self.layout.size()) };
+    }
+}
+
+#[test_case]
+fn random_alloc_free_realloc() {
+    let seed = cmdline::parse("seed").unwrap_or_else(rand::next_u64);
+    serial_println!("seed={}", seed);
+
+    let mut generator = Generator(seed);
+    let mut slots: [Option<Allocation>; SLOTS] = [None; SLOTS];
+
+    for operation in 1..=OPERATIONS {
+        let slot = &mut slots[generator.below(SLOTS as u64)];
+        let fill = generator.next() as u8;
+
+        match *slot {
+            None => {
+                let layout = generator.layout();
+                let ptr = unsafe { alloc(layout) };
+                // The heap can run out with many large blocks; that's fine.
+                if !ptr.is_null() {
+                    assert_eq!(ptr as usize % layout.align(), 0);
+                    let mut allocation = Allocation { ptr, layout, fill };
+                    allocation.refill(fill);
+                    *slot = Some(allocation);
+                }
+            }
+            Some(allocation) if generator.below(2) == 0 => {
+                allocation.verify(allocation.layout.size());
+                unsafe { dealloc(allocation.ptr, allocation.layout) };
+                *slot = None;
+            }
+            Some(mut allocation) => {
+                let new_size = generator.layout().size();
+                let ptr = unsafe { realloc(allocation.ptr, allocation.layout, new_size) };
+                if !ptr.is_null() {
+                    let old_size = allocation.layout.size();
+                    allocation.ptr = ptr;
+                    allocation.layout =
+                        Layout::from_size_align(new_size, allocation.layout.align()).unwrap();
+                    assert_eq!(ptr as usize % allocation.layout.align(), 0);
+                    allocation.verify(old_size.min(new_size));
+                    allocation.refill(fill);
+                    *slot = Some(allocation);
+                }
+            }
+        }
+
+        if operation % CHECK_EVERY == 0 {
+            if let Err(error) = allocator::check() {
+                panic!("after {} operations: {:?}", operation, error);
+            }
+        }
+    }
+
+    for allocation in slots.iter().flatten() {
+        allocation.verify(allocation.layout.size());
+        unsafe { dealloc(allocation.ptr, allocation.layout) };
+    }
+    allocator::check().expect("free lists broken after freeing everything");
+}
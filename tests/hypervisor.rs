@@ -0,0 +1,34 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use rust_os_playground::{hypervisor, time};
+
+rust_os_playground::kernel_test_main!(heap, hypervisor);
+
+/// The tests run in QEMU, with KVM or without.
+#[test_case]
+fn runs_under_qemu() {
+    assert!(hypervisor::is_virtualized());
+    assert!(matches!(
+        hypervisor::detect(),
+        Some(hypervisor::Hypervisor::Kvm) | Some(hypervisor::Hypervisor::Qemu)
+    ));
+}
+
+#[test_case]
+fn takes_the_tsc_frequency_from_the_hypervisor() {
+    if let Some(hz) = hypervisor::tsc_hz() {
+        assert_eq!(time::tsc_hz(), Some(hz));
+    }
+}
+
+#[test_case]
+fn kvm_clock_goes_forward() {
+    if let Some(before) = hypervisor::kvm_clock_ns() {
+        let after = hypervisor::kvm_clock_ns().unwrap();
+        assert!(after >= before);
+    }
+}
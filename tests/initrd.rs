@@ -0,0 +1,99 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{format, string::String, vec::Vec};
+use common::run_to_exit;
+use rust_os_playground::fs::{self, tar::TarFs, FileSystem, FileType, FsError};
+use rust_os_playground::process::Process;
+use rust_os_playground::{bf, initrd, programs, tty};
+
+rust_os_playground::kernel_test_main!(memory, process => {
+    initrd::init().expect("can't mount the initrd");
+});
+
+fn names(path: &str) -> Vec<String> {
+    fs::read_dir(path)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect()
+}
+
+#[test_case]
+fn initrd_has_programs_and_configuration() {
+    assert_eq!(names("/init"), ["bf", "bin", "etc"]);
+    assert_eq!(names("/init/bin"), ["cat", "echo", "hello", "stress"]);
+    assert_eq!(fs::read_file("/init/bin/hello").unwrap(), programs::HELLO);
+    assert!(!fs::read_file("/init/etc/motd").unwrap().is_empty());
+    for name in names("/init/bf") {
+        let source = fs::read_file(&format!("/init/bf/{}", name)).unwrap();
+        assert!(bf::Program::compile(&source).is_ok(), "{}", name);
+    }
+
+    assert_eq!(
+        fs::metadata("/init/etc").unwrap().file_type,
+        FileType::Directory
+    );
+    assert_eq!(
+        fs::metadata("/init/bin/hello/x").err(),
+        Some(FsError::NotADirectory)
+    );
+    assert_eq!(fs::metadata("/init/nope").err(), Some(FsError::NotFound));
+    assert_eq!(fs::create_file("/init/new").err(), Some(FsError::ReadOnly));
+}
+
+#[test_case]
+fn run_program_from_initrd() {
+    let elf = fs::read_file("/init/bin/echo").unwrap();
+
+    tty::capture_output(true);
+    let pid = Process::spawn_with_args(&elf, &["echo", "from", "initrd"], &[]).unwrap();
+    assert_eq!(run_to_exit(pid), 0);
+    assert!(tty::take_captured_output().contains("from initrd\n"));
+    tty::capture_output(false);
+}
+
+/// A ustar header for a file, without its data.
+fn header(name: &str, size: usize) -> [u8; 512] {
+    let mut header = [0; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+
+    header[148..156].copy_from_slice(b"        ");
+    let sum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+    header[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+    header
+}
+
+#[test_case]
+fn implied_directories_and_corrupt_archives() {
+    let mut archive = Vec::new();
+    archive.extend_from_slice(&header("a/b/c.txt", 3));
+    archive.extend_from_slice(b"abc");
+    archive.resize(1024 + 1024, 0);
+    let tar = TarFs::new(Vec::leak(archive.clone())).unwrap();
+
+    assert_eq!(tar.read_file("/a/b/c.txt").unwrap(), b"abc");
+    assert_eq!(tar.read_dir("/a").unwrap()[0].name, "b");
+
+    // A flipped bit breaks the checksum
+    archive[0] ^= 1;
+    assert_eq!(
+        TarFs::new(Vec::leak(archive.clone())).err(),
+        Some(FsError::Corrupt)
+    );
+
+    // The data is cut off
+    archive[0] ^= 1;
+    archive.truncate(512 + 2);
+    assert_eq!(TarFs::new(Vec::leak(archive)).err(), Some(FsError::Corrupt));
+}
@@ -0,0 +1,135 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use common::{executable, run_to_exit, Asm, Reg, DATA_OFFSET};
+use rust_os_playground::ipc::{self, IpcError, MESSAGE_SIZE};
+use rust_os_playground::memory::address_space::{USER_END, USER_START};
+use rust_os_playground::process::{Pid, Process};
+use rust_os_playground::syscall::{errno, number};
+
+rust_os_playground::kernel_test_main!(memory, process);
+
+const DATA: u64 = USER_START + DATA_OFFSET;
+
+#[test_case]
+fn send_and_receive_in_the_kernel() {
+    let port = ipc::create_port();
+
+    ipc::send(port, b"first").unwrap();
+    ipc::send(port, b"second").unwrap();
+
+    let message = ipc::try_recv(port).unwrap().unwrap();
+    assert_eq!(message.data(), b"first");
+    assert_eq!(message.sender(), Pid::KERNEL);
+    assert_eq!(ipc::recv(port).unwrap().data(), b"second");
+    assert!(ipc::try_recv(port).unwrap().is_none());
+}
+
+#[test_case]
+fn send_errors() {
+    let port = ipc::create_port();
+
+    assert_eq!(
+        ipc::send(port, &[0; MESSAGE_SIZE + 1]),
+        Err(IpcError::TooLong)
+    );
+    assert_eq!(
+        ipc::send(ipc::PortId::from_u64(u64::MAX), b"x"),
+        Err(IpcError::NoSuchPort)
+    );
+
+    while ipc::send(port, b"x").is_ok() {}
+    assert_eq!(ipc::send(port, b"x"), Err(IpcError::QueueFull));
+}
+
+#[test_case]
+fn process_sends_to_the_kernel() {
+    let port = ipc::create_port();
+
+    // exit(send(port, "hello", 5))
+    let code = Asm::new()
+        .mov(Reg::Rax, number::SEND)
+        .mov(Reg::Rdi, port.as_u64())
+        .mov(Reg::Rsi, DATA)
+        .mov(Reg::Rdx, 5)
+        .syscall()
+        .mov_rdi_rax()
+        .mov(Reg::Rax, number::EXIT)
+        .syscall()
+        .spin();
+    let pid = Process::spawn(&executable(USER_START, &code, b"hello")).unwrap();
+
+    // Blocks until the process has run
+    let message = ipc::recv(port).unwrap();
+    assert_eq!(message.data(), b"hello");
+    assert_eq!(message.sender(), pid);
+    assert_eq!(run_to_exit(pid), 0);
+}
+
+#[test_case]
+fn process_blocks_in_recv() {
+    let kernel_port = ipc::create_port();
+
+    // Creates a port, sends its ID to the kernel, and exits with the length of
+    // the first message it receives on it.
+    let code = Asm::new()
+        .mov(Reg::Rax, number::CREATE_PORT)
+        .syscall()
+        .push_rax()
+        .mov(Reg::Rax, number::SEND)
+        .mov(Reg::Rdi, kernel_port.as_u64())
+        .mov_rsi_rsp()
+        .mov(Reg::Rdx, 8)
+        .syscall()
+        .pop_rdi()
+        .mov(Reg::Rax, number::RECV)
+        .mov(Reg::Rsi, USER_END - 128)
+        .mov(Reg::Rdx, 64)
+        .syscall()
+        .mov_rdi_rax()
+        .mov(Reg::Rax, number::EXIT)
+        .syscall()
+        .spin();
+    let pid = Process::spawn(&executable(USER_START, &code, &[])).unwrap();
+
+    let message = ipc::recv(kernel_port).unwrap();
+    let mut id = [0; 8];
+    id.copy_from_slice(message.data());
+    let port = ipc::PortId::from_u64(u64::from_le_bytes(id));
+
+    // Only the owner can receive
+    assert_eq!(ipc::try_recv(port).err(), Some(IpcError::NotOwner));
+
+    ipc::send(port, b"ping").unwrap();
+    assert_eq!(run_to_exit(pid), 4);
+
+    // The port went away with the process
+    assert_eq!(ipc::send(port, b"ping"), Err(IpcError::NoSuchPort));
+}
+
+#[test_case]
+fn process_cant_receive_from_foreign_port() {
+    let port = ipc::create_port();
+
+    // exit(recv(port, stack, 64))
+    let code = Asm::new()
+        .mov(Reg::Rax, number::RECV)
+        .mov(Reg::Rdi, port.as_u64())
+        .mov(Reg::Rsi, USER_END - 128)
+        .mov(Reg::Rdx, 64)
+        .syscall()
+        .mov_rdi_rax()
+        .mov(Reg::Rax, number::EXIT)
+        .syscall()
+        .spin();
+    let pid = Process::spawn(&executable(USER_START, &code, &[])).unwrap();
+
+    assert_eq!(run_to_exit(pid), -errno::EBADF);
+}
@@ -0,0 +1,199 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::vec::Vec;
+use common::net::{from_peer, wire_to_peer, Wire, OUR_IP, PEER_IP};
+use common::{block_on, run};
+use rust_os_playground::error::KernelError;
+use rust_os_playground::net::eth::{self, ETHERTYPE_IPV4};
+use rust_os_playground::net::icmp::{self, EchoHeader, EchoSocket};
+use rust_os_playground::net::ipv4::{self, Config};
+use rust_os_playground::net::{Ipv4Address, NetError, PacketBuf};
+use rust_os_playground::time;
+
+rust_os_playground::kernel_test_main!(heap);
+
+fn echo(kind: u8, identifier: u16, sequence: u16, data: &[u8]) -> PacketBuf {
+    let mut packet = PacketBuf::new();
+    packet.extend_from_slice(data);
+    let header = EchoHeader {
+        kind,
+        identifier,
+        sequence,
+    };
+    header.push(&mut packet);
+    packet
+}
+
+/// Takes the packets sent so far, without their Ethernet and IPv4 headers.
+fn take_sent(wire: &Wire) -> Vec<(ipv4::Header, PacketBuf)> {
+    let frames = core::mem::take(&mut *wire.sent.lock());
+    frames
+        .into_iter()
+        .map(|mut frame| {
+            let header = eth::Header::pull(&mut frame).unwrap();
+            assert_eq!(header.ethertype, ETHERTYPE_IPV4);
+            (ipv4::Header::pull(&mut frame).unwrap(), frame)
+        })
+        .collect()
+}
+
+#[test_case]
+fn addresses() {
+    assert_eq!("10.0.2.2".parse(), Ok(Ipv4Address::new(10, 0, 2, 2)));
+    for bad in ["10.0.2", "10.0.2.256", "10.0.2.2.1", "10.0..2", ""].iter() {
+        assert_eq!(
+            bad.parse::<Ipv4Address>(),
+            Err(KernelError::InvalidArgument)
+        );
+    }
+
+    let config = Config {
+        address: Ipv4Address::new(10, 0, 2, 15),
+        prefix_len: 24,
+        gateway: Some(Ipv4Address::new(10, 0, 2, 2)),
+    };
+    assert_eq!(config.netmask(), Ipv4Address::new(255, 255, 255, 0));
+    assert_eq!(config.broadcast(), Ipv4Address::new(10, 0, 2, 255));
+    let neighbour = Ipv4Address::new(10, 0, 2, 3);
+    assert_eq!(config.next_hop(neighbour), Some(neighbour));
+    let far = Ipv4Address::new(1, 1, 1, 1);
+    assert_eq!(config.next_hop(far), config.gateway);
+    assert_eq!(
+        Config {
+            gateway: None,
+            ..config
+        }
+        .next_hop(far),
+        None
+    );
+}
+
+#[test_case]
+fn checksums() {
+    // An example header, checksum included
+    let header = [
+        0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0xb8, 0x61, 0xc0, 0xa8, 0x00,
+        0x01, 0xc0, 0xa8, 0x00, 0xc7,
+    ];
+    assert_eq!(ipv4::checksum(&header), 0);
+
+    let mut without = header;
+    without[10..12].copy_from_slice(&[0, 0]);
+    assert_eq!(ipv4::checksum(&without), 0xb861);
+
+    // Odd lengths are padded with a zero
+    assert_eq!(ipv4::checksum(&[0x12, 0x34, 0x56]), !0x6834);
+}
+
+#[test_case]
+fn headers_round_trip() {
+    let header = ipv4::Header {
+        source: PEER_IP,
+        destination: OUR_IP,
+        protocol: ipv4::PROTOCOL_UDP,
+        ttl: 17,
+        identification: 0x1234,
+    };
+    let mut packet = PacketBuf::new();
+    packet.extend_from_slice(b"payload");
+    header.push(&mut packet);
+    assert_eq!(packet.len(), ipv4::HEADER_LEN + 7);
+
+    // The padding of a short frame is cut off
+    packet.extend_from_slice(&[0; 10]);
+    let mut copy = packet.clone();
+    assert_eq!(ipv4::Header::pull(&mut copy), Some(header));
+    assert_eq!(&copy[..], b"payload");
+
+    let mut corrupt = packet.clone();
+    corrupt[8] = 18;
+    assert_eq!(ipv4::Header::pull(&mut corrupt), None);
+
+    // Fragments are dropped
+    let mut fragment = packet.clone();
+    fragment[6] |= 1 << 5;
+    fragment[10..12].copy_from_slice(&[0, 0]);
+    let checksum = ipv4::checksum(&fragment[..ipv4::HEADER_LEN]);
+    fragment[10..12].copy_from_slice(&checksum.to_be_bytes());
+    assert_eq!(ipv4::Header::pull(&mut fragment), None);
+}
+
+#[test_case]
+fn answers_pings() {
+    let (wire, interface) = wire_to_peer();
+
+    let request = echo(icmp::TYPE_ECHO_REQUEST, 7, 3, b"are you there?");
+    run(eth::handle(
+        &interface,
+        from_peer(ipv4::PROTOCOL_ICMP, request),
+    ));
+
+    let mut sent = take_sent(&wire);
+    assert_eq!(sent.len(), 1);
+    let (header, reply) = &mut sent[0];
+    assert_eq!((header.source, header.destination), (OUR_IP, PEER_IP));
+    assert_eq!(header.protocol, ipv4::PROTOCOL_ICMP);
+    let expected = EchoHeader {
+        kind: icmp::TYPE_ECHO_REPLY,
+        identifier: 7,
+        sequence: 3,
+    };
+    assert_eq!(EchoHeader::pull(reply), Some(expected));
+    assert_eq!(&reply[..], b"are you there?");
+}
+
+#[test_case]
+fn echo_sockets_get_their_replies() {
+    let (wire, interface) = wire_to_peer();
+    let socket = EchoSocket::open();
+    let other = EchoSocket::open();
+    assert_ne!(socket.identifier(), other.identifier());
+
+    let sent = run(socket.send(PEER_IP, 1, b"ping"));
+    assert_eq!(sent, Ok(()));
+    let (_, mut request) = take_sent(&wire).pop().unwrap();
+    let header = EchoHeader::pull(&mut request).unwrap();
+    assert_eq!(header.kind, icmp::TYPE_ECHO_REQUEST);
+    assert_eq!(header.identifier, socket.identifier());
+
+    let reply = echo(icmp::TYPE_ECHO_REPLY, socket.identifier(), 1, b"ping");
+    run(eth::handle(
+        &interface,
+        from_peer(ipv4::PROTOCOL_ICMP, reply),
+    ));
+
+    assert_eq!(other.try_receive(), None);
+    let reply = run(socket.receive());
+    assert_eq!((reply.source, reply.sequence, reply.len), (PEER_IP, 1, 4));
+    assert_eq!(socket.try_receive(), None);
+}
+
+#[test_case]
+fn replies_time_out() {
+    let socket = EchoSocket::open();
+    let start = time::ticks();
+    assert_eq!(block_on(time::timeout(2, socket.receive())), None);
+    assert!(time::ticks() >= start + 2);
+}
+
+#[test_case]
+fn unroutable_destinations() {
+    let (_wire, interface) = wire_to_peer();
+    let far = Ipv4Address::new(203, 0, 113, 1);
+
+    let send = ipv4::send_on(&interface, far, ipv4::PROTOCOL_UDP, PacketBuf::new());
+    assert_eq!(run(send), Err(NetError::NoRoute));
+
+    let mut large = PacketBuf::new();
+    large.extend_from_slice(&[0; 1500]);
+    let send = ipv4::send_on(&interface, PEER_IP, ipv4::PROTOCOL_UDP, large);
+    assert_eq!(run(send), Err(NetError::TooLarge));
+}
@@ -0,0 +1,65 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{rc::Rc, string::String};
+use core::cell::RefCell;
+use futures_util::stream::StreamExt;
+use rust_os_playground::task::{executor::Executor, keyboard, Task};
+
+rust_os_playground::kernel_test_main!(heap);
+
+fn type_scancodes(scancodes: &[u8]) {
+    for &scancode in scancodes {
+        keyboard::add_scancode(scancode);
+    }
+}
+
+// Scancode set 1: a key's release is its press with the top bit set, and
+// some keys are prefixed with 0xE0.
+const H: [u8; 2] = [0x23, 0xA3];
+const I: [u8; 2] = [0x17, 0x97];
+const SPACE: [u8; 2] = [0x39, 0xB9];
+const SHIFT_A: [u8; 4] = [0x2A, 0x1E, 0x9E, 0xAA];
+const ARROW_UP: [u8; 4] = [0xE0, 0x48, 0xE0, 0xC8];
+const ENTER: [u8; 2] = [0x1C, 0x9C];
+
+// The keyboard stream can only be taken once, so this is the only test.
+#[test_case]
+fn decodes_typed_characters() {
+    let typed = Rc::new(RefCell::new(String::new()));
+    let mut executor = Executor::new();
+
+    let collected = typed.clone();
+    executor.spawn(Task::new(async move {
+        let mut characters = keyboard::characters();
+        while let Some(character) = characters.next().await {
+            collected.borrow_mut().push(character);
+            if character == '\n' {
+                break;
+            }
+        }
+    }));
+
+    // Nothing typed yet, the task waits for input.
+    executor.run_until_idle();
+    assert_eq!(*typed.borrow(), "");
+
+    // Typing wakes it up.
+    type_scancodes(&H);
+    type_scancodes(&I);
+    executor.run_until_idle();
+    assert_eq!(*typed.borrow(), "hi");
+
+    // Shift changes the case, and keys without a character are dropped.
+    type_scancodes(&SPACE);
+    type_scancodes(&SHIFT_A);
+    type_scancodes(&ARROW_UP);
+    type_scancodes(&ENTER);
+    executor.run_until_idle();
+    assert_eq!(*typed.borrow(), "hi A\n");
+}
@@ -0,0 +1,106 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
+use common::{drive, Task};
+use core::task::{Context, Poll};
+use futures_util::task;
+use rust_os_playground::net::icmp::{self, EchoSocket};
+use rust_os_playground::net::loopback::{self, Loopback};
+use rust_os_playground::net::udp::{self, Endpoint, UdpSocket};
+use rust_os_playground::net::{self, Ipv4Address, NetDevice, PacketBuf};
+use rust_os_playground::shell;
+
+rust_os_playground::kernel_test_main!(heap);
+
+/// Registers a loopback interface, and returns the tasks of the stack on top,
+/// with an echo service on UDP port 7. It's the only configured interface, so
+/// that packets are routed through it.
+fn lo() -> Vec<Task> {
+    for interface in net::interfaces() {
+        interface.set_ipv4_config(None);
+    }
+
+    let name = loopback::register();
+    let interface = net::interface(&name).unwrap();
+    vec![Box::pin(net::run(interface)), Box::pin(udp::echo(7))]
+}
+
+#[test_case]
+fn frames_come_back() {
+    let device = Loopback::new();
+    assert!(device.is_loopback());
+    let mut context = Context::from_waker(task::noop_waker_ref());
+    assert!(device.receive().as_mut().poll(&mut context).is_pending());
+
+    for _ in 0..loopback::MAX_QUEUED + 1 {
+        let mut sent = device.send(PacketBuf::from_slice(b"frame"));
+        assert_eq!(sent.as_mut().poll(&mut context), Poll::Ready(Ok(())));
+    }
+
+    // Up to the limit, and in order
+    for _ in 0..loopback::MAX_QUEUED {
+        match device.receive().as_mut().poll(&mut context) {
+            Poll::Ready(frame) => assert_eq!(&frame[..], b"frame"),
+            Poll::Pending => panic!("frame is missing"),
+        }
+    }
+    assert!(device.receive().as_mut().poll(&mut context).is_pending());
+
+    let stats = device.stats().unwrap();
+    let sent = loopback::MAX_QUEUED as u64 + 1;
+    assert_eq!((stats.tx_packets, stats.tx_bytes), (sent, sent * 5));
+    assert_eq!((stats.rx_packets, stats.rx_dropped), (sent - 1, 1));
+}
+
+#[test_case]
+fn ifconfig_shows_the_interface() {
+    net::register_commands();
+    let name = loopback::register();
+
+    let mut out = String::new();
+    shell::execute(&format!("ifconfig {}", name), &mut out).unwrap();
+    assert!(out.starts_with(&format!("{}: up, mtu 1500\n", name)));
+    assert!(out.contains("    loopback\n    inet 127.0.0.1/8\n"));
+    assert!(out.contains("    rx 0 packets, 0 bytes, 0 errors, 0 dropped\n"));
+
+    out.clear();
+    shell::execute("ifconfig nothing0", &mut out).unwrap();
+    assert_eq!(out, "ifconfig: no interface nothing0\n");
+}
+
+#[test_case]
+fn udp_over_loopback() {
+    let mut tasks = lo();
+    let socket = UdpSocket::bind(0).unwrap();
+    let echo = Endpoint::new(Ipv4Address::LOOPBACK, 7);
+
+    drive(&mut tasks, socket.send_to(b"hello, me", echo)).unwrap();
+    let mut buf = [0; 64];
+    let (len, source) = drive(&mut tasks, socket.recv_from(&mut buf));
+    assert_eq!((&buf[..len], source), (&b"hello, me"[..], echo));
+}
+
+#[test_case]
+fn ping_over_loopback() {
+    let mut tasks = lo();
+    let socket = EchoSocket::open();
+    drive(&mut tasks, socket.send(Ipv4Address::LOOPBACK, 1, b"ping")).unwrap();
+    let reply = drive(&mut tasks, socket.receive());
+    assert_eq!(
+        (reply.source, reply.sequence, reply.len),
+        (Ipv4Address::LOOPBACK, 1, 4)
+    );
+
+    let mut out = String::new();
+    let ping = icmp::ping(&["ping", "127.0.0.1", "1"], &mut out);
+    drive(&mut tasks, ping).unwrap();
+    assert!(out.contains("1 packets transmitted, 1 received, 0% packet loss"));
+}
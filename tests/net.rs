@@ -0,0 +1,141 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, collections::VecDeque, format, sync::Arc, vec::Vec};
+use core::task::{Context, Poll};
+use futures_util::{future, task};
+use rust_os_playground::net::{self, buf, MacAddress, NetDevice, NetError, NetFuture, PacketBuf};
+use spin::Mutex;
+
+rust_os_playground::kernel_test_main!(heap);
+
+/// A device whose sent frames are received again.
+struct Echo {
+    frames: Mutex<VecDeque<PacketBuf>>,
+}
+
+impl NetDevice for Echo {
+    fn mac_address(&self) -> MacAddress {
+        MacAddress([2, 0, 0, 0, 0, 1])
+    }
+
+    fn mtu(&self) -> usize {
+        1500
+    }
+
+    fn send(&self, frame: PacketBuf) -> NetFuture<'_, Result<(), NetError>> {
+        if frame.len() > 1514 {
+            return Box::pin(future::ready(Err(NetError::TooLarge)));
+        }
+        self.frames.lock().push_back(frame);
+        Box::pin(future::ready(Ok(())))
+    }
+
+    fn receive(&self) -> NetFuture<'_, PacketBuf> {
+        Box::pin(future::poll_fn(move |_| {
+            match self.frames.lock().pop_front() {
+                Some(frame) => Poll::Ready(frame),
+                None => Poll::Pending,
+            }
+        }))
+    }
+}
+
+fn echo() -> Arc<Echo> {
+    Arc::new(Echo {
+        frames: Mutex::new(VecDeque::new()),
+    })
+}
+
+#[test_case]
+fn headers_are_pushed_and_pulled() {
+    let mut packet = PacketBuf::new();
+    assert!(packet.is_empty());
+    assert_eq!(packet.headroom(), buf::DEFAULT_HEADROOM);
+
+    packet.extend_from_slice(b"payload");
+    packet.push(4).copy_from_slice(b"ipv4");
+    let header = packet.push(3);
+    assert_eq!(header, [0, 0, 0]);
+    header.copy_from_slice(b"eth");
+    assert_eq!(&packet[..], b"ethipv4payload");
+    assert_eq!(packet.headroom(), buf::DEFAULT_HEADROOM - 7);
+    assert_eq!(packet.tailroom(), buf::CAPACITY - buf::DEFAULT_HEADROOM - 7);
+
+    assert_eq!(packet.pull(3), Some(&b"eth"[..]));
+    assert_eq!(packet.pull(4), Some(&b"ipv4"[..]));
+    assert_eq!(packet.pull(8), None);
+    assert_eq!(&packet[..], b"payload");
+
+    packet[0] = b'P';
+    packet.truncate(3);
+    assert_eq!(&packet[..], b"Pay");
+    packet.truncate(10);
+    assert_eq!(packet.len(), 3);
+
+    // Clones get the same headroom
+    let copy = packet.clone();
+    assert_eq!(
+        (copy.headroom(), &copy[..]),
+        (packet.headroom(), &packet[..])
+    );
+}
+
+#[test_case]
+fn buffers_are_reused() {
+    let packets: Vec<PacketBuf> = (0..3).map(|_| PacketBuf::new()).collect();
+    let pooled = buf::pooled();
+    drop(packets);
+    assert_eq!(buf::pooled(), pooled + 3);
+
+    let packet = PacketBuf::from_slice(&[1; 60]);
+    assert_eq!(buf::pooled(), pooled + 2);
+    assert_eq!(packet.headroom(), 0);
+
+    // The pool doesn't grow without bounds
+    let packets: Vec<PacketBuf> = (0..buf::POOL_SIZE + 8).map(|_| PacketBuf::new()).collect();
+    drop(packets);
+    assert_eq!(buf::pooled(), buf::POOL_SIZE);
+}
+
+#[test_case]
+fn mac_addresses() {
+    let mac = MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+    assert_eq!(format!("{}", mac), "52:54:00:12:34:56");
+    assert!(!mac.is_multicast());
+    assert!(MacAddress::BROADCAST.is_broadcast());
+    assert!(MacAddress::BROADCAST.is_multicast());
+}
+
+#[test_case]
+fn devices_are_registered_by_kind() {
+    let first = echo();
+    assert_eq!(net::register("echo", first.clone()), "echo0");
+    assert_eq!(net::register("echo", echo()), "echo1");
+
+    let names: Vec<_> = net::devices().into_iter().map(|(name, _)| name).collect();
+    assert_eq!(names, ["echo0", "echo1"]);
+    assert!(net::get("echo2").is_none());
+
+    // Frames go through the trait object
+    let device = net::get("echo0").unwrap();
+    let mut context = Context::from_waker(task::noop_waker_ref());
+    let mut receive = device.receive();
+    assert!(receive.as_mut().poll(&mut context).is_pending());
+
+    let send = device.send(PacketBuf::from_slice(b"frame"));
+    assert_eq!(
+        future::Future::poll(Box::pin(send).as_mut(), &mut context),
+        Poll::Ready(Ok(()))
+    );
+    match receive.as_mut().poll(&mut context) {
+        Poll::Ready(frame) => assert_eq!(&frame[..], b"frame"),
+        Poll::Pending => panic!("frame not received"),
+    }
+    assert_eq!(first.frames.lock().len(), 0);
+}
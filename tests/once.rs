@@ -0,0 +1,41 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rust_os_playground::sync::{Lazy, Once};
+use x86_64::instructions::interrupts;
+
+rust_os_playground::kernel_test_main!();
+
+#[test_case]
+fn initializes_once() {
+    let once = Once::new();
+    assert!(once.get().is_none());
+
+    assert_eq!(*once.call_once(|| 1), 1);
+    assert_eq!(*once.call_once(|| 2), 1);
+    assert_eq!(once.get(), Some(&1));
+}
+
+static CALLS: AtomicUsize = AtomicUsize::new(0);
+static LAZY: Lazy<usize> = Lazy::new(|| CALLS.fetch_add(1, Ordering::Relaxed) + 42);
+
+#[test_case]
+fn lazy_initializes_on_first_use() {
+    assert_eq!(CALLS.load(Ordering::Relaxed), 0);
+    assert_eq!(*LAZY, 42);
+    assert_eq!(*Lazy::force(&LAZY), 42);
+    assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+}
+
+#[test_case]
+fn initializes_with_interrupts_disabled() {
+    let once = Once::new();
+    interrupts::enable();
+
+    assert!(!*once.call_once(interrupts::are_enabled));
+    assert!(interrupts::are_enabled());
+}
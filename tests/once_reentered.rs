@@ -0,0 +1,13 @@
+#![no_std]
+#![no_main]
+
+use rust_os_playground::sync::Once;
+
+static ONCE: Once<u32> = Once::new();
+
+rust_os_playground::should_panic! {
+    /// Spinning here would hang the CPU for good, so `Once` panics instead.
+    fn reentering_panics() {
+        ONCE.call_once(|| *ONCE.call_once(|| 1) + 1);
+    }
+}
@@ -0,0 +1,163 @@
+// Enumerates the PCI bus of the test machine and sets up MSIs for QEMU's "edu"
+// test device, which Cargo.toml adds to the machine. The edu device raises an
+// interrupt when something is written to its "raise interrupt" register, which
+// makes it easy to check that a message really arrives.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use rust_os_playground::device;
+use rust_os_playground::interrupts::{self, DYNAMIC_VECTORS, FIRST_DYNAMIC_VECTOR};
+use rust_os_playground::memory;
+use rust_os_playground::pci::{self, Address, Bar, Device, PciError};
+use rust_os_playground::time;
+use x86_64::PhysAddr;
+
+const EDU_VENDOR_ID: u16 = 0x1234;
+const EDU_DEVICE_ID: u16 = 0x11E8;
+
+// Registers of the edu device, in BAR 0
+const EDU_LIVENESS: u64 = 0x04;
+const EDU_INTERRUPT_STATUS: u64 = 0x24;
+const EDU_RAISE_INTERRUPT: u64 = 0x60;
+const EDU_ACKNOWLEDGE_INTERRUPT: u64 = 0x64;
+
+rust_os_playground::kernel_test_main!(heap, pci);
+
+fn edu() -> Device {
+    pci::find(EDU_VENDOR_ID, EDU_DEVICE_ID).expect("no edu device")
+}
+
+// Where the edu device's registers are mapped, for the interrupt handler
+static EDU_REGISTERS: AtomicU64 = AtomicU64::new(0);
+static EDU_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+fn edu_read(register: u64) -> u32 {
+    let address = EDU_REGISTERS.load(Ordering::Relaxed) + register;
+    unsafe { ptr::read_volatile(address as *const u32) }
+}
+
+fn edu_write(register: u64, value: u32) {
+    let address = EDU_REGISTERS.load(Ordering::Relaxed) + register;
+    unsafe { ptr::write_volatile(address as *mut u32, value) };
+}
+
+fn edu_interrupt() {
+    EDU_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    edu_write(EDU_ACKNOWLEDGE_INTERRUPT, edu_read(EDU_INTERRUPT_STATUS));
+}
+
+#[test_case]
+fn finds_devices() {
+    let devices = pci::devices();
+
+    // The host bridge is always there
+    let host_bridge = Device::probe(Address::new(0, 0, 0)).unwrap();
+    assert_eq!((host_bridge.class, host_bridge.subclass), (0x06, 0x00));
+    assert_eq!(devices.first(), Some(&host_bridge));
+
+    let addresses: Vec<Address> = devices.iter().map(|device| device.address).collect();
+    let mut sorted = addresses.clone();
+    sorted.sort();
+    assert_eq!(addresses, sorted);
+
+    assert!(devices.contains(&edu()));
+    assert_eq!(pci::find(0xFFFF, 0xFFFF), None);
+}
+
+#[test_case]
+fn adds_devices_to_the_tree() {
+    // QEMU's machine has everything on bus 0
+    let buses = device::children(None);
+    assert_eq!(buses.len(), 1);
+    assert_eq!(device::get(buses[0]).unwrap().name(), "PCI bus 0");
+
+    let functions = device::children(Some(buses[0]));
+    assert_eq!(functions.len(), pci::devices().len());
+    let names: Vec<String> = functions
+        .iter()
+        .map(|&id| device::get(id).unwrap().name())
+        .collect();
+    assert!(names.iter().any(|name| name == "QEMU edu"), "{:?}", names);
+    assert_eq!(device::parent(functions[0]), Some(buses[0]));
+}
+
+#[test_case]
+fn reads_bars_and_capabilities() {
+    let edu = edu();
+
+    match edu.bar(0) {
+        Some(Bar::Memory { address, size, .. }) => {
+            assert_ne!(address, 0);
+            assert_eq!(size, 1 << 20);
+        }
+        bar => panic!("unexpected BAR 0: {:?}", bar),
+    }
+    assert_eq!(edu.bar(1), None);
+    assert_eq!(edu.bar(6), None);
+
+    let capabilities: Vec<u8> = edu.capabilities().map(|capability| capability.id).collect();
+    assert!(capabilities.contains(&pci::CAPABILITY_MSI));
+    assert!(edu.find_capability(pci::CAPABILITY_MSI).unwrap() >= 0x40);
+    assert_eq!(edu.find_capability(pci::CAPABILITY_MSIX), None);
+    assert_eq!(edu.msix_vectors(), None);
+    assert_eq!(
+        edu.enable_msix(&[edu_interrupt]),
+        Err(PciError::NoCapability)
+    );
+}
+
+#[test_case]
+fn msis_run_the_handler() {
+    let edu = edu();
+    let base = match edu.bar(0) {
+        Some(Bar::Memory { address, .. }) => address,
+        bar => panic!("unexpected BAR 0: {:?}", bar),
+    };
+    EDU_REGISTERS.store(
+        memory::phys_to_virt(PhysAddr::new(base)).as_u64(),
+        Ordering::Relaxed,
+    );
+
+    let vector = edu.enable_msi(edu_interrupt).unwrap();
+    assert!((FIRST_DYNAMIC_VECTOR..FIRST_DYNAMIC_VECTOR + DYNAMIC_VECTORS as u8).contains(&vector));
+
+    // The BAR is decoded now
+    edu_write(EDU_LIVENESS, 0x1234_5678);
+    assert_eq!(edu_read(EDU_LIVENESS), !0x1234_5678);
+
+    for expected in 1..=3 {
+        edu_write(EDU_RAISE_INTERRUPT, 1);
+
+        let deadline = time::ticks() + 10;
+        while EDU_INTERRUPTS.load(Ordering::Relaxed) < expected && time::ticks() < deadline {
+            x86_64::instructions::hlt();
+        }
+        assert_eq!(EDU_INTERRUPTS.load(Ordering::Relaxed), expected);
+    }
+    assert_eq!(interrupts::vector_count(vector), 3);
+    assert_eq!(edu_read(EDU_INTERRUPT_STATUS), 0);
+}
+
+#[test_case]
+fn vectors_run_out() {
+    let mut vectors = Vec::new();
+    while let Some(vector) = interrupts::allocate_vector(edu_interrupt) {
+        vectors.push(vector);
+    }
+    // The edu device holds on to one of them
+    assert_eq!(vectors.len(), DYNAMIC_VECTORS - 1);
+
+    vectors.iter().copied().for_each(interrupts::free_vector);
+    let vector = interrupts::allocate_vector(edu_interrupt).unwrap();
+    assert!(vectors.contains(&vector));
+    interrupts::free_vector(vector);
+}
@@ -0,0 +1,44 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use rust_os_playground::{cpu, interrupts, per_cpu};
+
+rust_os_playground::kernel_test_main!();
+
+per_cpu! {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    pub static LIMIT: u64 = 10;
+}
+
+#[test_case]
+fn each_cpu_has_its_own() {
+    COUNTER.get().fetch_add(3, Ordering::Relaxed);
+
+    let this = COUNTER.for_cpu(cpu::index()).unwrap();
+    assert_eq!(this.load(Ordering::Relaxed), 3);
+    let total: u64 = COUNTER.iter().map(|c| c.load(Ordering::Relaxed)).sum();
+    assert_eq!(total, 3);
+
+    assert_eq!(LIMIT.iter().count(), cpu::MAX_CPUS);
+    assert_eq!(*LIMIT.get(), 10);
+    assert!(LIMIT.for_cpu(cpu::MAX_CPUS).is_none());
+}
+
+#[test_case]
+fn counts_timer_interrupts() {
+    assert!(!interrupts::in_interrupt());
+
+    // The timer is IRQ 0. Other interrupts may wake us first.
+    let ticks = interrupts::irq_count(0);
+    for _ in 0..100 {
+        x86_64::instructions::hlt();
+        if interrupts::irq_count(0) > ticks {
+            return;
+        }
+    }
+    panic!("the timer interrupt wasn't counted");
+}
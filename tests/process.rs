@@ -0,0 +1,149 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use common::{executable, run_to_exit, Asm, Reg};
+use rust_os_playground::memory::address_space::{AddressSpaceError, USER_START};
+use rust_os_playground::process::{self, Pid, Process, SpawnError, WaitError};
+use rust_os_playground::{memory, syscall};
+
+rust_os_playground::kernel_test_main!(memory, process);
+
+#[test_case]
+fn process_runs_in_user_mode() {
+    // jmp $
+    let pid = Process::spawn(&executable(USER_START, &[0xEB, 0xFE], &[])).unwrap();
+
+    // The process gets preempted by the timer while spinning in ring 3, which
+    // counts as one user tick.
+    for _ in 0..100 {
+        process::yield_now();
+        if process::user_ticks(pid).unwrap() > 0 {
+            return;
+        }
+        x86_64::instructions::hlt();
+    }
+
+    panic!("process never ran in user mode");
+}
+
+#[test_case]
+fn spawn_rejects_invalid_executables() {
+    assert_eq!(
+        Process::spawn(b"not an executable").err(),
+        Some(SpawnError::Elf(rust_os_playground::elf::ElfError::TooShort))
+    );
+    assert_eq!(
+        Process::spawn(&executable(0x20_0000, &[0xEB, 0xFE], &[])).err(),
+        Some(SpawnError::BadEntryPoint)
+    );
+}
+
+#[test_case]
+fn spawn_rejects_kernel_addresses() {
+    // The entry point is fine, but the segment reaches below the user range.
+    let mut elf = executable(USER_START, &[0xEB, 0xFE], &[]);
+    elf[64 + 16..64 + 24].copy_from_slice(&(USER_START - 4096).to_le_bytes());
+
+    assert_eq!(
+        Process::spawn(&elf).err(),
+        Some(SpawnError::Memory(AddressSpaceError::OutsideUserSpace))
+    );
+}
+
+#[test_case]
+fn wait_returns_exit_code() {
+    let code = Asm::new()
+        .mov(Reg::Rax, syscall::number::EXIT)
+        .mov(Reg::Rdi, 3)
+        .syscall()
+        .spin();
+    let elf = executable(USER_START, &code, &[]);
+
+    let pid = Process::spawn(&elf).unwrap();
+    assert_eq!(run_to_exit(pid), 3);
+
+    // Reaped
+    assert_eq!(process::state(pid), None);
+    assert_eq!(process::wait(pid), Err(WaitError::NoSuchProcess));
+}
+
+#[test_case]
+fn exited_processes_free_their_memory() {
+    let code = Asm::new()
+        .mov(Reg::Rax, syscall::number::EXIT)
+        .mov(Reg::Rdi, 0)
+        .syscall()
+        .spin();
+    let elf = executable(USER_START, &code, &[]);
+
+    // The first process may allocate page tables that stay around for good.
+    run_to_exit(Process::spawn(&elf).unwrap());
+    let frames = memory::allocated_frames();
+
+    for _ in 0..10 {
+        run_to_exit(Process::spawn(&elf).unwrap());
+    }
+
+    assert_eq!(memory::allocated_frames(), frames);
+}
+
+#[test_case]
+fn wait_only_for_children() {
+    // The kernel has no parent
+    assert_eq!(process::wait(Pid::KERNEL), Err(WaitError::NotAChild));
+}
+
+#[test_case]
+fn argc_is_on_the_stack() {
+    // exit(argc)
+    let code = Asm::new()
+        .pop_rdi()
+        .mov(Reg::Rax, syscall::number::EXIT)
+        .syscall()
+        .spin();
+    let elf = executable(USER_START, &code, &[]);
+
+    let pid = Process::spawn_with_args(&elf, &["prog", "a", "b"], &["HOME=/"]).unwrap();
+    assert_eq!(run_to_exit(pid), 3);
+
+    let pid = Process::spawn(&elf).unwrap();
+    assert_eq!(run_to_exit(pid), 0);
+}
+
+#[test_case]
+fn spawn_rejects_long_arguments() {
+    let elf = executable(USER_START, &[0xEB, 0xFE], &[]);
+    let arg = "x".repeat(process::ARGS_MAX as usize);
+
+    assert_eq!(
+        Process::spawn_with_args(&elf, &[&arg], &[]).err(),
+        Some(SpawnError::ArgumentsTooLong)
+    );
+}
+
+#[test_case]
+fn detached_processes_are_reaped() {
+    let code = Asm::new()
+        .mov(Reg::Rax, syscall::number::EXIT)
+        .mov(Reg::Rdi, 0)
+        .syscall()
+        .spin();
+    let pid = Process::spawn(&executable(USER_START, &code, &[])).unwrap();
+    process::detach(pid).unwrap();
+    assert_eq!(process::wait(pid), Err(WaitError::NotAChild));
+
+    for _ in 0..100 {
+        process::yield_now();
+        if process::state(pid).is_none() {
+            return;
+        }
+    }
+    panic!("detached process wasn't reaped");
+}
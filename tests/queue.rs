@@ -0,0 +1,72 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rust_os_playground::sync::{mpsc, spsc};
+
+rust_os_playground::kernel_test_main!();
+
+static SPSC: spsc::Queue<u32, 4> = spsc::Queue::new();
+
+#[test_case]
+fn spsc_is_fifo_and_bounded() {
+    for lap in 0..3 {
+        for i in 0..4 {
+            assert_eq!(SPSC.push(lap * 10 + i), Ok(()));
+        }
+        assert!(SPSC.is_full());
+        assert_eq!(SPSC.push(99), Err(99));
+
+        for i in 0..4 {
+            assert_eq!(SPSC.pop(), Some(lap * 10 + i));
+        }
+        assert_eq!(SPSC.pop(), None);
+        assert!(SPSC.is_empty());
+    }
+}
+
+#[test_case]
+fn mpsc_is_fifo_and_bounded() {
+    let queue = mpsc::Queue::<u32, 3>::new();
+
+    for lap in 0..3 {
+        for i in 0..3 {
+            assert_eq!(queue.push(lap * 10 + i), Ok(()));
+        }
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.push(99), Err(99));
+
+        assert_eq!(queue.pop(), Some(lap * 10));
+        assert_eq!(queue.push(lap * 10 + 3), Ok(()));
+        for i in 1..4 {
+            assert_eq!(queue.pop(), Some(lap * 10 + i));
+        }
+        assert_eq!(queue.pop(), None);
+    }
+}
+
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug)]
+struct Counted;
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test_case]
+fn drops_what_is_left() {
+    {
+        let queue = mpsc::Queue::<Counted, 4>::new();
+        queue.push(Counted).unwrap();
+        queue.push(Counted).unwrap();
+        drop(queue.pop());
+        assert_eq!(DROPPED.load(Ordering::Relaxed), 1);
+    }
+    assert_eq!(DROPPED.load(Ordering::Relaxed), 2);
+}
@@ -0,0 +1,125 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec::Vec};
+use rust_os_playground::fs::{
+    self, ramfs::RamFs, DirEntry, FileSystem, FileType, FsError, Metadata,
+};
+
+rust_os_playground::kernel_test_main!(heap, fs);
+
+fn names(path: &str) -> Vec<alloc::string::String> {
+    fs::read_dir(path)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect()
+}
+
+#[test_case]
+fn files_and_directories() {
+    let fs = RamFs::new();
+
+    fs.create_dir("/a").unwrap();
+    fs.create_file("/a/b").unwrap();
+    assert_eq!(fs.create_file("/a/b"), Err(FsError::AlreadyExists));
+    assert_eq!(fs.create_file("/missing/b"), Err(FsError::NotFound));
+    assert_eq!(fs.create_file("/a/b/c"), Err(FsError::NotADirectory));
+    assert_eq!(fs.create_dir("/"), Err(FsError::InvalidPath));
+
+    assert_eq!(fs.write("/a/b", 0, b"hello").unwrap(), 5);
+    // Writing past the end leaves a zeroed gap
+    fs.write("/a/b", 7, b"!").unwrap();
+    assert_eq!(fs.read_file("/a/b").unwrap(), b"hello\0\0!");
+    assert_eq!(fs.metadata("/a/b").unwrap().size, 8);
+
+    let mut buf = [0; 4];
+    assert_eq!(fs.read("/a/b", 3, &mut buf).unwrap(), 4);
+    assert_eq!(&buf, b"lo\0\0");
+    assert_eq!(fs.read("/a/b", 100, &mut buf).unwrap(), 0);
+
+    fs.truncate("/a/b", 2).unwrap();
+    assert_eq!(fs.read_file("/a/b").unwrap(), b"he");
+
+    // A file can't grow without bounds
+    assert_eq!(fs.write("/a/b", 1 << 40, b"!"), Err(FsError::FileTooLarge));
+    assert_eq!(fs.write("/a/b", u64::MAX, b"!"), Err(FsError::FileTooLarge));
+    assert_eq!(fs.truncate("/a/b", 1 << 40), Err(FsError::FileTooLarge));
+    assert_eq!(fs.metadata("/a/b").unwrap().size, 2);
+
+    let entries = fs.read_dir("/a").unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].name, "b");
+    assert_eq!(entries[0].metadata.file_type, FileType::File);
+    assert_eq!(fs.read_dir("/a/b").err(), Some(FsError::NotADirectory));
+    assert_eq!(fs.read_file("/a").err(), Some(FsError::IsADirectory));
+
+    assert_eq!(fs.remove("/a"), Err(FsError::NotEmpty));
+    fs.remove("/a/b").unwrap();
+    fs.remove("/a").unwrap();
+    assert_eq!(fs.metadata("/a").err(), Some(FsError::NotFound));
+}
+
+#[test_case]
+fn tmp_is_mounted() {
+    assert_eq!(names("/"), ["tmp"]);
+    assert_eq!(fs::metadata("/").unwrap().file_type, FileType::Directory);
+
+    fs::create_file("/tmp/note").unwrap();
+    fs::write("/tmp/note", 0, b"remember").unwrap();
+    assert_eq!(fs::read_file("//tmp/./note").unwrap(), b"remember");
+    assert_eq!(names("/tmp"), ["note"]);
+
+    assert_eq!(fs::read_file("/elsewhere").err(), Some(FsError::NotFound));
+    assert_eq!(fs::remove("/tmp"), Err(FsError::InvalidPath));
+    fs::remove("/tmp/note").unwrap();
+}
+
+#[test_case]
+fn nested_mounts() {
+    fs::create_dir("/tmp/mnt").unwrap();
+    fs::mount("/tmp/mnt/", Arc::new(RamFs::new())).unwrap();
+    assert_eq!(
+        fs::mount("/tmp/mnt", Arc::new(RamFs::new())),
+        Err(FsError::AlreadyExists)
+    );
+
+    // The longest mount path wins.
+    fs::create_file("/tmp/mnt/inner").unwrap();
+    assert_eq!(names("/tmp/mnt"), ["inner"]);
+
+    fs::unmount("/tmp/mnt").unwrap();
+    assert!(names("/tmp/mnt").is_empty());
+    fs::remove("/tmp/mnt").unwrap();
+    assert_eq!(fs::mounts(), ["/tmp"]);
+}
+
+/// A filesystem with one file that says it's bigger than any heap.
+struct Huge;
+
+impl FileSystem for Huge {
+    fn metadata(&self, _path: &str) -> Result<Metadata, FsError> {
+        Ok(Metadata {
+            file_type: FileType::File,
+            size: 1 << 46,
+        })
+    }
+
+    fn read_dir(&self, _path: &str) -> Result<Vec<DirEntry>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn read(&self, _path: &str, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(0)
+    }
+}
+
+#[test_case]
+fn reading_a_huge_file_fails() {
+    assert_eq!(Huge.read_file("/file").err(), Some(FsError::NoMemory));
+}
@@ -0,0 +1,53 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use rust_os_playground::rand;
+
+rust_os_playground::kernel_test_main!();
+
+/// The test vector of RFC 8439, section 2.3.2.
+#[test_case]
+fn chacha20_block_function() {
+    let mut key = [0; 8];
+    for (i, word) in key.iter_mut().enumerate() {
+        let i = 4 * i as u32;
+        *word = u32::from_le_bytes([i as u8, i as u8 + 1, i as u8 + 2, i as u8 + 3]);
+    }
+    let nonce = [0x0900_0000, 0x4a00_0000, 0];
+
+    let block = rand::chacha20_block(&key, 1, &nonce);
+    assert_eq!(
+        block,
+        [
+            0xe4e7_f110,
+            0x1559_3bd1,
+            0x1fdd_0f50,
+            0xc471_20a3,
+            0xc7f4_d1c7,
+            0x0368_c033,
+            0x9aaa_2204,
+            0x4e6c_d4c3,
+            0x4664_82d2,
+            0x09aa_9f07,
+            0x05d7_c214,
+            0xa202_8bd9,
+            0xd19c_12b5,
+            0xb94e_16de,
+            0xe883_d0cb,
+            0x4e3c_50a2,
+        ]
+    );
+}
+
+#[test_case]
+fn fills_with_different_bytes() {
+    let (mut one, mut other) = ([0u8; 100], [0u8; 100]);
+    rand::fill(&mut one);
+    rand::fill(&mut other);
+    assert_ne!(one, other);
+    assert!(one.iter().any(|byte| *byte != 0));
+    assert_ne!(rand::next_u64(), rand::next_u64());
+}
@@ -0,0 +1,47 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use common::executable;
+use rust_os_playground::memory::address_space::USER_START;
+use rust_os_playground::process::{self, Process, TIME_SLICE_TICKS};
+
+rust_os_playground::kernel_test_main!(memory, process);
+
+#[test_case]
+fn spinning_processes_share_the_cpu() {
+    // jmp $
+    let spinner = executable(USER_START, &[0xEB, 0xFE], &[]);
+    let a = Process::spawn(&spinner).unwrap();
+    let b = Process::spawn(&spinner).unwrap();
+
+    for _ in 0..1000 {
+        process::yield_now();
+
+        let a = process::accounting(a).unwrap();
+        let b = process::accounting(b).unwrap();
+        if a.preemptions < 3 || b.preemptions < 3 {
+            continue;
+        }
+
+        // Round robin: neither gets more than a slice ahead of the other.
+        assert!(a.user_ticks.abs_diff(b.user_ticks) <= TIME_SLICE_TICKS);
+
+        // A spinner only ever leaves the CPU when its slice is used up.
+        for process in [a, b].iter() {
+            let ticks = process.user_ticks + process.kernel_ticks;
+            assert!(ticks >= process.preemptions * TIME_SLICE_TICKS);
+            assert!(ticks <= (process.preemptions + 1) * TIME_SLICE_TICKS);
+            assert!(process.dispatches >= process.preemptions);
+        }
+        return;
+    }
+
+    panic!("processes weren't preempted");
+}
@@ -0,0 +1,65 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, task::Wake};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use rust_os_playground::sync::Semaphore;
+
+rust_os_playground::kernel_test_main!(heap);
+
+/// Counts how often it was woken.
+struct CountingWaker(AtomicUsize);
+
+impl Wake for CountingWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn counting_waker() -> (Arc<CountingWaker>, Waker) {
+    let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+    (counter.clone(), Waker::from(counter))
+}
+
+#[test_case]
+fn hands_out_at_most_its_permits() {
+    let semaphore = Semaphore::new(2);
+
+    let first = semaphore.acquire();
+    let second = semaphore.try_acquire().expect("a permit is free");
+    assert_eq!(semaphore.available_permits(), 0);
+    assert!(semaphore.try_acquire().is_none());
+
+    drop(first);
+    assert_eq!(semaphore.available_permits(), 1);
+    second.forget();
+    assert_eq!(semaphore.available_permits(), 1);
+}
+
+#[test_case]
+fn async_acquire_waits_for_a_permit() {
+    let semaphore = Semaphore::new(1);
+    let held = semaphore.acquire();
+    let (counter, waker) = counting_waker();
+    let mut context = Context::from_waker(&waker);
+
+    let mut acquire = semaphore.acquire_async();
+    assert!(Pin::new(&mut acquire).poll(&mut context).is_pending());
+    assert_eq!(counter.0.load(Ordering::Relaxed), 0);
+
+    drop(held);
+    assert_eq!(counter.0.load(Ordering::Relaxed), 1);
+    match Pin::new(&mut acquire).poll(&mut context) {
+        Poll::Ready(permit) => drop(permit),
+        Poll::Pending => panic!("the permit was given back"),
+    }
+    assert_eq!(semaphore.available_permits(), 1);
+}
@@ -0,0 +1,55 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use rust_os_playground::sync::SeqLock;
+use rust_os_playground::time;
+use x86_64::instructions::interrupts;
+
+rust_os_playground::kernel_test_main!();
+
+#[test_case]
+fn reads_what_was_written() {
+    let lock = SeqLock::new((1u64, 1u64));
+    assert_eq!(lock.read(), (1, 1));
+
+    lock.write(|pair| *pair = (2, 3));
+    assert_eq!(lock.read(), (2, 3));
+}
+
+#[test_case]
+fn writes_with_interrupts_disabled() {
+    let lock = SeqLock::new(false);
+    interrupts::enable();
+
+    lock.write(|enabled| *enabled = interrupts::are_enabled());
+    assert!(!lock.read());
+    assert!(interrupts::are_enabled());
+}
+
+#[test_case]
+fn now_follows_the_ticks() {
+    let start = time::ticks();
+    while time::ticks() < start + 2 {
+        x86_64::instructions::hlt();
+    }
+
+    // No tick can come in between the two.
+    let (now, uptime_ms) = interrupts::without_interrupts(|| (time::now(), time::uptime_ms()));
+    let now_ms = now.as_millis() as u64;
+    assert!(now_ms >= uptime_ms);
+    assert!(now_ms < uptime_ms + 1000 / time::TIMER_HZ);
+}
+
+#[test_case]
+fn now_never_goes_backwards() {
+    let mut last = time::now();
+    let start = time::ticks();
+    while time::ticks() < start + 3 {
+        let now = time::now();
+        assert!(now >= last);
+        last = now;
+    }
+}
@@ -0,0 +1,136 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{format, rc::Rc, string::String};
+use core::cell::{Cell, RefCell};
+use core::fmt::{self, Write};
+use rust_os_playground::task::{executor, executor::Executor, Task};
+use rust_os_playground::{fs, shell, time, tty};
+
+rust_os_playground::kernel_test_main!(heap, fs => {
+    fs::register_commands();
+    time::register_commands();
+    executor::register_commands();
+});
+
+fn run(line: &str) -> String {
+    let mut out = String::new();
+    shell::execute(line, &mut out).unwrap();
+    out
+}
+
+fn test_echo(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    writeln!(out, "{}", args[1..].join(" "))
+}
+
+#[test_case]
+fn registered_command_runs() {
+    shell::register("test_echo", "echo the arguments", test_echo);
+
+    assert_eq!(run("test_echo  hello   world"), "hello world\n");
+}
+
+#[test_case]
+fn help_lists_commands() {
+    shell::register("test_help", "a command for the help test", test_echo);
+
+    assert!(run("help").contains("a command for the help test"));
+}
+
+#[test_case]
+fn unknown_command() {
+    assert!(run("no_such_command").starts_with("unknown command"));
+    assert_eq!(run("   "), "");
+}
+
+#[test_case]
+fn file_commands() {
+    assert_eq!(run("mkdir /tmp/shell"), "");
+    assert_eq!(run("echo hello   there > /tmp/shell/greeting"), "");
+    assert_eq!(run("echo again >> /tmp/shell/greeting"), "");
+    assert_eq!(run("cat /tmp/shell/greeting"), "hello there\nagain\n");
+    assert_eq!(run("echo replaced > /tmp/shell/greeting"), "");
+    assert_eq!(run("cat /tmp/shell/greeting"), "replaced\n");
+
+    assert_eq!(
+        run("ls /tmp/shell"),
+        alloc::format!("{:<24} 9\n", "greeting")
+    );
+    assert!(run("ls /tmp").contains("shell/"));
+    assert_eq!(
+        run("cat /tmp/missing"),
+        "cat: /tmp/missing: no such file or directory\n"
+    );
+    assert!(run("echo oops >").starts_with("usage"));
+}
+
+#[test_case]
+fn burn_goes_over_the_poll_budget() {
+    let overruns = executor::overruns();
+    let done = Rc::new(Cell::new(false));
+    let mut executor = Executor::new();
+
+    let finished = done.clone();
+    executor.spawn(Task::new(async move {
+        let mut out = String::new();
+        let start = time::ticks();
+        shell::execute_async("sleep 30", &mut out).await.unwrap();
+        assert!(time::ticks() >= start + time::ms_to_ticks(30));
+        assert_eq!(executor::overruns(), overruns);
+
+        shell::execute_async("burn 50", &mut out).await.unwrap();
+        assert_eq!(out, "");
+        finished.set(true);
+    }));
+
+    let deadline = time::ticks() + time::TIMER_HZ;
+    while !done.get() {
+        assert!(time::ticks() < deadline, "sleep didn't wake up");
+        executor.run_until_idle();
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(executor::overruns(), overruns + 1);
+    assert!(run("burn 1000000").starts_with("burn: at most"));
+}
+
+#[test_case]
+fn more_pages_with_keys() {
+    let text: String = (1..=30).map(|i| format!("line {}\n", i)).collect();
+    fs::create_file("/tmp/more.txt").unwrap();
+    fs::write("/tmp/more.txt", 0, text.as_bytes()).unwrap();
+
+    let out = Rc::new(RefCell::new(String::new()));
+    let done = Rc::new(Cell::new(false));
+    let mut executor = Executor::new();
+
+    let (output, finished) = (out.clone(), done.clone());
+    executor.spawn(Task::new(async move {
+        let mut out = String::new();
+        shell::execute_async("more /tmp/more.txt", &mut out)
+            .await
+            .unwrap();
+        *output.borrow_mut() = out;
+        finished.set(true);
+    }));
+
+    // A screen less the prompt, then a line at a time until q
+    executor.run_until_idle();
+    assert!(!done.get());
+    assert_eq!(tty::mode(), tty::Mode::Raw);
+    for key in ['x', '\r', 'q'] {
+        tty::input(key);
+        executor.run_until_idle();
+    }
+    assert!(done.get());
+    assert_eq!(tty::mode(), tty::Mode::Canonical);
+
+    let out = out.borrow();
+    assert!(out.contains("line 24\n--More--(80%)"));
+    assert!(out.contains("line 25\n--More--(83%)"));
+    assert!(!out.contains("line 26"));
+}
@@ -0,0 +1,112 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use common::{executable, run_to_exit, Asm, Reg};
+use rust_os_playground::memory;
+use rust_os_playground::memory::address_space::USER_START;
+use rust_os_playground::process::Process;
+use rust_os_playground::shm::{self, ShmError, ShmId};
+use rust_os_playground::syscall::{errno, number};
+
+rust_os_playground::kernel_test_main!(memory, process);
+
+/// Runs a program that maps the region, then runs `then` with the address in rax.
+fn run_with_mapping(id: ShmId, then: impl FnOnce(Asm) -> Asm) -> i64 {
+    let asm = Asm::new()
+        .mov(Reg::Rax, number::SHM_MAP)
+        .mov(Reg::Rdi, id.as_u64())
+        .syscall();
+    let code = then(asm).mov(Reg::Rax, number::EXIT).syscall().spin();
+
+    let pid = Process::spawn(&executable(USER_START, &code, &[])).unwrap();
+    run_to_exit(pid)
+}
+
+#[test_case]
+fn process_reads_kernel_data() {
+    let id = shm::create(8192).unwrap();
+    shm::get(id).unwrap().write(4100, &[42]);
+
+    // exit(mapping[4100])
+    let code = run_with_mapping(id, |asm| asm.load_byte_rdi_from_rax(4100));
+    assert_eq!(code, 42);
+
+    shm::destroy(id).unwrap();
+}
+
+#[test_case]
+fn kernel_reads_process_data() {
+    let id = shm::create(4096).unwrap();
+
+    // mapping[200] = 7; exit(0)
+    let code = run_with_mapping(id, |asm| asm.store_byte_to_rax(200, 7).mov(Reg::Rdi, 0));
+    assert_eq!(code, 0);
+
+    let mut byte = [0];
+    shm::get(id).unwrap().read(200, &mut byte);
+    assert_eq!(byte, [7]);
+
+    shm::destroy(id).unwrap();
+}
+
+#[test_case]
+fn memory_is_freed_after_last_unmap() {
+    // shm_map(shm_create(8192))[0] = 1; exit(0)
+    let code = Asm::new()
+        .mov(Reg::Rax, number::SHM_CREATE)
+        .mov(Reg::Rdi, 8192)
+        .syscall()
+        .mov_rdi_rax()
+        .mov(Reg::Rax, number::SHM_MAP)
+        .syscall()
+        .store_byte_to_rax(0, 1)
+        .mov(Reg::Rax, number::EXIT)
+        .mov(Reg::Rdi, 0)
+        .syscall()
+        .spin();
+    let elf = executable(USER_START, &code, &[]);
+
+    run_to_exit(Process::spawn(&elf).unwrap());
+    let frames = memory::allocated_frames();
+
+    for _ in 0..5 {
+        assert_eq!(run_to_exit(Process::spawn(&elf).unwrap()), 0);
+    }
+
+    assert_eq!(memory::allocated_frames(), frames);
+}
+
+#[test_case]
+fn mapping_outlives_handle() {
+    let id = shm::create(4096).unwrap();
+    let memory = shm::get(id).unwrap();
+    shm::destroy(id).unwrap();
+
+    memory.write(0, b"still here");
+    assert!(shm::get(id).is_none());
+    assert_eq!(shm::map(id), Err(ShmError::NoSuchRegion));
+}
+
+#[test_case]
+fn errors() {
+    assert_eq!(shm::create(0), Err(ShmError::InvalidSize));
+    assert_eq!(
+        shm::create(memory::shared::MAX_SIZE + 1),
+        Err(ShmError::InvalidSize)
+    );
+
+    // The kernel has nowhere to map it
+    let id = shm::create(4096).unwrap();
+    assert_eq!(shm::map(id), Err(ShmError::NoAddressSpace));
+    shm::destroy(id).unwrap();
+
+    let code = run_with_mapping(ShmId::from_u64(u64::MAX), |asm| asm.mov_rdi_rax());
+    assert_eq!(code, -errno::EINVAL);
+}
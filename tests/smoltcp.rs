@@ -0,0 +1,149 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use common::{drive, Task};
+use core::panic::PanicInfo;
+use core::task::Poll;
+use futures_util::future;
+use rust_os_playground::allocator;
+use rust_os_playground::net::icmp::EchoSocket;
+use rust_os_playground::net::ipv4::Config;
+use rust_os_playground::net::udp::{self, Endpoint};
+use rust_os_playground::net::{
+    self, smol, Interface, Ipv4Address, MacAddress, NetDevice, NetError, NetFuture, PacketBuf,
+};
+use spin::Mutex;
+
+rust_os_playground::kernel_test_main!(heap);
+
+const OUR_IP: Ipv4Address = Ipv4Address::new(192, 168, 9, 1);
+const SMOLTCP_IP: Ipv4Address = Ipv4Address::new(192, 168, 9, 2);
+
+type Frames = Arc<Mutex<VecDeque<PacketBuf>>>;
+
+/// One end of a cable: what it sends, the other end receives.
+struct End {
+    mac: MacAddress,
+    inbox: Frames,
+    outbox: Frames,
+}
+
+impl NetDevice for End {
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn mtu(&self) -> usize {
+        1500
+    }
+
+    fn send(&self, frame: PacketBuf) -> NetFuture<'_, Result<(), NetError>> {
+        self.outbox.lock().push_back(frame);
+        Box::pin(future::ready(Ok(())))
+    }
+
+    fn receive(&self) -> NetFuture<'_, PacketBuf> {
+        Box::pin(future::poll_fn(move |_| {
+            match self.inbox.lock().pop_front() {
+                Some(frame) => Poll::Ready(frame),
+                None => Poll::Pending,
+            }
+        }))
+    }
+}
+
+/// Registers the two ends of a cable on 192.168.9.0/24, one for our stack and
+/// one for smoltcp. Ours is the only other configured interface, so that our
+/// packets are routed through it.
+fn cable() -> (Arc<Interface>, Arc<Interface>) {
+    for interface in net::interfaces() {
+        interface.set_ipv4_config(None);
+    }
+
+    let (one_way, other_way) = (Frames::default(), Frames::default());
+    let ours = MacAddress([2, 0, 0, 0, 0, 1]);
+    let theirs = MacAddress([2, 0, 0, 0, 0, 2]);
+    let ends = [
+        (ours, OUR_IP, &one_way, &other_way),
+        (theirs, SMOLTCP_IP, &other_way, &one_way),
+    ];
+    let mut interfaces = ends.iter().map(|&(mac, address, inbox, outbox)| {
+        let end = End {
+            mac,
+            inbox: inbox.clone(),
+            outbox: outbox.clone(),
+        };
+        let name = net::register("cable", Arc::new(end));
+        let interface = net::interface(&name).unwrap();
+        interface.set_ipv4_config(Some(Config {
+            address,
+            prefix_len: 24,
+            gateway: None,
+        }));
+        interface
+    });
+    (interfaces.next().unwrap(), interfaces.next().unwrap())
+}
+
+#[test_case]
+fn sockets_need_the_stack() {
+    assert_eq!(smol::UdpSocket::bind(0).err(), Some(NetError::Down));
+    assert_eq!(smol::TcpListener::bind(80).err(), Some(NetError::Down));
+}
+
+#[test_case]
+fn talks_to_our_stack() {
+    let (ours, theirs) = cable();
+    let mut tasks: Vec<Task> = vec![
+        Box::pin(net::run(ours)),
+        Box::pin(smol::run(theirs.clone())),
+        Box::pin(udp::echo(7)),
+    ];
+    let mut buf = [0; 64];
+
+    // smoltcp to our echo service, and back
+    let socket = smol::UdpSocket::bind(0).unwrap();
+    let echo = Endpoint::new(OUR_IP, 7);
+    drive(&mut tasks, socket.send_to(b"hello", echo)).unwrap();
+    let (len, source) = drive(&mut tasks, socket.recv_from(&mut buf));
+    assert_eq!((&buf[..len], source), (&b"hello"[..], echo));
+
+    // Our stack doesn't handle smoltcp's interface anymore
+    assert_eq!(theirs.ipv4_config(), None);
+
+    // Our stack to smoltcp
+    let receiver = smol::UdpSocket::bind(5000).unwrap();
+    assert_eq!(
+        smol::UdpSocket::bind(5000).err(),
+        Some(NetError::AddressInUse)
+    );
+    let ours = udp::UdpSocket::bind(0).unwrap();
+    let destination = Endpoint::new(SMOLTCP_IP, 5000);
+    drive(&mut tasks, ours.send_to(b"hi there", destination)).unwrap();
+    let (len, source) = drive(&mut tasks, receiver.recv_from(&mut buf));
+    assert_eq!(&buf[..len], b"hi there");
+    assert_eq!(source, Endpoint::new(OUR_IP, ours.local_port()));
+
+    // smoltcp answers pings itself
+    let ping = EchoSocket::open();
+    drive(&mut tasks, ping.send(SMOLTCP_IP, 1, b"ping")).unwrap();
+    let reply = drive(&mut tasks, ping.receive());
+    assert_eq!(
+        (reply.source, reply.sequence, reply.len),
+        (SMOLTCP_IP, 1, 4)
+    );
+
+    // Datagrams that would need fragmenting aren't sent
+    let large = [0; 1500];
+    let send = drive(&mut tasks, socket.send_to(&large, echo));
+    assert_eq!(send, Err(NetError::TooLarge));
+}
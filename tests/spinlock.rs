@@ -0,0 +1,104 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use rust_os_playground::sync::{RwSpinLock, SpinLock};
+use x86_64::instructions::interrupts;
+
+rust_os_playground::kernel_test_main!();
+
+#[test_case]
+fn disables_interrupts_while_held() {
+    let lock = SpinLock::new(0);
+    interrupts::enable();
+
+    {
+        let mut guard = lock.lock();
+        assert!(!interrupts::are_enabled());
+        *guard += 1;
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(*lock.lock(), 1);
+}
+
+#[test_case]
+fn nested_guards_restore_on_the_outermost() {
+    let (outer, inner) = (SpinLock::new(()), SpinLock::new(()));
+    interrupts::enable();
+
+    let outer_guard = outer.lock();
+    {
+        let _inner_guard = inner.lock();
+    }
+    assert!(!interrupts::are_enabled());
+    drop(outer_guard);
+    assert!(interrupts::are_enabled());
+}
+
+#[test_case]
+fn leaves_interrupts_disabled_if_they_were() {
+    let lock = SpinLock::new(());
+
+    interrupts::without_interrupts(|| {
+        drop(lock.lock());
+        assert!(!interrupts::are_enabled());
+    });
+}
+
+#[test_case]
+fn try_lock_fails_while_held() {
+    let lock = SpinLock::new(());
+    interrupts::enable();
+
+    let guard = lock.try_lock().expect("lock is free");
+    assert!(lock.is_locked());
+    assert!(lock.try_lock().is_none());
+    assert!(!interrupts::are_enabled());
+    drop(guard);
+    assert!(!lock.is_locked());
+    assert!(interrupts::are_enabled());
+}
+
+#[test_case]
+fn readers_share_writers_exclude() {
+    let lock = RwSpinLock::new(1);
+
+    {
+        let (one, other) = (lock.read(), lock.read());
+        assert_eq!(*one + *other, 2);
+        assert!(lock.try_write().is_none());
+    }
+
+    let mut writer = lock.write();
+    *writer += 1;
+    assert!(lock.try_read().is_none());
+    assert!(lock.try_write().is_none());
+    drop(writer);
+    assert_eq!(*lock.read(), 2);
+}
+
+#[test_case]
+fn rw_lock_leaves_interrupts_alone() {
+    let lock = RwSpinLock::new(());
+    interrupts::enable();
+
+    let _reader = lock.read();
+    assert!(interrupts::are_enabled());
+}
+
+#[test_case]
+fn irq_safe_rw_lock_disables_interrupts() {
+    let lock = RwSpinLock::new_irq_safe(());
+    interrupts::enable();
+
+    let (one, other) = (lock.read(), lock.read());
+    assert!(!interrupts::are_enabled());
+    drop(one);
+    drop(other);
+    assert!(interrupts::are_enabled());
+
+    drop(lock.write());
+    assert!(interrupts::are_enabled());
+}
@@ -3,11 +3,12 @@
 #![feature(abi_x86_interrupt)]
 
 use core::panic::PanicInfo;
-use lazy_static::lazy_static;
+use rust_os_playground::sync::Lazy;
 use rust_os_playground::{
     exit_qemu, gdt, serial_print, serial_println, test_panic_handler, QemuExitCode,
 };
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::VirtAddr;
 
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
@@ -33,19 +34,17 @@ fn stack_overflow() {
     volatile::Volatile::new(0).read(); // Prevent tail recursion optimizations.
 }
 
-lazy_static! {
-    static ref TEST_IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
+static TEST_IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
+    let mut idt = InterruptDescriptorTable::new();
 
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(test_double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(test_double_fault_handler)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    }
 
-        idt
-    };
-}
+    idt
+});
 
 pub fn init_test_idt() {
     TEST_IDT.load();
@@ -55,7 +54,21 @@ extern "x86-interrupt" fn test_double_fault_handler(
     _stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    serial_println!("[ok]");
-    exit_qemu(QemuExitCode::Success);
+    // The overflowed stack can't take the interrupt frame, so getting here
+    // at all means the CPU switched stacks; check that it was to ours.
+    let marker = 0u8;
+    let here = VirtAddr::from_ptr(&marker);
+    let top = gdt::double_fault_stack_top();
+    if here < top && here >= top - gdt::DOUBLE_FAULT_STACK_SIZE {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!(
+            "double fault handler ran on {:?}, not on the IST stack",
+            here
+        );
+        exit_qemu(QemuExitCode::Failure);
+    }
     loop {}
 }
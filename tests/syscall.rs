@@ -0,0 +1,324 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::vec::Vec;
+use common::{executable, run_to_exit, Asm, Reg, DATA_OFFSET};
+use rust_os_playground::file::flags;
+use rust_os_playground::memory::address_space::{USER_END, USER_START};
+use rust_os_playground::process::{self, Pid, Process};
+use rust_os_playground::syscall::{self, errno, number};
+use rust_os_playground::{fs, time};
+use x86_64::{structures::paging::Page, VirtAddr};
+
+rust_os_playground::kernel_test_main!(memory, process, fs);
+
+const DATA: u64 = USER_START + DATA_OFFSET;
+
+/// A program that makes one system call and exits with its result.
+fn syscall_program(number: u64, args: &[u64], data: &[u8]) -> Vec<u8> {
+    let registers = [Reg::Rdi, Reg::Rsi, Reg::Rdx];
+    let mut asm = Asm::new().mov(Reg::Rax, number);
+    for (&reg, &arg) in registers.iter().zip(args) {
+        asm = asm.mov(reg, arg);
+    }
+
+    let code = asm
+        .syscall()
+        .mov_rdi_rax()
+        .mov(Reg::Rax, number::EXIT)
+        .syscall()
+        .spin();
+
+    executable(USER_START, &code, data)
+}
+
+fn run(number: u64, args: &[u64], data: &[u8]) -> i64 {
+    let pid = Process::spawn(&syscall_program(number, args, data)).unwrap();
+    run_to_exit(pid)
+}
+
+/// Like `run`, but the segment's second page, at `USER_START + 4096`, is
+/// unmapped behind the VMAs' back before the program starts, so that only the
+/// copy itself notices.
+fn run_with_hole(number: u64, args: &[u64]) -> i64 {
+    let data = [b'x'; 4096];
+    let pid = Process::spawn(&syscall_program(number, args, &data)).unwrap();
+    let hole = Page::containing_address(VirtAddr::new(USER_START + 4096));
+    process::with_address_space(pid, |address_space| address_space.unmap_page(hole))
+        .unwrap()
+        .unwrap();
+    run_to_exit(pid)
+}
+
+#[test_case]
+fn exit_code() {
+    let code = Asm::new()
+        .mov(Reg::Rax, number::EXIT)
+        .mov(Reg::Rdi, 7)
+        .syscall()
+        .spin();
+    let pid = Process::spawn(&executable(USER_START, &code, &[])).unwrap();
+
+    assert_eq!(run_to_exit(pid), 7);
+}
+
+#[test_case]
+fn write_to_stdout() {
+    assert_eq!(run(number::WRITE, &[1, DATA, 6], b"hello\n"), 6);
+}
+
+#[test_case]
+fn write_rejects_kernel_pointer() {
+    assert_eq!(run(number::WRITE, &[1, 0x20_0000, 4], &[]), -errno::EFAULT);
+}
+
+#[test_case]
+fn write_rejects_unmapped_range() {
+    // Starts inside the segment but runs past its end
+    assert_eq!(run(number::WRITE, &[1, DATA, 8192], b"x"), -errno::EFAULT);
+}
+
+#[test_case]
+fn copy_faults_on_unmapped_page() {
+    let hole = USER_START + 4096;
+    assert_eq!(run_with_hole(number::WRITE, &[1, hole, 8]), -errno::EFAULT);
+}
+
+#[test_case]
+fn write_rejects_bad_fd() {
+    assert_eq!(run(number::WRITE, &[7, DATA, 1], b"x"), -errno::EBADF);
+}
+
+#[test_case]
+fn read_stdin_in_background_is_empty() {
+    // The stack is writable
+    assert_eq!(run(number::READ, &[0, USER_END - 16, 8], &[]), 0);
+}
+
+#[test_case]
+fn read_rejects_read_only_buffer() {
+    assert_eq!(run(number::READ, &[0, DATA, 8], b"x"), -errno::EFAULT);
+}
+
+#[test_case]
+fn yield_returns() {
+    assert_eq!(run(number::YIELD, &[], &[]), 0);
+}
+
+#[test_case]
+fn sleep_waits() {
+    let start = time::ticks();
+    assert_eq!(run(number::SLEEP, &[50], &[]), 0);
+    assert!(time::ticks() - start >= 50 * time::TIMER_HZ / 1000);
+}
+
+#[test_case]
+fn spawn_child() {
+    let child_code = Asm::new()
+        .mov(Reg::Rax, number::EXIT)
+        .mov(Reg::Rdi, 42)
+        .syscall()
+        .spin();
+    let child = executable(USER_START, &child_code, &[]);
+
+    let parent = Process::spawn(&syscall_program(
+        number::SPAWN,
+        &[DATA, child.len() as u64],
+        &child,
+    ))
+    .unwrap();
+    let child_pid = run_to_exit(parent);
+
+    // PIDs are handed out in order
+    assert!(child_pid > parent.as_u64() as i64);
+
+    // The parent didn't wait for the child, so the kernel reaps it.
+    let child_pid = Pid::from_u64(child_pid as u64);
+    for _ in 0..100 {
+        process::yield_now();
+        if process::state(child_pid).is_none() {
+            return;
+        }
+        x86_64::instructions::hlt();
+    }
+
+    panic!("orphan wasn't reaped");
+}
+
+#[test_case]
+fn wait_for_child() {
+    let child_code = Asm::new()
+        .mov(Reg::Rax, number::EXIT)
+        .mov(Reg::Rdi, 42)
+        .syscall()
+        .spin();
+    let child = executable(USER_START, &child_code, &[]);
+
+    // spawn(child), then exit(wait(pid))
+    let code = Asm::new()
+        .mov(Reg::Rax, number::SPAWN)
+        .mov(Reg::Rdi, DATA)
+        .mov(Reg::Rsi, child.len() as u64)
+        .syscall()
+        .mov_rdi_rax()
+        .mov(Reg::Rax, number::WAIT)
+        .syscall()
+        .mov_rdi_rax()
+        .mov(Reg::Rax, number::EXIT)
+        .syscall()
+        .spin();
+    let pid = Process::spawn(&executable(USER_START, &code, &child)).unwrap();
+
+    assert_eq!(run_to_exit(pid), 42);
+}
+
+#[test_case]
+fn wait_rejects_non_children() {
+    assert_eq!(run(number::WAIT, &[0], &[]), -errno::ECHILD);
+}
+
+#[test_case]
+fn spawn_rejects_garbage() {
+    assert_eq!(run(number::SPAWN, &[DATA, 64], &[0; 64]), -errno::EINVAL);
+}
+
+#[test_case]
+fn unknown_syscall() {
+    assert_eq!(run(999, &[], &[]), -errno::ENOSYS);
+}
+
+#[test_case]
+fn open_returns_lowest_free_fd() {
+    let path = b"/tmp/lowest";
+    let args = [DATA, path.len() as u64, flags::READ | flags::CREATE];
+    assert_eq!(run(number::OPEN, &args, path), 3);
+}
+
+#[test_case]
+fn file_read_write_seek() {
+    let path = b"/tmp/syscall.txt";
+    let mut data = path.to_vec();
+    data.resize(64, 0);
+    data.extend_from_slice(b"file data");
+
+    // fd = open(path), write(fd, "file data"), seek(fd, 5), then
+    // exit(read(fd, stack, 32))
+    let code = Asm::new()
+        .mov(Reg::Rax, number::OPEN)
+        .mov(Reg::Rdi, DATA)
+        .mov(Reg::Rsi, path.len() as u64)
+        .mov(Reg::Rdx, flags::READ | flags::WRITE | flags::CREATE)
+        .syscall()
+        .mov_rdi_rax()
+        .mov(Reg::Rax, number::WRITE)
+        .mov(Reg::Rsi, DATA + 64)
+        .mov(Reg::Rdx, 9)
+        .syscall()
+        .mov(Reg::Rax, number::SEEK)
+        .mov(Reg::Rsi, 5)
+        .mov(Reg::Rdx, 0)
+        .syscall()
+        .mov(Reg::Rax, number::READ)
+        .mov(Reg::Rsi, USER_END - 64)
+        .mov(Reg::Rdx, 32)
+        .syscall()
+        .mov_rdi_rax()
+        .mov(Reg::Rax, number::EXIT)
+        .syscall()
+        .spin();
+    let pid = Process::spawn(&executable(USER_START, &code, &data)).unwrap();
+
+    assert_eq!(run_to_exit(pid), 4);
+    assert_eq!(fs::read_file("/tmp/syscall.txt").unwrap(), b"file data");
+}
+
+#[test_case]
+fn closed_fd_is_bad() {
+    let path = b"/tmp/closed";
+
+    // fd = open(path), close(fd), then exit(write(fd, ...))
+    let code = Asm::new()
+        .mov(Reg::Rax, number::OPEN)
+        .mov(Reg::Rdi, DATA)
+        .mov(Reg::Rsi, path.len() as u64)
+        .mov(Reg::Rdx, flags::WRITE | flags::CREATE)
+        .syscall()
+        .mov_rdi_rax()
+        .mov(Reg::Rax, number::CLOSE)
+        .syscall()
+        .mov(Reg::Rax, number::WRITE)
+        .mov(Reg::Rsi, DATA)
+        .mov(Reg::Rdx, 1)
+        .syscall()
+        .mov_rdi_rax()
+        .mov(Reg::Rax, number::EXIT)
+        .syscall()
+        .spin();
+    let pid = Process::spawn(&executable(USER_START, &code, path)).unwrap();
+
+    assert_eq!(run_to_exit(pid), -errno::EBADF);
+}
+
+#[test_case]
+fn open_errors() {
+    let path = b"/tmp/missing";
+    let args = [DATA, path.len() as u64, flags::READ];
+    assert_eq!(run(number::OPEN, &args, path), -errno::ENOENT);
+
+    let args = [DATA, path.len() as u64, 1 << 10];
+    assert_eq!(run(number::OPEN, &args, path), -errno::EINVAL);
+
+    let path = b"/tmp";
+    let args = [DATA, path.len() as u64, flags::WRITE];
+    assert_eq!(run(number::OPEN, &args, path), -errno::EISDIR);
+
+    let args = [DATA, syscall::PATH_MAX as u64 + 1, flags::READ];
+    assert_eq!(run(number::OPEN, &args, path), -errno::ENAMETOOLONG);
+
+    let args = [0x20_0000, path.len() as u64, flags::READ];
+    assert_eq!(run(number::OPEN, &args, path), -errno::EFAULT);
+}
+
+#[test_case]
+fn open_stops_at_nul() {
+    let path = b"/tmp/nul\0junk";
+    let args = [DATA, path.len() as u64, flags::CREATE];
+    assert_eq!(run(number::OPEN, &args, path), 3);
+    assert!(fs::metadata("/tmp/nul").is_ok());
+}
+
+#[test_case]
+fn open_truncates_at_len() {
+    let path = b"/tmp/truncated";
+    let args = [DATA, 10, flags::CREATE];
+    assert_eq!(run(number::OPEN, &args, path), 3);
+    assert!(fs::metadata("/tmp/trunc").is_ok());
+    assert!(fs::metadata("/tmp/truncated").is_err());
+}
+
+#[test_case]
+fn open_path_across_pages() {
+    // Starts 4 bytes before the end of the first page
+    let start = USER_START + 4096 - 4;
+    let mut data = [0; 4096 - DATA_OFFSET as usize - 4].to_vec();
+    data.extend_from_slice(b"/tmp/across\0");
+    let args = [start, syscall::PATH_MAX as u64, flags::CREATE];
+    assert_eq!(run(number::OPEN, &args, &data), 3);
+    assert!(fs::metadata("/tmp/across").is_ok());
+
+    // Runs into a page that isn't there anymore
+    let args = [start, 16, flags::CREATE];
+    assert_eq!(run_with_hole(number::OPEN, &args), -errno::EFAULT);
+}
+
+#[test_case]
+fn console_is_not_seekable() {
+    assert_eq!(run(number::SEEK, &[0, 0, 0], &[]), -errno::ESPIPE);
+}
@@ -0,0 +1,103 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use common::{executable, run_to_exit, Asm, Reg};
+use rust_os_playground::memory::address_space::{USER_END, USER_START};
+use rust_os_playground::process::{self, Pid, Process, State};
+use rust_os_playground::syscall::number;
+use rust_os_playground::tty::{self, Mode};
+
+rust_os_playground::kernel_test_main!(memory, process);
+
+/// `read(0, stack, 64)`, then exits with the number of bytes read.
+fn reader(raw: bool) -> Pid {
+    let mut asm = Asm::new();
+    if raw {
+        asm = asm
+            .mov(Reg::Rax, number::TTY_MODE)
+            .mov(Reg::Rdi, 1)
+            .syscall();
+    }
+    let code = asm
+        .mov(Reg::Rax, number::READ)
+        .mov(Reg::Rdi, 0)
+        .mov(Reg::Rsi, USER_END - 128)
+        .mov(Reg::Rdx, 64)
+        .syscall()
+        .mov_rdi_rax()
+        .mov(Reg::Rax, number::EXIT)
+        .syscall()
+        .spin();
+
+    let pid = Process::spawn(&executable(USER_START, &code, &[])).unwrap();
+    tty::set_foreground(Some(pid));
+
+    // Let it run until it waits for input
+    for _ in 0..100 {
+        process::yield_now();
+        if process::state(pid) == Some(State::Blocked) {
+            return pid;
+        }
+    }
+
+    panic!("process didn't block in read");
+}
+
+fn type_in(input: &str) {
+    for character in input.chars() {
+        tty::input(character);
+    }
+}
+
+#[test_case]
+fn canonical_mode_reads_edited_lines() {
+    let pid = reader(false);
+
+    // "ac\n", the read stops at the end of the first line
+    type_in("ab\x08c\nmore\n");
+
+    assert_eq!(run_to_exit(pid), 3);
+    assert_eq!(tty::foreground(), None);
+
+    // The second line goes to the shell now that the process is gone.
+    assert_eq!(tty::try_read_line().as_deref(), Some("more"));
+}
+
+#[test_case]
+fn raw_mode_reads_characters() {
+    let pid = reader(true);
+    assert_eq!(tty::mode(), Mode::Raw);
+
+    type_in("x");
+
+    assert_eq!(run_to_exit(pid), 1);
+
+    // The shell gets the console back in canonical mode.
+    assert_eq!(tty::mode(), Mode::Canonical);
+}
+
+#[test_case]
+fn shell_doesnt_get_input_meant_for_foreground_process() {
+    let pid = reader(false);
+
+    type_in("for the process\n");
+    assert_eq!(tty::try_read_line(), None);
+
+    assert_eq!(run_to_exit(pid), 16);
+}
+
+#[test_case]
+fn shell_lines() {
+    type_in("help\n\x7f\x7fecho hi\r");
+
+    assert_eq!(tty::try_read_line().as_deref(), Some("help"));
+    assert_eq!(tty::try_read_line().as_deref(), Some("echo hi"));
+    assert_eq!(tty::try_read_line(), None);
+}
@@ -0,0 +1,172 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{boxed::Box, vec::Vec};
+use common::net::{from_peer, wire_to_peer, Wire, OUR_IP, PEER_IP};
+use common::run;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::Context;
+use futures_util::task;
+use rust_os_playground::net::eth;
+use rust_os_playground::net::ipv4;
+use rust_os_playground::net::udp::{self, Endpoint, UdpSocket};
+use rust_os_playground::net::{NetError, PacketBuf};
+
+rust_os_playground::kernel_test_main!(heap);
+
+const PEER_PORT: u16 = 4000;
+
+/// A datagram from `PEER_PORT` to `port`.
+fn datagram(port: u16, data: &[u8]) -> PacketBuf {
+    let mut packet = PacketBuf::new();
+    packet.extend_from_slice(data);
+    let header = udp::Header {
+        source_port: PEER_PORT,
+        destination_port: port,
+    };
+    header.push(PEER_IP, OUR_IP, &mut packet);
+    packet
+}
+
+/// Takes the datagrams sent so far, without their headers.
+fn take_sent(wire: &Wire) -> Vec<(udp::Header, PacketBuf)> {
+    let frames = core::mem::take(&mut *wire.sent.lock());
+    frames
+        .into_iter()
+        .map(|mut frame| {
+            eth::Header::pull(&mut frame).unwrap();
+            let ip_header = ipv4::Header::pull(&mut frame).unwrap();
+            assert_eq!(ip_header.protocol, ipv4::PROTOCOL_UDP);
+            assert_eq!((ip_header.source, ip_header.destination), (OUR_IP, PEER_IP));
+            (udp::Header::pull(&ip_header, &mut frame).unwrap(), frame)
+        })
+        .collect()
+}
+
+#[test_case]
+fn binds_ports() {
+    let socket = UdpSocket::bind(5000).unwrap();
+    assert_eq!(socket.local_port(), 5000);
+    assert_eq!(
+        UdpSocket::bind(5000).map(|_| ()),
+        Err(NetError::AddressInUse)
+    );
+    drop(socket);
+    assert!(UdpSocket::bind(5000).is_ok());
+
+    let first = UdpSocket::bind(0).unwrap();
+    let second = UdpSocket::bind(0).unwrap();
+    assert!(first.local_port() >= udp::FIRST_EPHEMERAL_PORT);
+    assert!(second.local_port() >= udp::FIRST_EPHEMERAL_PORT);
+    assert_ne!(first.local_port(), second.local_port());
+}
+
+#[test_case]
+fn sends_datagrams() {
+    let (wire, _interface) = wire_to_peer();
+    let socket = UdpSocket::bind(5001).unwrap();
+
+    let sent = run(socket.send_to(b"hello", Endpoint::new(PEER_IP, PEER_PORT)));
+    assert_eq!(sent, Ok(()));
+
+    // Pulling the header checks the checksum
+    let sent = take_sent(&wire);
+    assert_eq!(sent.len(), 1);
+    let (header, data) = &sent[0];
+    assert_eq!(
+        (header.source_port, header.destination_port),
+        (5001, PEER_PORT)
+    );
+    assert_eq!(&data[..], b"hello");
+
+    let too_large = [0; udp::MAX_PAYLOAD + 1];
+    let sent = run(socket.send_to(&too_large, Endpoint::new(PEER_IP, PEER_PORT)));
+    assert_eq!(sent, Err(NetError::TooLarge));
+}
+
+#[test_case]
+fn receives_datagrams() {
+    let (_wire, interface) = wire_to_peer();
+    let socket = UdpSocket::bind(5002).unwrap();
+    let other = UdpSocket::bind(5003).unwrap();
+
+    for data in [&b"first"[..], b"second"].iter() {
+        let packet = datagram(5002, data);
+        run(eth::handle(
+            &interface,
+            from_peer(ipv4::PROTOCOL_UDP, packet),
+        ));
+    }
+    assert_eq!(other.try_recv_from(&mut [0; 16]), None);
+
+    let peer = Endpoint::new(PEER_IP, PEER_PORT);
+    let mut buf = [0; 16];
+    assert_eq!(run(socket.recv_from(&mut buf)), (5, peer));
+    assert_eq!(&buf[..5], b"first");
+
+    // What doesn't fit is dropped
+    let mut small = [0; 3];
+    assert_eq!(socket.try_recv_from(&mut small), Some((3, peer)));
+    assert_eq!(&small, b"sec");
+    assert_eq!(socket.try_recv_from(&mut buf), None);
+}
+
+#[test_case]
+fn drops_broken_datagrams() {
+    let (_wire, interface) = wire_to_peer();
+    let socket = UdpSocket::bind(5004).unwrap();
+    let mut buf = [0; 16];
+
+    let mut corrupt = datagram(5004, b"data");
+    corrupt[8] ^= 1;
+    run(eth::handle(
+        &interface,
+        from_peer(ipv4::PROTOCOL_UDP, corrupt),
+    ));
+    assert_eq!(socket.try_recv_from(&mut buf), None);
+
+    // No checksum at all is fine, though
+    let mut unchecked = datagram(5004, b"data");
+    unchecked[6..8].copy_from_slice(&[0, 0]);
+    run(eth::handle(
+        &interface,
+        from_peer(ipv4::PROTOCOL_UDP, unchecked),
+    ));
+    assert!(socket.try_recv_from(&mut buf).is_some());
+}
+
+#[test_case]
+fn echo_service() {
+    let (wire, interface) = wire_to_peer();
+    let mut context = Context::from_waker(task::noop_waker_ref());
+    let mut echo: Pin<Box<dyn Future<Output = ()>>> = Box::pin(udp::echo(7));
+    assert!(echo.as_mut().poll(&mut context).is_pending());
+
+    let packet = datagram(7, b"echo, echo");
+    run(eth::handle(
+        &interface,
+        from_peer(ipv4::PROTOCOL_UDP, packet),
+    ));
+    assert!(echo.as_mut().poll(&mut context).is_pending());
+
+    let sent = take_sent(&wire);
+    assert_eq!(sent.len(), 1);
+    let (header, data) = &sent[0];
+    assert_eq!(
+        (header.source_port, header.destination_port),
+        (7, PEER_PORT)
+    );
+    assert_eq!(&data[..], b"echo, echo");
+
+    // The port is free again once the service stops
+    drop(echo);
+    assert!(UdpSocket::bind(7).is_ok());
+}
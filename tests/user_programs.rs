@@ -0,0 +1,111 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os_playground::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{rc::Rc, string::String};
+use common::run_to_exit;
+use core::cell::RefCell;
+use rust_os_playground::process::{self, Process};
+use rust_os_playground::task::{executor::Executor, Task};
+use rust_os_playground::{fs, initrd, programs, shell, time, tty};
+
+rust_os_playground::kernel_test_main!(memory, process, fs => {
+    initrd::init().expect("can't mount the initrd");
+    programs::register_commands();
+});
+
+#[test_case]
+fn hello() {
+    tty::capture_output(true);
+
+    let pid = Process::spawn(programs::HELLO).unwrap();
+    assert_eq!(run_to_exit(pid), 0);
+    assert!(tty::take_captured_output().contains("Hello from user space!"));
+
+    tty::capture_output(false);
+}
+
+#[test_case]
+fn echo_prints_its_arguments() {
+    tty::capture_output(true);
+
+    let pid = Process::spawn_with_args(programs::ECHO, &["echo", "one", "two"], &[]).unwrap();
+    assert_eq!(run_to_exit(pid), 0);
+    assert!(tty::take_captured_output().contains("one two\n"));
+
+    tty::capture_output(false);
+}
+
+#[test_case]
+fn stress() {
+    tty::capture_output(true);
+
+    let pid = Process::spawn(programs::STRESS).unwrap();
+    let code = run_to_exit(pid);
+    let output = tty::take_captured_output();
+    assert!(output.contains("stress: ok"), "{}", output);
+    assert_eq!(code, 0);
+
+    tty::capture_output(false);
+}
+
+#[test_case]
+fn cat_prints_files() {
+    fs::create_file("/tmp/cat.txt").unwrap();
+    fs::write("/tmp/cat.txt", 0, b"meow\n").unwrap();
+
+    tty::capture_output(true);
+    let pid = Process::spawn_with_args(programs::CAT, &["cat", "/tmp/cat.txt"], &[]).unwrap();
+    assert_eq!(run_to_exit(pid), 0);
+    assert!(tty::take_captured_output().contains("meow\n"));
+
+    let pid = Process::spawn_with_args(programs::CAT, &["cat", "/tmp/nope"], &[]).unwrap();
+    assert_eq!(run_to_exit(pid), 1);
+    assert!(tty::take_captured_output().contains("cat: /tmp/nope: error 2"));
+    tty::capture_output(false);
+}
+
+/// Runs a command line in the shell, letting the processes run meanwhile.
+fn run_command(line: &'static str) -> String {
+    let out = Rc::new(RefCell::new(None));
+    let mut executor = Executor::new();
+
+    let result = out.clone();
+    executor.spawn(Task::new(async move {
+        let mut out = String::new();
+        shell::execute_async(line, &mut out).await.unwrap();
+        *result.borrow_mut() = Some(out);
+    }));
+
+    let deadline = time::ticks() + 5 * time::TIMER_HZ;
+    while out.borrow().is_none() {
+        assert!(time::ticks() < deadline, "{} didn't finish", line);
+        executor.run_until_idle();
+        process::yield_now();
+        x86_64::instructions::hlt();
+    }
+    let out = out.borrow_mut().take().unwrap();
+    out
+}
+
+#[test_case]
+fn run_waits_for_the_exit_code() {
+    tty::capture_output(true);
+    assert_eq!(run_command("run hello"), "hello exited with 0\n");
+    assert!(tty::take_captured_output().contains("Hello from user space!"));
+
+    assert_eq!(
+        run_command("run /init/bin/cat /tmp/nope"),
+        "/init/bin/cat exited with 1\n"
+    );
+    tty::capture_output(false);
+
+    assert_eq!(tty::foreground(), None);
+    assert!(run_command("run nope").starts_with("run: nope: "));
+}
@@ -0,0 +1,112 @@
+#!/usr/bin/env python3
+"""Embeds the kernel's symbol table into its reserved `.ksymtab` section.
+
+Usage: embed_symbols.py <kernel-elf>
+
+Runs `nm` on the linked kernel, serializes the function symbols in the format
+described at the top of src/symbols.rs, and writes them into the `.ksymtab`
+section in place with `objcopy --update-section`. The section keeps its size,
+so no address in the kernel changes. Set NM/OBJCOPY/READELF to use different
+binutils (e.g. the llvm-* variants).
+"""
+
+import os
+import re
+import struct
+import subprocess
+import sys
+import tempfile
+
+SECTION = ".ksymtab"
+MAGIC = b"KSYMTAB1"
+FUNCTION_TYPES = set("tTwW")
+HASH_SUFFIX = re.compile(r"::h[0-9a-f]{16}$")
+
+
+def tool(name):
+    return os.environ.get(name.upper(), name)
+
+
+def section_size(kernel):
+    output = subprocess.run(
+        [tool("readelf"), "--wide", "--section-headers", kernel],
+        check=True, capture_output=True, text=True,
+    ).stdout
+    for line in output.splitlines():
+        fields = line.replace("[ ", "[").split()
+        if SECTION in fields:
+            index = fields.index(SECTION)
+            # Name is followed by type, address, offset, size
+            return int(fields[index + 4], 16)
+    sys.exit(f"embed_symbols: {kernel} has no {SECTION} section")
+
+
+def symbols(kernel):
+    output = subprocess.run(
+        [tool("nm"), "--defined-only", "--demangle", "--print-size", "--numeric-sort", kernel],
+        check=True, capture_output=True, text=True,
+    ).stdout
+    for line in output.splitlines():
+        fields = line.split(maxsplit=3)
+        if len(fields) == 4:
+            address, size, kind, name = fields
+        elif len(fields) == 3:
+            (address, kind, name), size = fields, "0"
+        else:
+            continue
+        if kind in FUNCTION_TYPES:
+            yield int(address, 16), int(size, 16), HASH_SUFFIX.sub("", name)
+
+
+def serialize(entries, capacity):
+    header_size, entry_size = 24, 24
+    strings = bytearray()
+    table = bytearray()
+    for address, size, name in entries:
+        encoded = name.encode()
+        table += struct.pack("<QQII", address, size, len(strings), len(encoded))
+        strings += encoded
+    strings_offset = header_size + len(table)
+    blob = MAGIC + struct.pack("<QQ", len(entries), strings_offset) + table + strings
+    if len(blob) > capacity:
+        return None
+    return blob + bytes(capacity - len(blob))
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit(__doc__)
+    kernel = sys.argv[1]
+    capacity = section_size(kernel)
+    entries = sorted(set(symbols(kernel)))
+
+    all_count = len(entries)
+    blob = serialize(entries, capacity)
+    if blob is None:
+        # Drop the longest names first, those are mostly generic instantiations
+        # that are the least helpful in a backtrace anyway.
+        by_length = sorted(entries, key=lambda entry: len(entry[2]))
+        used = 24 + sum(24 + len(entry[2].encode()) for entry in entries)
+        while used > capacity:
+            dropped = by_length.pop()
+            used -= 24 + len(dropped[2].encode())
+        entries = sorted(by_length)
+        blob = serialize(entries, capacity)
+        print(
+            f"embed_symbols: {SECTION} full, dropped {all_count - len(entries)} symbols",
+            file=sys.stderr,
+        )
+
+    with tempfile.NamedTemporaryFile(suffix=".bin", delete=False) as blob_file:
+        blob_file.write(blob)
+    try:
+        subprocess.run(
+            [tool("objcopy"), f"--update-section={SECTION}={blob_file.name}", kernel],
+            check=True,
+        )
+    finally:
+        os.unlink(blob_file.name)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,85 @@
+#!/usr/bin/env python3
+"""Compares the golden transcripts in a serial log with tests/golden/.
+
+Usage: golden.py [--update] [--dir DIR] [LOG...] (reads stdin without LOGs)
+
+Looks for the blocks between `GOLDEN BEGIN <name>` and `GOLDEN END <name>`
+lines written by `golden::transcript` (see src/golden.rs), and compares each
+with tests/golden/<name>.txt, where `::` in the name becomes `.`. Prints a
+diff for every transcript that doesn't match or has no expected output yet,
+and exits with 1 if there was any. With --update, writes the transcripts to
+tests/golden/ instead.
+"""
+
+import argparse
+import difflib
+import fileinput
+import sys
+from pathlib import Path
+
+DEFAULT_DIR = Path(__file__).resolve().parent.parent / "tests" / "golden"
+
+
+def parse(lines):
+    name, body = None, []
+    for line in lines:
+        line = line.rstrip("\r\n")
+        if name is None:
+            if line.startswith("GOLDEN BEGIN "):
+                name, body = line[len("GOLDEN BEGIN "):], []
+        elif line == "GOLDEN END " + name:
+            yield name, "".join(body)
+            name = None
+        else:
+            body.append(line + "\n")
+    if name is not None:
+        sys.exit(f"golden: transcript {name} has no end, did the test crash?")
+
+
+def path_for(directory, name):
+    return directory / (name.replace("::", ".") + ".txt")
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("--update", action="store_true")
+    parser.add_argument("--dir", type=Path, default=DEFAULT_DIR)
+    parser.add_argument("logs", nargs="*")
+    args = parser.parse_args()
+
+    transcripts = list(parse(fileinput.input(args.logs)))
+    if not transcripts:
+        sys.exit("golden: no transcripts in the log")
+
+    failed = 0
+    for name, actual in transcripts:
+        path = path_for(args.dir, name)
+        if args.update:
+            args.dir.mkdir(parents=True, exist_ok=True)
+            path.write_text(actual)
+            print(f"golden: wrote {path}")
+            continue
+
+        expected = path.read_text() if path.exists() else ""
+        if actual == expected:
+            print(f"golden: {name} ok")
+            continue
+
+        failed += 1
+        if not path.exists():
+            print(f"golden: {name} has no expected output, {path} (see --update)")
+        sys.stdout.writelines(
+            difflib.unified_diff(
+                expected.splitlines(keepends=True),
+                actual.splitlines(keepends=True),
+                fromfile=str(path),
+                tofile=f"{name} (serial log)",
+            )
+        )
+
+    if failed:
+        sys.exit(f"golden: {failed} of {len(transcripts)} transcripts differ")
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,7 @@
+#!/bin/sh
+# Cargo runner for the kernel: embeds the symbol table into the freshly linked
+# kernel (see src/symbols.rs) and then hands over to bootimage as before.
+set -e
+
+python3 "$(dirname "$0")/embed_symbols.py" "$1"
+exec bootimage runner "$@"
@@ -0,0 +1,108 @@
+#!/usr/bin/env python3
+"""The host's end of the serial port in framed mode (see src/serial/mux.rs).
+
+Usage: serial_mux.py [--log FILE] [--gdb-port PORT] HOST:PORT
+
+Connects to the kernel's serial port as QEMU serves it, e.g. with
+`-serial tcp:127.0.0.1:4555,server=on,wait=on`, for a kernel booted with
+`serial=framed` on its command line. Console output goes to stdout, and what's
+typed on stdin goes to the console. The log goes to stderr, or to FILE, which
+golden.py and trace_to_chrome.py can read like a plain serial log. With
+--gdb-port, GDB can connect to that port (`target remote :PORT`) to talk to
+the GDB channel.
+"""
+
+import argparse
+import os
+import selectors
+import socket
+import sys
+
+CONSOLE, LOG, GDB = 0, 1, 2
+MAX_FRAME = 255
+
+
+def frames(channel, data):
+    for start in range(0, len(data), MAX_FRAME):
+        chunk = data[start:start + MAX_FRAME]
+        yield bytes([channel, len(chunk)]) + chunk
+
+
+class Deframer:
+    """Takes the kernel's frames apart, like `mux::Deframer`."""
+
+    def __init__(self):
+        self.buffer = b""
+
+    def feed(self, data):
+        self.buffer += data
+        while len(self.buffer) >= 2 and len(self.buffer) >= 2 + self.buffer[1]:
+            channel, length = self.buffer[0], self.buffer[1]
+            yield channel, self.buffer[2:2 + length]
+            self.buffer = self.buffer[2 + length:]
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("address", metavar="HOST:PORT")
+    parser.add_argument("--log", type=argparse.FileType("wb"))
+    parser.add_argument("--gdb-port", type=int)
+    args = parser.parse_args()
+
+    host, port = args.address.rsplit(":", 1)
+    kernel = socket.create_connection((host, int(port)))
+    log = args.log or sys.stderr.buffer
+    outputs = {CONSOLE: sys.stdout.buffer, LOG: log}
+
+    selector = selectors.DefaultSelector()
+    selector.register(kernel, selectors.EVENT_READ, "kernel")
+    selector.register(sys.stdin.fileno(), selectors.EVENT_READ, "stdin")
+    if args.gdb_port is not None:
+        listener = socket.create_server(("127.0.0.1", args.gdb_port))
+        selector.register(listener, selectors.EVENT_READ, "listener")
+    gdb = None
+
+    deframer = Deframer()
+    while True:
+        for key, _ in selector.select():
+            if key.data == "kernel":
+                data = kernel.recv(4096)
+                if not data:
+                    return
+                for channel, payload in deframer.feed(data):
+                    if channel == GDB:
+                        if gdb is not None:
+                            gdb.sendall(payload)
+                    elif channel in outputs:
+                        outputs[channel].write(payload)
+                        outputs[channel].flush()
+            elif key.data == "stdin":
+                data = os.read(sys.stdin.fileno(), 4096)
+                if not data:
+                    selector.unregister(sys.stdin.fileno())
+                    continue
+                for frame in frames(CONSOLE, data):
+                    kernel.sendall(frame)
+            elif key.data == "listener":
+                connection, _ = listener.accept()
+                if gdb is not None:
+                    connection.close()
+                    continue
+                gdb = connection
+                selector.register(gdb, selectors.EVENT_READ, "gdb")
+            elif key.data == "gdb":
+                data = gdb.recv(4096)
+                if not data:
+                    selector.unregister(gdb)
+                    gdb.close()
+                    gdb = None
+                    continue
+                for frame in frames(GDB, data):
+                    kernel.sendall(frame)
+
+
+if __name__ == "__main__":
+    try:
+        main()
+    except KeyboardInterrupt:
+        pass
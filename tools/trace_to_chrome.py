@@ -0,0 +1,58 @@
+#!/usr/bin/env python3
+"""Converts a kernel trace dump into Chrome trace event JSON.
+
+Usage: trace_to_chrome.py [--tsc-mhz MHZ] < serial.log > trace.json
+
+Looks for the block between the `TRACE BEGIN` and `TRACE END` lines written by
+`trace::dump` (see src/trace.rs) and emits one instant event per line, with the
+CPU as the thread ID. Timestamps are converted from TSC cycles to microseconds
+using the given TSC frequency (default 1000 MHz), relative to the first event.
+The output can be loaded into chrome://tracing or https://ui.perfetto.dev.
+"""
+
+import argparse
+import json
+import sys
+
+
+def parse(lines):
+    inside = False
+    for line in lines:
+        line = line.rstrip("\r\n")
+        if line.endswith("TRACE BEGIN"):
+            inside = True
+        elif line.endswith("TRACE END"):
+            inside = False
+        elif inside:
+            fields = line.split(" ", 3)
+            if len(fields) < 3:
+                continue
+            cpu, tsc, category = int(fields[0]), int(fields[1]), fields[2]
+            message = fields[3] if len(fields) == 4 else ""
+            yield cpu, tsc, category, message
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("--tsc-mhz", type=float, default=1000.0)
+    args = parser.parse_args()
+
+    events = sorted(parse(sys.stdin), key=lambda event: event[1])
+    start = events[0][1] if events else 0
+    trace = [
+        {
+            "name": message,
+            "cat": category,
+            "ph": "i",
+            "s": "t",
+            "ts": (tsc - start) / args.tsc_mhz,
+            "pid": 0,
+            "tid": cpu,
+        }
+        for cpu, tsc, category, message in events
+    ]
+    json.dump({"traceEvents": trace}, sys.stdout, indent=1)
+
+
+if __name__ == "__main__":
+    main()
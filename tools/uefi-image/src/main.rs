@@ -0,0 +1,61 @@
+// Makes a UEFI disk image of a kernel built with the "uefi" feature, and boots
+// it in QEMU with OVMF as the firmware:
+//
+//     cargo build --features uefi
+//     cargo run --manifest-path tools/uefi-image/Cargo.toml -- \
+//         target/x86_64_custom_target/debug/rust-os-playground
+//
+// bootimage only knows version 0.9 of the bootloader, so this takes its place
+// for UEFI. With `--bios`, it makes a BIOS disk image instead, which boots the
+// same kernel through 0.11's BIOS stages. With `--no-run`, it only makes the
+// image, next to the kernel.
+
+use std::env;
+use std::path::PathBuf;
+use std::process::{self, Command};
+
+fn main() {
+    let mut kernel = None;
+    let (mut bios, mut run) = (false, true);
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--bios" => bios = true,
+            "--no-run" => run = false,
+            _ => kernel = Some(PathBuf::from(arg)),
+        }
+    }
+    let kernel = kernel.unwrap_or_else(|| {
+        eprintln!("usage: uefi-image [--bios] [--no-run] <kernel>");
+        process::exit(2);
+    });
+
+    let image = kernel.with_extension(if bios { "bios.img" } else { "uefi.img" });
+    let created = if bios {
+        bootloader::BiosBoot::new(&kernel).create_disk_image(&image)
+    } else {
+        bootloader::UefiBoot::new(&kernel).create_disk_image(&image)
+    };
+    if let Err(err) = created {
+        eprintln!("can't make {}: {}", image.display(), err);
+        process::exit(1);
+    }
+    println!("{}", image.display());
+    if !run {
+        return;
+    }
+
+    let mut qemu = Command::new("qemu-system-x86_64");
+    qemu.arg("-drive")
+        .arg(format!("format=raw,file={}", image.display()))
+        .args(["-serial", "stdio"])
+        .args(["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"])
+        .args(["-nic", "user,model=e1000"]);
+    if !bios {
+        qemu.arg("-bios").arg(ovmf_prebuilt::ovmf_pure_efi());
+    }
+    let status = qemu.status().unwrap_or_else(|err| {
+        eprintln!("can't run QEMU: {}", err);
+        process::exit(1);
+    });
+    process::exit(status.code().unwrap_or(1));
+}
@@ -0,0 +1,47 @@
+// Prints the files given as arguments, like cat(1), or its standard input if
+// there are none.
+
+#![no_std]
+#![no_main]
+
+use user_programs::{args, close, entry_point, flags, open, println, read, write, STDIN, STDOUT};
+
+entry_point!(main);
+
+fn main() -> i64 {
+    if args().count() < 2 {
+        return copy(STDIN);
+    }
+
+    for path in args().skip(1) {
+        let fd = open(path, flags::READ);
+        if fd < 0 {
+            println!("cat: {}: error {}", path, -fd);
+            return 1;
+        }
+
+        let result = copy(fd as u64);
+        close(fd as u64);
+        if result != 0 {
+            println!("cat: {}: error {}", path, result);
+            return 1;
+        }
+    }
+
+    0
+}
+
+/// Copies everything from `fd` to the standard output, and returns 0, or the
+/// error number.
+fn copy(fd: u64) -> i64 {
+    let mut buf = [0; 512];
+    loop {
+        match read(fd, &mut buf) {
+            0 => return 0,
+            len if len < 0 => return -len,
+            len => {
+                write(STDOUT, &buf[..len as usize]);
+            }
+        }
+    }
+}
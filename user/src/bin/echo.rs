@@ -0,0 +1,20 @@
+// Prints its arguments, separated by spaces, like echo(1).
+
+#![no_std]
+#![no_main]
+
+use user_programs::{args, entry_point, print, println};
+
+entry_point!(main);
+
+fn main() -> i64 {
+    for (i, arg) in args().skip(1).enumerate() {
+        if i > 0 {
+            print!(" ");
+        }
+        print!("{}", arg);
+    }
+    println!();
+
+    0
+}
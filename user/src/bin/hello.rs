@@ -0,0 +1,11 @@
+#![no_std]
+#![no_main]
+
+use user_programs::{entry_point, println};
+
+entry_point!(main);
+
+fn main() -> i64 {
+    println!("Hello from user space!");
+    0
+}
@@ -0,0 +1,80 @@
+// Hammers the system call interface, including with bad arguments, which
+// have to be rejected without taking the kernel down.
+
+#![no_std]
+#![no_main]
+
+use user_programs::*;
+
+entry_point!(main);
+
+const ITERATIONS: u64 = 1000;
+
+fn check(ok: bool, what: &str) {
+    if !ok {
+        println!("stress: FAIL {}", what);
+        exit(1);
+    }
+}
+
+fn main() -> i64 {
+    for _ in 0..ITERATIONS {
+        check(yield_now() == 0, "yield");
+    }
+    check(sleep(20) == 0, "sleep");
+
+    // Pointers into the kernel, unmapped memory, and numbers nobody knows
+    check(
+        syscall(number::WRITE, 1, 0x20_0000, 16) == -errno::EFAULT,
+        "write from kernel memory",
+    );
+    check(
+        syscall(number::WRITE, 1, 0, 16) == -errno::EFAULT,
+        "write from null",
+    );
+    check(
+        syscall(number::READ, 0, u64::MAX - 8, 16) == -errno::EFAULT,
+        "read into wrapping range",
+    );
+    check(write(7, b"x") == -errno::EBADF, "write to bad fd");
+    check(close(7) == -errno::EBADF, "close bad fd");
+    check(
+        syscall(number::OPEN, 0x20_0000, 16, flags::READ) == -errno::EFAULT,
+        "open path in kernel memory",
+    );
+    check(
+        open("/nonexistent", flags::READ) == -errno::ENOENT,
+        "open missing file",
+    );
+    check(syscall(999, 0, 0, 0) == -errno::ENOSYS, "unknown syscall");
+    check(syscall(number::WAIT, 0, 0, 0) < 0, "wait for non-child");
+
+    // Messages to ourselves
+    let port = create_port();
+    check(port > 0, "create_port");
+    for i in 0..ITERATIONS {
+        let message = i.to_le_bytes();
+        check(send(port as u64, &message) == 0, "send");
+
+        let mut buf = [0; 8];
+        check(recv(port as u64, &mut buf) == 8, "recv");
+        check(buf == message, "message contents");
+    }
+
+    // Shared memory, mapped twice
+    let handle = shm_create(8192);
+    check(handle > 0, "shm_create");
+    let first = shm_map(handle as u64);
+    let second = shm_map(handle as u64);
+    check(first > 0 && second > 0 && first != second, "shm_map");
+    unsafe {
+        *((first + 4096) as *mut u64) = 0xDEAD_BEEF;
+        check(
+            *((second + 4096) as *const u64) == 0xDEAD_BEEF,
+            "shared memory contents",
+        );
+    }
+
+    println!("stress: ok");
+    0
+}
@@ -0,0 +1,241 @@
+// The runtime for the user programs that are embedded into the kernel: the
+// entry point, system call wrappers and `println!`. The system call numbers
+// and the calling convention have to match src/syscall.rs in the kernel.
+
+#![no_std]
+
+use core::arch::{asm, global_asm};
+use core::ffi::{c_char, CStr};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+pub mod number {
+    pub const READ: u64 = 0;
+    pub const WRITE: u64 = 1;
+    pub const EXIT: u64 = 2;
+    pub const YIELD: u64 = 3;
+    pub const SLEEP: u64 = 4;
+    pub const SPAWN: u64 = 5;
+    pub const WAIT: u64 = 6;
+    pub const CREATE_PORT: u64 = 7;
+    pub const SEND: u64 = 8;
+    pub const RECV: u64 = 9;
+    pub const SHM_CREATE: u64 = 10;
+    pub const SHM_MAP: u64 = 11;
+    pub const TTY_MODE: u64 = 12;
+    pub const OPEN: u64 = 13;
+    pub const CLOSE: u64 = 14;
+    pub const SEEK: u64 = 15;
+}
+
+pub mod errno {
+    pub const ENOENT: i64 = 2;
+    pub const EBADF: i64 = 9;
+    pub const EFAULT: i64 = 14;
+    pub const EISDIR: i64 = 21;
+    pub const ENOSYS: i64 = 38;
+}
+
+/// The flags for `open`.
+pub mod flags {
+    pub const READ: u64 = 1 << 0;
+    pub const WRITE: u64 = 1 << 1;
+    pub const CREATE: u64 = 1 << 2;
+    pub const TRUNCATE: u64 = 1 << 3;
+    pub const APPEND: u64 = 1 << 4;
+}
+
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+/// Where `seek` counts from.
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+// The kernel starts us with the stack pointer at argc, followed by argv, envp
+// and the auxiliary vector. The stack pointer is aligned, but functions expect
+// it to be off by one return address, which the call takes care of.
+global_asm!(
+    ".global _start",
+    "_start:",
+    "mov rdi, rsp",
+    "and rsp, -16",
+    "call {main}",
+    "ud2",
+    main = sym start_main,
+);
+
+extern "Rust" {
+    fn user_main() -> i64;
+}
+
+static ARGC: AtomicUsize = AtomicUsize::new(0);
+static ARGV: AtomicPtr<*const c_char> = AtomicPtr::new(core::ptr::null_mut());
+
+extern "C" fn start_main(stack: *const usize) -> ! {
+    unsafe {
+        ARGC.store(*stack, Ordering::Relaxed);
+        ARGV.store(stack.add(1) as *mut *const c_char, Ordering::Relaxed);
+    }
+    exit(unsafe { user_main() })
+}
+
+/// Returns the program's arguments, usually starting with its name. Arguments
+/// that aren't valid UTF-8 come out empty.
+pub fn args() -> impl Iterator<Item = &'static str> {
+    let argv = ARGV.load(Ordering::Relaxed);
+    (0..ARGC.load(Ordering::Relaxed)).map(move |i| unsafe { string(*argv.add(i)) })
+}
+
+/// Returns the program's environment, as `NAME=value` strings.
+pub fn vars() -> impl Iterator<Item = &'static str> {
+    let argv = ARGV.load(Ordering::Relaxed);
+    let mut envp = argv.wrapping_add(ARGC.load(Ordering::Relaxed) + 1);
+
+    core::iter::from_fn(move || {
+        if argv.is_null() {
+            return None;
+        }
+        let pointer = unsafe { *envp };
+        if pointer.is_null() {
+            return None;
+        }
+        envp = envp.wrapping_add(1);
+        Some(unsafe { string(pointer) })
+    })
+}
+
+/// Returns the value of the given environment variable.
+pub fn var(name: &str) -> Option<&'static str> {
+    vars().find_map(|var| {
+        let (var_name, value) = var.split_once('=')?;
+        (var_name == name).then_some(value)
+    })
+}
+
+unsafe fn string(pointer: *const c_char) -> &'static str {
+    CStr::from_ptr(pointer).to_str().unwrap_or("")
+}
+
+/// Defines the program's `main`, which returns the exit code.
+#[macro_export]
+macro_rules! entry_point {
+    ($main:path) => {
+        #[export_name = "user_main"]
+        fn __main() -> i64 {
+            let main: fn() -> i64 = $main;
+            main()
+        }
+    };
+}
+
+pub fn syscall(number: u64, arg1: u64, arg2: u64, arg3: u64) -> i64 {
+    let result: i64;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") number as i64 => result,
+            in("rdi") arg1,
+            in("rsi") arg2,
+            in("rdx") arg3,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack)
+        );
+    }
+    result
+}
+
+pub fn read(fd: u64, buf: &mut [u8]) -> i64 {
+    syscall(number::READ, fd, buf.as_mut_ptr() as u64, buf.len() as u64)
+}
+
+pub fn write(fd: u64, buf: &[u8]) -> i64 {
+    syscall(number::WRITE, fd, buf.as_ptr() as u64, buf.len() as u64)
+}
+
+pub fn exit(code: i64) -> ! {
+    syscall(number::EXIT, code as u64, 0, 0);
+    unreachable!("exit returned");
+}
+
+pub fn yield_now() -> i64 {
+    syscall(number::YIELD, 0, 0, 0)
+}
+
+pub fn sleep(milliseconds: u64) -> i64 {
+    syscall(number::SLEEP, milliseconds, 0, 0)
+}
+
+pub fn create_port() -> i64 {
+    syscall(number::CREATE_PORT, 0, 0, 0)
+}
+
+pub fn send(port: u64, data: &[u8]) -> i64 {
+    syscall(number::SEND, port, data.as_ptr() as u64, data.len() as u64)
+}
+
+pub fn recv(port: u64, buf: &mut [u8]) -> i64 {
+    syscall(
+        number::RECV,
+        port,
+        buf.as_mut_ptr() as u64,
+        buf.len() as u64,
+    )
+}
+
+pub fn shm_create(size: u64) -> i64 {
+    syscall(number::SHM_CREATE, size, 0, 0)
+}
+
+pub fn shm_map(handle: u64) -> i64 {
+    syscall(number::SHM_MAP, handle, 0, 0)
+}
+
+pub fn open(path: &str, flags: u64) -> i64 {
+    syscall(number::OPEN, path.as_ptr() as u64, path.len() as u64, flags)
+}
+
+pub fn close(fd: u64) -> i64 {
+    syscall(number::CLOSE, fd, 0, 0)
+}
+
+pub fn seek(fd: u64, offset: i64, whence: u64) -> i64 {
+    syscall(number::SEEK, fd, offset as u64, whence)
+}
+
+pub struct Stdout;
+
+impl Write for Stdout {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match write(STDOUT, s.as_bytes()) {
+            len if len == s.len() as i64 => Ok(()),
+            _ => Err(fmt::Error),
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    let _ = Stdout.write_fmt(args);
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    println!("panic: {}", info);
+    exit(101)
+}